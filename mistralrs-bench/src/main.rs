@@ -6,7 +6,7 @@ use mistralrs_core::{
     parse_isq_value, Constraint, DefaultSchedulerMethod, DeviceLayerMapMetadata, DeviceMapMetadata,
     DeviceMapSetting, DrySamplingParams, Loader, LoaderBuilder, MemoryGpuConfig, MistralRs,
     MistralRsBuilder, ModelSelected, NormalRequest, PagedAttentionConfig, PagedCacheType, Request,
-    RequestMessage, Response, SamplingParams, SchedulerConfig, TokenSource, Usage,
+    RequestMessage, RequestPriority, Response, SamplingParams, SchedulerConfig, TokenSource, Usage,
 };
 use std::fmt::Display;
 use std::sync::Arc;
@@ -67,6 +67,13 @@ async fn run_bench(
         logits_bias: None,
         n_choices: 1,
         dry_params: Some(DrySamplingParams::default()),
+        seed: None,
+        beam_search: None,
+        typical_p: None,
+        top_a: None,
+        xtc_params: None,
+        mirostat_params: None,
+        sampler_order: None,
     };
     let sender = mistralrs.get_sender(None).unwrap();
     let (tx, mut rx) = channel(10_000);
@@ -87,6 +94,10 @@ async fn run_bench(
         web_search_options: None,
         model_id: None,
         truncate_sequence: false,
+        priority: RequestPriority::default(),
+        timeout: None,
+        prompt_logprobs: None,
+        traceparent: None,
     }));
 
     let mut usages = Vec::new();
@@ -252,6 +263,10 @@ async fn warmup_run(mistralrs: Arc<MistralRs>) {
         web_search_options: None,
         model_id: None,
         truncate_sequence: false,
+        priority: RequestPriority::default(),
+        timeout: None,
+        prompt_logprobs: None,
+        traceparent: None,
     }));
 
     if sender.send(req.clone()).await.is_err() {
@@ -304,20 +319,20 @@ struct Args {
     in_situ_quant: Option<String>,
 
     /// GPU memory to allocate for KV cache with PagedAttention in MBs.
-    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
+    /// PagedAttention is supported on CUDA, Metal and CPU. It is automatically activated on CUDA but not on Metal or CPU.
     /// The priority is as follows: `pa-ctxt-len` > `pa-gpu-mem-usage` > `pa-gpu-mem`.
     #[arg(long = "pa-gpu-mem")]
     paged_attn_gpu_mem: Option<usize>,
 
     /// Percentage of GPU memory to utilize after allocation of KV cache with PagedAttention, from 0 to 1.
     /// If this is not set and the device is CUDA, it will default to `0.9`.
-    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
+    /// PagedAttention is supported on CUDA, Metal and CPU. It is automatically activated on CUDA but not on Metal or CPU.
     /// The priority is as follows: `pa-ctxt-len` > `pa-gpu-mem-usage` > `pa-gpu-mem`.
     #[arg(long = "pa-gpu-mem-usage")]
     paged_attn_gpu_mem_usage: Option<f32>,
 
     /// Total context length to allocate the KV cache for (total number of tokens which the KV cache can hold).
-    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
+    /// PagedAttention is supported on CUDA, Metal and CPU. It is automatically activated on CUDA but not on Metal or CPU.
     /// The priority is as follows: `pa-ctxt-len` > `pa-gpu-mem-usage` > `pa-gpu-mem`.
     /// This is the default setting, and it defaults to the `max-seq-len` specified in after the model type.
     #[arg(long = "pa-ctxt-len")]
@@ -337,7 +352,7 @@ struct Args {
     #[arg(long = "no-paged-attn", default_value_t = false)]
     no_paged_attn: bool,
 
-    /// Enable PagedAttention on Metal. Because PagedAttention is already enabled on CUDA, this is only applicable on Metal.
+    /// Enable PagedAttention on Metal or CPU. Because PagedAttention is already enabled on CUDA, this is only applicable on Metal and CPU.
     #[arg(long = "paged-attn", default_value_t = false)]
     paged_attn: bool,
 }
@@ -419,10 +434,9 @@ async fn main() -> anyhow::Result<()> {
 
     let no_paged_attn = if device.is_cuda() || mistralrs_core::distributed::use_nccl() {
         args.no_paged_attn
-    } else if device.is_metal() {
-        !args.paged_attn
     } else {
-        true
+        // Metal and CPU: PagedAttention is opt-in
+        !args.paged_attn
     };
 
     let cache_config = match (
@@ -503,6 +517,7 @@ async fn main() -> anyhow::Result<()> {
             SchedulerConfig::PagedAttentionMeta {
                 max_num_seqs: *args.concurrency.as_ref().unwrap().iter().max().unwrap(),
                 config: cache_config.clone(),
+                max_num_batched_tokens: None,
             }
         } else {
             SchedulerConfig::DefaultScheduler {
@@ -511,6 +526,7 @@ async fn main() -> anyhow::Result<()> {
                         .try_into()
                         .unwrap(),
                 ),
+                max_num_batched_tokens: None,
             }
         }
     } else {
@@ -520,6 +536,7 @@ async fn main() -> anyhow::Result<()> {
                     .try_into()
                     .unwrap(),
             ),
+            max_num_batched_tokens: None,
         }
     };
     let mistralrs = MistralRsBuilder::new(pipeline, scheduler_config, false, None)
@@ -130,6 +130,9 @@ pub fn nccl_daemon_replicator(request_sender: Sender<Request>) {
                             continue;
                         }
                         Request::TerminateAllSeqsNextStep => Request::TerminateAllSeqsNextStep,
+                        Request::Cancel { id } => Request::Cancel { id },
+                        // Session snapshots are rejected with tensor parallelism and never replicated.
+                        Request::SaveSession(_) | Request::LoadSession(_) => continue,
                     };
 
                     if request_sender.send(req).await.is_err() {
@@ -194,6 +197,9 @@ pub fn ring_daemon_replicator(request_sender: Sender<Request>) {
                             continue;
                         }
                         Request::TerminateAllSeqsNextStep => Request::TerminateAllSeqsNextStep,
+                        Request::Cancel { id } => Request::Cancel { id },
+                        // Session snapshots are rejected with tensor parallelism and never replicated.
+                        Request::SaveSession(_) | Request::LoadSession(_) => continue,
                     };
 
                     request_sender.send(req).await.unwrap();
@@ -1,9 +1,10 @@
 use crate::{
     pipeline::NormalCache,
     prefix_cacher::MatchingCache,
-    request::{DetokenizationRequest, NormalRequest, TokenizationRequest},
+    request::{Constraint, DetokenizationRequest, NormalRequest, TokenizationRequest},
     sequence::SeqStepType,
-    tools::{ToolCallingMatcher, ToolChoice},
+    telemetry,
+    tools::{tool_call_grammar, ToolCallingMatcher, ToolChoice},
     ModelCategory, RequestMessage, Response,
 };
 use candle_core::Tensor;
@@ -11,7 +12,7 @@ use either::Either;
 use std::{
     ops::Deref,
     sync::{atomic::Ordering, Arc},
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 use tracing::warn;
 
@@ -33,8 +34,10 @@ impl Engine {
                     &request.messages,
                     RequestMessage::Chat { .. } | RequestMessage::VisionChat { .. }
                 );
-                let has_tooling =
-                    !self.tool_callbacks.is_empty() || !self.tool_callbacks_with_tools.is_empty();
+                // Requests disallowing tools, such as MCP sampling requests, don't use the callbacks
+                let has_tooling = !matches!(request.tool_choice, Some(ToolChoice::None))
+                    && (!self.tool_callbacks.is_empty()
+                        || !self.all_tool_callbacks_with_tools().is_empty());
                 let has_search = request.web_search_options.is_some();
 
                 if is_chat && (has_search || has_tooling) {
@@ -50,14 +53,20 @@ impl Engine {
             }
             Request::Tokenize(req) => self.tokenize_text(req).await,
             Request::Detokenize(req) => self.detokenize_text(req).await,
+            Request::SaveSession(req) => self.save_session(req).await,
+            Request::LoadSession(req) => self.load_session(req).await,
             Request::Terminate => (),
             Request::TerminateAllSeqsNextStep => {
                 TERMINATE_ALL_NEXT_STEP.store(true, Ordering::SeqCst)
             }
+            Request::Cancel { id } => get_mut_arcmutex!(self.scheduler).cancel_request(id),
         }
     }
 
-    pub(super) async fn add_request(&self, request: NormalRequest) {
+    pub(super) async fn add_request(&self, mut request: NormalRequest) {
+        // The deadline counts from when the engine receives the request.
+        let deadline = request.timeout.map(|timeout| Instant::now() + timeout);
+        let span = telemetry::request_span(request.id, request.traceparent.as_deref());
         let is_chat = matches!(
             request.messages,
             RequestMessage::Chat { .. } | RequestMessage::VisionChat { .. }
@@ -137,8 +146,44 @@ impl Engine {
             _ => None,
         };
 
+        // Constrain decoding to a valid tool call when the tool choice requires one.
+        if let Some(tool_choice) = request
+            .tool_choice
+            .as_ref()
+            .filter(|_| is_chat && matches!(request.constraint, Constraint::None))
+        {
+            let pipeline = get_mut_arcmutex!(self.pipeline);
+            let grammar = match (pipeline.get_chat_template(), pipeline.tokenizer()) {
+                (Some(chat_template), Some(tokenizer)) => tool_call_grammar(
+                    &*self.tool_parser,
+                    &chat_template,
+                    &tokenizer,
+                    tool_choice,
+                    request.tools.as_deref().unwrap_or_default(),
+                ),
+                _ => Ok(None),
+            };
+            drop(pipeline);
+            match grammar {
+                Ok(Some(grammar)) => request.constraint = Constraint::Lark(grammar),
+                Ok(None) => (),
+                Err(e) => {
+                    request
+                        .response
+                        .send(Response::ValidationError(e.to_string().into()))
+                        .await
+                        .unwrap_or_else(|_| warn!("Receiver disconnected"));
+                    return;
+                }
+            }
+        }
+
         let matcher = Arc::new(handle_seq_error!(
-            ToolCallingMatcher::new(request.tool_choice.unwrap_or(ToolChoice::Auto),),
+            ToolCallingMatcher::new(
+                request.tool_choice.unwrap_or(ToolChoice::Auto),
+                self.tool_parser.clone(),
+                request.tools.as_deref().unwrap_or_default(),
+            ),
             request.response
         ));
 
@@ -163,6 +208,8 @@ impl Engine {
         };
         let mut added_seq = false;
 
+        let tokenize_span =
+            tracing::info_span!(parent: &span, "tokenize", num_tokens = tracing::field::Empty);
         let (mut prompt_tokens, prompt_text) = match request.messages {
             RequestMessage::Chat {
                 messages,
@@ -231,6 +278,8 @@ impl Engine {
                 (it, handle_seq_error!(prompt, request.response))
             }
         };
+        tokenize_span.record("num_tokens", prompt_tokens.len());
+        drop(tokenize_span);
         if prompt_tokens.is_empty() {
             request
                 .response
@@ -375,12 +424,66 @@ impl Engine {
             }
         };
 
-        let group = Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
-            request.sampling_params.n_choices,
-            request.is_streaming,
-            is_chat,
-            best_of,
-        )));
+        if let Some(error) = request.sampling_params.range_error() {
+            request
+                .response
+                .send(Response::ValidationError(error.into()))
+                .await
+                .unwrap_or_else(|_| warn!("Receiver disconnected"));
+            return;
+        }
+
+        if request.prompt_logprobs.is_some() && request.is_streaming {
+            request
+                .response
+                .send(Response::ValidationError(
+                    "Prompt logprobs are not supported for streaming requests.".into(),
+                ))
+                .await
+                .unwrap_or_else(|_| warn!("Receiver disconnected"));
+            return;
+        }
+
+        // Beam search runs `num_beams` sequences and returns the `n_choices` best of them.
+        let n_seqs = if let Some(beam_search) = &request.sampling_params.beam_search {
+            let pipeline = get_mut_arcmutex!(self.pipeline);
+            let error = if beam_search.num_beams < request.sampling_params.n_choices {
+                Some("Beam search requires `num_beams` to be at least the number of choices.")
+            } else if request.is_streaming {
+                Some("Beam search does not support streaming.")
+            } else if !matches!(request.constraint, Constraint::None) {
+                Some("Beam search does not support grammar constraints.")
+            } else if pipeline.get_metadata().cache_config.is_some() {
+                Some("Beam search is not supported with PagedAttention.")
+            } else if pipeline.cache().is_hybrid() {
+                Some("Beam search is not supported for hybrid models.")
+            } else if pipeline
+                .get_chat_template()
+                .is_some_and(|chat_template| chat_template.is_harmony_format())
+            {
+                Some("Beam search is not supported for Harmony format models.")
+            } else {
+                None
+            };
+            drop(pipeline);
+            if let Some(error) = error {
+                request
+                    .response
+                    .send(Response::ValidationError(error.into()))
+                    .await
+                    .unwrap_or_else(|_| warn!("Receiver disconnected"));
+                return;
+            }
+            beam_search.num_beams
+        } else {
+            request.sampling_params.n_choices
+        };
+
+        let mut group = SequenceGroup::new(n_seqs, request.is_streaming, is_chat, best_of);
+        if request.sampling_params.beam_search.is_some() {
+            group.set_best_beams(request.sampling_params.n_choices);
+        }
+        let group = Arc::new(tokio::sync::Mutex::new(group));
 
         let tokenizer = get_mut_arcmutex!(self.pipeline).tokenizer();
 
@@ -395,6 +498,11 @@ impl Engine {
             topk,
             topp,
             minp,
+            request.sampling_params.typical_p.unwrap_or(0.0),
+            request.sampling_params.top_a.unwrap_or(0.0),
+            request.sampling_params.xtc_params,
+            request.sampling_params.mirostat_params,
+            request.sampling_params.sampler_order,
             request.logits_processors.unwrap_or_default(),
         );
         let sampler = handle_seq_error!(sampler, request.response);
@@ -411,7 +519,7 @@ impl Engine {
         }
 
         // Add sequences
-        for response_index in 0..request.sampling_params.n_choices {
+        for response_index in 0..n_seqs {
             let factory = get_mut_arcmutex!(self.pipeline)
                 .get_metadata()
                 .llg_factory
@@ -554,6 +662,24 @@ impl Engine {
                 eos_toks,
             );
 
+            seq.set_request_id(request.id);
+            seq.set_span(span.clone());
+            seq.set_priority(request.priority);
+            if let Some(deadline) = deadline {
+                seq.set_deadline(deadline);
+            }
+
+            // Offset the seed per choice so that `n > 1` still yields distinct completions.
+            if let Some(seed) = request.sampling_params.seed {
+                seq.set_rng_seed(seed.wrapping_add(response_index as u64));
+            }
+            if let Some(beam_search) = &request.sampling_params.beam_search {
+                seq.set_beam_search(beam_search.clone());
+            }
+            if let Some(top_n) = request.prompt_logprobs {
+                seq.set_prompt_logprobs(top_n);
+            }
+
             // Only "track" a new sequence if it is a traditional one
             if matches!(seq_step_type, SeqStepType::PromptAndDecode) {
                 self.logger.add_new_sequence();
@@ -614,14 +740,19 @@ impl Engine {
                 );
             }
 
-            let prefill_cache = handle_seq_error!(
-                get_mut_arcmutex!(self.prefix_cacher).search_for_matching_cache(
-                    seq.get_toks(),
-                    seq.image_hashes(),
-                    seq.audio_hashes(),
-                ),
-                request.response
-            );
+            // Every prompt token must be run through the model to score it.
+            let prefill_cache = if request.prompt_logprobs.is_some() {
+                None
+            } else {
+                handle_seq_error!(
+                    get_mut_arcmutex!(self.prefix_cacher).search_for_matching_cache(
+                        seq.get_toks(),
+                        seq.image_hashes(),
+                        seq.audio_hashes(),
+                    ),
+                    request.response
+                )
+            };
 
             seq = match prefill_cache.clone() {
                 Some(MatchingCache::Normal {
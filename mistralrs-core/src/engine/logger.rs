@@ -1,93 +1,317 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
 use std::thread;
 use std::time::Duration;
 
+use serde::Serialize;
 use tracing::info;
 
+use crate::paged_attention::BlockEngine;
+
+/// Upper bounds, in seconds, of the latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 14] = [
+    0.005, 0.01, 0.025, 0.05, 0.075, 0.1, 0.25, 0.5, 1., 2.5, 5., 10., 30., 60.,
+];
+
+/// A cumulative histogram of latencies, in seconds.
+#[derive(Clone, Debug, Serialize)]
+#[cfg_attr(feature = "pyo3_macros", pyo3::pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+pub struct LatencyHistogram {
+    /// Upper bounds of the buckets.
+    pub buckets: Vec<f64>,
+    /// `counts[i]` is the number of observations less than or equal to `buckets[i]`.
+    pub counts: Vec<u64>,
+    /// Number of observations.
+    pub count: u64,
+    /// Sum of the observations.
+    pub sum: f64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: LATENCY_BUCKETS.to_vec(),
+            counts: vec![0; LATENCY_BUCKETS.len()],
+            count: 0,
+            sum: 0.,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    fn observe(&mut self, latency: Duration) {
+        let secs = latency.as_secs_f64();
+        for (bound, count) in self.buckets.iter().zip(&mut self.counts) {
+            if secs <= *bound {
+                *count += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+}
+
+/// A snapshot of the scheduling, KV cache and throughput statistics of an engine.
+#[derive(Clone, Debug, Serialize)]
+#[cfg_attr(feature = "pyo3_macros", pyo3::pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+pub struct EngineStats {
+    /// Sequences which are running.
+    pub num_running: usize,
+    /// Sequences which are waiting to be scheduled, including swapped out ones.
+    pub num_waiting: usize,
+    /// Total number of KV cache blocks. The block counts are only available with PagedAttention.
+    pub kv_blocks_total: Option<usize>,
+    /// KV cache blocks which are used by sequences or hold prefix cached data.
+    pub kv_blocks_used: Option<usize>,
+    /// KV cache blocks which are free.
+    pub kv_blocks_free: Option<usize>,
+    /// KV cache blocks which hold prefix cached data. Those not used by a sequence can be reclaimed when needed.
+    pub kv_blocks_cached: Option<usize>,
+    /// Sequences which have been started.
+    pub num_sequences: usize,
+    /// Sequences which started from a prefix cache hit.
+    pub prefix_cache_hits: usize,
+    /// Fraction of the sequences which started from a prefix cache hit.
+    pub prefix_cache_hit_rate: f64,
+    /// Prompt tokens processed per second, over the last few seconds.
+    pub prefill_tokens_per_sec: f64,
+    /// Tokens generated per second, over the last few seconds.
+    pub decode_tokens_per_sec: f64,
+    /// Prompt tokens processed since the engine started.
+    pub total_prefill_tokens: usize,
+    /// Tokens generated since the engine started.
+    pub total_decode_tokens: usize,
+    /// Sequences which were preempted to free KV cache blocks, by swapping or recomputation.
+    pub num_preemptions: usize,
+    /// Time from a sequence being added until its prompt has been processed.
+    pub time_to_first_token: LatencyHistogram,
+    /// Time between generated tokens of a sequence.
+    pub inter_token_latency: LatencyHistogram,
+}
+
+#[derive(Default)]
+struct Counters {
+    enable_logging: AtomicBool,
+    prefix_cache_hits: AtomicUsize,
+    total_new_seqs: AtomicUsize,
+    num_running: AtomicUsize,
+    num_waiting: AtomicUsize,
+    prefill_tokens: AtomicUsize,
+    decode_tokens: AtomicUsize,
+    /// `f64` bits of the throughput over the last interval.
+    prefill_tok_per_sec: AtomicU64,
+    decode_tok_per_sec: AtomicU64,
+    num_preemptions: AtomicUsize,
+    kv_blocks_total: AtomicUsize,
+    kv_blocks_free: AtomicUsize,
+    kv_blocks_cached: AtomicUsize,
+    time_to_first_token: Mutex<LatencyHistogram>,
+    inter_token_latency: Mutex<LatencyHistogram>,
+}
+
+/// Tracks the statistics of an engine and periodically logs its throughput once logging is enabled.
+/// Clones share the same counters.
+#[derive(Clone)]
 pub struct IntervalLogger {
-    enable_logging: Arc<AtomicBool>,
-    prefix_cache_hits: Arc<AtomicUsize>,
-    tokens_processed: Arc<AtomicUsize>,
-    total_new_seqs: Arc<AtomicUsize>,
-    num_running: Arc<AtomicUsize>,
-    num_waiting: Arc<AtomicUsize>,
+    counters: Arc<Counters>,
 }
 
 impl IntervalLogger {
-    /// Starts an interval logger. Call `begin_logging` to begin the logging process.
+    /// Starts an interval logger. Call `enable_logging` to begin the logging process.
     pub fn new(interval: Duration) -> Self {
-        let prefix_cache_hits = Arc::new(AtomicUsize::new(0));
-        let tokens_processed = Arc::new(AtomicUsize::new(0));
-        let total_new_seqs = Arc::new(AtomicUsize::new(0));
-        let enable_logging = Arc::new(AtomicBool::new(false));
-        let num_running = Arc::new(AtomicUsize::new(0));
-        let num_waiting = Arc::new(AtomicUsize::new(0));
-
-        let t_prefix_cache_hits = prefix_cache_hits.clone();
-        let t_tokens_processed = tokens_processed.clone();
-        let t_total_new_seqs = total_new_seqs.clone();
-        let t_enable_logging = enable_logging.clone();
-        let t_num_running = num_running.clone();
-        let t_num_waiting = num_waiting.clone();
+        let counters = Arc::new(Counters::default());
+
+        let t_counters = Arc::downgrade(&counters);
         thread::spawn(move || {
-            // Start the actual logging
+            let (mut last_prefill, mut last_decode) = (0, 0);
             loop {
                 thread::sleep(interval);
-                if !t_enable_logging.load(Ordering::Relaxed) {
+                // Stop once the engine is gone.
+                let Some(counters) = Weak::upgrade(&t_counters) else {
+                    break;
+                };
+
+                let prefill = counters.prefill_tokens.load(Ordering::Relaxed);
+                let decode = counters.decode_tokens.load(Ordering::Relaxed);
+                let prefill_tok_per_sec = (prefill - last_prefill) as f64 / interval.as_secs_f64();
+                let decode_tok_per_sec = (decode - last_decode) as f64 / interval.as_secs_f64();
+                (last_prefill, last_decode) = (prefill, decode);
+                counters
+                    .prefill_tok_per_sec
+                    .store(prefill_tok_per_sec.to_bits(), Ordering::Relaxed);
+                counters
+                    .decode_tok_per_sec
+                    .store(decode_tok_per_sec.to_bits(), Ordering::Relaxed);
+
+                if !counters.enable_logging.load(Ordering::Relaxed) {
                     continue;
                 }
 
-                let total_new_seqs = t_total_new_seqs.load(Ordering::Relaxed);
-                let prefix_cache_hits = t_prefix_cache_hits.load(Ordering::Relaxed);
-                let tokens_processed = t_tokens_processed.swap(0, Ordering::Relaxed);
-                let num_running = t_num_running.load(Ordering::Relaxed);
-                let num_waiting = t_num_waiting.load(Ordering::Relaxed);
+                let total_new_seqs = counters.total_new_seqs.load(Ordering::Relaxed);
+                let prefix_cache_hits = counters.prefix_cache_hits.load(Ordering::Relaxed);
+                let num_running = counters.num_running.load(Ordering::Relaxed);
+                let num_waiting = counters.num_waiting.load(Ordering::Relaxed);
+                let throughput = prefill_tok_per_sec + decode_tok_per_sec;
 
-                if total_new_seqs != 0 && tokens_processed != 0 {
+                if total_new_seqs != 0 && throughput != 0. {
                     info!(
-                        "Throughput (T/s) {:.2}, Prefix cache hitrate {:.2}%, {num_running} running, {num_waiting} waiting",
-                        tokens_processed as f64 / interval.as_secs_f64(),
+                        "Throughput (T/s) {throughput:.2}, Prefix cache hitrate {:.2}%, {num_running} running, {num_waiting} waiting",
                         100. * prefix_cache_hits as f64 / total_new_seqs as f64,
                     );
                 }
             }
         });
 
-        Self {
-            prefix_cache_hits,
-            tokens_processed,
-            total_new_seqs,
-            enable_logging,
-            num_running,
-            num_waiting,
-        }
+        Self { counters }
     }
 
     pub fn enable_logging(&self) {
-        self.enable_logging.store(true, Ordering::Relaxed);
+        self.counters.enable_logging.store(true, Ordering::Relaxed);
     }
 
-    pub fn add_tokens_processed(&self, num_tokens: usize) {
-        self.tokens_processed
+    pub fn add_prefill_tokens(&self, num_tokens: usize) {
+        self.counters
+            .prefill_tokens
+            .fetch_add(num_tokens, Ordering::Relaxed);
+    }
+
+    pub fn add_decode_tokens(&self, num_tokens: usize) {
+        self.counters
+            .decode_tokens
             .fetch_add(num_tokens, Ordering::Relaxed);
     }
 
     pub fn add_new_sequence(&self) {
-        self.total_new_seqs.fetch_add(1, Ordering::Relaxed);
+        self.counters.total_new_seqs.fetch_add(1, Ordering::Relaxed);
     }
 
     pub fn add_prefix_cache_hit(&self) {
-        self.prefix_cache_hits.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .prefix_cache_hits
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn observe_time_to_first_token(&self, latency: Duration) {
+        self.counters
+            .time_to_first_token
+            .lock()
+            .unwrap()
+            .observe(latency);
+    }
+
+    /// Record the time between two generated tokens of a sequence.
+    pub fn observe_inter_token_latency(&self, latency: Duration) {
+        self.counters
+            .inter_token_latency
+            .lock()
+            .unwrap()
+            .observe(latency);
     }
 
     pub fn set_num_running(&self, running: usize) {
-        self.num_running.store(running, Ordering::Relaxed);
+        self.counters.num_running.store(running, Ordering::Relaxed);
     }
 
     pub fn set_num_waiting(&self, waiting: usize) {
-        self.num_waiting.store(waiting, Ordering::Relaxed);
+        self.counters.num_waiting.store(waiting, Ordering::Relaxed);
+    }
+
+    pub fn set_num_preemptions(&self, preemptions: usize) {
+        self.counters
+            .num_preemptions
+            .store(preemptions, Ordering::Relaxed);
+    }
+
+    pub fn set_kv_block_usage(&self, block_engine: &BlockEngine) {
+        self.counters
+            .kv_blocks_total
+            .store(block_engine.num_gpu_blocks(), Ordering::Relaxed);
+        self.counters
+            .kv_blocks_free
+            .store(block_engine.num_free_blocks(), Ordering::Relaxed);
+        self.counters
+            .kv_blocks_cached
+            .store(block_engine.prefix_cache_size(), Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> EngineStats {
+        let counters = &self.counters;
+        let num_sequences = counters.total_new_seqs.load(Ordering::Relaxed);
+        let prefix_cache_hits = counters.prefix_cache_hits.load(Ordering::Relaxed);
+        let kv_blocks_total = counters.kv_blocks_total.load(Ordering::Relaxed);
+        let kv_blocks_free = counters.kv_blocks_free.load(Ordering::Relaxed);
+        let paged = kv_blocks_total != 0;
+
+        EngineStats {
+            num_running: counters.num_running.load(Ordering::Relaxed),
+            num_waiting: counters.num_waiting.load(Ordering::Relaxed),
+            kv_blocks_total: paged.then_some(kv_blocks_total),
+            kv_blocks_used: paged.then_some(kv_blocks_total.saturating_sub(kv_blocks_free)),
+            kv_blocks_free: paged.then_some(kv_blocks_free),
+            kv_blocks_cached: paged.then(|| counters.kv_blocks_cached.load(Ordering::Relaxed)),
+            num_sequences,
+            prefix_cache_hits,
+            prefix_cache_hit_rate: if num_sequences == 0 {
+                0.
+            } else {
+                prefix_cache_hits as f64 / num_sequences as f64
+            },
+            prefill_tokens_per_sec: f64::from_bits(
+                counters.prefill_tok_per_sec.load(Ordering::Relaxed),
+            ),
+            decode_tokens_per_sec: f64::from_bits(
+                counters.decode_tok_per_sec.load(Ordering::Relaxed),
+            ),
+            total_prefill_tokens: counters.prefill_tokens.load(Ordering::Relaxed),
+            total_decode_tokens: counters.decode_tokens.load(Ordering::Relaxed),
+            num_preemptions: counters.num_preemptions.load(Ordering::Relaxed),
+            time_to_first_token: counters.time_to_first_token.lock().unwrap().clone(),
+            inter_token_latency: counters.inter_token_latency.lock().unwrap().clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{IntervalLogger, LatencyHistogram};
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let mut histogram = LatencyHistogram::default();
+        for latency in [20, 20, 2_000, 120_000] {
+            histogram.observe(Duration::from_millis(latency));
+        }
+        let count_le = |bound: f64| {
+            let i = histogram.buckets.iter().position(|b| *b == bound).unwrap();
+            histogram.counts[i]
+        };
+        assert_eq!(count_le(0.01), 0);
+        assert_eq!(count_le(0.025), 2);
+        assert_eq!(count_le(1.), 2);
+        assert_eq!(count_le(2.5), 3);
+        assert_eq!(count_le(60.), 3);
+        assert_eq!(histogram.count, 4);
+        assert!((histogram.sum - 122.04).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_latencies_are_observed_once_each() {
+        let logger = IntervalLogger::new(Duration::from_secs(3600));
+        logger.observe_time_to_first_token(Duration::from_millis(300));
+        logger.observe_inter_token_latency(Duration::from_millis(20));
+        logger.observe_inter_token_latency(Duration::from_millis(40));
+
+        let stats = logger.stats();
+        assert_eq!(stats.time_to_first_token.count, 1);
+        assert_eq!(stats.inter_token_latency.count, 2);
+        assert!((stats.inter_token_latency.sum - 0.06).abs() < 1e-9);
     }
 }
@@ -1,20 +1,21 @@
 use crate::{
     distributed,
     pipeline::{
+        finish_unscheduled_seq,
         llg::{constraint_from_llg_grammar, llg_grammar_from_constraint},
         text_models_inputs_processor::PagedAttentionMeta,
         CacheBackendMetadata, CacheInstruction,
     },
-    prefix_cacher::PrefixCacheManagerV2,
+    prefix_cacher::{DiskPrefixCache, PrefixCacheDiskConfig, PrefixCacheManagerV2},
     response::CompletionChoice,
     scheduler::{Scheduler, SchedulerOutput},
     search::{self, rag::SearchPipeline},
     sequence::{SeqStepType, StopReason},
-    tools, CompletionResponse, SchedulerConfig, DEBUG,
+    telemetry, tools, CompletionResponse, McpClient, SchedulerConfig, DEBUG,
 };
 use interprocess::local_socket::{traits::Listener, ListenerOptions};
 use llguidance::ParserFactory;
-pub use logger::IntervalLogger;
+pub use logger::{EngineStats, IntervalLogger, LatencyHistogram};
 use mistralrs_quant::RingConfig;
 use rand::SeedableRng;
 use rand_isaac::Isaac64Rng;
@@ -43,7 +44,8 @@ use tokio::{
 
 use crate::{
     get_mut_arcmutex, handle_pipeline_forward_error,
-    pipeline::{ModelCategory, Pipeline},
+    kv_cache::{EitherCache, KvCacheQuantization},
+    pipeline::{ModelCategory, ModelKind, Pipeline},
     request::Request,
     response::{ChatCompletionResponse, Choice, ResponseMessage},
     sequence::{SequenceRecognizer, SequenceState},
@@ -53,6 +55,7 @@ use crate::{
 mod add_request;
 mod logger;
 mod search_request;
+mod session;
 
 pub enum EngineInstruction {
     Terminate,
@@ -152,6 +155,42 @@ pub static ENGINE_INSTRUCTIONS: LazyLock<
     std::sync::Mutex<HashMap<usize, Option<EngineInstruction>>>,
 > = LazyLock::new(|| std::sync::Mutex::new(HashMap::new()));
 
+type BlockOps = (
+    HashMap<usize, usize>,
+    HashMap<usize, usize>,
+    HashMap<usize, Vec<usize>>,
+);
+
+/// The swap in, swap out and copy operations of a PagedAttention step. They are executed before
+/// the first batch of the step which is run.
+struct PendingBlockOps(Option<BlockOps>);
+
+impl PendingBlockOps {
+    fn new(
+        blocks_to_swap_in: HashMap<usize, usize>,
+        blocks_to_swap_out: HashMap<usize, usize>,
+        blocks_to_copy: HashMap<usize, Vec<usize>>,
+    ) -> Self {
+        Self(Some((
+            blocks_to_swap_in,
+            blocks_to_swap_out,
+            blocks_to_copy,
+        )))
+    }
+
+    /// The operations to execute with the batch about to run, empty for the later batches.
+    fn take(&mut self) -> BlockOps {
+        self.0.take().unwrap_or_default()
+    }
+
+    /// The operations which were not executed with a batch, if any.
+    fn into_unexecuted(self) -> Option<BlockOps> {
+        self.0.filter(|(swap_in, swap_out, copy)| {
+            !swap_in.is_empty() || !swap_out.is_empty() || !copy.is_empty()
+        })
+    }
+}
+
 pub struct Engine {
     tx: Sender<Request>,
     rx: Arc<Mutex<Receiver<Request>>>,
@@ -160,6 +199,8 @@ pub struct Engine {
     search_callback: Option<Arc<search::SearchCallback>>,
     tool_callbacks: tools::ToolCallbacks,
     tool_callbacks_with_tools: tools::ToolCallbacksWithTools,
+    tool_parser: Arc<dyn tools::ToolCallParser>,
+    mcp_client: Option<Arc<McpClient>>,
     scheduler: Arc<Mutex<dyn Scheduler>>,
     id: Arc<Mutex<usize>>,
     no_kv_cache: bool,
@@ -190,12 +231,17 @@ impl Engine {
         mut no_kv_cache: bool,
         mut no_prefix_cache: bool,
         prefix_cache_n: usize,
+        prefix_cache_disk: Option<PrefixCacheDiskConfig>,
+        kv_cache_quantization: Option<KvCacheQuantization>,
         disable_eos_stop: bool,
         throughput_logging_enabled: bool,
         search_embedding_model: Option<SearchEmbeddingModel>,
         search_callback: Option<Arc<search::SearchCallback>>,
         tool_callbacks: tools::ToolCallbacks,
         tool_callbacks_with_tools: tools::ToolCallbacksWithTools,
+        tool_parser: Option<Arc<dyn tools::ToolCallParser>>,
+        mcp_client: Option<Arc<McpClient>>,
+        logger: IntervalLogger,
     ) -> anyhow::Result<Self> {
         no_kv_cache |= get_mut_arcmutex!(pipeline).get_metadata().no_kv_cache;
 
@@ -204,6 +250,39 @@ impl Engine {
             || get_mut_arcmutex!(pipeline).get_metadata().no_prefix_cache
             || prefix_cache_n == 0;
 
+        // Quantized storage only applies to the non-paged KV cache, so it is set up before any sequence runs.
+        let kv_cache_quantization = match kv_cache_quantization {
+            Some(quantization) if !no_kv_cache => {
+                let pipeline = get_mut_arcmutex!(pipeline);
+                if pipeline.get_metadata().cache_config.is_some() {
+                    tracing::warn!(
+                        "KV cache quantization does not apply when PagedAttention is used, ignoring it."
+                    );
+                    None
+                } else if let EitherCache::Normal(_) = pipeline.cache() {
+                    pipeline
+                        .cache()
+                        .normal()
+                        .set_quantization(Some(quantization));
+                    tracing::info!("Storing the KV cache quantized as {quantization:?}.");
+                    Some(quantization)
+                } else {
+                    tracing::warn!(
+                        "KV cache quantization is not supported for this model, ignoring it."
+                    );
+                    None
+                }
+            }
+            _ => None,
+        };
+
+        let tool_parser = match tool_parser {
+            Some(tool_parser) => tool_parser,
+            None => tools::detect_tool_call_parser(
+                get_mut_arcmutex!(pipeline).get_chat_template().as_deref(),
+            ),
+        };
+
         let search_pipeline = match search_embedding_model {
             Some(search_embedding_model) => Some(SearchPipeline::new(
                 search_embedding_model,
@@ -218,7 +297,65 @@ impl Engine {
         // This ensures PagedAttention prefix caching respects the same setting
         get_mut_arcmutex!(scheduler).set_prefix_caching_enabled(!no_prefix_cache);
 
+        // Chunked prefill resumes a prompt from its KV cache, which only text pipelines support.
+        let chunked_prefill_enabled = {
+            let pipeline = get_mut_arcmutex!(pipeline);
+            let metadata = pipeline.get_metadata();
+            !no_kv_cache
+                && !metadata.is_xlora
+                && !matches!(
+                    metadata.kind,
+                    ModelKind::Speculative { .. } | ModelKind::NGramSpeculative { .. }
+                )
+                && matches!(pipeline.category(), ModelCategory::Text)
+                && !pipeline.cache().is_hybrid()
+        };
+        get_mut_arcmutex!(scheduler).set_chunked_prefill_enabled(chunked_prefill_enabled);
+
+        // Speculative decoding verifies every sequence at its own offset, so with PagedAttention
+        // completions of different lengths (after accepting different numbers of tokens) share a step.
+        let ragged_completions_enabled = matches!(
+            get_mut_arcmutex!(pipeline).get_metadata().kind,
+            ModelKind::Speculative { .. } | ModelKind::NGramSpeculative { .. }
+        );
+        get_mut_arcmutex!(scheduler).set_ragged_completions_enabled(ragged_completions_enabled);
+
         let block_engine = get_mut_arcmutex!(scheduler).block_engine();
+        if let Some(block_engine) = &block_engine {
+            logger.set_kv_block_usage(&*get_mut_arcmutex!(block_engine));
+        }
+
+        let disk_prefix_cache = match prefix_cache_disk {
+            Some(disk_config) if !no_prefix_cache => {
+                let pipeline = get_mut_arcmutex!(pipeline);
+                let metadata = pipeline.get_metadata();
+                // Caches are only valid for the exact model and KV dtype that produced them.
+                let fingerprint = format!(
+                    "{}/{:?}/{:?}/{:?}/{}",
+                    pipeline.name(),
+                    metadata.kind,
+                    metadata.activation_dtype,
+                    kv_cache_quantization,
+                    metadata.num_hidden_layers
+                );
+                let device = pipeline.device();
+                let layer_devices = (0..metadata.num_hidden_layers)
+                    .map(|layer| {
+                        pipeline
+                            .device_mapper()
+                            .and_then(|mapper| mapper.device_for(layer, false).cloned())
+                            .unwrap_or_else(|| device.clone())
+                    })
+                    .collect();
+                Some(DiskPrefixCache::new(
+                    &disk_config,
+                    fingerprint,
+                    layer_devices,
+                    device,
+                )?)
+            }
+            _ => None,
+        };
 
         Ok(Self {
             tx,
@@ -228,6 +365,8 @@ impl Engine {
             search_callback,
             tool_callbacks,
             tool_callbacks_with_tools,
+            tool_parser,
+            mcp_client,
             scheduler: scheduler.clone(),
             id: Arc::new(Mutex::new(0)),
             no_kv_cache,
@@ -235,16 +374,26 @@ impl Engine {
                 prefix_cache_n,
                 no_prefix_cache,
                 block_engine,
+                disk_prefix_cache,
             ))),
             is_debug: DEBUG.load(Ordering::Relaxed),
             disable_eos_stop,
             throughput_logging_enabled,
-            logger: IntervalLogger::new(Duration::from_secs(5)),
+            logger,
             handles: Arc::new(Mutex::new(Vec::new())),
             pending_notify: Arc::new(Notify::new()),
         })
     }
 
+    /// The tool callbacks with tools, including the current tools of the MCP servers.
+    pub(crate) fn all_tool_callbacks_with_tools(&self) -> tools::ToolCallbacksWithTools {
+        let mut callbacks = self.tool_callbacks_with_tools.clone();
+        if let Some(mcp_client) = &self.mcp_client {
+            callbacks.extend(mcp_client.get_tool_callbacks_with_tools());
+        }
+        callbacks
+    }
+
     /// Returns the maximum supported sequence length for the underlying model, if applicable.
     #[allow(dead_code)]
     pub fn max_sequence_length(&self) -> Option<usize> {
@@ -308,6 +457,8 @@ impl Engine {
                 break 'lp;
             }
 
+            self.finish_stopped_sequences().await;
+
             let (waiting_len, running_len) = {
                 let scheduler = get_mut_arcmutex!(self.scheduler);
                 (scheduler.waiting_len(), scheduler.running_len())
@@ -367,6 +518,9 @@ impl Engine {
                     if !scheduled.completion.is_empty() {
                         let current_completion_ids: Vec<usize> =
                             scheduled.completion.iter().map(|seq| *seq.id()).collect();
+                        let decode_span = telemetry::decode_step_span(
+                            scheduled.completion.iter().map(|seq| seq.span()),
+                        );
                         let res = {
                             let mut pipeline = get_mut_arcmutex!(self.pipeline);
                             let pre_op = if !self.no_kv_cache
@@ -415,13 +569,24 @@ impl Engine {
                             'lp,
                             self.prefix_cacher
                         );
+                        drop(decode_span);
 
-                        self.logger.add_tokens_processed(scheduled.completion.len());
+                        self.logger.add_decode_tokens(scheduled.completion.len());
+                        for seq in scheduled.completion.iter_mut() {
+                            if let Some(latency) = seq.mark_token_generated() {
+                                self.logger.observe_inter_token_latency(latency);
+                            }
+                        }
 
                         last_completion_ids = current_completion_ids;
                     }
 
                     if !scheduled.prompt.is_empty() {
+                        let prefill_spans = scheduled
+                            .prompt
+                            .iter_mut()
+                            .map(|seq| seq.prefill_span())
+                            .collect::<Vec<_>>();
                         let prompt_exec_time = {
                             let mut pipeline = get_mut_arcmutex!(self.pipeline);
 
@@ -476,15 +641,20 @@ impl Engine {
                             'lp,
                             self.prefix_cacher
                         );
+                        drop(prefill_spans);
 
                         let total_processed_tokens: usize = scheduled
                             .prompt
                             .iter()
                             .map(|seq| seq.get_toks().len())
                             .sum();
-                        self.logger.add_tokens_processed(total_processed_tokens);
+                        self.logger.add_prefill_tokens(total_processed_tokens);
 
                         for seq in scheduled.prompt.iter_mut() {
+                            if seq.is_partial_prefill() {
+                                seq.advance_prefill_chunk();
+                                continue;
+                            }
                             match seq.sequence_stepping_type() {
                                 SeqStepType::OneShot => {
                                     seq.set_state(SequenceState::Done(StopReason::GeneratedImage))
@@ -503,6 +673,12 @@ impl Engine {
                             seq.prompt_tok_per_sec = prompt_tok_per_sec;
                             seq.prompt_timestamp = Some(now);
                             seq.total_prompt_time = Some(prompt_exec_time.as_millis());
+                            seq.mark_token_generated();
+                            #[allow(clippy::cast_possible_truncation)]
+                            self.logger
+                                .observe_time_to_first_token(Duration::from_millis(
+                                    (now - seq.timestamp()) as u64,
+                                ));
                         }
                         last_completion_ids = vec![];
                     }
@@ -534,16 +710,42 @@ impl Engine {
                         }
                     }
                 }
-                SchedulerOutput::PagedAttention { mut output } => {
-                    if !output.scheduled.is_empty() {
-                        let is_prompt = get_mut_arcmutex!(output.scheduled[0]).is_prompt();
+                SchedulerOutput::PagedAttention { output } => {
+                    // With chunked prefill, the prompt chunks are run after the decode batch. The
+                    // block operations are executed before the first batch which is run.
+                    let mut block_ops = PendingBlockOps::new(
+                        output.blocks_to_swap_in,
+                        output.blocks_to_swap_out,
+                        output.blocks_to_copy,
+                    );
+                    for mut scheduled in [output.scheduled, output.prompt_chunks] {
+                        if scheduled.is_empty() {
+                            continue;
+                        }
+                        let (blocks_to_swap_in, blocks_to_swap_out, blocks_to_copy) =
+                            block_ops.take();
+                        let is_prompt = get_mut_arcmutex!(scheduled[0]).is_prompt();
 
-                        let mut guards = output
-                            .scheduled
+                        let mut guards = scheduled
                             .iter_mut()
                             .map(|seq| seq.lock().unwrap())
                             .collect::<Vec<_>>();
 
+                        let prefill_spans = guards
+                            .iter_mut()
+                            .filter(|seq| seq.is_prompt())
+                            .map(|seq| seq.prefill_span())
+                            .collect::<Vec<_>>();
+                        let decode_span =
+                            (!is_prompt || prefill_spans.len() < guards.len()).then(|| {
+                                telemetry::decode_step_span(
+                                    guards
+                                        .iter()
+                                        .filter(|seq| !seq.is_prompt())
+                                        .map(|seq| seq.span()),
+                                )
+                            });
+
                         let mut guards_mut =
                             guards.iter_mut().map(|seq| &mut **seq).collect::<Vec<_>>();
 
@@ -576,7 +778,9 @@ impl Engine {
                                     rng.clone(),
                                     CacheBackendMetadata::PagedAttention {
                                         metadata,
-                                        blocks_to_copy: output.blocks_to_copy,
+                                        blocks_to_swap_in,
+                                        blocks_to_swap_out,
+                                        blocks_to_copy,
                                     },
                                 )
                                 .await
@@ -590,18 +794,19 @@ impl Engine {
                             'lp,
                             self.prefix_cacher
                         );
+                        drop((prefill_spans, decode_span));
 
-                        let total_processed_tokens: usize = guards
-                            .iter()
-                            .map(|seq| {
-                                if seq.is_prompt() {
-                                    seq.get_toks().len()
-                                } else {
-                                    1
-                                }
-                            })
-                            .sum();
-                        self.logger.add_tokens_processed(total_processed_tokens);
+                        let (prompts, completions): (Vec<_>, Vec<_>) =
+                            guards.iter().partition(|seq| seq.is_prompt());
+                        self.logger.add_prefill_tokens(
+                            prompts.iter().map(|seq| seq.get_toks().len()).sum(),
+                        );
+                        self.logger.add_decode_tokens(completions.len());
+                        for seq in guards.iter_mut().filter(|seq| !seq.is_prompt()) {
+                            if let Some(latency) = seq.mark_token_generated() {
+                                self.logger.observe_inter_token_latency(latency);
+                            }
+                        }
 
                         if self.is_debug {
                             let ms_from_last_run = run_start.elapsed().as_secs_f64();
@@ -630,6 +835,14 @@ impl Engine {
 
                         if is_prompt {
                             for mut seq in guards {
+                                if seq.is_partial_prefill() {
+                                    seq.advance_prefill_chunk();
+                                    continue;
+                                }
+                                if seq.is_prompt() {
+                                    // Chunked prefill tells prompts and decodes apart by state.
+                                    seq.set_state(SequenceState::RunningCompletion);
+                                }
                                 let now = SystemTime::now()
                                     .duration_since(UNIX_EPOCH)
                                     .expect("Time travel has occurred!")
@@ -640,9 +853,30 @@ impl Engine {
                                 seq.prompt_tok_per_sec = prompt_tok_per_sec * 1000.;
                                 seq.prompt_timestamp = Some(now);
                                 seq.total_prompt_time = Some(now - seq.timestamp());
+                                seq.mark_token_generated();
+                                #[allow(clippy::cast_possible_truncation)]
+                                self.logger
+                                    .observe_time_to_first_token(Duration::from_millis(
+                                        (now - seq.timestamp()) as u64,
+                                    ));
                             }
                         }
                     }
+                    // The scheduler has already updated its block tables, so the operations of a
+                    // step which runs no batch are executed by themselves.
+                    if let Some((blocks_to_swap_in, blocks_to_swap_out, blocks_to_copy)) =
+                        block_ops.into_unexecuted()
+                    {
+                        if let Err(e) = get_mut_arcmutex!(self.pipeline).execute_scheduler_ops(
+                            &blocks_to_swap_in,
+                            &blocks_to_swap_out,
+                            &blocks_to_copy,
+                        ) {
+                            tracing::error!(
+                                "Failed to execute the scheduler block operations: {e}"
+                            );
+                        }
+                    }
                 }
             }
 
@@ -660,6 +894,34 @@ impl Engine {
                 }
             }
             scheduler.free_finished_sequence_groups();
+            if let Some(block_engine) = scheduler.block_engine() {
+                self.logger
+                    .set_kv_block_usage(&*get_mut_arcmutex!(block_engine));
+            }
+        }
+    }
+
+    /// Respond to the sequences which are finished without another step, such as waiting
+    /// sequences past their deadline.
+    async fn finish_stopped_sequences(&self) {
+        let stopped = get_mut_arcmutex!(self.scheduler).take_stopped_sequences();
+        if stopped.is_empty() {
+            return;
+        }
+
+        let model = {
+            let pipeline = get_mut_arcmutex!(self.pipeline);
+            // Free the Mamba state pool slots of partially prefilled sequences (hybrid models)
+            if !pipeline.get_metadata().no_kv_cache && pipeline.cache().is_hybrid() {
+                let mut hybrid_cache = pipeline.cache().hybrid();
+                for idx in stopped.iter().filter_map(|(seq, _)| seq.mamba_state_idx()) {
+                    hybrid_cache.free_seq(idx);
+                }
+            }
+            pipeline.name()
+        };
+        for (mut seq, reason) in stopped {
+            finish_unscheduled_seq(&mut seq, reason, model.clone()).await;
         }
     }
 
@@ -679,6 +941,10 @@ impl Engine {
     }
 
     fn replicate_request_to_daemons(&self, request: &Request) {
+        // Each worker only holds a shard of the KV cache, so session snapshots are not supported.
+        if matches!(request, Request::SaveSession(_) | Request::LoadSession(_)) {
+            return;
+        }
         if !distributed::is_daemon() && mistralrs_quant::distributed::use_nccl() {
             let name = distributed::ipc_name().unwrap();
             let num_workers =
@@ -707,3 +973,37 @@ impl Engine {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::PendingBlockOps;
+
+    fn block_ops() -> PendingBlockOps {
+        PendingBlockOps::new(
+            HashMap::from([(1, 2)]),
+            HashMap::new(),
+            HashMap::from([(3, vec![4])]),
+        )
+    }
+
+    #[test]
+    fn test_block_ops_are_executed_without_a_batch() {
+        let (swap_in, swap_out, copy) = block_ops().into_unexecuted().unwrap();
+        assert_eq!(swap_in, HashMap::from([(1, 2)]));
+        assert!(swap_out.is_empty());
+        assert_eq!(copy, HashMap::from([(3, vec![4])]));
+
+        let empty = PendingBlockOps::new(HashMap::new(), HashMap::new(), HashMap::new());
+        assert!(empty.into_unexecuted().is_none());
+    }
+
+    #[test]
+    fn test_block_ops_are_executed_with_the_first_batch() {
+        let mut ops = block_ops();
+        assert_eq!(ops.take().0, HashMap::from([(1, 2)]));
+        assert!(ops.take().2.is_empty());
+        assert!(ops.into_unexecuted().is_none());
+    }
+}
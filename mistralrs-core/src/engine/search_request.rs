@@ -4,14 +4,14 @@ use bm25::{Embedder, EmbedderBuilder, Language, ScoredDocument, Scorer};
 use either::Either;
 use indexmap::IndexMap;
 use tokenizers::InputSequence;
-use tracing::{level_filters::LevelFilter, Dispatch};
+use tracing::{info_span, level_filters::LevelFilter, Dispatch, Instrument};
 
 use crate::{
     get_mut_arcmutex,
     request::SearchContextSize,
     search::{self, ExtractFunctionParameters, SearchFunctionParameters, SearchResult},
-    MessageContent, NormalRequest, RequestMessage, Response, ToolCallResponse, ToolChoice,
-    WebSearchOptions,
+    telemetry, MessageContent, NormalRequest, RequestMessage, Response, ToolCallResponse,
+    ToolChoice, WebSearchOptions,
 };
 
 use super::Engine;
@@ -361,7 +361,7 @@ async fn do_custom_tool(
             format!("ERROR: {e}")
         })
     } else if let Some(callback_with_tool) = this
-        .tool_callbacks_with_tools
+        .all_tool_callbacks_with_tools()
         .get(&tool_calls.function.name)
     {
         (callback_with_tool.callback)(&tool_calls.function).unwrap_or_else(|e| {
@@ -390,6 +390,32 @@ async fn do_custom_tool(
     second_request
 }
 
+/// Run the tool called by the model and build the next request, in a `tool_call` span of the request's trace.
+async fn run_tool_call(
+    this: Arc<Engine>,
+    visible_req: NormalRequest,
+    tool_calls: &ToolCallResponse,
+    web_search_options: Option<&WebSearchOptions>,
+) -> NormalRequest {
+    let span = info_span!(parent: None, "tool_call", tool = %tool_calls.function.name);
+    telemetry::set_remote_parent(&span, visible_req.traceparent.as_deref());
+
+    async move {
+        if search::search_tool_called(&tool_calls.function.name) {
+            let web_search_options = web_search_options.unwrap();
+            if tool_calls.function.name == search::SEARCH_TOOL_NAME {
+                do_search(this, visible_req, tool_calls, web_search_options).await
+            } else {
+                do_extraction(this, visible_req, tool_calls, web_search_options).await
+            }
+        } else {
+            do_custom_tool(this, visible_req, tool_calls).await
+        }
+    }
+    .instrument(span)
+    .await
+}
+
 /// Drive one or more web-search / extraction rounds without recursion.
 ///
 /// Strategy:
@@ -418,12 +444,13 @@ pub(super) async fn search_request(this: Arc<Engine>, request: NormalRequest) {
     }
 
     // Add Tool definitions from tool callbacks with tools if they're not already present
-    if !this.tool_callbacks_with_tools.is_empty() {
+    let tool_callbacks_with_tools = this.all_tool_callbacks_with_tools();
+    if !tool_callbacks_with_tools.is_empty() {
         let tools = probe.tools.get_or_insert_with(Vec::new);
         let existing_tool_names: Vec<String> =
             tools.iter().map(|t| t.function.name.clone()).collect();
 
-        for (name, callback_with_tool) in &this.tool_callbacks_with_tools {
+        for (name, callback_with_tool) in &tool_callbacks_with_tools {
             if !existing_tool_names.contains(name) {
                 tools.push(callback_with_tool.tool.clone());
             }
@@ -559,16 +586,13 @@ pub(super) async fn search_request(this: Arc<Engine>, request: NormalRequest) {
 
                 // Tool requested -> build the next turn.
                 let tc = tc_opt.unwrap();
-                let next_visible = if search::search_tool_called(&tc.function.name) {
-                    let web_search_options = web_search_options.as_ref().unwrap();
-                    if tc.function.name == search::SEARCH_TOOL_NAME {
-                        do_search(this_clone.clone(), visible_req, tc, web_search_options).await
-                    } else {
-                        do_extraction(this_clone.clone(), visible_req, tc, web_search_options).await
-                    }
-                } else {
-                    do_custom_tool(this_clone.clone(), visible_req, tc).await
-                };
+                let next_visible = run_tool_call(
+                    this_clone.clone(),
+                    visible_req,
+                    tc,
+                    web_search_options.as_ref(),
+                )
+                .await;
 
                 // The fresh request becomes both the user-visible context and
                 // the next `current` we will dispatch.
@@ -578,8 +602,9 @@ pub(super) async fn search_request(this: Arc<Engine>, request: NormalRequest) {
             }
             // ------------------------- STREAMING -------------------------
             else {
-                // We need the *last* chunk to see whether a tool was called.
+                // The tool calls are streamed in fragments, which are merged here.
                 let mut last_choice = None;
+                let mut tool_calls = Vec::new();
 
                 while let Some(resp) = receiver.recv().await {
                     match resp {
@@ -590,8 +615,15 @@ pub(super) async fn search_request(this: Arc<Engine>, request: NormalRequest) {
                             // first probe turn while still hiding the internal
                             // search/extract trigger.
                             let first_choice = &chunk.choices[0];
-                            if first_choice.delta.tool_calls.is_none() {
-                                let _ = user_sender.send(Response::Chunk(chunk.clone())).await;
+                            match &first_choice.delta.tool_calls {
+                                Some(deltas) => {
+                                    for delta in deltas.iter().cloned() {
+                                        delta.accumulate(&mut tool_calls);
+                                    }
+                                }
+                                None => {
+                                    let _ = user_sender.send(Response::Chunk(chunk.clone())).await;
+                                }
                             }
                             last_choice = Some(first_choice.clone());
 
@@ -683,10 +715,12 @@ pub(super) async fn search_request(this: Arc<Engine>, request: NormalRequest) {
                     }
                 }
 
-                let Some(choice) = last_choice else { break };
+                if last_choice.is_none() {
+                    break;
+                }
 
-                let tc_opt = match &choice.delta.tool_calls {
-                    Some(calls) if calls.len() == 1 => Some(&calls[0]),
+                let tc_opt = match &tool_calls[..] {
+                    [call] => Some(call),
                     _ => None,
                 };
 
@@ -695,16 +729,13 @@ pub(super) async fn search_request(this: Arc<Engine>, request: NormalRequest) {
                 }
 
                 let tc = tc_opt.unwrap();
-                let next_visible = if search::search_tool_called(&tc.function.name) {
-                    let web_search_options = web_search_options.as_ref().unwrap();
-                    if tc.function.name == search::SEARCH_TOOL_NAME {
-                        do_search(this_clone.clone(), visible_req, tc, web_search_options).await
-                    } else {
-                        do_extraction(this_clone.clone(), visible_req, tc, web_search_options).await
-                    }
-                } else {
-                    do_custom_tool(this_clone.clone(), visible_req, tc).await
-                };
+                let next_visible = run_tool_call(
+                    this_clone.clone(),
+                    visible_req,
+                    tc,
+                    web_search_options.as_ref(),
+                )
+                .await;
 
                 visible_req = next_visible.clone();
                 visible_req.response = user_sender.clone();
@@ -0,0 +1,154 @@
+use std::{path::Path, sync::Arc};
+
+use anyhow::Context;
+use tracing::warn;
+
+use crate::{
+    get_mut_arcmutex,
+    kv_cache::EitherCache,
+    prefix_cacher::{SessionSnapshot, SessionTarget},
+    request::{LoadSessionRequest, NormalRequest, Request, SaveSessionRequest},
+    sampler::SamplingParams,
+    RequestMessage,
+};
+
+use super::Engine;
+
+/// With tensor parallelism each worker only holds a shard of the KV cache.
+fn check_not_distributed() -> anyhow::Result<()> {
+    if mistralrs_quant::distributed::use_nccl() || cfg!(feature = "ring") {
+        anyhow::bail!("Session snapshots are not supported with tensor parallelism.");
+    }
+    Ok(())
+}
+
+impl Engine {
+    pub(super) async fn save_session(self: Arc<Self>, request: SaveSessionRequest) {
+        // Saving may prefill the tokens through the engine loop, so it must not block it.
+        let this = self.clone();
+        let handle = tokio::spawn(async move {
+            let res = this
+                .try_save_session(request.id, &request.tokens, &request.path)
+                .await;
+            request
+                .response
+                .send(res)
+                .await
+                .unwrap_or_else(|_| warn!("Receiver disconnected"));
+        });
+        get_mut_arcmutex!(self.handles).push(handle);
+    }
+
+    async fn try_save_session(
+        &self,
+        id: usize,
+        toks: &[u32],
+        path: &Path,
+    ) -> anyhow::Result<usize> {
+        check_not_distributed()?;
+        let session_len = get_mut_arcmutex!(self.prefix_cacher).session_len(toks.len());
+        if session_len == 0 {
+            anyhow::bail!("The session is too short to be saved.");
+        }
+
+        let mut snapshot = self.export_session(toks)?;
+        if snapshot
+            .as_ref()
+            .is_none_or(|snapshot| snapshot.toks.len() < session_len)
+        {
+            // The conversation is not (fully) cached, for example because it was evicted.
+            self.prefill_session(id, toks).await?;
+            snapshot = self.export_session(toks)?;
+        }
+        let snapshot = snapshot.context("The session could not be cached.")?;
+
+        let name = get_mut_arcmutex!(self.pipeline).name();
+        snapshot.save(path, &name)?;
+        Ok(snapshot.toks.len())
+    }
+
+    fn export_session(&self, toks: &[u32]) -> anyhow::Result<Option<SessionSnapshot>> {
+        let metadata = get_mut_arcmutex!(self.pipeline).get_metadata();
+        let mut prefix_cacher = get_mut_arcmutex!(self.prefix_cacher);
+        match &metadata.cache_engine {
+            Some(cache_engine) => {
+                prefix_cacher.export_session(toks, Some(cache_engine.get_kv_cache().as_slice()))
+            }
+            None => prefix_cacher.export_session(toks, None),
+        }
+    }
+
+    /// Run `toks` through the model, so that their KV cache is added to the prefix cache when the sequence finishes.
+    async fn prefill_session(&self, id: usize, toks: &[u32]) -> anyhow::Result<()> {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let request = NormalRequest::new_simple(
+            RequestMessage::CompletionTokens(toks.to_vec()),
+            SamplingParams {
+                max_len: Some(1),
+                ..SamplingParams::deterministic()
+            },
+            tx,
+            id,
+            None,
+            None,
+        );
+        self.tx.send(Request::Normal(Box::new(request))).await?;
+        rx.recv()
+            .await
+            .context("Channel was erroneously closed!")?
+            .as_result()
+            .map_err(|e| anyhow::anyhow!("Prefilling the session failed: {e}"))?;
+        Ok(())
+    }
+
+    pub(super) async fn load_session(&self, request: LoadSessionRequest) {
+        let res = self.try_load_session(&request.path);
+        request
+            .response
+            .send(res)
+            .await
+            .unwrap_or_else(|_| warn!("Receiver disconnected"));
+    }
+
+    fn try_load_session(&self, path: &Path) -> anyhow::Result<Vec<u32>> {
+        check_not_distributed()?;
+        let pipeline = get_mut_arcmutex!(self.pipeline);
+        let metadata = pipeline.get_metadata();
+        let name = pipeline.name();
+
+        if let Some(cache_engine) = &metadata.cache_engine {
+            let kv_cache = cache_engine.get_kv_cache();
+            let snapshot = SessionSnapshot::load(path, &name, kv_cache.len())?;
+            let mut toks = snapshot.toks.clone();
+            let n_restored = get_mut_arcmutex!(self.prefix_cacher)
+                .import_session(snapshot, SessionTarget::Paged(kv_cache.as_slice()))?;
+            toks.truncate(n_restored);
+            return Ok(toks);
+        }
+
+        let EitherCache::Normal(_) = pipeline.cache() else {
+            anyhow::bail!("Session snapshots are not supported for this model.");
+        };
+        let templates = pipeline.cache().normal().0.clone();
+        let device = pipeline.device();
+        let layer_devices = (0..templates.len())
+            .map(|layer| {
+                pipeline
+                    .device_mapper()
+                    .and_then(|mapper| mapper.device_for(layer, false).cloned())
+                    .unwrap_or_else(|| device.clone())
+            })
+            .collect::<Vec<_>>();
+        let snapshot = SessionSnapshot::load(path, &name, templates.len())?;
+        let toks = snapshot.toks.clone();
+        get_mut_arcmutex!(self.prefix_cacher).import_session(
+            snapshot,
+            SessionTarget::Normal {
+                templates: &templates,
+                layer_devices: &layer_devices,
+                dtype: metadata.activation_dtype,
+            },
+        )?;
+        Ok(toks)
+    }
+}
@@ -1,6 +1,6 @@
 use std::sync::{Arc, Mutex, MutexGuard};
 
-use candle_core::{Result, Tensor, D};
+use candle_core::{DType, Result, Tensor, D};
 
 use crate::{
     get_mut_arcmutex,
@@ -10,11 +10,13 @@ use crate::{
 
 mod full_cache;
 mod hybrid_cache;
+mod quantized;
 mod rotating_cache;
 mod single_cache;
 
 pub use full_cache::{EitherCache, LayerCaches};
 pub use hybrid_cache::{HybridCache, HybridCacheConfig, HybridLayerCache, HybridLayerType};
+pub use quantized::KvCacheQuantization;
 pub use rotating_cache::RotatingCache;
 pub use single_cache::SingleCache;
 
@@ -54,23 +56,64 @@ impl KvCache {
         Self::Rotating { k, v }
     }
 
+    /// Quantized caches are dequantized to F32.
     pub fn k(&self) -> Result<Option<Tensor>> {
-        match self {
-            Self::Normal { k, .. } => k.current_data(),
-            Self::Rotating { k, .. } => k.current_data(),
+        let k = match self {
+            Self::Normal { k, .. } => k.current_data()?,
+            Self::Rotating { k, .. } => k.current_data()?,
+        };
+        match self.quantization() {
+            Some(quantization) => k
+                .map(|k| quantization.dequantize(&k, DType::F32))
+                .transpose(),
+            None => Ok(k),
         }
     }
 
+    /// Quantized caches are dequantized to F32.
     pub fn v(&self) -> Result<Option<Tensor>> {
+        let v = match self {
+            Self::Normal { v, .. } => v.current_data()?,
+            Self::Rotating { v, .. } => v.current_data()?,
+        };
+        match self.quantization() {
+            Some(quantization) => v
+                .map(|v| quantization.dequantize(&v, DType::F32))
+                .transpose(),
+            None => Ok(v),
+        }
+    }
+
+    pub fn quantization(&self) -> Option<KvCacheQuantization> {
         match self {
-            Self::Normal { v, .. } => v.current_data(),
-            Self::Rotating { v, .. } => v.current_data(),
+            Self::Normal { k, .. } => k.quantization,
+            Self::Rotating { k, .. } => k.quantization,
         }
     }
 
+    /// Set the storage format of the cache, which must not hold any data yet.
+    pub fn set_quantization(&mut self, quantization: Option<KvCacheQuantization>) {
+        match self {
+            Self::Normal { k, v } => {
+                k.quantization = quantization;
+                v.quantization = quantization;
+            }
+            Self::Rotating { k, v } => {
+                k.quantization = quantization;
+                v.quantization = quantization;
+            }
+        }
+    }
+
+    /// Appends to the cache and returns all cached keys and values. Quantized caches store the new keys and
+    /// values quantized and return the dequantized cache in the dtype of the inputs.
     pub fn append(&mut self, k: &Tensor, v: &Tensor) -> Result<(Tensor, Tensor)> {
-        let k = k.contiguous()?;
-        let v = v.contiguous()?;
+        let quantization = self.quantization();
+        let dtype = k.dtype();
+        let (k, v) = match quantization {
+            Some(quantization) => (quantization.quantize(k)?, quantization.quantize(v)?),
+            None => (k.contiguous()?, v.contiguous()?),
+        };
         let (out_k, out_v) = match self {
             Self::Normal { k: kc, v: vc } => {
                 kc.append(&k)?;
@@ -105,7 +148,13 @@ impl KvCache {
             }
             Some(v) => v,
         };
-        Ok((k, v))
+        match quantization {
+            Some(quantization) => Ok((
+                quantization.dequantize(&k, dtype)?,
+                quantization.dequantize(&v, dtype)?,
+            )),
+            None => Ok((k, v)),
+        }
     }
 
     pub fn current_seq_len(&self) -> usize {
@@ -227,6 +276,13 @@ impl NormalCache {
         }
         Arc::new(Mutex::new(Self(caches)))
     }
+
+    /// Set the storage format of every layer. This must be done before any data is cached.
+    pub fn set_quantization(&mut self, quantization: Option<KvCacheQuantization>) {
+        for cache in &mut self.0 {
+            cache.set_quantization(quantization);
+        }
+    }
 }
 
 pub struct NormalCacheManager;
@@ -320,6 +376,7 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                         current_seq_len: 0,
                         max_seq_len: 0,
                         capacity_seq_len: 0,
+                        quantization: None,
                     },
                     v: SingleCache {
                         all_data: None,
@@ -327,6 +384,7 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                         current_seq_len: 0,
                         max_seq_len: 0,
                         capacity_seq_len: 0,
+                        quantization: None,
                     },
                 });
                 continue;
@@ -337,6 +395,7 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                     let template_cache_csl = old_k.current_seq_len;
                     let template_cache_msl = old_k.max_seq_len;
                     let template_cache_capsl = old_k.capacity_seq_len;
+                    let template_cache_quantization = old_k.quantization;
 
                     caches.push(KvCache::Normal {
                         k: SingleCache {
@@ -345,6 +404,7 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                             current_seq_len: template_cache_csl,
                             max_seq_len: template_cache_msl,
                             capacity_seq_len: template_cache_capsl,
+                            quantization: template_cache_quantization,
                         },
                         v: SingleCache {
                             all_data: v_cache.map(|x| x.contiguous().unwrap()),
@@ -352,6 +412,7 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                             current_seq_len: template_cache_csl,
                             max_seq_len: template_cache_msl,
                             capacity_seq_len: template_cache_capsl,
+                            quantization: template_cache_quantization,
                         },
                     });
                 }
@@ -361,6 +422,7 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                     let template_cache_msl = old_k.max_seq_len;
                     let template_cache_offset = old_k.offset;
                     let template_cache_capsl = old_k.capacity_seq_len;
+                    let template_cache_quantization = old_k.quantization;
 
                     caches.push(KvCache::Rotating {
                         k: RotatingCache {
@@ -370,6 +432,7 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                             max_seq_len: template_cache_msl,
                             offset: template_cache_offset,
                             capacity_seq_len: template_cache_capsl,
+                            quantization: template_cache_quantization,
                         },
                         v: RotatingCache {
                             all_data: v_cache.map(|x| x.contiguous().unwrap()),
@@ -378,6 +441,7 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                             max_seq_len: template_cache_msl,
                             offset: template_cache_offset,
                             capacity_seq_len: template_cache_capsl,
+                            quantization: template_cache_quantization,
                         },
                     });
                 }
@@ -430,6 +494,7 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                                 current_seq_len: cache_k.current_seq_len,
                                 max_seq_len: cache_k.max_seq_len,
                                 capacity_seq_len: cache_k.capacity_seq_len,
+                                quantization: cache_k.quantization,
                             },
                             v: SingleCache {
                                 all_data: Some(v),
@@ -437,6 +502,7 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                                 current_seq_len: cache_v.current_seq_len,
                                 max_seq_len: cache_v.max_seq_len,
                                 capacity_seq_len: cache_v.capacity_seq_len,
+                                quantization: cache_v.quantization,
                             },
                         });
                     }
@@ -452,6 +518,7 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                                 max_seq_len: cache_k.max_seq_len,
                                 offset: cache_k.offset,
                                 capacity_seq_len: cache_k.capacity_seq_len,
+                                quantization: cache_k.quantization,
                             },
                             v: RotatingCache {
                                 all_data: Some(v),
@@ -460,6 +527,7 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                                 max_seq_len: cache_v.max_seq_len,
                                 offset: cache_v.offset,
                                 capacity_seq_len: cache_v.capacity_seq_len,
+                                quantization: cache_v.quantization,
                             },
                         });
                     }
@@ -497,7 +565,8 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
         let old_caches = pipeline.cache().normal().0.clone();
 
         for (layer_idx, layer) in pipeline.cache().normal().0.iter_mut().enumerate() {
-            if !load_preallocated_cache {
+            // Preallocated caches are in the model dtype, so quantized caches grow on demand instead.
+            if !load_preallocated_cache || layer.quantization().is_some() {
                 layer.reset();
                 continue;
             }
@@ -535,6 +604,7 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                 KvCache::Normal { k, .. } => {
                     let template_cache_dim = k.dim;
                     let template_cache_msl = k.max_seq_len;
+                    let template_cache_quantization = k.quantization;
 
                     let cache = KvCache::Normal {
                         k: SingleCache {
@@ -543,6 +613,7 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                             current_seq_len: 0,
                             max_seq_len: template_cache_msl,
                             capacity_seq_len: k_cache.dims()[template_cache_dim],
+                            quantization: template_cache_quantization,
                         },
                         v: SingleCache {
                             all_data: Some(v_cache.zeros_like().unwrap()),
@@ -550,6 +621,7 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                             current_seq_len: 0,
                             max_seq_len: template_cache_msl,
                             capacity_seq_len: k_cache.dims()[template_cache_dim],
+                            quantization: template_cache_quantization,
                         },
                     };
                     *layer = cache;
@@ -557,6 +629,7 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                 KvCache::Rotating { k, .. } => {
                     let template_cache_dim = k.dim;
                     let template_cache_msl = k.max_seq_len;
+                    let template_cache_quantization = k.quantization;
 
                     // Rotating cache is not preallocated.
                     let cache = KvCache::Rotating {
@@ -567,6 +640,7 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                             max_seq_len: template_cache_msl,
                             offset: 0,
                             capacity_seq_len: 0,
+                            quantization: template_cache_quantization,
                         },
                         v: RotatingCache {
                             all_data: None,
@@ -575,6 +649,7 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                             max_seq_len: template_cache_msl,
                             offset: 0,
                             capacity_seq_len: 0,
+                            quantization: template_cache_quantization,
                         },
                     };
                     *layer = cache;
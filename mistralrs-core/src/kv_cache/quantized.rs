@@ -0,0 +1,211 @@
+//! Quantized storage for the non-paged KV cache.
+//!
+//! A quantized cache stores its data as a `u8` tensor where the last (head) dimension of size `head_dim` is
+//! replaced by two dimensions, `(n_groups, group_bytes)`. Each group holds the quantized values of `group_size`
+//! consecutive elements followed by two bytes encoding their scale, so the batch and sequence dimensions are
+//! unchanged and the cache can be grown, sliced, batched and moved between devices like an unquantized one.
+//!
+//! The scale `s` of a group is `absmax / qmax`, stored as an exponent byte `e` and a mantissa byte `m` such
+//! that `s ~= 2^(e - 127) * (1 + m / 256)`. Values are quantized against the stored scale, so dequantization
+//! is `q * s` without any further correction.
+
+use std::{f64::consts::LN_2, str::FromStr};
+
+use candle_core::{DType, Result, Tensor, D};
+use serde::{Deserialize, Serialize};
+
+/// Group size along the head dimension. Head dimensions which are not a multiple of this use one group per head.
+const GROUP_SIZE: usize = 32;
+/// Offset of the stored scale exponent.
+const EXPONENT_BIAS: f64 = 127.;
+
+/// Quantized storage format of the non-paged KV cache.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "pyo3_macros", pyo3::pyclass(eq, eq_int))]
+pub enum KvCacheQuantization {
+    /// 8-bit integers with a scale per group, about half the memory of a 16-bit cache.
+    Int8,
+    /// 4-bit integers with a scale per group, about a quarter of the memory of a 16-bit cache.
+    Int4,
+}
+
+impl FromStr for KvCacheQuantization {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "int8" => Ok(Self::Int8),
+            "int4" => Ok(Self::Int4),
+            other => Err(format!(
+                "Unexpected `KvCacheQuantization`, got `{other}` but expected `int8` and `int4`."
+            )),
+        }
+    }
+}
+
+impl KvCacheQuantization {
+    fn qmax(&self) -> f64 {
+        match self {
+            Self::Int8 => 127.,
+            Self::Int4 => 7.,
+        }
+    }
+
+    fn group_size(head_dim: usize) -> usize {
+        if head_dim % GROUP_SIZE == 0 {
+            GROUP_SIZE
+        } else {
+            head_dim
+        }
+    }
+
+    /// Quantize `xs` of shape `(.., head_dim)` into the packed `u8` layout `(.., n_groups, group_bytes)`.
+    pub(crate) fn quantize(&self, xs: &Tensor) -> Result<Tensor> {
+        let mut dims = xs.dims().to_vec();
+        let head_dim = dims.pop().expect("KV cache tensors have a head dimension");
+        let group_size = Self::group_size(head_dim);
+        if *self == Self::Int4 && group_size % 2 != 0 {
+            candle_core::bail!(
+                "int4 KV cache quantization requires an even head dimension, got {head_dim}"
+            );
+        }
+        let n_groups = head_dim / group_size;
+
+        let xs = xs
+            .to_dtype(DType::F32)?
+            .reshape(with_tail(&dims, &[n_groups, group_size]))?;
+
+        // Encode the scale, then quantize against the value that will be decoded so both sides agree.
+        let scale = (xs.abs()?.max_keepdim(D::Minus1)? / self.qmax())?.clamp(1e-30, 1e30)?;
+        let exponent = ((scale.log()? / LN_2)?).floor()?;
+        let mantissa = ((scale / (exponent.clone() * LN_2)?.exp()?)? - 1.)?
+            .affine(256., 0.)?
+            .round()?
+            .clamp(0., 255.)?;
+        let scale = decode_scale(&(exponent.clone() + EXPONENT_BIAS)?, &mantissa)?;
+
+        let qmax = self.qmax();
+        let q = xs.broadcast_div(&scale)?.round()?.clamp(-qmax, qmax)?;
+        let q = match self {
+            Self::Int8 => (q + 128.)?,
+            Self::Int4 => {
+                // Pack consecutive pairs as `lo + 16 * hi`.
+                let q = (q + 8.)?.reshape(with_tail(&dims, &[n_groups, group_size / 2, 2]))?;
+                let lo = q.narrow(D::Minus1, 0, 1)?.squeeze(D::Minus1)?;
+                let hi = q.narrow(D::Minus1, 1, 1)?.squeeze(D::Minus1)?;
+                (lo + hi.affine(16., 0.)?)?
+            }
+        };
+
+        Tensor::cat(&[q, (exponent + EXPONENT_BIAS)?, mantissa], D::Minus1)?.to_dtype(DType::U8)
+    }
+
+    /// Dequantize packed data of shape `(.., n_groups, group_bytes)` into `(.., head_dim)` with the given dtype.
+    pub(crate) fn dequantize(&self, data: &Tensor, dtype: DType) -> Result<Tensor> {
+        let mut dims = data.dims().to_vec();
+        let group_bytes = dims
+            .pop()
+            .expect("quantized KV cache tensors have a group dimension");
+        let n_groups = dims
+            .pop()
+            .expect("quantized KV cache tensors have a group dimension");
+        let n_values = group_bytes - 2;
+
+        let data = data.to_dtype(DType::F32)?;
+        let scale = decode_scale(
+            &data.narrow(D::Minus1, n_values, 1)?,
+            &data.narrow(D::Minus1, n_values + 1, 1)?,
+        )?;
+        let q = data.narrow(D::Minus1, 0, n_values)?;
+        let (q, group_size) = match self {
+            Self::Int8 => ((q - 128.)?, n_values),
+            Self::Int4 => {
+                let hi = (q.clone() / 16.)?.floor()?;
+                let lo = (q - hi.affine(16., 0.)?)?;
+                let q = Tensor::stack(&[lo, hi], D::Minus1)?.flatten_from(D::Minus2)?;
+                ((q - 8.)?, 2 * n_values)
+            }
+        };
+
+        q.broadcast_mul(&scale)?
+            .reshape(with_tail(&dims, &[n_groups * group_size]))?
+            .to_dtype(dtype)
+    }
+}
+
+fn with_tail(dims: &[usize], tail: &[usize]) -> Vec<usize> {
+    dims.iter().chain(tail).copied().collect()
+}
+
+/// Scale from its biased exponent and mantissa bytes, both given as `f32`.
+fn decode_scale(exponent: &Tensor, mantissa: &Tensor) -> Result<Tensor> {
+    let pow = ((exponent - EXPONENT_BIAS)? * LN_2)?.exp()?;
+    pow * mantissa.affine(1. / 256., 1.)?
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor, D};
+
+    use super::KvCacheQuantization;
+    use crate::kv_cache::KvCache;
+
+    #[test]
+    fn test_roundtrip() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        for head_dim in [64, 80, 96] {
+            let xs = Tensor::randn(0f32, 3., (2, 4, 7, head_dim), &dev)?;
+            for (quant, bytes_per_value, tol) in [
+                (KvCacheQuantization::Int8, 1., 1. / 127.),
+                (KvCacheQuantization::Int4, 0.5, 1. / 7.),
+            ] {
+                let packed = quant.quantize(&xs)?;
+                assert_eq!(packed.dtype(), DType::U8);
+                assert_eq!(&packed.dims()[..3], &[2, 4, 7]);
+                let n_groups = packed.dim(3)?;
+                assert_eq!(
+                    packed.dim(4)?,
+                    (head_dim as f64 / n_groups as f64 * bytes_per_value) as usize + 2
+                );
+
+                let ys = quant.dequantize(&packed, DType::F32)?;
+                assert_eq!(ys.dims(), xs.dims());
+                // Errors stay within one quantization step of the head's absmax.
+                let absmax = xs.abs()?.max_keepdim(D::Minus1)?;
+                let err = (ys - &xs)?.abs()?.broadcast_div(&absmax)?;
+                let max_err = err.flatten_all()?.max(0)?.to_scalar::<f32>()?;
+                assert!(max_err <= tol as f32, "{quant:?} {head_dim}: {max_err}");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache_stores_packed_bytes() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        for (quant, group_bytes) in [
+            (KvCacheQuantization::Int8, 32 + 2),
+            (KvCacheQuantization::Int4, 16 + 2),
+        ] {
+            let mut cache = KvCache::new_normal(2, 1024, 4);
+            cache.set_quantization(Some(quant));
+            for seq_len in [5, 1] {
+                let k = Tensor::randn(0f32, 1., (1, 2, seq_len, 64), &dev)?.to_dtype(DType::F16)?;
+                let (out_k, _) = cache.append(&k, &k)?;
+                assert_eq!(out_k.dtype(), DType::F16);
+                assert_eq!(out_k.dims(), &[1, 2, cache.current_seq_len(), 64]);
+            }
+            let KvCache::Normal { k, v } = &cache else {
+                unreachable!()
+            };
+            for stored in [k.current_data()?.unwrap(), v.current_data()?.unwrap()] {
+                assert_eq!(stored.dtype(), DType::U8);
+                assert_eq!(stored.dims(), &[1, 2, 6, 2, group_bytes]);
+            }
+            // The buffer grows by the capacity, and only holds the packed data.
+            let buffer = k.all_data().unwrap();
+            assert_eq!(buffer.dtype(), DType::U8);
+            assert_eq!(buffer.elem_count(), 2 * buffer.dim(2)? * 2 * group_bytes);
+        }
+        Ok(())
+    }
+}
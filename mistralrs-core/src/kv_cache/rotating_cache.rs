@@ -1,6 +1,6 @@
 use candle_core::{Result, Tensor};
 
-use super::NormalCache;
+use super::{KvCacheQuantization, NormalCache};
 
 #[derive(Debug, Clone)]
 pub struct RotatingCache {
@@ -14,6 +14,8 @@ pub struct RotatingCache {
     // sequence to grow past this limit.
     pub max_seq_len: usize,
     pub capacity_seq_len: usize,
+    /// Storage format when the cache is quantized. `all_data` is then packed as described in `quantized`.
+    pub quantization: Option<KvCacheQuantization>,
 }
 
 impl RotatingCache {
@@ -25,6 +27,7 @@ impl RotatingCache {
             current_seq_len: 0,
             max_seq_len,
             capacity_seq_len,
+            quantization: None,
         }
     }
 
@@ -1,6 +1,6 @@
 use candle_core::{Result, Tensor};
 
-use super::NormalCache;
+use super::{KvCacheQuantization, NormalCache};
 
 #[derive(Debug, Clone)]
 pub struct SingleCache {
@@ -13,6 +13,8 @@ pub struct SingleCache {
     pub current_seq_len: usize,
     pub capacity_seq_len: usize,
     pub max_seq_len: usize,
+    /// Storage format when the cache is quantized. `all_data` is then packed as described in `quantized`.
+    pub quantization: Option<KvCacheQuantization>,
 }
 
 impl SingleCache {
@@ -23,6 +25,7 @@ impl SingleCache {
             current_seq_len: 0,
             max_seq_len,
             capacity_seq_len,
+            quantization: None,
         }
     }
 
@@ -1,10 +1,11 @@
 #![deny(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 use candle_core::Device;
-use engine::Engine;
 pub use engine::{
     get_engine_terminate_flag, reset_engine_terminate_flag, should_terminate_engine_sequences,
-    EngineInstruction, SearchEmbeddingModel, ENGINE_INSTRUCTIONS, TERMINATE_ALL_NEXT_STEP,
+    EngineInstruction, EngineStats, LatencyHistogram, SearchEmbeddingModel, ENGINE_INSTRUCTIONS,
+    TERMINATE_ALL_NEXT_STEP,
 };
+use engine::{Engine, IntervalLogger};
 use hf_hub::Cache;
 pub use lora::Ordering;
 pub use pipeline::ModelCategory;
@@ -20,11 +21,11 @@ use std::{
     error::Error,
     fs::OpenOptions,
     io::Write,
-    sync::{atomic::AtomicBool, Arc, Mutex, RwLock},
+    sync::{atomic::AtomicBool, Arc, Mutex, RwLock, Weak},
     thread::{self, JoinHandle},
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use tokio::sync::mpsc::{channel, Sender};
+use tokio::sync::mpsc::{channel, error::TrySendError, Sender};
 use tracing::info;
 use tracing::warn;
 
@@ -40,6 +41,7 @@ pub use model_loader::{
 };
 mod embedding_models;
 mod kv_cache;
+mod mcp_sampling;
 mod search;
 
 mod model_selected;
@@ -66,6 +68,7 @@ mod sampler;
 mod scheduler;
 mod sequence;
 mod speech_models;
+mod telemetry;
 pub mod think_tags;
 mod toml_selector;
 mod tools;
@@ -79,15 +82,20 @@ pub use device_map::{
     DeviceLayerMapMetadata, DeviceMapMetadata, DeviceMapSetting, LayerDeviceMapper,
 };
 pub use gguf::{GGUFArchitecture, GGUF_MULTI_FILE_DELIMITER};
+pub use kv_cache::KvCacheQuantization;
 pub use mistralrs_audio::AudioInput;
 pub use mistralrs_mcp::{
     CalledFunction, Function, Tool, ToolCallback, ToolCallbackWithTool, ToolType,
 };
 pub use mistralrs_mcp::{
-    McpClient, McpClientConfig, McpServerConfig, McpServerSource, McpToolInfo,
+    McpClient, McpClientConfig, McpContent, McpCreateMessageRequest, McpCreateMessageResult,
+    McpModelHint, McpModelPreferences, McpPrompt, McpPromptArgument, McpPromptInfo,
+    McpPromptMessage, McpPromptResult, McpResourceInfo, McpResourceTemplate,
+    McpResourceTemplateInfo, McpSamplingApproval, McpSamplingConfig, McpSamplingHandler,
+    McpSamplingMessage, McpServerConfig, McpServerSource, McpToolInfo,
 };
 pub use mistralrs_quant::{IsqType, MULTI_LORA_DELIMITER};
-pub use paged_attention::{MemoryGpuConfig, PagedAttentionConfig, PagedCacheType};
+pub use paged_attention::{MemoryGpuConfig, PagedAttentionConfig, PagedCacheType, PreemptionMode};
 pub use pipeline::{
     chat_template::ChatTemplate, parse_isq_value, AdapterPaths, AnyMoeLoader, AnyMoePipeline,
     AutoDeviceMapParams, AutoLoader, AutoLoaderBuilder, DiffusionGenerationParams, DiffusionLoader,
@@ -98,26 +106,33 @@ pub use pipeline::{
     Loader, LocalModelPaths, LoraAdapterPaths, MistralLoader, MixtralLoader, Modalities, ModelKind,
     ModelPaths, MultimodalPromptPrefixer, NormalLoader, NormalLoaderBuilder, NormalLoaderType,
     NormalSpecificConfig, Phi2Loader, Phi3Loader, Phi3VLoader, Qwen2Loader, SpeculativeConfig,
-    SpeculativeLoader, SpeculativePipeline, SpeechLoader, SpeechPipeline, Starcoder2Loader,
-    SupportedModality, TokenSource, VisionLoader, VisionLoaderBuilder, VisionLoaderType,
-    VisionSpecificConfig, UQFF_MULTI_FILE_DELIMITER,
+    SpeculativeDraft, SpeculativeLoader, SpeculativePipeline, SpeechLoader, SpeechPipeline,
+    Starcoder2Loader, SupportedModality, TokenSource, VisionLoader, VisionLoaderBuilder,
+    VisionLoaderType, VisionSpecificConfig, UQFF_MULTI_FILE_DELIMITER,
 };
+pub use prefix_cacher::PrefixCacheDiskConfig;
 pub use request::{
     ApproximateUserLocation, Constraint, DetokenizationRequest, ImageGenerationResponseFormat,
-    LlguidanceGrammar, MessageContent, NormalRequest, ReasoningEffort, Request, RequestMessage,
-    SearchContextSize, TokenizationRequest, WebSearchOptions, WebSearchUserLocation,
+    LlguidanceGrammar, LoadSessionRequest, MessageContent, NormalRequest, ReasoningEffort, Request,
+    RequestMessage, RequestPriority, SaveSessionRequest, SearchContextSize, TokenizationRequest,
+    WebSearchOptions, WebSearchUserLocation,
 };
 pub use response::*;
 pub use sampler::{
-    CustomLogitsProcessor, DrySamplingParams, SamplingParams, StopTokens, TopLogprob,
+    BeamSearchParams, CustomLogitsProcessor, DrySamplingParams, MirostatParams, SamplerKind,
+    SamplingParams, StopTokens, TopLogprob, XtcSamplingParams,
 };
 pub use scheduler::{DefaultSchedulerMethod, SchedulerConfig};
 pub use search::{SearchCallback, SearchFunctionParameters, SearchResult};
 use serde::Serialize;
 pub use speech_models::{utils as speech_utils, SpeechGenerationConfig, SpeechLoaderType};
+pub use telemetry::{current_traceparent, set_remote_parent, TRACEPARENT_HEADER};
 use tokio::runtime::Runtime;
 use toml_selector::{TomlLoaderArgs, TomlSelector};
-pub use tools::{ToolCallResponse, ToolCallType, ToolCallbacks, ToolChoice};
+pub use tools::{
+    partial_tool_calls, tool_call_parser, tool_call_parser_names, FunctionDelta, PartialToolCall,
+    ToolCallDelta, ToolCallParser, ToolCallResponse, ToolCallType, ToolCallbacks, ToolChoice,
+};
 pub use topology::{LayerTopology, Topology};
 pub use utils::debug::initialize_logging;
 pub use utils::memory_usage::MemoryUsage;
@@ -137,12 +152,19 @@ pub struct EngineConfig {
     pub no_kv_cache: bool,
     pub no_prefix_cache: bool,
     pub prefix_cache_n: usize,
+    pub prefix_cache_disk: Option<PrefixCacheDiskConfig>,
+    pub kv_cache_quantization: Option<KvCacheQuantization>,
     pub disable_eos_stop: bool,
     pub throughput_logging_enabled: bool,
     pub search_embedding_model: Option<SearchEmbeddingModel>,
     pub search_callback: Option<Arc<SearchCallback>>,
     pub tool_callbacks: tools::ToolCallbacks,
     pub tool_callbacks_with_tools: tools::ToolCallbacksWithTools,
+    /// Parser of the tool call format. It is detected from the chat template if not set.
+    pub tool_parser: Option<Arc<dyn ToolCallParser>>,
+    /// Connected MCP client whose tools are available to the model. The tools are kept up to
+    /// date as the MCP servers change their tool lists.
+    pub mcp_client: Option<Arc<McpClient>>,
 }
 
 impl Default for EngineConfig {
@@ -151,12 +173,16 @@ impl Default for EngineConfig {
             no_kv_cache: false,
             no_prefix_cache: false,
             prefix_cache_n: 16,
+            prefix_cache_disk: None,
+            kv_cache_quantization: None,
             disable_eos_stop: false,
             throughput_logging_enabled: true,
             search_embedding_model: None,
             search_callback: None,
             tool_callbacks: HashMap::new(),
             tool_callbacks_with_tools: HashMap::new(),
+            tool_parser: None,
+            mcp_client: None,
         }
     }
 }
@@ -198,6 +224,7 @@ struct EngineInstance {
     reboot_state: RebootState,
     config: MistralRsConfig,
     category: ModelCategory,
+    logger: IntervalLogger,
 }
 
 /// The MistralRs struct handles sending requests to multiple engines.
@@ -211,6 +238,10 @@ pub struct MistralRs {
     id: String,
     creation_time: u64,
     next_request_id: Mutex<RefCell<usize>>,
+    default_request_timeout: Option<Duration>,
+    mcp_sampling_approval: Option<McpSamplingApproval>,
+    /// Handed to the MCP clients to serve sampling requests, without keeping the instance alive
+    weak_self: Weak<MistralRs>,
 }
 
 #[derive(Clone)]
@@ -220,13 +251,17 @@ struct RebootState {
     no_kv_cache: bool,
     no_prefix_cache: bool,
     prefix_cache_n: usize,
+    prefix_cache_disk: Option<PrefixCacheDiskConfig>,
+    kv_cache_quantization: Option<KvCacheQuantization>,
     disable_eos_stop: bool,
     throughput_logging_enabled: bool,
     search_embedding_model: Option<SearchEmbeddingModel>,
     search_callback: Option<Arc<search::SearchCallback>>,
     tool_callbacks: tools::ToolCallbacks,
     tool_callbacks_with_tools: tools::ToolCallbacksWithTools,
+    tool_parser: Option<Arc<dyn ToolCallParser>>,
     mcp_client_config: Option<McpClientConfig>,
+    mcp_client: Option<Arc<McpClient>>,
 }
 
 #[derive(Debug)]
@@ -260,13 +295,18 @@ pub struct MistralRsBuilder {
     no_kv_cache: Option<bool>,
     no_prefix_cache: Option<bool>,
     prefix_cache_n: Option<usize>,
+    prefix_cache_disk: Option<PrefixCacheDiskConfig>,
+    kv_cache_quantization: Option<KvCacheQuantization>,
     disable_eos_stop: Option<bool>,
     throughput_logging_enabled: bool,
     search_embedding_model: Option<SearchEmbeddingModel>,
     search_callback: Option<Arc<SearchCallback>>,
     tool_callbacks: tools::ToolCallbacks,
     tool_callbacks_with_tools: tools::ToolCallbacksWithTools,
+    tool_parser: Option<Arc<dyn ToolCallParser>>,
     mcp_client_config: Option<McpClientConfig>,
+    mcp_sampling_approval: Option<McpSamplingApproval>,
+    default_request_timeout: Option<Duration>,
 }
 
 impl MistralRsBuilder {
@@ -286,13 +326,18 @@ impl MistralRsBuilder {
             no_kv_cache: None,
             no_prefix_cache: None,
             prefix_cache_n: None,
+            prefix_cache_disk: None,
+            kv_cache_quantization: None,
             disable_eos_stop: None,
             throughput_logging_enabled: throughput_logging,
             search_embedding_model,
             search_callback: None,
             tool_callbacks: HashMap::new(),
             tool_callbacks_with_tools: HashMap::new(),
+            tool_parser: None,
             mcp_client_config: None,
+            mcp_sampling_approval: None,
+            default_request_timeout: None,
         }
     }
     pub fn with_log(mut self, log: String) -> Self {
@@ -315,6 +360,19 @@ impl MistralRsBuilder {
         self.prefix_cache_n = Some(prefix_cache_n);
         self
     }
+    /// Persist evicted prefix caches to disk so they can be reused across restarts.
+    pub fn with_prefix_cache_disk(mut self, prefix_cache_disk: PrefixCacheDiskConfig) -> Self {
+        self.prefix_cache_disk = Some(prefix_cache_disk);
+        self
+    }
+    /// Store the KV cache quantized when PagedAttention is not used.
+    pub fn with_kv_cache_quantization(
+        mut self,
+        kv_cache_quantization: KvCacheQuantization,
+    ) -> Self {
+        self.kv_cache_quantization = Some(kv_cache_quantization);
+        self
+    }
     pub fn with_disable_eos_stop(mut self, disable_eos_stop: bool) -> Self {
         self.disable_eos_stop = Some(disable_eos_stop);
         self
@@ -355,12 +413,32 @@ impl MistralRsBuilder {
         self
     }
 
+    /// Parse tool calls with this parser instead of detecting the format from the chat template.
+    pub fn with_tool_parser(mut self, tool_parser: Arc<dyn ToolCallParser>) -> Self {
+        self.tool_parser = Some(tool_parser);
+        self
+    }
+
     /// Configure MCP client to connect to external MCP servers.
     pub fn with_mcp_client(mut self, config: McpClientConfig) -> Self {
         self.mcp_client_config = Some(config);
         self
     }
 
+    /// Decide whether to serve each sampling request of the MCP servers, which are otherwise
+    /// all served when sampling is enabled in the MCP client configuration.
+    pub fn with_mcp_sampling_approval(mut self, approval: McpSamplingApproval) -> Self {
+        self.mcp_sampling_approval = Some(approval);
+        self
+    }
+
+    /// Timeout for requests which do not specify their own. This is not applied by the engine,
+    /// but is exposed through [`MistralRs::default_request_timeout`] for frontends to use.
+    pub fn with_default_request_timeout(mut self, timeout: Duration) -> Self {
+        self.default_request_timeout = Some(timeout);
+        self
+    }
+
     pub async fn build(self) -> Arc<MistralRs> {
         MistralRs::new(self).await
     }
@@ -379,6 +457,38 @@ impl Drop for MistralRs {
 }
 
 impl MistralRs {
+    /// Connect to the configured MCP servers. MCP is disabled if the client fails to initialize.
+    async fn init_mcp_client(config: &McpClientConfig) -> Option<Arc<McpClient>> {
+        let mut mcp_client = McpClient::new(config.clone());
+        let total_servers = config.servers.len();
+
+        match mcp_client.initialize().await {
+            Ok(()) => {
+                let tools_count = mcp_client.get_tools().len();
+                if tools_count == 0 {
+                    warn!(
+                        "MCP client initialized but no tools were registered from {} servers",
+                        total_servers
+                    );
+                } else {
+                    info!(
+                        "MCP client initialized successfully with {} tools from {} servers",
+                        tools_count, total_servers
+                    );
+                }
+                Some(Arc::new(mcp_client))
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to initialize MCP client with {} configured servers: {}",
+                    total_servers, e
+                );
+                warn!("Continuing without MCP functionality. Check your MCP configuration and server availability.");
+                None
+            }
+        }
+    }
+
     /// Create an engine instance with the given configuration
     fn create_engine_instance(
         pipeline: Arc<tokio::sync::Mutex<dyn Pipeline>>,
@@ -386,6 +496,16 @@ impl MistralRs {
         config: EngineConfig,
         reboot_state: RebootState,
     ) -> Result<EngineInstance, String> {
+        if config.prefix_cache_disk.is_some()
+            && !config.no_prefix_cache
+            && matches!(method, SchedulerConfig::PagedAttentionMeta { .. })
+        {
+            return Err(
+                "The prefix cache disk tier is not supported with PagedAttention, disable one of them."
+                    .to_string(),
+            );
+        }
+
         let (tx, rx) = channel(10_000);
 
         let pipeline_guard = pipeline.try_lock().unwrap();
@@ -411,7 +531,10 @@ impl MistralRs {
             max_seq_len,
         };
 
+        let logger = IntervalLogger::new(Duration::from_secs(5));
+
         let tx_for_engine = tx.clone();
+        let engine_logger = logger.clone();
         let engine_handler = thread::spawn(move || {
             #[cfg(feature = "metal")]
             objc::rc::autoreleasepool(move || {
@@ -425,12 +548,17 @@ impl MistralRs {
                         config.no_kv_cache,
                         config.no_prefix_cache,
                         config.prefix_cache_n,
+                        config.prefix_cache_disk.clone(),
+                        config.kv_cache_quantization,
                         config.disable_eos_stop,
                         config.throughput_logging_enabled,
                         config.search_embedding_model,
                         config.search_callback.clone(),
                         config.tool_callbacks.clone(),
                         config.tool_callbacks_with_tools.clone(),
+                        config.tool_parser.clone(),
+                        config.mcp_client.clone(),
+                        engine_logger,
                     )
                     .expect("Engine creation failed.");
                     Arc::new(engine).run().await;
@@ -449,12 +577,17 @@ impl MistralRs {
                         config.no_kv_cache,
                         config.no_prefix_cache,
                         config.prefix_cache_n,
+                        config.prefix_cache_disk.clone(),
+                        config.kv_cache_quantization,
                         config.disable_eos_stop,
                         config.throughput_logging_enabled,
                         config.search_embedding_model,
                         config.search_callback.clone(),
                         config.tool_callbacks.clone(),
                         config.tool_callbacks_with_tools.clone(),
+                        config.tool_parser.clone(),
+                        config.mcp_client.clone(),
+                        engine_logger,
                     )
                     .expect("Engine creation failed.");
                     Arc::new(engine).run().await;
@@ -468,6 +601,7 @@ impl MistralRs {
             reboot_state,
             config: mistralrs_config,
             category,
+            logger,
         })
     }
 
@@ -479,13 +613,18 @@ impl MistralRs {
             no_kv_cache,
             no_prefix_cache,
             prefix_cache_n,
+            prefix_cache_disk,
+            kv_cache_quantization,
             disable_eos_stop,
             throughput_logging_enabled,
             search_embedding_model,
             search_callback,
             tool_callbacks,
-            mut tool_callbacks_with_tools,
+            tool_callbacks_with_tools,
+            tool_parser,
             mcp_client_config,
+            mcp_sampling_approval,
+            default_request_timeout,
         } = config;
 
         mistralrs_quant::cublaslt::maybe_init_cublas_lt_wrapper(
@@ -502,6 +641,7 @@ impl MistralRs {
             );
             SchedulerConfig::DefaultScheduler {
                 method: DefaultSchedulerMethod::Fixed(NonZeroUsize::new(1).unwrap()),
+                max_num_batched_tokens: None,
             }
         } else {
             method
@@ -513,41 +653,10 @@ impl MistralRs {
         let disable_eos_stop = disable_eos_stop.unwrap_or(false);
 
         // Initialize MCP client if configured
-        if let Some(config) = &mcp_client_config {
-            let mut mcp_client = McpClient::new(config.clone());
-            let total_servers = config.servers.len();
-
-            match mcp_client.initialize().await {
-                Ok(()) => {
-                    let mcp_callbacks_with_tools = mcp_client.get_tool_callbacks_with_tools();
-                    let tools_count = mcp_callbacks_with_tools.len();
-
-                    // Merge MCP tool callbacks with tools into the new collection
-                    for (name, callback_with_tool) in mcp_callbacks_with_tools {
-                        tool_callbacks_with_tools.insert(name.clone(), callback_with_tool.clone());
-                    }
-
-                    if tools_count == 0 {
-                        warn!(
-                            "MCP client initialized but no tools were registered from {} servers",
-                            total_servers
-                        );
-                    } else {
-                        info!(
-                            "MCP client initialized successfully with {} tools from {} servers",
-                            tools_count, total_servers
-                        );
-                    }
-                }
-                Err(e) => {
-                    warn!(
-                        "Failed to initialize MCP client with {} configured servers: {}",
-                        total_servers, e
-                    );
-                    warn!("Continuing without MCP functionality. Check your MCP configuration and server availability.");
-                }
-            }
-        }
+        let mcp_client = match &mcp_client_config {
+            Some(config) => Self::init_mcp_client(config).await,
+            None => None,
+        };
 
         let reboot_state = RebootState {
             pipeline: pipeline.clone(),
@@ -555,13 +664,17 @@ impl MistralRs {
             no_kv_cache,
             no_prefix_cache,
             prefix_cache_n,
+            prefix_cache_disk: prefix_cache_disk.clone(),
+            kv_cache_quantization,
             disable_eos_stop,
             throughput_logging_enabled,
             search_embedding_model,
             search_callback: search_callback.clone(),
             tool_callbacks: tool_callbacks.clone(),
             tool_callbacks_with_tools: tool_callbacks_with_tools.clone(),
+            tool_parser: tool_parser.clone(),
             mcp_client_config: mcp_client_config.clone(),
+            mcp_client: mcp_client.clone(),
         };
 
         // Create the engine configuration
@@ -569,12 +682,16 @@ impl MistralRs {
             no_kv_cache,
             no_prefix_cache,
             prefix_cache_n,
+            prefix_cache_disk,
+            kv_cache_quantization,
             disable_eos_stop,
             throughput_logging_enabled,
             search_embedding_model,
             search_callback,
             tool_callbacks,
             tool_callbacks_with_tools,
+            tool_parser,
+            mcp_client: mcp_client.clone(),
         };
 
         // Create the engine instance
@@ -637,6 +754,10 @@ impl MistralRs {
                     web_search_options: None,
                     model_id: None,
                     truncate_sequence: false,
+                    priority: RequestPriority::default(),
+                    timeout: None,
+                    prompt_logprobs: None,
+                    traceparent: None,
                 }));
                 info!("Beginning dummy run.");
                 let start = Instant::now();
@@ -664,7 +785,7 @@ impl MistralRs {
         let mut engines = HashMap::new();
         engines.insert(id.clone(), engine_instance);
 
-        Arc::new(Self {
+        let mistralrs = Arc::new_cyclic(|weak_self| Self {
             engines: RwLock::new(engines),
             default_engine_id: RwLock::new(Some(id.clone())),
             log,
@@ -674,7 +795,31 @@ impl MistralRs {
                 .expect("Time travel has occurred!")
                 .as_secs(),
             next_request_id: Mutex::new(RefCell::new(1)),
-        })
+            default_request_timeout,
+            mcp_sampling_approval,
+            weak_self: weak_self.clone(),
+        });
+
+        if let Some(mcp_client) = &mcp_client {
+            mistralrs.serve_mcp_sampling(mcp_client);
+        }
+
+        mistralrs
+    }
+
+    /// Serve the sampling requests of the MCP client's servers with the models of this instance,
+    /// if sampling is enabled in its configuration.
+    fn serve_mcp_sampling(&self, mcp_client: &McpClient) {
+        let Some(config) = mcp_client.sampling_config() else {
+            return;
+        };
+        mcp_client.set_sampling_handler(Arc::new(mcp_sampling::MistralRsSamplingHandler::new(
+            self.weak_self.clone(),
+            config.clone(),
+        )));
+        if let Some(approval) = &self.mcp_sampling_approval {
+            mcp_client.set_sampling_approval(approval.clone());
+        }
     }
 
     /// Attempts to reboot a specific engine by model_id
@@ -695,12 +840,16 @@ impl MistralRs {
                 no_kv_cache: reboot_state.no_kv_cache,
                 no_prefix_cache: reboot_state.no_prefix_cache,
                 prefix_cache_n: reboot_state.prefix_cache_n,
+                prefix_cache_disk: reboot_state.prefix_cache_disk.clone(),
+                kv_cache_quantization: reboot_state.kv_cache_quantization,
                 disable_eos_stop: reboot_state.disable_eos_stop,
                 throughput_logging_enabled: reboot_state.throughput_logging_enabled,
                 search_embedding_model: reboot_state.search_embedding_model,
                 search_callback: reboot_state.search_callback.clone(),
                 tool_callbacks: reboot_state.tool_callbacks.clone(),
                 tool_callbacks_with_tools: reboot_state.tool_callbacks_with_tools.clone(),
+                tool_parser: reboot_state.tool_parser.clone(),
+                mcp_client: reboot_state.mcp_client.clone(),
             };
             let new_engine_instance = Self::create_engine_instance(
                 reboot_state.pipeline.clone(),
@@ -774,6 +923,11 @@ impl MistralRs {
         self.creation_time
     }
 
+    /// Timeout to use for requests which do not specify one, if configured.
+    pub fn default_request_timeout(&self) -> Option<Duration> {
+        self.default_request_timeout
+    }
+
     /// Get model category for a specific model. If model_id is None, uses default engine.
     pub fn get_model_category(
         &self,
@@ -834,6 +988,33 @@ impl MistralRs {
         }
     }
 
+    /// Get a snapshot of the scheduling, KV cache and throughput statistics of a model's engine.
+    pub fn engine_stats(&self, model_id: Option<&str>) -> Result<EngineStats, MistralRsError> {
+        let resolved_model_id = match model_id {
+            Some(id) => id.to_string(),
+            None => {
+                let default_lock = self
+                    .default_engine_id
+                    .read()
+                    .map_err(|_| MistralRsError::SenderPoisoned)?;
+                default_lock
+                    .as_ref()
+                    .ok_or(MistralRsError::EnginePoisoned)?
+                    .clone()
+            }
+        };
+
+        let engines = self
+            .engines
+            .read()
+            .map_err(|_| MistralRsError::SenderPoisoned)?;
+        if let Some(engine_instance) = engines.get(&resolved_model_id) {
+            Ok(engine_instance.logger.stats())
+        } else {
+            Err(MistralRsError::EnginePoisoned)
+        }
+    }
+
     pub fn next_request_id(&self) -> usize {
         let l = self.next_request_id.lock().unwrap();
         let last = &mut *l.borrow_mut();
@@ -848,7 +1029,7 @@ impl MistralRs {
         model_id: String,
         pipeline: Arc<tokio::sync::Mutex<dyn Pipeline>>,
         method: SchedulerConfig,
-        config: AddModelConfig,
+        mut config: AddModelConfig,
     ) -> Result<(), String> {
         // For hybrid models (Mamba-Attention), force batch_size=1 to prevent state bleeding
         let method = {
@@ -859,25 +1040,39 @@ impl MistralRs {
                 );
                 SchedulerConfig::DefaultScheduler {
                     method: DefaultSchedulerMethod::Fixed(NonZeroUsize::new(1).unwrap()),
+                    max_num_batched_tokens: None,
                 }
             } else {
                 method
             }
         };
 
+        if config.engine_config.mcp_client.is_none() {
+            if let Some(mcp_config) = &config.mcp_client_config {
+                config.engine_config.mcp_client = Self::init_mcp_client(mcp_config).await;
+                if let Some(mcp_client) = &config.engine_config.mcp_client {
+                    self.serve_mcp_sampling(mcp_client);
+                }
+            }
+        }
+
         let reboot_state = RebootState {
             pipeline: pipeline.clone(),
             method: method.clone(),
             no_kv_cache: config.engine_config.no_kv_cache,
             no_prefix_cache: config.engine_config.no_prefix_cache,
             prefix_cache_n: config.engine_config.prefix_cache_n,
+            prefix_cache_disk: config.engine_config.prefix_cache_disk.clone(),
+            kv_cache_quantization: config.engine_config.kv_cache_quantization,
             disable_eos_stop: config.engine_config.disable_eos_stop,
             throughput_logging_enabled: config.engine_config.throughput_logging_enabled,
             search_embedding_model: config.engine_config.search_embedding_model,
             search_callback: config.engine_config.search_callback.clone(),
             tool_callbacks: config.engine_config.tool_callbacks.clone(),
             tool_callbacks_with_tools: config.engine_config.tool_callbacks_with_tools.clone(),
+            tool_parser: config.engine_config.tool_parser.clone(),
             mcp_client_config: config.mcp_client_config.clone(),
+            mcp_client: config.engine_config.mcp_client.clone(),
         };
 
         let engine_instance =
@@ -901,6 +1096,24 @@ impl MistralRs {
         Ok(())
     }
 
+    /// Terminate every engine and wait for it to stop, so that its state is dropped cleanly before
+    /// the process exits. No request can be served afterwards. This blocks the calling thread.
+    pub fn shutdown(&self) {
+        let engines = match self.engines.write() {
+            Ok(mut engines) => std::mem::take(&mut *engines),
+            Err(_) => return,
+        };
+        for engine_instance in engines.values() {
+            // The engine is idle, or checks for new requests before its next step.
+            let _ = engine_instance.sender.try_send(Request::Terminate);
+        }
+        for (model_id, engine_instance) in engines {
+            if engine_instance.engine_handler.join().is_err() {
+                warn!("The engine of model `{model_id}` panicked while shutting down");
+            }
+        }
+    }
+
     /// Remove a model engine from the MistralRs instance
     pub fn remove_model(&self, model_id: &str) -> Result<(), String> {
         let mut engines = self
@@ -985,6 +1198,35 @@ impl MistralRs {
             .map_err(|_| MistralRsError::SenderPoisoned)
     }
 
+    /// Cancel the in-flight request with the given ID on the engine for `model_id`. Its sequences
+    /// are finished with the `canceled` finish reason on the next step, including sequences
+    /// which are still waiting to be scheduled.
+    ///
+    /// This does not block, so it is safe to call from `Drop` implementations. If the request
+    /// channel is full, the cancellation is sent in the background.
+    pub fn cancel_request(&self, id: usize, model_id: Option<&str>) -> Result<(), MistralRsError> {
+        let sender = self.get_sender(model_id)?;
+        match sender.try_send(Request::Cancel { id }) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(request)) => {
+                match tokio::runtime::Handle::try_current() {
+                    Ok(handle) => {
+                        handle.spawn(async move {
+                            let _ = sender.send(request).await;
+                        });
+                    }
+                    Err(_) => {
+                        thread::spawn(move || {
+                            let _ = sender.blocking_send(request);
+                        });
+                    }
+                }
+                Ok(())
+            }
+            Err(TrySendError::Closed(_)) => Err(MistralRsError::SenderPoisoned),
+        }
+    }
+
     pub fn maybe_log_request(this: Arc<Self>, repr: String) {
         if let Some(file) = &this.log {
             let mut f = OpenOptions::new()
@@ -1046,7 +1288,12 @@ impl MistralRs {
             .read()
             .map_err(|_| "Failed to acquire read lock on engines")?;
         if let Some(engine_instance) = engines.get(&resolved_model_id) {
-            Ok(engine_instance.reboot_state.tool_callbacks_with_tools.len())
+            let mcp_tools_count = engine_instance
+                .reboot_state
+                .mcp_client
+                .as_ref()
+                .map_or(0, |client| client.get_tool_callbacks_with_tools().len());
+            Ok(engine_instance.reboot_state.tool_callbacks_with_tools.len() + mcp_tools_count)
         } else {
             Err(format!("Model {resolved_model_id} not found"))
         }
@@ -1079,6 +1326,34 @@ impl MistralRs {
         }
     }
 
+    /// Get the connected MCP client of a specific model, if MCP is configured and the client
+    /// initialized successfully
+    pub fn mcp_client(&self, model_id: Option<&str>) -> Result<Option<Arc<McpClient>>, String> {
+        let resolved_model_id = match model_id {
+            Some(id) => id.to_string(),
+            None => {
+                let default_lock = self
+                    .default_engine_id
+                    .read()
+                    .map_err(|_| "Failed to acquire read lock")?;
+                default_lock
+                    .as_ref()
+                    .ok_or("No default engine set")?
+                    .clone()
+            }
+        };
+
+        let engines = self
+            .engines
+            .read()
+            .map_err(|_| "Failed to acquire read lock on engines")?;
+        if let Some(engine_instance) = engines.get(&resolved_model_id) {
+            Ok(engine_instance.reboot_state.mcp_client.clone())
+        } else {
+            Err(format!("Model {resolved_model_id} not found"))
+        }
+    }
+
     /// Get config for a specific model
     pub fn config(&self, model_id: Option<&str>) -> Result<MistralRsConfig, String> {
         let resolved_model_id = match model_id {
@@ -0,0 +1,217 @@
+//! Serves the `sampling/createMessage` requests of MCP servers with the models of a
+//! [`MistralRs`] instance.
+
+use std::sync::Weak;
+
+use anyhow::Result;
+use either::Either;
+use indexmap::IndexMap;
+use mistralrs_mcp::{
+    McpContent, McpCreateMessageRequest, McpCreateMessageResult, McpSamplingConfig,
+    McpSamplingHandler,
+};
+use tokio::sync::mpsc::channel;
+
+use crate::{
+    Constraint, MistralRs, NormalRequest, Request, RequestMessage, RequestPriority, Response,
+    SamplingParams, StopTokens, ToolChoice,
+};
+
+/// Generates the messages requested by MCP servers with a model of the instance.
+///
+/// Holds the instance weakly, as the instance owns the MCP client which owns this handler.
+pub(crate) struct MistralRsSamplingHandler {
+    mistralrs: Weak<MistralRs>,
+    config: McpSamplingConfig,
+}
+
+impl MistralRsSamplingHandler {
+    pub(crate) fn new(mistralrs: Weak<MistralRs>, config: McpSamplingConfig) -> Self {
+        Self { mistralrs, config }
+    }
+
+    /// The first loaded model matching a hint of the request if enabled, else the configured
+    /// model, else the default model.
+    fn select_model(
+        &self,
+        mistralrs: &MistralRs,
+        request: &McpCreateMessageRequest,
+    ) -> Result<String> {
+        if self.config.use_model_hints {
+            let models = mistralrs.list_models().map_err(anyhow::Error::msg)?;
+            if let Some(model) = model_matching_hints(&models, request) {
+                return Ok(model);
+            }
+        }
+
+        match &self.config.model {
+            Some(model) => Ok(model.clone()),
+            None => mistralrs
+                .get_default_model_id()
+                .map_err(anyhow::Error::msg)?
+                .ok_or_else(|| anyhow::anyhow!("No model is loaded to serve sampling requests")),
+        }
+    }
+}
+
+/// The first of `models` whose ID contains a model hint of the request, trying the hints in order
+/// and ignoring case.
+fn model_matching_hints(models: &[String], request: &McpCreateMessageRequest) -> Option<String> {
+    request
+        .model_preferences
+        .iter()
+        .flat_map(|preferences| preferences.hints.iter().flatten())
+        .filter_map(|hint| hint.name.as_deref())
+        .map(str::to_lowercase)
+        .find_map(|hint| {
+            models
+                .iter()
+                .find(|model| model.to_lowercase().contains(&hint))
+                .cloned()
+        })
+}
+
+#[async_trait::async_trait]
+impl McpSamplingHandler for MistralRsSamplingHandler {
+    async fn create_message(
+        &self,
+        server_id: &str,
+        request: McpCreateMessageRequest,
+    ) -> Result<McpCreateMessageResult> {
+        let mistralrs = self
+            .mistralrs
+            .upgrade()
+            .ok_or_else(|| anyhow::anyhow!("The models serving sampling requests were dropped"))?;
+        let model = self.select_model(&mistralrs, &request)?;
+
+        let mut messages = Vec::new();
+        if let Some(system_prompt) = &request.system_prompt {
+            messages.push(IndexMap::from([
+                ("role".to_string(), Either::Left("system".to_string())),
+                ("content".to_string(), Either::Left(system_prompt.clone())),
+            ]));
+        }
+        for message in &request.messages {
+            let text = match &message.content {
+                McpContent::Text { text } => text.clone(),
+                McpContent::Image { .. } => {
+                    anyhow::bail!("Image content is not supported in sampling requests")
+                }
+                content @ McpContent::Resource { .. } => content.to_string(),
+            };
+            messages.push(IndexMap::from([
+                ("role".to_string(), Either::Left(message.role.clone())),
+                ("content".to_string(), Either::Left(text)),
+            ]));
+        }
+
+        let sampling_params = SamplingParams {
+            temperature: request.temperature,
+            top_k: request.temperature.is_none().then_some(1),
+            stop_toks: request.stop_sequences.clone().map(StopTokens::Seqs),
+            max_len: Some(request.max_tokens),
+            ..SamplingParams::deterministic()
+        };
+
+        let (tx, mut rx) = channel(1);
+        let normal_request = Request::Normal(Box::new(NormalRequest {
+            id: mistralrs.next_request_id(),
+            messages: RequestMessage::Chat {
+                messages,
+                enable_thinking: None,
+                reasoning_effort: None,
+            },
+            sampling_params,
+            response: tx,
+            return_logprobs: false,
+            is_streaming: false,
+            constraint: Constraint::None,
+            suffix: None,
+            // Sampling has no access to the tools, which could call back into the server
+            tool_choice: Some(ToolChoice::None),
+            tools: None,
+            logits_processors: None,
+            return_raw_logits: false,
+            web_search_options: None,
+            model_id: Some(model.clone()),
+            truncate_sequence: false,
+            priority: RequestPriority::default(),
+            timeout: mistralrs.default_request_timeout(),
+            prompt_logprobs: None,
+            traceparent: None,
+        }));
+        tracing::debug!("Serving sampling request of MCP server `{server_id}` with `{model}`");
+        mistralrs
+            .get_sender(Some(&model))?
+            .send(normal_request)
+            .await
+            .map_err(|_| anyhow::anyhow!("The model `{model}` is not running"))?;
+
+        let response = match rx.recv().await {
+            Some(Response::Done(response)) => response,
+            Some(Response::ModelError(e, _)) => anyhow::bail!(e),
+            Some(Response::InternalError(e)) | Some(Response::ValidationError(e)) => {
+                anyhow::bail!(e.to_string())
+            }
+            Some(_) => anyhow::bail!("Unexpected response to sampling request"),
+            None => anyhow::bail!("The model dropped the sampling request"),
+        };
+        let choice = response
+            .choices
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("The model generated no message"))?;
+
+        Ok(McpCreateMessageResult {
+            role: "assistant".to_string(),
+            content: McpContent::Text {
+                text: choice.message.content.unwrap_or_default(),
+            },
+            model,
+            stop_reason: Some(
+                match (choice.finish_reason.as_str(), &choice.stop_sequence) {
+                    ("length", _) => "maxTokens",
+                    (_, Some(_)) => "stopSequence",
+                    _ => "endTurn",
+                }
+                .to_string(),
+            ),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mistralrs_mcp::McpCreateMessageRequest;
+
+    use super::model_matching_hints;
+
+    #[test]
+    fn matches_model_hints_in_order() {
+        let models = ["Qwen/Qwen3-4B", "google/gemma-3-4b-it"].map(String::from);
+        let request = |hints: serde_json::Value| {
+            serde_json::from_value::<McpCreateMessageRequest>(serde_json::json!({
+                "messages": [],
+                "maxTokens": 16,
+                "modelPreferences": { "hints": hints },
+            }))
+            .unwrap()
+        };
+
+        assert_eq!(
+            model_matching_hints(&models, &request(serde_json::json!([{ "name": "GEMMA" }]))),
+            Some("google/gemma-3-4b-it".to_string())
+        );
+        assert_eq!(
+            model_matching_hints(
+                &models,
+                &request(serde_json::json!([{ "name": "claude" }, { "name": "qwen3" }, {}]))
+            ),
+            Some("Qwen/Qwen3-4B".to_string())
+        );
+        assert_eq!(
+            model_matching_hints(&models, &request(serde_json::json!([{ "name": "llama" }]))),
+            None
+        );
+    }
+}
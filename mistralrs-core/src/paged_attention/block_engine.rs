@@ -47,8 +47,8 @@ impl LogicalTokenBlock {
 
     pub fn pop_token(&mut self) {
         assert_ne!(self.num_tokens, 0);
-        self.tokens.pop();
         self.num_tokens -= 1;
+        self.tokens[self.num_tokens] = 0;
     }
 
     pub fn toks(&self) -> &[usize] {
@@ -236,11 +236,20 @@ pub struct BlockEngine {
     prefix_cacher: PrefixCacher,
     /// Track number of cached blocks used per sequence (for releasing cache entries).
     cached_blocks_per_seq: HashMap<SeqID, usize>,
+    /// The CPU block pool which sequences are swapped out to.
+    cpu_pool: BlockPool,
+    /// Maps swapped out sequence ID to its table of CPU blocks.
+    swapped_block_tables: HashMap<SeqID, BlockTable>,
 }
 
 impl BlockEngine {
     #[must_use]
-    pub fn new(block_size: usize, num_gpu_blocks: usize, prefix_caching_enabled: bool) -> Self {
+    pub fn new(
+        block_size: usize,
+        num_gpu_blocks: usize,
+        num_cpu_blocks: usize,
+        prefix_caching_enabled: bool,
+    ) -> Self {
         Self {
             num_gpu_blocks,
             block_size,
@@ -248,6 +257,8 @@ impl BlockEngine {
             block_tables: HashMap::new(),
             prefix_cacher: PrefixCacher::new(prefix_caching_enabled),
             cached_blocks_per_seq: HashMap::new(),
+            cpu_pool: BlockPool::new(block_size, num_cpu_blocks),
+            swapped_block_tables: HashMap::new(),
         }
     }
 
@@ -265,6 +276,29 @@ impl BlockEngine {
         self.block_size
     }
 
+    /// Total number of GPU blocks.
+    pub fn num_gpu_blocks(&self) -> usize {
+        self.num_gpu_blocks
+    }
+
+    /// Number of GPU blocks in the free pool, not counting evictable cached blocks.
+    pub fn num_free_blocks(&self) -> usize {
+        self.pool.num_free()
+    }
+
+    /// Number of blocks that can be handed out right now: free blocks plus evictable cached blocks.
+    pub fn num_available_blocks(&self) -> usize {
+        self.pool.num_free() + self.prefix_cacher.num_evictable_blocks()
+    }
+
+    /// Number of GPU blocks of a sequence which only it references, and so which are freed when
+    /// it is preempted. Blocks shared with other sequences or the prefix cache are not counted.
+    pub fn num_exclusive_blocks(&self, seq_id: usize) -> usize {
+        self.block_tables.get(&seq_id).map_or(0, |table| {
+            table.iter().filter(|block| !block.is_shared()).count()
+        })
+    }
+
     pub fn can_allocate(&mut self, seq: &mut impl BlockEngineSequence) -> AllocStatus {
         let logical_blocks = seq.logical_token_blocks();
         let num_required_blocks = logical_blocks.len();
@@ -421,6 +455,7 @@ impl BlockEngine {
             self.cached_blocks_per_seq.remove(&id);
             // block_table is dropped here, automatically returning blocks to pool
         }
+        self.swapped_block_tables.remove(&id);
     }
 
     /// Free a sequence's blocks during preemption.
@@ -492,6 +527,94 @@ impl BlockEngine {
         }
     }
 
+    /// Undo [`Self::append_token_slot_to_seq`] for slots which were reserved for tokens that
+    /// were then dropped from the sequence, such as rejected speculative tokens. Frees the
+    /// physical blocks past the last non-empty logical block, returning how many were freed.
+    pub fn remove_token_slots_from_seq(&mut self, sequence: &impl BlockEngineSequence) -> usize {
+        let logical_blocks = sequence.logical_token_blocks();
+        let num_blocks = logical_blocks.len()
+            - usize::from(logical_blocks.last().is_some_and(|last| last.is_empty()));
+        match self.block_tables.get_mut(&sequence.get_id()) {
+            Some(table) if table.len() > num_blocks => {
+                let n_freed = table.len() - num_blocks;
+                // The block refs are dropped here, returning the blocks to the pool.
+                table.truncate(num_blocks);
+                n_freed
+            }
+            _ => 0,
+        }
+    }
+
+    /// Whether the GPU blocks of a sequence fit in the free CPU blocks.
+    pub fn can_swap_out(&self, seq: &impl BlockEngineSequence) -> bool {
+        self.block_tables
+            .get(&seq.get_id())
+            .is_some_and(|table| table.len() <= self.cpu_pool.num_free())
+    }
+
+    /// Move the blocks of a sequence from the GPU to the CPU, freeing the GPU blocks like
+    /// [`Self::free_sequence_for_preemption`]. Returns the GPU to CPU block mapping to copy.
+    pub fn swap_out(&mut self, seq: &impl BlockEngineSequence) -> HashMap<usize, usize> {
+        let seq_id = seq.get_id();
+        let Some(gpu_table) = self.block_tables.remove(&seq_id) else {
+            return HashMap::new();
+        };
+        let num_cached = self.cached_blocks_per_seq.remove(&seq_id).unwrap_or(0);
+        if num_cached > 0 && self.prefix_cacher.is_enabled() {
+            let logical_blocks = seq.logical_token_blocks();
+            self.prefix_cacher
+                .release_blocks(&logical_blocks[..num_cached.min(logical_blocks.len())]);
+        }
+
+        let mut mapping = HashMap::new();
+        let cpu_table = gpu_table
+            .iter()
+            .map(|gpu_block| {
+                let cpu_block = self
+                    .cpu_pool
+                    .allocate()
+                    .expect("Should have free CPU blocks to swap out");
+                mapping.insert(gpu_block.block_id(), cpu_block.block_id());
+                cpu_block
+            })
+            .collect();
+        self.swapped_block_tables.insert(seq_id, cpu_table);
+        mapping
+    }
+
+    /// Whether a swapped out sequence can be moved back to the GPU, together with the slot
+    /// for its next token.
+    pub fn can_swap_in(&self, seq: &impl BlockEngineSequence) -> bool {
+        self.swapped_block_tables
+            .get(&seq.get_id())
+            .is_some_and(|table| {
+                table.len() + seq.blocks_to_add_new_tok() <= self.num_available_blocks()
+            })
+    }
+
+    /// Move the blocks of a swapped out sequence from the CPU back to the GPU. Returns the CPU
+    /// to GPU block mapping to copy.
+    pub fn swap_in(&mut self, seq: &impl BlockEngineSequence) -> HashMap<usize, usize> {
+        let seq_id = seq.get_id();
+        let Some(cpu_table) = self.swapped_block_tables.remove(&seq_id) else {
+            return HashMap::new();
+        };
+
+        let mut mapping = HashMap::new();
+        let gpu_table = cpu_table
+            .iter()
+            .map(|cpu_block| {
+                let gpu_block = self.allocate_block_with_eviction();
+                mapping.insert(cpu_block.block_id(), gpu_block.block_id());
+                gpu_block
+            })
+            .collect();
+        self.block_tables.insert(seq_id, gpu_table);
+        self.cached_blocks_per_seq.insert(seq_id, 0);
+        // The CPU blocks are dropped here, returning them to the CPU pool.
+        mapping
+    }
+
     /// Get prefix cache statistics (hits, misses).
     pub fn prefix_cache_stats(&self) -> (usize, usize) {
         self.prefix_cacher.stats()
@@ -506,6 +629,37 @@ impl BlockEngine {
     pub fn prefix_cache_size(&self) -> usize {
         self.prefix_cacher.num_cached_blocks()
     }
+
+    /// Physical blocks of the longest cached prefix of `logical_blocks`, without marking them as in use.
+    /// The returned refs keep the blocks alive while their contents are read.
+    pub fn cached_prefix_blocks(&self, logical_blocks: &[LogicalTokenBlock]) -> Vec<BlockRef> {
+        self.prefix_cacher.lookup_prefix(logical_blocks)
+    }
+
+    /// Allocate `n` blocks which are not owned by any sequence, evicting cached blocks if necessary.
+    /// Returns `None` if not enough blocks are available.
+    pub fn allocate_unowned_blocks(&mut self, n: usize) -> Option<Vec<BlockRef>> {
+        if self.num_available_blocks() < n {
+            return None;
+        }
+        Some(
+            (0..n)
+                .map(|_| self.allocate_block_with_eviction())
+                .collect(),
+        )
+    }
+
+    /// Add filled blocks to the prefix cache, so that sequences starting with `logical_blocks` reuse them.
+    /// The blocks are pinned until the first such sequence, as no sequence holds them before.
+    pub fn cache_prefix_blocks(
+        &mut self,
+        logical_blocks: &[LogicalTokenBlock],
+        block_refs: &[BlockRef],
+    ) {
+        self.prefix_cacher
+            .insert_blocks(logical_blocks, block_refs, 0);
+        self.prefix_cacher.pin_blocks(logical_blocks);
+    }
 }
 
 #[cfg(test)]
@@ -577,6 +731,126 @@ mod tests {
         assert_eq!(pool.num_free(), 2); // All returned
     }
 
+    struct TestSeq {
+        id: usize,
+        blocks: Vec<LogicalTokenBlock>,
+    }
+
+    impl TestSeq {
+        fn new(id: usize, n_toks: usize) -> Self {
+            let mut seq = Self {
+                id,
+                blocks: Vec::new(),
+            };
+            for tok in 0..n_toks {
+                crate::sequence::util_append_token_to_blocks(tok, &mut seq.blocks, 4);
+            }
+            seq
+        }
+    }
+
+    impl BlockEngineSequence for TestSeq {
+        fn blocks_to_add_new_tok(&self) -> usize {
+            usize::from(
+                self.blocks
+                    .last()
+                    .is_none_or(|last| last.is_full() || last.is_empty()),
+            )
+        }
+        fn take_physical_blocks_prefill(&mut self) -> Option<Vec<BlockRef>> {
+            None
+        }
+        fn get_id(&self) -> usize {
+            self.id
+        }
+        fn logical_token_blocks(&self) -> &[LogicalTokenBlock] {
+            &self.blocks
+        }
+        fn increment_waitlist_count(&mut self) -> usize {
+            0
+        }
+        fn set_prefix_cache_len(&mut self, _len: usize) {}
+        fn block_size(&self) -> usize {
+            4
+        }
+    }
+
+    #[test]
+    fn test_remove_token_slots_after_rejected_tokens() {
+        let mut engine = BlockEngine::new(4, 8, 0, false);
+        let mut seq = TestSeq::new(0, 5);
+        engine.allocate(&mut seq);
+        assert_eq!(engine.block_tables[&0].len(), 2);
+
+        // Reserve slots for 6 speculative tokens.
+        for tok in 5..11 {
+            crate::sequence::util_append_token_to_blocks(tok, &mut seq.blocks, 4);
+            assert!(engine.append_token_slot_to_seq(&seq).is_none());
+        }
+        assert_eq!(engine.block_tables[&0].len(), 3);
+        assert_eq!(engine.num_available_blocks(), 5);
+
+        // Only 2 of them were accepted.
+        for _ in 0..4 {
+            if seq.blocks.last().unwrap().is_empty() {
+                seq.blocks.pop();
+            }
+            seq.blocks.last_mut().unwrap().pop_token();
+        }
+        assert_eq!(seq.blocks.iter().map(|b| b.num_tokens()).sum::<usize>(), 7);
+        assert_eq!(engine.remove_token_slots_from_seq(&seq), 1);
+        assert_eq!(engine.block_tables[&0].len(), 2);
+        assert_eq!(engine.num_available_blocks(), 6);
+        assert_eq!(engine.remove_token_slots_from_seq(&seq), 0);
+    }
+
+    #[test]
+    fn test_swap_out_then_in() {
+        let mut engine = BlockEngine::new(4, 4, 2, false);
+        let mut seq = TestSeq::new(0, 6);
+        engine.allocate(&mut seq);
+        let gpu_blocks = engine.block_tables[&0]
+            .iter()
+            .map(|block| block.block_id())
+            .collect::<Vec<_>>();
+
+        assert!(engine.can_swap_out(&seq));
+        let swap_out = engine.swap_out(&seq);
+        assert_eq!(swap_out.len(), 2);
+        assert!(gpu_blocks.iter().all(|block| swap_out.contains_key(block)));
+        assert!(!engine.block_tables.contains_key(&0));
+        assert_eq!(engine.num_available_blocks(), 4);
+        assert_eq!(engine.cpu_pool.num_free(), 0);
+
+        // Another sequence takes 3 of the GPU blocks in the meantime.
+        let mut other = TestSeq::new(1, 9);
+        engine.allocate(&mut other);
+        assert!(!engine.can_swap_in(&seq));
+        engine.free_sequence(1);
+
+        assert!(engine.can_swap_in(&seq));
+        let swap_in = engine.swap_in(&seq);
+        let mut cpu_blocks = swap_in.keys().copied().collect::<Vec<_>>();
+        cpu_blocks.sort();
+        let mut swapped_out = swap_out.values().copied().collect::<Vec<_>>();
+        swapped_out.sort();
+        assert_eq!(cpu_blocks, swapped_out);
+        assert_eq!(engine.block_tables[&0].len(), 2);
+        assert_eq!(engine.cpu_pool.num_free(), 2);
+    }
+
+    #[test]
+    fn test_logical_block_pop_then_append() {
+        let mut block = LogicalTokenBlock::new(4);
+        for tok in 1..=4 {
+            block.append_token_id(tok);
+        }
+        block.pop_token();
+        block.append_token_id(5);
+        assert!(block.is_full());
+        assert_eq!(block.toks(), &[1, 2, 3, 5]);
+    }
+
     #[test]
     fn test_block_ids_are_reused() {
         let pool = BlockPool::new(16, 2);
@@ -5,7 +5,6 @@ use std::{
 };
 
 use candle_core::{DType, Device, Result, Tensor};
-#[cfg(any(all(feature = "cuda", target_family = "unix"), feature = "metal"))]
 use mistralrs_paged_attn::copy_blocks;
 use serde::{Deserialize, Serialize};
 
@@ -45,13 +44,17 @@ impl FromStr for PagedCacheType {
 pub struct CacheConfig {
     pub block_size: usize,
     pub num_gpu_blocks: usize,
+    /// Blocks of the host memory pool used to swap out preempted sequences, 0 to recompute them.
+    pub num_cpu_blocks: usize,
     pub cache_type: PagedCacheType,
 }
 
 pub type KVCache = (Tensor, Tensor);
 
+#[derive(Clone)]
 pub struct CacheEngine {
     gpu_cache: Arc<Mutex<Vec<KVCache>>>,
+    cpu_cache: Arc<Mutex<Vec<KVCache>>>,
 }
 
 impl CacheEngine {
@@ -71,6 +74,11 @@ impl CacheEngine {
                 device,
                 layer_devices,
             )?)),
+            cpu_cache: Arc::new(Mutex::new(Self::allocate_cpu_cache(
+                model_config,
+                cache_config,
+                dtype,
+            )?)),
         })
     }
 
@@ -190,6 +198,50 @@ impl CacheEngine {
         Ok(gpu_cache)
     }
 
+    fn allocate_cpu_cache(
+        model_config: &dyn ModelConfigLike,
+        cache_config: &CacheConfig,
+        dtype: DType,
+    ) -> Result<Vec<KVCache>> {
+        if cache_config.num_cpu_blocks == 0 {
+            return Ok(Vec::new());
+        }
+        let key_block_shape =
+            Self::calculate_key_block_shape(model_config, dtype, cache_config.block_size);
+        let value_block_shape =
+            Self::calculate_value_block_shape(model_config, cache_config.block_size);
+        let mut cpu_cache = Vec::new();
+        for _ in 0..model_config.num_layers() {
+            let key_blocks = unsafe {
+                Tensor::empty(
+                    (
+                        cache_config.num_cpu_blocks,
+                        key_block_shape.0,
+                        key_block_shape.1,
+                        key_block_shape.2,
+                        key_block_shape.3,
+                    ),
+                    dtype,
+                    &Device::Cpu,
+                )?
+            };
+            let value_blocks = unsafe {
+                Tensor::empty(
+                    (
+                        cache_config.num_cpu_blocks,
+                        value_block_shape.0,
+                        value_block_shape.1,
+                        value_block_shape.2,
+                    ),
+                    dtype,
+                    &Device::Cpu,
+                )?
+            };
+            cpu_cache.push((key_blocks, value_blocks));
+        }
+        Ok(cpu_cache)
+    }
+
     fn calculate_key_block_shape(
         model_config: &dyn ModelConfigLike,
         dtype: DType,
@@ -218,30 +270,71 @@ impl CacheEngine {
 }
 
 impl CacheEngine {
-    pub fn execute_scheduler_ops(&self, blocks_to_copy: &HashMap<usize, Vec<usize>>) -> Result<()> {
+    /// Swap out before swapping in and copying, which may reuse the freed blocks.
+    pub fn execute_scheduler_ops(
+        &self,
+        blocks_to_swap_in: &HashMap<usize, usize>,
+        blocks_to_swap_out: &HashMap<usize, usize>,
+        blocks_to_copy: &HashMap<usize, Vec<usize>>,
+    ) -> Result<()> {
+        if !blocks_to_swap_out.is_empty() {
+            self.swap_out(blocks_to_swap_out)?;
+        }
+        if !blocks_to_swap_in.is_empty() {
+            self.swap_in(blocks_to_swap_in)?;
+        }
         if !blocks_to_copy.is_empty() {
             self.copy(blocks_to_copy)?;
         }
         Ok(())
     }
 
-    pub fn copy(&self, src_to_dst: &HashMap<usize, Vec<usize>>) -> Result<()> {
-        #[cfg(any(all(feature = "cuda", target_family = "unix"), feature = "metal"))]
-        {
-            let mut gpu_cache = self.get_kv_cache();
-            #[allow(clippy::map_identity)]
-            let caches: (Vec<&mut Tensor>, Vec<&mut Tensor>) =
-                gpu_cache.iter_mut().map(|(a, b)| (a, b)).unzip();
-            let (key_caches, value_caches) = caches;
-
-            // NOTE(EricLBuehler): This may synchronize the CPU and GPU
-            copy_blocks(key_caches, value_caches, src_to_dst)?;
-            Ok(())
+    /// Copy GPU blocks to CPU blocks, mapping the source to the destination block.
+    pub fn swap_out(&self, src_to_dst: &HashMap<usize, usize>) -> Result<()> {
+        let gpu_cache = self.get_kv_cache();
+        let cpu_cache = self.cpu_cache.lock().expect("KV cache mutex was poisoned");
+        for ((gpu_k, gpu_v), (cpu_k, cpu_v)) in gpu_cache.iter().zip(cpu_cache.iter()) {
+            Self::swap_blocks(gpu_k, cpu_k, src_to_dst)?;
+            Self::swap_blocks(gpu_v, cpu_v, src_to_dst)?;
         }
-        #[cfg(not(any(all(feature = "cuda", target_family = "unix"), feature = "metal")))]
-        {
-            let _ = src_to_dst;
-            candle_core::bail!("Paged attention requires the CUDA or Metal feature flags.");
+        Ok(())
+    }
+
+    /// Copy CPU blocks to GPU blocks, mapping the source to the destination block.
+    pub fn swap_in(&self, src_to_dst: &HashMap<usize, usize>) -> Result<()> {
+        let gpu_cache = self.get_kv_cache();
+        let cpu_cache = self.cpu_cache.lock().expect("KV cache mutex was poisoned");
+        for ((gpu_k, gpu_v), (cpu_k, cpu_v)) in gpu_cache.iter().zip(cpu_cache.iter()) {
+            Self::swap_blocks(cpu_k, gpu_k, src_to_dst)?;
+            Self::swap_blocks(cpu_v, gpu_v, src_to_dst)?;
+        }
+        Ok(())
+    }
+
+    // The `swap_blocks` kernels of `mistralrs-paged-attn` only copy from the host to the device, so
+    // the blocks are gathered with one transfer and written in place block by block.
+    fn swap_blocks(src: &Tensor, dst: &Tensor, src_to_dst: &HashMap<usize, usize>) -> Result<()> {
+        let (src_blocks, dst_blocks): (Vec<u32>, Vec<usize>) = src_to_dst
+            .iter()
+            .map(|(&src, &dst)| (src as u32, dst))
+            .unzip();
+        let src_blocks = Tensor::new(src_blocks, src.device())?;
+        let blocks = src.index_select(&src_blocks, 0)?.to_device(dst.device())?;
+        for (i, dst_block) in dst_blocks.into_iter().enumerate() {
+            dst.slice_set(&blocks.narrow(0, i, 1)?, 0, dst_block)?;
         }
+        Ok(())
+    }
+
+    pub fn copy(&self, src_to_dst: &HashMap<usize, Vec<usize>>) -> Result<()> {
+        let mut gpu_cache = self.get_kv_cache();
+        #[allow(clippy::map_identity)]
+        let caches: (Vec<&mut Tensor>, Vec<&mut Tensor>) =
+            gpu_cache.iter_mut().map(|(a, b)| (a, b)).unzip();
+        let (key_caches, value_caches) = caches;
+
+        // NOTE(EricLBuehler): This may synchronize the CPU and GPU
+        copy_blocks(key_caches, value_caches, src_to_dst)?;
+        Ok(())
     }
 }
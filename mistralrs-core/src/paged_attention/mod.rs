@@ -34,6 +34,7 @@ pub struct PagedAttentionConfig {
     pub(crate) block_size: Option<usize>,
     pub(crate) mem_gpu: MemoryGpuConfig,
     pub(crate) cache_type: PagedCacheType,
+    pub(crate) preemption_mode: PreemptionMode,
 }
 
 impl PagedAttentionConfig {
@@ -46,8 +47,31 @@ impl PagedAttentionConfig {
             block_size,
             mem_gpu,
             cache_type,
+            preemption_mode: PreemptionMode::default(),
         })
     }
+
+    /// Set how sequences are preempted when the KV cache runs out of blocks.
+    pub fn with_preemption_mode(mut self, preemption_mode: PreemptionMode) -> Self {
+        self.preemption_mode = preemption_mode;
+        self
+    }
+
+    pub fn preemption_mode(&self) -> PreemptionMode {
+        self.preemption_mode
+    }
+}
+
+/// How the PagedAttention scheduler frees the KV cache blocks of a running sequence when the
+/// KV cache runs out of blocks.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PreemptionMode {
+    /// Free the blocks and prefill the sequence again once it is rescheduled.
+    #[default]
+    Recompute,
+    /// Copy the blocks to a pool of `swap_space_mb` MB of host memory, and back once the
+    /// sequence is rescheduled. Sequences which are still prompts are recomputed.
+    Swap { swap_space_mb: usize },
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -99,6 +123,7 @@ pub fn calculate_cache_config(
     block_size: Option<usize>,
     dtype: DType,
     cache_type: PagedCacheType,
+    preemption_mode: PreemptionMode,
     config: &dyn ModelConfigLike,
     device: &Device,
     layer_devices: &[Option<Device>],
@@ -165,14 +190,32 @@ To raise this cap run: `sudo sysctl -w iogpu.wired_limit_mb=<desired_mb>`.",
         anyhow::bail!("Num GPU blocks is 0. This means there is not enough memory. Either reduce the memory amount/utilization/context size or disable PagedAttention.");
     }
 
+    let num_cpu_blocks = match preemption_mode {
+        PreemptionMode::Recompute => 0,
+        PreemptionMode::Swap { swap_space_mb } => {
+            let num_cpu_blocks =
+                mb_to_blocks!(swap_space_mb * SIZE_IN_MB, dtype_size, block_size, config);
+            if num_cpu_blocks == 0 {
+                anyhow::bail!(
+                    "Num CPU blocks is 0. Increase the swap space or preempt by recomputation."
+                );
+            }
+            num_cpu_blocks
+        }
+    };
+
     if !silent {
         info!("Allocating {mem_gpu} MB for PagedAttention KV cache per GPU");
         info!("PagedAttention KV cache type is {dtype:?}");
         info!("Using PagedAttention with block size {block_size} and {num_gpu_blocks} GPU blocks: available context length is {} tokens", num_gpu_blocks*block_size);
+        if let PreemptionMode::Swap { swap_space_mb } = preemption_mode {
+            info!("PagedAttention preempts sequences by swapping to {swap_space_mb} MB of host memory ({num_cpu_blocks} CPU blocks)");
+        }
     }
     Ok(CacheConfig {
         block_size,
         num_gpu_blocks,
+        num_cpu_blocks,
         cache_type,
     })
 }
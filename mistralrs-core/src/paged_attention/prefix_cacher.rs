@@ -12,7 +12,7 @@
 //! and content in a sequence.
 
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     hash::{DefaultHasher, Hash, Hasher},
     time::Instant,
 };
@@ -74,6 +74,9 @@ pub struct PrefixCacher {
     /// LRU queue of block hashes with active_users == 0, ordered by last access time.
     /// Front = least recently used (evict first).
     lru_queue: VecDeque<BlockHash>,
+    /// Blocks which hold an active user of their own, so that they are not evicted before a sequence
+    /// reuses them (e.g. restored sessions). The first sequence matching a pinned block takes over the pin.
+    pinned: HashSet<BlockHash>,
     /// Whether prefix caching is enabled.
     enabled: bool,
     /// Statistics
@@ -87,6 +90,7 @@ impl PrefixCacher {
         Self {
             cache: HashMap::new(),
             lru_queue: VecDeque::new(),
+            pinned: HashSet::new(),
             enabled,
             hits: 0,
             misses: 0,
@@ -154,9 +158,12 @@ impl PrefixCacher {
             if let Some(entry) = self.cache.get_mut(hash) {
                 // Cache hit! Verify token count matches.
                 if entry.num_tokens == logical_block.num_tokens() {
-                    // Update access time and active users count
+                    // Update access time and active users count. A pinned block already counts
+                    // the pin as a user, which this sequence takes over.
                     entry.last_access = now;
-                    entry.active_users += 1;
+                    if !self.pinned.remove(hash) {
+                        entry.active_users += 1;
+                    }
 
                     // Remove from LRU queue if it was there (active_users was 0)
                     if entry.active_users == 1 {
@@ -240,6 +247,48 @@ impl PrefixCacher {
         }
     }
 
+    /// Cached blocks of the longest cached prefix of `logical_blocks`, without marking them as in use.
+    pub fn lookup_prefix(&self, logical_blocks: &[LogicalTokenBlock]) -> Vec<BlockRef> {
+        if !self.enabled {
+            return Vec::new();
+        }
+
+        let hashes = self.compute_block_hashes(logical_blocks);
+        hashes
+            .iter()
+            .zip(logical_blocks)
+            .map_while(|(hash, logical_block)| {
+                self.cache
+                    .get(hash)
+                    .filter(|entry| {
+                        logical_block.is_full() && entry.num_tokens == logical_block.num_tokens()
+                    })
+                    .map(|entry| entry.block_ref.clone())
+            })
+            .collect()
+    }
+
+    /// Pin the cached blocks of `logical_blocks`, so that they are not evicted until a sequence reuses them.
+    pub fn pin_blocks(&mut self, logical_blocks: &[LogicalTokenBlock]) {
+        if !self.enabled {
+            return;
+        }
+
+        let hashes = self.compute_block_hashes(logical_blocks);
+        for (hash, logical_block) in hashes.iter().zip(logical_blocks) {
+            if !logical_block.is_full() || self.pinned.contains(hash) {
+                continue;
+            }
+            if let Some(entry) = self.cache.get_mut(hash) {
+                entry.active_users += 1;
+                if entry.active_users == 1 {
+                    self.lru_queue.retain(|h| h != hash);
+                }
+                self.pinned.insert(*hash);
+            }
+        }
+    }
+
     /// Decrement active users count for cached blocks when a sequence finishes or is preempted.
     /// Blocks with active_users == 0 become eligible for eviction.
     pub fn release_blocks(&mut self, logical_blocks: &[LogicalTokenBlock]) {
@@ -329,6 +378,7 @@ impl PrefixCacher {
     #[allow(dead_code)]
     pub fn clear(&mut self) {
         self.lru_queue.clear();
+        self.pinned.clear();
         self.cache.clear();
         // All BlockRefs are dropped here, returning blocks to pool
     }
@@ -366,4 +416,36 @@ mod tests {
         // Same tokens but different parent should produce different hash
         assert_ne!(hash2_with_parent, hash2_without_parent);
     }
+
+    #[test]
+    fn test_pinned_blocks_survive_eviction_until_matched() {
+        let pool = super::super::block_engine::BlockPool::new(2, 2);
+        let mut logical_blocks = Vec::new();
+        for toks in [[1, 2], [3, 4]] {
+            let mut block = LogicalTokenBlock::new(2);
+            for tok in toks {
+                block.append_token_id(tok);
+            }
+            logical_blocks.push(block);
+        }
+        let block_refs = vec![pool.allocate().unwrap(), pool.allocate().unwrap()];
+
+        let mut cacher = PrefixCacher::new(true);
+        cacher.insert_blocks(&logical_blocks, &block_refs, 0);
+        cacher.pin_blocks(&logical_blocks);
+        drop(block_refs);
+        assert_eq!(cacher.num_evictable_blocks(), 0);
+        cacher.evict_blocks(2);
+        assert_eq!(cacher.lookup_prefix(&logical_blocks).len(), 2);
+
+        // The matching sequence takes over the pins, and releasing it makes the blocks evictable.
+        let (_, num_matched) = cacher.match_prefix(&logical_blocks);
+        assert_eq!(num_matched, 2);
+        assert_eq!(cacher.num_evictable_blocks(), 0);
+        cacher.release_blocks(&logical_blocks);
+        assert_eq!(cacher.num_evictable_blocks(), 2);
+        cacher.evict_blocks(2);
+        assert_eq!(cacher.num_cached_blocks(), 0);
+        assert_eq!(pool.num_free(), 2);
+    }
 }
@@ -6,6 +6,7 @@ type SrcBlockFrom = usize;
 type DstBlocksTo = Vec<usize>;
 
 use std::{
+    cmp::Reverse,
     collections::{HashMap, VecDeque},
     sync::{atomic::Ordering, Arc, Mutex},
 };
@@ -16,7 +17,10 @@ use crate::{
     engine::IntervalLogger,
     get_mut_arcmutex,
     paged_attention::BlockEngine,
-    scheduler::{Scheduler, SchedulerOutput},
+    scheduler::{
+        can_batch_prefill_chunk, now_ms, waiting_order_key, Scheduler, SchedulerOutput,
+        PRIORITY_AGING_INTERVAL_MS,
+    },
     sequence::{Sequence, SequenceState, StopReason},
     TERMINATE_ALL_NEXT_STEP,
 };
@@ -34,20 +38,37 @@ const WAITING_TIMEOUT: usize = 64;
 pub struct PagedAttentionSchedulerOutput {
     /// Either ALL prompt or ALL completion.
     pub scheduled: Vec<Arc<Mutex<Sequence>>>,
+    /// Prompt chunks to prefill in a second step, after `scheduled`. Only used by chunked prefill.
+    pub prompt_chunks: Vec<Arc<Mutex<Sequence>>>,
+    /// CPU to GPU blocks of sequences which are swapped back in.
+    pub blocks_to_swap_in: HashMap<usize, usize>,
+    /// GPU to CPU blocks of sequences which are swapped out.
+    pub blocks_to_swap_out: HashMap<usize, usize>,
     pub blocks_to_copy: HashMap<SrcBlockFrom, DstBlocksTo>,
 }
 
 pub struct PagedAttentionSchedulerConfig {
     pub max_num_seqs: usize,
+    /// Token budget of a step with chunked prefill, see [`crate::SchedulerConfig`].
+    pub max_num_batched_tokens: Option<usize>,
 }
 
 pub struct PagedAttentionScheduler {
     waiting: VecDeque<Arc<Mutex<Sequence>>>,
     running: VecDeque<Arc<Mutex<Sequence>>>,
+    swapped: VecDeque<Arc<Mutex<Sequence>>>,
     config: PagedAttentionSchedulerConfig,
     pub block_engine: Arc<tokio::sync::Mutex<BlockEngine>>,
     block_size: usize,
     prefix_caching_enabled: bool,
+    chunked_prefill_enabled: bool,
+    ragged_completions_enabled: bool,
+    /// Preempt completions by swapping them out to the CPU blocks instead of recomputing them.
+    swap_enabled: bool,
+    /// Swap outs of the current scheduling pass, see [`Self::_preempt_by_swap`].
+    blocks_to_swap_out: HashMap<usize, usize>,
+    /// Number of sequences preempted so far, by swapping or recomputation.
+    num_preemptions: usize,
 }
 
 impl PagedAttentionScheduler {
@@ -57,14 +78,21 @@ impl PagedAttentionScheduler {
         Self {
             waiting: VecDeque::new(),
             running: VecDeque::new(),
+            swapped: VecDeque::new(),
             block_engine: Arc::new(tokio::sync::Mutex::new(BlockEngine::new(
                 cache_config.block_size,
                 cache_config.num_gpu_blocks,
+                cache_config.num_cpu_blocks,
                 true, // Default enabled, will be configured by Engine
             ))),
             block_size: cache_config.block_size,
             config,
             prefix_caching_enabled: true,
+            chunked_prefill_enabled: true,
+            ragged_completions_enabled: false,
+            swap_enabled: cache_config.num_cpu_blocks > 0,
+            blocks_to_swap_out: HashMap::new(),
+            num_preemptions: 0,
         }
     }
 
@@ -150,9 +178,162 @@ impl PagedAttentionScheduler {
     }
 
     pub fn schedule(&mut self, logger: &IntervalLogger) -> PagedAttentionSchedulerOutput {
+        if let Some(budget) = self
+            .config
+            .max_num_batched_tokens
+            .filter(|_| self.chunked_prefill_enabled)
+        {
+            return self.schedule_chunked(logger, budget);
+        }
+
+        // Swapped out sequences are rescheduled before new ones are admitted.
+        let (scheduled, did_ignore) = if self.swapped.is_empty() {
+            self.admit_waiting(logger)
+        } else {
+            (VecDeque::new(), false)
+        };
+
+        if !scheduled.is_empty() || did_ignore {
+            // Bucket scheduled prompts by sequence length to ensure all sequences in a batch
+            // have the same length (required for correct flash attention varlen operation).
+            let scheduled = self.bucket_and_preempt_sequences(scheduled);
+
+            logger.set_num_running(self.running.len());
+            logger.set_num_waiting(self.waiting_len());
+
+            return PagedAttentionSchedulerOutput {
+                scheduled: scheduled.into_iter().collect(),
+                prompt_chunks: Vec::new(),
+                blocks_to_swap_in: HashMap::new(),
+                blocks_to_swap_out: std::mem::take(&mut self.blocks_to_swap_out),
+                blocks_to_copy: HashMap::new(),
+            };
+        }
+
+        let mut blocks_to_copy = self.reserve_decode_slots();
+
+        // Bucket running completions by sequence length to ensure all sequences in a batch
+        // have the same length (required for correct flash attention varlen operation).
+        self.bucket_running_completions();
+        let blocks_to_swap_in = self.swap_in_sequences(&mut blocks_to_copy);
+
+        self.running
+            .iter()
+            .for_each(|seq| get_mut_arcmutex!(seq).set_state(SequenceState::RunningCompletion));
+
+        if TERMINATE_ALL_NEXT_STEP.load(Ordering::SeqCst) {
+            self.running.iter().for_each(|seq| {
+                get_mut_arcmutex!(seq).set_state(SequenceState::Done(StopReason::Canceled))
+            });
+            TERMINATE_ALL_NEXT_STEP.store(false, Ordering::SeqCst);
+        }
+
+        logger.set_num_running(self.running.len());
+        logger.set_num_waiting(self.waiting_len());
+
+        PagedAttentionSchedulerOutput {
+            scheduled: self.running.clone().into_iter().collect(),
+            prompt_chunks: Vec::new(),
+            blocks_to_swap_in,
+            blocks_to_swap_out: std::mem::take(&mut self.blocks_to_swap_out),
+            blocks_to_copy,
+        }
+    }
+
+    /// Keep only the running completions of one bucket, see [`Self::bucket_and_preempt_sequences`].
+    /// Pipelines which decode each sequence at its own offset can run all of them at once.
+    fn bucket_running_completions(&mut self) {
+        if self.ragged_completions_enabled {
+            return;
+        }
+        let running_for_bucket = std::mem::take(&mut self.running);
+        self.running = self.bucket_and_preempt_sequences(running_for_bucket);
+    }
+
+    /// Schedule the decode batch together with chunks of the running prompts, so that a long
+    /// prompt does not stall the running decodes. Each decode uses one token of `budget` and
+    /// the rest is spent on prompt chunks of the same offset and length.
+    fn schedule_chunked(
+        &mut self,
+        logger: &IntervalLogger,
+        budget: usize,
+    ) -> PagedAttentionSchedulerOutput {
+        // Admitted prompts have all of their blocks allocated and join the running queue.
+        if self.swapped.is_empty() {
+            self.admit_waiting(logger);
+        }
+
+        let (mut prompts, decodes): (VecDeque<_>, VecDeque<_>) = std::mem::take(&mut self.running)
+            .into_iter()
+            .partition(|seq| get_mut_arcmutex!(seq).is_prompt());
+        self.running = decodes;
+
+        let mut blocks_to_copy = self.reserve_decode_slots();
+        self.bucket_running_completions();
+        let blocks_to_swap_in = self.swap_in_sequences(&mut blocks_to_copy);
+
+        self.running
+            .iter()
+            .for_each(|seq| get_mut_arcmutex!(seq).set_state(SequenceState::RunningCompletion));
+
+        if TERMINATE_ALL_NEXT_STEP.load(Ordering::SeqCst) {
+            self.running.iter().chain(prompts.iter()).for_each(|seq| {
+                get_mut_arcmutex!(seq).set_state(SequenceState::Done(StopReason::Canceled))
+            });
+            TERMINATE_ALL_NEXT_STEP.store(false, Ordering::SeqCst);
+        }
+
+        let now = now_ms();
+        prompts
+            .make_contiguous()
+            .sort_by_key(|seq| waiting_order_key(&get_mut_arcmutex!(seq), now));
+        let mut budget_left = budget.saturating_sub(self.running.len());
+        let mut prompt_chunks = Vec::new();
+        if let Some(first) = prompts.front().filter(|_| budget_left > 0) {
+            prompt_chunks.push(first.clone());
+            let mut first = get_mut_arcmutex!(first);
+            // Prompts which cannot be chunked are prefilled at once, by themselves.
+            if first.can_chunk_prefill() {
+                let token_offset = first.token_offset();
+                let chunk = first.set_prefill_chunk(budget_left);
+                drop(first);
+                budget_left -= chunk;
+                for seq in prompts.iter().skip(1) {
+                    if budget_left < chunk {
+                        break;
+                    }
+                    let mut seq_guard = get_mut_arcmutex!(seq);
+                    if can_batch_prefill_chunk(token_offset, &seq_guard, chunk) {
+                        seq_guard.set_prefill_chunk(chunk);
+                        prompt_chunks.push(seq.clone());
+                        budget_left -= chunk;
+                    }
+                }
+            }
+        }
+
+        let scheduled = self.running.iter().cloned().collect();
+        self.running.extend(prompts);
+
+        logger.set_num_running(self.running.len());
+        logger.set_num_waiting(self.waiting_len());
+
+        PagedAttentionSchedulerOutput {
+            scheduled,
+            prompt_chunks,
+            blocks_to_swap_in,
+            blocks_to_swap_out: std::mem::take(&mut self.blocks_to_swap_out),
+            blocks_to_copy,
+        }
+    }
+
+    /// Move waiting sequences to the running queue as prompts while their blocks can be
+    /// allocated. Returns the admitted sequences and whether any sequence was ignored.
+    fn admit_waiting(&mut self, logger: &IntervalLogger) -> (VecDeque<Arc<Mutex<Sequence>>>, bool) {
         let mut scheduled: VecDeque<Arc<Mutex<Sequence>>> = VecDeque::new();
         let mut for_waiting_again: VecDeque<Arc<Mutex<Sequence>>> = VecDeque::new();
         let mut did_ignore = false;
+        self.sort_waiting_by_priority();
         while !self.waiting.is_empty() {
             let seq = self.waiting.front().unwrap().clone();
 
@@ -164,7 +345,9 @@ impl PagedAttentionScheduler {
                 get_mut_arcmutex!(self.block_engine).can_allocate(&mut *get_mut_arcmutex!(seq));
             match can_allocate {
                 AllocStatus::Later { waitlisted_count } => {
-                    if waitlisted_count > WAITING_TIMEOUT {
+                    if self.preempt_lower_priority_for(&seq) {
+                        // Enough blocks were freed by preempting lower priority sequences.
+                    } else if waitlisted_count > WAITING_TIMEOUT {
                         if let Some(seq_to_preempt) = self.running.pop_back() {
                             self._preempt_by_recompute(seq_to_preempt);
                             if !matches!(
@@ -234,21 +417,11 @@ impl PagedAttentionScheduler {
             }
         }
         self.waiting.extend(for_waiting_again);
+        (scheduled, did_ignore)
+    }
 
-        if !scheduled.is_empty() || did_ignore {
-            // Bucket scheduled prompts by sequence length to ensure all sequences in a batch
-            // have the same length (required for correct flash attention varlen operation).
-            let scheduled = self.bucket_and_preempt_sequences(scheduled);
-
-            logger.set_num_running(self.running.len());
-            logger.set_num_waiting(self.waiting.len());
-
-            return PagedAttentionSchedulerOutput {
-                scheduled: scheduled.into_iter().collect(),
-                blocks_to_copy: HashMap::new(),
-            };
-        }
-
+    /// Reserve a token slot for every running sequence, preempting sequences as needed.
+    fn reserve_decode_slots(&mut self) -> HashMap<SrcBlockFrom, DstBlocksTo> {
         let mut blocks_to_copy = HashMap::new();
 
         // Reserve token slots for the running sequence groups, preempting the lowest (earliest) first.
@@ -257,7 +430,8 @@ impl PagedAttentionScheduler {
         // sequences, which will be put into the waiting or swapped out state depending on
         // the preemption method (recompute or swap, respectively).
 
-        // Sorts by creation time, in descending order so that earliest are latest (first come first serve).
+        // Sorts by effective priority, then by creation time in descending order so that the lowest
+        // priority and earliest are latest (and so are preempted first).
         self.sort_running_by_priority_fcfs();
 
         let mut running: VecDeque<Arc<Mutex<Sequence>>> = VecDeque::new();
@@ -274,7 +448,8 @@ impl PagedAttentionScheduler {
                     self._preempt(seq_to_preempt);
                 } else {
                     // Nothing to preempt, preempt ourselves. Also, do not bother looking at anything else.
-                    self._preempt(seq.clone());
+                    // A sequence which does not fit by itself is not worth swapping out.
+                    self._preempt_by_recompute(seq.clone());
                     finished_with_break = true;
                     break;
                 }
@@ -298,31 +473,58 @@ impl PagedAttentionScheduler {
             }
         }
         self.running = running;
+        blocks_to_copy
+    }
 
-        // Bucket running completions by sequence length to ensure all sequences in a batch
-        // have the same length (required for correct flash attention varlen operation).
-        let running_for_bucket = std::mem::take(&mut self.running);
-        let bucketed = self.bucket_and_preempt_sequences(running_for_bucket);
-        self.running = bucketed;
-
-        self.running
-            .iter()
-            .for_each(|seq| get_mut_arcmutex!(seq).set_state(SequenceState::RunningCompletion));
-
-        if TERMINATE_ALL_NEXT_STEP.load(Ordering::SeqCst) {
-            self.running.iter().for_each(|seq| {
-                get_mut_arcmutex!(seq).set_state(SequenceState::Done(StopReason::Canceled))
-            });
-            TERMINATE_ALL_NEXT_STEP.store(false, Ordering::SeqCst);
+    /// Swap sequences back in, highest priority first, while their blocks and a slot for their
+    /// next token fit. Nothing is swapped in by a scheduling pass which swaps out, as the CPU
+    /// blocks freed here could be swapped out to before they are read. Swapped in sequences
+    /// join the running queue as completions, in the bucket of the running completions.
+    fn swap_in_sequences(
+        &mut self,
+        blocks_to_copy: &mut HashMap<SrcBlockFrom, DstBlocksTo>,
+    ) -> HashMap<usize, usize> {
+        let mut blocks_to_swap_in = HashMap::new();
+        if !self.blocks_to_swap_out.is_empty() {
+            return blocks_to_swap_in;
         }
 
-        logger.set_num_running(self.running.len());
-        logger.set_num_waiting(self.waiting.len());
-
-        PagedAttentionSchedulerOutput {
-            scheduled: self.running.clone().into_iter().collect(),
-            blocks_to_copy,
+        let now = now_ms();
+        self.swapped
+            .make_contiguous()
+            .sort_by_key(|seq| waiting_order_key(&get_mut_arcmutex!(seq), now));
+        let mut bucket = self.running.front().map(|seq| {
+            let seq = get_mut_arcmutex!(seq);
+            (seq.len(), seq.token_offset())
+        });
+        let mut swapped = VecDeque::new();
+        while let Some(seq) = self.swapped.pop_front() {
+            if self.running.len() >= self.config.max_num_seqs {
+                swapped.push_back(seq);
+                break;
+            }
+            let seq_guard = get_mut_arcmutex!(seq);
+            let key = (seq_guard.len(), seq_guard.token_offset());
+            if !self.ragged_completions_enabled && bucket.is_some_and(|bucket| bucket != key) {
+                drop(seq_guard);
+                swapped.push_back(seq);
+                continue;
+            }
+            if !get_mut_arcmutex!(self.block_engine).can_swap_in(&*seq_guard) {
+                drop(seq_guard);
+                swapped.push_back(seq);
+                break;
+            }
+            blocks_to_swap_in.extend(get_mut_arcmutex!(self.block_engine).swap_in(&*seq_guard));
+            self._append_token_slot_to_seq(&seq_guard, blocks_to_copy);
+            seq_guard.set_state(SequenceState::RunningCompletion);
+            drop(seq_guard);
+            bucket = Some(key);
+            self.running.push_back(seq);
         }
+        swapped.extend(self.swapped.drain(..));
+        self.swapped = swapped;
+        blocks_to_swap_in
     }
 
     pub fn free_finished_sequence_groups(&mut self) {
@@ -390,14 +592,36 @@ impl PagedAttentionScheduler {
         self._free(seq_id);
     }
 
-    /// Preempt sequences by dropping their cache and recomputing later.
+    /// Preempt sequences by swapping out their cache if enabled, and by dropping their cache
+    /// and recomputing later otherwise. Prompts and sequences which do not fit in the free CPU
+    /// blocks are always recomputed.
     fn _preempt(&mut self, seq: Arc<Mutex<Sequence>>) {
-        self._preempt_by_recompute(seq)
+        let can_swap_out = self.swap_enabled && {
+            let seq_guard = get_mut_arcmutex!(seq);
+            !seq_guard.is_prompt() && get_mut_arcmutex!(self.block_engine).can_swap_out(&*seq_guard)
+        };
+        if can_swap_out {
+            self._preempt_by_swap(seq)
+        } else {
+            self._preempt_by_recompute(seq)
+        }
     }
 
-    fn _preempt_by_recompute(&mut self, seq: Arc<Mutex<Sequence>>) {
+    fn _preempt_by_swap(&mut self, seq: Arc<Mutex<Sequence>>) {
+        self.num_preemptions += 1;
         let seq_guard = get_mut_arcmutex!(seq);
+        seq_guard.set_state(SequenceState::Swapped);
+        let blocks_to_swap_out = get_mut_arcmutex!(self.block_engine).swap_out(&*seq_guard);
+        drop(seq_guard);
+        self.blocks_to_swap_out.extend(blocks_to_swap_out);
+        self.swapped.push_back(seq);
+    }
+
+    fn _preempt_by_recompute(&mut self, seq: Arc<Mutex<Sequence>>) {
+        self.num_preemptions += 1;
+        let mut seq_guard = get_mut_arcmutex!(seq);
         seq_guard.set_state(SequenceState::Waiting);
+        seq_guard.reset_chunked_prefill();
         let seq_id = seq_guard.get_id();
         // Get logical blocks for proper cache ref release
         let logical_blocks = seq_guard.logical_token_blocks().to_vec();
@@ -429,11 +653,90 @@ impl PagedAttentionScheduler {
     }
 
     fn sort_running_by_priority_fcfs(&mut self) {
-        self.running
-            .make_contiguous()
-            .sort_by_key(|seq| get_mut_arcmutex!(seq).timestamp());
+        let now = now_ms();
+        self.running.make_contiguous().sort_by_key(|seq| {
+            let seq = get_mut_arcmutex!(seq);
+            (
+                seq.effective_priority(now, PRIORITY_AGING_INTERVAL_MS),
+                seq.timestamp(),
+            )
+        });
         self.running.make_contiguous().reverse();
     }
+
+    fn sort_waiting_by_priority(&mut self) {
+        let now = now_ms();
+        self.waiting
+            .make_contiguous()
+            .sort_by_key(|seq| waiting_order_key(&get_mut_arcmutex!(seq), now));
+    }
+
+    /// Preempt running sequences of a lower effective (aged) priority than `seq`, lowest priority
+    /// and most recent first, until `seq` can be allocated. Nothing is preempted if freeing all
+    /// such sequences would still not make room. `seq` must be at the front of the waiting queue,
+    /// and stays there. Returns whether `seq` can now be allocated.
+    fn preempt_lower_priority_for(&mut self, seq: &Arc<Mutex<Sequence>>) -> bool {
+        let now = now_ms();
+        let (priority, num_required_blocks) = {
+            let seq = get_mut_arcmutex!(seq);
+            (
+                seq.effective_priority(now, PRIORITY_AGING_INTERVAL_MS),
+                seq.logical_token_blocks().len(),
+            )
+        };
+
+        let mut victims = self
+            .running
+            .iter()
+            .filter_map(|running| {
+                let running = get_mut_arcmutex!(running);
+                let running_priority = running.effective_priority(now, PRIORITY_AGING_INTERVAL_MS);
+                (running_priority < priority).then(|| {
+                    (
+                        running.get_id(),
+                        (running_priority, Reverse(running.timestamp())),
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+        // Shared blocks stay allocated when a victim is preempted, so they free nothing.
+        let freeable = {
+            let block_engine = get_mut_arcmutex!(self.block_engine);
+            victims
+                .iter()
+                .map(|(id, _)| block_engine.num_exclusive_blocks(*id))
+                .sum::<usize>()
+        };
+        if get_mut_arcmutex!(self.block_engine).num_available_blocks() + freeable
+            < num_required_blocks
+        {
+            return false;
+        }
+        victims.sort_by_key(|(_, key)| *key);
+
+        // Keep `seq` at the front of the waiting queue, ahead of the preempted sequences.
+        let front = self.waiting.pop_front().unwrap();
+        let mut can_allocate = false;
+        for (id, _) in victims {
+            let idx = self
+                .running
+                .iter()
+                .position(|running| get_mut_arcmutex!(running).get_id() == id)
+                .unwrap();
+            let victim = self.running.remove(idx).unwrap();
+            info!("Preempting sequence {id} to schedule a higher priority sequence.");
+            self._preempt(victim);
+            if matches!(
+                get_mut_arcmutex!(self.block_engine).can_allocate(&mut *get_mut_arcmutex!(seq)),
+                AllocStatus::Ok
+            ) {
+                can_allocate = true;
+                break;
+            }
+        }
+        self.waiting.push_front(front);
+        can_allocate
+    }
 }
 
 impl Scheduler for PagedAttentionScheduler {
@@ -441,12 +744,12 @@ impl Scheduler for PagedAttentionScheduler {
         self.waiting.push_back(Arc::new(Mutex::new(seq)));
     }
     fn schedule(&mut self, logger: &IntervalLogger) -> SchedulerOutput<'_> {
-        SchedulerOutput::PagedAttention {
-            output: self.schedule(logger),
-        }
+        let output = self.schedule(logger);
+        logger.set_num_preemptions(self.num_preemptions);
+        SchedulerOutput::PagedAttention { output }
     }
     fn waiting_len(&self) -> usize {
-        self.waiting.len()
+        self.waiting.len() + self.swapped.len()
     }
     fn running_len(&self) -> usize {
         self.running.len()
@@ -473,4 +776,198 @@ impl Scheduler for PagedAttentionScheduler {
     fn set_prefix_caching_enabled(&mut self, enabled: bool) {
         self.set_prefix_caching_enabled_sync(enabled);
     }
+    fn set_chunked_prefill_enabled(&mut self, enabled: bool) {
+        self.chunked_prefill_enabled = enabled;
+    }
+    fn set_ragged_completions_enabled(&mut self, enabled: bool) {
+        self.ragged_completions_enabled = enabled;
+    }
+    fn cancel_request(&mut self, request_id: usize) {
+        for seq in self
+            .waiting
+            .iter()
+            .chain(self.running.iter())
+            .chain(self.swapped.iter())
+        {
+            let mut seq = get_mut_arcmutex!(seq);
+            if seq.request_id() == request_id {
+                seq.request_cancel();
+            }
+        }
+    }
+    fn take_stopped_sequences(&mut self) -> Vec<(Sequence, StopReason)> {
+        let mut stopped = Vec::new();
+        for queue in [&mut self.waiting, &mut self.swapped, &mut self.running] {
+            let (taken, kept) = std::mem::take(queue).into_iter().partition(|seq| {
+                // A sequence still shared, e.g. by the batch of the previous step, is taken on a
+                // later step.
+                if Arc::strong_count(seq) > 1 {
+                    return false;
+                }
+                let seq = get_mut_arcmutex!(seq);
+                // Running sequences are only taken between prefill chunks.
+                seq.unscheduled_stop_reason().is_some() && (seq.is_prompt() || !seq.is_running())
+            });
+            *queue = kept;
+            stopped.extend(taken);
+        }
+
+        let mut block_engine = get_mut_arcmutex!(self.block_engine);
+        stopped
+            .into_iter()
+            .map(|seq| {
+                let seq = Arc::into_inner(seq)
+                    .expect("Only unshared sequences are taken")
+                    .into_inner()
+                    .unwrap();
+                // Frees the GPU blocks of a prompt, or the CPU blocks of a swapped sequence.
+                block_engine.free_sequence(seq.get_id());
+                let reason = seq.unscheduled_stop_reason().unwrap();
+                (seq, reason)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{PagedAttentionScheduler, PagedAttentionSchedulerConfig};
+    use crate::{
+        engine::IntervalLogger,
+        get_mut_arcmutex,
+        paged_attention::{CacheConfig, PagedCacheType},
+        request::RequestPriority,
+        scheduler::{now_ms, test_utils::test_sequence, Scheduler, PRIORITY_AGING_INTERVAL_MS},
+        sequence::{Sequence, SequenceState, StopReason},
+    };
+
+    const BLOCK_SIZE: usize = 4;
+
+    fn scheduler(num_gpu_blocks: usize) -> PagedAttentionScheduler {
+        PagedAttentionScheduler::new(
+            PagedAttentionSchedulerConfig {
+                max_num_seqs: 8,
+                max_num_batched_tokens: None,
+            },
+            CacheConfig {
+                block_size: BLOCK_SIZE,
+                num_gpu_blocks,
+                num_cpu_blocks: 0,
+                cache_type: PagedCacheType::Auto,
+            },
+        )
+    }
+
+    fn sequence(id: usize, age_intervals: u128, priority: RequestPriority) -> Sequence {
+        let timestamp = now_ms() - age_intervals * PRIORITY_AGING_INTERVAL_MS;
+        // Two blocks, with room to decode a token.
+        let mut seq = test_sequence(id, timestamp, 2 * BLOCK_SIZE - 2, Some(BLOCK_SIZE));
+        seq.set_priority(priority);
+        seq
+    }
+
+    fn ids(scheduled: &[std::sync::Arc<std::sync::Mutex<Sequence>>]) -> Vec<usize> {
+        scheduled
+            .iter()
+            .map(|seq| *get_mut_arcmutex!(seq).id())
+            .collect()
+    }
+
+    #[test]
+    fn test_waiting_sequences_are_ordered_by_aged_priority() {
+        let mut scheduler = scheduler(16);
+        scheduler.add_seq(sequence(0, 0, RequestPriority::Normal));
+        scheduler.add_seq(sequence(1, 0, RequestPriority::High));
+        // Waited for three aging intervals, so ahead of fresh high priority sequences.
+        scheduler.add_seq(sequence(2, 3, RequestPriority::Low));
+        scheduler.add_seq(sequence(3, 0, RequestPriority::Low));
+
+        scheduler.sort_waiting_by_priority();
+        let waiting = scheduler.waiting.iter().cloned().collect::<Vec<_>>();
+        assert_eq!(ids(&waiting), vec![2, 1, 0, 3]);
+    }
+
+    #[test]
+    fn test_higher_priority_preempts_lower_priority() {
+        let logger = IntervalLogger::new(Duration::from_secs(3600));
+        // Room for exactly one sequence of two blocks.
+        let mut scheduler = scheduler(2);
+        scheduler.add_seq(sequence(0, 0, RequestPriority::Low));
+        assert_eq!(ids(&scheduler.schedule(&logger).scheduled), vec![0]);
+
+        scheduler.add_seq(sequence(1, 0, RequestPriority::High));
+        assert_eq!(ids(&scheduler.schedule(&logger).scheduled), vec![1]);
+        let preempted = scheduler.waiting.front().unwrap();
+        assert_eq!(*get_mut_arcmutex!(preempted).id(), 0);
+        assert!(matches!(
+            get_mut_arcmutex!(preempted).getstate(),
+            SequenceState::Waiting
+        ));
+    }
+
+    #[test]
+    fn test_aged_sequences_are_not_preempted() {
+        let logger = IntervalLogger::new(Duration::from_secs(3600));
+        let mut scheduler = scheduler(2);
+        // Aged by two classes, as high as a fresh high priority sequence.
+        scheduler.add_seq(sequence(0, 2, RequestPriority::Low));
+        assert_eq!(ids(&scheduler.schedule(&logger).scheduled), vec![0]);
+
+        scheduler.add_seq(sequence(1, 0, RequestPriority::High));
+        assert_eq!(ids(&scheduler.schedule(&logger).scheduled), vec![0]);
+        let waiting = scheduler.waiting.iter().cloned().collect::<Vec<_>>();
+        assert_eq!(ids(&waiting), vec![1]);
+    }
+
+    #[test]
+    fn test_sequences_past_deadline_are_taken_while_waiting() {
+        let logger = IntervalLogger::new(Duration::from_secs(3600));
+        let mut scheduler = scheduler(2);
+        scheduler.add_seq(sequence(0, 0, RequestPriority::Normal));
+        assert_eq!(ids(&scheduler.schedule(&logger).scheduled), vec![0]);
+
+        let mut seq = sequence(1, 0, RequestPriority::Normal);
+        seq.set_deadline(Instant::now());
+        scheduler.add_seq(seq);
+        let stopped = scheduler.take_stopped_sequences();
+        assert_eq!(stopped.len(), 1);
+        assert_eq!(*stopped[0].0.id(), 1);
+        assert_eq!(stopped[0].1, StopReason::Timeout);
+        assert!(scheduler.waiting.is_empty());
+
+        // Decoding sequences are finished when their next token is sampled.
+        get_mut_arcmutex!(scheduler.running[0]).set_deadline(Instant::now());
+        assert!(scheduler.take_stopped_sequences().is_empty());
+    }
+
+    #[test]
+    fn test_canceled_sequences_are_taken_while_waiting() {
+        let mut scheduler = scheduler(4);
+        for id in 0..2 {
+            let mut seq = sequence(id, 0, RequestPriority::Normal);
+            seq.set_request_id(id);
+            scheduler.add_seq(seq);
+        }
+        scheduler.cancel_request(1);
+        let stopped = scheduler.take_stopped_sequences();
+        assert_eq!(stopped.len(), 1);
+        assert_eq!(*stopped[0].0.id(), 1);
+        assert_eq!(stopped[0].1, StopReason::Canceled);
+        assert_eq!(ids(scheduler.waiting.make_contiguous()), vec![0]);
+    }
+
+    #[test]
+    fn test_shared_sequences_are_taken_once_released() {
+        let mut scheduler = scheduler(4);
+        scheduler.add_seq(sequence(0, 0, RequestPriority::Normal));
+        let shared = scheduler.waiting[0].clone();
+        scheduler.cancel_request(0);
+        assert!(scheduler.take_stopped_sequences().is_empty());
+        assert_eq!(scheduler.waiting.len(), 1);
+
+        drop(shared);
+        assert_eq!(scheduler.take_stopped_sequences().len(), 1);
+    }
 }
@@ -377,6 +377,11 @@ impl AnyMoePipelineMixin for AnyMoePipeline {
             -1,
             0.0,
             0.0,
+            0.0,
+            0.0,
+            None,
+            None,
+            None,
             vec![],
         )
         .map_err(candle_core::Error::msg)?;
@@ -0,0 +1,475 @@
+//! Beam search decoding.
+//!
+//! The beams of a request are the sequences of its [`SequenceGroup`](crate::sequence::SequenceGroup).
+//! Every step keeps the `num_beams` best live continuations over all beams. Continuations which
+//! finish a hypothesis, such as EOS, are set aside in the group and do not take a beam. A beam which
+//! is continued from another beam takes over a copy of that beam's tokens and KV cache. The beams
+//! of a group start from the same prompt, so only the first one prefills it: the others take over
+//! its KV cache with the first token, as every first continuation comes from the first beam.
+//!
+//! Candidates are ranked by their raw logprobs. Penalties, logit bias and logits processors of
+//! the sampling parameters are not applied to them.
+//!
+//! The search ends when the finished hypotheses cannot be beaten by the live beams, or when the
+//! beams stop together, for example at the maximum length. The beams then take over the best
+//! hypotheses and finish with them.
+
+use candle_core::{DType, Result, Tensor, D};
+
+use crate::{
+    prefix_cacher::PrefixCacheManagerV2,
+    sampler::{BeamSearchParams, Logprobs},
+    sequence::{FinishedBeam, Sequence, StopReason},
+};
+
+use super::{sampling::finish_or_add_toks_to_seq, Pipeline};
+
+struct Candidate {
+    parent: usize,
+    token: u32,
+    logprob: f32,
+    cumulative_logprob: f32,
+    score: f32,
+    stop: Option<StopReason>,
+}
+
+impl Candidate {
+    fn logprobs(&self) -> Logprobs {
+        Logprobs {
+            token: self.token,
+            logprob: self.logprob,
+            bytes: None,
+            top_logprobs: Some(Vec::new()),
+        }
+    }
+
+    /// Whether this continuation ends its own hypothesis. Other stop reasons, such as the length
+    /// limit, apply to every beam at once.
+    fn is_finished(&self) -> bool {
+        matches!(
+            self.stop,
+            Some(StopReason::Eos | StopReason::StopTok(_) | StopReason::StopString { .. })
+        )
+    }
+
+    fn stops_every_beam(&self) -> bool {
+        self.stop.is_some() && !self.is_finished()
+    }
+}
+
+/// Pick the continuations of a step from `candidates`, sorted by descending cumulative logprob.
+///
+/// Returns the indices of the at most `width` live continuations and of the finished ones. Like
+/// the live ones, a finished continuation must rank among the `width` best to be kept.
+fn select_continuations(candidates: &[Candidate], width: usize) -> (Vec<usize>, Vec<usize>) {
+    let mut live = Vec::with_capacity(width);
+    let mut finished = Vec::new();
+    for (rank, candidate) in candidates.iter().enumerate() {
+        if candidate.is_finished() {
+            if rank < width {
+                finished.push(rank);
+            }
+        } else {
+            live.push(rank);
+            if live.len() == width {
+                break;
+            }
+        }
+    }
+    (live, finished)
+}
+
+/// Whether the best live score can no longer make the `width` best finished scores, sorted in
+/// descending order.
+fn is_search_done(
+    params: &BeamSearchParams,
+    finished_scores: &[f32],
+    width: usize,
+    best_live_score: Option<f32>,
+) -> bool {
+    let Some(best_live_score) = best_live_score else {
+        return true;
+    };
+    finished_scores.len() >= width
+        && (params.early_stopping || best_live_score <= finished_scores[width - 1])
+}
+
+#[derive(Clone, Copy)]
+enum Hypothesis {
+    Finished(usize),
+    Live(usize),
+}
+
+/// The `width` best of the finished and live hypotheses by score. Hypotheses are repeated if
+/// there are fewer than `width`, which only happens with tiny vocabularies.
+fn best_hypotheses(finished_scores: &[f32], live_scores: &[f32], width: usize) -> Vec<Hypothesis> {
+    let mut hypotheses = finished_scores
+        .iter()
+        .enumerate()
+        .map(|(i, score)| (*score, Hypothesis::Finished(i)))
+        .chain(
+            live_scores
+                .iter()
+                .enumerate()
+                .map(|(i, score)| (*score, Hypothesis::Live(i))),
+        )
+        .collect::<Vec<_>>();
+    hypotheses.sort_by(|a, b| b.0.total_cmp(&a.0));
+    let mut best = hypotheses
+        .into_iter()
+        .map(|(_, hypothesis)| hypothesis)
+        .take(width)
+        .collect::<Vec<_>>();
+    for i in best.len()..width {
+        if best.is_empty() {
+            break;
+        }
+        best.push(best[i % best.len()]);
+    }
+    best
+}
+
+/// For each sequence of a prompt batch, the index of the earlier beam of its group whose prompt
+/// it shares, if any. These beams are left out of the forward pass. Beams which score their prompt
+/// logprobs run the prompt themselves.
+pub(crate) fn shared_prompt_beams(seqs: &[&mut Sequence]) -> Vec<Option<usize>> {
+    seqs.iter()
+        .enumerate()
+        .map(|(i, seq)| {
+            if seq.beam_search().is_none() || seq.wants_prompt_logprobs() {
+                return None;
+            }
+            seqs[..i]
+                .iter()
+                .position(|other| other.group_id() == seq.group_id())
+        })
+        .collect()
+}
+
+/// Expand the outputs of the sequences which ran the forward pass to the whole batch, giving the
+/// beams which shared a prompt the output of the beam which ran it.
+pub(crate) fn share_prompt_logits<T: Clone>(shared: &[Option<usize>], outputs: Vec<T>) -> Vec<T> {
+    let mut outputs = outputs.into_iter();
+    let mut shared_outputs: Vec<T> = Vec::with_capacity(shared.len());
+    for leader in shared {
+        let output = match leader {
+            Some(leader) => shared_outputs[*leader].clone(),
+            None => outputs
+                .next()
+                .expect("Every sequence which ran the prompt has an output."),
+        };
+        shared_outputs.push(output);
+    }
+    shared_outputs
+}
+
+/// Advance the beams of every beam search group in the batch by one token.
+///
+/// The scheduler runs the beams of a group in the same batch. Returns whether any beam was
+/// continued from another one. The sequence caches were then rearranged and must be cloned into
+/// the model cache before the next step.
+pub(crate) async fn step_beams(
+    this: &dyn Pipeline,
+    beams: Vec<(&mut Sequence, Tensor)>,
+    prefix_cacher: &mut PrefixCacheManagerV2,
+    eos_tok: Option<&[u32]>,
+) -> Result<bool> {
+    let mut groups: Vec<(usize, Vec<(&mut Sequence, Tensor)>)> = Vec::new();
+    for (seq, logits) in beams {
+        let group_id = seq.group_id();
+        match groups.iter_mut().find(|(id, _)| *id == group_id) {
+            Some((_, group)) => group.push((seq, logits)),
+            None => groups.push((group_id, vec![(seq, logits)])),
+        }
+    }
+
+    let mut reordered = false;
+    for (_, group) in groups {
+        reordered |= step_group(this, group, prefix_cacher, eos_tok).await?;
+    }
+    Ok(reordered)
+}
+
+async fn step_group(
+    this: &dyn Pipeline,
+    beams: Vec<(&mut Sequence, Tensor)>,
+    prefix_cacher: &mut PrefixCacheManagerV2,
+    eos_tok: Option<&[u32]>,
+) -> Result<bool> {
+    let (mut seqs, logits): (Vec<&mut Sequence>, Vec<Tensor>) = beams.into_iter().unzip();
+    let params = seqs[0]
+        .beam_search()
+        .cloned()
+        .expect("Beam search step on a sequence without beam search params.");
+    let width = seqs.len();
+    let max_seq_len = this.get_metadata().max_seq_len;
+
+    // All beams are identical before the first token, and only the first one ran the prompt, so
+    // only expand it. The other beams copy its KV cache below.
+    let n_parents = if seqs.iter().all(|seq| seq.logprobs().is_empty()) {
+        1
+    } else {
+        width
+    };
+    let mut candidates = Vec::new();
+    for (parent, logits) in logits.iter().enumerate().take(n_parents) {
+        let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+        // Base 10, like the logprobs reported by the sampler.
+        let logprobs = (candle_nn::ops::log_softmax(&logits, D::Minus1)?
+            / std::f64::consts::LN_10)?
+            .to_vec1::<f32>()?;
+        let mut top = logprobs.into_iter().enumerate().collect::<Vec<_>>();
+        // Twice the width, so that `width` live continuations remain even if the best ones of
+        // this beam finish their hypotheses.
+        let k = (2 * width).min(top.len());
+        top.select_nth_unstable_by(k - 1, |a, b| b.1.total_cmp(&a.1));
+        top.truncate(k);
+
+        let seq = &seqs[parent];
+        let len = seq.logprobs().len() + 1;
+        candidates.extend(top.into_iter().map(|(token, logprob)| {
+            let cumulative_logprob = seq.cumulative_logprob() + logprob;
+            Candidate {
+                parent,
+                token: token as u32,
+                logprob,
+                cumulative_logprob,
+                score: params.score(cumulative_logprob, len),
+                stop: seq.is_done(token as u32, eos_tok, max_seq_len),
+            }
+        }));
+    }
+    candidates.sort_by(|a, b| b.cumulative_logprob.total_cmp(&a.cumulative_logprob));
+
+    let (mut live, finished) = select_continuations(&candidates, width);
+    let (is_done, finished_beams) = {
+        let mut group = seqs[0].get_mut_group();
+        let finished_beams = group.finished_beams_mut();
+        finished_beams.extend(finished.iter().map(|&i| FinishedBeam {
+            score: candidates[i].score,
+            state: seqs[candidates[i].parent].beam_state(),
+            logprobs: candidates[i].logprobs(),
+        }));
+        finished_beams.sort_by(|a, b| b.score.total_cmp(&a.score));
+        finished_beams.truncate(width);
+
+        let finished_scores = finished_beams
+            .iter()
+            .map(|beam| beam.score)
+            .collect::<Vec<_>>();
+        let best_live_score = live.first().map(|&i| candidates[i].score);
+        if is_search_done(&params, &finished_scores, width, best_live_score) {
+            // Live beams could still beat the hypotheses, but they are not done.
+            live.clear();
+            (true, std::mem::take(finished_beams))
+        } else if live.iter().any(|&i| candidates[i].stops_every_beam()) {
+            (true, std::mem::take(finished_beams))
+        } else {
+            (false, Vec::new())
+        }
+    };
+
+    if is_done {
+        finish_group(
+            this,
+            seqs,
+            &candidates,
+            &live,
+            finished_beams,
+            prefix_cacher,
+            eos_tok,
+        )
+        .await?;
+        return Ok(false);
+    }
+
+    // Beams keep their own best continuation, other continuations go to the beams which
+    // have none, copying the state of their parent.
+    let mut assigned: Vec<Option<usize>> = vec![None; width];
+    let mut orphans = Vec::new();
+    for &i in live.iter().cycle().take(width) {
+        let parent = candidates[i].parent;
+        if assigned[parent].is_none() {
+            assigned[parent] = Some(i);
+        } else {
+            orphans.push(i);
+        }
+    }
+    let mut reordered = false;
+    let mut orphans = orphans.into_iter();
+    for (slot, assigned) in assigned.iter_mut().enumerate() {
+        if assigned.is_some() {
+            continue;
+        }
+        let i = orphans
+            .next()
+            .expect("Every beam is assigned a continuation.");
+        let parent = candidates[i].parent;
+        // Parents always keep a continuation of their own, so `parent != slot`.
+        let (parent_seq, seq) = if parent < slot {
+            let (left, right) = seqs.split_at_mut(slot);
+            (&*left[parent], &mut *right[0])
+        } else {
+            let (left, right) = seqs.split_at_mut(parent);
+            (&*right[0], &mut *left[slot])
+        };
+        seq.copy_beam_state_from(parent_seq);
+        *assigned = Some(i);
+        reordered = true;
+    }
+
+    for (seq, assigned) in seqs.iter_mut().zip(assigned) {
+        let candidate = &candidates[assigned.expect("Every beam is assigned a continuation.")];
+        finish_or_add_toks_to_seq(
+            this,
+            prefix_cacher,
+            seq,
+            candidate.logprobs(),
+            eos_tok,
+            true,
+        )
+        .await?;
+    }
+
+    Ok(reordered)
+}
+
+/// End the search of a group: every beam takes over one of the best hypotheses and finishes
+/// with its last token.
+async fn finish_group(
+    this: &dyn Pipeline,
+    mut seqs: Vec<&mut Sequence>,
+    candidates: &[Candidate],
+    live: &[usize],
+    finished_beams: Vec<FinishedBeam>,
+    prefix_cacher: &mut PrefixCacheManagerV2,
+    eos_tok: Option<&[u32]>,
+) -> Result<()> {
+    let finished_scores = finished_beams
+        .iter()
+        .map(|beam| beam.score)
+        .collect::<Vec<_>>();
+    let live_scores = live
+        .iter()
+        .map(|&i| candidates[i].score)
+        .collect::<Vec<_>>();
+    let parent_states = seqs.iter().map(|seq| seq.beam_state()).collect::<Vec<_>>();
+
+    let hypotheses = best_hypotheses(&finished_scores, &live_scores, seqs.len());
+    for (slot, (seq, hypothesis)) in seqs.iter_mut().zip(hypotheses).enumerate() {
+        // The KV cache of a beam only matches its own tokens, so other hypotheses are not
+        // added to the prefix cache.
+        let (logprobs, is_own) = match hypothesis {
+            Hypothesis::Finished(i) => {
+                seq.restore_beam_state(finished_beams[i].state.clone());
+                (finished_beams[i].logprobs.clone(), false)
+            }
+            Hypothesis::Live(i) => {
+                let candidate = &candidates[live[i]];
+                seq.restore_beam_state(parent_states[candidate.parent].clone());
+                (candidate.logprobs(), candidate.parent == slot)
+            }
+        };
+        finish_or_add_toks_to_seq(this, prefix_cacher, seq, logprobs, eos_tok, is_own).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(early_stopping: bool) -> BeamSearchParams {
+        BeamSearchParams {
+            num_beams: 2,
+            length_penalty: 1.0,
+            early_stopping,
+        }
+    }
+
+    fn candidate(token: u32, cumulative_logprob: f32, stop: Option<StopReason>) -> Candidate {
+        Candidate {
+            parent: 0,
+            token,
+            logprob: cumulative_logprob,
+            cumulative_logprob,
+            score: cumulative_logprob,
+            stop,
+        }
+    }
+
+    #[test]
+    fn test_finished_continuations_do_not_take_beams() {
+        let candidates = [
+            candidate(0, -0.1, Some(StopReason::Eos)),
+            candidate(1, -0.2, None),
+            candidate(2, -0.3, Some(StopReason::StopTok(2))),
+            candidate(3, -0.4, None),
+            candidate(4, -0.5, None),
+        ];
+        let (live, finished) = select_continuations(&candidates, 2);
+        assert_eq!(live, vec![1, 3]);
+        // Only finished continuations ranking among the two best are kept.
+        assert_eq!(finished, vec![0]);
+    }
+
+    #[test]
+    fn test_length_limit_stops_every_beam() {
+        let candidates = [
+            candidate(0, -0.1, Some(StopReason::Length(4))),
+            candidate(1, -0.2, Some(StopReason::Eos)),
+        ];
+        let (live, finished) = select_continuations(&candidates, 2);
+        assert_eq!(live, vec![0]);
+        assert_eq!(finished, vec![1]);
+        assert!(candidates[0].stops_every_beam());
+        assert!(!candidates[1].stops_every_beam());
+    }
+
+    #[test]
+    fn test_search_is_done() {
+        // Not done until every beam has a finished hypothesis.
+        assert!(!is_search_done(&params(false), &[-1.0], 2, Some(-2.0)));
+        assert!(!is_search_done(&params(true), &[-1.0], 2, Some(-2.0)));
+        // Done once the best live beam cannot beat the worst kept hypothesis.
+        assert!(is_search_done(&params(false), &[-1.0, -2.0], 2, Some(-2.5)));
+        assert!(!is_search_done(
+            &params(false),
+            &[-1.0, -2.0],
+            2,
+            Some(-1.5)
+        ));
+        // Early stopping ends the search as soon as there are enough hypotheses.
+        assert!(is_search_done(&params(true), &[-1.0, -2.0], 2, Some(-1.5)));
+        // Without live continuations, the search cannot go on.
+        assert!(is_search_done(&params(false), &[], 2, None));
+    }
+
+    #[test]
+    fn test_shared_prompts_take_the_logits_of_their_first_beam() {
+        let shared = [None, Some(0), None, Some(2), Some(0)];
+        assert_eq!(
+            share_prompt_logits(&shared, vec!["a", "b"]),
+            vec!["a", "a", "b", "b", "a"]
+        );
+    }
+
+    #[test]
+    fn test_best_hypotheses_merge_finished_and_live() {
+        let best = best_hypotheses(&[-1.0, -3.0], &[-2.0, -4.0], 3);
+        assert!(matches!(
+            best[..],
+            [
+                Hypothesis::Finished(0),
+                Hypothesis::Live(0),
+                Hypothesis::Finished(1)
+            ]
+        ));
+
+        let best = best_hypotheses(&[-1.0], &[], 2);
+        assert!(matches!(
+            best[..],
+            [Hypothesis::Finished(0), Hypothesis::Finished(0)]
+        ));
+    }
+}
@@ -372,11 +372,13 @@ impl Loader for GGUFLoader {
             layer_devices.push(device);
         }
 
-        // TODO: PagedAttention is not supported with CPU for now.
-        // This check is not really necessary because `get_device_layers` should prevent it.
-        let mapping_uses_cpu = mapper.get_unique_devices().iter().any(Device::is_cpu);
-        if mapping_uses_cpu {
-            warn!("Device mapping contains a mix of GPU and CPU. There is no CPU support for PagedAttention, disabling PagedAttention.");
+        // PagedAttention works on the CPU, but the KV cache is sized from a single device's memory, so a mix of GPU and
+        // CPU is not supported. This check is not really necessary because `get_device_layers` should prevent it.
+        let unique_devices = mapper.get_unique_devices();
+        let mapping_is_mixed = unique_devices.iter().any(Device::is_cpu)
+            && unique_devices.iter().any(|dev| !dev.is_cpu());
+        if mapping_is_mixed && paged_attn_config.is_some() {
+            warn!("Device mapping contains a mix of GPU and CPU. PagedAttention does not support mixed mappings, disabling PagedAttention.");
             paged_attn_config = None;
         }
 
@@ -473,6 +475,7 @@ impl Loader for GGUFLoader {
                 paged_attn_config.block_size,
                 internal_dtype,
                 paged_attn_config.cache_type,
+                paged_attn_config.preemption_mode,
                 model_config,
                 device,
                 &layer_devices,
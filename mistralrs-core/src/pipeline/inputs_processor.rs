@@ -80,6 +80,7 @@ pub mod text_models_inputs_processor {
         Tensor::cat(&padded_x[..], 0).map_err(anyhow::Error::msg)
     }
 
+    #[derive(Clone)]
     pub struct PagedAttentionMeta {
         pub sliding_window: Option<usize>,
         pub block_size: usize,
@@ -133,11 +134,11 @@ pub mod text_models_inputs_processor {
         pub seq_indices: Vec<usize>,
     }
 
-    // chunk_offset_toks is the number of tokens by which the tokens are offset,
+    // chunk_offset_toks is, for each sequence, the number of tokens by which its tokens are offset,
     // chunk_offset_toks / prompt_chunksize = number of batches
     #[allow(clippy::too_many_arguments)]
     pub fn make_prompt_chunk<T: WithDType + Debug>(
-        chunk_offset_toks: usize,
+        chunk_offset_toks: &[usize],
         toks: Vec<&[T]>,
         seq_ids: &[usize],
         device: &Device,
@@ -163,7 +164,8 @@ pub mod text_models_inputs_processor {
         let flash_attn = crate::using_flash_attn();
         let mut seqlens_q = if flash_attn { vec![0] } else { Vec::new() };
         let mut seqlens_k = if flash_attn { vec![0] } else { Vec::new() };
-        for (seq_id, ctxt) in seq_ids.iter().zip(toks) {
+        for ((seq_id, ctxt), &chunk_offset_toks) in seq_ids.iter().zip(toks).zip(chunk_offset_toks)
+        {
             let prompt_len = ctxt.len();
             let offset = last_n_context_len.unwrap_or_default();
             seqlen_offsets.push(offset.1 + chunk_offset_toks);
@@ -224,7 +226,8 @@ pub mod text_models_inputs_processor {
                         // Pad [0,start_idx) with _PAD_TOKEN_ID
                         slot_mapping.push(_PAD_SLOT_ID);
                     }
-                    ctxt_len.push(i);
+                    // Each token attends to the cached tokens before it and to itself.
+                    ctxt_len.push(i + 1);
 
                     let block_number = if i / paged_attn_metadata.block_size >= table.len() {
                         panic!(
@@ -315,18 +318,24 @@ pub mod text_models_inputs_processor {
             )?;
             let block_tables = block_tables.reshape(((), max_block_table_len))?;
 
-            let max_context_len = paged_attn_context_lens
+            let max_num_tokens = paged_attn_context_lens
                 .iter()
                 .map(|x| x.len())
                 .max()
                 .unwrap();
+            let max_context_len = paged_attn_context_lens
+                .iter()
+                .flatten()
+                .copied()
+                .max()
+                .unwrap_or(0);
 
             let context_lens = _make_tensor_with_pad(
                 paged_attn_context_lens
                     .iter()
                     .map(|x| x.iter().map(|x| *x as u32).collect::<Vec<_>>())
                     .collect::<Vec<_>>(),
-                max_context_len,
+                max_num_tokens,
                 0,
                 &Device::Cpu,
             )?
@@ -352,7 +361,7 @@ pub mod text_models_inputs_processor {
                 block_tables: Some(block_tables_map),
                 context_lens: Some(context_lens_map),
                 max_context_len: Some(max_context_len),
-                is_first_prompt_chunk: chunk_offset_toks == 0,
+                is_first_prompt_chunk: chunk_offset_toks.iter().all(|&offset| offset == 0),
             })
         } else {
             None
@@ -576,9 +585,12 @@ pub mod text_models_inputs_processor {
         paged_attn_metadata: Option<&mut PagedAttentionMeta>,
         mapper: Option<&dyn DeviceMapper>,
     ) -> Result<InnerInputProcessorOutput> {
-        let offset = input_seqs[0].token_offset();
+        let offsets = input_seqs
+            .iter()
+            .map(|seq| seq.token_offset())
+            .collect::<Vec<_>>();
         make_prompt_chunk(
-            offset,
+            &offsets,
             toks,
             &input_seqs.iter().map(|s| *s.id()).collect::<Vec<_>>(),
             device,
@@ -1,7 +1,7 @@
 use std::fmt::{self, Display};
 
 use crate::paged_attention::{
-    calculate_cache_config, ModelConfigLike, DEFAULT_PAGED_ATTENTION_BLOCK_SIZE,
+    calculate_cache_config, ModelConfigLike, PreemptionMode, DEFAULT_PAGED_ATTENTION_BLOCK_SIZE,
 };
 use crate::utils::debug::DeviceRepr;
 use crate::{DeviceLayerMapMetadata, DeviceMapMetadata, MemoryUsage, PagedAttentionConfig};
@@ -206,6 +206,8 @@ pub fn get_device_layers(
                 paged_attn_config
                     .map(|cfg| cfg.cache_type)
                     .unwrap_or_default(),
+                // Host memory for swapping does not count towards the device memory.
+                PreemptionMode::Recompute,
                 &*model_cfg,
                 &devices[0],
                 &devices.iter().map(|d| Some(d.clone())).collect::<Vec<_>>(),
@@ -303,6 +303,9 @@ pub enum ModelKind {
         draft: Box<ModelKind>,
     },
 
+    #[strum(to_string = "speculative: target: `{target}`, draft: n-gram lookup")]
+    NGramSpeculative { target: Box<ModelKind> },
+
     #[strum(to_string = "anymoe: target: `{target}`")]
     AnyMoe { target: Box<ModelKind> },
 }
@@ -364,7 +367,7 @@ impl ModelKind {
 
                 [t.quantized_kind(), d.quantized_kind()].concat()
             }
-            AnyMoe { target } => target.quantized_kind(),
+            NGramSpeculative { target } | AnyMoe { target } => target.quantized_kind(),
         }
     }
 
@@ -389,7 +392,7 @@ impl ModelKind {
 
                 [t.adapted_kind(), d.adapted_kind()].concat()
             }
-            AnyMoe { target } => target.adapted_kind(),
+            NGramSpeculative { target } | AnyMoe { target } => target.adapted_kind(),
         }
     }
 }
@@ -1,5 +1,6 @@
 mod amoe;
 mod auto;
+mod beam_search;
 pub mod chat_template;
 mod diffusion;
 mod embedding;
@@ -59,7 +60,10 @@ pub(crate) use processing::{
     apply_chat_template, BasicProcessor, MessagesAction, Processor, ProcessorCreator,
 };
 use rand_isaac::Isaac64Rng;
-pub use speculative::{SpeculativeConfig, SpeculativeLoader, SpeculativePipeline};
+pub(crate) use sampling::finish_unscheduled_seq;
+pub use speculative::{
+    SpeculativeConfig, SpeculativeDraft, SpeculativeLoader, SpeculativePipeline,
+};
 pub use speech::{SpeechLoader, SpeechPipeline};
 use std::any::Any;
 use std::collections::HashMap;
@@ -312,6 +316,8 @@ pub enum CacheBackendMetadata {
     },
     PagedAttention {
         metadata: PagedAttentionMeta,
+        blocks_to_swap_in: HashMap<usize, usize>,
+        blocks_to_swap_out: HashMap<usize, usize>,
         blocks_to_copy: HashMap<usize, Vec<usize>>,
     },
 }
@@ -402,6 +408,20 @@ pub trait Pipeline:
         return_raw_logits: bool,
     ) -> Result<ForwardInputsResult, candle_core::Error>;
 
+    /// Swap and copy the PagedAttention blocks of the KV cache as decided by the scheduler.
+    fn execute_scheduler_ops(
+        &self,
+        blocks_to_swap_in: &HashMap<usize, usize>,
+        blocks_to_swap_out: &HashMap<usize, usize>,
+        blocks_to_copy: &HashMap<usize, Vec<usize>>,
+    ) -> Result<(), candle_core::Error> {
+        self.get_metadata()
+            .cache_engine
+            .as_ref()
+            .expect("PagedAttention must have cache engines.")
+            .execute_scheduler_ops(blocks_to_swap_in, blocks_to_swap_out, blocks_to_copy)
+    }
+
     /// Returns the total of model execution time.
     #[allow(clippy::too_many_arguments)]
     async fn step(
@@ -416,6 +436,25 @@ pub trait Pipeline:
     ) -> Result<Duration, candle_core::Error> {
         match backend_metadata {
             CacheBackendMetadata::DefaultInstructions { pre_op, post_op } => {
+                // Prompt logprobs need the logits of every prompt position.
+                let forward_raw_logits = return_raw_logits
+                    || (is_prompt && input_seqs.iter().any(|seq| seq.wants_prompt_logprobs()));
+                // Only the first beam of a beam search runs the prompt, the other beams take
+                // over its KV cache with the first token.
+                let shared_beams = if is_prompt && !return_raw_logits {
+                    beam_search::shared_prompt_beams(input_seqs)
+                } else {
+                    vec![None; input_seqs.len()]
+                };
+                let all_seqs = input_seqs;
+                let mut forward_seqs = all_seqs
+                    .iter_mut()
+                    .zip(&shared_beams)
+                    .filter(|(_, leader)| leader.is_none())
+                    .map(|(seq, _)| &mut **seq)
+                    .collect::<Vec<_>>();
+                let input_seqs = &mut forward_seqs[..];
+                let tokenizer = self.tokenizer();
                 let inputs_iter =
                     std::iter::once(self.get_processor().inputs_processor().process_inputs(
                         self.tokenizer(),
@@ -425,7 +464,7 @@ pub trait Pipeline:
                         &self.device(),
                         self.get_metadata().no_kv_cache,
                         None,
-                        return_raw_logits,
+                        forward_raw_logits,
                         self.get_input_processor_config(),
                         None,
                         self.device_mapper(),
@@ -460,12 +499,22 @@ pub trait Pipeline:
                     }
 
                     let start = Instant::now();
-                    let raw_logits = self.forward_inputs(inputs, return_raw_logits)?;
+                    let raw_logits = self.forward_inputs(inputs, forward_raw_logits)?;
                     let end = Instant::now();
                     exec_duration += end.duration_since(start);
 
                     for (logit_idx, seq_idx) in seq_indices.into_iter().enumerate() {
-                        if let ForwardInputsResult::RawLogits { logits } = &raw_logits {
+                        if let (false, ForwardInputsResult::RawLogits { logits: all_logits }) =
+                            (return_raw_logits, &raw_logits)
+                        {
+                            logits[seq_idx] = Some(ForwardInputsResult::CausalGeneration {
+                                logits: sampling::score_prompt_logits(
+                                    &mut *input_seqs[seq_idx],
+                                    &all_logits.i(logit_idx)?,
+                                    tokenizer.as_deref(),
+                                )?,
+                            });
+                        } else if let ForwardInputsResult::RawLogits { logits } = &raw_logits {
                             raw_out_logits[seq_idx][i] =
                                 Some(logits.i(logit_idx)?.to_device(&Device::Cpu)?);
                         } else if let ForwardInputsResult::Embeddings { embeddings } = &raw_logits {
@@ -547,26 +596,33 @@ pub trait Pipeline:
                     ForwardInputsResult::RawLogits { .. }
                     | ForwardInputsResult::Embeddings { .. } => unreachable!(),
                     ForwardInputsResult::CausalGeneration { .. } => {
-                        self.sample_causal_gen(
-                            input_seqs,
-                            logits
-                                .into_iter()
-                                .map(|r| {
-                                    #[allow(irrefutable_let_patterns)]
-                                    let ForwardInputsResult::CausalGeneration { logits } = r
-                                    else {
-                                        unreachable!(
-                                            "All results must have same type, `CausalGeneration`"
-                                        )
-                                    };
-                                    logits
-                                })
-                                .collect::<Vec<_>>(),
-                            prefix_cacher,
-                            disable_eos_stop,
-                            rng,
-                        )
-                        .await?;
+                        let logits = beam_search::share_prompt_logits(&shared_beams, logits);
+                        // Prompts which are only partially prefilled do not sample a token yet.
+                        let (mut seqs, logits): (Vec<_>, Vec<_>) = all_seqs
+                            .iter_mut()
+                            .zip(logits)
+                            .filter(|(seq, _)| !seq.is_partial_prefill())
+                            .map(|(seq, r)| {
+                                #[allow(irrefutable_let_patterns)]
+                                let ForwardInputsResult::CausalGeneration { logits } = r
+                                else {
+                                    unreachable!(
+                                        "All results must have same type, `CausalGeneration`"
+                                    )
+                                };
+                                (&mut **seq, logits)
+                            })
+                            .unzip();
+                        if !seqs.is_empty() {
+                            self.sample_causal_gen(
+                                &mut seqs,
+                                logits,
+                                prefix_cacher,
+                                disable_eos_stop,
+                                rng,
+                            )
+                            .await?;
+                        }
                     }
                     ForwardInputsResult::Image { .. } => {
                         response::send_image_responses(
@@ -640,15 +696,21 @@ pub trait Pipeline:
             }
             CacheBackendMetadata::PagedAttention {
                 metadata,
+                blocks_to_swap_in,
+                blocks_to_swap_out,
                 blocks_to_copy,
             } => {
                 // Cloning might be bad?
-                self.get_metadata()
-                    .cache_engine
-                    .as_ref()
-                    .expect("PagedAttention must have cache engines.")
-                    .execute_scheduler_ops(&blocks_to_copy)?;
-
+                self.execute_scheduler_ops(
+                    &blocks_to_swap_in,
+                    &blocks_to_swap_out,
+                    &blocks_to_copy,
+                )?;
+
+                // Prompt logprobs need the logits of every prompt position.
+                let forward_raw_logits = return_raw_logits
+                    || (is_prompt && input_seqs.iter().any(|seq| seq.wants_prompt_logprobs()));
+                let tokenizer = self.tokenizer();
                 let inputs_iter =
                     std::iter::once(self.get_processor().inputs_processor().process_inputs(
                         self.tokenizer(),
@@ -658,7 +720,7 @@ pub trait Pipeline:
                         &self.device(),
                         self.get_metadata().no_kv_cache,
                         None,
-                        return_raw_logits,
+                        forward_raw_logits,
                         self.get_input_processor_config(),
                         Some(metadata),
                         self.device_mapper(),
@@ -677,12 +739,22 @@ pub trait Pipeline:
                     } = inputs.map_err(candle_core::Error::msg)?;
 
                     let start = Instant::now();
-                    let raw_logits = self.forward_inputs(inputs, return_raw_logits)?;
+                    let raw_logits = self.forward_inputs(inputs, forward_raw_logits)?;
                     let end = Instant::now();
                     exec_duration += end.duration_since(start);
 
                     for (logit_idx, seq_idx) in seq_indices.into_iter().enumerate() {
-                        if let ForwardInputsResult::RawLogits { logits } = &raw_logits {
+                        if let (false, ForwardInputsResult::RawLogits { logits: all_logits }) =
+                            (return_raw_logits, &raw_logits)
+                        {
+                            logits[seq_idx] = Some(ForwardInputsResult::CausalGeneration {
+                                logits: sampling::score_prompt_logits(
+                                    &mut *input_seqs[seq_idx],
+                                    &all_logits.i(logit_idx)?,
+                                    tokenizer.as_deref(),
+                                )?,
+                            });
+                        } else if let ForwardInputsResult::RawLogits { logits } = &raw_logits {
                             raw_out_logits[seq_idx][i] =
                                 Some(logits.i(logit_idx)?.to_device(&Device::Cpu)?);
                         } else if let ForwardInputsResult::Embeddings { embeddings } = &raw_logits {
@@ -749,24 +821,30 @@ pub trait Pipeline:
                     ForwardInputsResult::RawLogits { .. }
                     | ForwardInputsResult::Embeddings { .. } => unreachable!(),
                     ForwardInputsResult::CausalGeneration { .. } => {
-                        self.sample_causal_gen(
-                            input_seqs,
-                            logits
-                                .into_iter()
-                                .map(|r| {
-                                    #[allow(irrefutable_let_patterns)]
-                                    let ForwardInputsResult::CausalGeneration { logits } = r
-                                    else {
-                                        unreachable!("All results must have same type")
-                                    };
-                                    logits
-                                })
-                                .collect::<Vec<_>>(),
-                            prefix_cacher,
-                            disable_eos_stop,
-                            rng,
-                        )
-                        .await?;
+                        // Prompts which are only partially prefilled do not sample a token yet.
+                        let (mut seqs, logits): (Vec<_>, Vec<_>) = input_seqs
+                            .iter_mut()
+                            .zip(logits)
+                            .filter(|(seq, _)| !seq.is_partial_prefill())
+                            .map(|(seq, r)| {
+                                #[allow(irrefutable_let_patterns)]
+                                let ForwardInputsResult::CausalGeneration { logits } = r
+                                else {
+                                    unreachable!("All results must have same type")
+                                };
+                                (&mut **seq, logits)
+                            })
+                            .unzip();
+                        if !seqs.is_empty() {
+                            self.sample_causal_gen(
+                                &mut seqs,
+                                logits,
+                                prefix_cacher,
+                                disable_eos_stop,
+                                rng,
+                            )
+                            .await?;
+                        }
                     }
                     ForwardInputsResult::Image { .. } => {
                         response::send_image_responses(
@@ -1,17 +1,19 @@
 use std::sync::Arc;
 
-use candle_core::{DType, Result, Tensor};
+use candle_core::{DType, Result, Tensor, D};
 use rand_isaac::Isaac64Rng;
 
 use crate::{
     prefix_cacher::PrefixCacheManagerV2,
-    sampler::Logprobs,
-    sequence::{Sequence, SequenceRecognizer, SequenceState, StopReason},
-    tools::{parse_text_tools, ToolCallResponse, ToolCallType},
+    response::{Response, ResponseLogprob},
+    sampler::{partial_sort_top_k, Logprobs, TopLogprob},
+    sequence::{SeqStepType, Sequence, SequenceRecognizer, SequenceState, StopReason},
+    tools::{parse_text_tools, ToolCallDelta, ToolCallResponse, ToolCallType},
 };
 use mistralrs_mcp::CalledFunction;
+use tokenizers::Tokenizer;
 
-use super::Pipeline;
+use super::{beam_search, CacheManagerMixin, Pipeline};
 
 macro_rules! fixup_sentencepiece {
     ($txt:expr) => {
@@ -128,19 +130,32 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                                             arguments: tc.arguments,
                                         },
                                     })
+                                    .map(ToolCallDelta::from)
                                     .collect()
                             } else {
                                 vec![]
                             }
                         } else {
-                            // Not in Harmony mode - parse text for tool calls
+                            // Not in Harmony mode - parse text for tool calls, and send what was not
+                            // streamed yet
                             let (_, tool_calls) =
                                 parse_text_tools(this, delta.as_str(), seq.tools.clone())
                                     .map_err(candle_core::Error::msg)?;
+                            let partial = seq
+                                .tools
+                                .as_ref()
+                                .map(|t| t.partial_calls(&delta))
+                                .unwrap_or_default();
                             if !tool_calls.is_empty() {
                                 is_done = Some(StopReason::ToolCalls);
+                                seq.tool_call_stream_mut().finish(&partial, tool_calls)
+                            } else if seq.tool_call_stream_mut().is_started() {
+                                // A streamed call which never parsed is also sent as text, as the
+                                // held back text would be lost otherwise
+                                seq.tool_call_stream_mut().deltas(&partial)
+                            } else {
+                                vec![]
                             }
-                            tool_calls
                         };
 
                         seq.add_streaming_chunk_choice_to_group(crate::ChunkChoice {
@@ -162,6 +177,7 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                             } else {
                                 None
                             },
+                            stop_sequence: is_done.and_then(|reason| stop_sequence(seq, reason)),
                         });
                     } else {
                         seq.add_streaming_completion_chunk_choice_to_group(
@@ -207,6 +223,41 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                 ));
                 this.reset_non_granular_state();
             }
+        } else if seq.get_mut_group().is_chat && !seq.is_harmony_mode() {
+            // The output may be a tool call: stream the fragments of the calls generated so far. The text is
+            // held back, so the calls are parsed from their start.
+            let tool_calls = match (seq.tools.clone(), seq.peek_delta()) {
+                (Some(t), Ok(Some(d))) => {
+                    let partial = t.partial_calls(&d);
+                    seq.tool_call_stream_mut().deltas(&partial)
+                }
+                _ => vec![],
+            };
+            if !tool_calls.is_empty() {
+                seq.add_streaming_chunk_choice_to_group(crate::ChunkChoice {
+                    delta: crate::Delta {
+                        content: None,
+                        role: "assistant".to_string(),
+                        tool_calls: Some(tool_calls),
+                        reasoning_content: None,
+                    },
+                    index: seq.get_response_index(),
+                    finish_reason: None,
+                    logprobs: None,
+                    stop_sequence: None,
+                });
+                if seq
+                    .get_mut_group()
+                    .maybe_send_streaming_response(seq, this.name().clone(), None)
+                    .await
+                    .is_err()
+                {
+                    seq.set_state(crate::sequence::SequenceState::Done(
+                        crate::sequence::StopReason::Canceled,
+                    ));
+                    this.reset_non_granular_state();
+                }
+            }
         }
 
         // Handle Done state regardless of tool detection - must be outside the tool_use check
@@ -236,27 +287,30 @@ pub(crate) async fn finish_or_add_toks_to_seq(
             let logprobs = if seq.return_logprobs() {
                 let mut logprobs = Vec::new();
                 for logprob in seq.logprobs() {
-                    let resp_logprob = crate::ResponseLogprob {
-                        token: crate::handle_seq_error_ok!(
-                        tokenizer
-                        .as_ref()
-                        .ok_or(candle_core::Error::Msg(
-                            "`finish_or_add_toks_to_seq` requires the pipeline to have a tokenizer"
-                                .to_string(),
-                        ))?.decode(&[logprob.token], false),
+                    logprobs.push(crate::handle_seq_error_ok!(
+                        response_logprob(tokenizer.as_deref(), logprob),
                         seq.responder()
-                    ),
-                        bytes: logprob.bytes.clone().map(|b| b.into_bytes()),
-                        logprob: logprob.logprob,
-                        top_logprobs: logprob.top_logprobs.clone().unwrap(),
-                    };
-                    logprobs.push(resp_logprob);
+                    ));
                 }
                 Some(logprobs)
             } else {
                 None
             };
 
+            let prompt_logprobs = if let Some(scored) = seq.prompt_logprobs() {
+                // The first prompt token is not predicted by the model.
+                let mut prompt_logprobs = vec![None];
+                for logprob in scored {
+                    prompt_logprobs.push(Some(crate::handle_seq_error_ok!(
+                        response_logprob(tokenizer.as_deref(), logprob),
+                        seq.responder()
+                    )));
+                }
+                Some(prompt_logprobs)
+            } else {
+                None
+            };
+
             // Signal EOS to Harmony parser if in Harmony mode
             seq.harmony_process_eos();
 
@@ -269,6 +323,7 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                 | crate::sequence::StopReason::Eos
                 | crate::sequence::StopReason::StopTok(_)
                 | crate::sequence::StopReason::Canceled
+                | crate::sequence::StopReason::Timeout
                 | crate::sequence::StopReason::ToolCalls => {
                     String::from_utf8_lossy(seq.completion_bytes())
                         .trim_start()
@@ -336,6 +391,7 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                     (text_new.map(ToString::to_string), tool_calls, None)
                 };
 
+                let stop_sequence = stop_sequence(seq, reason);
                 if !tool_calls.is_empty() {
                     reason = StopReason::ToolCalls;
                 }
@@ -350,14 +406,44 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                         reasoning_content,
                     },
                     logprobs: logprobs.map(|l| crate::Logprobs { content: Some(l) }),
+                    stop_sequence,
                 };
                 seq.add_choice_to_group(choice);
             } else {
+                let completion_logprobs = match &logprobs {
+                    Some(logprobs) => {
+                        // Echoed prompt tokens come first, as in the OpenAI legacy API.
+                        let echoed = match (&prompt_logprobs, seq.echoed_prompt()) {
+                            (Some(prompt_logprobs), Some(prompt)) => {
+                                let mut tokens = Vec::with_capacity(seq.prompt_tokens());
+                                for token in &seq.get_toks()[..seq.prompt_tokens()] {
+                                    tokens.push(crate::handle_seq_error_ok!(
+                                        decode_token(tokenizer.as_deref(), *token),
+                                        seq.responder()
+                                    ));
+                                }
+                                Some((prompt, tokens, prompt_logprobs.as_slice()))
+                            }
+                            _ => None,
+                        };
+                        Some(match echoed {
+                            Some((prompt, tokens, prompt_logprobs)) => {
+                                crate::CompletionLogprobs::new(
+                                    &format!("{prompt}{text}"),
+                                    Some((tokens.as_slice(), prompt_logprobs)),
+                                    logprobs,
+                                )
+                            }
+                            None => crate::CompletionLogprobs::new(&text, None, logprobs),
+                        })
+                    }
+                    None => None,
+                };
                 let choice = crate::CompletionChoice {
                     finish_reason: fixup_sentencepiece!(reason),
                     index: seq.get_response_index(),
                     text,
-                    logprobs: None,
+                    logprobs: completion_logprobs,
                 };
                 seq.add_completion_choice_to_group(choice);
             }
@@ -373,12 +459,13 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                     .maybe_send_chat_done_response(
                         crate::ChatCompletionResponse {
                             id: seq.id().to_string(),
-                            choices: group.get_choices().to_vec(),
+                            choices: group.get_choices(),
                             created: seq.creation_time(),
                             model: pipeline_name,
                             system_fingerprint: crate::SYSTEM_FINGERPRINT.to_string(),
                             object: "chat.completion".to_string(),
                             usage: group.get_usage(),
+                            prompt_logprobs: prompt_logprobs.clone(),
                         },
                         seq.responder(),
                     )
@@ -395,6 +482,7 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                             system_fingerprint: crate::SYSTEM_FINGERPRINT.to_string(),
                             object: "text_completion".to_string(),
                             usage: group.get_usage(),
+                            prompt_logprobs: prompt_logprobs.clone(),
                         },
                         seq.responder(),
                     )
@@ -408,6 +496,125 @@ pub(crate) async fn finish_or_add_toks_to_seq(
     Ok(())
 }
 
+/// Finish a sequence which is not decoding, such as a waiting sequence past its deadline, and
+/// respond with what it generated so far. Sequences which do not generate text get an error.
+pub(crate) async fn finish_unscheduled_seq(seq: &mut Sequence, reason: StopReason, model: String) {
+    seq.set_state(SequenceState::Done(reason));
+    if matches!(seq.sequence_stepping_type(), SeqStepType::OneShot) || seq.return_raw_logits {
+        let e = anyhow::anyhow!("The request finished with reason `{reason}` before it was run");
+        // The receiver of a canceled request may be gone.
+        let _ = seq
+            .responder()
+            .send(Response::InternalError(e.into()))
+            .await;
+        return;
+    }
+
+    let text = String::from_utf8_lossy(seq.completion_bytes())
+        .trim_start()
+        .to_string();
+    let is_chat = seq.get_mut_group().is_chat;
+    if seq.get_mut_group().is_streaming {
+        // Everything generated so far was streamed already.
+        if is_chat {
+            seq.add_streaming_chunk_choice_to_group(crate::ChunkChoice {
+                delta: crate::Delta {
+                    content: None,
+                    role: "assistant".to_string(),
+                    tool_calls: None,
+                    reasoning_content: None,
+                },
+                index: seq.get_response_index(),
+                finish_reason: Some(reason.to_string()),
+                logprobs: None,
+                stop_sequence: None,
+            });
+        } else {
+            seq.add_streaming_completion_chunk_choice_to_group(crate::CompletionChunkChoice {
+                text: String::new(),
+                index: seq.get_response_index(),
+                finish_reason: Some(reason.to_string()),
+                logprobs: None,
+            });
+        }
+        let usage = seq.get_mut_group().get_usage();
+        let _ = seq
+            .get_mut_group()
+            .maybe_send_streaming_response(seq, model, Some(usage))
+            .await;
+    } else if is_chat {
+        seq.add_choice_to_group(crate::Choice {
+            finish_reason: reason.to_string(),
+            index: seq.get_response_index(),
+            message: crate::ResponseMessage {
+                content: Some(text),
+                role: "assistant".to_string(),
+                tool_calls: None,
+                reasoning_content: None,
+            },
+            logprobs: None,
+            stop_sequence: None,
+        });
+        let group = seq.get_mut_group();
+        let _ = group
+            .maybe_send_chat_done_response(
+                crate::ChatCompletionResponse {
+                    id: seq.id().to_string(),
+                    choices: group.get_choices(),
+                    created: seq.creation_time(),
+                    model,
+                    system_fingerprint: crate::SYSTEM_FINGERPRINT.to_string(),
+                    object: "chat.completion".to_string(),
+                    usage: group.get_usage(),
+                    prompt_logprobs: None,
+                },
+                seq.responder(),
+            )
+            .await;
+    } else {
+        seq.add_completion_choice_to_group(crate::CompletionChoice {
+            finish_reason: reason.to_string(),
+            index: seq.get_response_index(),
+            text,
+            logprobs: None,
+        });
+        let group = seq.get_mut_group();
+        let _ = group
+            .maybe_send_completion_done_response(
+                crate::CompletionResponse {
+                    id: seq.id().to_string(),
+                    choices: group.get_completion_choices().to_vec(),
+                    created: seq.creation_time(),
+                    model,
+                    system_fingerprint: crate::SYSTEM_FINGERPRINT.to_string(),
+                    object: "text_completion".to_string(),
+                    usage: group.get_usage(),
+                    prompt_logprobs: None,
+                },
+                seq.responder(),
+            )
+            .await;
+    }
+}
+
+fn decode_token(tokenizer: Option<&Tokenizer>, token: u32) -> Result<String> {
+    tokenizer
+        .ok_or(candle_core::Error::Msg(
+            "`finish_or_add_toks_to_seq` requires the pipeline to have a tokenizer".to_string(),
+        ))?
+        .decode(&[token], false)
+        .map_err(candle_core::Error::msg)
+}
+
+fn response_logprob(tokenizer: Option<&Tokenizer>, logprob: &Logprobs) -> Result<ResponseLogprob> {
+    Ok(ResponseLogprob {
+        token: decode_token(tokenizer, logprob.token)?,
+        bytes: logprob.bytes.clone().map(|b| b.into_bytes()),
+        logprob: logprob.logprob,
+        top_logprobs: logprob.top_logprobs.clone().unwrap_or_default(),
+    })
+}
+
 pub async fn sample_and_add_toks(
     this: &dyn Pipeline,
     seqs: &mut [&mut Sequence],
@@ -419,13 +626,32 @@ pub async fn sample_and_add_toks(
     let seqs_len = seqs.len();
     debug_assert_eq!(logits_seq.len(), seqs_len);
 
-    let use_async_pool = seqs_len > 1;
+    let metadata = this.get_metadata();
+    let eos_tok = if disable_eos_stop {
+        None
+    } else {
+        Some(&metadata.eos_tok[..])
+    };
+
+    // Beam search sequences are advanced per group rather than sampled independently.
+    let mut sampled_seqs = Vec::with_capacity(seqs_len);
+    let mut beams = Vec::new();
+    for (seq, logits) in std::iter::zip(seqs.iter_mut(), logits_seq) {
+        if seq.beam_search().is_some() {
+            beams.push((&mut **seq, logits));
+        } else {
+            sampled_seqs.push((&mut **seq, logits));
+        }
+    }
+
+    let use_async_pool = sampled_seqs.len() > 1;
 
-    let sampling_futures: Vec<_> = std::iter::zip(logits_seq, seqs.iter_mut())
-        .map(|(logits_per_seq, seq)| {
+    let sampling_futures: Vec<_> = sampled_seqs
+        .iter_mut()
+        .map(|(seq, logits_per_seq)| {
             let return_logprobs = seq.return_logprobs();
             sample_sequence(
-                logits_per_seq,
+                logits_per_seq.clone(),
                 seq,
                 return_logprobs,
                 rng.clone(),
@@ -437,22 +663,95 @@ pub async fn sample_and_add_toks(
         .collect();
     let sampled_vec = futures::future::join_all(sampling_futures).await;
 
-    for (sampled, seq) in std::iter::zip(sampled_vec, seqs.iter_mut()) {
+    for (sampled, (seq, _)) in std::iter::zip(sampled_vec, sampled_seqs) {
         let next_token = crate::handle_seq_error_stateaware_ok!(sampled, seq);
 
-        let metadata = this.get_metadata();
-        let eos_tok = if disable_eos_stop {
-            None
-        } else {
-            Some(&metadata.eos_tok[..])
-        };
-
         finish_or_add_toks_to_seq(this, prefix_cacher, seq, next_token, eos_tok, true).await?;
     }
 
+    if !beams.is_empty()
+        && beam_search::step_beams(this, beams, prefix_cacher, eos_tok).await?
+        && !metadata.no_kv_cache
+    {
+        // Beams were continued from other beams, so rebuild the model cache from theirs.
+        this.clone_in_cache(seqs);
+    }
+
     Ok(())
 }
 
+/// The stop sequence of `seq` which `reason` reports, if any.
+fn stop_sequence(seq: &Sequence, reason: StopReason) -> Option<String> {
+    match reason {
+        StopReason::StopString {
+            stop_string_idx, ..
+        } => seq.stop_strings().get(stop_string_idx).cloned(),
+        _ => None,
+    }
+}
+
+/// Score the prompt tokens of `seq` which follow the positions of `logits`, the logits of every
+/// position of its prompt step. Returns the logits of the last position, used for sampling.
+pub(crate) fn score_prompt_logits(
+    seq: &mut Sequence,
+    logits: &Tensor,
+    tokenizer: Option<&Tokenizer>,
+) -> Result<Tensor> {
+    // The prompts of a batch are padded at the end to the longest one.
+    let n_positions = seq.get_toks().len();
+    let last_logits = logits.narrow(0, n_positions - 1, 1)?;
+    let Some(top_n) = seq
+        .prompt_logprobs_top_n()
+        .filter(|_| seq.wants_prompt_logprobs())
+    else {
+        return Ok(last_logits);
+    };
+
+    // Row `i` predicts the token after position `token_offset + i`.
+    let start = seq.token_offset();
+    let n_scored = n_positions.min(seq.prompt_tokens().saturating_sub(start + 1));
+    if n_scored == 0 {
+        return Ok(last_logits);
+    }
+    // Base 10, like the logprobs reported by the sampler.
+    let logprobs = (candle_nn::ops::log_softmax(
+        &logits.narrow(0, 0, n_scored)?.to_dtype(DType::F32)?,
+        D::Minus1,
+    )? / std::f64::consts::LN_10)?
+        .to_vec2::<f32>()?;
+
+    let decode = |token: u32| {
+        tokenizer
+            .map(|tokenizer| tokenizer.decode(&[token], false))
+            .transpose()
+            .map_err(candle_core::Error::msg)
+    };
+    let mut scored = Vec::with_capacity(n_scored);
+    for (i, mut row) in logprobs.into_iter().enumerate() {
+        let token = seq.full_toks()[start + i + 1];
+        let logprob = row[token as usize];
+        let top_logprobs = partial_sort_top_k(&mut row, top_n, false)
+            .into_iter()
+            .map(|(token, logprob)| {
+                Ok(TopLogprob {
+                    token,
+                    logprob,
+                    bytes: decode(token)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        scored.push(Logprobs {
+            token,
+            logprob,
+            bytes: decode(token)?,
+            top_logprobs: Some(top_logprobs),
+        });
+    }
+    seq.add_prompt_logprobs(scored);
+
+    Ok(last_logits)
+}
+
 /// Async sample optionally adding to trie.
 #[allow(clippy::too_many_arguments)]
 pub async fn sample_sequence(
@@ -465,6 +764,8 @@ pub async fn sample_sequence(
     multiple_sequences: bool,
 ) -> Result<Logprobs> {
     let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+    // Seeded sequences draw from their own RNG so batching does not affect them.
+    let rng = seq.rng().unwrap_or(rng);
 
     let sampler = seq.sampler();
     let ctx_clone = seq.get_toks().to_vec();
@@ -614,3 +915,151 @@ pub async fn sample_target_sequence_speculative(
     }
     Ok(sampled)
 }
+
+/// Verify n-gram proposals: `logits` has one position per proposal plus one more, and sampling
+/// stops after the first target sample which differs from the proposal at its position.
+/// Each position is sampled with the sequence's own sampler, with the accepted proposals
+/// temporarily appended as context, so the output matches non-speculative decoding.
+/// The proposals never advanced the LLG state, so unlike [`sample_target_sequence_speculative`]
+/// there is nothing to roll back.
+pub async fn sample_target_sequence_ngram(
+    logits: Tensor,
+    seq: &mut Sequence,
+    return_logprobs: bool,
+    rng: Arc<std::sync::Mutex<Isaac64Rng>>,
+    proposals: &[u32],
+) -> Result<Vec<Logprobs>> {
+    let mut sampled = Vec::new();
+    let mut n_tmp = 0;
+    for (chunk, proposal) in logits
+        .chunk(proposals.len() + 1, 1)?
+        .into_iter()
+        .zip(proposals.iter().map(Some).chain([None]))
+    {
+        let sample =
+            sample_sequence(chunk, seq, return_logprobs, rng.clone(), true, false, false).await?;
+        let sampled_token = sample.token;
+        sampled.push(sample);
+        if proposal != Some(&sampled_token) {
+            break;
+        }
+        seq.add_tmp_tok(sampled_token);
+        n_tmp += 1;
+    }
+    if n_tmp > 0 {
+        seq.remove_tmp_tok(n_tmp);
+    }
+    Ok(sampled)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use candle_core::{DType, Device, Tensor};
+    use rand::SeedableRng;
+    use rand_isaac::Isaac64Rng;
+
+    use super::{sample_sequence, score_prompt_logits};
+    use crate::{
+        sampler::Sampler,
+        scheduler::test_utils::{test_sequence, test_sequence_with_sampler},
+    };
+
+    fn sampler() -> Sampler {
+        Sampler::new(
+            Some(1.0),
+            0,
+            None,
+            None,
+            None,
+            None,
+            None,
+            -1,
+            1.0,
+            0.0,
+            0.0,
+            0.0,
+            None,
+            None,
+            None,
+            vec![],
+        )
+        .unwrap()
+    }
+
+    /// The tokens sampled by a sequence seeded with `seed`, while an unseeded sequence samples
+    /// from the engine RNG in between if `interleave` is set.
+    async fn seeded_tokens(seed: u64, interleave: bool) -> Vec<u32> {
+        let engine_rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(0)));
+        let mut seeded = test_sequence_with_sampler(0, 0, 1, None, sampler());
+        seeded.set_rng_seed(seed);
+        let mut unseeded = test_sequence_with_sampler(1, 0, 1, None, sampler());
+        let logits = || Tensor::zeros((1, 1, 64), DType::F32, &Device::Cpu).unwrap();
+
+        let mut tokens = Vec::new();
+        for _ in 0..16 {
+            if interleave {
+                sample_sequence(
+                    logits(),
+                    &mut unseeded,
+                    false,
+                    engine_rng.clone(),
+                    false,
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            }
+            let sampled = sample_sequence(
+                logits(),
+                &mut seeded,
+                false,
+                engine_rng.clone(),
+                false,
+                false,
+                interleave,
+            )
+            .await
+            .unwrap();
+            tokens.push(sampled.token);
+        }
+        tokens
+    }
+
+    #[tokio::test]
+    async fn test_seeded_sequences_ignore_the_engine_rng() {
+        let alone = seeded_tokens(1, false).await;
+        assert_eq!(alone, seeded_tokens(1, true).await);
+        assert_ne!(alone, seeded_tokens(2, false).await);
+    }
+
+    #[test]
+    fn test_prompt_logits_of_prompts_with_different_lengths() {
+        // The logits of a batch with prompts of 3 and 5 tokens, the first one padded.
+        let logits = Tensor::arange(0f32, 40f32, &Device::Cpu)
+            .unwrap()
+            .reshape((5, 8))
+            .unwrap();
+        for prompt_len in [3, 5] {
+            let mut seq = test_sequence(0, 0, prompt_len, None);
+            seq.set_prompt_logprobs(1);
+            let last_logits = score_prompt_logits(&mut seq, &logits, None).unwrap();
+            assert_eq!(
+                last_logits.to_vec2::<f32>().unwrap(),
+                logits
+                    .narrow(0, prompt_len - 1, 1)
+                    .unwrap()
+                    .to_vec2::<f32>()
+                    .unwrap()
+            );
+            let logprobs = seq.prompt_logprobs().unwrap();
+            assert_eq!(logprobs.len(), prompt_len - 1);
+            assert!(logprobs
+                .iter()
+                .zip(1..)
+                .all(|(logprobs, token)| logprobs.token == token));
+        }
+    }
+}
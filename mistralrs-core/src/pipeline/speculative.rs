@@ -1,5 +1,6 @@
 use std::{
     any::Any,
+    collections::HashMap,
     sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
@@ -9,17 +10,18 @@ use candle_core::{Device, IndexOp, Result, Tensor};
 use mistralrs_quant::IsqType;
 use rand_isaac::Isaac64Rng;
 use tokenizers::Tokenizer;
-use tracing::warn;
 
 use crate::{
     device_map::DeviceMapper,
     get_mut_arcmutex,
-    kv_cache::NormalCacheManager,
+    kv_cache::{KvCache, NormalCacheManager},
+    paged_attention::{CacheConfig, MemoryGpuConfig},
     pipeline::sampling::{
-        finish_or_add_toks_to_seq, sample_sequence, sample_target_sequence_speculative,
+        finish_or_add_toks_to_seq, sample_sequence, sample_target_sequence_ngram,
+        sample_target_sequence_speculative,
     },
     prefix_cacher::PrefixCacheManagerV2,
-    sequence::Sequence,
+    sequence::{Sequence, SequenceState},
     DeviceMapSetting, Loader, ModelKind, PagedAttentionConfig, Pipeline, TokenSource, TryIntoDType,
 };
 
@@ -27,19 +29,69 @@ use crate::kv_cache::CacheManager;
 use crate::utils::progress::ProgressScopeGuard;
 
 use super::{
-    chat_template::ChatTemplate, sampling::SpeculativeSample, AnyMoePipelineMixin,
-    CacheBackendMetadata, CacheInstruction, CacheManagerMixin, EitherCache, ForwardInputsResult,
-    GeneralMetadata, IsqPipelineMixin, MetadataMixin, ModelCategory, ModelPaths,
-    PreProcessingMixin,
+    chat_template::ChatTemplate, sampling::SpeculativeSample,
+    text_models_inputs_processor::PagedAttentionMeta, AnyMoePipelineMixin, CacheBackendMetadata,
+    CacheInstruction, CacheManagerMixin, EitherCache, ForwardInputsResult, GeneralMetadata,
+    IsqPipelineMixin, MetadataMixin, ModelCategory, ModelPaths, PreProcessingMixin,
 };
 
-/// A loader for a speculative pipeline using 2 [`Loader`]s.
+/// A loader for a speculative pipeline using a target [`Loader`] and, unless the
+/// [`SpeculativeDraft::NGram`] draft is used, a draft [`Loader`].
 pub struct SpeculativeLoader {
     pub target: Box<dyn Loader>,
-    pub draft: Box<dyn Loader>,
+    pub draft: Option<Box<dyn Loader>>,
     pub config: SpeculativeConfig,
 }
 
+impl SpeculativeLoader {
+    fn check_draft(&self) -> anyhowResult<()> {
+        match (&self.config.draft, &self.draft) {
+            (SpeculativeDraft::Model, None) => {
+                anyhow::bail!("Speculative decoding with a draft model requires a draft loader.")
+            }
+            (SpeculativeDraft::NGram { .. }, Some(_)) => {
+                anyhow::bail!("N-gram speculative decoding does not use a draft model.")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn build(
+        &self,
+        target: Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>,
+        draft: Option<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>>,
+    ) -> anyhowResult<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>> {
+        let pipeline = match draft {
+            Some(draft) => SpeculativePipeline::new(target, draft, self.config)?,
+            None => SpeculativePipeline::new_ngram(target, self.config)?,
+        };
+        Ok(Arc::new(tokio::sync::Mutex::new(pipeline)))
+    }
+
+    /// The draft model keeps its own PagedAttention KV cache, addressed through the block tables of
+    /// the target, so it is sized to as many blocks of the same size as the target has.
+    fn draft_paged_attn_config(
+        target: &Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>,
+        paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> anyhowResult<Option<PagedAttentionConfig>> {
+        let (Some(paged_attn_config), Some(cache_config)) = (
+            paged_attn_config,
+            get_mut_arcmutex!(target)
+                .get_metadata()
+                .cache_config
+                .clone(),
+        ) else {
+            return Ok(None);
+        };
+        PagedAttentionConfig::new(
+            Some(cache_config.block_size),
+            MemoryGpuConfig::ContextSize(cache_config.num_gpu_blocks * cache_config.block_size),
+            paged_attn_config.cache_type,
+        )
+        .map(|config| Some(config.with_preemption_mode(paged_attn_config.preemption_mode)))
+    }
+}
+
 impl Loader for SpeculativeLoader {
     #[allow(clippy::type_complexity, clippy::too_many_arguments)]
     fn load_model_from_hf(
@@ -54,14 +106,7 @@ impl Loader for SpeculativeLoader {
         paged_attn_config: Option<PagedAttentionConfig>,
     ) -> anyhowResult<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>> {
         let _progress_guard = ProgressScopeGuard::new(silent);
-        let paged_attn_config = if paged_attn_config.is_none() {
-            warn!(
-                "Speculative decoding does not currently support PagedAttention, running without"
-            );
-            None
-        } else {
-            paged_attn_config
-        };
+        self.check_draft()?;
 
         let target = self.target.load_model_from_hf(
             revision.clone(),
@@ -73,21 +118,24 @@ impl Loader for SpeculativeLoader {
             in_situ_quant,
             paged_attn_config,
         )?;
-        let draft = self.draft.load_model_from_hf(
-            revision,
-            token_source,
-            dtype,
-            device,
-            silent,
-            mapper,
-            in_situ_quant,
-            paged_attn_config,
-        )?;
-        Ok(Arc::new(tokio::sync::Mutex::new(SpeculativePipeline::new(
-            target,
-            draft,
-            self.config,
-        )?)))
+        let paged_attn_config = Self::draft_paged_attn_config(&target, paged_attn_config)?;
+        let draft = self
+            .draft
+            .as_ref()
+            .map(|draft| {
+                draft.load_model_from_hf(
+                    revision,
+                    token_source,
+                    dtype,
+                    device,
+                    silent,
+                    mapper,
+                    in_situ_quant,
+                    paged_attn_config,
+                )
+            })
+            .transpose()?;
+        self.build(target, draft)
     }
 
     #[allow(clippy::type_complexity, clippy::too_many_arguments)]
@@ -102,14 +150,7 @@ impl Loader for SpeculativeLoader {
         paged_attn_config: Option<PagedAttentionConfig>,
     ) -> anyhowResult<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>> {
         let _progress_guard = ProgressScopeGuard::new(silent);
-        let paged_attn_config = if paged_attn_config.is_none() {
-            warn!(
-                "Speculative decoding does not currently support PagedAttention, running without"
-            );
-            None
-        } else {
-            paged_attn_config
-        };
+        self.check_draft()?;
 
         let target = self.target.load_model_from_path(
             paths,
@@ -120,33 +161,49 @@ impl Loader for SpeculativeLoader {
             in_situ_quant,
             paged_attn_config,
         )?;
-        let draft = self.draft.load_model_from_path(
-            paths,
-            dtype,
-            device,
-            silent,
-            mapper.clone(),
-            in_situ_quant,
-            paged_attn_config,
-        )?;
-        Ok(Arc::new(tokio::sync::Mutex::new(SpeculativePipeline::new(
-            target,
-            draft,
-            self.config,
-        )?)))
+        let paged_attn_config = Self::draft_paged_attn_config(&target, paged_attn_config)?;
+        let draft = self
+            .draft
+            .as_ref()
+            .map(|draft| {
+                draft.load_model_from_path(
+                    paths,
+                    dtype,
+                    device,
+                    silent,
+                    mapper.clone(),
+                    in_situ_quant,
+                    paged_attn_config,
+                )
+            })
+            .transpose()?;
+        self.build(target, draft)
     }
     fn get_id(&self) -> String {
-        format!(
-            "Speculative: tgt = `{}`, draft = `{}`, gamma = `{}`",
-            self.target.get_id(),
-            self.draft.get_id(),
-            self.config.gamma,
-        )
+        match &self.draft {
+            Some(draft) => format!(
+                "Speculative: tgt = `{}`, draft = `{}`, gamma = `{}`",
+                self.target.get_id(),
+                draft.get_id(),
+                self.config.gamma,
+            ),
+            None => format!(
+                "Speculative: tgt = `{}`, draft = `{}`, gamma = `{}`",
+                self.target.get_id(),
+                self.config.draft,
+                self.config.gamma,
+            ),
+        }
     }
     fn get_kind(&self) -> ModelKind {
-        ModelKind::Speculative {
-            target: Box::new(self.target.get_kind()),
-            draft: Box::new(self.draft.get_kind()),
+        match &self.draft {
+            Some(draft) => ModelKind::Speculative {
+                target: Box::new(self.target.get_kind()),
+                draft: Box::new(draft.get_kind()),
+            },
+            None => ModelKind::NGramSpeculative {
+                target: Box::new(self.target.get_kind()),
+            },
         }
     }
 }
@@ -162,19 +219,55 @@ impl Loader for SpeculativeLoader {
 /// - Else (q_i(x) > p_i(x)) accept that token with prob p_i(x)/q_i(x)
 ///     - If rejected, sample token from from p'_i(x) = norm(max(0, p(x) − q(x))) and do not take any more'
 ///
+/// With the [`SpeculativeDraft::NGram`] draft there is no draft model: the proposals are looked up
+/// in the sequence itself (prompt lookup decoding) and the target keeps the longest prefix which
+/// matches its own samples, plus the first token it samples after that prefix.
+///
+/// A step verifies the draft tokens of every sequence in the batch in one forward pass of the
+/// target, each sequence at its own offset. The KV cache positions of rejected tokens are then
+/// dropped: the normal KV cache is narrowed, and with PagedAttention the slots reserved for
+/// them are given back to the block engine.
 pub struct SpeculativePipeline {
     target: Arc<tokio::sync::Mutex<dyn Pipeline>>,
-    draft: Arc<tokio::sync::Mutex<dyn Pipeline>>,
+    drafter: Drafter,
     gamma: usize,
     metadata: Arc<GeneralMetadata>,
     category: ModelCategory,
+    /// The cache of the target, for the engine's queries about the cache kind.
+    cache: EitherCache,
+}
+
+enum Drafter {
+    Model(Arc<tokio::sync::Mutex<dyn Pipeline>>),
+    NGram { min_ngram: usize, max_ngram: usize },
+}
+
+/// The draft tokens of one step for each sequence, as produced by the [`Drafter`].
+enum Proposals {
+    Model(Vec<Vec<SpeculativeSample>>),
+    NGram(Vec<Vec<u32>>),
+}
+
+/// Where a speculative pipeline gets its draft tokens from.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, strum::Display)]
+pub enum SpeculativeDraft {
+    /// Run a separate draft model γ times.
+    #[default]
+    #[strum(to_string = "model")]
+    Model,
+    /// Prompt lookup: find the most recent earlier occurrence of the last n tokens of the
+    /// sequence, trying n from `max_ngram` down to `min_ngram`, and propose the (up to γ) tokens
+    /// which followed it.
+    #[strum(to_string = "n-gram ({min_ngram}..={max_ngram})")]
+    NGram { min_ngram: usize, max_ngram: usize },
 }
 
 #[derive(Copy, Clone)]
 /// Metadata for a speculative pipeline
 pub struct SpeculativeConfig {
-    /// γ completions to run of the draft model
+    /// γ completions to run of the draft model, or the maximum number of n-gram proposals
     pub gamma: usize,
+    pub draft: SpeculativeDraft,
 }
 
 impl SpeculativePipeline {
@@ -183,6 +276,11 @@ impl SpeculativePipeline {
         draft: Arc<tokio::sync::Mutex<dyn Pipeline>>,
         config: SpeculativeConfig,
     ) -> Result<Self> {
+        if config.draft != SpeculativeDraft::Model {
+            candle_core::bail!(
+                "`SpeculativePipeline::new` requires `SpeculativeDraft::Model`, use `SpeculativePipeline::new_ngram` for n-gram drafts."
+            );
+        }
         if get_mut_arcmutex!(target)
             .tokenizer()
             .as_ref()
@@ -216,17 +314,209 @@ impl SpeculativePipeline {
         {
             candle_core::bail!("Target and draft models' input processors do not match. This is required for speculative decoding.");
         }
-        let metadata = get_mut_arcmutex!(target).get_metadata().clone();
+        let target_cache_config = get_mut_arcmutex!(target)
+            .get_metadata()
+            .cache_config
+            .clone();
+        let draft_cache_config = get_mut_arcmutex!(draft).get_metadata().cache_config.clone();
+        let cache_config = match (target_cache_config, draft_cache_config) {
+            (None, None) => None,
+            (Some(target), Some(draft)) if target.block_size == draft.block_size => {
+                // Both KV caches are addressed through the same block tables.
+                Some(CacheConfig {
+                    num_gpu_blocks: target.num_gpu_blocks.min(draft.num_gpu_blocks),
+                    num_cpu_blocks: target.num_cpu_blocks.min(draft.num_cpu_blocks),
+                    ..target
+                })
+            }
+            _ => candle_core::bail!("Target and draft models must either both use PagedAttention with the same block size, or neither. This is required for speculative decoding."),
+        };
+        let kind = ModelKind::Speculative {
+            target: Box::new(get_mut_arcmutex!(target).get_metadata().kind.clone()),
+            draft: Box::new(get_mut_arcmutex!(draft).get_metadata().kind.clone()),
+        };
+        let metadata = speculative_metadata(
+            &get_mut_arcmutex!(target).get_metadata(),
+            kind,
+            cache_config,
+        );
         let category = get_mut_arcmutex!(target).category();
+        let cache = get_mut_arcmutex!(target).cache().clone();
         // TODO: some checks or relaxation here?
         Ok(Self {
             target,
-            draft,
+            drafter: Drafter::Model(draft),
             gamma: config.gamma,
             metadata,
             category,
+            cache,
         })
     }
+
+    /// Create a speculative pipeline which drafts from n-gram matches in the sequence itself
+    /// instead of running a draft model.
+    pub fn new_ngram(
+        target: Arc<tokio::sync::Mutex<dyn Pipeline>>,
+        config: SpeculativeConfig,
+    ) -> Result<Self> {
+        let SpeculativeDraft::NGram {
+            min_ngram,
+            max_ngram,
+        } = config.draft
+        else {
+            candle_core::bail!(
+                "`SpeculativePipeline::new_ngram` requires `SpeculativeDraft::NGram`."
+            );
+        };
+        if min_ngram == 0 || min_ngram > max_ngram {
+            candle_core::bail!(
+                "N-gram speculative decoding requires `1 <= min_ngram <= max_ngram`, got {min_ngram}..={max_ngram}."
+            );
+        }
+        if config.gamma == 0 {
+            candle_core::bail!("N-gram speculative decoding requires `gamma >= 1`.");
+        }
+        let kind = ModelKind::NGramSpeculative {
+            target: Box::new(get_mut_arcmutex!(target).get_metadata().kind.clone()),
+        };
+        let target_metadata = get_mut_arcmutex!(target).get_metadata();
+        let metadata =
+            speculative_metadata(&target_metadata, kind, target_metadata.cache_config.clone());
+        let category = get_mut_arcmutex!(target).category();
+        let cache = get_mut_arcmutex!(target).cache().clone();
+        Ok(Self {
+            target,
+            drafter: Drafter::NGram {
+                min_ngram,
+                max_ngram,
+            },
+            gamma: config.gamma,
+            metadata,
+            category,
+            cache,
+        })
+    }
+}
+
+/// The target's metadata, reporting the speculative `kind` so the engine can tell it apart, and
+/// the PagedAttention `cache_config` which the KV caches of both models can hold.
+fn speculative_metadata(
+    target: &GeneralMetadata,
+    kind: ModelKind,
+    cache_config: Option<CacheConfig>,
+) -> Arc<GeneralMetadata> {
+    Arc::new(GeneralMetadata {
+        max_seq_len: target.max_seq_len,
+        llg_factory: target.llg_factory.clone(),
+        no_kv_cache: target.no_kv_cache,
+        no_prefix_cache: target.no_prefix_cache,
+        num_hidden_layers: target.num_hidden_layers,
+        eos_tok: target.eos_tok.clone(),
+        kind,
+        is_xlora: target.is_xlora,
+        activation_dtype: target.activation_dtype,
+        sliding_window: target.sliding_window,
+        cache_config,
+        cache_engine: target.cache_engine.clone(),
+        model_metadata: target.model_metadata.clone(),
+        modalities: target.modalities.clone(),
+    })
+}
+
+/// Prompt lookup: find the most recent earlier occurrence of the last n tokens of `toks`, trying
+/// n from `max_ngram` down to `min_ngram`, and return up to `max_toks` of the tokens which followed it.
+pub(crate) fn ngram_proposals(
+    toks: &[u32],
+    min_ngram: usize,
+    max_ngram: usize,
+    max_toks: usize,
+) -> Vec<u32> {
+    for n in (min_ngram..=max_ngram).rev() {
+        if toks.len() <= n {
+            continue;
+        }
+        let suffix = &toks[toks.len() - n..];
+        if let Some(start) = (0..toks.len() - n)
+            .rev()
+            .find(|&start| &toks[start..start + n] == suffix)
+        {
+            let from = start + n;
+            return toks[from..(from + max_toks).min(toks.len())].to_vec();
+        }
+    }
+    Vec::new()
+}
+
+/// Drop the last `n` positions of a pipeline's KV cache after its proposals were rejected.
+fn rollback_cache(pipeline: &dyn Pipeline, n: usize) -> Result<()> {
+    if n == 0 {
+        return Ok(());
+    }
+    match pipeline.cache() {
+        EitherCache::Full(full) => {
+            for (k, v) in full.lock().iter_mut().flatten() {
+                *k = k.i((.., .., ..k.dims()[2] - n, ..))?;
+                *v = v.i((.., .., ..v.dims()[2] - n, ..))?;
+            }
+        }
+        EitherCache::Normal(normal) => {
+            for cache in &mut *normal.lock().unwrap().0 {
+                cache
+                    .set_len(cache.current_seq_len() - n)
+                    .map_err(|_| candle_core::Error::msg("KV cache set_len failed."))?;
+            }
+        }
+        EitherCache::Hybrid(_) => {
+            unreachable!("Speculative decoding is not supported with hybrid caches")
+        }
+    }
+    if pipeline.get_metadata().is_xlora {
+        match pipeline.cache() {
+            EitherCache::Full(full) => {
+                for (k, v) in full.xlora_lock().iter_mut().flatten() {
+                    *k = k.i((.., .., ..k.dims()[2] - n, ..))?;
+                    *v = v.i((.., .., ..v.dims()[2] - n, ..))?;
+                }
+            }
+            EitherCache::Normal(_) | EitherCache::Hybrid(_) => {
+                unreachable!()
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Drop the last `n` positions of the KV cache of one sequence, as cloned out of a batch.
+fn rollback_seq_cache(cache: &mut [Option<KvCache>], n: usize) -> Result<()> {
+    for cache in cache.iter_mut().flatten() {
+        cache
+            .set_len(cache.current_seq_len() - n)
+            .map_err(|_| candle_core::Error::msg("KV cache set_len failed."))?;
+    }
+    Ok(())
+}
+
+/// Whether the block engine can hand out the blocks for `n_toks` more tokens of each of `n_seqs`
+/// sequences, including a copy of the last block of each.
+fn can_reserve_slots(metadata: &PagedAttentionMeta, n_seqs: usize, n_toks: usize) -> bool {
+    get_mut_arcmutex!(metadata.block_engine).num_available_blocks()
+        >= n_seqs * (n_toks.div_ceil(metadata.block_size) + 1)
+}
+
+impl SpeculativePipeline {
+    /// Reserve the PagedAttention KV cache slot of the last token of `seq`, like the scheduler
+    /// does for the next token of a completion.
+    fn reserve_token_slot(&self, seq: &Sequence, metadata: &PagedAttentionMeta) -> Result<()> {
+        let copy = get_mut_arcmutex!(metadata.block_engine).append_token_slot_to_seq(seq);
+        if let Some((src, dst)) = copy {
+            self.execute_scheduler_ops(
+                &HashMap::new(),
+                &HashMap::new(),
+                &HashMap::from([(src, vec![dst])]),
+            )?;
+        }
+        Ok(())
+    }
 }
 
 impl PreProcessingMixin for SpeculativePipeline {
@@ -241,17 +531,24 @@ impl PreProcessingMixin for SpeculativePipeline {
 impl IsqPipelineMixin for SpeculativePipeline {
     fn re_isq_model(&mut self, dtype: IsqType) -> anyhow::Result<()> {
         get_mut_arcmutex!(self.target).re_isq_model(dtype)?;
-        get_mut_arcmutex!(self.draft).re_isq_model(dtype)
+        match &self.drafter {
+            Drafter::Model(draft) => get_mut_arcmutex!(draft).re_isq_model(dtype),
+            Drafter::NGram { .. } => Ok(()),
+        }
     }
 }
 
 impl CacheManagerMixin for SpeculativePipeline {
     fn clone_in_cache(&self, seqs: &mut [&mut Sequence]) {
-        NormalCacheManager.clone_in_cache(&*get_mut_arcmutex!(self.draft), seqs, true);
+        if let Drafter::Model(draft) = &self.drafter {
+            NormalCacheManager.clone_in_cache(&*get_mut_arcmutex!(draft), seqs, true);
+        }
         NormalCacheManager.clone_in_cache(&*get_mut_arcmutex!(self.target), seqs, false);
     }
     fn clone_out_cache(&self, seqs: &mut [&mut Sequence]) {
-        NormalCacheManager.clone_out_cache(&*get_mut_arcmutex!(self.draft), seqs, true);
+        if let Drafter::Model(draft) = &self.drafter {
+            NormalCacheManager.clone_out_cache(&*get_mut_arcmutex!(draft), seqs, true);
+        }
         NormalCacheManager.clone_out_cache(&*get_mut_arcmutex!(self.target), seqs, false);
     }
     fn set_none_cache(
@@ -261,12 +558,14 @@ impl CacheManagerMixin for SpeculativePipeline {
         modify_draft_cache: bool,
         load_preallocated_cache: bool,
     ) {
-        NormalCacheManager.set_none_cache(
-            &*get_mut_arcmutex!(self.draft),
-            seqs,
-            modify_draft_cache,
-            load_preallocated_cache,
-        );
+        if let Drafter::Model(draft) = &self.drafter {
+            NormalCacheManager.set_none_cache(
+                &*get_mut_arcmutex!(draft),
+                seqs,
+                modify_draft_cache,
+                load_preallocated_cache,
+            );
+        }
         NormalCacheManager.set_none_cache(
             &*get_mut_arcmutex!(self.target),
             seqs,
@@ -278,7 +577,7 @@ impl CacheManagerMixin for SpeculativePipeline {
         }
     }
     fn cache(&self) -> &EitherCache {
-        unreachable!()
+        &self.cache
     }
     fn do_preallocated_cache(&self) -> bool {
         // KV cache size is not the same (necessarily)
@@ -294,16 +593,29 @@ impl MetadataMixin for SpeculativePipeline {
         get_mut_arcmutex!(self.target).tokenizer()
     }
     fn name(&self) -> String {
+        let draft = match &self.drafter {
+            Drafter::Model(draft) => get_mut_arcmutex!(draft).name(),
+            Drafter::NGram {
+                min_ngram,
+                max_ngram,
+            } => SpeculativeDraft::NGram {
+                min_ngram: *min_ngram,
+                max_ngram: *max_ngram,
+            }
+            .to_string(),
+        };
         format!(
             "Speculative: tgt = `{}`, draft = `{}`, gamma = `{}`",
             get_mut_arcmutex!(self.target).name(),
-            get_mut_arcmutex!(self.draft).name(),
+            draft,
             self.gamma,
         )
     }
     fn reset_non_granular_state(&self) {
         get_mut_arcmutex!(self.target).reset_non_granular_state();
-        get_mut_arcmutex!(self.draft).reset_non_granular_state();
+        if let Drafter::Model(draft) = &self.drafter {
+            get_mut_arcmutex!(draft).reset_non_granular_state();
+        }
     }
     fn get_metadata(&self) -> Arc<GeneralMetadata> {
         self.metadata.clone()
@@ -322,6 +634,29 @@ impl Pipeline for SpeculativePipeline {
     ) -> Result<ForwardInputsResult> {
         unreachable!()
     }
+    /// Swap and copy PagedAttention blocks in the KV caches of both the target and the draft model.
+    fn execute_scheduler_ops(
+        &self,
+        blocks_to_swap_in: &HashMap<usize, usize>,
+        blocks_to_swap_out: &HashMap<usize, usize>,
+        blocks_to_copy: &HashMap<usize, Vec<usize>>,
+    ) -> Result<()> {
+        get_mut_arcmutex!(self.target)
+            .get_metadata()
+            .cache_engine
+            .as_ref()
+            .expect("PagedAttention must have cache engines.")
+            .execute_scheduler_ops(blocks_to_swap_in, blocks_to_swap_out, blocks_to_copy)?;
+        if let Drafter::Model(draft) = &self.drafter {
+            get_mut_arcmutex!(draft)
+                .get_metadata()
+                .cache_engine
+                .as_ref()
+                .expect("PagedAttention must have cache engines.")
+                .execute_scheduler_ops(blocks_to_swap_in, blocks_to_swap_out, blocks_to_copy)?;
+        }
+        Ok(())
+    }
     async fn sample_causal_gen(
         &self,
         _seqs: &mut [&mut Sequence],
@@ -342,7 +677,7 @@ impl Pipeline for SpeculativePipeline {
         rng: Arc<Mutex<Isaac64Rng>>,
         backend_metadata: CacheBackendMetadata,
     ) -> Result<Duration> {
-        match backend_metadata {
+        let (paged_attn_meta, post_op) = match backend_metadata {
             CacheBackendMetadata::DefaultInstructions { pre_op, post_op } => {
                 match pre_op {
                     CacheInstruction::In => self.clone_in_cache(input_seqs),
@@ -358,25 +693,64 @@ impl Pipeline for SpeculativePipeline {
                     ),
                     _ => unreachable!("Unreachable PRE cache op."),
                 }
+                (None, Some(post_op))
+            }
+            CacheBackendMetadata::PagedAttention {
+                metadata,
+                blocks_to_swap_in,
+                blocks_to_swap_out,
+                blocks_to_copy,
+            } => {
+                self.execute_scheduler_ops(
+                    &blocks_to_swap_in,
+                    &blocks_to_swap_out,
+                    &blocks_to_copy,
+                )?;
+                (Some(metadata), None)
+            }
+        };
 
-                let start = Instant::now();
-                assert_eq!(input_seqs.len(), 1);
+        let start = Instant::now();
 
-                let seq = &mut input_seqs[0];
+        // Each sequence continues its KV cache from here: after the cached prompt prefix, or at
+        // the last token, which is the only one without a KV cache entry.
+        let bases = input_seqs
+            .iter()
+            .map(|seq| {
+                if is_prompt {
+                    seq.token_offset()
+                } else {
+                    seq.get_toks().len() - 1
+                }
+            })
+            .collect::<Vec<_>>();
+        // With PagedAttention, every draft token needs a KV cache slot. If there are not enough
+        // free blocks for them, only the next token is verified.
+        let look_ahead = paged_attn_meta
+            .as_ref()
+            .is_none_or(|metadata| can_reserve_slots(metadata, input_seqs.len(), self.gamma));
 
+        // ======================= Propose draft tokens ============================
+        // `n_logits` is the number of target positions which are sampled below, for every sequence.
+        let (prefill_tokens, proposals, n_logits) = match &self.drafter {
+            Drafter::Model(draft) => {
+                let gamma = if look_ahead { self.gamma } else { 1 };
                 // ======================= Run draft model gamma times producing tokens ============================
                 // ======================= Sample the `gamma` logits. ============================
-                let mut draft_samples = Vec::new();
-                for i in 0..self.gamma {
-                    let is_xlora = get_mut_arcmutex!(self.draft).get_metadata().is_xlora;
-                    let device = get_mut_arcmutex!(self.draft).device();
-                    let no_kv_cache = get_mut_arcmutex!(self.draft).get_metadata().no_kv_cache;
+                let mut draft_samples = input_seqs
+                    .iter()
+                    .map(|_| Vec::with_capacity(gamma))
+                    .collect::<Vec<_>>();
+                for i in 0..gamma {
+                    let is_xlora = get_mut_arcmutex!(draft).get_metadata().is_xlora;
+                    let device = get_mut_arcmutex!(draft).device();
+                    let no_kv_cache = get_mut_arcmutex!(draft).get_metadata().no_kv_cache;
                     let inputs = self
                         .get_processor()
                         .inputs_processor()
                         .process_inputs(
                             self.tokenizer(),
-                            &mut [seq],
+                            input_seqs,
                             is_prompt && i == 0, // Only prompt (no kv cache) if first
                             is_xlora,
                             &device,
@@ -384,12 +758,12 @@ impl Pipeline for SpeculativePipeline {
                             None,
                             false,
                             None,
-                            None, // TODO: get block tables/handle it
-                            get_mut_arcmutex!(self.draft).device_mapper(),
+                            paged_attn_meta.clone(),
+                            get_mut_arcmutex!(draft).device_mapper(),
                         )
                         .unwrap()
                         .inputs;
-                    let logits = get_mut_arcmutex!(self.draft).forward_inputs(inputs, false)?;
+                    let logits = get_mut_arcmutex!(draft).forward_inputs(inputs, false)?;
                     #[allow(irrefutable_let_patterns)]
                     let ForwardInputsResult::CausalGeneration { logits } = logits
                     else {
@@ -398,236 +772,246 @@ impl Pipeline for SpeculativePipeline {
                         );
                     };
 
-                    let sample = sample_sequence(
-                        logits.clone(),
-                        seq,
-                        seq.return_logprobs(),
-                        rng.clone(),
-                        false, // todo tune
-                        true,
-                        false,
-                    )
-                    .await?;
-                    seq.add_tmp_tok(sample.token);
-                    draft_samples.push(SpeculativeSample { sample });
+                    for (seq_i, seq) in input_seqs.iter_mut().enumerate() {
+                        let sample = sample_sequence(
+                            logits.i(seq_i..seq_i + 1)?,
+                            seq,
+                            seq.return_logprobs(),
+                            rng.clone(),
+                            false, // todo tune
+                            true,
+                            false,
+                        )
+                        .await?;
+                        seq.add_tmp_tok(sample.token);
+                        // The next draft run and the target write this token's KV cache, but not
+                        // that of the last one.
+                        if let Some(metadata) = paged_attn_meta.as_ref().filter(|_| i + 1 < gamma) {
+                            self.reserve_token_slot(seq, metadata)?;
+                        }
+                        draft_samples[seq_i].push(SpeculativeSample { sample });
+                    }
                 }
-                seq.remove_tmp_tok(self.gamma);
 
                 // ======================= Add all draft tokens but the last one. Add the last from the seq. ============================
-                let mut draft_prefill_tokens = if is_prompt {
-                    seq.get_toks().to_vec()
-                } else {
-                    vec![*seq.get_toks().last().unwrap()]
-                };
-                for (i, sample) in draft_samples.iter().enumerate() {
-                    if i == draft_samples.len() - 1 {
-                        continue;
-                    }
-                    draft_prefill_tokens.push(sample.sample.token);
+                let mut prefill_tokens = Vec::with_capacity(input_seqs.len());
+                for ((seq, samples), base) in input_seqs.iter_mut().zip(&draft_samples).zip(&bases)
+                {
+                    seq.remove_tmp_tok(gamma);
+                    let mut toks = seq.get_toks()[*base..].to_vec();
+                    toks.extend(samples[..gamma - 1].iter().map(|s| s.sample.token));
+                    prefill_tokens.push(toks);
                 }
-                seq.set_prefill_toks(draft_prefill_tokens);
-
-                // ======================= Run the model with all draft tokens. ============================
-
-                let initial_cache_len = match get_mut_arcmutex!(self.target).cache() {
-                    EitherCache::Full(full) => full.lock()[0]
-                        .as_ref()
-                        .map(|(k, _)| k.dims()[2])
-                        .unwrap_or(0),
-                    EitherCache::Normal(normal) => normal.lock().unwrap().0[0].current_seq_len(),
-                    EitherCache::Hybrid(_) => {
-                        unreachable!("Speculative decoding is not supported with hybrid caches")
+                (prefill_tokens, Proposals::Model(draft_samples), gamma)
+            }
+            Drafter::NGram {
+                min_ngram,
+                max_ngram,
+            } => {
+                // ======================= Look up the proposals in the sequence itself. ============================
+                // All proposals are added: the target also samples the token after the last one.
+                let proposals = input_seqs
+                    .iter()
+                    .map(|seq| {
+                        let max_toks = if look_ahead { self.gamma } else { 0 };
+                        ngram_proposals(seq.get_toks(), *min_ngram, *max_ngram, max_toks)
+                    })
+                    .collect::<Vec<_>>();
+                // Every sequence feeds the same number of tokens: shorter proposals are padded like
+                // `make_prompt_chunk` pads, and the padding is rolled back as rejected.
+                let n_proposals = proposals.iter().map(Vec::len).max().unwrap_or(0);
+                let mut prefill_tokens = Vec::with_capacity(input_seqs.len());
+                for ((seq, proposals), base) in input_seqs.iter_mut().zip(&proposals).zip(&bases) {
+                    let mut toks = seq.get_toks()[*base..].to_vec();
+                    toks.extend_from_slice(proposals);
+                    toks.resize(toks.len() + n_proposals - proposals.len(), 0);
+                    if let Some(metadata) = &paged_attn_meta {
+                        for tok in &toks[toks.len() - n_proposals..] {
+                            seq.add_tmp_tok(*tok);
+                            self.reserve_token_slot(seq, metadata)?;
+                        }
+                        seq.remove_tmp_tok(n_proposals);
                     }
-                };
-
-                // ========= Run the model ============
-                let is_xlora = get_mut_arcmutex!(self.target).get_metadata().is_xlora;
-                let device = get_mut_arcmutex!(self.target).device();
-                let no_kv_cache = get_mut_arcmutex!(self.target).get_metadata().no_kv_cache;
-                let inputs = self
-                    .get_processor()
-                    .inputs_processor()
-                    .process_inputs(
-                        self.tokenizer(),
-                        &mut [seq],
-                        true, // use the "prefill" tokens
-                        is_xlora,
-                        &device,
-                        no_kv_cache,
-                        Some((self.gamma, initial_cache_len)), // Get the last gamma, see above
-                        false,
-                        None,
-                        None, // TODO: get block tables/handle it
-                        get_mut_arcmutex!(self.target).device_mapper(),
-                    )
-                    .unwrap()
-                    .inputs;
-
-                let logits = get_mut_arcmutex!(self.target).forward_inputs(inputs, false)?;
-                #[allow(irrefutable_let_patterns)]
-                let ForwardInputsResult::CausalGeneration { logits } = logits
-                else {
-                    candle_core::bail!(
-                        "Speculative decoding requires `CausalGeneration` forward results"
-                    );
-                };
-
-                // Reset the prefill tokens
-                seq.reset_prefill_toks();
-
-                // ======================= Rejection sampling. ============================
-                // Map from each target sample to corresponding in draft sample
-                // this will first rollback LLG state if any, and then advance for the accepted tokens only
-                let samples = sample_target_sequence_speculative(
-                    logits.clone(),
-                    seq,
-                    seq.return_logprobs(),
-                    rng.clone(),
-                    &draft_samples,
-                )
-                .await?;
+                    prefill_tokens.push(toks);
+                }
+                (prefill_tokens, Proposals::NGram(proposals), n_proposals + 1)
+            }
+        };
+        for ((seq, toks), base) in input_seqs.iter_mut().zip(prefill_tokens).zip(&bases) {
+            seq.set_prefill_toks_at(toks, *base);
+        }
 
-                let accepted_tokens = samples.into_iter().map(|s| s.sample).collect::<Vec<_>>();
+        // ======================= Run the model with all draft tokens. ============================
+        let is_xlora = get_mut_arcmutex!(self.target).get_metadata().is_xlora;
+        let device = get_mut_arcmutex!(self.target).device();
+        let no_kv_cache = get_mut_arcmutex!(self.target).get_metadata().no_kv_cache;
+        let inputs = self
+            .get_processor()
+            .inputs_processor()
+            .process_inputs(
+                self.tokenizer(),
+                input_seqs,
+                true, // use the "prefill" tokens
+                is_xlora,
+                &device,
+                no_kv_cache,
+                Some((n_logits, 0)), // Get the last `n_logits`, see above
+                false,
+                None,
+                paged_attn_meta.clone(),
+                get_mut_arcmutex!(self.target).device_mapper(),
+            )
+            .unwrap()
+            .inputs;
 
-                // ======================= Narrow caches to account for rejections ============================
-                let n_not_accepted = self.gamma - accepted_tokens.len();
+        let logits = get_mut_arcmutex!(self.target).forward_inputs(inputs, false)?;
+        #[allow(irrefutable_let_patterns)]
+        let ForwardInputsResult::CausalGeneration { logits } = logits
+        else {
+            candle_core::bail!("Speculative decoding requires `CausalGeneration` forward results");
+        };
 
-                match get_mut_arcmutex!(self.draft).cache() {
-                    EitherCache::Full(full) => {
-                        for (k, v) in full.lock().iter_mut().flatten() {
-                            *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-                            *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
-                        }
-                    }
-                    EitherCache::Normal(normal) => {
-                        for cache in &mut *normal.lock().unwrap().0 {
-                            cache
-                                .set_len(cache.current_seq_len() - n_not_accepted)
-                                .map_err(|_| candle_core::Error::msg("KV cache set_len failed."))?;
-                        }
-                    }
-                    EitherCache::Hybrid(_) => {
-                        unreachable!("Speculative decoding is not supported with hybrid caches")
-                    }
-                }
-                if get_mut_arcmutex!(self.draft).get_metadata().is_xlora {
-                    match get_mut_arcmutex!(self.draft).cache() {
-                        EitherCache::Full(full) => {
-                            for (k, v) in full.xlora_lock().iter_mut().flatten() {
-                                *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-                                *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
-                            }
-                        }
-                        EitherCache::Normal(_) | EitherCache::Hybrid(_) => {
-                            unreachable!()
-                        }
-                    }
-                }
-                match get_mut_arcmutex!(self.target).cache() {
-                    EitherCache::Full(full) => {
-                        for (k, v) in full.lock().iter_mut().flatten() {
-                            *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-                            *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
-                        }
-                    }
-                    EitherCache::Normal(normal) => {
-                        for cache in &mut *normal.lock().unwrap().0 {
-                            cache
-                                .set_len(cache.current_seq_len() - n_not_accepted)
-                                .map_err(|_| candle_core::Error::msg("KV cache set_len failed."))?;
-                        }
-                    }
-                    EitherCache::Hybrid(_) => {
-                        unreachable!("Speculative decoding is not supported with hybrid caches")
-                    }
-                }
-                if get_mut_arcmutex!(self.draft).get_metadata().is_xlora {
-                    match get_mut_arcmutex!(self.target).cache() {
-                        EitherCache::Full(full) => {
-                            for (k, v) in full.xlora_lock().iter_mut().flatten() {
-                                *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-                                *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
-                            }
-                        }
-                        EitherCache::Normal(_) | EitherCache::Hybrid(_) => {
-                            unreachable!()
-                        }
-                    }
-                }
+        // Reset the prefill tokens
+        for seq in input_seqs.iter_mut() {
+            seq.reset_prefill_toks();
+        }
 
-                let eos_owned = get_mut_arcmutex!(self.target)
-                    .get_metadata()
-                    .eos_tok
-                    .clone();
-                let eos_tok = if disable_eos_stop {
-                    None
-                } else {
-                    Some(&eos_owned[..])
-                };
-                // Add the tokens to the seq and the trie
-                for accepted in accepted_tokens {
-                    // Do not use the prefix cacher
-                    finish_or_add_toks_to_seq(
-                        self,
-                        prefix_cacher,
+        let mut accepted_tokens = Vec::with_capacity(input_seqs.len());
+        for (seq_i, seq) in input_seqs.iter_mut().enumerate() {
+            let logits = logits.i(seq_i..seq_i + 1)?;
+            let accepted = match &proposals {
+                Proposals::Model(draft_samples) => {
+                    // ======================= Rejection sampling. ============================
+                    // Map from each target sample to corresponding in draft sample
+                    // this will first rollback LLG state if any, and then advance for the accepted tokens only
+                    let samples = sample_target_sequence_speculative(
+                        logits,
                         seq,
-                        accepted.clone(),
-                        eos_tok,
-                        false,
+                        seq.return_logprobs(),
+                        rng.clone(),
+                        &draft_samples[seq_i],
                     )
                     .await?;
+                    samples.into_iter().map(|s| s.sample).collect::<Vec<_>>()
                 }
+                Proposals::NGram(proposals) => {
+                    // ======================= Keep the proposals the target agrees with. ============================
+                    let proposals = &proposals[seq_i];
+                    sample_target_sequence_ngram(
+                        logits.i((.., ..proposals.len() + 1))?,
+                        seq,
+                        seq.return_logprobs(),
+                        rng.clone(),
+                        proposals,
+                    )
+                    .await?
+                }
+            };
+            accepted_tokens.push(accepted);
+        }
 
-                // Trick to improve lower bounds. Sample last token in multinomial
-                /*
-                let sample = sample_sequence(
-                    logits.clone(),
-                    seq,
-                    seq.return_logprobs(),
-                    rng.clone(),
-                    false, // todo tune
-                    true, // do not add to tok trie yet
-                    true,
-                )
-                .await?;
-                finish_or_add_toks_to_seq(self, prefix_cacher, seq, sample, eos_tok, false);
-                */
-                let end = Instant::now();
-                let exec_duration = end.duration_since(start);
-
-                match post_op {
-                    CacheInstruction::Out => {
-                        self.clone_out_cache(input_seqs);
+        // ======================= Narrow caches to account for rejections ============================
+        let n_not_accepted = accepted_tokens
+            .iter()
+            .map(|accepted| n_logits - accepted.len())
+            .collect::<Vec<_>>();
+        let post_op = match post_op {
+            Some(CacheInstruction::Out)
+                if n_not_accepted.iter().any(|&n| n != n_not_accepted[0]) =>
+            {
+                // The batched KV cache has a single length, so narrow the cache of each sequence.
+                // Their lengths now differ, so the scheduler batches them apart and the next step
+                // clones their caches in.
+                self.clone_out_cache(input_seqs);
+                for (seq, n) in input_seqs.iter_mut().zip(&n_not_accepted) {
+                    rollback_seq_cache(seq.normal_cache(), *n)?;
+                    if matches!(self.drafter, Drafter::Model(_)) {
+                        rollback_seq_cache(seq.normal_draft_cache(), *n)?;
                     }
-                    CacheInstruction::Nothing => (),
-                    CacheInstruction::Reset {
-                        reset_non_granular,
-                        load_preallocated_cache,
-                    } => self.set_none_cache(
-                        input_seqs,
-                        reset_non_granular,
-                        true,
-                        load_preallocated_cache,
-                    ),
-                    _ => unreachable!("Unreachable pre cache op."),
                 }
+                None
+            }
+            Some(post_op) => {
+                if let Drafter::Model(draft) = &self.drafter {
+                    rollback_cache(&*get_mut_arcmutex!(draft), n_not_accepted[0])?;
+                }
+                rollback_cache(&*get_mut_arcmutex!(self.target), n_not_accepted[0])?;
+                Some(post_op)
+            }
+            // The paged KV caches are rolled back once the accepted tokens are added, below.
+            None => None,
+        };
+
+        let eos_owned = get_mut_arcmutex!(self.target)
+            .get_metadata()
+            .eos_tok
+            .clone();
+        let eos_tok = if disable_eos_stop {
+            None
+        } else {
+            Some(&eos_owned[..])
+        };
+        // Add the tokens to the seq and the trie
+        for (seq, accepted_tokens) in input_seqs.iter_mut().zip(accepted_tokens) {
+            for accepted in accepted_tokens {
+                // Do not use the prefix cacher
+                finish_or_add_toks_to_seq(self, prefix_cacher, seq, accepted, eos_tok, false)
+                    .await?;
+                // Stop once a token finished the sequence, the rest were never generated.
+                if matches!(seq.getstate(), SequenceState::Done(_)) {
+                    break;
+                }
+            }
+            // Give back the slots which were reserved for the rejected tokens.
+            if let Some(metadata) = &paged_attn_meta {
+                get_mut_arcmutex!(metadata.block_engine).remove_token_slots_from_seq(&**seq);
+            }
+        }
 
-                // Done! We have:
-                // - Run the draft model gamma times
-                // - Reset draft model cache fully
-                // - Sampled draft model's distributions
-                // - Run target model
-                // - Execute speculative decoding algorithm on the resulting distributions
-                // - Added the accepted tokens to buffer and trie
-                // - Maybe fixed up cache of base model based on accepted tokens.
+        // Trick to improve lower bounds. Sample last token in multinomial
+        /*
+        let sample = sample_sequence(
+            logits.clone(),
+            seq,
+            seq.return_logprobs(),
+            rng.clone(),
+            false, // todo tune
+            true, // do not add to tok trie yet
+            true,
+        )
+        .await?;
+        finish_or_add_toks_to_seq(self, prefix_cacher, seq, sample, eos_tok, false);
+        */
+        let end = Instant::now();
+        let exec_duration = end.duration_since(start);
 
-                Ok(exec_duration)
+        match post_op {
+            Some(CacheInstruction::Out) => {
+                self.clone_out_cache(input_seqs);
             }
-            CacheBackendMetadata::PagedAttention {
-                metadata: _,
-                blocks_to_copy: _,
-            } => unreachable!(),
+            Some(CacheInstruction::Nothing) | None => (),
+            Some(CacheInstruction::Reset {
+                reset_non_granular,
+                load_preallocated_cache,
+            }) => self.set_none_cache(
+                input_seqs,
+                reset_non_granular,
+                true,
+                load_preallocated_cache,
+            ),
+            _ => unreachable!("Unreachable pre cache op."),
         }
+
+        // Done! We have:
+        // - Run the draft model gamma times
+        // - Reset draft model cache fully
+        // - Sampled draft model's distributions
+        // - Run target model
+        // - Execute speculative decoding algorithm on the resulting distributions
+        // - Added the accepted tokens to buffer and trie
+        // - Maybe fixed up cache of base model based on accepted tokens.
+
+        Ok(exec_duration)
     }
     fn category(&self) -> ModelCategory {
         self.category.clone()
@@ -635,3 +1019,28 @@ impl Pipeline for SpeculativePipeline {
 }
 
 impl AnyMoePipelineMixin for SpeculativePipeline {}
+
+#[cfg(test)]
+mod tests {
+    use super::ngram_proposals;
+
+    #[test]
+    fn ngram_proposals_prefer_longest_most_recent_match() {
+        // The last bigram `1 2` occurs at 0 and 4: the later one is followed by `5 6`.
+        let toks = [1, 2, 3, 4, 1, 2, 5, 6, 1, 2];
+        assert_eq!(ngram_proposals(&toks, 1, 2, 3), vec![5, 6, 1]);
+        // The proposals stop at the end of the sequence.
+        assert_eq!(ngram_proposals(&toks, 1, 2, 8), vec![5, 6, 1, 2]);
+
+        // No trigram `9 1 2` match, so it falls back to the bigram.
+        let toks = [7, 1, 2, 8, 9, 1, 2];
+        assert_eq!(ngram_proposals(&toks, 1, 3, 2), vec![8, 9]);
+    }
+
+    #[test]
+    fn ngram_proposals_without_match() {
+        assert!(ngram_proposals(&[1, 2, 3, 4], 1, 3, 4).is_empty());
+        assert!(ngram_proposals(&[1, 2, 3, 1, 2], 3, 3, 4).is_empty());
+        assert!(ngram_proposals(&[], 1, 3, 4).is_empty());
+    }
+}
@@ -0,0 +1,543 @@
+//! Optional on-disk tier for the prefix cache.
+//!
+//! Caches evicted from memory are written as safetensors files into a per-model directory, alongside a JSON index
+//! holding hashes of the token prefixes they cover. The index survives restarts, so a prefix computed before a
+//! redeploy can be loaded back instead of re-prefilled.
+
+use std::{collections::HashMap, fs, path::PathBuf};
+
+use candle_core::{Device, Tensor};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::kv_cache::{KvCache, KvCacheQuantization, SingleCache};
+
+const INDEX_FILE: &str = "index.json";
+/// Granularity of the prefix hashes kept in the index, so disk matches are rounded down to a multiple of it.
+const HASH_BLOCK: usize = 64;
+/// Number of index changes after which it is written out. The rest are written when the tier is dropped.
+const INDEX_FLUSH_INTERVAL: usize = 16;
+
+/// Configuration for the on-disk tier of the prefix cache.
+#[derive(Clone, Debug)]
+pub struct PrefixCacheDiskConfig {
+    /// Directory to store the serialized KV caches in. Each model gets its own subdirectory.
+    pub path: PathBuf,
+    /// Size budget for the model's subdirectory in MB. Least recently used caches are removed beyond it.
+    pub max_size_mb: usize,
+}
+
+impl PrefixCacheDiskConfig {
+    pub fn new(path: impl Into<PathBuf>, max_size_mb: usize) -> Self {
+        Self {
+            path: path.into(),
+            max_size_mb,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct DiskEntry {
+    /// Number of tokens the cache covers.
+    len: usize,
+    /// Hashes of the first `HASH_BLOCK`, `2 * HASH_BLOCK`, ... tokens, ending with the hash of all `len` tokens.
+    prefix_hashes: Vec<u64>,
+    image_hashes: Option<Vec<u64>>,
+    audio_hashes: Option<Vec<u64>>,
+    /// Whether each layer had a cache. Layers without one are not serialized.
+    layers: Vec<bool>,
+    dim: usize,
+    max_seq_len: usize,
+    /// Storage format of the serialized tensors, `None` if they are in the model dtype.
+    #[serde(default)]
+    quantization: Option<KvCacheQuantization>,
+    size_bytes: u64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct DiskIndex {
+    fingerprint: String,
+    /// File stem => entry, least recently used first.
+    entries: IndexMap<String, DiskEntry>,
+}
+
+/// A prefix match found in the disk tier.
+pub(super) struct DiskMatch {
+    key: String,
+    pub(super) match_len: usize,
+    pub(super) images_match_until: usize,
+    pub(super) audios_match_until: usize,
+}
+
+/// A cache loaded back from disk, truncated to the matched length.
+pub(super) struct LoadedCache {
+    pub(super) cache: Vec<Option<KvCache>>,
+    pub(super) image_hashes: Option<Vec<u64>>,
+    pub(super) audio_hashes: Option<Vec<u64>>,
+}
+
+pub(crate) struct DiskPrefixCache {
+    dir: PathBuf,
+    max_size_bytes: u64,
+    size_bytes: u64,
+    index: DiskIndex,
+    /// Index changes not written out yet.
+    n_unflushed: usize,
+    layer_devices: Vec<Device>,
+    device: Device,
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+fn fnv_update(mut hash: u64, word: u64) -> u64 {
+    for byte in word.to_le_bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// FNV-1a, which unlike `DefaultHasher` is stable across Rust versions and therefore restarts.
+fn stable_hash(words: impl IntoIterator<Item = u64>) -> u64 {
+    words.into_iter().fold(FNV_OFFSET, fnv_update)
+}
+
+/// The `i`th element is the hash of the first `i + 1` tokens.
+fn running_hashes(toks: &[u32]) -> Vec<u64> {
+    toks.iter()
+        .scan(FNV_OFFSET, |hash, tok| {
+            *hash = fnv_update(*hash, *tok as u64);
+            Some(*hash)
+        })
+        .collect()
+}
+
+fn prefix_hashes(toks: &[u32]) -> Vec<u64> {
+    let running = running_hashes(toks);
+    (HASH_BLOCK..toks.len())
+        .step_by(HASH_BLOCK)
+        .chain([toks.len()])
+        .map(|end| running[end - 1])
+        .collect()
+}
+
+fn shared_prefix_len<T: PartialEq>(a: &[T], b: &[T]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+impl DiskPrefixCache {
+    /// `fingerprint` identifies the model and KV cache dtype; caches written under another fingerprint are
+    /// never loaded. `layer_devices` gives the device each layer's cache is restored to.
+    pub(crate) fn new(
+        config: &PrefixCacheDiskConfig,
+        fingerprint: String,
+        layer_devices: Vec<Device>,
+        device: Device,
+    ) -> anyhow::Result<Self> {
+        let dir = config.path.join(format!(
+            "{:016x}",
+            stable_hash(fingerprint.bytes().map(u64::from))
+        ));
+        fs::create_dir_all(&dir)?;
+
+        let index = match fs::read(dir.join(INDEX_FILE)) {
+            Ok(raw) => match serde_json::from_slice::<DiskIndex>(&raw) {
+                Ok(index) if index.fingerprint == fingerprint => index,
+                Ok(_) => {
+                    warn!(
+                        "Prefix cache directory `{}` belongs to another model, ignoring its contents.",
+                        dir.display()
+                    );
+                    DiskIndex::default()
+                }
+                Err(e) => {
+                    warn!(
+                        "Could not parse prefix cache index in `{}`, ignoring its contents: {e}",
+                        dir.display()
+                    );
+                    DiskIndex::default()
+                }
+            },
+            Err(_) => DiskIndex::default(),
+        };
+
+        let mut this = Self {
+            dir,
+            max_size_bytes: config.max_size_mb as u64 * 1024 * 1024,
+            size_bytes: 0,
+            index: DiskIndex {
+                fingerprint,
+                entries: index.entries,
+            },
+            n_unflushed: 0,
+            layer_devices,
+            device,
+        };
+        // Drop entries whose files went missing, e.g. if the directory was partially cleaned up.
+        this.index
+            .entries
+            .retain(|key, _| this.dir.join(format!("{key}.safetensors")).exists());
+        // Files written after the last index flush are not referenced, remove them.
+        for entry in fs::read_dir(&this.dir)?.flatten() {
+            let path = entry.path();
+            let referenced = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .is_some_and(|stem| this.index.entries.contains_key(stem));
+            if path.extension().is_some_and(|ext| ext == "safetensors") && !referenced {
+                let _ = fs::remove_file(&path);
+            }
+        }
+        this.size_bytes = this.index.entries.values().map(|e| e.size_bytes).sum();
+        this.enforce_budget();
+        this.write_index();
+
+        info!(
+            "Prefix cache disk tier at `{}` holds {} caches ({} MB).",
+            this.dir.display(),
+            this.index.entries.len(),
+            this.size_bytes / (1024 * 1024)
+        );
+        Ok(this)
+    }
+
+    fn path_for(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.safetensors"))
+    }
+
+    fn key_for(toks: &[u32], image_hashes: Option<&[u64]>, audio_hashes: Option<&[u64]>) -> String {
+        let words = toks
+            .iter()
+            .map(|t| *t as u64)
+            .chain([u64::MAX])
+            .chain(image_hashes.unwrap_or_default().iter().copied())
+            .chain([u64::MAX])
+            .chain(audio_hashes.unwrap_or_default().iter().copied());
+        format!("{:016x}", stable_hash(words))
+    }
+
+    /// Write a cache evicted from memory to disk. Caches with sliding-window layers are not persisted.
+    pub(super) fn insert(
+        &mut self,
+        toks: &[u32],
+        cache: &[Option<KvCache>],
+        image_hashes: Option<&[u64]>,
+        audio_hashes: Option<&[u64]>,
+    ) {
+        if let Err(e) = self.try_insert(toks, cache, image_hashes, audio_hashes) {
+            warn!("Failed to write prefix cache to disk: {e}");
+        }
+    }
+
+    fn try_insert(
+        &mut self,
+        toks: &[u32],
+        cache: &[Option<KvCache>],
+        image_hashes: Option<&[u64]>,
+        audio_hashes: Option<&[u64]>,
+    ) -> anyhow::Result<()> {
+        if cache.iter().flatten().any(KvCache::is_rotating) {
+            return Ok(());
+        }
+        // The last sampled token of a sequence has no KV yet; only record the tokens the cache covers.
+        let cached_len = cache
+            .iter()
+            .flatten()
+            .map(KvCache::current_seq_len)
+            .min()
+            .unwrap_or(0);
+        let toks = &toks[..toks.len().min(cached_len)];
+        if toks.is_empty() {
+            return Ok(());
+        }
+
+        // Already covered by a persisted entry, e.g. because it was loaded from disk. Just mark it as recently used.
+        if let Some(m) = self.best_match(toks, image_hashes, audio_hashes) {
+            if m.match_len == toks.len()
+                && m.images_match_until == image_hashes.map_or(0, <[u64]>::len)
+                && m.audios_match_until == audio_hashes.map_or(0, <[u64]>::len)
+            {
+                self.touch(&m.key);
+                return Ok(());
+            }
+        }
+
+        let key = Self::key_for(toks, image_hashes, audio_hashes);
+
+        let mut tensors = HashMap::new();
+        let mut layers = Vec::with_capacity(cache.len());
+        let (mut dim, mut max_seq_len, mut quantization) = (2, toks.len(), None);
+        for (i, layer) in cache.iter().enumerate() {
+            let Some(KvCache::Normal { k, v }) = layer else {
+                layers.push(false);
+                continue;
+            };
+            let (Some(k_data), Some(v_data)) = (k.current_data()?, v.current_data()?) else {
+                layers.push(false);
+                continue;
+            };
+            let k_data = k_data.narrow(k.dim(), 0, toks.len())?;
+            let v_data = v_data.narrow(v.dim(), 0, toks.len())?;
+            dim = k.dim();
+            max_seq_len = k.max_seq_len();
+            quantization = k.quantization;
+            tensors.insert(format!("layer.{i}.k"), k_data.to_device(&Device::Cpu)?);
+            tensors.insert(format!("layer.{i}.v"), v_data.to_device(&Device::Cpu)?);
+            layers.push(true);
+        }
+        if tensors.is_empty() {
+            return Ok(());
+        }
+
+        let path = self.path_for(&key);
+        candle_core::safetensors::save(&tensors, &path)?;
+        let size_bytes = fs::metadata(&path)?.len();
+
+        self.size_bytes += size_bytes;
+        self.index.entries.insert(
+            key,
+            DiskEntry {
+                len: toks.len(),
+                prefix_hashes: prefix_hashes(toks),
+                image_hashes: image_hashes.map(|x| x.to_vec()),
+                audio_hashes: audio_hashes.map(|x| x.to_vec()),
+                layers,
+                dim,
+                max_seq_len,
+                quantization,
+                size_bytes,
+            },
+        );
+        self.enforce_budget();
+        self.mark_changed();
+        Ok(())
+    }
+
+    /// Find the entry sharing the longest prefix with `toks`, rounded down to a multiple of `HASH_BLOCK` unless the
+    /// whole entry matches.
+    pub(super) fn best_match(
+        &self,
+        toks: &[u32],
+        image_hashes: Option<&[u64]>,
+        audio_hashes: Option<&[u64]>,
+    ) -> Option<DiskMatch> {
+        let running = running_hashes(toks);
+        let mut best: Option<DiskMatch> = None;
+        for (key, entry) in &self.index.entries {
+            let mut match_len = 0;
+            for (i, hash) in entry.prefix_hashes.iter().enumerate() {
+                let end = ((i + 1) * HASH_BLOCK).min(entry.len);
+                if end > toks.len() || running[end - 1] != *hash {
+                    break;
+                }
+                match_len = end;
+            }
+            if match_len == 0 || best.as_ref().is_some_and(|b| b.match_len >= match_len) {
+                continue;
+            }
+            let images_match_until = match (image_hashes, &entry.image_hashes) {
+                (Some(input), Some(cached)) => shared_prefix_len(input, cached),
+                _ => 0,
+            };
+            let audios_match_until = match (audio_hashes, &entry.audio_hashes) {
+                (Some(input), Some(cached)) => shared_prefix_len(input, cached),
+                _ => 0,
+            };
+            best = Some(DiskMatch {
+                key: key.clone(),
+                match_len,
+                images_match_until,
+                audios_match_until,
+            });
+        }
+        best
+    }
+
+    /// Load a matched cache back onto the layer devices. Unreadable entries are dropped from the index.
+    pub(super) fn load(&mut self, m: &DiskMatch) -> Option<LoadedCache> {
+        match self.try_load(&m.key, m.match_len) {
+            Ok(loaded) => {
+                self.touch(&m.key);
+                Some(loaded)
+            }
+            Err(e) => {
+                warn!("Failed to load prefix cache `{}` from disk: {e}", m.key);
+                self.remove(&m.key);
+                self.mark_changed();
+                None
+            }
+        }
+    }
+
+    fn try_load(&self, key: &str, len: usize) -> anyhow::Result<LoadedCache> {
+        let Some(entry) = self.index.entries.get(key) else {
+            anyhow::bail!("no such entry");
+        };
+        let mut tensors = candle_core::safetensors::load(self.path_for(key), &Device::Cpu)?;
+        let mut take = |name: String, device: &Device| -> anyhow::Result<Tensor> {
+            let tensor = tensors
+                .remove(&name)
+                .ok_or_else(|| anyhow::anyhow!("missing tensor `{name}`"))?;
+            Ok(tensor.to_device(device)?)
+        };
+        let truncate = |data: Tensor| -> candle_core::Result<Tensor> {
+            data.narrow(entry.dim, 0, len)?.contiguous()
+        };
+
+        let mut cache = Vec::with_capacity(entry.layers.len());
+        for (i, present) in entry.layers.iter().enumerate() {
+            if !present {
+                cache.push(None);
+                continue;
+            }
+            let device = self.layer_devices.get(i).unwrap_or(&self.device);
+            let k = truncate(take(format!("layer.{i}.k"), device)?)?;
+            let v = truncate(take(format!("layer.{i}.v"), device)?)?;
+            let single = |data: Tensor| SingleCache {
+                all_data: Some(data),
+                dim: entry.dim,
+                current_seq_len: len,
+                capacity_seq_len: len,
+                max_seq_len: entry.max_seq_len,
+                quantization: entry.quantization,
+            };
+            cache.push(Some(KvCache::Normal {
+                k: single(k),
+                v: single(v),
+            }));
+        }
+
+        Ok(LoadedCache {
+            cache,
+            image_hashes: entry.image_hashes.clone(),
+            audio_hashes: entry.audio_hashes.clone(),
+        })
+    }
+
+    /// Mark an entry as recently used.
+    fn touch(&mut self, key: &str) {
+        if let Some(idx) = self.index.entries.get_index_of(key) {
+            let last = self.index.entries.len() - 1;
+            self.index.entries.move_index(idx, last);
+            self.mark_changed();
+        }
+    }
+
+    /// Record an index change, writing the index out once enough of them accumulated.
+    fn mark_changed(&mut self) {
+        self.n_unflushed += 1;
+        if self.n_unflushed >= INDEX_FLUSH_INTERVAL {
+            self.write_index();
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.index.entries.shift_remove(key) {
+            self.size_bytes = self.size_bytes.saturating_sub(entry.size_bytes);
+            let _ = fs::remove_file(self.path_for(key));
+        }
+    }
+
+    /// Remove the least recently used entries until the directory fits the budget.
+    fn enforce_budget(&mut self) {
+        while self.size_bytes > self.max_size_bytes {
+            let Some(key) = self.index.entries.keys().next().cloned() else {
+                break;
+            };
+            self.remove(&key);
+        }
+    }
+
+    /// Persist the index, writing to a temporary file first so a crash never leaves it truncated.
+    fn write_index(&mut self) {
+        self.n_unflushed = 0;
+        let res = serde_json::to_vec(&self.index)
+            .map_err(anyhow::Error::from)
+            .and_then(|raw| {
+                let tmp = self.dir.join(format!("{INDEX_FILE}.tmp"));
+                fs::write(&tmp, raw)?;
+                fs::rename(&tmp, self.dir.join(INDEX_FILE))?;
+                Ok(())
+            });
+        if let Err(e) = res {
+            warn!(
+                "Failed to write prefix cache index in `{}`: {e}",
+                self.dir.display()
+            );
+        }
+    }
+}
+
+impl Drop for DiskPrefixCache {
+    fn drop(&mut self) {
+        if self.n_unflushed > 0 {
+            self.write_index();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    use super::{DiskPrefixCache, PrefixCacheDiskConfig};
+    use crate::kv_cache::KvCache;
+
+    fn cache(len: usize) -> candle_core::Result<Vec<Option<KvCache>>> {
+        let mut layer = KvCache::new_normal(2, 8192, 16);
+        let k = Tensor::arange(0f32, (len * 4) as f32, &Device::Cpu)?.reshape((1, 1, len, 4))?;
+        layer.append(&k, &(&k * 2.)?)?;
+        Ok(vec![Some(layer), None])
+    }
+
+    #[test]
+    fn test_roundtrip_and_budget() -> anyhow::Result<()> {
+        let dir =
+            std::env::temp_dir().join(format!("mistralrs-prefix-cache-{}", std::process::id()));
+        let config = PrefixCacheDiskConfig::new(&dir, 1);
+        let new = || {
+            DiskPrefixCache::new(
+                &config,
+                "model/f32".to_string(),
+                vec![Device::Cpu; 2],
+                Device::Cpu,
+            )
+        };
+
+        let mut disk = new()?;
+        let toks = (0..100).collect::<Vec<u32>>();
+        disk.insert(&toks, &cache(100)?, None, None);
+        drop(disk);
+
+        // The index survives a restart.
+        let mut disk = new()?;
+        let m = disk
+            .best_match(&[&toks[..], &[7]].concat(), None, None)
+            .unwrap();
+        assert_eq!(m.match_len, 100);
+        // Partial matches are rounded down to the hashed blocks.
+        let m = disk
+            .best_match(&[&toks[..70], &[7]].concat(), None, None)
+            .unwrap();
+        assert_eq!(m.match_len, 64);
+        assert!(disk.best_match(&[1, 2, 3], None, None).is_none());
+        let loaded = disk.load(&m).unwrap();
+        assert!(loaded.cache[1].is_none());
+        let v = loaded.cache[0].as_ref().unwrap().v()?.unwrap();
+        assert_eq!(v.dims(), &[1, 1, 64, 4]);
+        assert_eq!(v.dtype(), DType::F32);
+        assert_eq!(v.flatten_all()?.to_vec1::<f32>()?[5], 10.);
+
+        // A 1 MB budget holds a handful of these; older entries are evicted first.
+        for i in 0..16u32 {
+            let toks = (0..4096).map(|t| t + i * 4096).collect::<Vec<_>>();
+            disk.insert(&toks, &cache(4096)?, None, None);
+        }
+        assert!(disk.size_bytes <= disk.max_size_bytes);
+        assert!(disk.best_match(&toks, None, None).is_none());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -6,7 +6,7 @@ use std::{
 use candle_core::{Device, Result};
 use indexmap::IndexMap;
 use itertools::Itertools;
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::{
     get_mut_arcmutex,
@@ -15,6 +15,13 @@ use crate::{
     sequence::{self, Sequence},
 };
 
+mod disk;
+mod session;
+
+pub(crate) use disk::DiskPrefixCache;
+pub use disk::PrefixCacheDiskConfig;
+pub(crate) use session::{SessionSnapshot, SessionTarget};
+
 type BlockBestMatch<'a> = (
     usize,                   // matched_len
     &'a [LogicalTokenBlock], // logical blocks
@@ -59,6 +66,15 @@ struct CacheElement {
     cache: Vec<Option<KvCache>>,
     audio_hashes: Option<Vec<u64>>,
     image_hashes: Option<Vec<u64>>,
+    /// Not evicted until a sequence reuses it, set for restored sessions.
+    pinned: bool,
+}
+
+/// Image and audio hashes of a sequence cached with PagedAttention, whose blocks only identify the tokens.
+#[derive(Clone, Default)]
+struct MultimodalHashes {
+    image_hashes: Option<Vec<u64>>,
+    audio_hashes: Option<Vec<u64>>,
 }
 
 #[derive(Clone)]
@@ -72,9 +88,12 @@ struct BlockCacheElement {
 pub struct PrefixCacheManagerV2 {
     caches: IndexMap<Tokens, CacheElement>,
     block_caches: IndexMap<Vec<u64>, BlockCacheElement>, // (hashed logical blocks) => BlockCacheElement
+    /// Multimodal hashes of the latest multimodal sequences cached with PagedAttention, for session snapshots.
+    block_multimodal_hashes: IndexMap<Tokens, MultimodalHashes>,
     n_on_device: usize,
     no_prefix_cache: bool,
     block_engine: Option<Arc<tokio::sync::Mutex<BlockEngine>>>,
+    disk: Option<DiskPrefixCache>,
 }
 
 #[derive(Clone)]
@@ -101,16 +120,23 @@ impl PrefixCacheManagerV2 {
         n_on_device: usize,
         no_prefix_cache: bool,
         block_engine: Option<Arc<tokio::sync::Mutex<BlockEngine>>>,
+        mut disk: Option<DiskPrefixCache>,
     ) -> Self {
         if !no_prefix_cache {
             info!("PrefixCacherV2 is enabled. Expect higher multi-turn throughput for both text and multimodal.");
         }
+        // Rejected when the engine is created, as PagedAttention blocks are not persisted.
+        if block_engine.is_some() && disk.take().is_some() {
+            warn!("The prefix cache disk tier is not supported with PagedAttention, disabling it.");
+        }
         PrefixCacheManagerV2 {
             caches: IndexMap::new(),
             block_caches: IndexMap::new(),
+            block_multimodal_hashes: IndexMap::new(),
             n_on_device,
             no_prefix_cache,
             block_engine,
+            disk,
         }
     }
 
@@ -122,6 +148,13 @@ impl PrefixCacheManagerV2 {
         }
 
         if let Some(_block_engine) = &self.block_engine {
+            self.add_block_multimodal_hashes(
+                seq.get_toks().to_vec(),
+                MultimodalHashes {
+                    image_hashes: seq.image_hashes().map(|x| x.to_vec()),
+                    audio_hashes: seq.audio_hashes().map(|x| x.to_vec()),
+                },
+            );
             // let logical_token_blocks = seq.logical_token_blocks();
             // let block_engine = get_mut_arcmutex!(block_engine);
             // let block_table = &block_engine.block_tables[seq.id()];
@@ -144,18 +177,34 @@ impl PrefixCacheManagerV2 {
             // }
         } else {
             let cache = seq.normal_cache().to_vec();
-
+            let image_hashes = seq.image_hashes().map(|x| x.to_vec());
+            let audio_hashes = seq.audio_hashes().map(|x| x.to_vec());
             self.caches.insert(
                 seq.get_toks().to_vec().into(),
                 CacheElement {
                     cache,
-                    image_hashes: seq.image_hashes().map(|x| x.to_vec()),
-                    audio_hashes: seq.audio_hashes().map(|x| x.to_vec()),
+                    image_hashes,
+                    audio_hashes,
+                    pinned: false,
                 },
             );
         }
     }
 
+    /// Remember the multimodal hashes of a sequence cached in PagedAttention blocks, keeping the latest
+    /// `n_on_device` sequences.
+    fn add_block_multimodal_hashes(&mut self, toks: Vec<u32>, hashes: MultimodalHashes) {
+        if hashes.image_hashes.is_none() && hashes.audio_hashes.is_none() {
+            return;
+        }
+        let toks = Tokens(toks);
+        self.block_multimodal_hashes.shift_remove(&toks);
+        self.block_multimodal_hashes.insert(toks, hashes);
+        while self.block_multimodal_hashes.len() > self.n_on_device {
+            self.block_multimodal_hashes.shift_remove_index(0);
+        }
+    }
+
     /// Evict the caches. This will evict the first k seqs such that the number of sequences on device after the copy is
     /// the maximum allowed. Returns the number of evicted sequences.
     pub fn evict_caches(&mut self) -> Result<usize> {
@@ -190,7 +239,7 @@ impl PrefixCacheManagerV2 {
         }
         let mut n_evicted = 0;
         // Intentionally evict the first ones first, as they are the oldest
-        for cache in self.caches.values_mut() {
+        for (tokens, cache) in self.caches.iter_mut() {
             if n_on_device - n_evicted <= self.n_on_device {
                 break;
             }
@@ -208,7 +257,15 @@ impl PrefixCacheManagerV2 {
                 }
             };
 
-            if !matches!(cache_device, Device::Cpu) {
+            if !matches!(cache_device, Device::Cpu) && !cache.pinned {
+                if let Some(disk) = &mut self.disk {
+                    disk.insert(
+                        &tokens.0,
+                        &cache.cache,
+                        cache.image_hashes.as_deref(),
+                        cache.audio_hashes.as_deref(),
+                    );
+                }
                 cache.cache.clear();
                 n_evicted += 1;
             }
@@ -239,6 +296,7 @@ impl PrefixCacheManagerV2 {
         let len = self.caches.len() + self.block_caches.len();
 
         self.caches.clear();
+        self.block_multimodal_hashes.clear();
         // With BlockRef, clearing automatically returns blocks to pool
         self.block_caches.clear();
         Ok(len)
@@ -378,8 +436,9 @@ impl PrefixCacheManagerV2 {
 
         let toks = Tokens(toks.to_vec());
 
-        let mut best_match: Option<(usize, &CacheElement, usize, usize)> = None;
-        for (k, v) in &self.caches {
+        // (match length, index in `caches`, images match until, audios match until)
+        let mut best_match: Option<(usize, usize, usize, usize)> = None;
+        for (i, (k, v)) in self.caches.iter().enumerate() {
             let match_len = toks.shared_prefix_len(k);
             if match_len == 0 {
                 continue;
@@ -413,18 +472,54 @@ impl PrefixCacheManagerV2 {
                 .as_ref()
                 .is_none_or(|(len, _, _, _)| match_len > *len)
             {
-                best_match = Some((match_len, v, images_match_until, audios_match_until));
+                best_match = Some((match_len, i, images_match_until, audios_match_until));
             }
         }
 
-        if let Some((match_len, cache_element, images_match_until, audios_match_until)) = best_match
-        {
+        let memory_match_len = best_match.as_ref().map_or(0, |(len, _, _, _)| *len);
+        let disk_match = self
+            .disk
+            .as_ref()
+            .and_then(|disk| disk.best_match(&toks.0, image_hashes, audio_hashes))
+            .filter(|m| m.match_len > memory_match_len && m.match_len < toks.0.len());
+        let best_match = match disk_match {
+            Some(m) => {
+                let Some(loaded) = self.disk.as_mut().and_then(|disk| disk.load(&m)) else {
+                    return Ok(None);
+                };
+                let element = CacheElement {
+                    cache: loaded.cache,
+                    image_hashes: loaded.image_hashes,
+                    audio_hashes: loaded.audio_hashes,
+                    pinned: false,
+                };
+                // Promote the cache back into memory so later turns hit it without touching the disk.
+                self.caches
+                    .insert(toks.0[..m.match_len].to_vec().into(), element.clone());
+                Some((
+                    m.match_len,
+                    element,
+                    m.images_match_until,
+                    m.audios_match_until,
+                ))
+            }
+            None => best_match.map(|(len, i, images, audios)| {
+                let (_, element) = self
+                    .caches
+                    .get_index_mut(i)
+                    .expect("Matched cache is present");
+                // Reused now, so it ages like any other cache.
+                element.pinned = false;
+                (len, element.clone(), images, audios)
+            }),
+        };
+
+        if let Some((match_len, mut cache, images_match_until, audios_match_until)) = best_match {
             let new_toks = toks.0[match_len..].to_vec();
             if new_toks.is_empty() {
                 return Ok(None);
             }
 
-            let mut cache = cache_element.clone();
             // Count how many input images are not already cached
             let images_to_keep = if let Some(input_hashes) = image_hashes {
                 input_hashes.len().saturating_sub(images_match_until)
@@ -456,3 +551,20 @@ impl PrefixCacheManagerV2 {
         Ok(None)
     }
 }
+
+impl Drop for PrefixCacheManagerV2 {
+    /// Caches are only written to the disk tier when evicted, so persist the ones still in memory.
+    fn drop(&mut self) {
+        let Some(disk) = &mut self.disk else {
+            return;
+        };
+        for (tokens, cache) in &self.caches {
+            disk.insert(
+                &tokens.0,
+                &cache.cache,
+                cache.image_hashes.as_deref(),
+                cache.audio_hashes.as_deref(),
+            );
+        }
+    }
+}
@@ -0,0 +1,530 @@
+//! Session snapshots: the KV state of a conversation, exported to a file and imported back into the prefix cache.
+//!
+//! A snapshot is a single safetensors file holding the keys and values of each layer in the
+//! `(1, n_kv_heads, seq_len, head_dim)` layout, plus a JSON header stored as a `u8` tensor with the tokens they
+//! cover and the multimodal hashes. The layout does not depend on the cache backend, so a snapshot taken with
+//! PagedAttention can be restored into a normal KV cache and vice versa.
+
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::Context;
+use candle_core::{DType, Device, Tensor};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::{CacheElement, MultimodalHashes, PrefixCacheManagerV2, Tokens};
+use crate::{
+    get_mut_arcmutex,
+    kv_cache::{KvCache, SingleCache},
+    paged_attention::LogicalTokenBlock,
+    sequence,
+};
+
+const METADATA_TENSOR: &str = "session.metadata";
+const FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct SessionMetadata {
+    version: u32,
+    /// Name of the model which produced the snapshot.
+    model: String,
+    toks: Vec<u32>,
+    image_hashes: Option<Vec<u64>>,
+    audio_hashes: Option<Vec<u64>>,
+    /// Whether each layer had a cache. Layers without one are not serialized.
+    layers: Vec<bool>,
+}
+
+/// The KV state of a conversation.
+pub(crate) struct SessionSnapshot {
+    pub(crate) toks: Vec<u32>,
+    image_hashes: Option<Vec<u64>>,
+    audio_hashes: Option<Vec<u64>>,
+    /// Keys and values of each layer, in the `(1, n_kv_heads, seq_len, head_dim)` layout.
+    layers: Vec<Option<(Tensor, Tensor)>>,
+}
+
+/// Where an imported session is restored to.
+pub(crate) enum SessionTarget<'a> {
+    /// The non-paged KV cache. `templates` is the model's cache, which gives the storage format of each layer.
+    Normal {
+        templates: &'a [KvCache],
+        layer_devices: &'a [Device],
+        dtype: DType,
+    },
+    /// The `(key, value)` block tensors of each PagedAttention layer.
+    Paged(&'a [(Tensor, Tensor)]),
+}
+
+impl SessionSnapshot {
+    pub(crate) fn save(&self, path: &Path, model: &str) -> anyhow::Result<()> {
+        let metadata = SessionMetadata {
+            version: FORMAT_VERSION,
+            model: model.to_string(),
+            toks: self.toks.clone(),
+            image_hashes: self.image_hashes.clone(),
+            audio_hashes: self.audio_hashes.clone(),
+            layers: self.layers.iter().map(Option::is_some).collect(),
+        };
+        let mut tensors = HashMap::new();
+        tensors.insert(
+            METADATA_TENSOR.to_string(),
+            Tensor::new(serde_json::to_vec(&metadata)?.as_slice(), &Device::Cpu)?,
+        );
+        for (i, layer) in self.layers.iter().enumerate() {
+            if let Some((k, v)) = layer {
+                tensors.insert(format!("layer.{i}.k"), k.to_device(&Device::Cpu)?);
+                tensors.insert(format!("layer.{i}.v"), v.to_device(&Device::Cpu)?);
+            }
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        candle_core::safetensors::save(&tensors, path)?;
+        Ok(())
+    }
+
+    /// Load a snapshot for a model named `model` with `num_layers` layers.
+    pub(crate) fn load(path: &Path, model: &str, num_layers: usize) -> anyhow::Result<Self> {
+        let mut tensors = candle_core::safetensors::load(path, &Device::Cpu)
+            .with_context(|| format!("Could not read session snapshot `{}`", path.display()))?;
+        let metadata = tensors
+            .remove(METADATA_TENSOR)
+            .context("Not a session snapshot, the metadata is missing.")?;
+        let metadata: SessionMetadata = serde_json::from_slice(&metadata.to_vec1::<u8>()?)?;
+        if metadata.version != FORMAT_VERSION {
+            anyhow::bail!(
+                "Unsupported session snapshot version {}, expected {FORMAT_VERSION}.",
+                metadata.version
+            );
+        }
+        if metadata.layers.len() != num_layers {
+            anyhow::bail!(
+                "Session snapshot has {} layers but the model has {num_layers}.",
+                metadata.layers.len()
+            );
+        }
+        if metadata.model != model {
+            warn!(
+                "Session snapshot `{}` was saved with model `{}`, loading it into `{model}`.",
+                path.display(),
+                metadata.model
+            );
+        }
+
+        let mut layers = Vec::with_capacity(num_layers);
+        for (i, present) in metadata.layers.iter().enumerate() {
+            if !present {
+                layers.push(None);
+                continue;
+            }
+            let mut take = |name: String| {
+                tensors
+                    .remove(&name)
+                    .with_context(|| format!("Session snapshot is missing tensor `{name}`."))
+            };
+            let (k, v) = (take(format!("layer.{i}.k"))?, take(format!("layer.{i}.v"))?);
+            if k.dims() != v.dims() || k.rank() != 4 || k.dim(2)? != metadata.toks.len() {
+                anyhow::bail!("Session snapshot has malformed tensors for layer {i}.");
+            }
+            layers.push(Some((k, v)));
+        }
+
+        Ok(Self {
+            toks: metadata.toks,
+            image_hashes: metadata.image_hashes,
+            audio_hashes: metadata.audio_hashes,
+            layers,
+        })
+    }
+}
+
+fn logical_blocks_for(toks: &[u32], block_size: usize) -> Vec<LogicalTokenBlock> {
+    let mut logical_blocks = Vec::new();
+    for tok in toks {
+        sequence::util_append_token_to_blocks(*tok as usize, &mut logical_blocks, block_size);
+    }
+    logical_blocks
+}
+
+fn check_paged_dtype(dtype: DType) -> anyhow::Result<()> {
+    if dtype == DType::F8E4M3 {
+        anyhow::bail!("Session snapshots do not support the FP8 PagedAttention cache.");
+    }
+    Ok(())
+}
+
+impl PrefixCacheManagerV2 {
+    /// Number of leading tokens of a `n_toks` long sequence which can be saved in a session. With PagedAttention
+    /// only full blocks are cached.
+    pub(crate) fn session_len(&self, n_toks: usize) -> usize {
+        match &self.block_engine {
+            Some(block_engine) => {
+                let block_size = get_mut_arcmutex!(block_engine).block_size();
+                n_toks - n_toks % block_size
+            }
+            None => n_toks,
+        }
+    }
+
+    /// Snapshot the longest cached prefix of `toks`. With PagedAttention, `paged_kv_cache` holds the block tensors.
+    pub(crate) fn export_session(
+        &mut self,
+        toks: &[u32],
+        paged_kv_cache: Option<&[(Tensor, Tensor)]>,
+    ) -> anyhow::Result<Option<SessionSnapshot>> {
+        if self.no_prefix_cache {
+            anyhow::bail!("Session snapshots require the prefix cache to be enabled.");
+        }
+
+        if let Some(block_engine) = &self.block_engine {
+            let paged_kv_cache =
+                paged_kv_cache.context("PagedAttention is enabled but there is no KV cache")?;
+            let block_size = get_mut_arcmutex!(block_engine).block_size();
+            let logical_blocks = logical_blocks_for(toks, block_size);
+            // Holding the refs keeps the blocks from being evicted and reused while they are read.
+            let blocks = get_mut_arcmutex!(block_engine).cached_prefix_blocks(&logical_blocks);
+            if blocks.is_empty() {
+                return Ok(None);
+            }
+            let n_blocks = blocks.len();
+            let block_ids = blocks
+                .iter()
+                .map(|block| block.block_id() as u32)
+                .collect::<Vec<_>>();
+
+            let mut layers = Vec::with_capacity(paged_kv_cache.len());
+            for (key_cache, value_cache) in paged_kv_cache {
+                check_paged_dtype(key_cache.dtype())?;
+                let ids = Tensor::new(block_ids.as_slice(), key_cache.device())?;
+                // Keys are `(num_blocks, h, d / x, block_size, x)` and values `(num_blocks, h, d, block_size)`.
+                let (_, h, d_x, bs, x) = key_cache.dims5()?;
+                let k = key_cache
+                    .index_select(&ids, 0)?
+                    .permute((1, 0, 3, 2, 4))?
+                    .reshape((1, h, n_blocks * bs, d_x * x))?;
+                let v = value_cache
+                    .index_select(&ids, 0)?
+                    .permute((1, 0, 3, 2))?
+                    .reshape((1, h, n_blocks * bs, d_x * x))?;
+                layers.push(Some((k, v)));
+            }
+            // The blocks only identify the tokens, so take the hashes of a sequence which the session is a prefix of.
+            let toks = Tokens(toks[..n_blocks * block_size].to_vec());
+            let hashes = self
+                .block_multimodal_hashes
+                .iter()
+                .rev()
+                .find(|(cached, _)| toks.shared_prefix_len(cached) == toks.0.len())
+                .map(|(_, hashes)| hashes.clone())
+                .unwrap_or_default();
+            return Ok(Some(SessionSnapshot {
+                toks: toks.0,
+                image_hashes: hashes.image_hashes,
+                audio_hashes: hashes.audio_hashes,
+                layers,
+            }));
+        }
+
+        let toks = Tokens(toks.to_vec());
+        let best_match = self
+            .caches
+            .iter()
+            .filter(|(_, element)| !element.cache.iter().flatten().any(KvCache::is_rotating))
+            .map(|(cached, element)| (toks.shared_prefix_len(cached), element))
+            .filter(|(match_len, _)| *match_len > 0)
+            .max_by_key(|(match_len, _)| *match_len);
+        let Some((match_len, element)) = best_match else {
+            return Ok(None);
+        };
+        // The last sampled token of a sequence has no KV yet.
+        let match_len = element
+            .cache
+            .iter()
+            .flatten()
+            .map(KvCache::current_seq_len)
+            .min()
+            .unwrap_or(0)
+            .min(match_len);
+        if match_len == 0 {
+            return Ok(None);
+        }
+
+        let mut layers = Vec::with_capacity(element.cache.len());
+        for layer in &element.cache {
+            let kv = match layer {
+                Some(layer) => match (layer.k()?, layer.v()?) {
+                    (Some(k), Some(v)) => {
+                        Some((k.narrow(2, 0, match_len)?, v.narrow(2, 0, match_len)?))
+                    }
+                    _ => None,
+                },
+                None => None,
+            };
+            layers.push(kv);
+        }
+        Ok(Some(SessionSnapshot {
+            toks: toks.0[..match_len].to_vec(),
+            image_hashes: element.image_hashes.clone(),
+            audio_hashes: element.audio_hashes.clone(),
+            layers,
+        }))
+    }
+
+    /// Restore a snapshot into the prefix cache, so that the next request continuing the conversation reuses it.
+    /// The restored cache is pinned until then, so that eviction does not drop it first.
+    /// Returns the number of tokens restored, which with PagedAttention only covers full blocks.
+    pub(crate) fn import_session(
+        &mut self,
+        snapshot: SessionSnapshot,
+        target: SessionTarget<'_>,
+    ) -> anyhow::Result<usize> {
+        if self.no_prefix_cache {
+            anyhow::bail!("Session snapshots require the prefix cache to be enabled.");
+        }
+
+        match target {
+            SessionTarget::Paged(paged_kv_cache) => {
+                let block_engine = self
+                    .block_engine
+                    .as_ref()
+                    .context("PagedAttention is enabled but there is no block engine")?;
+                let block_size = get_mut_arcmutex!(block_engine).block_size();
+                let n_blocks = snapshot.toks.len() / block_size;
+                if n_blocks == 0 {
+                    return Ok(0);
+                }
+                let n_toks = n_blocks * block_size;
+                let mut logical_blocks = logical_blocks_for(&snapshot.toks[..n_toks], block_size);
+                logical_blocks.truncate(n_blocks);
+
+                let blocks = get_mut_arcmutex!(block_engine)
+                    .allocate_unowned_blocks(n_blocks)
+                    .context("Not enough free KV cache blocks to load the session.")?;
+                for (i, (key_cache, value_cache)) in paged_kv_cache.iter().enumerate() {
+                    check_paged_dtype(key_cache.dtype())?;
+                    let Some(Some((k, v))) = snapshot.layers.get(i) else {
+                        anyhow::bail!("Session snapshot has no KV cache for layer {i}.");
+                    };
+                    let (_, h, d_x, bs, x) = key_cache.dims5()?;
+                    if k.dims() != [1, h, snapshot.toks.len(), d_x * x] {
+                        anyhow::bail!(
+                            "Session snapshot has KV shape {:?} for layer {i}, which does not match the model.",
+                            k.dims()
+                        );
+                    }
+                    let prepare = |data: &Tensor| {
+                        data.narrow(2, 0, n_toks)?
+                            .to_device(key_cache.device())?
+                            .to_dtype(key_cache.dtype())?
+                            .squeeze(0)
+                    };
+                    let k = prepare(k)?
+                        .reshape((h, n_blocks, bs, d_x, x))?
+                        .permute((1, 0, 3, 2, 4))?
+                        .contiguous()?;
+                    let v = prepare(v)?
+                        .reshape((h, n_blocks, bs, d_x * x))?
+                        .permute((1, 0, 3, 2))?
+                        .contiguous()?;
+                    for (j, block) in blocks.iter().enumerate() {
+                        key_cache.slice_set(&k.narrow(0, j, 1)?, 0, block.block_id())?;
+                        value_cache.slice_set(&v.narrow(0, j, 1)?, 0, block.block_id())?;
+                    }
+                }
+                get_mut_arcmutex!(block_engine).cache_prefix_blocks(&logical_blocks, &blocks);
+                self.add_block_multimodal_hashes(
+                    snapshot.toks[..n_toks].to_vec(),
+                    MultimodalHashes {
+                        image_hashes: snapshot.image_hashes,
+                        audio_hashes: snapshot.audio_hashes,
+                    },
+                );
+                Ok(n_toks)
+            }
+            SessionTarget::Normal {
+                templates,
+                layer_devices,
+                dtype,
+            } => {
+                let mut cache = Vec::with_capacity(templates.len());
+                for (i, template) in templates.iter().enumerate() {
+                    let KvCache::Normal { k: template, .. } = template else {
+                        anyhow::bail!("Session snapshots do not support sliding window caches.");
+                    };
+                    let Some(Some((k, v))) = snapshot.layers.get(i) else {
+                        anyhow::bail!("Session snapshot has no KV cache for layer {i}.");
+                    };
+                    let device = &layer_devices[i];
+                    let single = |data: &Tensor| -> candle_core::Result<SingleCache> {
+                        let data = data.to_device(device)?.to_dtype(dtype)?;
+                        let data = match template.quantization {
+                            Some(quantization) => quantization.quantize(&data)?,
+                            None => data,
+                        };
+                        Ok(SingleCache {
+                            all_data: Some(data),
+                            dim: template.dim,
+                            current_seq_len: snapshot.toks.len(),
+                            capacity_seq_len: snapshot.toks.len(),
+                            max_seq_len: template.max_seq_len,
+                            quantization: template.quantization,
+                        })
+                    };
+                    cache.push(Some(KvCache::Normal {
+                        k: single(k)?,
+                        v: single(v)?,
+                    }));
+                }
+                let n_toks = snapshot.toks.len();
+                self.caches.insert(
+                    snapshot.toks.into(),
+                    CacheElement {
+                        cache,
+                        image_hashes: snapshot.image_hashes,
+                        audio_hashes: snapshot.audio_hashes,
+                        pinned: true,
+                    },
+                );
+                Ok(n_toks)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use candle_core::{DType, Device, Tensor};
+
+    use super::{
+        logical_blocks_for, MultimodalHashes, PrefixCacheManagerV2, SessionTarget, Tokens,
+    };
+    use crate::{
+        get_mut_arcmutex,
+        kv_cache::KvCache,
+        paged_attention::BlockEngine,
+        prefix_cacher::{CacheElement, MatchingCache},
+    };
+
+    fn values(tensor: &Tensor) -> candle_core::Result<Vec<f32>> {
+        tensor.flatten_all()?.to_vec1()
+    }
+
+    #[test]
+    fn test_normal_session_roundtrip() -> anyhow::Result<()> {
+        let toks = vec![1, 2, 3, 4, 5, 6];
+        let k = Tensor::arange(0f32, 48., &Device::Cpu)?.reshape((1, 2, 6, 4))?;
+        let v = k.affine(1., 100.)?;
+        let mut cache = KvCache::new_normal(2, 64, 16);
+        cache.append(&k, &v)?;
+
+        let mut source = PrefixCacheManagerV2::new(16, false, None, None);
+        source.caches.insert(
+            Tokens(toks.clone()),
+            CacheElement {
+                cache: vec![Some(cache.clone()), Some(cache)],
+                image_hashes: Some(vec![7]),
+                audio_hashes: None,
+                pinned: false,
+            },
+        );
+        let snapshot = source.export_session(&toks, None)?.unwrap();
+        assert_eq!(snapshot.toks, toks);
+
+        let templates = vec![KvCache::new_normal(2, 64, 16); 2];
+        let mut target = PrefixCacheManagerV2::new(16, false, None, None);
+        let n_restored = target.import_session(
+            snapshot,
+            SessionTarget::Normal {
+                templates: &templates,
+                layer_devices: &[Device::Cpu, Device::Cpu],
+                dtype: DType::F32,
+            },
+        )?;
+        assert_eq!(n_restored, 6);
+        assert!(target.caches.values().all(|element| element.pinned));
+
+        let matched =
+            target.search_for_matching_cache(&[1, 2, 3, 4, 5, 6, 7], Some(&[7][..]), None)?;
+        let Some(MatchingCache::Normal {
+            normal,
+            images_to_keep,
+            offset,
+            ..
+        }) = matched
+        else {
+            panic!("The restored session was not matched");
+        };
+        assert_eq!((offset, images_to_keep), (6, 0));
+        for layer in normal.iter().flatten() {
+            assert_eq!(values(&layer.k()?.unwrap())?, values(&k)?);
+            assert_eq!(values(&layer.v()?.unwrap())?, values(&v)?);
+        }
+        // Reused, so it can be evicted again.
+        assert!(target.caches.values().all(|element| !element.pinned));
+        Ok(())
+    }
+
+    #[test]
+    fn test_paged_session_roundtrip() -> anyhow::Result<()> {
+        // 4 blocks of 2 tokens, 2 heads of size 4 stored as `d / x = 2` chunks of `x = 2`.
+        let toks = vec![1, 2, 3, 4, 5];
+        let paged_cache = |data: bool| -> candle_core::Result<(Tensor, Tensor)> {
+            let (k, v) = if data {
+                let k = Tensor::arange(0f32, 64., &Device::Cpu)?;
+                (k.clone(), k.affine(1., 100.)?)
+            } else {
+                let zeros = Tensor::zeros(64, DType::F32, &Device::Cpu)?;
+                (zeros.clone(), zeros)
+            };
+            Ok((k.reshape((4, 2, 2, 2, 2))?, v.reshape((4, 2, 4, 2))?))
+        };
+        let manager = |hashes: bool| {
+            let block_engine = Arc::new(tokio::sync::Mutex::new(BlockEngine::new(2, 4, 0, true)));
+            let mut manager =
+                PrefixCacheManagerV2::new(16, false, Some(block_engine.clone()), None);
+            if hashes {
+                manager.add_block_multimodal_hashes(
+                    toks.clone(),
+                    MultimodalHashes {
+                        image_hashes: Some(vec![7]),
+                        audio_hashes: None,
+                    },
+                );
+            }
+            (block_engine, manager)
+        };
+
+        let source_cache = [paged_cache(true)?];
+        let (source_engine, mut source) = manager(true);
+        let blocks = get_mut_arcmutex!(source_engine)
+            .allocate_unowned_blocks(2)
+            .unwrap();
+        get_mut_arcmutex!(source_engine)
+            .cache_prefix_blocks(&logical_blocks_for(&toks[..4], 2), &blocks);
+        let snapshot = source.export_session(&toks, Some(&source_cache))?.unwrap();
+        assert_eq!(snapshot.toks, [1, 2, 3, 4]);
+        assert_eq!(snapshot.image_hashes, Some(vec![7]));
+        let expected = snapshot.layers.clone();
+
+        let target_cache = [paged_cache(false)?];
+        let (target_engine, mut target) = manager(false);
+        let n_restored = target.import_session(snapshot, SessionTarget::Paged(&target_cache))?;
+        assert_eq!(n_restored, 4);
+        // The restored blocks are pinned, so only the other two can be handed out.
+        assert_eq!(get_mut_arcmutex!(target_engine).num_available_blocks(), 2);
+
+        let restored = target.export_session(&toks, Some(&target_cache))?.unwrap();
+        assert_eq!(restored.toks, [1, 2, 3, 4]);
+        assert_eq!(restored.image_hashes, Some(vec![7]));
+        for (restored, expected) in restored.layers.iter().zip(&expected) {
+            let ((k, v), (expected_k, expected_v)) =
+                (restored.as_ref().unwrap(), expected.as_ref().unwrap());
+            assert_eq!(values(k)?, values(expected_k)?);
+            assert_eq!(values(v)?, values(expected_v)?);
+        }
+        Ok(())
+    }
+}
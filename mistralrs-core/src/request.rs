@@ -9,7 +9,7 @@ use crate::{
     response::Response, sampler::SamplingParams, tools::ToolChoice, CustomLogitsProcessor,
     DiffusionGenerationParams, Tool,
 };
-use std::{fmt::Debug, sync::Arc};
+use std::{fmt::Debug, path::PathBuf, sync::Arc, time::Duration};
 use tokio::sync::mpsc::Sender;
 
 pub type LlguidanceGrammar = llguidance::api::TopLevelGrammar;
@@ -62,6 +62,22 @@ impl ReasoningEffort {
     }
 }
 
+/// Scheduling priority class of a request.
+/// Waiting sequences are scheduled highest priority first, and lower-priority running sequences
+/// are preempted first when the KV cache is exhausted.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[cfg_attr(feature = "pyo3_macros", pyo3::pyclass(eq, eq_int))]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum RequestPriority {
+    /// Bulk or batch work that may be delayed
+    Low,
+    #[default]
+    Normal,
+    /// Latency-sensitive, interactive work
+    High,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 /// Message or messages for a [`Request`].
 pub enum RequestMessage {
@@ -173,6 +189,11 @@ pub struct WebSearchOptions {
 ///     4) Sample the next token (topk, topp, minp, etc)
 /// - `return_raw_logits`: Return raw logits.
 /// - `truncate_sequence`: Whether to truncate the prompt if it exceeds the model's maximum context length.
+/// - `priority`: Scheduling priority class of the request.
+/// - `timeout`: Give up on the request after this long. Sequences still generating are finished
+///   with the `timeout` finish reason, returning what was generated so far.
+/// - `prompt_logprobs`: Return the logprob of every prompt token after the first, with this many
+///   top alternatives each. Not supported for streaming requests.
 pub struct NormalRequest {
     pub messages: RequestMessage,
     pub sampling_params: SamplingParams,
@@ -193,6 +214,15 @@ pub struct NormalRequest {
     pub model_id: Option<String>,
     #[serde(default)]
     pub truncate_sequence: bool,
+    #[serde(default)]
+    pub priority: RequestPriority,
+    #[serde(default)]
+    pub timeout: Option<Duration>,
+    #[serde(default)]
+    pub prompt_logprobs: Option<usize>,
+    /// W3C `traceparent` of the caller's span, so that the engine's spans join the caller's trace.
+    #[serde(default)]
+    pub traceparent: Option<String>,
 }
 
 impl NormalRequest {
@@ -220,6 +250,10 @@ impl NormalRequest {
             web_search_options: None,
             model_id: None,
             truncate_sequence: false,
+            priority: RequestPriority::default(),
+            timeout: None,
+            prompt_logprobs: None,
+            traceparent: None,
         }
     }
 }
@@ -249,6 +283,31 @@ pub struct DetokenizationRequest {
     pub response: Sender<anyhow::Result<String>>,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+/// Request to save the KV cache of a conversation to a session snapshot file.
+/// - `tokens` is the conversation so far, for example from a [`TokenizationRequest`] of the messages without the
+///   generation prompt. The longest cached prefix is saved; if the tokens are not cached, they are prefilled first.
+/// - `id` is the request ID used to prefill the tokens, from [`crate::MistralRs::next_request_id`].
+/// - Responds with the number of tokens saved. With PagedAttention only full blocks are saved.
+pub struct SaveSessionRequest {
+    pub id: usize,
+    pub tokens: Vec<u32>,
+    pub path: PathBuf,
+    #[serde(default = "default_responder")]
+    #[serde(skip)]
+    pub response: Sender<anyhow::Result<usize>>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+/// Request to load a session snapshot file into the prefix cache, so that the next request continuing the
+/// conversation reuses its KV cache. Responds with the tokens of the restored conversation.
+pub struct LoadSessionRequest {
+    pub path: PathBuf,
+    #[serde(default = "default_responder")]
+    #[serde(skip)]
+    pub response: Sender<anyhow::Result<Vec<u32>>>,
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 /// A request to the Engine, encapsulating the various parameters as well as
 /// the `mpsc` response `Sender` used to return the [`Response`].
@@ -257,10 +316,17 @@ pub enum Request {
     ReIsq(IsqType),
     Tokenize(TokenizationRequest),
     Detokenize(DetokenizationRequest),
+    SaveSession(SaveSessionRequest),
+    LoadSession(LoadSessionRequest),
     // Sending a terminate request causes the `run` function to return to the thread created in `MistralRs::new`,
     // and then Engine will be dropped.
     Terminate,
     TerminateAllSeqsNextStep,
+    /// Cancel the sequences of the request with this ID on the next step, finishing them with the
+    /// `canceled` finish reason.
+    Cancel {
+        id: usize,
+    },
 }
 
 impl Debug for Request {
@@ -288,8 +354,15 @@ impl Debug for Request {
             Request::Detokenize(req) => {
                 write!(f, "Tokenization Request {:?}", req.tokens)
             }
+            Request::SaveSession(req) => {
+                write!(f, "Save Session Request {}", req.path.display())
+            }
+            Request::LoadSession(req) => {
+                write!(f, "Load Session Request {}", req.path.display())
+            }
             Request::Terminate => write!(f, "Termination Request"),
             Request::TerminateAllSeqsNextStep => write!(f, "Terminate All Seqs Next Step"),
+            Request::Cancel { id } => write!(f, "Cancel Request {id}"),
         }
     }
 }
@@ -1,4 +1,5 @@
 use std::{
+    collections::HashMap,
     error::Error,
     fmt::{Debug, Display},
     sync::Arc,
@@ -9,7 +10,10 @@ use candle_core::Tensor;
 use pyo3::{pyclass, pymethods};
 use serde::Serialize;
 
-use crate::{sampler::TopLogprob, tools::ToolCallResponse};
+use crate::{
+    sampler::TopLogprob,
+    tools::{ToolCallDelta, ToolCallResponse},
+};
 
 pub const SYSTEM_FINGERPRINT: &str = "local";
 
@@ -48,7 +52,7 @@ generate_repr!(ResponseMessage);
 pub struct Delta {
     pub content: Option<String>,
     pub role: String,
-    pub tool_calls: Option<Vec<ToolCallResponse>>,
+    pub tool_calls: Option<Vec<ToolCallDelta>>,
     /// Reasoning/analysis content delta from Harmony format.
     /// This contains incremental chain-of-thought reasoning.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -89,6 +93,9 @@ pub struct Choice {
     pub index: usize,
     pub message: ResponseMessage,
     pub logprobs: Option<Logprobs>,
+    /// The stop sequence which ended the generation, which `finish_reason` reports as `stop` too.
+    #[serde(skip)]
+    pub stop_sequence: Option<String>,
 }
 
 generate_repr!(Choice);
@@ -102,6 +109,9 @@ pub struct ChunkChoice {
     pub index: usize,
     pub delta: Delta,
     pub logprobs: Option<ResponseLogprob>,
+    /// The stop sequence which ended the generation, which `finish_reason` reports as `stop` too.
+    #[serde(skip)]
+    pub stop_sequence: Option<String>,
 }
 
 generate_repr!(ChunkChoice);
@@ -149,6 +159,9 @@ pub struct ChatCompletionResponse {
     pub system_fingerprint: String,
     pub object: String,
     pub usage: Usage,
+    /// Logprobs of the prompt tokens, if requested. The first prompt token has none.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_logprobs: Option<Vec<Option<ResponseLogprob>>>,
 }
 
 generate_repr!(ChatCompletionResponse);
@@ -169,6 +182,90 @@ pub struct ChatCompletionChunkResponse {
 
 generate_repr!(ChatCompletionChunkResponse);
 
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// Logprobs of a completion choice, in the format of the OpenAI legacy completions API.
+/// Echoed prompt tokens come first; the first prompt token has no logprob.
+pub struct CompletionLogprobs {
+    pub tokens: Vec<String>,
+    pub token_logprobs: Vec<Option<f32>>,
+    pub top_logprobs: Vec<Option<HashMap<String, f32>>>,
+    /// Offset of each token in the choice text.
+    pub text_offset: Vec<usize>,
+}
+
+impl CompletionLogprobs {
+    /// Build the logprobs of the generated tokens, preceded by those of the echoed prompt given
+    /// as its decoded tokens and their logprobs, aligned by position. Offsets are those of the
+    /// tokens in `text`, the choice text including the echoed prompt.
+    pub(crate) fn new(
+        text: &str,
+        echoed_prompt: Option<(&[String], &[Option<ResponseLogprob>])>,
+        generated: &[ResponseLogprob],
+    ) -> Self {
+        let mut logprobs = Self {
+            tokens: Vec::new(),
+            token_logprobs: Vec::new(),
+            top_logprobs: Vec::new(),
+            text_offset: Vec::new(),
+        };
+        if let Some((tokens, prompt)) = echoed_prompt {
+            for (i, token) in tokens.iter().enumerate() {
+                logprobs.push(token.clone(), prompt.get(i).and_then(Option::as_ref));
+            }
+        }
+        for logprob in generated {
+            logprobs.push(logprob.token.clone(), Some(logprob));
+        }
+        logprobs.text_offset = text_offsets(text, &logprobs.tokens);
+        logprobs
+    }
+
+    fn push(&mut self, token: String, logprob: Option<&ResponseLogprob>) {
+        self.tokens.push(token);
+        self.token_logprobs
+            .push(logprob.map(|logprob| logprob.logprob));
+        self.top_logprobs.push(logprob.map(|logprob| {
+            let mut top_logprobs = HashMap::new();
+            // Tokens decoding to the same text keep the most likely logprob.
+            for top in &logprob.top_logprobs {
+                let token = top.bytes.clone().unwrap_or_else(|| top.token.to_string());
+                top_logprobs.entry(token).or_insert(top.logprob);
+            }
+            top_logprobs
+        }));
+    }
+}
+
+/// Offset of each token in `text`. Tokens which do not appear where expected, such as a BOS token
+/// or a partial UTF-8 sequence, are placed at the current offset without advancing it.
+fn text_offsets(text: &str, tokens: &[String]) -> Vec<usize> {
+    let mut offset = 0;
+    let mut offsets = Vec::with_capacity(tokens.len());
+    for token in tokens {
+        let rest = &text[offset..];
+        // The completion text is trimmed at the start, so its first token may lose its spaces.
+        let trimmed = token.trim_start();
+        let (start, len) = if token.is_empty() {
+            (0, 0)
+        } else if rest.starts_with(token.as_str()) {
+            (0, token.len())
+        } else if !trimmed.is_empty() && rest.starts_with(trimmed) {
+            (0, trimmed.len())
+        } else if let Some(start) = rest.find(token.as_str()) {
+            (start, token.len())
+        } else {
+            (0, 0)
+        };
+        offsets.push(offset + start);
+        offset += start + len;
+    }
+    offsets
+}
+
+generate_repr!(CompletionLogprobs);
+
 #[cfg_attr(feature = "pyo3_macros", pyclass)]
 #[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
 #[derive(Debug, Clone, Serialize)]
@@ -177,7 +274,7 @@ pub struct CompletionChoice {
     pub finish_reason: String,
     pub index: usize,
     pub text: String,
-    pub logprobs: Option<()>,
+    pub logprobs: Option<CompletionLogprobs>,
 }
 
 generate_repr!(CompletionChoice);
@@ -194,6 +291,9 @@ pub struct CompletionResponse {
     pub system_fingerprint: String,
     pub object: String,
     pub usage: Usage,
+    /// Logprobs of the prompt tokens, if requested. The first prompt token has none.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_logprobs: Option<Vec<Option<ResponseLogprob>>>,
 }
 
 generate_repr!(CompletionResponse);
@@ -385,3 +485,75 @@ impl Response {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logprob(token: &str, logprob: f32, top: &[(u32, Option<&str>, f32)]) -> ResponseLogprob {
+        ResponseLogprob {
+            token: token.to_string(),
+            logprob,
+            bytes: Some(token.as_bytes().to_vec()),
+            top_logprobs: top
+                .iter()
+                .map(|&(token, bytes, logprob)| TopLogprob {
+                    token,
+                    logprob,
+                    bytes: bytes.map(ToString::to_string),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_completion_logprobs_align_echoed_prompt() {
+        let prompt_tokens = ["<s>", "Hello", " world"].map(ToString::to_string);
+        let prompt_logprobs = [
+            None,
+            None,
+            Some(logprob(" world", -0.5, &[(1, Some(" world"), -0.5)])),
+        ];
+        let generated = [logprob(" again", -1.0, &[])];
+        let logprobs = CompletionLogprobs::new(
+            "Hello world again",
+            Some((&prompt_tokens, &prompt_logprobs)),
+            &generated,
+        );
+
+        assert_eq!(logprobs.tokens, ["<s>", "Hello", " world", " again"]);
+        assert_eq!(
+            logprobs.token_logprobs,
+            [None, None, Some(-0.5), Some(-1.0)]
+        );
+        assert_eq!(logprobs.text_offset, [0, 0, 5, 11]);
+    }
+
+    #[test]
+    fn test_completion_logprobs_offsets_follow_trimmed_text() {
+        let generated = [
+            logprob(" Hi", -0.1, &[]),
+            logprob("\u{FFFD}", -0.2, &[]),
+            logprob("\u{FFFD}", -0.3, &[]),
+            logprob(" there", -0.4, &[]),
+        ];
+        let logprobs = CompletionLogprobs::new("Hi\u{e9} there", None, &generated);
+
+        assert_eq!(logprobs.text_offset, [0, 2, 2, 4]);
+    }
+
+    #[test]
+    fn test_completion_top_logprobs_keyed_by_token_text() {
+        let generated = [logprob(
+            "a",
+            -0.1,
+            &[(1, Some("a"), -0.1), (2, None, -2.0), (3, Some("a"), -3.0)],
+        )];
+        let logprobs = CompletionLogprobs::new("a", None, &generated);
+
+        let top = logprobs.top_logprobs[0].as_ref().unwrap();
+        assert_eq!(top.len(), 2);
+        assert_eq!(top["a"], -0.1);
+        assert_eq!(top["2"], -2.0);
+    }
+}
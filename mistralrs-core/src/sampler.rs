@@ -10,7 +10,10 @@ use mistralrs_quant::{CumSumOp, SortOp};
 #[cfg(feature = "pyo3_macros")]
 use pyo3::pyclass;
 
-use rand::distr::{weighted::WeightedIndex, Distribution};
+use rand::{
+    distr::{weighted::WeightedIndex, Distribution},
+    Rng,
+};
 use rand_isaac::Isaac64Rng;
 use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
 use serde::{Deserialize, Serialize};
@@ -42,6 +45,28 @@ pub struct SamplingParams {
     pub logits_bias: Option<HashMap<u32, f32>>,
     pub n_choices: usize,
     pub dry_params: Option<DrySamplingParams>,
+    /// Seed for a per-sequence RNG. When set, sampling for this request is reproducible
+    /// regardless of which other requests it is batched with.
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// Decode with deterministic beam search instead of sampling. The `n_choices` best
+    /// hypotheses are returned.
+    #[serde(default)]
+    pub beam_search: Option<BeamSearchParams>,
+    /// Locally typical sampling mass in [0, 1], where 0 and 1 disable it.
+    #[serde(default)]
+    pub typical_p: Option<f64>,
+    /// Top-a sampling: drop tokens with a probability below `top_a * max_prob^2`. In [0, 1].
+    #[serde(default)]
+    pub top_a: Option<f64>,
+    #[serde(default)]
+    pub xtc_params: Option<XtcSamplingParams>,
+    /// Mirostat v2 replaces the final multinomial sampling step.
+    #[serde(default)]
+    pub mirostat_params: Option<MirostatParams>,
+    /// Explicit order of the samplers. Samplers which are not listed are not applied.
+    #[serde(default)]
+    pub sampler_order: Option<Vec<SamplerKind>>,
 }
 
 impl SamplingParams {
@@ -64,7 +89,39 @@ impl SamplingParams {
             logits_bias: None,
             n_choices: 1,
             dry_params: None,
+            seed: None,
+            beam_search: None,
+            typical_p: None,
+            top_a: None,
+            xtc_params: None,
+            mirostat_params: None,
+            sampler_order: None,
+        }
+    }
+
+    /// Describe the first parameter which is out of its range, if any.
+    pub(crate) fn range_error(&self) -> Option<String> {
+        if let Some(typical_p) = self.typical_p.filter(|p| !(0.0..=1.0).contains(p)) {
+            return Some(format!("`typical_p` must be in [0, 1], got {typical_p}."));
+        }
+        if let Some(top_a) = self.top_a.filter(|a| !(0.0..=1.0).contains(a)) {
+            return Some(format!("`top_a` must be in [0, 1], got {top_a}."));
         }
+        if let Some(params) = &self.mirostat_params {
+            if params.tau <= 0.0 {
+                return Some(format!(
+                    "Mirostat tau must be positive, got {}.",
+                    params.tau
+                ));
+            }
+            if params.eta < 0.0 {
+                return Some(format!(
+                    "Mirostat eta must not be negative, got {}.",
+                    params.eta
+                ));
+            }
+        }
+        None
     }
 }
 
@@ -103,6 +160,107 @@ impl Default for DrySamplingParams {
     }
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+/// XTC (exclude top choices) parameters.
+/// - `probability`: Chance of applying XTC at each step
+/// - `threshold`: All tokens at or above this probability except the least likely of them are removed
+pub struct XtcSamplingParams {
+    pub probability: f32,
+    pub threshold: f32,
+}
+
+impl XtcSamplingParams {
+    pub fn new_with_defaults(probability: f32, threshold: Option<f32>) -> anyhow::Result<Self> {
+        let threshold = threshold.unwrap_or(0.1);
+        if !(0.0..=1.0).contains(&probability) {
+            anyhow::bail!("XTC probability must be in [0, 1], got {probability}.");
+        }
+        if !(0.0..=1.0).contains(&threshold) {
+            anyhow::bail!("XTC threshold must be in [0, 1], got {threshold}.");
+        }
+        Ok(Self {
+            probability,
+            threshold,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Mirostat v2 parameters.
+/// - `tau`: Target surprise (cross-entropy) of the generated text
+/// - `eta`: Learning rate of the surprise threshold
+pub struct MirostatParams {
+    pub tau: f32,
+    pub eta: f32,
+}
+
+impl MirostatParams {
+    pub fn new_with_defaults(tau: f32, eta: Option<f32>) -> anyhow::Result<Self> {
+        let eta = eta.unwrap_or(0.1);
+        if tau <= 0.0 {
+            anyhow::bail!("Mirostat tau must be positive, got {tau}.");
+        }
+        if eta < 0.0 {
+            anyhow::bail!("Mirostat eta must not be negative, got {eta}.");
+        }
+        Ok(Self { tau, eta })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "pyo3_macros", pyclass(eq, eq_int))]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+/// A sampler in an explicit sampler order.
+pub enum SamplerKind {
+    Temperature,
+    TopK,
+    TopP,
+    MinP,
+    TypicalP,
+    TopA,
+    Xtc,
+}
+
+/// Sampler order used if no explicit order is given.
+const DEFAULT_SAMPLER_ORDER: &[SamplerKind] = &[
+    SamplerKind::Temperature,
+    SamplerKind::TopK,
+    SamplerKind::TopP,
+    SamplerKind::MinP,
+    SamplerKind::TypicalP,
+    SamplerKind::TopA,
+    SamplerKind::Xtc,
+];
+
+/// Mirostat v2 state. `mu` adapts to the generated text, so every sequence has its own copy.
+#[derive(Debug)]
+struct MirostatState {
+    tau: f32,
+    eta: f32,
+    mu: Mutex<f32>,
+}
+
+impl MirostatState {
+    fn new(params: MirostatParams) -> Self {
+        Self {
+            tau: params.tau,
+            eta: params.eta,
+            mu: Mutex::new(2.0 * params.tau),
+        }
+    }
+}
+
+impl Clone for MirostatState {
+    fn clone(&self) -> Self {
+        Self {
+            tau: self.tau,
+            eta: self.eta,
+            mu: Mutex::new(*self.mu.lock().expect("could not lock mirostat mutex")),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 struct DrySamplingParamsInner {
     pub sequence_breakers: HashSet<u32>,
@@ -148,6 +306,41 @@ impl DrySamplingParamsInner {
     }
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Beam search parameters.
+/// - `num_beams`: Number of beams kept alive at each step
+/// - `length_penalty`: Exponent applied to the generated length when normalizing beam scores.
+///   Values above 0 favor longer hypotheses, values below 0 favor shorter ones.
+/// - `early_stopping`: Stop as soon as enough hypotheses have finished, instead of when no
+///   live beam can still beat them.
+pub struct BeamSearchParams {
+    pub num_beams: usize,
+    pub length_penalty: f32,
+    pub early_stopping: bool,
+}
+
+impl BeamSearchParams {
+    pub fn new_with_defaults(
+        num_beams: usize,
+        length_penalty: Option<f32>,
+        early_stopping: Option<bool>,
+    ) -> anyhow::Result<Self> {
+        if num_beams == 0 {
+            anyhow::bail!("`num_beams` must be at least 1.");
+        }
+        Ok(Self {
+            num_beams,
+            length_penalty: length_penalty.unwrap_or(1.0),
+            early_stopping: early_stopping.unwrap_or(false),
+        })
+    }
+
+    /// Length-normalized score of a hypothesis with `len` generated tokens.
+    pub fn score(&self, cumulative_logprob: f32, len: usize) -> f32 {
+        cumulative_logprob / (len.max(1) as f32).powf(self.length_penalty)
+    }
+}
+
 /// Customizable logits processor.
 ///
 /// # Example
@@ -191,6 +384,11 @@ pub struct Sampler {
     top_k: i64,
     top_p: f64,
     min_p: f64,
+    typical_p: f64,
+    top_a: f64,
+    xtc_params: Option<XtcSamplingParams>,
+    mirostat: Option<MirostatState>,
+    sampler_order: Option<Vec<SamplerKind>>,
     logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
     /// Cached Gumbel noise tensor to avoid reallocating it.
     gumbel_cache: Arc<Mutex<Option<Tensor>>>,
@@ -225,7 +423,7 @@ fn cmp_desc_by_prob(a: &(u32, f32), b: &(u32, f32)) -> std::cmp::Ordering {
 ///
 /// If `k >= probs.len()`, returns all elements sorted.
 /// Also zeros out elements in `probs` beyond top-k if `zero_rest` is true.
-fn partial_sort_top_k(probs: &mut [f32], k: usize, zero_rest: bool) -> Vec<(u32, f32)> {
+pub(crate) fn partial_sort_top_k(probs: &mut [f32], k: usize, zero_rest: bool) -> Vec<(u32, f32)> {
     let n = probs.len();
     if n == 0 || k == 0 {
         return Vec::new();
@@ -259,6 +457,29 @@ fn partial_sort_top_k(probs: &mut [f32], k: usize, zero_rest: bool) -> Vec<(u32,
     idx_probs
 }
 
+/// Scale `probs` to sum to 1, unless they are all zero.
+fn renormalize(probs: &mut [f32]) {
+    let sum = probs.iter().sum::<f32>();
+    if sum > 0.0 {
+        for prob in probs.iter_mut() {
+            *prob /= sum;
+        }
+    }
+}
+
+/// Zero all but the smallest set of tokens, taken in the order of `idx_probs`, whose
+/// cumulative probability reaches `mass`.
+fn keep_cumulative_mass(probs: &mut [f32], idx_probs: &[(u32, f32)], mass: f32) {
+    let mut cumsum = 0.;
+    for (index, prob) in idx_probs {
+        if cumsum >= mass {
+            probs[*index as usize] = 0.0;
+        } else {
+            cumsum += prob;
+        }
+    }
+}
+
 /// Find the index of the maximum element in a slice. O(n) scan.
 #[inline]
 fn argmax_f32(values: &[f32]) -> u32 {
@@ -283,6 +504,11 @@ impl Sampler {
         top_k: i64,
         top_p: f64,
         min_p: f64,
+        typical_p: f64,
+        top_a: f64,
+        xtc_params: Option<XtcSamplingParams>,
+        mirostat_params: Option<MirostatParams>,
+        sampler_order: Option<Vec<SamplerKind>>,
         logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
     ) -> anyhow::Result<Self> {
         let temperature = if temperature.is_none_or(|v| v < 1e-7) {
@@ -310,6 +536,11 @@ impl Sampler {
             top_k,
             top_p,
             min_p,
+            typical_p,
+            top_a,
+            xtc_params,
+            mirostat: mirostat_params.map(MirostatState::new),
+            sampler_order,
             logits_processors,
             gumbel_cache: Arc::new(Mutex::new(None)),
         })
@@ -743,6 +974,184 @@ impl Sampler {
         self.sample_multinomial(probs, return_logprobs, rng)
     }
 
+    /// Whether sampling needs the configurable sampler chain rather than top-k/top-p/min-p.
+    fn uses_sampler_chain(&self) -> bool {
+        self.sampler_order.is_some()
+            || (self.typical_p > 0.0 && self.typical_p < 1.0)
+            || self.top_a > 0.0
+            || self.xtc_params.is_some()
+            || self.mirostat.is_some()
+    }
+
+    /// Apply the samplers in `sampler_order`, or the default order, to `probs` and sample.
+    /// `probs` must have been computed without temperature.
+    fn sample_chain(
+        &self,
+        probs: &mut [f32],
+        temperature: f64,
+        return_logprobs: bool,
+        rng: Arc<Mutex<Isaac64Rng>>,
+    ) -> Result<Logprobs> {
+        let order = self
+            .sampler_order
+            .as_deref()
+            .unwrap_or(DEFAULT_SAMPLER_ORDER);
+        for sampler in order {
+            match sampler {
+                SamplerKind::Temperature => Self::apply_temperature(probs, temperature as f32),
+                SamplerKind::TopK => {
+                    if self.top_k > 0 {
+                        partial_sort_top_k(probs, self.top_k as usize, true);
+                    }
+                }
+                SamplerKind::TopP => Self::apply_top_p(probs, self.top_p as f32),
+                SamplerKind::MinP => Self::apply_min_p(probs, self.min_p as f32),
+                SamplerKind::TypicalP => Self::apply_typical_p(probs, self.typical_p as f32),
+                SamplerKind::TopA => Self::apply_top_a(probs, self.top_a as f32),
+                SamplerKind::Xtc => {
+                    if let Some(ref params) = self.xtc_params {
+                        Self::apply_xtc(probs, params, &rng);
+                    }
+                }
+            }
+            renormalize(probs);
+        }
+
+        match self.mirostat {
+            Some(ref mirostat) => self.sample_mirostat(probs, mirostat, return_logprobs, rng),
+            None => self.sample_multinomial(probs, return_logprobs, rng),
+        }
+    }
+
+    fn apply_temperature(probs: &mut [f32], temperature: f32) {
+        if temperature == 1.0 {
+            return;
+        }
+        // Rescale in log space, as `p^(1/T)` underflows for small temperatures.
+        let max_logprob = probs
+            .iter()
+            .filter(|prob| **prob > 0.0)
+            .map(|prob| prob.ln() / temperature)
+            .fold(f32::NEG_INFINITY, f32::max);
+        for prob in probs.iter_mut() {
+            if *prob > 0.0 {
+                *prob = (prob.ln() / temperature - max_logprob).exp();
+            }
+        }
+    }
+
+    fn apply_top_p(probs: &mut [f32], top_p: f32) {
+        if top_p <= 0.0 || top_p >= 1.0 {
+            return;
+        }
+        let idx_probs = partial_sort_top_k(probs, probs.len(), false);
+        keep_cumulative_mass(probs, &idx_probs, top_p);
+    }
+
+    fn apply_min_p(probs: &mut [f32], min_p: f32) {
+        if min_p <= 0.0 || min_p >= 1.0 {
+            return;
+        }
+        let min_p_threshold = probs.iter().copied().fold(0.0, f32::max) * min_p;
+        for prob in probs.iter_mut() {
+            if min_p_threshold >= *prob {
+                *prob = 0.0;
+            }
+        }
+    }
+
+    /// Locally typical sampling keeps the tokens whose surprise is closest to the entropy of
+    /// the distribution, up to a cumulative probability of `typical_p`.
+    fn apply_typical_p(probs: &mut [f32], typical_p: f32) {
+        if typical_p <= 0.0 || typical_p >= 1.0 {
+            return;
+        }
+        let entropy = -probs
+            .iter()
+            .filter(|prob| **prob > 0.0)
+            .map(|prob| prob * prob.ln())
+            .sum::<f32>();
+        let mut idx_probs = probs
+            .iter()
+            .enumerate()
+            .filter(|(_, prob)| **prob > 0.0)
+            .map(|(i, prob)| (i as u32, *prob))
+            .collect::<Vec<_>>();
+        idx_probs.sort_unstable_by(|(_, a), (_, b)| {
+            (-a.ln() - entropy)
+                .abs()
+                .total_cmp(&(-b.ln() - entropy).abs())
+        });
+        keep_cumulative_mass(probs, &idx_probs, typical_p);
+    }
+
+    fn apply_top_a(probs: &mut [f32], top_a: f32) {
+        if top_a <= 0.0 {
+            return;
+        }
+        // Always keep the most likely token, as the threshold exceeds it if `top_a > 1 / max_p`.
+        let most_likely = argmax_f32(probs) as usize;
+        let max_p = probs[most_likely];
+        let threshold = top_a * max_p * max_p;
+        for (i, prob) in probs.iter_mut().enumerate() {
+            if i != most_likely && *prob < threshold {
+                *prob = 0.0;
+            }
+        }
+    }
+
+    /// XTC removes all tokens above the threshold except the least likely of them, so that
+    /// the most predictable continuations are avoided while keeping a viable one.
+    fn apply_xtc(probs: &mut [f32], params: &XtcSamplingParams, rng: &Mutex<Isaac64Rng>) {
+        let roll: f32 = rng.lock().expect("could not lock rng mutex").random();
+        if roll >= params.probability {
+            return;
+        }
+        let above = probs
+            .iter()
+            .enumerate()
+            .filter(|(_, prob)| **prob > 0.0 && **prob >= params.threshold)
+            .map(|(i, prob)| (i, *prob))
+            .collect::<Vec<_>>();
+        if above.len() < 2 {
+            return;
+        }
+        let least_likely = above
+            .iter()
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(i, _)| *i);
+        for (i, _) in above {
+            if Some(i) != least_likely {
+                probs[i] = 0.0;
+            }
+        }
+    }
+
+    /// Mirostat v2: drop tokens more surprising than `mu`, sample, then move `mu` towards the
+    /// target surprise `tau`.
+    fn sample_mirostat(
+        &self,
+        probs: &mut [f32],
+        mirostat: &MirostatState,
+        return_logprobs: bool,
+        rng: Arc<Mutex<Isaac64Rng>>,
+    ) -> Result<Logprobs> {
+        let mut mu = mirostat.mu.lock().expect("could not lock mirostat mutex");
+        // Always keep the most likely token so there is something to sample.
+        let most_likely = argmax_f32(probs) as usize;
+        for (i, prob) in probs.iter_mut().enumerate() {
+            if i != most_likely && -prob.log2() > *mu {
+                *prob = 0.0;
+            }
+        }
+        renormalize(probs);
+
+        let next_token = self.sample_multinomial(probs, return_logprobs, rng)?;
+        let surprise = -probs[next_token.token as usize].log2();
+        *mu -= mirostat.eta * (surprise - mirostat.tau);
+        Ok(next_token)
+    }
+
     fn apply_penalties(&self, mut logits: Vec<f32>, context: &[u32]) -> Result<Tensor> {
         if context.is_empty() {
             candle_core::bail!("Penalty context is empty, this should not happen.");
@@ -940,6 +1349,12 @@ impl Sampler {
         } else {
             match self.temperature {
                 None => self.sample_argmax(logits, return_logprobs)?,
+                Some(temperature) if self.uses_sampler_chain() => {
+                    let probs = candle_nn::ops::softmax_last_dim(&logits)?;
+                    let mut probs: Vec<f32> = probs.to_vec1()?;
+
+                    self.sample_chain(&mut probs, temperature, return_logprobs, rng)?
+                }
                 Some(temperature) => {
                     let logits = (&logits / temperature)?;
                     let probs = candle_nn::ops::softmax_last_dim(&logits)?;
@@ -981,6 +1396,11 @@ mod tests {
             32,
             0.1,
             0.05,
+            0.0,
+            0.0,
+            None,
+            None,
+            None,
             vec![],
         )
         .unwrap();
@@ -1021,6 +1441,11 @@ mod tests {
             32,
             0.1,
             0.05,
+            0.0,
+            0.0,
+            None,
+            None,
+            None,
             vec![],
         )
         .unwrap();
@@ -1040,4 +1465,64 @@ mod tests {
         assert_eq!(res.top_logprobs, None);
         assert_eq!(res.logprob, 1023f64.log(10.) as f32)
     }
+
+    #[test]
+    fn test_beam_search_score() {
+        use super::BeamSearchParams;
+
+        assert!(BeamSearchParams::new_with_defaults(0, None, None).is_err());
+
+        let params = BeamSearchParams::new_with_defaults(4, None, None).unwrap();
+        assert_eq!(params.score(-6.0, 3), -2.0);
+        assert_eq!(params.score(-6.0, 0), -6.0);
+
+        let params = BeamSearchParams::new_with_defaults(4, Some(0.0), None).unwrap();
+        assert_eq!(params.score(-6.0, 3), -6.0);
+    }
+
+    #[test]
+    fn test_xtc_keeps_least_likely_top_choice() {
+        use super::{Sampler, XtcSamplingParams};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Mutex;
+
+        let params = XtcSamplingParams::new_with_defaults(1.0, Some(0.2)).unwrap();
+        let rng = Mutex::new(Isaac64Rng::seed_from_u64(42));
+        let mut probs = vec![0.5, 0.3, 0.15, 0.05];
+        Sampler::apply_xtc(&mut probs, &params, &rng);
+        assert_eq!(probs, vec![0.0, 0.3, 0.15, 0.05]);
+    }
+
+    #[test]
+    fn test_top_a_keeps_most_likely() {
+        use super::Sampler;
+
+        let mut probs = vec![0.2, 0.5, 0.3];
+        Sampler::apply_top_a(&mut probs, 1.0);
+        assert_eq!(probs, vec![0.0, 0.5, 0.3]);
+        Sampler::apply_top_a(&mut probs, 100.0);
+        assert_eq!(probs, vec![0.0, 0.5, 0.0]);
+    }
+
+    #[test]
+    fn test_sampling_param_ranges() {
+        use super::{MirostatParams, SamplingParams};
+
+        assert!(MirostatParams::new_with_defaults(5.0, Some(-0.1)).is_err());
+
+        let mut params = SamplingParams::deterministic();
+        assert_eq!(params.range_error(), None);
+        params.top_a = Some(1.5);
+        assert!(params.range_error().is_some());
+        params.top_a = Some(1.0);
+        params.typical_p = Some(-0.5);
+        assert!(params.range_error().is_some());
+        params.typical_p = None;
+        params.mirostat_params = Some(MirostatParams {
+            tau: 5.0,
+            eta: -1.0,
+        });
+        assert!(params.range_error().is_some());
+    }
 }
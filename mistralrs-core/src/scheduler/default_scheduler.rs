@@ -10,14 +10,16 @@ use crate::{
     sequence::{Sequence, SequenceState, StopReason},
 };
 
-use super::{Scheduler, SchedulerOutput};
+use super::{can_batch_prefill_chunk, now_ms, waiting_order_key, Scheduler, SchedulerOutput};
 
 pub trait FcfsBacker: Default {
     fn new() -> Self;
     fn add(&mut self, item: Sequence);
     fn into_iter(self) -> impl Iterator<Item = Sequence>;
     fn len(&self) -> usize;
-    fn sort_ascending_ids(&mut self);
+    fn iter_mut(&mut self) -> impl Iterator<Item = &mut Sequence>;
+    /// Order by effective (aged) priority, then by ascending ID.
+    fn sort_by_priority(&mut self);
 }
 
 impl FcfsBacker for VecDeque<Sequence> {
@@ -30,13 +32,17 @@ impl FcfsBacker for VecDeque<Sequence> {
     fn into_iter(self) -> impl Iterator<Item = Sequence> {
         <Self as IntoIterator>::into_iter(self)
     }
-    fn sort_ascending_ids(&mut self) {
+    fn sort_by_priority(&mut self) {
+        let now = now_ms();
         let slice = self.make_contiguous();
-        slice.sort_by_key(|seq| *seq.id());
+        slice.sort_by_key(|seq| waiting_order_key(seq, now));
     }
     fn len(&self) -> usize {
         VecDeque::len(self)
     }
+    fn iter_mut(&mut self) -> impl Iterator<Item = &mut Sequence> {
+        VecDeque::iter_mut(self)
+    }
 }
 
 pub struct DefaultSchedulerOutput<'a> {
@@ -175,10 +181,12 @@ pub struct DefaultScheduler<Backer: FcfsBacker> {
     running: Vec<Sequence>,
     method: DefaultSchedulerMethod,
     bucketing_manager: Box<dyn BucketingManager<Backer>>,
+    max_num_batched_tokens: Option<usize>,
+    chunked_prefill_enabled: bool,
 }
 
 impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
-    pub fn new(method: DefaultSchedulerMethod) -> Self {
+    pub fn new(method: DefaultSchedulerMethod, max_num_batched_tokens: Option<usize>) -> Self {
         let bucketing_manager: Box<dyn BucketingManager<_>> = match method {
             DefaultSchedulerMethod::Fixed(_) => Box::new(FixedBucketingManager),
         };
@@ -187,6 +195,8 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
             waiting: Backer::new(),
             method,
             bucketing_manager,
+            max_num_batched_tokens,
+            chunked_prefill_enabled: true,
         }
     }
 
@@ -204,6 +214,13 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
 
     /// Schedule all sequences based on their state and the available space.
     pub fn schedule(&mut self, logger: &IntervalLogger) -> DefaultSchedulerOutput<'_> {
+        if let Some(budget) = self
+            .max_num_batched_tokens
+            .filter(|_| self.chunked_prefill_enabled)
+        {
+            return self.schedule_chunked(logger, budget);
+        }
+
         // Filter out all done sequences
         let running = std::mem::take(&mut self.running);
         let mut waiting = std::mem::take(&mut self.waiting);
@@ -255,21 +272,11 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
             _ => {}
         }
 
-        // Sort the waiting seqs
-        waiting.sort_ascending_ids();
+        // Sort the waiting seqs so that higher priority (and long-waiting) seqs are admitted first
+        waiting.sort_by_priority();
 
         // If the waiting sequence will fit, add it. Otherwise remove it
-        let mut new_waiting = Backer::new();
-        for seq in waiting.into_iter() {
-            if self.sequence_fits(&running, &seq) {
-                if seq.is_waiting() {
-                    seq.set_state(SequenceState::RunningPrompt);
-                }
-                running.push(seq);
-            } else {
-                new_waiting.add(seq);
-            }
-        }
+        let new_waiting = self.admit_waiting(&mut running, waiting);
 
         let BucketedSeqs {
             running,
@@ -300,13 +307,156 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
         }
     }
 
-    fn sequence_fits(&self, running: &[Sequence], _seq: &Sequence) -> bool {
+    /// Schedule the decode batch together with chunks of the running prompts, so that a long
+    /// prompt does not stall the running decodes. Each decode uses one token of `budget` and
+    /// the rest is spent on prompt chunks of the same offset and length.
+    fn schedule_chunked(
+        &mut self,
+        logger: &IntervalLogger,
+        budget: usize,
+    ) -> DefaultSchedulerOutput<'_> {
+        let running = std::mem::take(&mut self.running);
+        let mut waiting = std::mem::take(&mut self.waiting);
+        let mut running = running
+            .into_iter()
+            .filter(|seq| seq.is_running())
+            .collect::<Vec<_>>();
+
+        waiting.sort_by_priority();
+        let new_waiting = self.admit_waiting(&mut running, waiting);
+
+        if TERMINATE_ALL_NEXT_STEP.load(Ordering::SeqCst) {
+            running
+                .iter_mut()
+                .for_each(|seq| seq.set_state(SequenceState::Done(StopReason::Canceled)));
+            TERMINATE_ALL_NEXT_STEP.store(false, Ordering::SeqCst);
+        }
+
+        let (mut prompts, completions): (Vec<_>, Vec<_>) =
+            running.into_iter().partition(|seq| seq.is_prompt());
+        let BucketedSeqs {
+            running: completions,
+            waiting: new_waiting,
+        } = self.bucketing_manager.bucket_and_waitlist_seqs_waiting(
+            completions,
+            new_waiting,
+            false,
+        );
+        self.waiting = new_waiting;
+
+        let now = now_ms();
+        prompts.sort_by_key(|seq| waiting_order_key(seq, now));
+        let mut budget_left = budget.saturating_sub(completions.len());
+        let mut n_prompts = 0;
+        if !prompts.is_empty() && budget_left > 0 {
+            // Prompts which cannot be chunked are prefilled at once, by themselves.
+            n_prompts = beam_group_len(&prompts, 0);
+            if prompts[0].can_chunk_prefill() {
+                // The beams of a group prefill the same chunks, usually run by the first beam only.
+                let token_offset = prompts[0].token_offset();
+                let n_forward = prompt_forward_len(&prompts, 0, n_prompts);
+                let chunk = prompts[0].set_prefill_chunk((budget_left / n_forward).max(1));
+                for seq in &mut prompts[1..n_prompts] {
+                    seq.set_prefill_chunk(chunk);
+                }
+                budget_left = budget_left.saturating_sub(chunk * n_forward);
+                let mut i = n_prompts;
+                while i < prompts.len() {
+                    let group_len = beam_group_len(&prompts, i);
+                    let n_forward = prompt_forward_len(&prompts, i, group_len);
+                    if budget_left < chunk * n_forward {
+                        break;
+                    }
+                    if prompts[i..i + group_len]
+                        .iter()
+                        .all(|seq| can_batch_prefill_chunk(token_offset, seq, chunk))
+                    {
+                        for j in i..i + group_len {
+                            prompts[j].set_prefill_chunk(chunk);
+                            prompts.swap(n_prompts, j);
+                            n_prompts += 1;
+                        }
+                        budget_left -= chunk * n_forward;
+                    }
+                    i += group_len;
+                }
+            }
+        }
+
+        self.running = completions;
+        let n_completions = self.running.len();
+        self.running.extend(prompts);
+
+        logger.set_num_running(self.running.len());
+        logger.set_num_waiting(self.waiting.len());
+
+        let (completion, prompt) = self.running.split_at_mut(n_completions);
+        DefaultSchedulerOutput {
+            completion: completion.iter_mut().collect::<Vec<_>>().into(),
+            prompt: prompt[..n_prompts].iter_mut().collect::<Vec<_>>().into(),
+        }
+    }
+
+    /// Move the waiting sequences which fit into `running`, returning the others. The beams of a
+    /// beam search are admitted together, as every step ranks the continuations of all of them.
+    fn admit_waiting(&self, running: &mut Vec<Sequence>, waiting: Backer) -> Backer {
+        let mut new_waiting = Backer::new();
+        let mut waiting = waiting.into_iter().peekable();
+        while let Some(seq) = waiting.next() {
+            let mut group = vec![seq];
+            if group[0].beam_search().is_some() {
+                let group_id = group[0].group_id();
+                while let Some(seq) = waiting.next_if(|seq| seq.group_id() == group_id) {
+                    group.push(seq);
+                }
+            }
+            // A group larger than the limit still runs by itself.
+            if running.is_empty() || self.sequences_fit(running, group.len()) {
+                for seq in group {
+                    if seq.is_waiting() {
+                        seq.set_state(SequenceState::RunningPrompt);
+                    }
+                    running.push(seq);
+                }
+            } else {
+                for seq in group {
+                    new_waiting.add(seq);
+                }
+            }
+        }
+        new_waiting
+    }
+
+    fn sequences_fit(&self, running: &[Sequence], n: usize) -> bool {
         match &self.method {
-            DefaultSchedulerMethod::Fixed(n) => (running.len() + 1) <= (*n).into(),
+            DefaultSchedulerMethod::Fixed(max) => (running.len() + n) <= (*max).into(),
         }
     }
 }
 
+/// Number of sequences of the `group_len` beams from `seqs[i]` which run their prompt chunks. The
+/// beams of a group share the prompt of the first one, unless their prompt logprobs are scored.
+fn prompt_forward_len(seqs: &[Sequence], i: usize, group_len: usize) -> usize {
+    if seqs[i].wants_prompt_logprobs() {
+        group_len
+    } else {
+        1
+    }
+}
+
+/// Number of sequences from `seqs[i]` which are beams of the same beam search. They are adjacent
+/// when ordered by [`waiting_order_key`], and are scheduled together.
+fn beam_group_len(seqs: &[Sequence], i: usize) -> usize {
+    if seqs[i].beam_search().is_none() {
+        return 1;
+    }
+    let group_id = seqs[i].group_id();
+    seqs[i..]
+        .iter()
+        .take_while(|seq| seq.group_id() == group_id)
+        .count()
+}
+
 impl Scheduler for DefaultScheduler<VecDeque<Sequence>> {
     fn schedule(&mut self, logger: &IntervalLogger) -> SchedulerOutput<'_> {
         SchedulerOutput::DefaultScheduler {
@@ -350,4 +500,97 @@ impl Scheduler for DefaultScheduler<VecDeque<Sequence>> {
     fn set_prefix_caching_enabled(&mut self, _enabled: bool) {
         // DefaultScheduler doesn't use PagedAttention prefix caching
     }
+    fn set_chunked_prefill_enabled(&mut self, enabled: bool) {
+        self.chunked_prefill_enabled = enabled;
+    }
+    fn set_ragged_completions_enabled(&mut self, _enabled: bool) {
+        // The batched normal KV cache has a single length, so completions are always bucketed.
+    }
+    fn cancel_request(&mut self, request_id: usize) {
+        self.waiting
+            .iter_mut()
+            .chain(self.running.iter_mut())
+            .filter(|seq| seq.request_id() == request_id)
+            .for_each(|seq| seq.request_cancel());
+    }
+    fn take_stopped_sequences(&mut self) -> Vec<(Sequence, StopReason)> {
+        let mut stopped = Vec::new();
+        // Bucketing waitlists running sequences, so any waiting sequence may be taken.
+        for seq in std::mem::take(&mut self.waiting).into_iter() {
+            match seq.unscheduled_stop_reason() {
+                Some(reason) => stopped.push((seq, reason)),
+                None => self.waiting.add(seq),
+            }
+        }
+        for seq in std::mem::take(&mut self.running) {
+            match seq.unscheduled_stop_reason().filter(|_| seq.is_prompt()) {
+                Some(reason) => stopped.push((seq, reason)),
+                None => self.running.push(seq),
+            }
+        }
+        stopped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, num::NonZeroUsize, time::Duration};
+
+    use super::{DefaultScheduler, DefaultSchedulerMethod};
+    use crate::{
+        engine::IntervalLogger,
+        scheduler::{now_ms, test_utils::test_sequence, Scheduler},
+        sequence::{Sequence, SequenceState},
+    };
+
+    fn scheduler(budget: usize) -> DefaultScheduler<VecDeque<Sequence>> {
+        DefaultScheduler::new(
+            DefaultSchedulerMethod::Fixed(NonZeroUsize::new(8).unwrap()),
+            Some(budget),
+        )
+    }
+
+    fn ids(scheduled: &[&mut Sequence]) -> Vec<usize> {
+        scheduled.iter().map(|seq| *seq.id()).collect()
+    }
+
+    #[test]
+    fn test_chunked_prefill_splits_budget_between_decodes_and_prompts() {
+        let logger = IntervalLogger::new(Duration::from_secs(3600));
+        let mut scheduler = scheduler(10);
+        let decoding = test_sequence(0, now_ms(), 4, None);
+        decoding.set_state(SequenceState::RunningCompletion);
+        scheduler.add_seq(decoding);
+        for id in 1..4 {
+            scheduler.add_seq(test_sequence(id, now_ms(), 4, None));
+        }
+
+        // The decode takes one token, leaving room for two prompts of four tokens.
+        let output = scheduler.schedule(&logger);
+        assert_eq!(ids(&output.completion), vec![0]);
+        assert_eq!(ids(&output.prompt), vec![1, 2]);
+        assert!(output
+            .prompt
+            .iter()
+            .all(|seq| seq.get_toks().len() == 4 && !seq.is_partial_prefill()));
+        assert_eq!(scheduler.running_len(), 4);
+    }
+
+    #[test]
+    fn test_chunked_prefill_continues_across_steps() {
+        let logger = IntervalLogger::new(Duration::from_secs(3600));
+        let mut scheduler = scheduler(4);
+        scheduler.add_seq(test_sequence(0, now_ms(), 10, None));
+
+        for (offset, chunk) in [(0, 0..4), (4, 4..8), (8, 8..10)] {
+            let mut output = scheduler.schedule(&logger);
+            assert_eq!(ids(&output.prompt), vec![0]);
+            let seq = &mut output.prompt[0];
+            assert_eq!(seq.token_offset(), offset);
+            assert_eq!(seq.get_toks(), chunk.collect::<Vec<u32>>());
+            assert_eq!(seq.is_partial_prefill(), offset < 8);
+            // As done by the engine once the chunk is prefilled.
+            seq.advance_prefill_chunk();
+        }
+    }
 }
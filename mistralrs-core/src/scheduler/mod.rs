@@ -1,6 +1,10 @@
 mod default_scheduler;
 
-use std::sync::Arc;
+use std::{
+    cmp::Reverse,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 pub use default_scheduler::{DefaultScheduler, DefaultSchedulerMethod, DefaultSchedulerOutput};
 use tokio::sync::Mutex;
@@ -11,37 +15,82 @@ use crate::{
         BlockEngine, BlockTables, CacheConfig, PagedAttentionScheduler,
         PagedAttentionSchedulerConfig, PagedAttentionSchedulerOutput,
     },
-    sequence::Sequence,
+    sequence::{Sequence, StopReason},
 };
 
+/// Waiting sequences are promoted by one priority class for every interval since they arrived,
+/// so that low-priority requests are still scheduled under a steady stream of high-priority ones.
+pub(crate) const PRIORITY_AGING_INTERVAL_MS: u128 = 30_000;
+
+/// Ordering key for waiting sequences: highest effective priority first, then arrival order.
+pub(crate) fn waiting_order_key(seq: &Sequence, now_ms: u128) -> (Reverse<usize>, usize) {
+    (
+        Reverse(seq.effective_priority(now_ms, PRIORITY_AGING_INTERVAL_MS)),
+        *seq.id(),
+    )
+}
+
+/// Current time in milliseconds since the epoch, comparable with [`Sequence::timestamp`].
+pub(crate) fn now_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time travel has occurred!")
+        .as_millis()
+}
+
+/// `max_num_batched_tokens` is the token budget of one engine step. When set, long prompts are
+/// prefilled in chunks which are interleaved with the decode batch: every running decode uses one
+/// token of the budget and the rest goes to prompt chunks. `None` prefills each prompt at once.
 #[derive(Clone)]
 pub enum SchedulerConfig {
     DefaultScheduler {
         method: DefaultSchedulerMethod,
+        max_num_batched_tokens: Option<usize>,
     },
     PagedAttentionMeta {
         max_num_seqs: usize,
         config: CacheConfig,
+        max_num_batched_tokens: Option<usize>,
     },
 }
 
 impl SchedulerConfig {
     pub fn into_scheduler(self) -> Arc<Mutex<dyn Scheduler>> {
         match self {
-            Self::DefaultScheduler { method } => {
-                Arc::new(Mutex::new(DefaultScheduler::new(method)))
-            }
+            Self::DefaultScheduler {
+                method,
+                max_num_batched_tokens,
+            } => Arc::new(Mutex::new(DefaultScheduler::new(
+                method,
+                max_num_batched_tokens,
+            ))),
             Self::PagedAttentionMeta {
                 max_num_seqs,
                 config,
+                max_num_batched_tokens,
             } => Arc::new(Mutex::new(PagedAttentionScheduler::new(
-                PagedAttentionSchedulerConfig { max_num_seqs },
+                PagedAttentionSchedulerConfig {
+                    max_num_seqs,
+                    max_num_batched_tokens,
+                },
                 config,
             ))),
         }
     }
 }
 
+/// Whether `candidate` can join a batch of prompt chunks of `chunk` tokens at `token_offset`.
+/// Batched chunks share an offset and a length so that the cache of every sequence stays aligned.
+pub(crate) fn can_batch_prefill_chunk(
+    token_offset: usize,
+    candidate: &Sequence,
+    chunk: usize,
+) -> bool {
+    candidate.token_offset() == token_offset
+        && candidate.prefill_remaining() >= chunk
+        && candidate.can_chunk_prefill()
+}
+
 pub enum SchedulerOutput<'a> {
     DefaultScheduler {
         output: DefaultSchedulerOutput<'a>,
@@ -70,4 +119,103 @@ pub trait Scheduler: Send + Sync {
     /// Set whether prefix caching is enabled. Called by Engine after creation
     /// to synchronize with the global no_prefix_cache setting.
     fn set_prefix_caching_enabled(&mut self, enabled: bool);
+
+    /// Cancel the waiting and running sequences of a request. They are finished on the next step.
+    fn cancel_request(&mut self, request_id: usize);
+
+    /// Remove the waiting, swapped out and partially prefilled sequences which should be
+    /// finished, see [`Sequence::unscheduled_stop_reason`]. The engine sends their responses.
+    fn take_stopped_sequences(&mut self) -> Vec<(Sequence, StopReason)>;
+
+    /// Set whether prompts may be prefilled in chunks. Called by Engine after creation, as not
+    /// every pipeline can resume a partially prefilled prompt.
+    fn set_chunked_prefill_enabled(&mut self, enabled: bool);
+
+    /// Set whether running completions of different lengths may be scheduled together. Called by
+    /// Engine after creation, for pipelines which give each sequence its own offset when decoding.
+    fn set_ragged_completions_enabled(&mut self, enabled: bool);
+}
+
+#[cfg(test)]
+pub(crate) mod test_utils {
+    use std::sync::Arc;
+
+    use crate::{
+        sampler::Sampler,
+        sequence::{SeqStepType, Sequence, SequenceGroup, SequenceRecognizer},
+    };
+
+    /// A waiting text sequence with a prompt of `prompt_len` tokens, created at `timestamp`.
+    /// `block_size` is set for the PagedAttention scheduler.
+    pub(crate) fn test_sequence(
+        id: usize,
+        timestamp: u128,
+        prompt_len: usize,
+        block_size: Option<usize>,
+    ) -> Sequence {
+        let sampler = Sampler::new(
+            None,
+            0,
+            None,
+            None,
+            None,
+            None,
+            None,
+            -1,
+            1.0,
+            0.0,
+            0.0,
+            0.0,
+            None,
+            None,
+            None,
+            vec![],
+        )
+        .unwrap();
+        test_sequence_with_sampler(id, timestamp, prompt_len, block_size, sampler)
+    }
+
+    /// [`test_sequence`], sampling with `sampler`.
+    pub(crate) fn test_sequence_with_sampler(
+        id: usize,
+        timestamp: u128,
+        prompt_len: usize,
+        block_size: Option<usize>,
+        sampler: Sampler,
+    ) -> Sequence {
+        let (responder, _) = tokio::sync::mpsc::channel(1);
+        let group = Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
+            1, false, true, None,
+        )));
+        Sequence::new_waiting(
+            (0..prompt_len as u32).collect(),
+            String::new(),
+            id,
+            timestamp,
+            1,
+            responder,
+            sampler,
+            vec![],
+            vec![],
+            None,
+            false,
+            false,
+            group,
+            0,
+            0,
+            SequenceRecognizer::None,
+            None,
+            None,
+            None,
+            None,
+            block_size,
+            None,
+            None,
+            SeqStepType::PromptAndDecode,
+            None,
+            None,
+            false,
+            vec![],
+        )
+    }
 }
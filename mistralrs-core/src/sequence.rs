@@ -3,8 +3,9 @@ use crate::{
     harmony::HarmonyContext,
     paged_attention::BlockRef,
     pipeline::{text_models_inputs_processor::PagedAttentionMeta, LayerCaches},
+    request::RequestPriority,
     response::{ChatCompletionChunkResponse, Choice, ChunkChoice, Response, SYSTEM_FINGERPRINT},
-    sampler::{Logprobs, Sampler},
+    sampler::{BeamSearchParams, Logprobs, Sampler},
     think_tags::ThinkTagContext,
     AudioInput, ChatCompletionResponse, Usage,
 };
@@ -12,16 +13,18 @@ use crate::{
     paged_attention::{BlockEngineSequence, LogicalTokenBlock},
     pipeline::{DiffusionGenerationParams, KvCache},
     response::CompletionChoice,
-    tools::ToolCallingMatcher,
+    tools::{ToolCallStream, ToolCallingMatcher},
     CompletionChunkChoice, CompletionChunkResponse, CompletionResponse, ImageChoice,
     ImageGenerationResponse, ImageGenerationResponseFormat,
 };
 use candle_core::Tensor;
+use rand::SeedableRng;
+use rand_isaac::Isaac64Rng;
 use std::{
     fmt::Display,
     hash::{DefaultHasher, Hash, Hasher},
     sync::{Arc, RwLock},
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use tokio::sync::{
     mpsc::{error::SendError, Sender},
@@ -39,6 +42,7 @@ pub enum StopReason {
         completion_bytes_pos: usize,
     },
     Canceled,
+    Timeout,
     GeneratedImage,
     GeneratedSpeech,
     ToolCalls,
@@ -51,6 +55,7 @@ impl Display for StopReason {
             StopReason::Length(_) | StopReason::ModelLength(_) => write!(f, "length"),
             StopReason::StopTok(_) | StopReason::StopString { .. } => write!(f, "stop"),
             StopReason::Canceled => write!(f, "canceled"),
+            StopReason::Timeout => write!(f, "timeout"),
             StopReason::GeneratedImage => write!(f, "generated_image"),
             StopReason::GeneratedSpeech => write!(f, "generated_speech"),
             StopReason::ToolCalls => write!(f, "tool_calls"),
@@ -149,6 +154,13 @@ impl SequenceCustomMetadata {
                 physical_blocks_prefill: _,
                 block_size: _,
             } => {
+                // The block pushed once the previous one became full holds no token yet.
+                if logical_token_blocks
+                    .last()
+                    .is_some_and(|last| last.is_empty())
+                {
+                    logical_token_blocks.pop();
+                }
                 let last = logical_token_blocks.last_mut().unwrap();
                 last.pop_token();
             }
@@ -414,6 +426,9 @@ pub struct Sequence {
 
     // Prefix caching
     prefill_prompt_toks: Option<Vec<u32>>,
+    /// Token offset the prompt had before chunked prefill or speculative verification moved it,
+    /// restored once the prefill tokens are reset.
+    chunked_prefill_offset: Option<usize>,
     /// Number of tokens at the start of the prompt that are cached (KV already computed).
     /// These tokens should be skipped during prefill.
     prefix_cache_len: usize,
@@ -443,6 +458,14 @@ pub struct Sequence {
     pub recognizer: SequenceRecognizer,
     scheduling_urgency: usize, // The number of passes since scheduling
     waitlisted_count: usize, // Used in PagedAttention to alert the user when a sequence repeatedly cannot be scheduled
+    priority: RequestPriority,
+    deadline: Option<Instant>, // Sequence is finished with `StopReason::Timeout` after this
+    last_token_time: Option<Instant>, // When the last token was generated, for the inter-token latency
+    request_id: usize,
+    cancel_requested: bool, // Sequence is finished with `StopReason::Canceled` on the next step
+    beam_search: Option<BeamSearchParams>,
+    prompt_logprobs_top_n: Option<usize>,
+    prompt_logprobs: Vec<Logprobs>, // Logprobs of the prompt tokens after the first
 
     // GPU things
     pub prompt_tok_per_sec: f32,
@@ -456,12 +479,20 @@ pub struct Sequence {
 
     // Tool calls
     pub tools: Option<Arc<ToolCallingMatcher>>,
+    tool_call_stream: ToolCallStream,
 
     // Harmony format parsing context (for GPT-OSS models)
     harmony_context: Option<HarmonyContext>,
 
     // Think tag parsing context (for models using <think>...</think> tags)
     think_tag_context: Option<ThinkTagContext>,
+
+    // Per-sequence RNG, used instead of the engine RNG when the request specifies a seed
+    rng: Option<Arc<std::sync::Mutex<Isaac64Rng>>>,
+
+    // Tracing
+    span: tracing::Span, // Span of the request this sequence belongs to
+    queue_span: Option<tracing::Span>, // Open until the prompt is first run
 }
 
 impl BlockEngineSequence for Sequence {
@@ -603,6 +634,7 @@ impl Sequence {
             creation_time,
             recognizer,
             prefill_prompt_toks: None,
+            chunked_prefill_offset: None,
             prefix_cache_len: 0,
             suffix,
             prefix,
@@ -623,14 +655,26 @@ impl Sequence {
             ),
             custom_metadata,
             tools,
+            tool_call_stream: ToolCallStream::default(),
             sequence_stepping_type,
             return_raw_logits,
             token_offset: 0,
             eos_tokens,
             total_prompt_time: None,
             waitlisted_count: 0,
+            priority: RequestPriority::default(),
+            deadline: None,
+            last_token_time: None,
+            request_id: 0,
+            cancel_requested: false,
+            beam_search: None,
+            prompt_logprobs_top_n: None,
+            prompt_logprobs: Vec::new(),
             harmony_context: None,
             think_tag_context: None,
+            rng: None,
+            span: tracing::Span::none(),
+            queue_span: None,
         }
     }
 
@@ -769,6 +813,59 @@ impl Sequence {
         self.token_offset
     }
 
+    /// Whether the prompt of this sequence may be prefilled over several steps.
+    pub(crate) fn can_chunk_prefill(&self) -> bool {
+        !self.has_images()
+            && !self.has_audios()
+            && !self.return_raw_logits
+            && self.mamba_state_idx.is_none()
+            && matches!(self.sequence_stepping_type, SeqStepType::PromptAndDecode)
+    }
+
+    /// Number of prompt tokens whose KV cache has not been computed yet.
+    pub(crate) fn prefill_remaining(&self) -> usize {
+        self.tokens.len().saturating_sub(self.token_offset)
+    }
+
+    /// Prefill at most `max_toks` of the remaining prompt tokens in the next step, returning the
+    /// size of the chunk.
+    pub(crate) fn set_prefill_chunk(&mut self, max_toks: usize) -> usize {
+        self.chunked_prefill_offset.get_or_insert(self.token_offset);
+        let end = self.tokens.len().min(self.token_offset + max_toks);
+        self.prefill_prompt_toks = Some(self.tokens[self.token_offset..end].to_vec());
+        end - self.token_offset
+    }
+
+    /// Whether the prompt chunk of this step leaves part of the prompt to prefill. No token is
+    /// sampled for such a sequence.
+    pub(crate) fn is_partial_prefill(&self) -> bool {
+        self.is_prompt()
+            && self
+                .prefill_prompt_toks
+                .as_ref()
+                .is_some_and(|toks| self.token_offset + toks.len() < self.tokens.len())
+    }
+
+    /// Move past the prompt chunk which was just prefilled.
+    pub(crate) fn advance_prefill_chunk(&mut self) {
+        if let Some(toks) = self.prefill_prompt_toks.take() {
+            self.token_offset += toks.len();
+        }
+    }
+
+    /// Drop the progress of a chunked prefill, for when the KV cache of the sequence is freed.
+    pub(crate) fn reset_chunked_prefill(&mut self) {
+        if self.chunked_prefill_offset.take().is_some() {
+            self.token_offset = 0;
+            self.prefill_prompt_toks = None;
+        }
+    }
+
+    /// All tokens of this sequence, ignoring the prompt chunk set for the current step.
+    pub(crate) fn full_toks(&self) -> &[u32] {
+        &self.tokens
+    }
+
     /// Get the number of prefix tokens that are cached (KV already computed).
     /// These tokens should be skipped during prefill.
     pub fn prefix_cache_len(&self) -> usize {
@@ -857,14 +954,37 @@ impl Sequence {
         self.sampler.clone()
     }
 
+    /// Give this sequence its own RNG seeded with `seed`.
+    pub fn set_rng_seed(&mut self, seed: u64) {
+        self.rng = Some(Arc::new(std::sync::Mutex::new(Isaac64Rng::seed_from_u64(
+            seed,
+        ))));
+    }
+
+    /// The per-sequence RNG, if this sequence was seeded.
+    pub fn rng(&self) -> Option<Arc<std::sync::Mutex<Isaac64Rng>>> {
+        self.rng.clone()
+    }
+
     /// Add a some prefill tokens. Only meant for internal speculative decoding usage.
     pub fn set_prefill_toks(&mut self, toks: Vec<u32>) {
         self.prefill_prompt_toks = Some(toks)
     }
 
+    /// Add some prefill tokens which continue the KV cache from `offset`. Only meant for internal
+    /// speculative decoding usage, [`Self::reset_prefill_toks`] restores the token offset.
+    pub(crate) fn set_prefill_toks_at(&mut self, toks: Vec<u32>, offset: usize) {
+        self.chunked_prefill_offset.get_or_insert(self.token_offset);
+        self.token_offset = offset;
+        self.prefill_prompt_toks = Some(toks);
+    }
+
     /// Remove the prefill tokens.
     pub fn reset_prefill_toks(&mut self) {
-        self.prefill_prompt_toks = None
+        self.prefill_prompt_toks = None;
+        if let Some(offset) = self.chunked_prefill_offset.take() {
+            self.token_offset = offset;
+        }
     }
 
     /// Internal api to add one raw token.
@@ -957,11 +1077,15 @@ impl Sequence {
         };
         if is_eos {
             Some(StopReason::Eos)
-        } else if matches!(
-            &*self.state.read().unwrap(),
-            SequenceState::Done(StopReason::Canceled)
-        ) {
+        } else if self.cancel_requested
+            || matches!(
+                &*self.state.read().unwrap(),
+                SequenceState::Done(StopReason::Canceled)
+            )
+        {
             Some(StopReason::Canceled)
+        } else if self.is_past_deadline() {
+            Some(StopReason::Timeout)
         } else if self.stop_tokens.contains(&tok) {
             Some(StopReason::StopTok(tok))
         } else if self.max_len.is_some()
@@ -991,6 +1115,10 @@ impl Sequence {
         &self.logprobs
     }
 
+    pub fn cumulative_logprob(&self) -> f32 {
+        self.cumulative_logprob
+    }
+
     pub fn return_logprobs(&self) -> bool {
         self.return_logprobs
     }
@@ -999,6 +1127,11 @@ impl Sequence {
         self.prompt_len
     }
 
+    /// The prompt echoed before the completion text, if any.
+    pub fn echoed_prompt(&self) -> Option<&str> {
+        self.prefix.as_deref()
+    }
+
     pub fn stop_strings(&self) -> &[String] {
         &self.stop_strings
     }
@@ -1036,10 +1169,175 @@ impl Sequence {
         self.timestamp
     }
 
+    pub fn priority(&self) -> RequestPriority {
+        self.priority
+    }
+
+    pub fn set_priority(&mut self, priority: RequestPriority) {
+        self.priority = priority;
+    }
+
+    /// Finish this sequence with `StopReason::Timeout` once `deadline` has passed.
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline);
+    }
+
+    pub fn is_past_deadline(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Why this sequence should be finished while it is not decoding: while it waits, is swapped
+    /// out or is between prefill chunks. Decoding sequences are finished by [`Self::is_done`].
+    pub fn unscheduled_stop_reason(&self) -> Option<StopReason> {
+        if self.is_finished_paged_attn() {
+            None
+        } else if self.cancel_requested {
+            Some(StopReason::Canceled)
+        } else {
+            self.is_past_deadline().then_some(StopReason::Timeout)
+        }
+    }
+
+    /// ID of the request this sequence belongs to.
+    pub fn request_id(&self) -> usize {
+        self.request_id
+    }
+
+    pub fn set_request_id(&mut self, request_id: usize) {
+        self.request_id = request_id;
+    }
+
+    /// Trace this sequence as part of the request `span`. It is queued until its prompt is first run.
+    pub fn set_span(&mut self, span: tracing::Span) {
+        self.queue_span = Some(tracing::info_span!(parent: &span, "queue"));
+        self.span = span;
+    }
+
+    /// Span of the request this sequence belongs to.
+    pub fn span(&self) -> &tracing::Span {
+        &self.span
+    }
+
+    /// Span of a prefill step of this sequence, ending its time in the queue.
+    pub fn prefill_span(&mut self) -> tracing::Span {
+        self.queue_span = None;
+        tracing::info_span!(
+            parent: &self.span,
+            "prefill",
+            seq_id = self.id,
+            num_tokens = self.get_toks().len(),
+            offset = self.token_offset,
+        )
+    }
+
+    /// Finish this sequence with `StopReason::Canceled` on the next step.
+    pub fn request_cancel(&mut self) {
+        self.cancel_requested = true;
+    }
+
+    /// Decode this sequence as one beam of a beam search.
+    pub fn set_beam_search(&mut self, beam_search: BeamSearchParams) {
+        self.beam_search = Some(beam_search);
+    }
+
+    pub fn beam_search(&self) -> Option<&BeamSearchParams> {
+        self.beam_search.as_ref()
+    }
+
+    /// Length-normalized beam search score, if this sequence is a beam.
+    pub fn beam_score(&self) -> Option<f32> {
+        let params = self.beam_search.as_ref()?;
+        Some(params.score(self.cumulative_logprob, self.logprobs.len()))
+    }
+
+    /// Snapshot of the generated tokens of this beam, without its KV cache.
+    pub(crate) fn beam_state(&self) -> BeamState {
+        BeamState {
+            tokens: self.tokens.clone(),
+            logprobs: self.logprobs.clone(),
+            cumulative_logprob: self.cumulative_logprob,
+            last_logprob: self.last_logprob,
+            last_completion_bytes_len: self.last_completion_bytes_len,
+            last_is_done: self.last_is_done,
+            completion_bytes: self.completion_bytes.clone(),
+            stream_idx: self.stream_idx,
+            think_tag_context: self.think_tag_context.clone(),
+        }
+    }
+
+    /// Continue this beam from a snapshot taken by [`Self::beam_state`]. The KV cache is kept.
+    pub(crate) fn restore_beam_state(&mut self, state: BeamState) {
+        self.tokens = state.tokens;
+        self.logprobs = state.logprobs;
+        self.cumulative_logprob = state.cumulative_logprob;
+        self.last_logprob = state.last_logprob;
+        self.last_completion_bytes_len = state.last_completion_bytes_len;
+        self.last_is_done = state.last_is_done;
+        self.completion_bytes = state.completion_bytes;
+        self.stream_idx = state.stream_idx;
+        self.think_tag_context = state.think_tag_context;
+    }
+
+    /// Continue this beam from `parent`: copy its generated tokens and KV cache.
+    pub(crate) fn copy_beam_state_from(&mut self, parent: &Sequence) {
+        self.restore_beam_state(parent.beam_state());
+        self.normal_cache = parent.normal_cache.clone();
+        self.normal_draft_cache = parent.normal_draft_cache.clone();
+        self.scaling_cache = parent.scaling_cache.clone();
+        self.cache = parent.cache.clone();
+        self.draft_cache = parent.draft_cache.clone();
+        self.xlora_cache = parent.xlora_cache.clone();
+    }
+
+    /// Score every prompt token, returning the top `top_n` alternatives of each.
+    pub fn set_prompt_logprobs(&mut self, top_n: usize) {
+        self.prompt_logprobs_top_n = Some(top_n);
+    }
+
+    pub fn prompt_logprobs_top_n(&self) -> Option<usize> {
+        self.prompt_logprobs_top_n
+    }
+
+    /// Whether some prompt tokens of this sequence still need to be scored.
+    pub(crate) fn wants_prompt_logprobs(&self) -> bool {
+        self.prompt_logprobs_top_n.is_some() && self.prompt_logprobs.len() + 1 < self.prompt_len
+    }
+
+    pub(crate) fn add_prompt_logprobs(&mut self, logprobs: Vec<Logprobs>) {
+        self.prompt_logprobs.extend(logprobs);
+    }
+
+    /// Logprobs of the prompt tokens after the first, if they were requested.
+    pub fn prompt_logprobs(&self) -> Option<&[Logprobs]> {
+        self.prompt_logprobs_top_n
+            .map(|_| self.prompt_logprobs.as_slice())
+    }
+
+    /// Identifies the group of this sequence; beams of one request share a group.
+    pub(crate) fn group_id(&self) -> usize {
+        Arc::as_ptr(&self.group) as usize
+    }
+
+    /// Priority class raised by one level for every `aging_interval_ms` since this sequence was
+    /// created, so that low-priority sequences cannot be starved by higher-priority ones.
+    pub fn effective_priority(&self, now_ms: u128, aging_interval_ms: u128) -> usize {
+        let aged_levels = now_ms.saturating_sub(self.timestamp) / aging_interval_ms.max(1);
+        self.priority as usize + aged_levels as usize
+    }
+
     pub fn prompt_timestamp(&self) -> Option<u128> {
         self.prompt_timestamp
     }
 
+    /// Mark a token as generated now. Returns the time since the previous token, if any.
+    pub fn mark_token_generated(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        self.last_token_time
+            .replace(now)
+            .map(|last| now.duration_since(last))
+    }
+
     fn update_time_info(&self) {
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -1066,7 +1364,11 @@ impl Sequence {
     }
 
     pub fn add_choice_to_group(&self, choice: Choice) {
-        get_mut_group!(self).choices.push(choice);
+        let score = self.beam_score().unwrap_or(self.cumulative_logprob);
+        let mut group = get_mut_group!(self);
+        group.choices.push(choice);
+        group.choice_scores.push(score);
+        drop(group);
         self.update_time_info();
     }
 
@@ -1091,7 +1393,7 @@ impl Sequence {
         );
         get_mut_group!(self)
             .completion_choices
-            .push((self.cumulative_logprob, choice));
+            .push((self.beam_score().unwrap_or(self.cumulative_logprob), choice));
         self.update_time_info();
     }
 
@@ -1257,6 +1559,11 @@ impl Sequence {
             .unwrap_or_default()
     }
 
+    /// The tool calls sent so far when streaming.
+    pub(crate) fn tool_call_stream_mut(&mut self) -> &mut ToolCallStream {
+        &mut self.tool_call_stream
+    }
+
     // === Think Tag Format Support ===
 
     /// Enable think tag parsing for this sequence.
@@ -1329,15 +1636,39 @@ impl Sequence {
     }
 }
 
+/// The generated tokens of a beam, see [`Sequence::beam_state`].
+#[derive(Clone)]
+pub(crate) struct BeamState {
+    tokens: Vec<u32>,
+    logprobs: Vec<Logprobs>,
+    cumulative_logprob: f32,
+    last_logprob: f32,
+    last_completion_bytes_len: usize,
+    last_is_done: Option<StopReason>,
+    completion_bytes: Vec<u8>,
+    stream_idx: usize,
+    think_tag_context: Option<ThinkTagContext>,
+}
+
+/// A beam search hypothesis which ends with `logprobs`, the token finishing it.
+pub(crate) struct FinishedBeam {
+    pub(crate) score: f32,
+    pub(crate) state: BeamState,
+    pub(crate) logprobs: Logprobs,
+}
+
 pub struct SequenceGroup {
     n_choices: usize, // The target number of choices to return. Can be decreased if an error is thrown.
     best_of: Option<usize>, // Top n seqs based on cumulative logprobs.
+    best_beams: Option<usize>, // Top n beam search hypotheses based on beam scores.
+    finished_beams: Vec<FinishedBeam>, // Best finished hypotheses, while the beams search on.
     pub total_prompt_toks: usize,
     pub total_toks: usize,
     pub total_prompt_time: u128,
     pub total_time: u128,
     pub total_completion_time: u128,
     choices: Vec<Choice>,
+    choice_scores: Vec<f32>,
     image_choices: Vec<ImageChoice>,
     speech_pcms: Vec<(Arc<Vec<f32>>, usize, usize)>, // (pcm, rate, channels)
     raw_choices: Vec<(Vec<Tensor>, Vec<u32>)>,
@@ -1358,6 +1689,7 @@ impl SequenceGroup {
     ) -> Self {
         Self {
             choices: Vec::new(),
+            choice_scores: Vec::new(),
             image_choices: Vec::new(),
             speech_pcms: Vec::new(),
             raw_choices: Vec::new(),
@@ -1374,16 +1706,61 @@ impl SequenceGroup {
             is_streaming,
             is_chat,
             best_of,
+            best_beams: None,
+            finished_beams: Vec::new(),
         }
     }
 
-    pub fn get_choices(&self) -> &[Choice] {
-        &self.choices
+    /// Only return the `n` best scoring hypotheses of this beam search group.
+    pub fn set_best_beams(&mut self, n: usize) {
+        self.best_beams = Some(n);
+    }
+
+    pub fn best_beams(&self) -> Option<usize> {
+        self.best_beams
     }
 
-    /// This may apply the best_of.
+    /// The finished beam search hypotheses, best first. They are turned into choices when the
+    /// search ends.
+    pub(crate) fn finished_beams_mut(&mut self) -> &mut Vec<FinishedBeam> {
+        &mut self.finished_beams
+    }
+
+    /// This may apply the best_beams.
+    pub fn get_choices(&self) -> Vec<Choice> {
+        if let Some(best_beams) = self.best_beams {
+            let mut choices = self
+                .choice_scores
+                .iter()
+                .copied()
+                .zip(self.choices.iter().cloned())
+                .collect::<Vec<_>>();
+            // Sort by descending beam score
+            choices.sort_by(|a, b| b.0.partial_cmp(&a.0).expect("No ordering."));
+            choices
+                .into_iter()
+                .take(best_beams)
+                .enumerate()
+                .map(|(index, (_, choice))| Choice { index, ..choice })
+                .collect::<Vec<_>>()
+        } else {
+            self.choices.clone()
+        }
+    }
+
+    /// This may apply the best_of or best_beams.
     pub fn get_completion_choices(&self) -> Vec<CompletionChoice> {
-        if let Some(best_of) = self.best_of {
+        if let Some(best_beams) = self.best_beams {
+            let mut choices = self.completion_choices.clone();
+            // Sort by descending beam score
+            choices.sort_by(|a, b| b.0.partial_cmp(&a.0).expect("No ordering."));
+            choices
+                .into_iter()
+                .take(best_beams)
+                .enumerate()
+                .map(|(index, (_, choice))| CompletionChoice { index, ..choice })
+                .collect::<Vec<_>>()
+        } else if let Some(best_of) = self.best_of {
             let mut choices = self.completion_choices.clone();
             // Sort by descending logprobs
             choices.sort_by(|a, b| b.0.partial_cmp(&a.0).expect("No ordering."));
@@ -0,0 +1,105 @@
+//! Tracing spans of the request lifecycle. With the `otel` feature they are exported over OTLP when
+//! `OTEL_EXPORTER_OTLP_ENDPOINT` (or `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT`) is set.
+
+use tracing::{field, info_span, Span};
+
+/// Name of the W3C trace context header.
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+/// The W3C `traceparent` of the current span, which can be propagated to the engine through
+/// `NormalRequest::traceparent`. This is `None` when spans are not exported.
+pub fn current_traceparent() -> Option<String> {
+    #[cfg(feature = "otel")]
+    {
+        use opentelemetry::propagation::TextMapPropagator;
+        use opentelemetry_sdk::propagation::TraceContextPropagator;
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        let mut carrier = std::collections::HashMap::new();
+        TraceContextPropagator::new().inject_context(&Span::current().context(), &mut carrier);
+        carrier.remove(TRACEPARENT_HEADER)
+    }
+    #[cfg(not(feature = "otel"))]
+    None
+}
+
+/// Make `span` a child of the remote span described by a W3C `traceparent`.
+pub fn set_remote_parent(span: &Span, traceparent: Option<&str>) {
+    #[cfg(feature = "otel")]
+    if let Some(traceparent) = traceparent {
+        use opentelemetry::propagation::TextMapPropagator;
+        use opentelemetry_sdk::propagation::TraceContextPropagator;
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        let carrier = std::collections::HashMap::from([(
+            TRACEPARENT_HEADER.to_string(),
+            traceparent.to_string(),
+        )]);
+        let _ = span.set_parent(TraceContextPropagator::new().extract(&carrier));
+    }
+    #[cfg(not(feature = "otel"))]
+    let _ = (span, traceparent);
+}
+
+/// The root span of a request in the engine. The spans of its sequences are children of it.
+pub(crate) fn request_span(request_id: usize, traceparent: Option<&str>) -> Span {
+    let span = info_span!(parent: None, "request", request_id);
+    set_remote_parent(&span, traceparent);
+    span
+}
+
+/// The span of a decoding step, linked to the requests of the sequences in the batch.
+pub(crate) fn decode_step_span<'a>(request_spans: impl IntoIterator<Item = &'a Span>) -> Span {
+    let span = info_span!(parent: None, "decode_step", batch_size = field::Empty);
+    let mut batch_size = 0;
+    for request_span in request_spans {
+        span.follows_from(request_span);
+        batch_size += 1;
+    }
+    span.record("batch_size", batch_size);
+    span
+}
+
+/// A tracing layer exporting spans over OTLP, if an OTLP endpoint is configured.
+#[cfg(feature = "otel")]
+pub(crate) fn otlp_layer<S>() -> Option<impl tracing_subscriber::Layer<S>>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_sdk::{
+        propagation::TraceContextPropagator, trace::SdkTracerProvider, Resource,
+    };
+
+    if std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_none()
+        && std::env::var_os("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT").is_none()
+    {
+        return None;
+    }
+
+    // The subscriber is not installed yet, so errors can't be logged.
+    let exporter = match opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()
+    {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            eprintln!("Failed to create the OTLP span exporter: {e}");
+            return None;
+        }
+    };
+
+    let mut resource = Resource::builder();
+    if std::env::var_os("OTEL_SERVICE_NAME").is_none() {
+        resource = resource.with_service_name("mistralrs");
+    }
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource.build())
+        .build();
+    let tracer = provider.tracer("mistralrs");
+    opentelemetry::global::set_tracer_provider(provider);
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+    Some(tracing_opentelemetry::layer().with_tracer(tracer))
+}
@@ -23,6 +23,7 @@ const THINK_CLOSE_TAG: &str = "</think>";
 ///
 /// This provides incremental parsing of token streams containing think tags,
 /// with delta extraction for streaming responses.
+#[derive(Clone)]
 pub struct ThinkTagContext {
     /// Accumulated final content (outside think blocks)
     accumulated_content: String,
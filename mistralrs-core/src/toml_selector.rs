@@ -9,14 +9,18 @@ use crate::{
     AnyMoeLoader, AutoDeviceMapParams, EmbeddingLoaderBuilder, EmbeddingSpecificConfig,
     GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoaderBuilder, GGUFSpecificConfig, Loader,
     ModelDType, NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, SpeculativeConfig,
-    SpeculativeLoader, Topology, VisionLoaderBuilder, VisionLoaderType, VisionSpecificConfig,
-    GGUF_MULTI_FILE_DELIMITER, UQFF_MULTI_FILE_DELIMITER,
+    SpeculativeDraft, SpeculativeLoader, Topology, VisionLoaderBuilder, VisionLoaderType,
+    VisionSpecificConfig, GGUF_MULTI_FILE_DELIMITER, UQFF_MULTI_FILE_DELIMITER,
 };
 
 fn default_one() -> usize {
     1
 }
 
+fn default_max_ngram() -> usize {
+    3
+}
+
 fn default_dtype() -> ModelDType {
     ModelDType::Auto
 }
@@ -483,7 +487,21 @@ pub struct SpeculativeTomlModelSelected {
     gamma: usize,
 
     /// Base model
-    draft_model: TomlModelSelected,
+    draft_model: Option<TomlModelSelected>,
+
+    /// Draft from n-gram matches in the sequence instead of a draft model
+    ngram: Option<NGramTomlSelected>,
+}
+
+#[derive(Deserialize)]
+pub struct NGramTomlSelected {
+    /// Shortest n-gram to look up
+    #[serde(default = "default_one")]
+    min_ngram: usize,
+
+    /// Longest n-gram to look up, tried first
+    #[serde(default = "default_max_ngram")]
+    max_ngram: usize,
 }
 
 #[derive(Deserialize)]
@@ -1007,12 +1025,34 @@ impl TryInto<Box<dyn Loader>> for (TomlSelector, TomlLoaderArgs) {
         };
         let loader = loader_from_selected(args.clone(), selector.model)?;
         let loader = if let Some(speculative) = selector.speculative {
-            let draft_loader = loader_from_selected(args, speculative.draft_model)?;
+            let (draft, draft_loader) = match (speculative.draft_model, speculative.ngram) {
+                (Some(draft_model), None) => (
+                    SpeculativeDraft::Model,
+                    Some(loader_from_selected(args, draft_model)?),
+                ),
+                (
+                    None,
+                    Some(NGramTomlSelected {
+                        min_ngram,
+                        max_ngram,
+                    }),
+                ) => (
+                    SpeculativeDraft::NGram {
+                        min_ngram,
+                        max_ngram,
+                    },
+                    None,
+                ),
+                _ => anyhow::bail!(
+                    "`[speculative]` requires exactly one of `draft_model` or `ngram`."
+                ),
+            };
             Box::new(SpeculativeLoader {
                 target: loader,
                 draft: draft_loader,
                 config: SpeculativeConfig {
                     gamma: speculative.gamma,
+                    draft,
                 },
             })
         } else {
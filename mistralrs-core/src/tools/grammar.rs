@@ -0,0 +1,92 @@
+//! Grammars which force the model to emit a tool call when the tool choice requires one.
+//!
+//! The grammar is written in llguidance's Lark dialect: the arguments are constrained with `%json` by the JSON
+//! schema of the tool, and the surrounding syntax is the model's own tool call format from its
+//! [`ToolCallParser`], so the output is parsed like any other tool call.
+
+use mistralrs_mcp::Tool;
+use serde_json::{json, Value};
+use tokenizers::Tokenizer;
+use tracing::warn;
+
+use super::{ToolCallParser, ToolChoice};
+use crate::ChatTemplate;
+
+pub(super) fn parameters_schema(tool: &Tool) -> Value {
+    match &tool.function.parameters {
+        Some(parameters) => json!(parameters),
+        None => json!({ "type": "object" }),
+    }
+}
+
+/// JSON schema of a single call of one of `tools`.
+pub(super) fn call_schema(tools: &[&Tool], arguments_key: &str) -> Value {
+    let calls = tools
+        .iter()
+        .map(|tool| {
+            json!({
+                "type": "object",
+                "properties": {
+                    "name": { "const": tool.function.name },
+                    arguments_key: parameters_schema(tool),
+                },
+                "required": ["name", arguments_key],
+                "additionalProperties": false,
+            })
+        })
+        .collect::<Vec<_>>();
+    match <[Value; 1]>::try_from(calls) {
+        Ok([call]) => call,
+        Err(calls) => json!({ "anyOf": calls }),
+    }
+}
+
+/// Render a marker as a Lark terminal. Special tokens are referenced by id, as their text never matches them.
+fn token_literal(tokenizer: &Tokenizer, text: &str) -> String {
+    tokenizer
+        .get_added_tokens_decoder()
+        .into_iter()
+        .find(|(_, token)| token.special && token.content == text)
+        .map(|(id, _)| format!("<[{id}]>"))
+        .unwrap_or_else(|| serde_json::to_string(text).unwrap())
+}
+
+/// The Lark grammar which forces a tool call allowed by `tool_choice`, or `None` if the tool choice does not
+/// require a call or the model's tool call format can't be constrained. In the latter case the tool choice is
+/// only conveyed by the prompt, which is logged as a warning.
+///
+/// The grammar starts at the first generated token, so reasoning models can't think before a forced call.
+pub(crate) fn tool_call_grammar(
+    parser: &dyn ToolCallParser,
+    chat_template: &ChatTemplate,
+    tokenizer: &Tokenizer,
+    tool_choice: &ToolChoice,
+    tools: &[Tool],
+) -> anyhow::Result<Option<String>> {
+    let tools = match tool_choice {
+        ToolChoice::None | ToolChoice::Auto => return Ok(None),
+        // Prefer the definition in `tools`, which the chat template was rendered with.
+        ToolChoice::Tool(forced) => vec![tools
+            .iter()
+            .find(|tool| tool.function.name == forced.function.name)
+            .unwrap_or(forced)],
+        ToolChoice::Required if tools.is_empty() => {
+            anyhow::bail!("Tool choice `required` needs at least one tool.")
+        }
+        ToolChoice::Required => tools.iter().collect(),
+    };
+    let (format, grammar) = if chat_template.is_harmony_format() {
+        ("harmony", None)
+    } else {
+        (
+            parser.name(),
+            parser.grammar(&tools, &|text| token_literal(tokenizer, text)),
+        )
+    };
+    if grammar.is_none() {
+        warn!(
+            "The `{format}` tool call format can't be constrained, so the model may answer without calling a tool despite the tool choice."
+        );
+    }
+    Ok(grammar)
+}
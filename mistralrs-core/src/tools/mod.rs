@@ -1,19 +1,25 @@
+mod grammar;
+mod parsers;
 mod request;
 mod response;
 
 use candle_core::Result;
-use regex::Regex;
+pub(crate) use grammar::tool_call_grammar;
+pub(crate) use parsers::detect_tool_call_parser;
+pub use parsers::{
+    partial_tool_calls, tool_call_parser, tool_call_parser_names, PartialToolCall, ToolCallParser,
+};
 pub use request::*;
 pub use response::*;
 use serde::de::{self, Deserializer, MapAccess, Visitor};
 use serde_json::{Map, Value};
 use std::collections::HashMap;
 use std::fmt;
-use std::sync::{Arc, OnceLock};
+use std::sync::Arc;
 use uuid::Uuid;
 
 use crate::Pipeline;
-use mistralrs_mcp::CalledFunction;
+use mistralrs_mcp::{CalledFunction, Tool};
 
 // Re-export the types so they're accessible as tools::Type
 pub use mistralrs_mcp::{ToolCallback, ToolCallbackWithTool};
@@ -24,72 +30,11 @@ pub type ToolCallbacks = HashMap<String, Arc<ToolCallback>>;
 /// Collection of callbacks with their tool definitions keyed by tool name.
 pub type ToolCallbacksWithTools = HashMap<String, ToolCallbackWithTool>;
 
-fn contains_tool_call_prefix(prefix: &str) -> bool {
-    prefix.contains("<tool_call>")
-        || prefix.contains("<｜tool▁call▁begin｜>")
-        || prefix.contains("<|python_tag|>")
-        || prefix.contains("[TOOL_CALLS]")
-}
-
-fn process_model_specific_message(message: &str) -> Result<String> {
-    static DEEPSEEK_REGEX: OnceLock<Regex> = OnceLock::new();
-    static QWEN_REGEX: OnceLock<Regex> = OnceLock::new();
-
-    // These are reasoning models so we need a regex.
-    let deepseek_regex = DEEPSEEK_REGEX.get_or_init(|| Regex::new(
-        r"(?s)<｜tool▁call▁begin｜>function<｜tool▁sep｜>(?P<name>[^\n]+)\n```json\n(?P<json>.+?)\n```<｜tool▁call▁end｜>",
-    ).unwrap());
-    let qwen_regex = QWEN_REGEX
-        .get_or_init(|| Regex::new(r"(?s)<tool_call>(?P<inner>.*?)</tool_call>").unwrap());
-
-    if let Some(message) = message.strip_prefix("<|python_tag|>") {
-        // Llama case
-        Ok(message.to_string())
-    } else if qwen_regex.is_match(message) {
-        if let Some(caps) = qwen_regex.captures(message) {
-            let inner = caps.name("inner").unwrap().as_str();
-            return Ok(inner.trim().to_string());
-        }
-        Ok(message.to_string())
-    } else if let Some(message) = message
-        .strip_prefix("[TOOL_CALLS][")
-        .and_then(|s| s.strip_suffix("]"))
-    {
-        // Mistral Nemo case
-        Ok(message.to_string())
-    } else if deepseek_regex.find(message).is_some() {
-        #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
-        struct ToolCall {
-            name: String,
-            arguments: Value,
-        }
-        let mut calls = Vec::new();
-        for caps in deepseek_regex.captures_iter(message) {
-            let name = caps
-                .name("name")
-                .ok_or("Could not capture function name")
-                .map_err(candle_core::Error::msg)?
-                .as_str()
-                .trim()
-                .to_string();
-            let json_str = caps
-                .name("json")
-                .ok_or("Could not capture JSON arguments")
-                .map_err(candle_core::Error::msg)?
-                .as_str()
-                .trim();
-            let arguments: Value =
-                serde_json::from_str(json_str).map_err(candle_core::Error::msg)?;
-            calls.push(ToolCall { name, arguments });
-        }
-        Ok(serde_json::to_string(&calls).map_err(candle_core::Error::msg)?)
-    } else {
-        Ok(message.to_string())
-    }
-}
-
 pub struct ToolCallingMatcher {
     tool_choice: ToolChoice,
+    parser: Arc<dyn ToolCallParser>,
+    /// The names of the tools of the request.
+    tool_names: Vec<String>,
 }
 
 // Same as CalledFunction, but has different cases for variations on the names
@@ -156,8 +101,32 @@ fn fix_broken_json(raw: &str) -> anyhow::Result<String> {
 }
 
 impl ToolCallingMatcher {
-    pub fn new(tool_choice: ToolChoice) -> anyhow::Result<Self> {
-        Ok(Self { tool_choice })
+    pub fn new(
+        tool_choice: ToolChoice,
+        parser: Arc<dyn ToolCallParser>,
+        tools: &[Tool],
+    ) -> anyhow::Result<Self> {
+        let mut tool_names = tools
+            .iter()
+            .map(|tool| tool.function.name.clone())
+            .collect::<Vec<_>>();
+        if let ToolChoice::Tool(tool) = &tool_choice {
+            tool_names.push(tool.function.name.clone());
+        }
+        Ok(Self {
+            tool_choice,
+            parser,
+            tool_names,
+        })
+    }
+
+    /// Rewrite the tool calls of a message to JSON with the model's tool call parser.
+    fn to_json(&self, message: &str) -> String {
+        let message = self
+            .parser
+            .to_json(message)
+            .unwrap_or_else(|| message.to_string());
+        fix_broken_json(&message).unwrap()
     }
 
     // Checks if the `message_prefix` could be a tool call. If false, either
@@ -174,8 +143,7 @@ impl ToolCallingMatcher {
         if matches!(self.tool_choice, ToolChoice::None) {
             return Ok((false, false));
         }
-        let message_prefix = process_model_specific_message(message_prefix)?;
-        let message_prefix = fix_broken_json(&message_prefix).unwrap();
+        let json = self.to_json(message_prefix);
 
         // Check if the prefix could be a JSON serialization of any of the following types.
         Ok([
@@ -184,14 +152,31 @@ impl ToolCallingMatcher {
         ]
         .iter()
         .find_map(|check| {
-            let (could_be_tool, is_complete_tool) = check(&message_prefix);
+            let (could_be_tool, is_complete_tool) = check(&json);
             if could_be_tool || is_complete_tool {
                 Some((could_be_tool, is_complete_tool))
             } else {
                 None
             }
         })
-        .unwrap_or((contains_tool_call_prefix(&message_prefix), false)))
+        .unwrap_or((self.parser.contains_start(message_prefix), false)))
+    }
+
+    /// The tool calls of a message which may still be being generated. The calls end before the first one
+    /// naming none of the tools of the request, so that text which merely looks like a call is not streamed.
+    pub(crate) fn partial_calls(&self, message: &str) -> Vec<PartialToolCall> {
+        if matches!(self.tool_choice, ToolChoice::None) {
+            return Vec::new();
+        }
+        let mut calls = partial_tool_calls(&*self.parser, message);
+        if let Some(unknown) = calls.iter().position(|call| {
+            call.name
+                .as_ref()
+                .is_some_and(|name| !self.tool_names.contains(name))
+        }) {
+            calls.truncate(unknown);
+        }
+        calls
     }
 
     pub fn get_call(
@@ -202,8 +187,7 @@ impl ToolCallingMatcher {
         if matches!(self.tool_choice, ToolChoice::None) {
             return Ok(Vec::new());
         }
-        let message = process_model_specific_message(message)?;
-        let message = fix_broken_json(&message).unwrap();
+        let message = self.to_json(message);
 
         if let Ok(deser) = serde_json::from_str::<CalledFunctionParameters>(&message) {
             let id = format!("call-{}", Uuid::new_v4());
@@ -234,7 +218,7 @@ impl ToolCallingMatcher {
                 })
                 .collect::<anyhow::Result<Vec<_>>>()?)
         } else {
-            if matches!(self.tool_choice, ToolChoice::Tool(_)) {
+            if matches!(self.tool_choice, ToolChoice::Tool(_) | ToolChoice::Required) {
                 anyhow::bail!("Tool choice was required but no tools were called.")
             }
             Ok(Vec::new())
@@ -242,6 +226,67 @@ impl ToolCallingMatcher {
     }
 }
 
+/// The tool calls sent so far by a streaming response, so that only the new fragments of each call are sent.
+#[derive(Clone, Default)]
+pub(crate) struct ToolCallStream {
+    /// The arguments sent for each call.
+    sent: Vec<String>,
+}
+
+impl ToolCallStream {
+    /// Whether any call was sent.
+    pub(crate) fn is_started(&self) -> bool {
+        !self.sent.is_empty()
+    }
+
+    /// The fragments of `calls` which have not been sent yet. A call is started once its name is complete.
+    pub(crate) fn deltas(&mut self, calls: &[PartialToolCall]) -> Vec<ToolCallDelta> {
+        let mut deltas = Vec::new();
+        for (index, call) in calls.iter().enumerate() {
+            let start = index == self.sent.len();
+            let name = match &call.name {
+                Some(name) if start => Some(name.clone()),
+                None if start => break,
+                _ => None,
+            };
+            if start {
+                self.sent.push(String::new());
+            }
+            let sent = &mut self.sent[index];
+            // The arguments only grow, unless a parser rewrote them. Then the rest is lost, as the sent
+            // fragments can't be taken back.
+            let arguments = match call.arguments.strip_prefix(sent.as_str()) {
+                Some(new) if !new.is_empty() || start => new.to_string(),
+                _ => continue,
+            };
+            sent.push_str(&arguments);
+            deltas.push(ToolCallDelta {
+                index,
+                id: start.then(|| format!("call-{}", Uuid::new_v4())),
+                tp: start.then_some(ToolCallType::Function),
+                function: FunctionDelta { name, arguments },
+            });
+        }
+        deltas
+    }
+
+    /// The remaining fragments of a finished message, whose complete `calls` were parsed from it.
+    pub(crate) fn finish(
+        &mut self,
+        partial: &[PartialToolCall],
+        calls: Vec<ToolCallResponse>,
+    ) -> Vec<ToolCallDelta> {
+        let mut deltas = self.deltas(partial);
+        // Calls which the partial parse missed are sent whole.
+        for mut call in calls.into_iter().skip(self.sent.len()) {
+            call.index = self.sent.len();
+            self.sent.push(call.function.arguments.clone());
+            deltas.push(call.into());
+        }
+        deltas
+    }
+}
+
 /// Checks if the given prefix could be the start of, or the entire JSON serialization of a given type, `T`.
 ///
 /// Returns a tuple of `(could_be_tool, is_entire_tool)`.
@@ -280,3 +325,109 @@ pub fn parse_text_tools<'a>(
     };
     Ok((text_new, tool_calls))
 }
+
+#[cfg(test)]
+mod tests {
+    use mistralrs_mcp::{CalledFunction, Function, Tool, ToolType};
+
+    use super::{
+        tool_call_parser, PartialToolCall, ToolCallDelta, ToolCallResponse, ToolCallStream,
+        ToolCallType, ToolCallingMatcher, ToolChoice,
+    };
+
+    fn partial(name: Option<&str>, arguments: &str) -> PartialToolCall {
+        PartialToolCall {
+            name: name.map(str::to_string),
+            arguments: arguments.to_string(),
+            complete: false,
+        }
+    }
+
+    fn summary(deltas: Vec<ToolCallDelta>) -> Vec<(usize, bool, Option<String>, String)> {
+        deltas
+            .into_iter()
+            .map(|delta| {
+                (
+                    delta.index,
+                    delta.id.is_some(),
+                    delta.function.name,
+                    delta.function.arguments,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn streams_new_fragments() {
+        let mut stream = ToolCallStream::default();
+        assert!(summary(stream.deltas(&[partial(None, "")])).is_empty());
+        assert!(!stream.is_started());
+
+        assert_eq!(
+            summary(stream.deltas(&[partial(Some("a"), "{\"x\": ")])),
+            vec![(0, true, Some("a".to_string()), "{\"x\": ".to_string())]
+        );
+        assert!(stream.is_started());
+        assert_eq!(
+            summary(stream.deltas(&[partial(Some("a"), "{\"x\": 1}"), partial(Some("b"), "")])),
+            vec![
+                (0, false, None, "1}".to_string()),
+                (1, true, Some("b".to_string()), String::new()),
+            ]
+        );
+        // Nothing new is sent again
+        assert!(summary(stream.deltas(&[partial(Some("a"), "{\"x\": 1}")])).is_empty());
+    }
+
+    #[test]
+    fn finishes_with_missed_calls() {
+        let mut stream = ToolCallStream::default();
+        stream.deltas(&[partial(Some("a"), "{")]);
+        let calls = ["a", "b"]
+            .into_iter()
+            .enumerate()
+            .map(|(index, name)| ToolCallResponse {
+                index,
+                id: format!("call-{index}"),
+                tp: ToolCallType::Function,
+                function: CalledFunction {
+                    name: name.to_string(),
+                    arguments: "{}".to_string(),
+                },
+            })
+            .collect();
+        assert_eq!(
+            summary(stream.finish(&[partial(Some("a"), "{}")], calls)),
+            vec![
+                (0, false, None, "}".to_string()),
+                (1, true, Some("b".to_string()), "{}".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn streams_only_calls_of_the_tools() {
+        let tool = Tool {
+            tp: ToolType::Function,
+            function: Function {
+                description: None,
+                name: "get_weather".to_string(),
+                parameters: None,
+            },
+        };
+        let matcher = ToolCallingMatcher::new(
+            ToolChoice::Auto,
+            tool_call_parser("mistral").unwrap(),
+            &[tool],
+        )
+        .unwrap();
+        let calls = matcher.partial_calls(
+            "[TOOL_CALLS][{\"name\": \"get_weather\", \"arguments\": {}}, {\"name\": \"get_time\", \"arguments\": {",
+        );
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].name.as_deref(), Some("get_weather"));
+        assert!(matcher
+            .partial_calls("[TOOL_CALLS][{\"name\": \"get_time\", \"arguments\": {")
+            .is_empty());
+    }
+}
@@ -0,0 +1,884 @@
+//! Parsers of the tool call formats of models.
+//!
+//! A parser rewrites the tool calls of a message to JSON, which is then read by [`super::ToolCallingMatcher`].
+//! Messages are rewritten while they are generated, so incomplete calls can be detected and streamed.
+
+use std::sync::Arc;
+
+use either::Either;
+use mistralrs_mcp::Tool;
+use serde_json::{json, Value};
+
+use super::grammar::{call_schema, parameters_schema};
+use crate::ChatTemplate;
+
+const HERMES_BEGIN: &str = "<tool_call>";
+const HERMES_END: &str = "</tool_call>";
+const GLM4_ARG_KEY: &str = "<arg_key>";
+const GLM4_ARG_KEY_END: &str = "</arg_key>";
+const GLM4_ARG_VALUE: &str = "<arg_value>";
+const GLM4_ARG_VALUE_END: &str = "</arg_value>";
+const GRANITE_BEGIN: &str = "<|tool_call|>";
+const MISTRAL_BEGIN: &str = "[TOOL_CALLS]";
+const MISTRAL_ARGS: &str = "[ARGS]";
+const MISTRAL_CALL_ID: &str = "[CALL_ID]";
+const LLAMA_BEGIN: &str = "<|python_tag|>";
+const DEEPSEEK_CALLS_BEGIN: &str = "<｜tool▁calls▁begin｜>";
+const DEEPSEEK_CALLS_END: &str = "<｜tool▁calls▁end｜>";
+const DEEPSEEK_BEGIN: &str = "<｜tool▁call▁begin｜>";
+const DEEPSEEK_SEP: &str = "<｜tool▁sep｜>";
+const DEEPSEEK_END: &str = "<｜tool▁call▁end｜>";
+const GEMMA_BEGIN: &str = "```tool_code";
+const GEMMA_END: &str = "```";
+
+/// The tool call format of a model.
+pub trait ToolCallParser: Send + Sync {
+    /// Name of the format, which selects the parser with `--tool-parser`.
+    fn name(&self) -> &'static str;
+
+    /// Whether a chat template renders tool calls in this format.
+    fn matches_template(&self, template: &str) -> bool;
+
+    /// Whether `text` contains the start of a tool call in this format.
+    fn contains_start(&self, text: &str) -> bool;
+
+    /// Rewrite the tool calls of a message, which may be incomplete, to the JSON of a call or a list of calls
+    /// with `name` and `arguments` keys, or `None` if the message is not a tool call in this format.
+    ///
+    /// While a call is generated its JSON must only grow, so that its arguments can be streamed.
+    fn to_json(&self, message: &str) -> Option<String>;
+
+    /// Lark grammar of a single call of one of `tools`, or `None` if calls in this format can't be constrained.
+    /// `literal` renders a marker of the format as a Lark terminal.
+    fn grammar(&self, _tools: &[&Tool], _literal: &dyn Fn(&str) -> String) -> Option<String> {
+        None
+    }
+}
+
+/// A tool call read from a message which is still being generated.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PartialToolCall {
+    /// The function name, once it is complete.
+    pub name: Option<String>,
+    /// The JSON of the arguments generated so far.
+    pub arguments: String,
+    /// Whether the call is complete.
+    pub complete: bool,
+}
+
+/// The JSON of a list of calls. A call without arguments has an incomplete name, and no calls follow a call
+/// whose arguments are incomplete JSON.
+fn calls_to_json<'a>(calls: impl IntoIterator<Item = (&'a str, Option<String>)>) -> String {
+    let mut json = String::from("[");
+    for (i, (name, arguments)) in calls.into_iter().enumerate() {
+        if i > 0 {
+            json.push_str(", ");
+        }
+        let name = serde_json::to_string(name.trim()).unwrap();
+        let Some(arguments) = arguments else {
+            json.push_str(&format!("{{\"name\": {}", &name[..name.len() - 1]));
+            return json;
+        };
+        json.push_str(&format!("{{\"name\": {name}, \"arguments\": {arguments}"));
+        if serde_json::from_str::<Value>(&arguments).is_err() {
+            return json;
+        }
+        json.push('}');
+    }
+    json.push(']');
+    json
+}
+
+/// Lark grammar with one alternative per tool, for formats where the name is not part of the JSON.
+fn per_tool_grammar(tools: &[&Tool], call: impl Fn(&Tool, &str) -> String) -> String {
+    let alternatives = (0..tools.len())
+        .map(|i| format!("call_{i}"))
+        .collect::<Vec<_>>()
+        .join(" | ");
+    let rules = tools
+        .iter()
+        .enumerate()
+        .map(|(i, tool)| {
+            format!(
+                "call_{i}: {}\nargs_{i}: %json {}\n",
+                call(tool, &format!("args_{i}")),
+                parameters_schema(tool)
+            )
+        })
+        .collect::<String>();
+    format!("call: {alternatives}\n{rules}")
+}
+
+fn quoted(text: &str) -> String {
+    serde_json::to_string(text).unwrap()
+}
+
+/// Tool calls as bare JSON, `{"name": ..., "arguments": ...}`.
+struct JsonParser;
+
+impl ToolCallParser for JsonParser {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn matches_template(&self, _template: &str) -> bool {
+        false
+    }
+
+    fn contains_start(&self, _text: &str) -> bool {
+        false
+    }
+
+    fn to_json(&self, _message: &str) -> Option<String> {
+        None
+    }
+
+    fn grammar(&self, tools: &[&Tool], _literal: &dyn Fn(&str) -> String) -> Option<String> {
+        Some(format!(
+            "start: call\ncall: %json {}\n",
+            call_schema(tools, "arguments")
+        ))
+    }
+}
+
+/// Llama 3.1+: `{"name": ..., "parameters": ...}`, optionally after `<|python_tag|>`.
+struct LlamaParser;
+
+impl ToolCallParser for LlamaParser {
+    fn name(&self) -> &'static str {
+        "llama"
+    }
+
+    fn matches_template(&self, template: &str) -> bool {
+        template.contains(LLAMA_BEGIN) || template.contains("ipython")
+    }
+
+    fn contains_start(&self, text: &str) -> bool {
+        text.contains(LLAMA_BEGIN)
+    }
+
+    fn to_json(&self, message: &str) -> Option<String> {
+        message.strip_prefix(LLAMA_BEGIN).map(ToString::to_string)
+    }
+
+    fn grammar(&self, tools: &[&Tool], literal: &dyn Fn(&str) -> String) -> Option<String> {
+        Some(format!(
+            "start: {}? call\ncall: %json {}\n",
+            literal(LLAMA_BEGIN),
+            call_schema(tools, "parameters")
+        ))
+    }
+}
+
+/// Hermes and Qwen: `<tool_call>{"name": ..., "arguments": ...}</tool_call>`, once per call.
+struct HermesParser;
+
+impl ToolCallParser for HermesParser {
+    fn name(&self) -> &'static str {
+        "hermes"
+    }
+
+    fn matches_template(&self, template: &str) -> bool {
+        template.contains(HERMES_BEGIN)
+    }
+
+    fn contains_start(&self, text: &str) -> bool {
+        text.contains(HERMES_BEGIN)
+    }
+
+    fn to_json(&self, message: &str) -> Option<String> {
+        let (_, calls) = message.split_once(HERMES_BEGIN)?;
+        let mut json = String::from("[");
+        for (i, call) in calls.split(HERMES_BEGIN).enumerate() {
+            if i > 0 {
+                json.push_str(", ");
+            }
+            match call.split_once(HERMES_END) {
+                Some((call, _)) => json.push_str(call.trim()),
+                None => {
+                    json.push_str(call.trim());
+                    return Some(json);
+                }
+            }
+        }
+        json.push(']');
+        Some(json)
+    }
+
+    fn grammar(&self, tools: &[&Tool], literal: &dyn Fn(&str) -> String) -> Option<String> {
+        Some(format!(
+            "start: {} \"\\n\" call \"\\n\" {}\ncall: %json {}\n",
+            literal(HERMES_BEGIN),
+            literal(HERMES_END),
+            call_schema(tools, "arguments")
+        ))
+    }
+}
+
+/// GLM-4.5: `<tool_call>NAME<arg_key>KEY</arg_key><arg_value>VALUE</arg_value></tool_call>`, where values which
+/// are not JSON are strings.
+struct Glm4Parser;
+
+impl ToolCallParser for Glm4Parser {
+    fn name(&self) -> &'static str {
+        "glm4"
+    }
+
+    fn matches_template(&self, template: &str) -> bool {
+        template.contains(HERMES_BEGIN) && template.contains(GLM4_ARG_KEY)
+    }
+
+    fn contains_start(&self, text: &str) -> bool {
+        text.contains(HERMES_BEGIN)
+    }
+
+    fn to_json(&self, message: &str) -> Option<String> {
+        let (_, calls) = message.split_once(HERMES_BEGIN)?;
+        let calls = calls.split(HERMES_BEGIN).map(|call| {
+            let (call, complete) = match call.split_once(HERMES_END) {
+                Some((call, _)) => (call, true),
+                None => (call, false),
+            };
+            let name_end = call.find(['\n', '<']).or(complete.then_some(call.len()));
+            let Some(name_end) = name_end else {
+                return (call, None);
+            };
+            let (name, mut rest) = call.split_at(name_end);
+            let mut arguments = Vec::new();
+            while let Some((key, value, remaining)) = rest
+                .split_once(GLM4_ARG_KEY)
+                .and_then(|(_, rest)| rest.split_once(GLM4_ARG_KEY_END))
+                .and_then(|(key, rest)| {
+                    let (_, rest) = rest.split_once(GLM4_ARG_VALUE)?;
+                    let (value, rest) = rest.split_once(GLM4_ARG_VALUE_END)?;
+                    Some((key, value, rest))
+                })
+            {
+                let value = serde_json::from_str::<Value>(value)
+                    .unwrap_or_else(|_| Value::String(value.to_string()));
+                arguments.push(format!("{}: {value}", quoted(key.trim())));
+                rest = remaining;
+            }
+            let mut arguments = format!("{{{}", arguments.join(", "));
+            if complete {
+                arguments.push('}');
+            }
+            (name, Some(arguments))
+        });
+        Some(calls_to_json(calls))
+    }
+}
+
+/// Granite: `<|tool_call|>[{"name": ..., "arguments": ...}]`.
+struct GraniteParser;
+
+impl ToolCallParser for GraniteParser {
+    fn name(&self) -> &'static str {
+        "granite"
+    }
+
+    fn matches_template(&self, template: &str) -> bool {
+        template.contains(GRANITE_BEGIN)
+    }
+
+    fn contains_start(&self, text: &str) -> bool {
+        text.contains(GRANITE_BEGIN)
+    }
+
+    fn to_json(&self, message: &str) -> Option<String> {
+        let (_, calls) = message.split_once(GRANITE_BEGIN)?;
+        Some(calls.trim_start().to_string())
+    }
+
+    fn grammar(&self, tools: &[&Tool], literal: &dyn Fn(&str) -> String) -> Option<String> {
+        Some(format!(
+            "start: {} calls\ncalls: %json {}\n",
+            literal(GRANITE_BEGIN),
+            json!({ "type": "array", "items": call_schema(tools, "arguments"), "minItems": 1 })
+        ))
+    }
+}
+
+/// Mistral: `[TOOL_CALLS][{"name": ..., "arguments": ...}]`, or `[TOOL_CALLS]NAME[ARGS]{...}` once per call with
+/// the v11 tokenizer. Both are parsed, `v11` selects the format calls are constrained to.
+struct MistralParser {
+    v11: bool,
+}
+
+impl ToolCallParser for MistralParser {
+    fn name(&self) -> &'static str {
+        if self.v11 {
+            "mistral_v11"
+        } else {
+            "mistral"
+        }
+    }
+
+    fn matches_template(&self, template: &str) -> bool {
+        template.contains(MISTRAL_BEGIN) && template.contains(MISTRAL_ARGS) == self.v11
+    }
+
+    fn contains_start(&self, text: &str) -> bool {
+        text.contains(MISTRAL_BEGIN)
+    }
+
+    fn to_json(&self, message: &str) -> Option<String> {
+        let (_, calls) = message.split_once(MISTRAL_BEGIN)?;
+        if calls.trim().is_empty() || calls.trim_start().starts_with('[') {
+            return Some(calls.trim_start().to_string());
+        }
+        let calls = calls
+            .split(MISTRAL_BEGIN)
+            .map(|call| match call.split_once(MISTRAL_ARGS) {
+                Some((name, arguments)) => {
+                    let name = name.split(MISTRAL_CALL_ID).next().unwrap_or(name);
+                    (name, Some(arguments.trim().to_string()))
+                }
+                None => (call.split(MISTRAL_CALL_ID).next().unwrap_or(call), None),
+            });
+        Some(calls_to_json(calls))
+    }
+
+    fn grammar(&self, tools: &[&Tool], literal: &dyn Fn(&str) -> String) -> Option<String> {
+        if self.v11 {
+            let calls = per_tool_grammar(tools, |tool, args| {
+                format!(
+                    "{} {} {} {args}",
+                    literal(MISTRAL_BEGIN),
+                    quoted(&tool.function.name),
+                    literal(MISTRAL_ARGS)
+                )
+            });
+            Some(format!("start: call\n{calls}"))
+        } else {
+            Some(format!(
+                "start: {} calls\ncalls: %json {}\n",
+                literal(MISTRAL_BEGIN),
+                json!({ "type": "array", "items": call_schema(tools, "arguments"), "minItems": 1 })
+            ))
+        }
+    }
+}
+
+/// DeepSeek: `<｜tool▁call▁begin｜>function<｜tool▁sep｜>NAME` followed by the arguments in a JSON code block.
+struct DeepSeekParser;
+
+impl ToolCallParser for DeepSeekParser {
+    fn name(&self) -> &'static str {
+        "deepseek"
+    }
+
+    fn matches_template(&self, template: &str) -> bool {
+        template.contains(DEEPSEEK_BEGIN)
+    }
+
+    fn contains_start(&self, text: &str) -> bool {
+        text.contains(DEEPSEEK_BEGIN)
+    }
+
+    fn to_json(&self, message: &str) -> Option<String> {
+        let (_, calls) = message.split_once(DEEPSEEK_BEGIN)?;
+        let calls = calls.split(DEEPSEEK_BEGIN).map(|call| {
+            let call = call.split(DEEPSEEK_END).next().unwrap_or(call);
+            let Some((_, call)) = call.split_once(DEEPSEEK_SEP) else {
+                return ("", None);
+            };
+            let Some((name, arguments)) = call.split_once('\n') else {
+                return (call, None);
+            };
+            let arguments = arguments
+                .trim_start()
+                .strip_prefix("```json")
+                .map(|arguments| {
+                    arguments
+                        .trim()
+                        .trim_end_matches('`')
+                        .trim_end()
+                        .to_string()
+                });
+            (name, arguments)
+        });
+        Some(calls_to_json(calls))
+    }
+
+    fn grammar(&self, tools: &[&Tool], literal: &dyn Fn(&str) -> String) -> Option<String> {
+        let calls = per_tool_grammar(tools, |tool, args| {
+            format!(
+                "{} \"function\" {} {} \"\\n```json\\n\" {args} \"\\n```\" {}",
+                literal(DEEPSEEK_BEGIN),
+                literal(DEEPSEEK_SEP),
+                quoted(&tool.function.name),
+                literal(DEEPSEEK_END)
+            )
+        });
+        Some(format!(
+            "start: {} call {}\n{calls}",
+            literal(DEEPSEEK_CALLS_BEGIN),
+            literal(DEEPSEEK_CALLS_END)
+        ))
+    }
+}
+
+/// Gemma: Python calls in a code block, ```` ```tool_code\nNAME(KEY=VALUE, ...)\n``` ````, one per line.
+struct GemmaParser;
+
+/// Convert a Python literal to JSON.
+fn python_to_json(literal: &str) -> Option<Value> {
+    let mut json = String::new();
+    let mut chars = literal.trim().chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' | '"' => {
+                let mut string = String::new();
+                loop {
+                    match chars.next()? {
+                        '\\' => string.push(match chars.next()? {
+                            'n' => '\n',
+                            't' => '\t',
+                            c => c,
+                        }),
+                        end if end == c => break,
+                        c => string.push(c),
+                    }
+                }
+                json.push_str(&quoted(&string));
+            }
+            c if c.is_alphabetic() => {
+                let mut word = String::from(c);
+                while let Some(c) = chars.next_if(|c| c.is_alphanumeric() || *c == '_') {
+                    word.push(c);
+                }
+                json.push_str(match word.as_str() {
+                    "True" => "true",
+                    "False" => "false",
+                    "None" => "null",
+                    word => word,
+                });
+            }
+            '(' => json.push('['),
+            ')' => json.push(']'),
+            c => json.push(c),
+        }
+    }
+    serde_json::from_str(&json).ok()
+}
+
+/// Split Python call arguments at the top level commas.
+fn split_python_arguments(arguments: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut depth, mut quote, mut start) = (0usize, None, 0);
+    let mut chars = arguments.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            (Some(_), '\\') => {
+                chars.next();
+            }
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => (),
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '(' | '[' | '{') => depth += 1,
+            (None, ')' | ']' | '}') => depth = depth.saturating_sub(1),
+            (None, ',') if depth == 0 => {
+                parts.push(&arguments[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    parts.push(&arguments[start..]);
+    parts.retain(|part| !part.trim().is_empty());
+    parts
+}
+
+impl ToolCallParser for GemmaParser {
+    fn name(&self) -> &'static str {
+        "gemma"
+    }
+
+    fn matches_template(&self, template: &str) -> bool {
+        template.contains(GEMMA_BEGIN)
+    }
+
+    fn contains_start(&self, text: &str) -> bool {
+        text.contains(GEMMA_BEGIN)
+    }
+
+    fn to_json(&self, message: &str) -> Option<String> {
+        let (_, block) = message.split_once(GEMMA_BEGIN)?;
+        let (block, complete) = match block.split_once(GEMMA_END) {
+            Some((block, _)) => (block, true),
+            None => (block, false),
+        };
+        let lines = block
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>();
+        let last = lines.len().saturating_sub(1);
+        let calls = lines.into_iter().enumerate().map(|(i, line)| {
+            let Some((name, arguments)) = line.split_once('(') else {
+                return (line, None);
+            };
+            let arguments = match arguments.strip_suffix(')') {
+                Some(arguments) if complete || i < last => arguments,
+                _ => return (name, Some(String::new())),
+            };
+            let arguments = split_python_arguments(arguments)
+                .into_iter()
+                .map(|argument| {
+                    let (key, value) = argument.split_once('=')?;
+                    Some((key.trim().to_string(), python_to_json(value)?))
+                })
+                .collect::<Option<serde_json::Map<_, _>>>();
+            match arguments {
+                Some(arguments) => (name, Some(Value::Object(arguments).to_string())),
+                None => (name, Some(String::new())),
+            }
+        });
+        Some(calls_to_json(calls))
+    }
+}
+
+/// Accepts the tool calls of all built-in formats, for chat templates whose format is not recognized.
+struct AutoParser(Vec<Arc<dyn ToolCallParser>>);
+
+impl ToolCallParser for AutoParser {
+    fn name(&self) -> &'static str {
+        "auto"
+    }
+
+    fn matches_template(&self, _template: &str) -> bool {
+        false
+    }
+
+    fn contains_start(&self, text: &str) -> bool {
+        self.0.iter().any(|parser| parser.contains_start(text))
+    }
+
+    fn to_json(&self, message: &str) -> Option<String> {
+        self.0.iter().find_map(|parser| parser.to_json(message))
+    }
+
+    fn grammar(&self, tools: &[&Tool], literal: &dyn Fn(&str) -> String) -> Option<String> {
+        JsonParser.grammar(tools, literal)
+    }
+}
+
+/// The built-in parsers, in the order they are detected from chat templates.
+fn builtin_parsers() -> Vec<Arc<dyn ToolCallParser>> {
+    vec![
+        Arc::new(Glm4Parser),
+        Arc::new(HermesParser),
+        Arc::new(GraniteParser),
+        Arc::new(MistralParser { v11: true }),
+        Arc::new(MistralParser { v11: false }),
+        Arc::new(DeepSeekParser),
+        Arc::new(LlamaParser),
+        Arc::new(GemmaParser),
+        Arc::new(JsonParser),
+    ]
+}
+
+/// Names of the built-in tool call parsers.
+pub fn tool_call_parser_names() -> Vec<&'static str> {
+    builtin_parsers()
+        .iter()
+        .map(|parser| parser.name())
+        .collect()
+}
+
+/// The built-in tool call parser with this name. `qwen` is an alias of `hermes`.
+pub fn tool_call_parser(name: &str) -> Option<Arc<dyn ToolCallParser>> {
+    let name = if name == "qwen" { "hermes" } else { name };
+    builtin_parsers()
+        .into_iter()
+        .find(|parser| parser.name() == name)
+}
+
+/// The parser of the tool call format of a chat template. If the format is not recognized, the calls of all
+/// built-in formats are accepted.
+pub(crate) fn detect_tool_call_parser(
+    chat_template: Option<&ChatTemplate>,
+) -> Arc<dyn ToolCallParser> {
+    let template = match chat_template.and_then(|template| template.chat_template.as_ref()) {
+        Some(value) => match &value.0 {
+            Either::Left(template) => template.clone(),
+            Either::Right(templates) => templates
+                .iter()
+                .flat_map(|template| template.values())
+                .cloned()
+                .collect::<Vec<_>>()
+                .join("\n"),
+        },
+        None => String::new(),
+    };
+    let parsers = builtin_parsers();
+    if let Some(parser) = parsers
+        .iter()
+        .find(|parser| parser.matches_template(&template))
+    {
+        return parser.clone();
+    }
+    Arc::new(AutoParser(
+        parsers
+            .into_iter()
+            .filter(|parser| !matches!(parser.name(), "glm4" | "mistral_v11" | "json"))
+            .collect(),
+    ))
+}
+
+/// Read the tool calls of a message which is still being generated.
+pub fn partial_tool_calls(parser: &dyn ToolCallParser, message: &str) -> Vec<PartialToolCall> {
+    let json = parser
+        .to_json(message)
+        .unwrap_or_else(|| message.to_string());
+    read_partial_calls(&json)
+}
+
+/// Read the calls of JSON which may be incomplete, as produced by [`ToolCallParser::to_json`].
+fn read_partial_calls(json: &str) -> Vec<PartialToolCall> {
+    let call_depth = match json.trim_start().chars().next() {
+        Some('{') => 1,
+        Some('[') => 2,
+        _ => return Vec::new(),
+    };
+
+    let mut calls: Vec<PartialToolCall> = Vec::new();
+    let mut depth = 0usize;
+    let (mut in_string, mut escaped, mut string_start) = (false, false, 0);
+    let mut expecting_key = false;
+    let mut key: Option<String> = None;
+    let mut value_start: Option<usize> = None;
+
+    let finish_value = |calls: &mut Vec<PartialToolCall>, key: &Option<String>, value: &str| {
+        let Some(call) = calls.last_mut() else {
+            return;
+        };
+        match key.as_deref() {
+            Some("name" | "function") => call.name = serde_json::from_str(value).ok(),
+            Some("arguments" | "parameters") => {
+                call.arguments = match serde_json::from_str::<Value>(value) {
+                    Ok(Value::String(arguments)) => arguments,
+                    _ => value.to_string(),
+                }
+            }
+            _ => (),
+        }
+    };
+
+    for (i, c) in json.char_indices() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+                if depth == call_depth && expecting_key {
+                    key = serde_json::from_str(&json[string_start..=i]).ok();
+                }
+            }
+            continue;
+        }
+        if c.is_whitespace() {
+            continue;
+        }
+        let starts_value = depth == call_depth && !expecting_key && value_start.is_none();
+        match c {
+            '"' => {
+                in_string = true;
+                string_start = i;
+                if starts_value {
+                    value_start = Some(i);
+                }
+            }
+            '{' | '[' => {
+                if starts_value {
+                    value_start = Some(i);
+                } else if c == '{' && depth + 1 == call_depth {
+                    calls.push(PartialToolCall::default());
+                    expecting_key = true;
+                }
+                depth += 1;
+            }
+            '}' | ']' => {
+                if depth == call_depth {
+                    if let Some(start) = value_start.take() {
+                        finish_value(&mut calls, &key, json[start..i].trim_end());
+                    }
+                    if let Some(call) = calls.last_mut() {
+                        call.complete = true;
+                    }
+                    key = None;
+                }
+                depth = depth.saturating_sub(1);
+            }
+            ',' if depth == call_depth => {
+                if let Some(start) = value_start.take() {
+                    finish_value(&mut calls, &key, json[start..i].trim_end());
+                }
+                key = None;
+                expecting_key = true;
+            }
+            ':' if depth == call_depth => expecting_key = false,
+            _ => {
+                if starts_value {
+                    value_start = Some(i);
+                }
+            }
+        }
+    }
+
+    // Arguments which are still being generated.
+    if let (Some("arguments" | "parameters"), Some(start), Some(call)) =
+        (key.as_deref(), value_start, calls.last_mut())
+    {
+        if !json[start..].starts_with('"') {
+            call.arguments = json[start..].trim_end().to_string();
+        }
+    }
+    calls
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use llguidance::{api::TopLevelGrammar, toktrie::ApproximateTokEnv, ParserFactory};
+    use mistralrs_mcp::{Function, Tool, ToolType};
+    use serde_json::json;
+
+    use super::{
+        detect_tool_call_parser, partial_tool_calls, read_partial_calls, tool_call_parser,
+        tool_call_parser_names, PartialToolCall,
+    };
+    use crate::ChatTemplate;
+
+    fn to_json(parser: &str, message: &str) -> String {
+        tool_call_parser(parser).unwrap().to_json(message).unwrap()
+    }
+
+    #[test]
+    fn rewrites_formats_to_json() {
+        let call = json!([{ "name": "get_weather", "arguments": { "city": "Paris" } }]);
+        for (parser, message) in [
+            (
+                "hermes",
+                "<tool_call>\n{\"name\": \"get_weather\", \"arguments\": {\"city\": \"Paris\"}}\n</tool_call>",
+            ),
+            (
+                "glm4",
+                "<tool_call>get_weather\n<arg_key>city</arg_key>\n<arg_value>Paris</arg_value>\n</tool_call>",
+            ),
+            (
+                "mistral",
+                "[TOOL_CALLS][{\"name\": \"get_weather\", \"arguments\": {\"city\": \"Paris\"}}]",
+            ),
+            ("mistral_v11", "[TOOL_CALLS]get_weather[ARGS]{\"city\": \"Paris\"}"),
+            (
+                "deepseek",
+                "<｜tool▁calls▁begin｜><｜tool▁call▁begin｜>function<｜tool▁sep｜>get_weather\n```json\n{\"city\": \"Paris\"}\n```<｜tool▁call▁end｜><｜tool▁calls▁end｜>",
+            ),
+            ("gemma", "```tool_code\nget_weather(city='Paris')\n```"),
+        ] {
+            let json = serde_json::from_str::<serde_json::Value>(&to_json(parser, message));
+            assert_eq!(json.ok(), Some(call.clone()), "{parser}");
+        }
+    }
+
+    #[test]
+    fn reads_incomplete_calls() {
+        let parser = tool_call_parser("mistral_v11").unwrap();
+        assert_eq!(
+            partial_tool_calls(&*parser, "[TOOL_CALLS]get_weather[ARGS]{\"city\": \"Pa"),
+            vec![PartialToolCall {
+                name: Some("get_weather".to_string()),
+                arguments: "{\"city\": \"Pa".to_string(),
+                complete: false,
+            }]
+        );
+
+        let json = r#"[{"name": "a", "arguments": {"x": [1, 2]}}, {"arguments": {}, "name": "b"}]"#;
+        let calls = read_partial_calls(json);
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].arguments, r#"{"x": [1, 2]}"#);
+        assert_eq!(calls[1].name.as_deref(), Some("b"));
+        assert!(calls.iter().all(|call| call.complete));
+    }
+
+    #[test]
+    fn builds_grammars() {
+        let tool = Tool {
+            tp: ToolType::Function,
+            function: Function {
+                description: None,
+                name: "get_weather".to_string(),
+                parameters: Some(HashMap::from([("type".to_string(), json!("object"))])),
+            },
+        };
+        let literal = |text: &str| serde_json::to_string(text).unwrap();
+        let grammar = tool_call_parser("qwen")
+            .unwrap()
+            .grammar(&[&tool], &literal)
+            .unwrap();
+        assert!(
+            grammar.starts_with("start: \"<tool_call>\" \"\\n\" call \"\\n\" \"</tool_call>\"\n")
+        );
+        let grammar = tool_call_parser("deepseek")
+            .unwrap()
+            .grammar(&[&tool], &literal)
+            .unwrap();
+        assert!(grammar.contains("call: call_0\ncall_0: "));
+        assert!(tool_call_parser("gemma")
+            .unwrap()
+            .grammar(&[&tool], &literal)
+            .is_none());
+    }
+
+    #[test]
+    fn detects_gemma_by_its_marker() {
+        let detect = |template: &str| {
+            let template: ChatTemplate =
+                serde_json::from_value(json!({ "chat_template": template })).unwrap();
+            detect_tool_call_parser(Some(&template)).name()
+        };
+        assert_eq!(detect("{{ '```tool_code\\n' + call + '\\n```' }}"), "gemma");
+        assert_eq!(
+            detect("{% if message.tool_code %}{{ message.tool_code }}{% endif %}"),
+            "auto"
+        );
+    }
+
+    #[test]
+    fn grammars_compile_with_llguidance() {
+        let tool = |name: &str| Tool {
+            tp: ToolType::Function,
+            function: Function {
+                description: None,
+                name: name.to_string(),
+                parameters: Some(HashMap::from([
+                    ("type".to_string(), json!("object")),
+                    (
+                        "properties".to_string(),
+                        json!({ "city": { "type": "string" } }),
+                    ),
+                    ("required".to_string(), json!(["city"])),
+                ])),
+            },
+        };
+        let (weather, time) = (tool("get_weather"), tool("get_time"));
+        let literal = |text: &str| serde_json::to_string(text).unwrap();
+        let factory = ParserFactory::new_simple(&ApproximateTokEnv::single_byte_env()).unwrap();
+        for name in tool_call_parser_names() {
+            let parser = tool_call_parser(name).unwrap();
+            for tools in [vec![&weather], vec![&weather, &time]] {
+                let Some(grammar) = parser.grammar(&tools, &literal) else {
+                    continue;
+                };
+                if let Err(e) = factory.create_parser(TopLevelGrammar::from_lark(grammar.clone())) {
+                    panic!("The `{name}` grammar does not compile: {e}\n{grammar}");
+                }
+            }
+        }
+    }
+}
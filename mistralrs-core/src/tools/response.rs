@@ -27,6 +27,72 @@ pub struct ToolCallResponse {
     pub function: CalledFunction,
 }
 
+/// The function of a [`ToolCallDelta`].
+#[cfg_attr(feature = "pyo3_macros", pyo3::pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct FunctionDelta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub arguments: String,
+}
+
+/// A fragment of a tool call in a streaming response.
+///
+/// The first fragment of a call has its id, type and function name, and the JSON of the arguments is split
+/// across the fragments with the same `index`.
+#[cfg_attr(feature = "pyo3_macros", pyo3::pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct ToolCallDelta {
+    pub index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub tp: Option<ToolCallType>,
+    pub function: FunctionDelta,
+}
+
+impl ToolCallDelta {
+    /// Merge this fragment into the tool calls received so far.
+    pub fn accumulate(self, calls: &mut Vec<ToolCallResponse>) {
+        match calls.iter_mut().find(|call| call.index == self.index) {
+            Some(call) => {
+                if let Some(id) = self.id {
+                    call.id = id;
+                }
+                if let Some(name) = self.function.name {
+                    call.function.name = name;
+                }
+                call.function.arguments.push_str(&self.function.arguments);
+            }
+            None => calls.push(ToolCallResponse {
+                index: self.index,
+                id: self.id.unwrap_or_default(),
+                tp: ToolCallType::Function,
+                function: CalledFunction {
+                    name: self.function.name.unwrap_or_default(),
+                    arguments: self.function.arguments,
+                },
+            }),
+        }
+    }
+}
+
+impl From<ToolCallResponse> for ToolCallDelta {
+    fn from(call: ToolCallResponse) -> Self {
+        Self {
+            index: call.index,
+            id: Some(call.id),
+            tp: Some(call.tp),
+            function: FunctionDelta {
+                name: Some(call.function.name),
+                arguments: call.function.arguments,
+            },
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -46,4 +112,36 @@ mod tests {
         let json = serde_json::to_value(&resp).unwrap();
         assert_eq!(json.get("index").and_then(|v| v.as_u64()), Some(0));
     }
+
+    #[test]
+    fn accumulates_deltas() {
+        let deltas = [
+            (0, Some("call-1"), Some("foo"), ""),
+            (0, None, None, "{\"a\": "),
+            (1, Some("call-2"), Some("bar"), "{}"),
+            (0, None, None, "1}"),
+        ];
+        let mut calls = Vec::new();
+        for (index, id, name, arguments) in deltas {
+            let delta = ToolCallDelta {
+                index,
+                id: id.map(ToString::to_string),
+                tp: id.map(|_| ToolCallType::Function),
+                function: FunctionDelta {
+                    name: name.map(ToString::to_string),
+                    arguments: arguments.to_string(),
+                },
+            };
+            if id.is_none() {
+                let json = serde_json::to_value(&delta).unwrap();
+                assert!(json.get("id").is_none() && json["function"].get("name").is_none());
+            }
+            delta.accumulate(&mut calls);
+        }
+
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].id, "call-1");
+        assert_eq!(calls[0].function.arguments, "{\"a\": 1}");
+        assert_eq!(calls[1].function.name, "bar");
+    }
 }
@@ -1,6 +1,6 @@
 use candle_core::{Device, DeviceLocation};
 use tracing::level_filters::LevelFilter;
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
 use crate::DEBUG;
 
@@ -24,7 +24,12 @@ pub fn initialize_logging() {
             .from_env_lossy()
             // disable info (and below) logs from symphonia
             .add_directive("symphonia=warn".parse().unwrap());
-        let _ = tracing_subscriber::fmt().with_env_filter(filter).try_init();
+        let subscriber = tracing_subscriber::registry()
+            .with(filter)
+            .with(tracing_subscriber::fmt::layer());
+        #[cfg(feature = "otel")]
+        let subscriber = subscriber.with(crate::telemetry::otlp_layer());
+        let _ = subscriber.try_init();
     });
 }
 
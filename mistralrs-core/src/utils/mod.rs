@@ -123,6 +123,7 @@ macro_rules! handle_pipeline_forward_error {
                                 reasoning_content: None,
                             },
                             logprobs: None,
+                            stop_sequence: None,
                         };
                         seq.add_choice_to_group(choice);
                     } else {
@@ -142,12 +143,13 @@ macro_rules! handle_pipeline_forward_error {
                     if group.is_chat {
                         let partial_completion_response = ChatCompletionResponse {
                             id: seq.id().to_string(),
-                            choices: group.get_choices().to_vec(),
+                            choices: group.get_choices(),
                             created: seq.creation_time(),
                             model: pipeline_name.clone(),
                             system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
                             object: "chat.completion".to_string(),
                             usage: group.get_usage(),
+                            prompt_logprobs: None,
                         };
 
                         seq.responder()
@@ -166,6 +168,7 @@ macro_rules! handle_pipeline_forward_error {
                             system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
                             object: "text_completion".to_string(),
                             usage: group.get_usage(),
+                            prompt_logprobs: None,
                         };
 
                         seq.responder()
@@ -219,18 +222,12 @@ macro_rules! serde_default_fn {
     };
 }
 
-/// `true` if built with CUDA (requires Unix) /Metal
-#[cfg(any(all(feature = "cuda", target_family = "unix"), feature = "metal"))]
+/// `true` if PagedAttention kernels are available. There is a CPU implementation in addition to the CUDA (requires Unix)
+/// and Metal ones, so this is always the case.
 pub const fn paged_attn_supported() -> bool {
     true
 }
 
-/// `true` if built with CUDA (requires Unix) /Metal
-#[cfg(not(any(all(feature = "cuda", target_family = "unix"), feature = "metal")))]
-pub const fn paged_attn_supported() -> bool {
-    false
-}
-
 /// `true` if built with the `flash-attn` or `flash-attn-v3` features, false otherwise.
 #[cfg(not(any(feature = "flash-attn", feature = "flash-attn-v3")))]
 pub const fn using_flash_attn() -> bool {
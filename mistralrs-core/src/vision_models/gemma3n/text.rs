@@ -397,11 +397,20 @@ impl Attention {
         {
             let shared_cache = &kv_caches[kv_shared_layer_index];
             // Cast device because kv cache on prev layer might be different device
+            // Cast dtype because quantized kv caches are read back in F32
             // https://github.com/EricLBuehler/mistral.rs/pull/1650#issuecomment-3393222444
             (
                 (
-                    shared_cache.k()?.unwrap().to_device(q.device())?,
-                    shared_cache.v()?.unwrap().to_device(q.device())?,
+                    shared_cache
+                        .k()?
+                        .unwrap()
+                        .to_device(q.device())?
+                        .to_dtype(q.dtype())?,
+                    shared_cache
+                        .v()?
+                        .unwrap()
+                        .to_device(q.device())?
+                        .to_dtype(q.dtype())?,
                 ),
                 true,
             )
@@ -1,12 +1,22 @@
 use crate::tools::{Function, Tool, ToolCallback, ToolCallbackWithTool, ToolType};
-use crate::transport::{HttpTransport, McpTransport, ProcessTransport, WebSocketTransport};
-use crate::types::McpToolResult;
-use crate::{McpClientConfig, McpServerConfig, McpServerSource, McpToolInfo};
+use crate::transport::{
+    HttpTransport, McpNotificationHandler, McpRequestHandler, McpTransport, ProcessTransport,
+    WebSocketTransport,
+};
+use crate::types::{
+    McpCreateMessageRequest, McpCreateMessageResult, McpPrompt, McpPromptResult,
+    McpResourceTemplate, McpToolResult,
+};
+use crate::{
+    McpClientConfig, McpPromptInfo, McpResourceInfo, McpResourceTemplateInfo, McpSamplingConfig,
+    McpServerConfig, McpServerSource, McpToolInfo,
+};
 use anyhow::Result;
+use futures_util::future::BoxFuture;
 use rust_mcp_schema::Resource;
 use serde_json::Value;
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock, Weak};
 use std::time::Duration;
 use tokio::sync::Semaphore;
 
@@ -31,8 +41,164 @@ pub trait McpServerConnection: Send + Sync {
     /// Read a resource from this server
     async fn read_resource(&self, uri: &str) -> Result<String>;
 
+    /// List available resource templates from this server
+    async fn list_resource_templates(&self) -> Result<Vec<McpResourceTemplate>>;
+
+    /// List available prompts from this server
+    async fn list_prompts(&self) -> Result<Vec<McpPrompt>>;
+
+    /// Get a prompt from this server, filled in with the given arguments
+    async fn get_prompt(
+        &self,
+        name: &str,
+        arguments: HashMap<String, String>,
+    ) -> Result<McpPromptResult>;
+
     /// Check if the connection is healthy
     async fn ping(&self) -> Result<()>;
+
+    /// Set the handler called with the notifications this server sends
+    fn set_notification_handler(&self, handler: McpNotificationHandler);
+}
+
+/// Generates the messages requested by MCP servers through `sampling/createMessage`
+#[async_trait::async_trait]
+pub trait McpSamplingHandler: Send + Sync {
+    /// Generate a message for a request of the given server
+    async fn create_message(
+        &self,
+        server_id: &str,
+        request: McpCreateMessageRequest,
+    ) -> Result<McpCreateMessageResult>;
+}
+
+/// Hook called with the server ID and each sampling request before it is served, returning the
+/// future of whether to serve it, so that it can wait for the user
+pub type McpSamplingApproval =
+    Arc<dyn Fn(&str, &McpCreateMessageRequest) -> BoxFuture<'static, bool> + Send + Sync>;
+
+/// Serves the sampling requests of the connected servers, once a handler is set
+struct McpSampling {
+    config: McpSamplingConfig,
+    handler: RwLock<Option<Arc<dyn McpSamplingHandler>>>,
+    approval: RwLock<Option<McpSamplingApproval>>,
+}
+
+impl McpSampling {
+    /// Check a request is allowed and approved, then generate its message with the handler
+    async fn create_message(&self, server_id: &str, params: Value) -> Result<Value> {
+        let mut request: McpCreateMessageRequest = serde_json::from_value(params)?;
+
+        if let Some(allowed_servers) = &self.config.allowed_servers {
+            if !allowed_servers.iter().any(|id| id == server_id) {
+                anyhow::bail!("Sampling is not allowed for MCP server `{server_id}`");
+            }
+        }
+        let approval = self.approval.read().unwrap().clone();
+        if let Some(approval) = approval {
+            if !approval(server_id, &request).await {
+                anyhow::bail!("The sampling request was rejected");
+            }
+        }
+        let handler =
+            self.handler.read().unwrap().clone().ok_or_else(|| {
+                anyhow::anyhow!("No model is available to serve sampling requests")
+            })?;
+
+        if let Some(max_tokens) = self.config.max_tokens {
+            request.max_tokens = request.max_tokens.min(max_tokens);
+        }
+        let result = handler.create_message(server_id, request).await?;
+        Ok(serde_json::to_value(result)?)
+    }
+
+    /// Handler of the requests of one server, serving `sampling/createMessage`
+    fn request_handler(self: &Arc<Self>, server_id: String) -> McpRequestHandler {
+        let sampling = Arc::clone(self);
+        Arc::new(move |method, params| {
+            if method != "sampling/createMessage" {
+                return None;
+            }
+            let sampling = Arc::clone(&sampling);
+            let server_id = server_id.clone();
+            Some(Box::pin(async move {
+                sampling.create_message(&server_id, params).await
+            }))
+        })
+    }
+}
+
+/// Capabilities the client advertises when initializing a connection
+fn client_capabilities(sampling: bool) -> Value {
+    let mut capabilities = serde_json::json!({
+        "tools": {}
+    });
+    if sampling {
+        capabilities["sampling"] = serde_json::json!({});
+    }
+    capabilities
+}
+
+async fn request_resource_templates(
+    transport: &dyn McpTransport,
+) -> Result<Vec<McpResourceTemplate>> {
+    let result = transport
+        .send_request("resources/templates/list", Value::Null)
+        .await?;
+
+    let templates = result
+        .get("resourceTemplates")
+        .cloned()
+        .ok_or_else(|| anyhow::anyhow!("Invalid resource templates response format"))?;
+
+    Ok(serde_json::from_value(templates)?)
+}
+
+async fn request_prompts(transport: &dyn McpTransport) -> Result<Vec<McpPrompt>> {
+    let result = transport.send_request("prompts/list", Value::Null).await?;
+
+    let prompts = result
+        .get("prompts")
+        .cloned()
+        .ok_or_else(|| anyhow::anyhow!("Invalid prompts response format"))?;
+
+    Ok(serde_json::from_value(prompts)?)
+}
+
+async fn request_prompt(
+    transport: &dyn McpTransport,
+    name: &str,
+    arguments: HashMap<String, String>,
+) -> Result<McpPromptResult> {
+    let params = serde_json::json!({
+        "name": name,
+        "arguments": arguments
+    });
+
+    let result = transport.send_request("prompts/get", params).await?;
+
+    Ok(serde_json::from_value(result)?)
+}
+
+/// Tools registered from the connected MCP servers
+#[derive(Default)]
+struct McpToolRegistry {
+    /// Registry of discovered tools from all connected servers
+    tools: HashMap<String, McpToolInfo>,
+    /// Legacy tool callbacks for backward compatibility
+    tool_callbacks: HashMap<String, Arc<ToolCallback>>,
+    /// Tool callbacks with associated Tool definitions for automatic tool calling
+    tool_callbacks_with_tools: HashMap<String, ToolCallbackWithTool>,
+}
+
+/// Registers the tools of one MCP server, and registers them again when the server's tool list changes
+#[derive(Clone)]
+struct ToolRegistrar {
+    server_id: String,
+    tool_prefix: Option<String>,
+    registry: Weak<RwLock<McpToolRegistry>>,
+    concurrency_semaphore: Arc<Semaphore>,
+    timeout_duration: Duration,
 }
 
 /// MCP client that manages connections to multiple MCP servers
@@ -46,6 +212,9 @@ pub trait McpServerConnection: Send + Sync {
 /// - **Multi-server Management**: Connects to and manages multiple MCP servers simultaneously
 /// - **Automatic Tool Discovery**: Discovers available tools from connected servers
 /// - **Tool Registration**: Converts MCP tools to internal Tool format for seamless integration
+/// - **Live Tool Updates**: Registers tools again when a server notifies that its tool list changed
+/// - **Prompts and Resources**: Lists and fetches the prompts, resources and resource templates of all servers
+/// - **Sampling**: Serves the `sampling/createMessage` requests of servers with an [`McpSamplingHandler`]
 /// - **Connection Pooling**: Maintains persistent connections for efficient tool execution
 /// - **Error Handling**: Robust error handling with proper cleanup and reconnection logic
 ///
@@ -73,30 +242,56 @@ pub struct McpClient {
     config: McpClientConfig,
     /// Active connections to MCP servers, indexed by server ID
     servers: HashMap<String, Arc<dyn McpServerConnection>>,
-    /// Registry of discovered tools from all connected servers
-    tools: HashMap<String, McpToolInfo>,
-    /// Legacy tool callbacks for backward compatibility
-    tool_callbacks: HashMap<String, Arc<ToolCallback>>,
-    /// Tool callbacks with associated Tool definitions for automatic tool calling
-    tool_callbacks_with_tools: HashMap<String, ToolCallbackWithTool>,
+    /// Tools discovered from all connected servers, updated when a server's tool list changes
+    registry: Arc<RwLock<McpToolRegistry>>,
     /// Semaphore to control maximum concurrent tool calls
     concurrency_semaphore: Arc<Semaphore>,
+    /// Serves the sampling requests of the servers, if enabled in the configuration
+    sampling: Option<Arc<McpSampling>>,
 }
 
 impl McpClient {
     /// Create a new MCP client with the given configuration
     pub fn new(config: McpClientConfig) -> Self {
         let max_concurrent = config.max_concurrent_calls.unwrap_or(10);
+        let sampling = config.sampling.clone().map(|config| {
+            Arc::new(McpSampling {
+                config,
+                handler: RwLock::new(None),
+                approval: RwLock::new(None),
+            })
+        });
         Self {
             config,
             servers: HashMap::new(),
-            tools: HashMap::new(),
-            tool_callbacks: HashMap::new(),
-            tool_callbacks_with_tools: HashMap::new(),
+            registry: Arc::new(RwLock::new(McpToolRegistry::default())),
             concurrency_semaphore: Arc::new(Semaphore::new(max_concurrent)),
+            sampling,
+        }
+    }
+
+    /// Set the handler generating the messages requested by the servers through sampling
+    ///
+    /// Sampling requests are rejected until a handler is set, or if sampling is not enabled in
+    /// the configuration.
+    pub fn set_sampling_handler(&self, handler: Arc<dyn McpSamplingHandler>) {
+        if let Some(sampling) = &self.sampling {
+            *sampling.handler.write().unwrap() = Some(handler);
+        }
+    }
+
+    /// Set the hook deciding whether to serve each sampling request, e.g. by asking the user
+    pub fn set_sampling_approval(&self, approval: McpSamplingApproval) {
+        if let Some(sampling) = &self.sampling {
+            *sampling.approval.write().unwrap() = Some(approval);
         }
     }
 
+    /// The sampling configuration, if sampling is enabled
+    pub fn sampling_config(&self) -> Option<&McpSamplingConfig> {
+        self.config.sampling.as_ref()
+    }
+
     /// Initialize connections to all configured servers
     pub async fn initialize(&mut self) -> Result<()> {
         for server_config in &self.config.servers {
@@ -114,18 +309,120 @@ impl McpClient {
     }
 
     /// Get tool callbacks that can be used with the existing tool calling system
-    pub fn get_tool_callbacks(&self) -> &HashMap<String, Arc<ToolCallback>> {
-        &self.tool_callbacks
+    ///
+    /// Returns a snapshot, as the tools are updated when a server's tool list changes.
+    pub fn get_tool_callbacks(&self) -> HashMap<String, Arc<ToolCallback>> {
+        self.registry.read().unwrap().tool_callbacks.clone()
     }
 
     /// Get tool callbacks with their associated Tool definitions
-    pub fn get_tool_callbacks_with_tools(&self) -> &HashMap<String, ToolCallbackWithTool> {
-        &self.tool_callbacks_with_tools
+    ///
+    /// Returns a snapshot, as the tools are updated when a server's tool list changes.
+    pub fn get_tool_callbacks_with_tools(&self) -> HashMap<String, ToolCallbackWithTool> {
+        self.registry
+            .read()
+            .unwrap()
+            .tool_callbacks_with_tools
+            .clone()
     }
 
     /// Get discovered tools information
-    pub fn get_tools(&self) -> &HashMap<String, McpToolInfo> {
-        &self.tools
+    ///
+    /// Returns a snapshot, as the tools are updated when a server's tool list changes.
+    pub fn get_tools(&self) -> HashMap<String, McpToolInfo> {
+        self.registry.read().unwrap().tools.clone()
+    }
+
+    /// List the prompts of all connected servers
+    ///
+    /// Servers which fail to list their prompts, e.g. because they offer none, are skipped.
+    pub async fn list_prompts(&self) -> Vec<McpPromptInfo> {
+        let mut prompts = Vec::new();
+        for connection in self.servers.values() {
+            match connection.list_prompts().await {
+                Ok(server_prompts) => {
+                    prompts.extend(server_prompts.into_iter().map(|prompt| McpPromptInfo {
+                        prompt,
+                        server_id: connection.server_id().to_string(),
+                        server_name: connection.server_name().to_string(),
+                    }))
+                }
+                Err(e) => tracing::debug!(
+                    "Failed to list prompts of MCP server `{}`: {e}",
+                    connection.server_name()
+                ),
+            }
+        }
+        prompts
+    }
+
+    /// Get a prompt from a server, filled in with the given arguments
+    pub async fn get_prompt(
+        &self,
+        server_id: &str,
+        name: &str,
+        arguments: HashMap<String, String>,
+    ) -> Result<McpPromptResult> {
+        self.server(server_id)?.get_prompt(name, arguments).await
+    }
+
+    /// List the resources of all connected servers
+    ///
+    /// Servers which fail to list their resources, e.g. because they offer none, are skipped.
+    pub async fn list_resources(&self) -> Vec<McpResourceInfo> {
+        let mut resources = Vec::new();
+        for connection in self.servers.values() {
+            match connection.list_resources().await {
+                Ok(server_resources) => resources.extend(server_resources.into_iter().map(
+                    |resource| McpResourceInfo {
+                        resource,
+                        server_id: connection.server_id().to_string(),
+                        server_name: connection.server_name().to_string(),
+                    },
+                )),
+                Err(e) => tracing::debug!(
+                    "Failed to list resources of MCP server `{}`: {e}",
+                    connection.server_name()
+                ),
+            }
+        }
+        resources
+    }
+
+    /// List the resource templates of all connected servers
+    ///
+    /// Servers which fail to list their resource templates, e.g. because they offer none, are skipped.
+    pub async fn list_resource_templates(&self) -> Vec<McpResourceTemplateInfo> {
+        let mut templates = Vec::new();
+        for connection in self.servers.values() {
+            match connection.list_resource_templates().await {
+                Ok(server_templates) => {
+                    templates.extend(server_templates.into_iter().map(|template| {
+                        McpResourceTemplateInfo {
+                            template,
+                            server_id: connection.server_id().to_string(),
+                            server_name: connection.server_name().to_string(),
+                        }
+                    }))
+                }
+                Err(e) => tracing::debug!(
+                    "Failed to list resource templates of MCP server `{}`: {e}",
+                    connection.server_name()
+                ),
+            }
+        }
+        templates
+    }
+
+    /// Read the text of a resource from a server
+    pub async fn read_resource(&self, server_id: &str, uri: &str) -> Result<String> {
+        self.server(server_id)?.read_resource(uri).await
+    }
+
+    fn server(&self, server_id: &str) -> Result<&Arc<dyn McpServerConnection>> {
+        self.servers
+            .get(server_id)
+            .ok_or_else(|| anyhow::anyhow!("Unknown MCP server `{server_id}`"))
     }
 
     /// Create connection based on server source type
@@ -133,6 +430,10 @@ impl McpClient {
         &self,
         config: &McpServerConfig,
     ) -> Result<Arc<dyn McpServerConnection>> {
+        let request_handler = self
+            .sampling
+            .as_ref()
+            .map(|sampling| sampling.request_handler(config.id.clone()));
         match &config.source {
             McpServerSource::Http {
                 url,
@@ -151,6 +452,7 @@ impl McpClient {
                     url.clone(),
                     *timeout_secs,
                     Some(merged_headers),
+                    request_handler,
                 )
                 .await?;
                 Ok(Arc::new(connection))
@@ -168,6 +470,7 @@ impl McpClient {
                     args.clone(),
                     work_dir.clone(),
                     env.clone(),
+                    request_handler,
                 )
                 .await?;
                 Ok(Arc::new(connection))
@@ -189,6 +492,7 @@ impl McpClient {
                     url.clone(),
                     *timeout_secs,
                     Some(merged_headers),
+                    request_handler,
                 )
                 .await?;
                 Ok(Arc::new(connection))
@@ -197,9 +501,11 @@ impl McpClient {
     }
 
     /// Discover tools from all connected servers and register them
+    ///
+    /// Each server's tools are registered again whenever it sends a
+    /// `notifications/tools/list_changed` notification.
     async fn discover_and_register_tools(&mut self) -> Result<()> {
         for (server_id, connection) in &self.servers {
-            let tools = connection.list_tools().await?;
             let server_config = self
                 .config
                 .servers
@@ -207,79 +513,38 @@ impl McpClient {
                 .find(|s| &s.id == server_id)
                 .ok_or_else(|| anyhow::anyhow!("Server config not found for {}", server_id))?;
 
-            for tool in tools {
-                let tool_name = if let Some(prefix) = &server_config.tool_prefix {
-                    format!("{}_{}", prefix, tool.name)
-                } else {
-                    tool.name.clone()
+            let registrar = ToolRegistrar {
+                server_id: server_id.clone(),
+                tool_prefix: server_config.tool_prefix.clone(),
+                registry: Arc::downgrade(&self.registry),
+                concurrency_semaphore: Arc::clone(&self.concurrency_semaphore),
+                timeout_duration: Duration::from_secs(self.config.tool_timeout_secs.unwrap_or(30)),
+            };
+            registrar.register_tools(connection).await?;
+
+            // The handler is owned by the connection, so it must not keep the connection alive
+            let weak_connection = Arc::downgrade(connection);
+            connection.set_notification_handler(Arc::new(move |method, _params| {
+                if method != "notifications/tools/list_changed" {
+                    return;
+                }
+                let Some(connection) = weak_connection.upgrade() else {
+                    return;
                 };
-
-                // Create tool callback that calls the MCP server with timeout and concurrency controls
-                let connection_clone = Arc::clone(connection);
-                let original_tool_name = tool.name.clone();
-                let semaphore_clone = Arc::clone(&self.concurrency_semaphore);
-                let timeout_duration =
-                    Duration::from_secs(self.config.tool_timeout_secs.unwrap_or(30));
-
-                let callback: Arc<ToolCallback> = Arc::new(move |called_function| {
-                    let connection = Arc::clone(&connection_clone);
-                    let tool_name = original_tool_name.clone();
-                    let semaphore = Arc::clone(&semaphore_clone);
-                    let arguments: serde_json::Value =
-                        serde_json::from_str(&called_function.arguments)?;
-
-                    // Use tokio::task::spawn_blocking to handle the async-to-sync bridge
-                    let rt = tokio::runtime::Handle::current();
-                    std::thread::spawn(move || {
-                        rt.block_on(async move {
-                            // Acquire semaphore permit for concurrency control
-                            let _permit = semaphore.acquire().await.map_err(|_| {
-                                anyhow::anyhow!("Failed to acquire concurrency permit")
-                            })?;
-
-                            // Execute tool call with timeout
-                            match tokio::time::timeout(
-                                timeout_duration,
-                                connection.call_tool(&tool_name, arguments),
-                            )
-                            .await
-                            {
-                                Ok(result) => result,
-                                Err(_) => Err(anyhow::anyhow!(
-                                    "Tool call timed out after {} seconds",
-                                    timeout_duration.as_secs()
-                                )),
-                            }
-                        })
-                    })
-                    .join()
-                    .map_err(|_| anyhow::anyhow!("Tool call thread panicked"))?
+                let registrar = registrar.clone();
+                tokio::spawn(async move {
+                    match registrar.register_tools(&connection).await {
+                        Ok(()) => tracing::info!(
+                            "Refreshed tools of MCP server `{}`",
+                            connection.server_name()
+                        ),
+                        Err(e) => tracing::warn!(
+                            "Failed to refresh tools of MCP server `{}`: {e}",
+                            connection.server_name()
+                        ),
+                    }
                 });
-
-                // Convert MCP tool schema to Tool definition
-                let function_def = Function {
-                    name: tool_name.clone(),
-                    description: tool.description.clone(),
-                    parameters: Self::convert_mcp_schema_to_parameters(&tool.input_schema),
-                };
-
-                let tool_def = Tool {
-                    tp: ToolType::Function,
-                    function: function_def,
-                };
-
-                // Store in both collections for backward compatibility
-                self.tool_callbacks
-                    .insert(tool_name.clone(), callback.clone());
-                self.tool_callbacks_with_tools.insert(
-                    tool_name.clone(),
-                    ToolCallbackWithTool {
-                        callback,
-                        tool: tool_def,
-                    },
-                );
-                self.tools.insert(tool_name, tool);
-            }
+            }));
         }
 
         Ok(())
@@ -322,6 +587,122 @@ impl McpClient {
     }
 }
 
+impl ToolRegistrar {
+    /// List the tools of the server and replace its previously registered tools with them
+    async fn register_tools(&self, connection: &Arc<dyn McpServerConnection>) -> Result<()> {
+        let tools = connection.list_tools().await?;
+
+        let mut registered = Vec::new();
+        for tool in tools {
+            let tool_name = if let Some(prefix) = &self.tool_prefix {
+                format!("{}_{}", prefix, tool.name)
+            } else {
+                tool.name.clone()
+            };
+
+            // Create tool callback that calls the MCP server with timeout and concurrency controls
+            let connection_clone = Arc::clone(connection);
+            let server_id_clone = self.server_id.clone();
+            let original_tool_name = tool.name.clone();
+            let semaphore_clone = Arc::clone(&self.concurrency_semaphore);
+            let timeout_duration = self.timeout_duration;
+
+            let callback: Arc<ToolCallback> = Arc::new(move |called_function| {
+                let connection = Arc::clone(&connection_clone);
+                let tool_name = original_tool_name.clone();
+                let semaphore = Arc::clone(&semaphore_clone);
+                let arguments: serde_json::Value =
+                    serde_json::from_str(&called_function.arguments)?;
+
+                // Create the span here so that it is a child of the caller's span
+                let span = tracing::info_span!(
+                    "mcp_call_tool",
+                    server = %server_id_clone,
+                    tool = %tool_name
+                );
+
+                // Use tokio::task::spawn_blocking to handle the async-to-sync bridge
+                let rt = tokio::runtime::Handle::current();
+                std::thread::spawn(move || {
+                    let _span = span.enter();
+                    rt.block_on(async move {
+                        // Acquire semaphore permit for concurrency control
+                        let _permit = semaphore
+                            .acquire()
+                            .await
+                            .map_err(|_| anyhow::anyhow!("Failed to acquire concurrency permit"))?;
+
+                        // Execute tool call with timeout
+                        match tokio::time::timeout(
+                            timeout_duration,
+                            connection.call_tool(&tool_name, arguments),
+                        )
+                        .await
+                        {
+                            Ok(result) => result,
+                            Err(_) => Err(anyhow::anyhow!(
+                                "Tool call timed out after {} seconds",
+                                timeout_duration.as_secs()
+                            )),
+                        }
+                    })
+                })
+                .join()
+                .map_err(|_| anyhow::anyhow!("Tool call thread panicked"))?
+            });
+
+            // Convert MCP tool schema to Tool definition
+            let function_def = Function {
+                name: tool_name.clone(),
+                description: tool.description.clone(),
+                parameters: McpClient::convert_mcp_schema_to_parameters(&tool.input_schema),
+            };
+
+            let tool_def = Tool {
+                tp: ToolType::Function,
+                function: function_def,
+            };
+
+            registered.push((tool_name, tool, callback, tool_def));
+        }
+
+        let Some(registry) = self.registry.upgrade() else {
+            return Ok(());
+        };
+        let mut registry = registry.write().unwrap();
+
+        // Drop the tools the server registered before, which it may have removed since
+        let stale = registry
+            .tools
+            .iter()
+            .filter(|(_, tool)| tool.server_id == self.server_id)
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        for name in stale {
+            registry.tools.remove(&name);
+            registry.tool_callbacks.remove(&name);
+            registry.tool_callbacks_with_tools.remove(&name);
+        }
+
+        for (tool_name, tool, callback, tool_def) in registered {
+            // Store in both collections for backward compatibility
+            registry
+                .tool_callbacks
+                .insert(tool_name.clone(), callback.clone());
+            registry.tool_callbacks_with_tools.insert(
+                tool_name.clone(),
+                ToolCallbackWithTool {
+                    callback,
+                    tool: tool_def,
+                },
+            );
+            registry.tools.insert(tool_name, tool);
+        }
+
+        Ok(())
+    }
+}
+
 /// HTTP-based MCP server connection
 pub struct HttpMcpConnection {
     server_id: String,
@@ -336,6 +717,7 @@ impl HttpMcpConnection {
         url: String,
         timeout_secs: Option<u64>,
         headers: Option<HashMap<String, String>>,
+        request_handler: Option<McpRequestHandler>,
     ) -> Result<Self> {
         let transport = HttpTransport::new(url, timeout_secs, headers)?;
 
@@ -345,18 +727,22 @@ impl HttpMcpConnection {
             transport: Arc::new(transport),
         };
 
+        // Serve the server's requests from the start, advertising sampling if they are served
+        let sampling = request_handler.is_some();
+        if let Some(request_handler) = request_handler {
+            connection.transport.set_request_handler(request_handler);
+        }
+
         // Initialize the connection
-        connection.initialize().await?;
+        connection.initialize(sampling).await?;
 
         Ok(connection)
     }
 
-    async fn initialize(&self) -> Result<()> {
+    async fn initialize(&self, sampling: bool) -> Result<()> {
         let init_params = serde_json::json!({
             "protocolVersion": "2025-03-26",
-            "capabilities": {
-                "tools": {}
-            },
+            "capabilities": client_capabilities(sampling),
             "clientInfo": {
                 "name": "mistral.rs",
                 "version": "0.6.0"
@@ -480,11 +866,31 @@ impl McpServerConnection for HttpMcpConnection {
         Err(anyhow::anyhow!("No readable content found in resource"))
     }
 
+    async fn list_resource_templates(&self) -> Result<Vec<McpResourceTemplate>> {
+        request_resource_templates(self.transport.as_ref()).await
+    }
+
+    async fn list_prompts(&self) -> Result<Vec<McpPrompt>> {
+        request_prompts(self.transport.as_ref()).await
+    }
+
+    async fn get_prompt(
+        &self,
+        name: &str,
+        arguments: HashMap<String, String>,
+    ) -> Result<McpPromptResult> {
+        request_prompt(self.transport.as_ref(), name, arguments).await
+    }
+
     async fn ping(&self) -> Result<()> {
         // Send a simple ping to check if the server is responsive
         self.transport.send_request("ping", Value::Null).await?;
         Ok(())
     }
+
+    fn set_notification_handler(&self, handler: McpNotificationHandler) {
+        self.transport.set_notification_handler(handler);
+    }
 }
 
 /// Process-based MCP server connection
@@ -502,6 +908,7 @@ impl ProcessMcpConnection {
         args: Vec<String>,
         work_dir: Option<String>,
         env: Option<HashMap<String, String>>,
+        request_handler: Option<McpRequestHandler>,
     ) -> Result<Self> {
         let transport = ProcessTransport::new(command, args, work_dir, env).await?;
 
@@ -511,18 +918,22 @@ impl ProcessMcpConnection {
             transport: Arc::new(transport),
         };
 
+        // Serve the server's requests from the start, advertising sampling if they are served
+        let sampling = request_handler.is_some();
+        if let Some(request_handler) = request_handler {
+            connection.transport.set_request_handler(request_handler);
+        }
+
         // Initialize the connection
-        connection.initialize().await?;
+        connection.initialize(sampling).await?;
 
         Ok(connection)
     }
 
-    async fn initialize(&self) -> Result<()> {
+    async fn initialize(&self, sampling: bool) -> Result<()> {
         let init_params = serde_json::json!({
             "protocolVersion": "2025-03-26",
-            "capabilities": {
-                "tools": {}
-            },
+            "capabilities": client_capabilities(sampling),
             "clientInfo": {
                 "name": "mistral.rs",
                 "version": "0.6.0"
@@ -646,11 +1057,31 @@ impl McpServerConnection for ProcessMcpConnection {
         Err(anyhow::anyhow!("No readable content found in resource"))
     }
 
+    async fn list_resource_templates(&self) -> Result<Vec<McpResourceTemplate>> {
+        request_resource_templates(self.transport.as_ref()).await
+    }
+
+    async fn list_prompts(&self) -> Result<Vec<McpPrompt>> {
+        request_prompts(self.transport.as_ref()).await
+    }
+
+    async fn get_prompt(
+        &self,
+        name: &str,
+        arguments: HashMap<String, String>,
+    ) -> Result<McpPromptResult> {
+        request_prompt(self.transport.as_ref(), name, arguments).await
+    }
+
     async fn ping(&self) -> Result<()> {
         // Send a simple ping to check if the server is responsive
         self.transport.send_request("ping", Value::Null).await?;
         Ok(())
     }
+
+    fn set_notification_handler(&self, handler: McpNotificationHandler) {
+        self.transport.set_notification_handler(handler);
+    }
 }
 
 /// WebSocket-based MCP server connection
@@ -667,6 +1098,7 @@ impl WebSocketMcpConnection {
         url: String,
         timeout_secs: Option<u64>,
         headers: Option<HashMap<String, String>>,
+        request_handler: Option<McpRequestHandler>,
     ) -> Result<Self> {
         let transport = WebSocketTransport::new(url, timeout_secs, headers).await?;
 
@@ -676,18 +1108,22 @@ impl WebSocketMcpConnection {
             transport: Arc::new(transport),
         };
 
+        // Serve the server's requests from the start, advertising sampling if they are served
+        let sampling = request_handler.is_some();
+        if let Some(request_handler) = request_handler {
+            connection.transport.set_request_handler(request_handler);
+        }
+
         // Initialize the connection
-        connection.initialize().await?;
+        connection.initialize(sampling).await?;
 
         Ok(connection)
     }
 
-    async fn initialize(&self) -> Result<()> {
+    async fn initialize(&self, sampling: bool) -> Result<()> {
         let init_params = serde_json::json!({
             "protocolVersion": "2025-03-26",
-            "capabilities": {
-                "tools": {}
-            },
+            "capabilities": client_capabilities(sampling),
             "clientInfo": {
                 "name": "mistral.rs",
                 "version": "0.6.0"
@@ -811,9 +1247,166 @@ impl McpServerConnection for WebSocketMcpConnection {
         Err(anyhow::anyhow!("No readable content found in resource"))
     }
 
+    async fn list_resource_templates(&self) -> Result<Vec<McpResourceTemplate>> {
+        request_resource_templates(self.transport.as_ref()).await
+    }
+
+    async fn list_prompts(&self) -> Result<Vec<McpPrompt>> {
+        request_prompts(self.transport.as_ref()).await
+    }
+
+    async fn get_prompt(
+        &self,
+        name: &str,
+        arguments: HashMap<String, String>,
+    ) -> Result<McpPromptResult> {
+        request_prompt(self.transport.as_ref(), name, arguments).await
+    }
+
     async fn ping(&self) -> Result<()> {
         // Send a simple ping to check if the server is responsive
         self.transport.send_request("ping", Value::Null).await?;
         Ok(())
     }
+
+    fn set_notification_handler(&self, handler: McpNotificationHandler) {
+        self.transport.set_notification_handler(handler);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        McpPrompt, McpPromptResult, McpResourceTemplate, McpServerConnection, McpToolRegistry,
+        ToolRegistrar,
+    };
+    use crate::transport::McpNotificationHandler;
+    use crate::McpToolInfo;
+    use anyhow::Result;
+    use rust_mcp_schema::Resource;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::time::Duration;
+    use tokio::sync::Semaphore;
+
+    /// A server which only lists its tools
+    struct ToolServer {
+        tools: Mutex<Vec<&'static str>>,
+    }
+
+    fn tool_info(name: &str, server_id: &str) -> McpToolInfo {
+        McpToolInfo {
+            name: name.to_string(),
+            description: None,
+            input_schema: serde_json::json!({}),
+            server_id: server_id.to_string(),
+            server_name: server_id.to_string(),
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl McpServerConnection for ToolServer {
+        fn server_id(&self) -> &str {
+            "server"
+        }
+
+        fn server_name(&self) -> &str {
+            "server"
+        }
+
+        async fn list_tools(&self) -> Result<Vec<McpToolInfo>> {
+            let tools = self.tools.lock().unwrap().clone();
+            Ok(tools
+                .into_iter()
+                .map(|name| tool_info(name, "server"))
+                .collect())
+        }
+
+        async fn call_tool(&self, _name: &str, _arguments: serde_json::Value) -> Result<String> {
+            anyhow::bail!("Not supported")
+        }
+
+        async fn list_resources(&self) -> Result<Vec<Resource>> {
+            Ok(Vec::new())
+        }
+
+        async fn read_resource(&self, _uri: &str) -> Result<String> {
+            anyhow::bail!("Not supported")
+        }
+
+        async fn list_resource_templates(&self) -> Result<Vec<McpResourceTemplate>> {
+            Ok(Vec::new())
+        }
+
+        async fn list_prompts(&self) -> Result<Vec<McpPrompt>> {
+            Ok(Vec::new())
+        }
+
+        async fn get_prompt(
+            &self,
+            _name: &str,
+            _arguments: HashMap<String, String>,
+        ) -> Result<McpPromptResult> {
+            anyhow::bail!("Not supported")
+        }
+
+        async fn ping(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn set_notification_handler(&self, _handler: McpNotificationHandler) {}
+    }
+
+    #[tokio::test]
+    async fn registers_changed_tools() {
+        let server = Arc::new(ToolServer {
+            tools: Mutex::new(vec!["a", "b"]),
+        });
+        let connection: Arc<dyn McpServerConnection> = server.clone();
+        let registry = Arc::new(RwLock::new(McpToolRegistry::default()));
+        registry
+            .write()
+            .unwrap()
+            .tools
+            .insert("other_a".to_string(), tool_info("a", "other"));
+        let registrar = ToolRegistrar {
+            server_id: "server".to_string(),
+            tool_prefix: Some("s".to_string()),
+            registry: Arc::downgrade(&registry),
+            concurrency_semaphore: Arc::new(Semaphore::new(1)),
+            timeout_duration: Duration::from_secs(1),
+        };
+        let names = |registry: &RwLock<McpToolRegistry>| {
+            let registry = registry.read().unwrap();
+            let mut names = registry.tools.keys().cloned().collect::<Vec<_>>();
+            let mut callbacks = registry
+                .tool_callbacks_with_tools
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>();
+            names.sort();
+            callbacks.sort();
+            (names, callbacks)
+        };
+
+        registrar.register_tools(&connection).await.unwrap();
+        assert_eq!(
+            names(&registry),
+            (
+                vec!["other_a".to_string(), "s_a".to_string(), "s_b".to_string()],
+                vec!["s_a".to_string(), "s_b".to_string()]
+            )
+        );
+
+        // After `notifications/tools/list_changed`, the removed tool is dropped
+        *server.tools.lock().unwrap() = vec!["b", "c"];
+        registrar.register_tools(&connection).await.unwrap();
+        assert_eq!(
+            names(&registry),
+            (
+                vec!["other_a".to_string(), "s_b".to_string(), "s_c".to_string()],
+                vec!["s_b".to_string(), "s_c".to_string()]
+            )
+        );
+    }
 }
@@ -16,7 +16,10 @@
 //! - **Automatic Tool Discovery**: Discovers and registers tools from connected MCP servers
 //! - **Bearer Token Authentication**: Supports authentication for secured MCP servers
 //! - **Concurrent Tool Execution**: Handles multiple tool calls efficiently
-//! - **Resource Access**: Access to MCP server resources like files and data
+//! - **Resource Access**: Access to MCP server resources like files and data, and resource templates
+//! - **Prompts**: Lists MCP server prompts and fills them in with arguments
+//! - **Live Tool Updates**: Refreshes a server's tools when it sends `notifications/tools/list_changed`
+//! - **Sampling**: Serves the `sampling/createMessage` requests of servers with a local model
 //! - **Tool Naming Prefix**: Avoid conflicts with customizable tool name prefixes
 //!
 //! # Transport Protocols
@@ -78,7 +81,9 @@
 //! ## Advanced Configuration
 //!
 //! ```rust,no_run
-//! use mistralrs_mcp::{McpClientConfig, McpServerConfig, McpServerSource, McpClient};
+//! use mistralrs_mcp::{
+//!     McpClient, McpClientConfig, McpSamplingConfig, McpServerConfig, McpServerSource,
+//! };
 //! use std::collections::HashMap;
 //!
 //! #[tokio::main]
@@ -133,6 +138,11 @@
 //!         auto_register_tools: true,
 //!         tool_timeout_secs: Some(30),
 //!         max_concurrent_calls: Some(5),
+//!         // Let the servers use the model through sampling, generating at most 512 tokens
+//!         sampling: Some(McpSamplingConfig {
+//!             max_tokens: Some(512),
+//!             ..Default::default()
+//!         }),
 //!     };
 //!     
 //!     // Initialize MCP client
@@ -152,9 +162,13 @@ pub mod tools;
 pub mod transport;
 pub mod types;
 
-pub use client::{McpClient, McpServerConnection};
+pub use client::{McpClient, McpSamplingApproval, McpSamplingHandler, McpServerConnection};
 pub use tools::{CalledFunction, Function, Tool, ToolCallback, ToolCallbackWithTool, ToolType};
-pub use types::McpToolResult;
+pub use types::{
+    McpContent, McpCreateMessageRequest, McpCreateMessageResult, McpModelHint, McpModelPreferences,
+    McpPrompt, McpPromptArgument, McpPromptMessage, McpPromptResult, McpResourceTemplate,
+    McpSamplingMessage, McpToolResult,
+};
 
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -233,6 +247,38 @@ pub struct McpClientConfig {
     /// Limits resource usage and prevents overwhelming servers with too many
     /// simultaneous requests. Defaults to 1 if not specified.
     pub max_concurrent_calls: Option<usize>,
+    /// Serve the `sampling/createMessage` requests of the servers with the local model
+    ///
+    /// When set, the client advertises the sampling capability, letting servers ask the
+    /// model to generate messages. Defaults to disabled if not specified.
+    #[serde(default)]
+    pub sampling: Option<McpSamplingConfig>,
+}
+
+/// Configuration for serving the `sampling/createMessage` requests of MCP servers
+///
+/// Servers use sampling to have the client's model generate messages for them, e.g. to
+/// summarize the output of a tool.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct McpSamplingConfig {
+    /// ID of the model serving the requests
+    ///
+    /// Defaults to the default model if not specified.
+    pub model: Option<String>,
+    /// Whether to serve a request with the first loaded model matching one of its model hints
+    ///
+    /// A hint matches the models whose ID contains it, ignoring case.
+    /// Defaults to true if not specified.
+    pub use_model_hints: bool,
+    /// Maximum number of tokens generated for a request
+    ///
+    /// Caps the `maxTokens` the server asks for. Defaults to no cap if not specified.
+    pub max_tokens: Option<usize>,
+    /// IDs of the servers allowed to send sampling requests
+    ///
+    /// Requests from other servers are rejected. Defaults to all servers if not specified.
+    pub allowed_servers: Option<Vec<String>>,
 }
 
 /// Configuration for an individual MCP server
@@ -299,6 +345,39 @@ pub struct McpToolInfo {
     pub server_name: String,
 }
 
+/// A prompt offered by an MCP server
+#[derive(Debug, Clone)]
+pub struct McpPromptInfo {
+    /// The prompt as reported by the MCP server
+    pub prompt: McpPrompt,
+    /// ID of the server this prompt comes from
+    pub server_id: String,
+    /// Display name of the server
+    pub server_name: String,
+}
+
+/// A resource offered by an MCP server
+#[derive(Debug, Clone)]
+pub struct McpResourceInfo {
+    /// The resource as reported by the MCP server
+    pub resource: rust_mcp_schema::Resource,
+    /// ID of the server this resource comes from
+    pub server_id: String,
+    /// Display name of the server
+    pub server_name: String,
+}
+
+/// A resource template offered by an MCP server
+#[derive(Debug, Clone)]
+pub struct McpResourceTemplateInfo {
+    /// The resource template as reported by the MCP server
+    pub template: McpResourceTemplate,
+    /// ID of the server this resource template comes from
+    pub server_id: String,
+    /// Display name of the server
+    pub server_name: String,
+}
+
 impl Default for McpClientConfig {
     fn default() -> Self {
         Self {
@@ -306,6 +385,18 @@ impl Default for McpClientConfig {
             auto_register_tools: true,
             tool_timeout_secs: None,
             max_concurrent_calls: Some(1),
+            sampling: None,
+        }
+    }
+}
+
+impl Default for McpSamplingConfig {
+    fn default() -> Self {
+        Self {
+            model: None,
+            use_model_hints: true,
+            max_tokens: None,
+            allowed_servers: None,
         }
     }
 }
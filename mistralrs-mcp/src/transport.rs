@@ -1,11 +1,14 @@
 use anyhow::Result;
-use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::future::BoxFuture;
+use futures_util::stream::SplitSink;
 use futures_util::{SinkExt, StreamExt};
 use http::{Request, Uri};
 use serde_json::Value;
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::TcpStream;
+use tokio::sync::oneshot;
 use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
 
 /// Transport layer for MCP communication
@@ -22,6 +25,140 @@ pub trait McpTransport: Send + Sync {
 
     /// Send initialization notification
     async fn send_initialization_notification(&self) -> Result<()>;
+
+    /// Set the handler called with the notifications the server sends, such as
+    /// `notifications/tools/list_changed`
+    fn set_notification_handler(&self, _handler: McpNotificationHandler) {}
+
+    /// Set the handler serving the requests the server sends, such as `sampling/createMessage`
+    fn set_request_handler(&self, _handler: McpRequestHandler) {}
+}
+
+/// Handler called with the method and params of each notification sent by an MCP server
+pub type McpNotificationHandler = Arc<dyn Fn(&str, &Value) + Send + Sync>;
+
+/// Handler called with the method and params of each request sent by an MCP server, returning
+/// the future of its result, or `None` if the method is not supported
+pub type McpRequestHandler =
+    Arc<dyn Fn(&str, Value) -> Option<BoxFuture<'static, Result<Value>>> + Send + Sync>;
+
+/// Routes the JSON-RPC messages read from a server: responses go to the pending request with
+/// the same id, notifications to the notification handler and requests to the request handler.
+struct MessageRouter {
+    /// Requests awaiting a response, or `None` once the connection is closed
+    pending: std::sync::Mutex<Option<HashMap<u64, oneshot::Sender<Value>>>>,
+    notification_handler: std::sync::RwLock<Option<McpNotificationHandler>>,
+    request_handler: std::sync::RwLock<Option<McpRequestHandler>>,
+}
+
+impl Default for MessageRouter {
+    fn default() -> Self {
+        Self {
+            pending: std::sync::Mutex::new(Some(HashMap::new())),
+            notification_handler: std::sync::RwLock::new(None),
+            request_handler: std::sync::RwLock::new(None),
+        }
+    }
+}
+
+impl MessageRouter {
+    /// Register a request before it is sent, returning the receiver of its response
+    fn expect_response(&self, id: u64) -> oneshot::Receiver<Value> {
+        let (tx, rx) = oneshot::channel();
+        if let Some(pending) = self.pending.lock().unwrap().as_mut() {
+            pending.insert(id, tx);
+        }
+        rx
+    }
+
+    fn set_notification_handler(&self, handler: McpNotificationHandler) {
+        *self.notification_handler.write().unwrap() = Some(handler);
+    }
+
+    fn set_request_handler(&self, handler: McpRequestHandler) {
+        *self.request_handler.write().unwrap() = Some(handler);
+    }
+
+    /// Route a message read from the server. For requests, this returns the future of the
+    /// JSON-RPC response which the transport must send back to the server.
+    fn route(&self, message: Value) -> Option<BoxFuture<'static, Value>> {
+        let method = message.get("method").and_then(Value::as_str);
+        match (method, message.get("id")) {
+            (Some(method), None) => {
+                let handler = self.notification_handler.read().unwrap().clone();
+                if let Some(handler) = handler {
+                    let params = message.get("params").cloned().unwrap_or(Value::Null);
+                    handler(method, &params);
+                }
+            }
+            (Some(method), Some(id)) => {
+                let params = message.get("params").cloned().unwrap_or(Value::Null);
+                let result = if method == "ping" {
+                    Some(Box::pin(async { Ok(serde_json::json!({})) }) as BoxFuture<_>)
+                } else {
+                    let handler = self.request_handler.read().unwrap().clone();
+                    handler.and_then(|handler| handler(method, params))
+                };
+                let id = id.clone();
+                let method = method.to_string();
+                return Some(Box::pin(async move {
+                    let error = match result {
+                        Some(result) => match result.await {
+                            Ok(result) => {
+                                return serde_json::json!({
+                                    "jsonrpc": "2.0",
+                                    "id": id,
+                                    "result": result
+                                })
+                            }
+                            Err(e) => serde_json::json!({"code": -32603, "message": e.to_string()}),
+                        },
+                        None => {
+                            tracing::debug!(
+                                "Rejecting unsupported `{method}` request from MCP server"
+                            );
+                            serde_json::json!({
+                                "code": -32601,
+                                "message": format!("Method not found: {method}")
+                            })
+                        }
+                    };
+                    serde_json::json!({"jsonrpc": "2.0", "id": id, "error": error})
+                }));
+            }
+            (None, Some(id)) => {
+                let sender = id.as_u64().and_then(|id| {
+                    self.pending
+                        .lock()
+                        .unwrap()
+                        .as_mut()
+                        .and_then(|pending| pending.remove(&id))
+                });
+                if let Some(sender) = sender {
+                    let _ = sender.send(message);
+                }
+            }
+            (None, None) => {}
+        }
+        None
+    }
+
+    /// Fail all pending and future requests once the connection is closed
+    fn close(&self) {
+        *self.pending.lock().unwrap() = None;
+    }
+}
+
+/// Extract the result of a JSON-RPC response, or its error
+fn response_result(response: Value) -> Result<Value> {
+    if let Some(error) = response.get("error") {
+        return Err(anyhow::anyhow!("MCP server error: {}", error));
+    }
+
+    response
+        .get("result")
+        .cloned()
+        .ok_or_else(|| anyhow::anyhow!("No result in MCP response"))
 }
 
 /// HTTP-based MCP transport
@@ -86,6 +223,7 @@ pub struct HttpTransport {
     base_url: String,
     headers: HashMap<String, String>,
     request_id: std::sync::Arc<std::sync::atomic::AtomicU64>,
+    router: MessageRouter,
 }
 
 impl HttpTransport {
@@ -147,32 +285,27 @@ impl HttpTransport {
             base_url,
             headers: headers.unwrap_or_default(),
             request_id: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(1)),
+            router: MessageRouter::default(),
         })
     }
 
-    /// Parse Server-Sent Events response to extract JSON-RPC message
+    /// Parse a Server-Sent Event to extract its JSON-RPC message
     ///
     /// Handles SSE format used by some MCP servers for streaming responses.
     /// SSE format: `data: <json>\n\n` or `event: <type>\ndata: <json>\n\n`
     ///
     /// # Arguments
     ///
-    /// * `sse_text` - Raw SSE response text from the server
+    /// * `event` - Raw text of one event
     ///
     /// # Returns
     ///
-    /// Parsed JSON value from the SSE data field
-    ///
-    /// # Errors
-    ///
-    /// - No valid JSON data found in SSE response
-    /// - Malformed SSE format
-    /// - JSON parsing errors
-    fn parse_sse_response(sse_text: &str) -> Result<Value> {
-        // SSE format: data: <json>\n\n or event: <type>\ndata: <json>\n\n
-        let mut json_data = None;
+    /// The JSON value of the event's data fields, or `None` for events without valid JSON
+    /// data, such as comments and keep-alives
+    fn parse_sse_event(event: &str) -> Option<Value> {
+        let mut data = Vec::new();
 
-        for line in sse_text.lines() {
+        for line in event.lines() {
             let line = line.trim();
 
             // Skip empty lines and comments
@@ -180,32 +313,58 @@ impl HttpTransport {
                 continue;
             }
 
-            // Parse SSE field
-            if let Some((field, value)) = line.split_once(':') {
-                let field = field.trim();
-                let value = value.trim();
-
-                match field {
-                    "data" => {
-                        // Try to parse the JSON data
-                        if let Ok(parsed) = serde_json::from_str::<Value>(value) {
-                            json_data = Some(parsed);
-                            break;
-                        }
-                    }
-                    "event" => {
-                        // Handle different event types if needed
-                        continue;
-                    }
-                    _ => {
-                        // Ignore other SSE fields like id, retry, etc.
-                        continue;
-                    }
-                }
+            // Parse SSE field, ignoring the others like event, id, retry, etc.
+            if let Some(("data", value)) = line.split_once(':') {
+                data.push(value.trim());
             }
         }
 
-        json_data.ok_or_else(|| anyhow::anyhow!("No valid JSON data found in SSE response"))
+        serde_json::from_str(&data.join("\n")).ok()
+    }
+
+    /// Take the next complete event out of the SSE bytes read so far
+    ///
+    /// Events end with a blank line, and the bytes of an incomplete event are left in the buffer.
+    fn next_sse_event(buffer: &mut Vec<u8>) -> Option<String> {
+        let end = buffer.windows(2).position(|w| w == b"\n\n")?;
+        let event = buffer.drain(..end + 2).collect::<Vec<_>>();
+        Some(String::from_utf8_lossy(&event).into_owned())
+    }
+
+    /// Send a JSON-RPC message to the server, such as the response to one of its requests
+    async fn post_message(
+        client: &reqwest::Client,
+        base_url: &str,
+        headers: &HashMap<String, String>,
+        message: &Value,
+    ) -> Result<()> {
+        let mut request_builder = client
+            .post(base_url)
+            .json(message)
+            .header("Accept", "application/json, text/event-stream");
+
+        // Add custom headers
+        for (key, value) in headers {
+            request_builder = request_builder.header(key, value);
+        }
+
+        request_builder.send().await?.error_for_status()?;
+        Ok(())
+    }
+
+    /// Route a message the server sent in an SSE stream, answering its requests in the background
+    fn route(&self, message: Value) {
+        if let Some(response) = self.router.route(message) {
+            let client = self.client.clone();
+            let base_url = self.base_url.clone();
+            let headers = self.headers.clone();
+            tokio::spawn(async move {
+                let response = response.await;
+                if let Err(e) = Self::post_message(&client, &base_url, &headers, &response).await {
+                    tracing::warn!("Failed to answer MCP server request: {e}");
+                }
+            });
+        }
     }
 }
 
@@ -300,23 +459,36 @@ impl McpTransport for HttpTransport {
             .unwrap_or("");
 
         let response_body: Value = if content_type.contains("text/event-stream") {
-            // Handle Server-Sent Events
-            let response_text = response.text().await?;
-            Self::parse_sse_response(&response_text)?
+            // Handle Server-Sent Events as they arrive, routing the notifications and requests
+            // sent before the response. The server may wait for the answer to its requests
+            // before sending the response.
+            let mut stream = response.bytes_stream();
+            let mut buffer = Vec::new();
+            let mut response_body = None;
+            'stream: while let Some(chunk) = stream.next().await {
+                buffer.extend(chunk?.iter().filter(|byte| **byte != b'\r'));
+                while let Some(event) = Self::next_sse_event(&mut buffer) {
+                    match Self::parse_sse_event(&event) {
+                        Some(message) if message.get("method").is_some() => self.route(message),
+                        Some(message) => {
+                            response_body = Some(message);
+                            break 'stream;
+                        }
+                        None => continue,
+                    }
+                }
+            }
+            if response_body.is_none() {
+                response_body = Self::parse_sse_event(&String::from_utf8_lossy(&buffer))
+                    .filter(|message| message.get("method").is_none());
+            }
+            response_body.ok_or_else(|| anyhow::anyhow!("No response in SSE stream"))?
         } else {
             // Handle regular JSON response
             response.json().await?
         };
 
-        // Check for JSON-RPC errors
-        if let Some(error) = response_body.get("error") {
-            return Err(anyhow::anyhow!("MCP server error: {}", error));
-        }
-
-        response_body
-            .get("result")
-            .cloned()
-            .ok_or_else(|| anyhow::anyhow!("No result in MCP response"))
+        response_result(response_body)
     }
 
     /// Tests the HTTP connection by sending a ping request
@@ -372,6 +544,17 @@ impl McpTransport for HttpTransport {
         request_builder.send().await?;
         Ok(())
     }
+
+    /// Notifications are only received when the server sends them in the SSE stream of a response
+    fn set_notification_handler(&self, handler: McpNotificationHandler) {
+        self.router.set_notification_handler(handler);
+    }
+
+    /// Requests are only received when the server sends them in the SSE stream of a response, and
+    /// are answered with a separate POST
+    fn set_request_handler(&self, handler: McpRequestHandler) {
+        self.router.set_request_handler(handler);
+    }
 }
 
 /// Process-based MCP transport using stdin/stdout communication
@@ -387,7 +570,7 @@ impl McpTransport for HttpTransport {
 /// - **No Network Overhead**: Direct pipe communication for maximum performance
 /// - **Environment Control**: Full control over working directory and environment variables
 /// - **Resource Management**: Automatic process cleanup and lifecycle management
-/// - **Synchronous Communication**: Request/response correlation over stdin/stdout
+/// - **Concurrent Requests**: Responses are matched to requests by id, so requests may overlap
 /// - **Error Handling**: Comprehensive process and communication error handling
 ///
 /// # Use Cases
@@ -446,14 +629,14 @@ impl McpTransport for HttpTransport {
 ///
 /// Uses JSON-RPC 2.0 over stdin/stdout with line-delimited messages:
 /// - Each request is a single line of JSON sent to stdin
-/// - Each response is a single line of JSON read from stdout
+/// - Each message from the server is a single line of JSON read from stdout by a background
+///   task, which matches responses to requests by id and dispatches notifications
 /// - Stderr is captured for debugging and error reporting
 pub struct ProcessTransport {
     child: std::sync::Arc<tokio::sync::Mutex<tokio::process::Child>>,
     request_id: std::sync::Arc<std::sync::atomic::AtomicU64>,
     stdin: std::sync::Arc<tokio::sync::Mutex<tokio::process::ChildStdin>>,
-    stdout_reader:
-        std::sync::Arc<tokio::sync::Mutex<tokio::io::BufReader<tokio::process::ChildStdout>>>,
+    router: Arc<MessageRouter>,
 }
 
 impl ProcessTransport {
@@ -519,7 +702,7 @@ impl ProcessTransport {
         work_dir: Option<String>,
         env: Option<HashMap<String, String>>,
     ) -> Result<Self> {
-        use tokio::io::BufReader;
+        use tokio::io::{AsyncBufReadExt, BufReader};
         use tokio::process::Command;
 
         let mut cmd = Command::new(command);
@@ -547,13 +730,47 @@ impl ProcessTransport {
             .stdout
             .take()
             .ok_or_else(|| anyhow::anyhow!("Failed to get stdout handle"))?;
-        let stdout_reader = BufReader::new(stdout);
+
+        let stdin = std::sync::Arc::new(tokio::sync::Mutex::new(stdin));
+        let router = Arc::new(MessageRouter::default());
+        let reader_router = router.clone();
+        let reader_stdin = stdin.clone();
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(stdout).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let message = match serde_json::from_str::<Value>(line.trim()) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        tracing::debug!("Ignoring non JSON-RPC output of MCP server: {e}");
+                        continue;
+                    }
+                };
+                // Answer requests from the server in the background, as they may take long
+                if let Some(response) = reader_router.route(message) {
+                    let stdin = reader_stdin.clone();
+                    tokio::spawn(async move {
+                        use tokio::io::AsyncWriteExt;
+
+                        let response_line = format!("{}\n", response.await);
+                        let mut stdin = stdin.lock().await;
+                        if let Err(e) = stdin.write_all(response_line.as_bytes()).await {
+                            tracing::warn!("Failed to answer MCP server request: {e}");
+                        }
+                        let _ = stdin.flush().await;
+                    });
+                }
+            }
+            reader_router.close();
+        });
 
         Ok(Self {
             child: std::sync::Arc::new(tokio::sync::Mutex::new(child)),
             request_id: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(1)),
-            stdin: std::sync::Arc::new(tokio::sync::Mutex::new(stdin)),
-            stdout_reader: std::sync::Arc::new(tokio::sync::Mutex::new(stdout_reader)),
+            stdin,
+            router,
         })
     }
 }
@@ -563,9 +780,8 @@ impl McpTransport for ProcessTransport {
     /// Sends an MCP request to the child process and returns the response
     ///
     /// This method implements JSON-RPC 2.0 over stdin/stdout pipes. It sends
-    /// a line-delimited JSON request to the process stdin and reads the
-    /// corresponding response from stdout. Communication is synchronous with
-    /// proper request/response correlation.
+    /// a line-delimited JSON request to the process stdin and waits for the
+    /// background reader to receive the response with the same id from stdout.
     ///
     /// # Arguments
     ///
@@ -613,7 +829,7 @@ impl McpTransport for ProcessTransport {
     /// }
     /// ```
     async fn send_request(&self, method: &str, params: Value) -> Result<Value> {
-        use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+        use tokio::io::AsyncWriteExt;
 
         // Ensure params is an object, not null
         let params = if params.is_null() {
@@ -632,6 +848,9 @@ impl McpTransport for ProcessTransport {
             "params": params
         });
 
+        // Register the request before sending it, so its response can't be missed
+        let response = self.router.expect_response(id);
+
         // Send request via stdin
         let request_line = serde_json::to_string(&request_body)? + "\n";
         let mut stdin = self.stdin.lock().await;
@@ -639,23 +858,12 @@ impl McpTransport for ProcessTransport {
         stdin.flush().await?;
         drop(stdin);
 
-        // Read response from stdout
-        let mut stdout_reader = self.stdout_reader.lock().await;
-        let mut response_line = String::new();
-        stdout_reader.read_line(&mut response_line).await?;
-        drop(stdout_reader);
-
-        let response_body: Value = serde_json::from_str(response_line.trim())?;
-
-        // Check for JSON-RPC errors
-        if let Some(error) = response_body.get("error") {
-            return Err(anyhow::anyhow!("MCP server error: {}", error));
-        }
+        // Wait for the background reader to receive the response from stdout
+        let response_body = response
+            .await
+            .map_err(|_| anyhow::anyhow!("MCP server process closed its output"))?;
 
-        response_body
-            .get("result")
-            .cloned()
-            .ok_or_else(|| anyhow::anyhow!("No result in MCP response"))
+        response_result(response_body)
     }
 
     /// Tests the process connection by sending a ping request
@@ -720,6 +928,14 @@ impl McpTransport for ProcessTransport {
         drop(stdin);
         Ok(())
     }
+
+    fn set_notification_handler(&self, handler: McpNotificationHandler) {
+        self.router.set_notification_handler(handler);
+    }
+
+    fn set_request_handler(&self, handler: McpRequestHandler) {
+        self.router.set_request_handler(handler);
+    }
 }
 
 /// WebSocket-based MCP transport
@@ -739,8 +955,9 @@ impl McpTransport for ProcessTransport {
 /// # Architecture
 ///
 /// The transport uses a split-stream design where the WebSocket connection is divided into
-/// separate read and write halves, each protected by async mutexes. This allows concurrent
-/// operations while maintaining thread safety. Request IDs are generated atomically to ensure
+/// separate read and write halves. The write half is protected by an async mutex, and the
+/// read half is owned by a background task which hands each response to the request with the
+/// same id and dispatches notifications. Request IDs are generated atomically to ensure
 /// unique identification of requests and proper response correlation.
 ///
 /// # Example Usage
@@ -778,9 +995,8 @@ pub struct WebSocketTransport {
     write: std::sync::Arc<
         tokio::sync::Mutex<SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>>,
     >,
-    read:
-        std::sync::Arc<tokio::sync::Mutex<SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>>>,
     request_id: std::sync::Arc<std::sync::atomic::AtomicU64>,
+    router: Arc<MessageRouter>,
 }
 
 impl WebSocketTransport {
@@ -857,12 +1073,47 @@ impl WebSocketTransport {
             .map_err(|e| anyhow::anyhow!("WebSocket connection failed: {}", e))?;
 
         // Split the stream
-        let (write, read) = ws_stream.split();
+        let (write, mut read) = ws_stream.split();
+
+        // Read messages in the background, matching responses to requests by id
+        let write = std::sync::Arc::new(tokio::sync::Mutex::new(write));
+        let router = Arc::new(MessageRouter::default());
+        let reader_router = router.clone();
+        let reader_write = write.clone();
+        tokio::spawn(async move {
+            while let Some(Ok(msg)) = read.next().await {
+                match msg {
+                    Message::Text(text) => match serde_json::from_str::<Value>(&text) {
+                        Ok(message) => {
+                            // Answer requests from the server in the background, as they may
+                            // take long
+                            if let Some(response) = reader_router.route(message) {
+                                let write = reader_write.clone();
+                                tokio::spawn(async move {
+                                    let message = Message::Text(response.await.to_string());
+                                    if let Err(e) = write.lock().await.send(message).await {
+                                        tracing::warn!("Failed to answer MCP server request: {e}");
+                                    }
+                                });
+                            }
+                        }
+                        Err(e) => tracing::debug!("Ignoring invalid MCP message: {e}"),
+                    },
+                    Message::Close(_) => break,
+                    // Binary messages and ping/pong or raw frames carry no JSON-RPC messages
+                    Message::Binary(_)
+                    | Message::Ping(_)
+                    | Message::Pong(_)
+                    | Message::Frame(_) => continue,
+                }
+            }
+            reader_router.close();
+        });
 
         Ok(Self {
-            write: std::sync::Arc::new(tokio::sync::Mutex::new(write)),
-            read: std::sync::Arc::new(tokio::sync::Mutex::new(read)),
+            write,
             request_id: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(1)),
+            router,
         })
     }
 }
@@ -937,6 +1188,9 @@ impl McpTransport for WebSocketTransport {
             "params": params
         });
 
+        // Register the request before sending it, so its response can't be missed
+        let response = self.router.expect_response(id);
+
         // Send request
         let message = Message::Text(serde_json::to_string(&request_body)?);
 
@@ -948,53 +1202,12 @@ impl McpTransport for WebSocketTransport {
                 .map_err(|e| anyhow::anyhow!("Failed to send WebSocket message: {}", e))?;
         }
 
-        // Read response
-        loop {
-            let mut read = self.read.lock().await;
-            let msg = read
-                .next()
-                .await
-                .ok_or_else(|| anyhow::anyhow!("WebSocket connection closed"))?
-                .map_err(|e| anyhow::anyhow!("WebSocket read error: {}", e))?;
-            drop(read);
-
-            match msg {
-                Message::Text(text) => {
-                    let response_body: Value = serde_json::from_str(&text)?;
-
-                    // Check if this is the response to our request
-                    if let Some(response_id) = response_body.get("id").and_then(|v| v.as_u64()) {
-                        if response_id == id {
-                            // Check for JSON-RPC errors
-                            if let Some(error) = response_body.get("error") {
-                                return Err(anyhow::anyhow!("MCP server error: {}", error));
-                            }
+        // Wait for the background reader to receive the response
+        let response_body = response
+            .await
+            .map_err(|_| anyhow::anyhow!("WebSocket connection closed"))?;
 
-                            return response_body
-                                .get("result")
-                                .cloned()
-                                .ok_or_else(|| anyhow::anyhow!("No result in MCP response"));
-                        }
-                    }
-                    // If it's not our response, continue reading
-                }
-                Message::Binary(_) => {
-                    // Handle binary messages if needed, for now skip
-                    continue;
-                }
-                Message::Close(_) => {
-                    return Err(anyhow::anyhow!("WebSocket connection closed by server"));
-                }
-                Message::Ping(_) | Message::Pong(_) => {
-                    // Handle ping/pong frames, continue reading
-                    continue;
-                }
-                Message::Frame(_) => {
-                    // Raw frames, continue reading
-                    continue;
-                }
-            }
-        }
+        response_result(response_body)
     }
 
     /// Sends a WebSocket ping frame to test connection health
@@ -1063,4 +1276,133 @@ impl McpTransport for WebSocketTransport {
         }
         Ok(())
     }
+
+    fn set_notification_handler(&self, handler: McpNotificationHandler) {
+        self.router.set_notification_handler(handler);
+    }
+
+    fn set_request_handler(&self, handler: McpRequestHandler) {
+        self.router.set_request_handler(handler);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HttpTransport, MessageRouter};
+    use serde_json::{json, Value};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn routes_responses_by_id() {
+        let router = MessageRouter::default();
+        let mut first = router.expect_response(1);
+        let mut second = router.expect_response(2);
+
+        assert!(router
+            .route(json!({"jsonrpc": "2.0", "id": 2, "result": "second"}))
+            .is_none());
+        assert!(first.try_recv().is_err());
+        router.route(json!({"jsonrpc": "2.0", "id": 1, "result": "first"}));
+
+        assert_eq!(first.try_recv().unwrap()["result"], "first");
+        assert_eq!(second.try_recv().unwrap()["result"], "second");
+    }
+
+    #[test]
+    fn close_fails_pending_requests() {
+        let router = MessageRouter::default();
+        let mut pending = router.expect_response(1);
+        router.close();
+        assert!(pending.try_recv().is_err());
+
+        // Requests sent after the connection closed fail too
+        let mut late = router.expect_response(2);
+        router.route(json!({"jsonrpc": "2.0", "id": 2, "result": {}}));
+        assert!(late.try_recv().is_err());
+    }
+
+    #[test]
+    fn dispatches_notifications() {
+        let router = MessageRouter::default();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let received_clone = Arc::clone(&received);
+        router.set_notification_handler(Arc::new(move |method, params| {
+            received_clone
+                .lock()
+                .unwrap()
+                .push((method.to_string(), params.clone()));
+        }));
+
+        assert!(router
+            .route(json!({"jsonrpc": "2.0", "method": "notifications/tools/list_changed"}))
+            .is_none());
+        router.route(json!({
+            "jsonrpc": "2.0",
+            "method": "notifications/message",
+            "params": {"level": "info"}
+        }));
+
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![
+                ("notifications/tools/list_changed".to_string(), Value::Null),
+                (
+                    "notifications/message".to_string(),
+                    json!({"level": "info"})
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn answers_requests() {
+        let router = MessageRouter::default();
+        let request = |id: u64, method: &str| json!({"jsonrpc": "2.0", "id": id, "method": method});
+
+        let ping = router.route(request(1, "ping")).unwrap().await;
+        assert_eq!(ping, json!({"jsonrpc": "2.0", "id": 1, "result": {}}));
+
+        // Without a request handler, only pings are served
+        let unsupported = router
+            .route(request(2, "sampling/createMessage"))
+            .unwrap()
+            .await;
+        assert_eq!(unsupported["error"]["code"], -32601);
+
+        router.set_request_handler(Arc::new(|method, _params| match method {
+            "sampling/createMessage" => Some(Box::pin(async {
+                anyhow::bail!("The sampling request was rejected")
+            })),
+            _ => None,
+        }));
+        let failed = router
+            .route(request(3, "sampling/createMessage"))
+            .unwrap()
+            .await;
+        assert_eq!(failed["id"], 3);
+        assert_eq!(failed["error"]["code"], -32603);
+        assert_eq!(
+            failed["error"]["message"],
+            "The sampling request was rejected"
+        );
+        let unknown = router.route(request(4, "roots/list")).unwrap().await;
+        assert_eq!(unknown["error"]["code"], -32601);
+    }
+
+    #[test]
+    fn splits_sse_events() {
+        let mut buffer = b"data: {\"id\": 1}\n\n: keep-alive\n\nevent: message\ndata: {\"id\":\ndata: 2}\n\ndata: {\"id\""
+            .to_vec();
+
+        let mut messages = Vec::new();
+        while let Some(event) = HttpTransport::next_sse_event(&mut buffer) {
+            messages.push(HttpTransport::parse_sse_event(&event));
+        }
+
+        assert_eq!(
+            messages,
+            vec![Some(json!({"id": 1})), None, Some(json!({"id": 2}))]
+        );
+        assert_eq!(buffer, b"data: {\"id\"");
+    }
 }
@@ -31,7 +31,11 @@ pub enum McpContent {
     #[serde(rename = "text")]
     Text { text: String },
     #[serde(rename = "image")]
-    Image { data: String, mime_type: String },
+    Image {
+        data: String,
+        #[serde(alias = "mimeType")]
+        mime_type: String,
+    },
     #[serde(rename = "resource")]
     Resource { resource: McpResourceReference },
 }
@@ -43,6 +47,118 @@ pub struct McpResourceReference {
     pub text: Option<String>,
 }
 
+/// A prompt template offered by an MCP server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct McpPrompt {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub arguments: Vec<McpPromptArgument>,
+}
+
+/// An argument of an MCP prompt
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct McpPromptArgument {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub required: bool,
+}
+
+/// An MCP prompt filled in with its arguments
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct McpPromptResult {
+    #[serde(default)]
+    pub description: Option<String>,
+    pub messages: Vec<McpPromptMessage>,
+}
+
+/// A message of an MCP prompt, sent by the `user` or the `assistant`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct McpPromptMessage {
+    pub role: String,
+    pub content: McpContent,
+}
+
+/// A template of resource URIs offered by an MCP server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct McpResourceTemplate {
+    /// RFC 6570 template of the URIs, e.g. `file:///{path}`
+    #[serde(rename = "uriTemplate")]
+    pub uri_template: String,
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default, rename = "mimeType")]
+    pub mime_type: Option<String>,
+}
+
+/// A `sampling/createMessage` request, sent by an MCP server to have the client's model
+/// generate a message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct McpCreateMessageRequest {
+    pub messages: Vec<McpSamplingMessage>,
+    #[serde(default)]
+    pub model_preferences: Option<McpModelPreferences>,
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+    /// Which MCP context the server asks to include: `none`, `thisServer` or `allServers`
+    #[serde(default)]
+    pub include_context: Option<String>,
+    #[serde(default)]
+    pub temperature: Option<f64>,
+    pub max_tokens: usize,
+    #[serde(default)]
+    pub stop_sequences: Option<Vec<String>>,
+    #[serde(default)]
+    pub metadata: Option<serde_json::Value>,
+}
+
+/// A message of a sampling request, sent by the `user` or the `assistant`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct McpSamplingMessage {
+    pub role: String,
+    pub content: McpContent,
+}
+
+/// The server's preferences for the model serving a sampling request
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct McpModelPreferences {
+    /// Model names to prefer, in order
+    #[serde(default)]
+    pub hints: Option<Vec<McpModelHint>>,
+    #[serde(default)]
+    pub cost_priority: Option<f64>,
+    #[serde(default)]
+    pub speed_priority: Option<f64>,
+    #[serde(default)]
+    pub intelligence_priority: Option<f64>,
+}
+
+/// A hint of the model to use for a sampling request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct McpModelHint {
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+/// The message generated for a `sampling/createMessage` request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct McpCreateMessageResult {
+    pub role: String,
+    pub content: McpContent,
+    /// The name of the model which generated the message
+    pub model: String,
+    /// Why sampling stopped: `endTurn`, `stopSequence` or `maxTokens`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_reason: Option<String>,
+}
+
 /// MCP server capabilities
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct McpServerCapabilities {
@@ -80,21 +196,25 @@ impl From<crate::McpToolInfo> for McpToolSchema {
     }
 }
 
+impl Display for McpContent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            McpContent::Text { text } => write!(f, "{text}"),
+            McpContent::Image { mime_type, .. } => write!(f, "[Image: {mime_type}]"),
+            McpContent::Resource { resource } => match &resource.text {
+                Some(text) => write!(f, "{text}"),
+                None => write!(f, "[Resource: {}]", resource.uri),
+            },
+        }
+    }
+}
+
 impl Display for McpToolResult {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let res = self
             .content
             .iter()
-            .map(|content| match content {
-                McpContent::Text { text } => text.clone(),
-                McpContent::Image { mime_type, .. } => {
-                    format!("[Image: {mime_type}]")
-                }
-                McpContent::Resource { resource } => resource
-                    .text
-                    .clone()
-                    .unwrap_or_else(|| format!("[Resource: {}]", resource.uri)),
-            })
+            .map(ToString::to_string)
             .collect::<Vec<_>>()
             .join("\n");
 
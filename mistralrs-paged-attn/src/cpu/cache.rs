@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+
+use candle_core::{CpuStorage, InplaceOp1, Layout, Result, Tensor};
+
+struct CopyBlocks {
+    /// `(src, dst)` block pairs.
+    pairs: Vec<(usize, usize)>,
+}
+
+impl CopyBlocks {
+    fn copy<T: Copy>(&self, cache: &mut [T], layout: &Layout) -> Result<()> {
+        let Some((start, end)) = layout.contiguous_offsets() else {
+            candle_core::bail!("cache must be contiguous ({layout:?})")
+        };
+        let cache = &mut cache[start..end];
+        let block_numel = cache.len() / layout.dims()[0];
+        for &(src, dst) in &self.pairs {
+            cache.copy_within(
+                src * block_numel..(src + 1) * block_numel,
+                dst * block_numel,
+            );
+        }
+        Ok(())
+    }
+}
+
+impl InplaceOp1 for CopyBlocks {
+    fn name(&self) -> &'static str {
+        "copy-blocks"
+    }
+
+    fn cpu_fwd(&self, cache: &mut CpuStorage, layout: &Layout) -> Result<()> {
+        match cache {
+            CpuStorage::F32(cache) => self.copy(cache, layout),
+            CpuStorage::F16(cache) => self.copy(cache, layout),
+            CpuStorage::BF16(cache) => self.copy(cache, layout),
+            CpuStorage::F8E4M3(cache) => self.copy(cache, layout),
+            cache => candle_core::bail!("cache dtype {:?} is not supported", cache.dtype()),
+        }
+    }
+}
+
+pub fn copy_blocks(
+    key_caches: Vec<&mut Tensor>,
+    value_caches: Vec<&mut Tensor>,
+    block_mapping: &HashMap<usize, Vec<usize>>,
+) -> Result<()> {
+    let op = CopyBlocks {
+        pairs: block_mapping
+            .iter()
+            .flat_map(|(src, dsts)| dsts.iter().map(move |dst| (*src, *dst)))
+            .collect(),
+    };
+    for cache in key_caches.into_iter().chain(value_caches) {
+        cache.inplace_op1(&op)?;
+    }
+    Ok(())
+}
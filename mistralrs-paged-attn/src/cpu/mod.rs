@@ -0,0 +1,77 @@
+//! Portable implementations of the PagedAttention kernels for host memory.
+//!
+//! These operate directly on `CpuStorage` and are used whenever the KV cache lives on the CPU, either
+//! because the crate was built without an accelerator backend or because the model was mapped to the CPU.
+
+mod cache;
+mod paged_attention;
+mod scale_update;
+
+pub use cache::copy_blocks;
+pub use paged_attention::{paged_attention, reshape_and_cache};
+pub use scale_update::kv_scale_update;
+
+use candle_core::{DType, Layout, Result, Tensor};
+
+fn contiguous<'a, T>(data: &'a [T], layout: &Layout, name: &str) -> Result<&'a [T]> {
+    match layout.contiguous_offsets() {
+        Some((start, end)) => Ok(&data[start..end]),
+        None => candle_core::bail!("`{name}` must be contiguous ({layout:?})"),
+    }
+}
+
+/// Geometry of a paged key or value cache.
+///
+/// The key cache is laid out as `(num_blocks, num_heads, head_size / x, block_size, x)` and the value cache as
+/// `(num_blocks, num_heads, head_size, block_size)`, which is the same layout with `x = 1`.
+#[derive(Clone, Copy)]
+struct CacheLayout {
+    num_heads: usize,
+    head_size: usize,
+    block_size: usize,
+    x: usize,
+}
+
+impl CacheLayout {
+    fn key(layout: &Layout) -> Result<Self> {
+        let (_, num_heads, head_size_x, block_size, x) = layout.shape().dims5()?;
+        Ok(Self {
+            num_heads,
+            head_size: head_size_x * x,
+            block_size,
+            x,
+        })
+    }
+
+    fn value(layout: &Layout) -> Result<Self> {
+        let (_, num_heads, head_size, block_size) = layout.shape().dims4()?;
+        Ok(Self {
+            num_heads,
+            head_size,
+            block_size,
+            x: 1,
+        })
+    }
+
+    /// Offset of element `d` of `head` for the token in slot `offset` of `block`.
+    #[inline]
+    fn index(&self, block: usize, head: usize, offset: usize, d: usize) -> usize {
+        (block * self.num_heads + head) * self.head_size * self.block_size
+            + ((d / self.x) * self.block_size + offset) * self.x
+            + d % self.x
+    }
+}
+
+/// FP8 caches store values divided by a per-layer scale; other cache dtypes are stored as is.
+fn cache_scale(cache: &Tensor, scale: Option<&Tensor>) -> Result<f32> {
+    match scale {
+        Some(scale) if cache.dtype() == DType::F8E4M3 => scale
+            .flatten_all()?
+            .to_dtype(DType::F32)?
+            .to_vec1::<f32>()?
+            .first()
+            .copied()
+            .ok_or_else(|| candle_core::Error::Msg("empty kv scale tensor".to_string())),
+        _ => Ok(1.0),
+    }
+}
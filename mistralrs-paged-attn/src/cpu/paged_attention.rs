@@ -0,0 +1,437 @@
+use candle_core::{
+    CpuStorage, CustomOp1, DType, InplaceOp2, Layout, Result, Shape, Storage, Tensor, WithDType,
+};
+use rayon::prelude::*;
+
+use super::{cache_scale, contiguous, CacheLayout};
+
+struct PagedAttention {
+    softmax_scale: f32,
+    softcapping: f32,
+
+    key_cache: Tensor,
+    value_cache: Tensor,
+    block_tables: Tensor,
+    context_lens: Tensor,
+    alibi_slopes: Option<Tensor>,
+    k_scale: f32,
+    v_scale: f32,
+}
+
+impl PagedAttention {
+    fn cpu_fwd_t<T: WithDType>(&self, q: &[T], q_l: &Layout) -> Result<(CpuStorage, Shape)> {
+        let (_, num_heads, head_size) = q_l.shape().dims3()?;
+        let q = contiguous(q, q_l, "q")?;
+
+        let (kc, kc_l) = self.key_cache.storage_and_layout();
+        let (vc, vc_l) = self.value_cache.storage_and_layout();
+        let (Storage::Cpu(kc), Storage::Cpu(vc)) = (&*kc, &*vc) else {
+            candle_core::bail!("key_cache and value_cache must be cpu tensors")
+        };
+        let k_layout = CacheLayout::key(kc_l)?;
+        let v_layout = CacheLayout::value(vc_l)?;
+        if k_layout.head_size != head_size || v_layout.head_size != head_size {
+            candle_core::bail!(
+                "paged-attention head size mismatch (q: {head_size}, key_cache: {}, value_cache: {})",
+                k_layout.head_size,
+                v_layout.head_size
+            )
+        }
+        if num_heads % k_layout.num_heads != 0 {
+            candle_core::bail!(
+                "number of query heads ({num_heads}) must be divisible by the number of kv heads ({})",
+                k_layout.num_heads
+            )
+        }
+
+        let out = match (kc, vc) {
+            (CpuStorage::F16(kc), CpuStorage::F16(vc)) => self.attend(
+                q,
+                num_heads,
+                contiguous(kc, kc_l, "key_cache")?,
+                k_layout,
+                contiguous(vc, vc_l, "value_cache")?,
+                v_layout,
+            )?,
+            (CpuStorage::BF16(kc), CpuStorage::BF16(vc)) => self.attend(
+                q,
+                num_heads,
+                contiguous(kc, kc_l, "key_cache")?,
+                k_layout,
+                contiguous(vc, vc_l, "value_cache")?,
+                v_layout,
+            )?,
+            (CpuStorage::F32(kc), CpuStorage::F32(vc)) => self.attend(
+                q,
+                num_heads,
+                contiguous(kc, kc_l, "key_cache")?,
+                k_layout,
+                contiguous(vc, vc_l, "value_cache")?,
+                v_layout,
+            )?,
+            (CpuStorage::F8E4M3(kc), CpuStorage::F8E4M3(vc)) => self.attend(
+                q,
+                num_heads,
+                contiguous(kc, kc_l, "key_cache")?,
+                k_layout,
+                contiguous(vc, vc_l, "value_cache")?,
+                v_layout,
+            )?,
+            (kc, vc) => candle_core::bail!(
+                "cache dtypes {:?}/{:?} are not supported",
+                kc.dtype(),
+                vc.dtype()
+            ),
+        };
+
+        Ok((T::to_cpu_storage_owned(out), q_l.shape().clone()))
+    }
+
+    fn attend<T: WithDType, C: WithDType>(
+        &self,
+        q: &[T],
+        num_heads: usize,
+        key_cache: &[C],
+        k_layout: CacheLayout,
+        value_cache: &[C],
+        v_layout: CacheLayout,
+    ) -> Result<Vec<T>> {
+        let head_size = k_layout.head_size;
+        let block_size = k_layout.block_size;
+        let num_queries_per_kv = num_heads / k_layout.num_heads;
+        let block_tables = self.block_tables.to_vec2::<u32>()?;
+        let context_lens = self.context_lens.to_vec1::<u32>()?;
+        let alibi_slopes = self
+            .alibi_slopes
+            .as_ref()
+            .map(|slopes| slopes.to_dtype(DType::F32)?.to_vec1::<f32>())
+            .transpose()?;
+
+        let mut out = vec![T::from_f64(0.); q.len()];
+        out.par_chunks_mut(head_size)
+            .zip(q.par_chunks(head_size))
+            .enumerate()
+            .for_each(|(i, (out, q))| {
+                let seq = i / num_heads;
+                let head = i % num_heads;
+                let kv_head = head / num_queries_per_kv;
+                let context_len = context_lens[seq] as usize;
+                if context_len == 0 {
+                    return;
+                }
+                let q = q.iter().map(|v| v.to_f64() as f32).collect::<Vec<_>>();
+                let slots = (0..context_len)
+                    .map(|pos| {
+                        (
+                            block_tables[seq][pos / block_size] as usize,
+                            pos % block_size,
+                        )
+                    })
+                    .collect::<Vec<_>>();
+
+                let mut logits = slots
+                    .iter()
+                    .enumerate()
+                    .map(|(pos, &(block, offset))| {
+                        let dot = q
+                            .iter()
+                            .enumerate()
+                            .map(|(d, q_d)| {
+                                let k = key_cache[k_layout.index(block, kv_head, offset, d)];
+                                q_d * k.to_f64() as f32
+                            })
+                            .sum::<f32>();
+                        let mut qk = dot * self.k_scale * self.softmax_scale;
+                        if self.softcapping != 1.0 {
+                            qk = (qk / self.softcapping).tanh() * self.softcapping;
+                        }
+                        if let Some(slopes) = &alibi_slopes {
+                            qk += slopes[head] * (pos as f32 - context_len as f32 + 1.0);
+                        }
+                        qk
+                    })
+                    .collect::<Vec<_>>();
+
+                let max_logit = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                let mut exp_sum = 0f32;
+                for logit in logits.iter_mut() {
+                    *logit = (*logit - max_logit).exp();
+                    exp_sum += *logit;
+                }
+                let inv_sum = 1.0 / (exp_sum + 1e-6);
+
+                let mut acc = vec![0f32; head_size];
+                for (&(block, offset), p) in slots.iter().zip(&logits) {
+                    for (d, acc_d) in acc.iter_mut().enumerate() {
+                        let v = value_cache[v_layout.index(block, kv_head, offset, d)];
+                        *acc_d += p * v.to_f64() as f32;
+                    }
+                }
+                for (o, acc_d) in out.iter_mut().zip(acc) {
+                    *o = T::from_f64((acc_d * inv_sum * self.v_scale) as f64);
+                }
+            });
+
+        Ok(out)
+    }
+}
+
+impl CustomOp1 for PagedAttention {
+    fn name(&self) -> &'static str {
+        "paged-attention"
+    }
+
+    fn cpu_fwd(&self, q: &CpuStorage, q_l: &Layout) -> Result<(CpuStorage, Shape)> {
+        match q {
+            CpuStorage::F32(q) => self.cpu_fwd_t(q, q_l),
+            CpuStorage::F16(q) => self.cpu_fwd_t(q, q_l),
+            CpuStorage::BF16(q) => self.cpu_fwd_t(q, q_l),
+            q => candle_core::bail!(
+                "paged-attention is only supported for f32/f16/bf16 ({:?})",
+                q.dtype()
+            ),
+        }
+    }
+}
+
+/// PagedAttention layer.
+///
+/// This implements scaled dot-product attention, `softmax(Q @ K^T . softmax_scale) @ V`, reading keys and
+/// values from the paged caches in host memory. See the accelerator implementations for the tensor layouts.
+///
+/// The resulting tensor has dimensions `(num_sequences, num_heads_q, head_size)`.
+#[allow(clippy::too_many_arguments)]
+pub fn paged_attention(
+    q: &Tensor,
+    k_scale: Option<&Tensor>,
+    v_scale: Option<&Tensor>,
+    key_cache: &Tensor,
+    value_cache: &Tensor,
+    block_tables: &Tensor,
+    context_lens: &Tensor,
+    alibi_slopes: Option<&Tensor>,
+    _max_context_len: usize,
+    softmax_scale: f32,
+    softcapping: f32,
+) -> Result<Tensor> {
+    let op = PagedAttention {
+        softmax_scale,
+        softcapping,
+        key_cache: key_cache.clone(),
+        value_cache: value_cache.clone(),
+        block_tables: block_tables.clone(),
+        context_lens: context_lens.clone(),
+        alibi_slopes: alibi_slopes.cloned(),
+        k_scale: cache_scale(key_cache, k_scale)?,
+        v_scale: cache_scale(value_cache, v_scale)?,
+    };
+    q.apply_op1(op)
+}
+
+#[derive(Clone, Copy)]
+enum CacheKind {
+    Key,
+    Value,
+}
+
+struct ReshapeAndCache {
+    slot_mapping: Vec<i64>,
+    scale: f32,
+    kind: CacheKind,
+}
+
+impl ReshapeAndCache {
+    fn write_from<C: WithDType>(
+        &self,
+        cache: &mut [C],
+        cache_l: &Layout,
+        src: &CpuStorage,
+        src_l: &Layout,
+    ) -> Result<()> {
+        match src {
+            CpuStorage::F32(src) => self.write(cache, cache_l, src, src_l),
+            CpuStorage::F16(src) => self.write(cache, cache_l, src, src_l),
+            CpuStorage::BF16(src) => self.write(cache, cache_l, src, src_l),
+            src => candle_core::bail!(
+                "reshape_and_cache is only supported for f32, f16 and bf16 ({:?})",
+                src.dtype()
+            ),
+        }
+    }
+
+    fn write<C: WithDType, T: WithDType>(
+        &self,
+        cache: &mut [C],
+        cache_l: &Layout,
+        src: &[T],
+        src_l: &Layout,
+    ) -> Result<()> {
+        let (num_tokens, num_heads, head_size) = src_l.shape().dims3()?;
+        let src = contiguous(src, src_l, "key/value")?;
+        let layout = match self.kind {
+            CacheKind::Key => CacheLayout::key(cache_l)?,
+            CacheKind::Value => CacheLayout::value(cache_l)?,
+        };
+        if layout.num_heads != num_heads || layout.head_size != head_size {
+            candle_core::bail!(
+                "shape mismatch between input {:?} and cache {:?}",
+                src_l.shape(),
+                cache_l.shape()
+            )
+        }
+        if self.slot_mapping.len() != num_tokens {
+            candle_core::bail!(
+                "shape mismatch slot_mapping {}, expected {num_tokens}",
+                self.slot_mapping.len()
+            )
+        }
+
+        let cache = &mut cache[cache_l.start_offset()..];
+        let inv_scale = 1.0 / self.scale as f64;
+        for (token, &slot) in self.slot_mapping.iter().enumerate() {
+            // Padding tokens are not cached.
+            if slot < 0 {
+                continue;
+            }
+            let block = slot as usize / layout.block_size;
+            let offset = slot as usize % layout.block_size;
+            for head in 0..num_heads {
+                let start = (token * num_heads + head) * head_size;
+                for (d, v) in src[start..start + head_size].iter().enumerate() {
+                    cache[layout.index(block, head, offset, d)] =
+                        C::from_f64(v.to_f64() * inv_scale);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl InplaceOp2 for ReshapeAndCache {
+    fn name(&self) -> &'static str {
+        "reshape-and-cache"
+    }
+
+    fn cpu_fwd(
+        &self,
+        cache: &mut CpuStorage,
+        cache_l: &Layout,
+        src: &CpuStorage,
+        src_l: &Layout,
+    ) -> Result<()> {
+        match cache {
+            CpuStorage::F32(cache) => self.write_from(cache, cache_l, src, src_l),
+            CpuStorage::F16(cache) => self.write_from(cache, cache_l, src, src_l),
+            CpuStorage::BF16(cache) => self.write_from(cache, cache_l, src, src_l),
+            CpuStorage::F8E4M3(cache) => self.write_from(cache, cache_l, src, src_l),
+            cache => candle_core::bail!("cache dtype {:?} is not supported", cache.dtype()),
+        }
+    }
+}
+
+/// Insert key and values at the provided slot mapping inside the key value paged cache.
+///
+/// * `key` - Key tensor of shape `(num_tokens, num_heads, head_size)`.
+/// * `value` - Value tensor of shape `(num_tokens, num_heads, head_size)`.
+/// * `key_cache` - Key cache paged tensor of shape `(num_blocks, num_heads, head_size / x, block_size, x)`
+///   with `x` being the size of an element in bytes.
+/// * `value_cache` - Value cache paged tensor of shape `(num_blocks, num_heads, head_size, block_size)`.
+/// * `slot_mapping` - Mapping associating a slot to each token of shape `(num_tokens)`.
+pub fn reshape_and_cache(
+    key: &Tensor,
+    value: &Tensor,
+    k_scale: Option<&Tensor>,
+    v_scale: Option<&Tensor>,
+    key_cache: &Tensor,
+    value_cache: &Tensor,
+    slot_mapping: &Tensor,
+) -> Result<()> {
+    let slot_mapping = slot_mapping
+        .flatten_all()?
+        .to_dtype(DType::I64)?
+        .to_vec1::<i64>()?;
+    key_cache.inplace_op2(
+        key,
+        &ReshapeAndCache {
+            slot_mapping: slot_mapping.clone(),
+            scale: cache_scale(key_cache, k_scale)?,
+            kind: CacheKind::Key,
+        },
+    )?;
+    value_cache.inplace_op2(
+        value,
+        &ReshapeAndCache {
+            slot_mapping,
+            scale: cache_scale(value_cache, v_scale)?,
+            kind: CacheKind::Value,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    use super::{paged_attention, reshape_and_cache};
+
+    #[test]
+    fn test_matches_naive_attention() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let (num_heads, num_kv_heads, head_size, block_size, num_blocks) = (4, 2, 8, 4, 4);
+        let x = 16 / DType::F32.size_in_bytes();
+        let context_len = 6;
+
+        let key_cache = Tensor::zeros(
+            (num_blocks, num_kv_heads, head_size / x, block_size, x),
+            DType::F32,
+            &dev,
+        )?;
+        let value_cache = Tensor::zeros(
+            (num_blocks, num_kv_heads, head_size, block_size),
+            DType::F32,
+            &dev,
+        )?;
+
+        // Tokens 0..4 live in block 2 and tokens 4..6 in block 0.
+        let k = Tensor::randn(0f32, 1., (context_len, num_kv_heads, head_size), &dev)?;
+        let v = Tensor::randn(0f32, 1., (context_len, num_kv_heads, head_size), &dev)?;
+        let slot_mapping = Tensor::new(&[8i64, 9, 10, 11, 0, 1], &dev)?;
+        reshape_and_cache(&k, &v, None, None, &key_cache, &value_cache, &slot_mapping)?;
+
+        let q = Tensor::randn(0f32, 1., (1, num_heads, head_size), &dev)?;
+        let block_tables = Tensor::new(&[[2u32, 0]], &dev)?;
+        let context_lens = Tensor::new(&[context_len as u32], &dev)?;
+        let scale = 1. / (head_size as f32).sqrt();
+        let out = paged_attention(
+            &q,
+            None,
+            None,
+            &key_cache,
+            &value_cache,
+            &block_tables,
+            &context_lens,
+            None,
+            context_len,
+            scale,
+            1.0,
+        )?;
+
+        for head in 0..num_heads {
+            let kv_head = head / (num_heads / num_kv_heads);
+            let q = q.get(0)?.get(head)?.unsqueeze(0)?;
+            let k = k.narrow(1, kv_head, 1)?.squeeze(1)?;
+            let v = v.narrow(1, kv_head, 1)?.squeeze(1)?;
+            let scores = (q.matmul(&k.t()?)? * scale as f64)?;
+            let scores = scores.broadcast_sub(&scores.max_keepdim(1)?)?.exp()?;
+            let probs = scores.broadcast_div(&scores.sum_keepdim(1)?)?;
+            let expected = probs.matmul(&v)?.squeeze(0)?;
+
+            let diff = (out.get(0)?.get(head)? - expected)?
+                .abs()?
+                .max(0)?
+                .to_scalar::<f32>()?;
+            assert!(diff < 1e-4, "head {head}: max abs diff {diff}");
+        }
+        Ok(())
+    }
+}
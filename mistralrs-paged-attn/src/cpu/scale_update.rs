@@ -0,0 +1,51 @@
+use candle_core::{CpuStorage, DType, InplaceOp1, Layout, Result, Tensor};
+
+/// Matches the accelerator kernels: the scale maps the observed abs-max onto 240.
+const DIV_CONST: f32 = 240.0;
+
+struct KvScaleUpdate {
+    absmax: f32,
+}
+
+impl InplaceOp1 for KvScaleUpdate {
+    fn name(&self) -> &'static str {
+        "kv-scale-update"
+    }
+
+    fn cpu_fwd(&self, scales: &mut CpuStorage, layout: &Layout) -> Result<()> {
+        let CpuStorage::F32(scales) = scales else {
+            candle_core::bail!("kv scales must be f32 ({:?})", scales.dtype())
+        };
+        let candidate = self.absmax / DIV_CONST;
+        let scale = &mut scales[layout.start_offset()];
+        if candidate > *scale {
+            *scale = candidate;
+        }
+        Ok(())
+    }
+}
+
+fn absmax(t: &Tensor) -> Result<f32> {
+    if t.elem_count() == 0 {
+        return Ok(0.);
+    }
+    t.abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_dtype(DType::F32)?
+        .to_scalar::<f32>()
+}
+
+pub fn kv_scale_update(
+    key: &Tensor,
+    value: &Tensor,
+    k_scales: &Tensor,
+    v_scales: &Tensor,
+) -> Result<()> {
+    k_scales.inplace_op1(&KvScaleUpdate {
+        absmax: absmax(key)?,
+    })?;
+    v_scales.inplace_op1(&KvScaleUpdate {
+        absmax: absmax(value)?,
+    })
+}
@@ -14,6 +14,9 @@ pub fn copy_blocks(
     value_caches: Vec<&mut Tensor>,
     block_mapping: &HashMap<usize, Vec<usize>>,
 ) -> Result<()> {
+    if key_caches.first().is_some_and(|c| c.device().is_cpu()) {
+        return crate::cpu::copy_blocks(key_caches, value_caches, block_mapping);
+    }
     let cache_dev = key_caches.first().unwrap().device();
     let Device::Cuda(dev) = cache_dev else {
         panic!("Expected the key caches to be on a CUDA device.")
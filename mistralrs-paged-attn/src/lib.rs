@@ -1,3 +1,7 @@
+mod cpu;
+#[cfg(not(any(all(feature = "cuda", target_family = "unix"), feature = "metal")))]
+pub use cpu::*;
+
 #[cfg(all(feature = "cuda", target_family = "unix"))]
 mod cuda;
 #[cfg(all(feature = "cuda", target_family = "unix"))]
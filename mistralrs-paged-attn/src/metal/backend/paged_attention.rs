@@ -329,6 +329,21 @@ pub fn paged_attention(
     softmax_scale: f32,
     softcapping: f32,
 ) -> Result<Tensor> {
+    if q.device().is_cpu() {
+        return crate::cpu::paged_attention(
+            q,
+            k_scale,
+            v_scale,
+            key_cache,
+            value_cache,
+            block_tables,
+            context_lens,
+            alibi_slopes,
+            max_context_len,
+            softmax_scale,
+            softcapping,
+        );
+    }
     let op = PagedAttention {
         softmax_scale,
         key_cache: key_cache.clone(),
@@ -363,6 +378,17 @@ pub fn reshape_and_cache(
     value_cache: &Tensor,
     slot_mapping: &Tensor,
 ) -> Result<()> {
+    if key.device().is_cpu() {
+        return crate::cpu::reshape_and_cache(
+            key,
+            value,
+            k_scale,
+            v_scale,
+            key_cache,
+            value_cache,
+            slot_mapping,
+        );
+    }
     let kv_ty = match key.dtype() {
         DType::F16 => PagedAttentionDType::F16,
         DType::BF16 => PagedAttentionDType::BF16,
@@ -80,6 +80,9 @@ pub fn kv_scale_update(
     k_scales: &Tensor,
     v_scales: &Tensor,
 ) -> Result<()> {
+    if key.device().is_cpu() {
+        return crate::cpu::kv_scale_update(key, value, k_scales, v_scales);
+    }
     let op = KvScaleUpdate {
         k_scales: k_scales.to_owned(),
         v_scales: v_scales.to_owned(),
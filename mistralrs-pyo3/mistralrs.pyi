@@ -28,6 +28,23 @@ class WebSearchOptions:
 class ToolChoice(Enum):
     NoTools = "None"
     Auto = "Auto"
+    Required = "Required"
+
+@dataclass
+class RequestPriority(Enum):
+    Low = "low"
+    Normal = "normal"
+    High = "high"
+
+@dataclass
+class SamplerKind(Enum):
+    Temperature = "temperature"
+    TopK = "top_k"
+    TopP = "top_p"
+    MinP = "min_p"
+    TypicalP = "typical_p"
+    TopA = "top_a"
+    Xtc = "xtc"
 
 @dataclass
 class ChatCompletionRequest:
@@ -63,6 +80,19 @@ class ChatCompletionRequest:
     web_search_options: WebSearchOptions | None = None
     enable_thinking: bool | None = None
     truncate_sequence: bool = False
+    seed: int | None = None
+    priority: RequestPriority | None = None
+    num_beams: int | None = None
+    length_penalty: float | None = None
+    early_stopping: bool | None = None
+    typical_p: float | None = None
+    top_a: float | None = None
+    xtc_probability: float | None = None
+    xtc_threshold: float | None = None
+    mirostat_tau: float | None = None
+    mirostat_eta: float | None = None
+    sampler_order: list[SamplerKind] | None = None
+    prompt_logprobs: int | None = None
 
 @dataclass
 class CompletionRequest:
@@ -91,6 +121,20 @@ class CompletionRequest:
     truncate_sequence: bool = False
     tool_schemas: list[str] | None = None
     tool_choice: ToolChoice | None = None
+    seed: int | None = None
+    priority: RequestPriority | None = None
+    num_beams: int | None = None
+    length_penalty: float | None = None
+    early_stopping: bool | None = None
+    typical_p: float | None = None
+    top_a: float | None = None
+    xtc_probability: float | None = None
+    xtc_threshold: float | None = None
+    mirostat_tau: float | None = None
+    mirostat_eta: float | None = None
+    sampler_order: list[SamplerKind] | None = None
+    logprobs: int | None = None
+    prompt_logprobs: int | None = None
 
 @dataclass
 class EmbeddingRequest:
@@ -361,6 +405,16 @@ class PagedCacheType(Enum):
     Auto: int = 0
     F8E4M3: int = 1
 
+class KvCacheQuantization(Enum):
+    Int8: int = 0
+    Int4: int = 1
+
+class ChatCompletionStreamer(Iterator[ChatCompletionChunkResponse]):
+    request_id: int
+    """
+    ID of the streamed request, which can be passed to `Runner.cancel_request`.
+    """
+
 class Runner:
     def __init__(
         self,
@@ -368,6 +422,9 @@ class Runner:
         max_seqs: int = 16,
         no_kv_cache: bool = False,
         prefix_cache_n: int = 16,
+        prefix_cache_disk: str | None = None,
+        prefix_cache_disk_size: int = 10240,
+        kv_cache_quant: KvCacheQuantization | None = None,
         token_source: str = "cache",
         speculative_gamma: int = 32,
         which_draft: Which | None = None,
@@ -379,6 +436,7 @@ class Runner:
         pa_gpu_mem: int | float | None = None,
         pa_blk_size: int | None = None,
         pa_cache_type: PagedCacheType | None = None,
+        pa_swap_space: int | None = None,
         no_paged_attn: bool = False,
         paged_attn: bool = False,
         seed: int | None = None,
@@ -386,6 +444,8 @@ class Runner:
         search_embedding_model: str | None = None,
         search_callback: Callable[[str], list[dict[str, str]]] | None = None,
         tool_callbacks: Mapping[str, Callable[[str, dict], str]] | None = None,
+        max_num_batched_tokens: int | None = None,
+        speculative_ngram: tuple[int, int] | None = None,
     ) -> None:
         """
         Load a model.
@@ -394,10 +454,15 @@ class Runner:
         - `max_seqs` specifies how many sequences may be running at any time.
         - `no_kv_cache` disables the KV cache.
         - `prefix_cache_n` sets the number of sequences to hold in the device prefix cache, others will be evicted to CPU.
+        - `prefix_cache_disk` sets a directory to persist evicted prefix caches to, so that they can be reused across restarts.
+            This only applies when PagedAttention is not used.
+        - `prefix_cache_disk_size` sets the size budget in MB for the on-disk prefix cache. Least recently used caches are removed beyond it.
+        - `kv_cache_quant` stores the KV cache quantized to int8 or int4 to reduce its memory usage. Values are dequantized when
+            attention is computed. This only applies when PagedAttention is not used.
         - `token_source` specifies where to load the HF token from.
             The token source follows the following format: "literal:<value>", "env:<value>", "path:<value>", "cache" to use a cached token or "none" to use no token.
         - `speculative_gamma` specifies the `gamma` parameter for specuative decoding, the ratio of draft tokens to generate before calling
-            the target model. If `which_draft` is not specified, this is ignored.
+            the target model. If neither `which_draft` nor `speculative_ngram` is specified, this is ignored.
         - `which_draft` specifies which draft model to load. Setting this parameter will cause a speculative decoding model to be loaded,
             with `which` as the target (higher quality) model and `which_draft` as the draft (lower quality) model.
         - `chat_template` specifies an optional JINJA chat template as a JSON file.
@@ -410,32 +475,39 @@ class Runner:
         - `in_situ_quant` sets the optional in-situ quantization for a model.
         - `anymoe_config` specifies the AnyMoE config. If this is set, then the model will be loaded as an AnyMoE model.
         - `pa_gpu_mem`: GPU memory to allocate for KV cache with PagedAttention in MBs.
-            PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
+            PagedAttention is supported on CUDA, Metal and CPU. It is automatically activated on CUDA but not on Metal or CPU.
             The priority is as follows: `pa-ctxt-len` > `pa-gpu-mem-usage` > `pa-gpu-mem`.
         - `pa_gpu_mem_usage`: Percentage of GPU memory to utilize after allocation of KV cache with PagedAttention, from 0 to 1.
             If this is not set and the device is CUDA, it will default to `0.9`.
-            PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
+            PagedAttention is supported on CUDA, Metal and CPU. It is automatically activated on CUDA but not on Metal or CPU.
             The priority is as follows: `pa-ctxt-len` > `pa-gpu-mem-usage` > `pa-gpu-mem`.
         - `pa_ctxt_len`: Total context length to allocate the KV cache for (total number of tokens which the KV cache can hold).
-            PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
+            PagedAttention is supported on CUDA, Metal and CPU. It is automatically activated on CUDA but not on Metal or CPU.
             The priority is as follows: `pa-ctxt-len` > `pa-gpu-mem-usage` > `pa-gpu-mem`.
             This is the default setting, and it defaults to the `max-seq-len` specified in after the model type.
         - `pa_blk_size` sets the block size (number of tokens per block) for PagedAttention. If this is not set and the device is CUDA,
-            it will default to 32. PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
+            it will default to 32. PagedAttention is supported on CUDA, Metal and CPU. It is automatically activated on CUDA but not on Metal or CPU.
         - `pa_cache_type` sets the PagedAttention KV cache type (auto or f8e4m3). Defaults to `auto`.
+        - `pa_swap_space` sets the host memory in MBs which the PagedAttention KV cache of preempted sequences is swapped out to.
+            By default, preempted sequences drop their KV cache and are prefilled again when rescheduled.
         - `no_paged_attn` disables PagedAttention on CUDA. Because PagedAttention is already disabled on Metal, this is only applicable on CUDA.
-        - `paged_attn` enables PagedAttention on Metal. Because PagedAttention is already enabled on CUDA, this is only applicable on Metal.
+        - `paged_attn` enables PagedAttention on Metal or CPU. Because PagedAttention is already enabled on CUDA, this is only applicable on Metal and CPU.
         - `seed`, used to ensure reproducible random number generation.
         - `enable_search`: Enable searching compatible with the OpenAI `web_search_options` setting. This loads the selected search embedding reranker (EmbeddingGemma by default).
         - `search_embedding_model`: select which built-in search embedding model to load (currently `"embedding_gemma"`).
         - `search_callback`: Custom Python callable to perform web searches. Should accept a query string and return a list of dicts with keys "title", "description", "url", and "content".
         - `tool_callbacks`: Mapping from tool name to Python callable invoked for generic tool calls. Each callable receives the tool name and a dict of arguments and should return the tool output as a string.
+        - `max_num_batched_tokens`: Token budget of an engine step. If set, long prompts are prefilled in chunks of at most this many
+            tokens, interleaved with the decoding of running sequences, so that a long prompt does not stall them.
+        - `speculative_ngram`: `(min_ngram, max_ngram)` to use n-gram (prompt lookup) speculative decoding without a draft model: up to
+            `speculative_gamma` tokens which followed an earlier occurrence of the last n-gram are proposed and verified by `which`.
+            Mutually exclusive with `which_draft`.
         """
         ...
 
     def send_chat_completion_request(
         self, request: ChatCompletionRequest
-    ) -> ChatCompletionResponse | Iterator[ChatCompletionChunkResponse]:
+    ) -> ChatCompletionResponse | ChatCompletionStreamer:
         """
         Send a chat completion request to the mistral.rs engine, returning the response object or a generator
         over chunk objects.
@@ -494,6 +566,17 @@ class Runner:
         the concept does not apply (such as diffusion or speech models).
         """
 
+    def engine_stats(self, model_id: str | None = None) -> EngineStats:
+        """
+        Return a snapshot of the scheduling, KV cache and throughput statistics for the current or specified model.
+        """
+
+    def cancel_request(self, request_id: int, model_id: str | None = None) -> None:
+        """
+        Cancel a request by its ID, such as the `request_id` of a chat completion stream. Its sequences are
+        finished with the `canceled` finish reason, including those which are still waiting to be scheduled.
+        """
+
 class MultiModelRunner:
     def __init__(self, runner: Runner) -> None:
         """
@@ -503,7 +586,7 @@ class MultiModelRunner:
 
     def send_chat_completion_request_to_model(
         self, request: ChatCompletionRequest, model_id: str
-    ) -> ChatCompletionResponse | Iterator[ChatCompletionChunkResponse]:
+    ) -> ChatCompletionResponse | ChatCompletionStreamer:
         """
         Send a chat completion request to a specific model ID, returning the response object
         or a generator over streamed chunks.
@@ -534,6 +617,16 @@ class MultiModelRunner:
         (for example diffusion or speech models).
         """
 
+    def engine_stats(self, model_id: str | None = None) -> EngineStats:
+        """
+        Return a snapshot of the engine statistics for the selected model.
+        """
+
+    def cancel_request(self, request_id: int, model_id: str | None = None) -> None:
+        """
+        Cancel a request by its ID on the selected model.
+        """
+
     def get_default_model_id(self) -> str | None:
         """
         Return the current default model ID, if any.
@@ -553,7 +646,7 @@ class MultiModelRunner:
         self,
         request: ChatCompletionRequest,
         model_id: str | None = None,
-    ) -> ChatCompletionResponse | Iterator[ChatCompletionChunkResponse]:
+    ) -> ChatCompletionResponse | ChatCompletionStreamer:
         """
         Send a chat completion request, optionally targeting a specific model ID.
         """
@@ -695,6 +788,32 @@ class Usage:
     total_prompt_time_sec: float
     total_completion_time_sec: float
 
+@dataclass
+class EngineStats:
+    num_running: int
+    num_waiting: int
+    kv_blocks_total: int | None
+    kv_blocks_used: int | None
+    kv_blocks_free: int | None
+    kv_blocks_cached: int | None
+    num_sequences: int
+    prefix_cache_hits: int
+    prefix_cache_hit_rate: float
+    prefill_tokens_per_sec: float
+    decode_tokens_per_sec: float
+    total_prefill_tokens: int
+    total_decode_tokens: int
+    num_preemptions: int
+    time_to_first_token: LatencyHistogram
+    inter_token_latency: LatencyHistogram
+
+@dataclass
+class LatencyHistogram:
+    buckets: list[float]
+    counts: list[int]
+    count: int
+    sum: float
+
 @dataclass
 class ToolCallType(Enum):
     Function = "function"
@@ -710,6 +829,18 @@ class ToolCallResponse:
     type: ToolCallType
     function: CalledFunction
 
+@dataclass
+class FunctionDelta:
+    name: str | None
+    arguments: str
+
+@dataclass
+class ToolCallDelta:
+    index: int
+    id: str | None
+    type: ToolCallType | None
+    function: FunctionDelta
+
 @dataclass
 class ResponseMessage:
     content: str
@@ -739,6 +870,7 @@ class Choice:
     index: int
     message: ResponseMessage
     logprobs: Logprobs
+    stop_sequence: str | None
 
 @dataclass
 class ChatCompletionResponse:
@@ -749,11 +881,13 @@ class ChatCompletionResponse:
     system_fingerprint: str
     object: str
     usage: Usage
+    prompt_logprobs: list[ResponseLogprob | None] | None
 
 @dataclass
 class Delta:
     content: str
     role: str
+    tool_calls: list[ToolCallDelta] | None
 
 @dataclass
 class ChunkChoice:
@@ -761,6 +895,7 @@ class ChunkChoice:
     index: int
     delta: Delta
     logprobs: ResponseLogprob | None
+    stop_sequence: str | None
 
 @dataclass
 class ChatCompletionChunkResponse:
@@ -771,12 +906,19 @@ class ChatCompletionChunkResponse:
     system_fingerprint: str
     object: str
 
+@dataclass
+class CompletionLogprobs:
+    tokens: list[str]
+    token_logprobs: list[float | None]
+    top_logprobs: list[dict[str, float] | None]
+    text_offset: list[int]
+
 @dataclass
 class CompletionChoice:
     finish_reason: str
     index: int
     text: str
-    # NOTE(EricLBuehler): `logprobs` in undocumented
+    logprobs: CompletionLogprobs | None
 
 @dataclass
 class CompletionResponse:
@@ -787,6 +929,7 @@ class CompletionResponse:
     system_fingerprint: str
     object: str
     usage: Usage
+    prompt_logprobs: list[ResponseLogprob | None] | None
 
 @dataclass
 class ImageChoice:
@@ -819,6 +962,15 @@ class McpServerConfigPy:
     resources: Optional[list[str]] = None
     bearer_token: Optional[str] = None
 
+@dataclass
+class McpSamplingConfigPy:
+    """Configuration for serving the sampling requests of MCP servers with the model"""
+
+    model: Optional[str] = None
+    use_model_hints: bool = True
+    max_tokens: Optional[int] = None
+    allowed_servers: Optional[list[str]] = None
+
 @dataclass
 class McpClientConfigPy:
     """Configuration for MCP client integration"""
@@ -827,3 +979,4 @@ class McpClientConfigPy:
     auto_register_tools: bool = True
     tool_timeout_secs: Optional[int] = None
     max_concurrent_calls: Optional[int] = None
+    sampling: Optional[McpSamplingConfigPy] = None
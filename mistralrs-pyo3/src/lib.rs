@@ -22,22 +22,24 @@ use util::{PyApiErr, PyApiResult};
 use candle_core::{Device, Result};
 use mistralrs_core::{
     initialize_logging, paged_attn_supported, parse_isq_value, AnyMoeLoader, AutoDeviceMapParams,
-    ChatCompletionResponse, CompletionResponse, Constraint, DefaultSchedulerMethod,
-    DetokenizationRequest, DeviceLayerMapMetadata, DeviceMapMetadata, DeviceMapSetting,
-    DiffusionGenerationParams, DiffusionLoaderBuilder, DrySamplingParams, EmbeddingLoaderBuilder,
-    EmbeddingSpecificConfig, GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoaderBuilder,
-    GGUFSpecificConfig, ImageGenerationResponse, ImageGenerationResponseFormat, LlguidanceGrammar,
-    Loader, MemoryGpuConfig, MistralRs, MistralRsBuilder, NormalLoaderBuilder, NormalRequest,
-    NormalSpecificConfig, PagedAttentionConfig, PagedCacheType, ReasoningEffort,
-    Request as _Request, RequestMessage, Response, ResponseOk, SamplingParams, SchedulerConfig,
-    SearchEmbeddingModel, SpeculativeConfig, SpeculativeLoader, SpeechLoader, StopTokens,
-    TokenSource, TokenizationRequest, Tool, Topology, VisionLoaderBuilder, VisionSpecificConfig,
+    BeamSearchParams, ChatCompletionResponse, CompletionResponse, Constraint,
+    DefaultSchedulerMethod, DetokenizationRequest, DeviceLayerMapMetadata, DeviceMapMetadata,
+    DeviceMapSetting, DiffusionGenerationParams, DiffusionLoaderBuilder, DrySamplingParams,
+    EmbeddingLoaderBuilder, EmbeddingSpecificConfig, GGMLLoaderBuilder, GGMLSpecificConfig,
+    GGUFLoaderBuilder, GGUFSpecificConfig, ImageGenerationResponse, ImageGenerationResponseFormat,
+    KvCacheQuantization, LlguidanceGrammar, Loader, MemoryGpuConfig, MirostatParams, MistralRs,
+    MistralRsBuilder, NormalLoaderBuilder, NormalRequest, NormalSpecificConfig,
+    PagedAttentionConfig, PagedCacheType, PreemptionMode, PrefixCacheDiskConfig, ReasoningEffort,
+    Request as _Request, RequestMessage, RequestPriority, Response, ResponseOk, SamplingParams,
+    SchedulerConfig, SearchEmbeddingModel, SpeculativeConfig, SpeculativeDraft, SpeculativeLoader,
+    SpeechLoader, StopTokens, TokenSource, TokenizationRequest, Tool, Topology,
+    VisionLoaderBuilder, VisionSpecificConfig, XtcSamplingParams,
 };
 use mistralrs_core::{
     CalledFunction, SearchCallback, SearchFunctionParameters, SearchResult, ToolCallback,
     ToolCallbacks,
 };
-use mistralrs_mcp::{McpClientConfig, McpServerConfig, McpServerSource};
+use mistralrs_mcp::{McpClientConfig, McpSamplingConfig, McpServerConfig, McpServerSource};
 use pyo3::prelude::*;
 use pyo3::types::PyList;
 use pyo3::Bound;
@@ -585,6 +587,9 @@ impl Runner {
         max_seqs = 16,
         no_kv_cache = false,
         prefix_cache_n = 16,
+        prefix_cache_disk = None,
+        prefix_cache_disk_size = 10240,
+        kv_cache_quant = None,
         token_source = "cache",
         speculative_gamma = 32,
         which_draft = None,
@@ -598,6 +603,7 @@ impl Runner {
         pa_ctxt_len = None,
         pa_blk_size = None,
         pa_cache_type = None,
+        pa_swap_space = None,
         no_paged_attn = false,
         paged_attn = false,
         seed = None,
@@ -606,12 +612,17 @@ impl Runner {
         search_callback = None,
         tool_callbacks = None,
         mcp_client_config = None,
+        max_num_batched_tokens = None,
+        speculative_ngram = None,
     ))]
     fn new(
         which: Which,
         max_seqs: usize,
         no_kv_cache: bool,
         prefix_cache_n: usize,
+        prefix_cache_disk: Option<String>,
+        prefix_cache_disk_size: usize,
+        kv_cache_quant: Option<KvCacheQuantization>,
         token_source: &str,
         speculative_gamma: usize,
         which_draft: Option<Which>,
@@ -625,6 +636,7 @@ impl Runner {
         pa_ctxt_len: Option<usize>,
         pa_blk_size: Option<usize>,
         pa_cache_type: Option<PagedCacheType>,
+        pa_swap_space: Option<usize>,
         no_paged_attn: bool,
         paged_attn: bool,
         seed: Option<u64>,
@@ -633,6 +645,8 @@ impl Runner {
         search_callback: Option<PyObject>,
         tool_callbacks: Option<PyObject>,
         mcp_client_config: Option<McpClientConfigPy>,
+        max_num_batched_tokens: Option<usize>,
+        speculative_ngram: Option<(usize, usize)>,
     ) -> PyApiResult<Self> {
         let tgt_non_granular_index = match which {
             Which::Plain { .. }
@@ -737,17 +751,35 @@ impl Runner {
             chat_template.clone(),
             jinja_explicit.clone(),
         )?;
-        let loader = if let Some(draft_which) = which_draft {
-            let draft = parse_which(draft_which, no_kv_cache, chat_template, jinja_explicit)?;
-            Box::new(SpeculativeLoader {
+        let loader = match (which_draft, speculative_ngram) {
+            (Some(_), Some(_)) => {
+                return Err(PyApiErr::from(
+                    "`which_draft` and `speculative_ngram` are mutually exclusive.",
+                ));
+            }
+            (Some(draft_which), None) => {
+                let draft = parse_which(draft_which, no_kv_cache, chat_template, jinja_explicit)?;
+                Box::new(SpeculativeLoader {
+                    target: loader,
+                    draft: Some(draft),
+                    config: SpeculativeConfig {
+                        gamma: speculative_gamma,
+                        draft: SpeculativeDraft::Model,
+                    },
+                })
+            }
+            (None, Some((min_ngram, max_ngram))) => Box::new(SpeculativeLoader {
                 target: loader,
-                draft,
+                draft: None,
                 config: SpeculativeConfig {
                     gamma: speculative_gamma,
+                    draft: SpeculativeDraft::NGram {
+                        min_ngram,
+                        max_ngram,
+                    },
                 },
-            })
-        } else {
-            loader
+            }),
+            (None, None) => loader,
         };
         let loader = if let Some(amoe_conf) = anymoe_config {
             Box::new(AnyMoeLoader {
@@ -817,10 +849,9 @@ impl Runner {
 
         let no_paged_attn = if device.is_cuda() || mistralrs_core::distributed::use_nccl() {
             no_paged_attn
-        } else if device.is_metal() {
-            !paged_attn
         } else {
-            true
+            // Metal and CPU: PagedAttention is opt-in
+            !paged_attn
         };
 
         let cache_config = match (
@@ -872,6 +903,11 @@ impl Runner {
             }
             (_, _, _, _, _, _) => None,
         };
+        let preemption_mode = match pa_swap_space {
+            Some(swap_space_mb) => PreemptionMode::Swap { swap_space_mb },
+            None => PreemptionMode::Recompute,
+        };
+        let cache_config = cache_config.map(|config| config.with_preemption_mode(preemption_mode));
 
         let pipeline = loader
             .load_model_from_hf(
@@ -892,6 +928,7 @@ impl Runner {
                 SchedulerConfig::PagedAttentionMeta {
                     max_num_seqs: max_seqs,
                     config: cache_config.clone(),
+                    max_num_batched_tokens,
                 }
             } else {
                 SchedulerConfig::DefaultScheduler {
@@ -900,6 +937,7 @@ impl Runner {
                             .try_into()
                             .map_err(|e| PyApiErr::from(format!("{e:?}")))?,
                     ),
+                    max_num_batched_tokens,
                 }
             }
         } else {
@@ -909,6 +947,7 @@ impl Runner {
                         .try_into()
                         .map_err(|e| PyApiErr::from(format!("{e:?}")))?,
                 ),
+                max_num_batched_tokens,
             }
         };
         let search_embedding_model = if enable_search {
@@ -939,6 +978,13 @@ impl Runner {
         if let Some(mcp_config) = mcp_client_config {
             builder = builder.with_mcp_client(mcp_config.into());
         }
+        if let Some(path) = prefix_cache_disk {
+            builder = builder
+                .with_prefix_cache_disk(PrefixCacheDiskConfig::new(path, prefix_cache_disk_size));
+        }
+        if let Some(quantization) = kv_cache_quant {
+            builder = builder.with_kv_cache_quantization(quantization);
+        }
         let rt = Runtime::new().expect("Failed to create Runner::new runtime");
         let mistralrs = rt.block_on(async {
             builder
@@ -968,6 +1014,34 @@ impl Runner {
             let constraint =
                 build_constraint(request.grammar.as_deref(), request.grammar_type.as_deref())?;
 
+            let xtc_params = if let Some(xtc_probability) = request.xtc_probability {
+                Some(XtcSamplingParams::new_with_defaults(
+                    xtc_probability,
+                    request.xtc_threshold,
+                )?)
+            } else {
+                None
+            };
+
+            let mirostat_params = if let Some(mirostat_tau) = request.mirostat_tau {
+                Some(MirostatParams::new_with_defaults(
+                    mirostat_tau,
+                    request.mirostat_eta,
+                )?)
+            } else {
+                None
+            };
+
+            let beam_search = if let Some(num_beams) = request.num_beams {
+                Some(BeamSearchParams::new_with_defaults(
+                    num_beams,
+                    request.length_penalty,
+                    request.early_stopping,
+                )?)
+            } else {
+                None
+            };
+
             let dry_params = if let Some(dry_multiplier) = request.dry_multiplier {
                 Some(DrySamplingParams::new_with_defaults(
                     dry_multiplier,
@@ -1192,6 +1266,7 @@ impl Runner {
             let tool_choice = request.tool_choice.as_ref().map(|x| match x {
                 ToolChoice::Auto => mistralrs_core::ToolChoice::Auto,
                 ToolChoice::NoTools => mistralrs_core::ToolChoice::None,
+                ToolChoice::Required => mistralrs_core::ToolChoice::Required,
             });
 
             let tools = if let Some(tools) = &request.tool_schemas {
@@ -1204,14 +1279,15 @@ impl Runner {
                 None
             };
 
+            let request_id = {
+                let l = NEXT_REQUEST_ID.lock().unwrap();
+                let last = &mut *l.borrow_mut();
+                let last_v = *last;
+                *last += 1;
+                last_v
+            };
             let model_request = _Request::Normal(Box::new(NormalRequest {
-                id: {
-                    let l = NEXT_REQUEST_ID.lock().unwrap();
-                    let last = &mut *l.borrow_mut();
-                    let last_v = *last;
-                    *last += 1;
-                    last_v
-                },
+                id: request_id,
                 messages,
                 sampling_params: SamplingParams {
                     temperature: request.temperature,
@@ -1227,6 +1303,13 @@ impl Runner {
                     n_choices: request.n_choices,
                     min_p: request.min_p,
                     dry_params,
+                    seed: request.seed,
+                    beam_search,
+                    typical_p: request.typical_p,
+                    top_a: request.top_a,
+                    xtc_params,
+                    mirostat_params,
+                    sampler_order: request.sampler_order.clone(),
                 },
                 response: tx,
                 return_logprobs: request.logprobs,
@@ -1240,6 +1323,10 @@ impl Runner {
                 web_search_options: request.web_search_options.clone(),
                 model_id: model_id.clone(),
                 truncate_sequence: request.truncate_sequence,
+                priority: request.priority,
+                timeout: None,
+                prompt_logprobs: request.prompt_logprobs,
+                traceparent: None,
             }));
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -1247,7 +1334,9 @@ impl Runner {
             sender.blocking_send(model_request).unwrap();
 
             if request.stream {
-                Ok(Either::Right(ChatCompletionStreamer::from_rx(rx)))
+                Ok(Either::Right(ChatCompletionStreamer::from_rx(
+                    rx, request_id,
+                )))
             } else {
                 let response = rx.blocking_recv().unwrap();
 
@@ -1324,6 +1413,10 @@ impl Runner {
                     web_search_options: None,
                     model_id: model_id.clone(),
                     truncate_sequence,
+                    priority: RequestPriority::default(),
+                    timeout: None,
+                    prompt_logprobs: None,
+                    traceparent: None,
                 }));
 
                 sender
@@ -1426,6 +1519,7 @@ impl Runner {
             let tool_choice = request.tool_choice.as_ref().map(|x| match x {
                 ToolChoice::Auto => mistralrs_core::ToolChoice::Auto,
                 ToolChoice::NoTools => mistralrs_core::ToolChoice::None,
+                ToolChoice::Required => mistralrs_core::ToolChoice::Required,
             });
 
             let tools = if let Some(tools) = &request.tool_schemas {
@@ -1438,6 +1532,34 @@ impl Runner {
                 None
             };
 
+            let xtc_params = if let Some(xtc_probability) = request.xtc_probability {
+                Some(XtcSamplingParams::new_with_defaults(
+                    xtc_probability,
+                    request.xtc_threshold,
+                )?)
+            } else {
+                None
+            };
+
+            let mirostat_params = if let Some(mirostat_tau) = request.mirostat_tau {
+                Some(MirostatParams::new_with_defaults(
+                    mirostat_tau,
+                    request.mirostat_eta,
+                )?)
+            } else {
+                None
+            };
+
+            let beam_search = if let Some(num_beams) = request.num_beams {
+                Some(BeamSearchParams::new_with_defaults(
+                    num_beams,
+                    request.length_penalty,
+                    request.early_stopping,
+                )?)
+            } else {
+                None
+            };
+
             let dry_params = if let Some(dry_multiplier) = request.dry_multiplier {
                 Some(DrySamplingParams::new_with_defaults(
                     dry_multiplier,
@@ -1466,7 +1588,7 @@ impl Runner {
                     temperature: request.temperature,
                     top_k: request.top_k,
                     top_p: request.top_p,
-                    top_n_logprobs: 1,
+                    top_n_logprobs: request.logprobs.unwrap_or(1),
                     frequency_penalty: request.frequency_penalty,
                     presence_penalty: request.presence_penalty,
                     repetition_penalty: request.repetition_penalty,
@@ -1476,9 +1598,16 @@ impl Runner {
                     n_choices: request.n_choices,
                     min_p: request.min_p,
                     dry_params,
+                    seed: request.seed,
+                    beam_search,
+                    typical_p: request.typical_p,
+                    top_a: request.top_a,
+                    xtc_params,
+                    mirostat_params,
+                    sampler_order: request.sampler_order.clone(),
                 },
                 response: tx,
-                return_logprobs: false,
+                return_logprobs: request.logprobs.is_some(),
                 is_streaming: false,
                 constraint,
                 suffix: request.suffix.clone(),
@@ -1489,6 +1618,12 @@ impl Runner {
                 web_search_options: None,
                 model_id: model_id.clone(),
                 truncate_sequence: request.truncate_sequence,
+                priority: request.priority,
+                timeout: None,
+                prompt_logprobs: request
+                    .prompt_logprobs
+                    .or(request.logprobs.filter(|_| request.echo_prompt)),
+                traceparent: None,
             }));
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -1552,6 +1687,10 @@ impl Runner {
             web_search_options: None,
             model_id: model_id.clone(),
             truncate_sequence: false,
+            priority: RequestPriority::default(),
+            timeout: None,
+            prompt_logprobs: None,
+            traceparent: None,
         }));
 
         let sender = self.runner.get_sender(model_id.as_deref())?;
@@ -1596,6 +1735,10 @@ impl Runner {
             web_search_options: None,
             model_id: model_id.clone(),
             truncate_sequence: false,
+            priority: RequestPriority::default(),
+            timeout: None,
+            prompt_logprobs: None,
+            traceparent: None,
         }));
 
         let sender = self.runner.get_sender(model_id.as_deref())?;
@@ -1700,6 +1843,23 @@ impl Runner {
             .map_err(PyApiErr::from)
     }
 
+    /// Return a snapshot of the scheduling, KV cache and throughput statistics for the requested model.
+    #[pyo3(signature = (model_id = None))]
+    fn engine_stats(&self, model_id: Option<String>) -> PyApiResult<mistralrs_core::EngineStats> {
+        self.runner
+            .engine_stats(model_id.as_deref())
+            .map_err(PyApiErr::from)
+    }
+
+    /// Cancel a request, such as a streamed chat completion, by its ID. Its sequences are
+    /// finished with the `canceled` finish reason.
+    #[pyo3(signature = (request_id, model_id = None))]
+    fn cancel_request(&self, request_id: usize, model_id: Option<String>) -> PyApiResult<()> {
+        self.runner
+            .cancel_request(request_id, model_id.as_deref())
+            .map_err(PyApiErr::from)
+    }
+
     /// Get the default model ID in multi-model mode.
     fn get_default_model_id(&self) -> PyApiResult<Option<String>> {
         self.runner.get_default_model_id().map_err(PyApiErr::from)
@@ -1733,6 +1893,34 @@ impl Runner {
             let constraint =
                 build_constraint(request.grammar.as_deref(), request.grammar_type.as_deref())?;
 
+            let xtc_params = if let Some(xtc_probability) = request.xtc_probability {
+                Some(XtcSamplingParams::new_with_defaults(
+                    xtc_probability,
+                    request.xtc_threshold,
+                )?)
+            } else {
+                None
+            };
+
+            let mirostat_params = if let Some(mirostat_tau) = request.mirostat_tau {
+                Some(MirostatParams::new_with_defaults(
+                    mirostat_tau,
+                    request.mirostat_eta,
+                )?)
+            } else {
+                None
+            };
+
+            let beam_search = if let Some(num_beams) = request.num_beams {
+                Some(BeamSearchParams::new_with_defaults(
+                    num_beams,
+                    request.length_penalty,
+                    request.early_stopping,
+                )?)
+            } else {
+                None
+            };
+
             let dry_params = if let Some(dry_multiplier) = request.dry_multiplier {
                 Some(DrySamplingParams::new_with_defaults(
                     dry_multiplier,
@@ -1957,6 +2145,7 @@ impl Runner {
             let tool_choice = request.tool_choice.as_ref().map(|x| match x {
                 ToolChoice::Auto => mistralrs_core::ToolChoice::Auto,
                 ToolChoice::NoTools => mistralrs_core::ToolChoice::None,
+                ToolChoice::Required => mistralrs_core::ToolChoice::Required,
             });
 
             let tools = if let Some(tools) = &request.tool_schemas {
@@ -1969,14 +2158,15 @@ impl Runner {
                 None
             };
 
+            let request_id = {
+                let l = NEXT_REQUEST_ID.lock().unwrap();
+                let last = &mut *l.borrow_mut();
+                let last_v = *last;
+                *last += 1;
+                last_v
+            };
             let model_request = _Request::Normal(Box::new(NormalRequest {
-                id: {
-                    let l = NEXT_REQUEST_ID.lock().unwrap();
-                    let last = &mut *l.borrow_mut();
-                    let last_v = *last;
-                    *last += 1;
-                    last_v
-                },
+                id: request_id,
                 messages,
                 sampling_params: SamplingParams {
                     temperature: request.temperature,
@@ -1992,6 +2182,13 @@ impl Runner {
                     n_choices: request.n_choices,
                     min_p: request.min_p,
                     dry_params,
+                    seed: request.seed,
+                    beam_search,
+                    typical_p: request.typical_p,
+                    top_a: request.top_a,
+                    xtc_params,
+                    mirostat_params,
+                    sampler_order: request.sampler_order.clone(),
                 },
                 response: tx,
                 return_logprobs: request.logprobs,
@@ -2005,6 +2202,10 @@ impl Runner {
                 web_search_options: request.web_search_options.clone(),
                 model_id: Some(model_id.clone()),
                 truncate_sequence: request.truncate_sequence,
+                priority: request.priority,
+                timeout: None,
+                prompt_logprobs: request.prompt_logprobs,
+                traceparent: None,
             }));
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -2012,7 +2213,9 @@ impl Runner {
             sender.blocking_send(model_request).unwrap();
 
             if request.stream {
-                Ok(Either::Right(ChatCompletionStreamer::from_rx(rx)))
+                Ok(Either::Right(ChatCompletionStreamer::from_rx(
+                    rx, request_id,
+                )))
             } else {
                 let response = rx.blocking_recv().unwrap();
 
@@ -2054,6 +2257,7 @@ impl Runner {
             let tool_choice = request.tool_choice.as_ref().map(|x| match x {
                 ToolChoice::Auto => mistralrs_core::ToolChoice::Auto,
                 ToolChoice::NoTools => mistralrs_core::ToolChoice::None,
+                ToolChoice::Required => mistralrs_core::ToolChoice::Required,
             });
 
             let tools = if let Some(tools) = &request.tool_schemas {
@@ -2066,6 +2270,34 @@ impl Runner {
                 None
             };
 
+            let xtc_params = if let Some(xtc_probability) = request.xtc_probability {
+                Some(XtcSamplingParams::new_with_defaults(
+                    xtc_probability,
+                    request.xtc_threshold,
+                )?)
+            } else {
+                None
+            };
+
+            let mirostat_params = if let Some(mirostat_tau) = request.mirostat_tau {
+                Some(MirostatParams::new_with_defaults(
+                    mirostat_tau,
+                    request.mirostat_eta,
+                )?)
+            } else {
+                None
+            };
+
+            let beam_search = if let Some(num_beams) = request.num_beams {
+                Some(BeamSearchParams::new_with_defaults(
+                    num_beams,
+                    request.length_penalty,
+                    request.early_stopping,
+                )?)
+            } else {
+                None
+            };
+
             let dry_params = if let Some(dry_multiplier) = request.dry_multiplier {
                 Some(DrySamplingParams::new_with_defaults(
                     dry_multiplier,
@@ -2094,7 +2326,7 @@ impl Runner {
                     temperature: request.temperature,
                     top_k: request.top_k,
                     top_p: request.top_p,
-                    top_n_logprobs: 1,
+                    top_n_logprobs: request.logprobs.unwrap_or(1),
                     frequency_penalty: request.frequency_penalty,
                     presence_penalty: request.presence_penalty,
                     repetition_penalty: request.repetition_penalty,
@@ -2104,9 +2336,16 @@ impl Runner {
                     n_choices: request.n_choices,
                     min_p: request.min_p,
                     dry_params,
+                    seed: request.seed,
+                    beam_search,
+                    typical_p: request.typical_p,
+                    top_a: request.top_a,
+                    xtc_params,
+                    mirostat_params,
+                    sampler_order: request.sampler_order.clone(),
                 },
                 response: tx,
-                return_logprobs: false,
+                return_logprobs: request.logprobs.is_some(),
                 is_streaming: false,
                 constraint,
                 suffix: request.suffix.clone(),
@@ -2117,6 +2356,12 @@ impl Runner {
                 web_search_options: None,
                 model_id: Some(model_id.clone()),
                 truncate_sequence: request.truncate_sequence,
+                priority: request.priority,
+                timeout: None,
+                prompt_logprobs: request
+                    .prompt_logprobs
+                    .or(request.logprobs.filter(|_| request.echo_prompt)),
+                traceparent: None,
             }));
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -2201,6 +2446,18 @@ impl MultiModelRunner {
         self.runner.max_sequence_length(model_id)
     }
 
+    /// Return a snapshot of the engine statistics for a model.
+    #[pyo3(signature = (model_id = None))]
+    fn engine_stats(&self, model_id: Option<String>) -> PyApiResult<mistralrs_core::EngineStats> {
+        self.runner.engine_stats(model_id)
+    }
+
+    /// Cancel a request by its ID on a model.
+    #[pyo3(signature = (request_id, model_id = None))]
+    fn cancel_request(&self, request_id: usize, model_id: Option<String>) -> PyApiResult<()> {
+        self.runner.cancel_request(request_id, model_id)
+    }
+
     /// Get the default model ID.
     fn get_default_model_id(&self) -> PyApiResult<Option<String>> {
         self.runner.get_default_model_id()
@@ -2437,6 +2694,50 @@ impl From<McpServerConfigPy> for McpServerConfig {
     }
 }
 
+/// Configuration for serving the sampling requests of MCP servers with the model
+#[pyclass]
+#[derive(Debug, Clone)]
+pub struct McpSamplingConfigPy {
+    #[pyo3(get, set)]
+    pub model: Option<String>,
+    #[pyo3(get, set)]
+    pub use_model_hints: bool,
+    #[pyo3(get, set)]
+    pub max_tokens: Option<usize>,
+    #[pyo3(get, set)]
+    pub allowed_servers: Option<Vec<String>>,
+}
+
+#[pymethods]
+impl McpSamplingConfigPy {
+    #[new]
+    #[pyo3(signature = (model=None, use_model_hints=true, max_tokens=None, allowed_servers=None))]
+    pub fn new(
+        model: Option<String>,
+        use_model_hints: bool,
+        max_tokens: Option<usize>,
+        allowed_servers: Option<Vec<String>>,
+    ) -> Self {
+        Self {
+            model,
+            use_model_hints,
+            max_tokens,
+            allowed_servers,
+        }
+    }
+}
+
+impl From<McpSamplingConfigPy> for McpSamplingConfig {
+    fn from(config: McpSamplingConfigPy) -> Self {
+        McpSamplingConfig {
+            model: config.model,
+            use_model_hints: config.use_model_hints,
+            max_tokens: config.max_tokens,
+            allowed_servers: config.allowed_servers,
+        }
+    }
+}
+
 /// Configuration for MCP client integration
 #[pyclass]
 #[derive(Debug, Clone)]
@@ -2449,23 +2750,27 @@ pub struct McpClientConfigPy {
     pub tool_timeout_secs: Option<u64>,
     #[pyo3(get, set)]
     pub max_concurrent_calls: Option<usize>,
+    #[pyo3(get, set)]
+    pub sampling: Option<McpSamplingConfigPy>,
 }
 
 #[pymethods]
 impl McpClientConfigPy {
     #[new]
-    #[pyo3(signature = (servers, auto_register_tools=true, tool_timeout_secs=None, max_concurrent_calls=None))]
+    #[pyo3(signature = (servers, auto_register_tools=true, tool_timeout_secs=None, max_concurrent_calls=None, sampling=None))]
     pub fn new(
         servers: Vec<McpServerConfigPy>,
         auto_register_tools: bool,
         tool_timeout_secs: Option<u64>,
         max_concurrent_calls: Option<usize>,
+        sampling: Option<McpSamplingConfigPy>,
     ) -> Self {
         Self {
             servers,
             auto_register_tools,
             tool_timeout_secs,
             max_concurrent_calls,
+            sampling,
         }
     }
 }
@@ -2477,6 +2782,7 @@ impl From<McpClientConfigPy> for McpClientConfig {
             auto_register_tools: config.auto_register_tools,
             tool_timeout_secs: config.tool_timeout_secs,
             max_concurrent_calls: config.max_concurrent_calls,
+            sampling: config.sampling.map(Into::into),
         }
     }
 }
@@ -2510,13 +2816,20 @@ fn mistralrs(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<mistralrs_core::Usage>()?;
     m.add_class::<mistralrs_core::ChatCompletionResponse>()?;
     m.add_class::<mistralrs_core::ChatCompletionChunkResponse>()?;
+    m.add_class::<mistralrs_core::CompletionLogprobs>()?;
     m.add_class::<mistralrs_core::CompletionChoice>()?;
     m.add_class::<mistralrs_core::CompletionResponse>()?;
     m.add_class::<mistralrs_core::TopLogprob>()?;
     m.add_class::<mistralrs_core::ModelDType>()?;
     m.add_class::<mistralrs_core::ImageGenerationResponseFormat>()?;
+    m.add_class::<mistralrs_core::RequestPriority>()?;
+    m.add_class::<mistralrs_core::KvCacheQuantization>()?;
+    m.add_class::<mistralrs_core::EngineStats>()?;
+    m.add_class::<mistralrs_core::LatencyHistogram>()?;
+    m.add_class::<mistralrs_core::SamplerKind>()?;
     m.add_class::<McpServerSourcePy>()?;
     m.add_class::<McpServerConfigPy>()?;
+    m.add_class::<McpSamplingConfigPy>()?;
     m.add_class::<McpClientConfigPy>()?;
     Ok(())
 }
@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 
 use either::Either;
-use mistralrs_core::WebSearchOptions;
+use mistralrs_core::{RequestPriority, SamplerKind, WebSearchOptions};
 use pyo3::{
     exceptions::{PyTypeError, PyValueError},
     pyclass, pymethods,
@@ -14,6 +14,7 @@ use pyo3::{
 pub enum ToolChoice {
     NoTools,
     Auto,
+    Required,
 }
 
 #[pyclass]
@@ -45,6 +46,20 @@ pub struct CompletionRequest {
     pub(crate) dry_allowed_length: Option<usize>,
     pub(crate) dry_sequence_breakers: Option<Vec<String>>,
     pub(crate) truncate_sequence: bool,
+    pub(crate) seed: Option<u64>,
+    pub(crate) priority: RequestPriority,
+    pub(crate) num_beams: Option<usize>,
+    pub(crate) length_penalty: Option<f32>,
+    pub(crate) early_stopping: Option<bool>,
+    pub(crate) typical_p: Option<f64>,
+    pub(crate) top_a: Option<f64>,
+    pub(crate) xtc_probability: Option<f32>,
+    pub(crate) xtc_threshold: Option<f32>,
+    pub(crate) mirostat_tau: Option<f32>,
+    pub(crate) mirostat_eta: Option<f32>,
+    pub(crate) sampler_order: Option<Vec<SamplerKind>>,
+    pub(crate) logprobs: Option<usize>,
+    pub(crate) prompt_logprobs: Option<usize>,
 }
 
 #[pymethods]
@@ -76,6 +91,20 @@ impl CompletionRequest {
         dry_allowed_length=None,
         dry_sequence_breakers=None,
         truncate_sequence=false,
+        seed=None,
+        priority=None,
+        num_beams=None,
+        length_penalty=None,
+        early_stopping=None,
+        typical_p=None,
+        top_a=None,
+        xtc_probability=None,
+        xtc_threshold=None,
+        mirostat_tau=None,
+        mirostat_eta=None,
+        sampler_order=None,
+        logprobs=None,
+        prompt_logprobs=None,
     ))]
     fn new(
         prompt: String,
@@ -103,6 +132,20 @@ impl CompletionRequest {
         dry_allowed_length: Option<usize>,
         dry_sequence_breakers: Option<Vec<String>>,
         truncate_sequence: Option<bool>,
+        seed: Option<u64>,
+        priority: Option<RequestPriority>,
+        num_beams: Option<usize>,
+        length_penalty: Option<f32>,
+        early_stopping: Option<bool>,
+        typical_p: Option<f64>,
+        top_a: Option<f64>,
+        xtc_probability: Option<f32>,
+        xtc_threshold: Option<f32>,
+        mirostat_tau: Option<f32>,
+        mirostat_eta: Option<f32>,
+        sampler_order: Option<Vec<SamplerKind>>,
+        logprobs: Option<usize>,
+        prompt_logprobs: Option<usize>,
     ) -> PyResult<Self> {
         Ok(Self {
             prompt,
@@ -130,6 +173,20 @@ impl CompletionRequest {
             dry_base,
             dry_sequence_breakers,
             truncate_sequence: truncate_sequence.unwrap_or(false),
+            seed,
+            priority: priority.unwrap_or_default(),
+            num_beams,
+            length_penalty,
+            early_stopping,
+            typical_p,
+            top_a,
+            xtc_probability,
+            xtc_threshold,
+            mirostat_tau,
+            mirostat_eta,
+            sampler_order,
+            logprobs,
+            prompt_logprobs,
         })
     }
 }
@@ -263,6 +320,19 @@ pub struct ChatCompletionRequest {
     /// Reasoning effort level for models that support extended thinking.
     /// Valid values: "low", "medium", "high"
     pub(crate) reasoning_effort: Option<String>,
+    pub(crate) seed: Option<u64>,
+    pub(crate) priority: RequestPriority,
+    pub(crate) num_beams: Option<usize>,
+    pub(crate) length_penalty: Option<f32>,
+    pub(crate) early_stopping: Option<bool>,
+    pub(crate) typical_p: Option<f64>,
+    pub(crate) top_a: Option<f64>,
+    pub(crate) xtc_probability: Option<f32>,
+    pub(crate) xtc_threshold: Option<f32>,
+    pub(crate) mirostat_tau: Option<f32>,
+    pub(crate) mirostat_eta: Option<f32>,
+    pub(crate) sampler_order: Option<Vec<SamplerKind>>,
+    pub(crate) prompt_logprobs: Option<usize>,
 }
 
 #[pymethods]
@@ -297,6 +367,19 @@ impl ChatCompletionRequest {
         enable_thinking=false,
         truncate_sequence=false,
         reasoning_effort=None,
+        seed=None,
+        priority=None,
+        num_beams=None,
+        length_penalty=None,
+        early_stopping=None,
+        typical_p=None,
+        top_a=None,
+        xtc_probability=None,
+        xtc_threshold=None,
+        mirostat_tau=None,
+        mirostat_eta=None,
+        sampler_order=None,
+        prompt_logprobs=None,
     ))]
     fn new(
         messages: Py<PyAny>,
@@ -327,6 +410,19 @@ impl ChatCompletionRequest {
         enable_thinking: Option<bool>,
         truncate_sequence: Option<bool>,
         reasoning_effort: Option<String>,
+        seed: Option<u64>,
+        priority: Option<RequestPriority>,
+        num_beams: Option<usize>,
+        length_penalty: Option<f32>,
+        early_stopping: Option<bool>,
+        typical_p: Option<f64>,
+        top_a: Option<f64>,
+        xtc_probability: Option<f32>,
+        xtc_threshold: Option<f32>,
+        mirostat_tau: Option<f32>,
+        mirostat_eta: Option<f32>,
+        sampler_order: Option<Vec<SamplerKind>>,
+        prompt_logprobs: Option<usize>,
     ) -> PyResult<Self> {
         let messages = Python::with_gil(|py| {
             if let Ok(messages) = messages.bind(py).downcast_exact::<PyList>() {
@@ -405,6 +501,19 @@ impl ChatCompletionRequest {
             enable_thinking,
             truncate_sequence: truncate_sequence.unwrap_or(false),
             reasoning_effort,
+            seed,
+            priority: priority.unwrap_or_default(),
+            num_beams,
+            length_penalty,
+            early_stopping,
+            typical_p,
+            top_a,
+            xtc_probability,
+            xtc_threshold,
+            mirostat_tau,
+            mirostat_eta,
+            sampler_order,
+            prompt_logprobs,
         })
     }
 }
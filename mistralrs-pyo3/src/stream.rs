@@ -7,11 +7,18 @@ use pyo3::{exceptions::PyValueError, pyclass, pymethods, PyRef, PyRefMut, PyResu
 pub struct ChatCompletionStreamer {
     rx: Receiver<Response>,
     is_done: bool,
+    /// ID of the streamed request, which can be passed to `Runner.cancel_request`
+    #[pyo3(get)]
+    request_id: usize,
 }
 
 impl ChatCompletionStreamer {
-    pub fn from_rx(rx: Receiver<Response>) -> Self {
-        Self { rx, is_done: false }
+    pub fn from_rx(rx: Receiver<Response>, request_id: usize) -> Self {
+        Self {
+            rx,
+            is_done: false,
+            request_id,
+        }
     }
 }
 
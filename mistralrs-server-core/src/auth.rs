@@ -0,0 +1,551 @@
+//! ## API key authentication and rate limiting.
+
+use std::{
+    collections::HashMap,
+    num::NonZeroU32,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::handler_core::{ErrorToResponse, JsonError};
+
+/// Environment variable with a comma separated list of API keys.
+pub const API_KEYS_ENV: &str = "MISTRALRS_API_KEYS";
+
+/// Environment variable with the path of an API keys file, used when no path is given explicitly.
+pub const API_KEYS_FILE_ENV: &str = "MISTRALRS_API_KEYS_FILE";
+
+/// An API key accepted by the server, with optional rate limits.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiKey {
+    /// The secret key, sent as `Authorization: Bearer <key>`.
+    pub key: String,
+    /// Name of the key in usage reports. Defaults to the last characters of the key.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Maximum number of requests per minute.
+    #[serde(default)]
+    pub requests_per_minute: Option<NonZeroU32>,
+    /// Maximum number of prompt and generated tokens per minute.
+    #[serde(default)]
+    pub tokens_per_minute: Option<NonZeroU32>,
+}
+
+impl ApiKey {
+    /// Creates a key without its own rate limits.
+    pub fn new(key: String) -> Self {
+        Self {
+            key,
+            name: None,
+            requests_per_minute: None,
+            tokens_per_minute: None,
+        }
+    }
+
+    /// Loads keys from a JSON file containing a list of keys.
+    ///
+    /// ```json
+    /// [
+    ///   { "key": "sk-alice", "name": "alice", "requests_per_minute": 60, "tokens_per_minute": 100000 },
+    ///   { "key": "sk-bob" }
+    /// ]
+    /// ```
+    pub fn from_file(path: &str) -> Result<Vec<Self>> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read the API keys file `{path}`"))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse the API keys file `{path}`"))
+    }
+
+    /// Loads keys from the comma separated `MISTRALRS_API_KEYS` environment variable.
+    pub fn from_env() -> Vec<Self> {
+        std::env::var(API_KEYS_ENV)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(|key| Self::new(key.to_string()))
+            .collect()
+    }
+}
+
+/// Usage and rate limits of an API key.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ApiKeyUsage {
+    pub object: &'static str,
+    pub name: String,
+    /// Requests accepted since the server started.
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+    pub requests_per_minute: Option<u32>,
+    pub tokens_per_minute: Option<u32>,
+}
+
+/// A token bucket which holds up to a minute worth of its rate.
+struct TokenBucket {
+    per_minute: f64,
+    available: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(per_minute: NonZeroU32) -> Self {
+        Self {
+            per_minute: per_minute.get() as f64,
+            available: per_minute.get() as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.available = (self.available + elapsed * self.per_minute / 60.).min(self.per_minute);
+        self.updated = now;
+    }
+
+    /// Time until `amount` is available.
+    fn wait_time(&self, amount: f64) -> Duration {
+        if self.available >= amount {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((amount - self.available) * 60. / self.per_minute)
+        }
+    }
+
+    fn remaining(&self) -> u64 {
+        self.available.max(0.) as u64
+    }
+}
+
+#[derive(Default, Clone, Copy, Deserialize)]
+struct TokenUsage {
+    #[serde(default, alias = "input_tokens")]
+    prompt_tokens: u64,
+    #[serde(default, alias = "output_tokens")]
+    completion_tokens: u64,
+}
+
+struct KeyCounters {
+    requests: Option<TokenBucket>,
+    tokens: Option<TokenBucket>,
+    num_requests: u64,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+}
+
+/// The state of an API key, shared by its requests.
+struct KeyState {
+    name: String,
+    requests_per_minute: Option<NonZeroU32>,
+    tokens_per_minute: Option<NonZeroU32>,
+    counters: Mutex<KeyCounters>,
+}
+
+enum RateLimited {
+    Requests(Duration),
+    Tokens(Duration),
+}
+
+impl KeyState {
+    /// Count a new request, unless a limit is reached.
+    fn admit(&self) -> Result<(), RateLimited> {
+        let now = Instant::now();
+        let mut counters = self.counters.lock().unwrap();
+        if let Some(bucket) = &mut counters.requests {
+            bucket.refill(now);
+            let wait = bucket.wait_time(1.);
+            if !wait.is_zero() {
+                return Err(RateLimited::Requests(wait));
+            }
+        }
+        // Token usage is only known once a request is done, so a request is accepted as long as the
+        // tokens of the previous ones have been paid back.
+        if let Some(bucket) = &mut counters.tokens {
+            bucket.refill(now);
+            let wait = bucket.wait_time(1.);
+            if !wait.is_zero() {
+                return Err(RateLimited::Tokens(wait));
+            }
+        }
+        if let Some(bucket) = &mut counters.requests {
+            bucket.available -= 1.;
+        }
+        counters.num_requests += 1;
+        Ok(())
+    }
+
+    fn record_usage(&self, usage: TokenUsage) {
+        let mut counters = self.counters.lock().unwrap();
+        if let Some(bucket) = &mut counters.tokens {
+            bucket.refill(Instant::now());
+            bucket.available -= (usage.prompt_tokens + usage.completion_tokens) as f64;
+        }
+        counters.prompt_tokens += usage.prompt_tokens;
+        counters.completion_tokens += usage.completion_tokens;
+    }
+
+    fn usage(&self) -> ApiKeyUsage {
+        let counters = self.counters.lock().unwrap();
+        ApiKeyUsage {
+            object: "usage",
+            name: self.name.clone(),
+            requests: counters.num_requests,
+            prompt_tokens: counters.prompt_tokens,
+            completion_tokens: counters.completion_tokens,
+            total_tokens: counters.prompt_tokens + counters.completion_tokens,
+            requests_per_minute: self.requests_per_minute.map(NonZeroU32::get),
+            tokens_per_minute: self.tokens_per_minute.map(NonZeroU32::get),
+        }
+    }
+
+    /// Adds the `x-ratelimit-*` headers to a response.
+    fn insert_headers(&self, headers: &mut HeaderMap) {
+        let counters = self.counters.lock().unwrap();
+        let limits = [
+            ("requests", self.requests_per_minute, &counters.requests),
+            ("tokens", self.tokens_per_minute, &counters.tokens),
+        ];
+        for (kind, limit, bucket) in limits {
+            if let (Some(limit), Some(bucket)) = (limit, bucket) {
+                headers.insert(
+                    header::HeaderName::try_from(format!("x-ratelimit-limit-{kind}")).unwrap(),
+                    HeaderValue::from(limit.get()),
+                );
+                headers.insert(
+                    header::HeaderName::try_from(format!("x-ratelimit-remaining-{kind}")).unwrap(),
+                    HeaderValue::from(bucket.remaining()),
+                );
+            }
+        }
+    }
+}
+
+/// The API keys accepted by the server.
+pub struct ApiKeyAuth {
+    keys: HashMap<String, Arc<KeyState>>,
+}
+
+impl ApiKeyAuth {
+    /// Creates the authentication state. The default limits apply to keys without their own limits.
+    pub fn new(
+        keys: Vec<ApiKey>,
+        default_requests_per_minute: Option<NonZeroU32>,
+        default_tokens_per_minute: Option<NonZeroU32>,
+    ) -> Self {
+        let keys = keys
+            .into_iter()
+            .map(|key| {
+                let requests_per_minute = key.requests_per_minute.or(default_requests_per_minute);
+                let tokens_per_minute = key.tokens_per_minute.or(default_tokens_per_minute);
+                let name = key.name.unwrap_or_else(|| {
+                    let suffix = key.key.len().saturating_sub(4);
+                    format!("...{}", key.key.get(suffix..).unwrap_or_default())
+                });
+                let state = KeyState {
+                    name,
+                    requests_per_minute,
+                    tokens_per_minute,
+                    counters: Mutex::new(KeyCounters {
+                        requests: requests_per_minute.map(TokenBucket::new),
+                        tokens: tokens_per_minute.map(TokenBucket::new),
+                        num_requests: 0,
+                        prompt_tokens: 0,
+                        completion_tokens: 0,
+                    }),
+                };
+                (key.key, Arc::new(state))
+            })
+            .collect();
+        Self { keys }
+    }
+
+    /// The usage of every key.
+    pub fn usage(&self) -> Vec<ApiKeyUsage> {
+        let mut usage = self
+            .keys
+            .values()
+            .map(|state| state.usage())
+            .collect::<Vec<_>>();
+        usage.sort_by(|a, b| a.name.cmp(&b.name));
+        usage
+    }
+}
+
+/// The API key a request was authenticated with.
+#[derive(Clone)]
+pub struct AuthenticatedKey(Arc<KeyState>);
+
+impl AuthenticatedKey {
+    pub fn usage(&self) -> ApiKeyUsage {
+        self.0.usage()
+    }
+}
+
+/// Error body in the OpenAI format.
+#[derive(Serialize, Debug)]
+struct OpenAIError {
+    error: OpenAIErrorBody,
+}
+
+#[derive(Serialize, Debug)]
+struct OpenAIErrorBody {
+    message: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    param: Option<String>,
+    code: Option<&'static str>,
+}
+
+impl ErrorToResponse for OpenAIError {}
+
+impl OpenAIError {
+    fn new(message: String, kind: &'static str, code: &'static str) -> Self {
+        Self {
+            error: OpenAIErrorBody {
+                message,
+                kind,
+                param: None,
+                code: Some(code),
+            },
+        }
+    }
+}
+
+/// The API key of a request, from the `Authorization: Bearer` header or the `x-api-key` header used by
+/// Anthropic clients.
+fn api_key(headers: &HeaderMap) -> Option<&str> {
+    if let Some(key) = headers.get("x-api-key") {
+        return key.to_str().ok().map(str::trim);
+    }
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+/// Middleware which rejects requests without a valid API key, enforces the rate limits of the key and
+/// accounts the tokens used by the responses.
+///
+/// Token usage is read from the `usage` of JSON responses and of streamed events, so streamed responses
+/// without usage information are not accounted.
+pub async fn authenticate(
+    State(auth): State<Arc<ApiKeyAuth>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(key) = api_key(request.headers()) else {
+        return OpenAIError::new(
+            "You didn't provide an API key. Provide it in an Authorization header using Bearer auth."
+                .to_string(),
+            "invalid_request_error",
+            "missing_api_key",
+        )
+        .to_response(StatusCode::UNAUTHORIZED);
+    };
+    let Some(state) = auth.keys.get(key).cloned() else {
+        return OpenAIError::new(
+            "Incorrect API key provided.".to_string(),
+            "invalid_request_error",
+            "invalid_api_key",
+        )
+        .to_response(StatusCode::UNAUTHORIZED);
+    };
+
+    if let Err(limited) = state.admit() {
+        let (kind, wait) = match limited {
+            RateLimited::Requests(wait) => ("requests", wait),
+            RateLimited::Tokens(wait) => ("tokens", wait),
+        };
+        let mut response = OpenAIError::new(
+            format!(
+                "Rate limit reached for {kind} per minute on API key `{}`. Please try again in {:.1}s.",
+                state.name,
+                wait.as_secs_f64()
+            ),
+            kind,
+            "rate_limit_exceeded",
+        )
+        .to_response(StatusCode::TOO_MANY_REQUESTS);
+        response.headers_mut().insert(
+            header::RETRY_AFTER,
+            HeaderValue::from(wait.as_secs_f64().ceil() as u64),
+        );
+        state.insert_headers(response.headers_mut());
+        return response;
+    }
+
+    request
+        .extensions_mut()
+        .insert(AuthenticatedKey(state.clone()));
+    let response = next.run(request).await;
+
+    let (mut parts, body) = response.into_parts();
+    state.insert_headers(&mut parts.headers);
+    let content_type = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let event_stream = if content_type.starts_with("text/event-stream") {
+        true
+    } else if content_type.starts_with("application/json") {
+        false
+    } else {
+        return Response::from_parts(parts, body);
+    };
+
+    let mut recorder = UsageRecorder {
+        state,
+        event_stream,
+        pending: Vec::new(),
+        usage: None,
+    };
+    let body = body.into_data_stream().map(move |chunk| {
+        if let Ok(bytes) = &chunk {
+            recorder.feed(bytes);
+        }
+        chunk
+    });
+    Response::from_parts(parts, Body::from_stream(body))
+}
+
+#[derive(Deserialize)]
+struct UsageField {
+    usage: Option<TokenUsage>,
+    /// Events of the Responses API hold the response.
+    response: Option<Box<UsageField>>,
+}
+
+fn parse_usage(data: &[u8]) -> Option<TokenUsage> {
+    let field = serde_json::from_slice::<UsageField>(data.trim_ascii()).ok()?;
+    field.usage.or_else(|| field.response?.usage)
+}
+
+/// Reads the token usage of a response body as it is sent, and records it once the body is dropped.
+struct UsageRecorder {
+    state: Arc<KeyState>,
+    event_stream: bool,
+    pending: Vec<u8>,
+    usage: Option<TokenUsage>,
+}
+
+impl UsageRecorder {
+    fn feed(&mut self, bytes: &[u8]) {
+        self.pending.extend_from_slice(bytes);
+        if !self.event_stream {
+            return;
+        }
+        while let Some(end) = self.pending.iter().position(|b| *b == b'\n') {
+            let line = self.pending.drain(..=end).collect::<Vec<_>>();
+            if let Some(usage) = line.strip_prefix(b"data:").and_then(parse_usage) {
+                self.usage = Some(usage);
+            }
+        }
+    }
+}
+
+impl Drop for UsageRecorder {
+    fn drop(&mut self) {
+        if !self.event_stream {
+            self.usage = parse_usage(&self.pending);
+        }
+        if let Some(usage) = self.usage {
+            self.state.record_usage(usage);
+        }
+    }
+}
+
+#[utoipa::path(
+  get,
+  tag = "Mistral.rs",
+  path = "/v1/usage",
+  responses((status = 200, description = "Usage and rate limits of the API key of the request", body = ApiKeyUsage))
+)]
+pub async fn usage(key: Option<Extension<AuthenticatedKey>>) -> Response {
+    match key {
+        Some(Extension(key)) => Json(key.usage()).into_response(),
+        None => JsonError::new("API keys are not configured".to_string())
+            .to_response(StatusCode::NOT_FOUND),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(requests_per_minute: Option<u32>, tokens_per_minute: Option<u32>) -> Arc<KeyState> {
+        let auth = ApiKeyAuth::new(
+            vec![ApiKey::new("sk-test".to_string())],
+            requests_per_minute.map(|limit| NonZeroU32::new(limit).unwrap()),
+            tokens_per_minute.map(|limit| NonZeroU32::new(limit).unwrap()),
+        );
+        auth.keys["sk-test"].clone()
+    }
+
+    #[test]
+    fn test_request_limit() {
+        let state = key(Some(2), None);
+        assert!(state.admit().is_ok());
+        assert!(state.admit().is_ok());
+        assert!(matches!(state.admit(), Err(RateLimited::Requests(_))));
+        assert_eq!(state.usage().requests, 2);
+    }
+
+    #[test]
+    fn test_token_limit() {
+        let state = key(None, Some(100));
+        assert!(state.admit().is_ok());
+        state.record_usage(TokenUsage {
+            prompt_tokens: 80,
+            completion_tokens: 40,
+        });
+        assert!(matches!(state.admit(), Err(RateLimited::Tokens(_))));
+
+        let usage = state.usage();
+        assert_eq!(usage.name, "...test");
+        assert_eq!(usage.total_tokens, 120);
+    }
+
+    #[test]
+    fn test_zero_limits_are_rejected() {
+        let keys = r#"[{ "key": "sk-test", "requests_per_minute": 0 }]"#;
+        assert!(serde_json::from_str::<Vec<ApiKey>>(keys).is_err());
+        let keys = r#"[{ "key": "sk-test", "tokens_per_minute": 1 }]"#;
+        assert!(serde_json::from_str::<Vec<ApiKey>>(keys).is_ok());
+    }
+
+    #[test]
+    fn test_streamed_usage() {
+        let mut recorder = UsageRecorder {
+            state: key(None, None),
+            event_stream: true,
+            pending: Vec::new(),
+            usage: None,
+        };
+        recorder.feed(b"data: {\"choices\":[]}\n\ndata: {\"usage\":{\"prompt_tokens\":3,");
+        recorder.feed(b"\"completion_tokens\":5,\"total_tokens\":8}}\n\ndata: [DONE]\n\n");
+        let state = recorder.state.clone();
+        drop(recorder);
+
+        let usage = state.usage();
+        assert_eq!(usage.prompt_tokens, 3);
+        assert_eq!(usage.completion_tokens, 5);
+    }
+}
@@ -5,7 +5,7 @@ use std::{ops::Deref, pin::Pin, task::Poll, time::Duration};
 use anyhow::{Context, Result};
 use axum::{
     extract::{Json, State},
-    http::{self},
+    http::{self, HeaderMap},
     response::{
         sse::{Event, KeepAlive, KeepAliveStream},
         IntoResponse, Sse,
@@ -23,8 +23,9 @@ use tokio::sync::mpsc::{Receiver, Sender};
 
 use crate::{
     completion_core::{
-        convert_stop_tokens, get_dry_sampling_params, handle_completion_error,
-        BaseCompletionResponder,
+        apply_header_timeout, convert_stop_tokens, get_beam_search_params, get_dry_sampling_params,
+        get_mirostat_params, get_request_timeout, get_traceparent, get_xtc_sampling_params,
+        handle_completion_error, BaseCompletionResponder,
     },
     handler_core::{
         base_process_non_streaming_response, create_response_channel, send_request_with_model,
@@ -216,6 +217,16 @@ pub async fn parse_request(
     state: SharedMistralRsState,
     tx: Sender<Response>,
 ) -> Result<(Request, bool)> {
+    let (request, is_streaming) = parse_normal_request(oairequest, state, tx).await?;
+    Ok((Request::Normal(request), is_streaming))
+}
+
+/// Like [`parse_request`], but returns the normal request so callers can adjust it.
+pub(crate) async fn parse_normal_request(
+    oairequest: ChatCompletionRequest,
+    state: SharedMistralRsState,
+    tx: Sender<Response>,
+) -> Result<(Box<NormalRequest>, bool)> {
     let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
     MistralRs::maybe_log_request(state.clone(), repr);
 
@@ -501,6 +512,18 @@ pub async fn parse_request(
         oairequest.dry_allowed_length,
     )?;
 
+    let beam_search = get_beam_search_params(
+        oairequest.num_beams,
+        oairequest.length_penalty,
+        oairequest.early_stopping,
+    )?;
+
+    let xtc_params = get_xtc_sampling_params(oairequest.xtc_probability, oairequest.xtc_threshold)?;
+
+    let mirostat_params = get_mirostat_params(oairequest.mirostat_tau, oairequest.mirostat_eta)?;
+
+    let timeout = get_request_timeout(oairequest.timeout, &state)?;
+
     let is_streaming = oairequest.stream.unwrap_or(false);
 
     if oairequest.grammar.is_some() && oairequest.response_format.is_some() {
@@ -522,7 +545,7 @@ pub async fn parse_request(
     };
 
     Ok((
-        Request::Normal(Box::new(NormalRequest {
+        Box::new(NormalRequest {
             id: state.next_request_id(),
             messages,
             sampling_params: SamplingParams {
@@ -539,6 +562,13 @@ pub async fn parse_request(
                 logits_bias: oairequest.logit_bias,
                 n_choices: oairequest.n_choices,
                 dry_params,
+                seed: oairequest.seed,
+                beam_search,
+                typical_p: oairequest.typical_p,
+                top_a: oairequest.top_a,
+                xtc_params,
+                mirostat_params,
+                sampler_order: oairequest.sampler_order,
             },
             response: tx,
             return_logprobs: oairequest.logprobs,
@@ -556,7 +586,11 @@ pub async fn parse_request(
                 Some(oairequest.model.clone())
             },
             truncate_sequence: oairequest.truncate_sequence.unwrap_or(false),
-        })),
+            priority: oairequest.priority.unwrap_or_default(),
+            timeout,
+            prompt_logprobs: oairequest.prompt_logprobs,
+            traceparent: None,
+        }),
         is_streaming,
     ))
 }
@@ -571,10 +605,15 @@ pub async fn parse_request(
 )]
 pub async fn chatcompletions(
     State(state): ExtractedMistralRsState,
-    Json(oairequest): Json<ChatCompletionRequest>,
+    headers: HeaderMap,
+    Json(mut oairequest): Json<ChatCompletionRequest>,
 ) -> ChatCompletionResponder {
     let (tx, mut rx) = create_response_channel(None);
 
+    if let Err(e) = apply_header_timeout(&headers, &mut oairequest.timeout) {
+        return handle_error(state, e.into());
+    }
+
     // Extract model_id for routing before parsing
     let model_id = if oairequest.model == "default" {
         None
@@ -582,17 +621,25 @@ pub async fn chatcompletions(
         Some(oairequest.model.clone())
     };
 
-    let (request, is_streaming) = match parse_request(oairequest, state.clone(), tx).await {
-        Ok(x) => x,
-        Err(e) => return handle_error(state, e.into()),
-    };
-
-    if let Err(e) = send_request_with_model(&state, request, model_id.as_deref()).await {
+    let (mut request, is_streaming) =
+        match parse_normal_request(oairequest, state.clone(), tx).await {
+            Ok(x) => x,
+            Err(e) => return handle_error(state, e.into()),
+        };
+    request.traceparent = get_traceparent(&headers);
+    let request_id = request.id;
+
+    if let Err(e) =
+        send_request_with_model(&state, Request::Normal(request), model_id.as_deref()).await
+    {
         return handle_error(state, e.into());
     }
 
     if is_streaming {
-        ChatCompletionResponder::Sse(create_streamer(rx, state, None, None))
+        // Stop generating if the client goes away mid-stream.
+        ChatCompletionResponder::Sse(create_cancelable_streamer(
+            rx, state, None, None, request_id, model_id,
+        ))
     } else {
         process_non_streaming_response(&mut rx, state).await
     }
@@ -620,6 +667,24 @@ pub fn create_streamer(
         .keep_alive(KeepAlive::new().interval(Duration::from_millis(keep_alive_interval)))
 }
 
+/// Creates a SSE streamer like [`create_streamer`], which cancels the request `request_id` sent to
+/// `model_id` when the client disconnects before it is done.
+pub fn create_cancelable_streamer(
+    rx: Receiver<Response>,
+    state: SharedMistralRsState,
+    on_chunk: Option<ChatCompletionOnChunkCallback>,
+    on_done: Option<ChatCompletionOnDoneCallback>,
+    request_id: usize,
+    model_id: Option<String>,
+) -> Sse<KeepAliveStream<ChatCompletionStreamer>> {
+    let streamer = base_create_streamer(rx, state, on_chunk, on_done)
+        .cancel_on_disconnect(request_id, model_id);
+    let keep_alive_interval = get_keep_alive_interval();
+
+    Sse::new(streamer)
+        .keep_alive(KeepAlive::new().interval(Duration::from_millis(keep_alive_interval)))
+}
+
 /// Process non-streaming chat completion responses.
 pub async fn process_non_streaming_response(
     rx: &mut Receiver<Response>,
@@ -1,10 +1,13 @@
 //! Core functionality for completions.
 
-use std::error::Error;
+use std::{error::Error, time::Duration};
 
 use anyhow::Result;
-use axum::response::Sse;
-use mistralrs_core::{DrySamplingParams, MistralRs, StopTokens as InternalStopTokens};
+use axum::{http::HeaderMap, response::Sse};
+use mistralrs_core::{
+    current_traceparent, BeamSearchParams, DrySamplingParams, MirostatParams, MistralRs,
+    StopTokens as InternalStopTokens, XtcSamplingParams, TRACEPARENT_HEADER,
+};
 
 use crate::{openai::StopTokens, types::SharedMistralRsState, util::sanitize_error_message};
 
@@ -48,6 +51,49 @@ pub(crate) fn convert_stop_tokens(stop_seqs: Option<StopTokens>) -> Option<Inter
     }
 }
 
+/// Header carrying the request timeout in seconds, as an alternative to the `timeout` body field.
+pub(crate) const REQUEST_TIMEOUT_HEADER: &str = "x-request-timeout";
+
+/// Helper function to override the `timeout` (in seconds) of a request with the timeout of the
+/// request headers, if they have one.
+pub(crate) fn apply_header_timeout(headers: &HeaderMap, timeout: &mut Option<f64>) -> Result<()> {
+    let Some(value) = headers.get(REQUEST_TIMEOUT_HEADER) else {
+        return Ok(());
+    };
+    let secs = value
+        .to_str()
+        .ok()
+        .and_then(|v| v.trim().parse::<f64>().ok())
+        .ok_or_else(|| anyhow::anyhow!("Invalid `{REQUEST_TIMEOUT_HEADER}` header value."))?;
+    *timeout = Some(secs);
+    Ok(())
+}
+
+/// Helper function to get the W3C `traceparent` to propagate into the engine. This is the context of the
+/// current span when spans are exported, and otherwise the caller's `traceparent` header.
+pub(crate) fn get_traceparent(headers: &HeaderMap) -> Option<String> {
+    current_traceparent().or_else(|| {
+        headers
+            .get(TRACEPARENT_HEADER)?
+            .to_str()
+            .ok()
+            .map(ToString::to_string)
+    })
+}
+
+/// Helper function to convert a request timeout in seconds to a [`Duration`], falling back to
+/// the server-wide default timeout if none was given.
+pub(crate) fn get_request_timeout(
+    timeout: Option<f64>,
+    state: &MistralRs,
+) -> Result<Option<Duration>> {
+    match timeout {
+        Some(secs) if secs > 0. => Ok(Some(Duration::try_from_secs_f64(secs)?)),
+        Some(secs) => anyhow::bail!("Request `timeout` must be positive, got {secs}."),
+        None => Ok(state.default_request_timeout()),
+    }
+}
+
 /// Helper function to get the dry sampling params.
 pub(crate) fn get_dry_sampling_params(
     dry_multiplier: Option<f32>,
@@ -68,3 +114,47 @@ pub(crate) fn get_dry_sampling_params(
         None => Ok(None),
     }
 }
+
+/// Helper function to get the XTC sampling params.
+pub(crate) fn get_xtc_sampling_params(
+    xtc_probability: Option<f32>,
+    xtc_threshold: Option<f32>,
+) -> Result<Option<XtcSamplingParams>> {
+    match xtc_probability {
+        Some(probability) => {
+            let params = XtcSamplingParams::new_with_defaults(probability, xtc_threshold)?;
+            Ok(Some(params))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Helper function to get the mirostat v2 params.
+pub(crate) fn get_mirostat_params(
+    mirostat_tau: Option<f32>,
+    mirostat_eta: Option<f32>,
+) -> Result<Option<MirostatParams>> {
+    match mirostat_tau {
+        Some(tau) => {
+            let params = MirostatParams::new_with_defaults(tau, mirostat_eta)?;
+            Ok(Some(params))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Helper function to get the beam search params.
+pub(crate) fn get_beam_search_params(
+    num_beams: Option<usize>,
+    length_penalty: Option<f32>,
+    early_stopping: Option<bool>,
+) -> Result<Option<BeamSearchParams>> {
+    match num_beams {
+        Some(num_beams) => {
+            let params =
+                BeamSearchParams::new_with_defaults(num_beams, length_penalty, early_stopping)?;
+            Ok(Some(params))
+        }
+        None => Ok(None),
+    }
+}
@@ -10,7 +10,7 @@ use std::{
 use anyhow::Result;
 use axum::{
     extract::{Json, State},
-    http::{self},
+    http::{self, HeaderMap},
     response::{
         sse::{Event, KeepAlive, KeepAliveStream},
         IntoResponse, Sse,
@@ -21,12 +21,12 @@ use mistralrs_core::{
     RequestMessage, Response, SamplingParams,
 };
 use tokio::sync::mpsc::{Receiver, Sender};
-use tracing::warn;
 
 use crate::{
     completion_core::{
-        convert_stop_tokens, get_dry_sampling_params, handle_completion_error,
-        BaseCompletionResponder,
+        apply_header_timeout, convert_stop_tokens, get_beam_search_params, get_dry_sampling_params,
+        get_mirostat_params, get_request_timeout, get_traceparent, get_xtc_sampling_params,
+        handle_completion_error, BaseCompletionResponder,
     },
     handler_core::{
         base_process_non_streaming_response, create_response_channel, send_request,
@@ -196,6 +196,16 @@ pub fn parse_request(
     state: Arc<MistralRs>,
     tx: Sender<Response>,
 ) -> Result<(Request, bool)> {
+    let (request, is_streaming) = parse_normal_request(oairequest, state, tx)?;
+    Ok((Request::Normal(request), is_streaming))
+}
+
+/// Like [`parse_request`], but returns the normal request so callers can adjust it.
+pub(crate) fn parse_normal_request(
+    oairequest: CompletionRequest,
+    state: Arc<MistralRs>,
+    tx: Sender<Response>,
+) -> Result<(Box<NormalRequest>, bool)> {
     let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
     MistralRs::maybe_log_request(state.clone(), repr);
 
@@ -204,10 +214,6 @@ pub fn parse_request(
 
     let stop_toks = convert_stop_tokens(oairequest.stop_seqs);
 
-    if oairequest.logprobs.is_some() {
-        warn!("Completion requests do not support logprobs.");
-    }
-
     let is_streaming = oairequest.stream.unwrap_or(false);
 
     let dry_params = get_dry_sampling_params(
@@ -217,8 +223,27 @@ pub fn parse_request(
         oairequest.dry_allowed_length,
     )?;
 
+    let beam_search = get_beam_search_params(
+        oairequest.num_beams,
+        oairequest.length_penalty,
+        oairequest.early_stopping,
+    )?;
+
+    let xtc_params = get_xtc_sampling_params(oairequest.xtc_probability, oairequest.xtc_threshold)?;
+
+    let mirostat_params = get_mirostat_params(oairequest.mirostat_tau, oairequest.mirostat_eta)?;
+
+    let timeout = get_request_timeout(oairequest.timeout, &state)?;
+
+    // Like the OpenAI API, `echo` with `logprobs` also scores the echoed prompt.
+    let prompt_logprobs = oairequest.prompt_logprobs.or(if oairequest.echo_prompt {
+        oairequest.logprobs
+    } else {
+        None
+    });
+
     Ok((
-        Request::Normal(Box::new(NormalRequest {
+        Box::new(NormalRequest {
             id: state.next_request_id(),
             messages: RequestMessage::Completion {
                 text: oairequest.prompt,
@@ -230,7 +255,7 @@ pub fn parse_request(
                 top_k: oairequest.top_k,
                 top_p: oairequest.top_p,
                 min_p: oairequest.min_p,
-                top_n_logprobs: 1,
+                top_n_logprobs: oairequest.logprobs.unwrap_or(1),
                 frequency_penalty: oairequest.frequency_penalty,
                 presence_penalty: oairequest.presence_penalty,
                 repetition_penalty: oairequest.repetition_penalty,
@@ -239,9 +264,16 @@ pub fn parse_request(
                 logits_bias: oairequest.logit_bias,
                 n_choices: oairequest.n_choices,
                 dry_params,
+                seed: oairequest.seed,
+                beam_search,
+                typical_p: oairequest.typical_p,
+                top_a: oairequest.top_a,
+                xtc_params,
+                mirostat_params,
+                sampler_order: oairequest.sampler_order,
             },
             response: tx,
-            return_logprobs: false,
+            return_logprobs: oairequest.logprobs.is_some(),
             is_streaming,
             suffix: oairequest.suffix,
             constraint: match oairequest.grammar {
@@ -262,7 +294,11 @@ pub fn parse_request(
                 Some(oairequest.model.clone())
             },
             truncate_sequence: oairequest.truncate_sequence.unwrap_or(false),
-        })),
+            priority: oairequest.priority.unwrap_or_default(),
+            timeout,
+            prompt_logprobs,
+            traceparent: None,
+        }),
         is_streaming,
     ))
 }
@@ -277,21 +313,31 @@ pub fn parse_request(
 )]
 pub async fn completions(
     State(state): ExtractedMistralRsState,
-    Json(oairequest): Json<CompletionRequest>,
+    headers: HeaderMap,
+    Json(mut oairequest): Json<CompletionRequest>,
 ) -> CompletionResponder {
     let (tx, mut rx) = create_response_channel(None);
 
-    let (request, is_streaming) = match parse_request(oairequest, state.clone(), tx) {
+    if let Err(e) = apply_header_timeout(&headers, &mut oairequest.timeout) {
+        return handle_error(state, e.into());
+    }
+
+    let (mut request, is_streaming) = match parse_normal_request(oairequest, state.clone(), tx) {
         Ok(x) => x,
         Err(e) => return handle_error(state, e.into()),
     };
+    request.traceparent = get_traceparent(&headers);
+    let (request_id, model_id) = (request.id, request.model_id.clone());
 
-    if let Err(e) = send_request(&state, request).await {
+    if let Err(e) = send_request(&state, Request::Normal(request)).await {
         return handle_error(state, e.into());
     }
 
     if is_streaming {
-        CompletionResponder::Sse(create_streamer(rx, state, None, None))
+        // Stop generating if the client goes away mid-stream.
+        CompletionResponder::Sse(create_cancelable_streamer(
+            rx, state, None, None, request_id, model_id,
+        ))
     } else {
         process_non_streaming_response(&mut rx, state).await
     }
@@ -319,6 +365,24 @@ pub fn create_streamer(
         .keep_alive(KeepAlive::new().interval(Duration::from_millis(keep_alive_interval)))
 }
 
+/// Creates a SSE streamer like [`create_streamer`], which cancels the request `request_id` sent to
+/// `model_id` when the client disconnects before it is done.
+pub fn create_cancelable_streamer(
+    rx: Receiver<Response>,
+    state: SharedMistralRsState,
+    on_chunk: Option<CompletionOnChunkCallback>,
+    on_done: Option<CompletionOnDoneCallback>,
+    request_id: usize,
+    model_id: Option<String>,
+) -> Sse<KeepAliveStream<CompletionStreamer>> {
+    let streamer = base_create_streamer(rx, state, on_chunk, on_done)
+        .cancel_on_disconnect(request_id, model_id);
+    let keep_alive_interval = get_keep_alive_interval();
+
+    Sse::new(streamer)
+        .keep_alive(KeepAlive::new().interval(Duration::from_millis(keep_alive_interval)))
+}
+
 /// Process non-streaming completion responses.
 pub async fn process_non_streaming_response(
     rx: &mut Receiver<Response>,
@@ -1,19 +1,23 @@
 //! OpenAI-compatible embeddings endpoint.
 
+use std::time::Duration;
+
 use anyhow::{anyhow, Context, Error as AnyhowError, Result};
 use axum::{
     extract::{Json, State},
-    http,
+    http::{self, HeaderMap},
     response::IntoResponse,
 };
 use base64::{prelude::BASE64_STANDARD, Engine};
 use futures::future::join_all;
 use mistralrs_core::{
-    Constraint, MistralRs, NormalRequest, Request, RequestMessage, Response, SamplingParams,
+    Constraint, MistralRs, NormalRequest, Request, RequestMessage, RequestPriority, Response,
+    SamplingParams,
 };
 use tokio::sync::mpsc::Receiver;
 
 use crate::{
+    completion_core::{apply_header_timeout, get_request_timeout},
     handler_core::{
         base_process_non_streaming_response, create_response_channel, send_request_with_model,
         ErrorToResponse, JsonError,
@@ -64,8 +68,13 @@ impl IntoResponse for EmbeddingResponder {
 )]
 pub async fn embeddings(
     State(state): ExtractedMistralRsState,
-    Json(oairequest): Json<EmbeddingRequest>,
+    headers: HeaderMap,
+    Json(mut oairequest): Json<EmbeddingRequest>,
 ) -> EmbeddingResponder {
+    if let Err(e) = apply_header_timeout(&headers, &mut oairequest.timeout) {
+        return validation_error(e);
+    }
+
     let repr =
         serde_json::to_string(&oairequest).expect("Serialization of embedding request failed.");
     MistralRs::maybe_log_request(state.clone(), repr);
@@ -74,6 +83,11 @@ pub async fn embeddings(
         return validation_error(e);
     }
 
+    let timeout = match get_request_timeout(oairequest.timeout, &state) {
+        Ok(timeout) => timeout,
+        Err(e) => return validation_error(e),
+    };
+
     if let Some(dimensions) = oairequest.dimensions {
         return validation_error(anyhow!(
             "Custom embedding dimensions ({dimensions}) are not supported."
@@ -113,6 +127,7 @@ pub async fn embeddings(
                         prompt,
                         model_override.as_deref(),
                         oairequest.truncate_sequence.unwrap_or(false),
+                        timeout,
                     )
                     .await
                 }
@@ -156,6 +171,7 @@ pub async fn embeddings(
                         tokens,
                         model_override.as_deref(),
                         oairequest.truncate_sequence.unwrap_or(false),
+                        timeout,
                     )
                     .await
                 }
@@ -243,6 +259,7 @@ async fn fetch_embedding(
     prompt: String,
     model_id: Option<&str>,
     truncate_sequence: bool,
+    timeout: Option<Duration>,
 ) -> Result<EmbeddingWithUsage> {
     let (tx, mut rx) = create_response_channel(Some(1));
 
@@ -262,6 +279,10 @@ async fn fetch_embedding(
         web_search_options: None,
         model_id: model_id.map(|m| m.to_string()),
         truncate_sequence,
+        priority: RequestPriority::default(),
+        timeout,
+        prompt_logprobs: None,
+        traceparent: None,
     }));
 
     send_request_with_model(&state, request, model_id)
@@ -276,6 +297,7 @@ async fn fetch_embedding_tokens(
     tokens: Vec<u32>,
     model_id: Option<&str>,
     truncate_sequence: bool,
+    timeout: Option<Duration>,
 ) -> Result<EmbeddingWithUsage> {
     let (tx, mut rx) = create_response_channel(Some(1));
 
@@ -295,6 +317,10 @@ async fn fetch_embedding_tokens(
         web_search_options: None,
         model_id: model_id.map(|m| m.to_string()),
         truncate_sequence,
+        priority: RequestPriority::default(),
+        timeout,
+        prompt_logprobs: None,
+        traceparent: None,
     }));
 
     send_request_with_model(&state, request, model_id)
@@ -1,12 +1,17 @@
 //! ## General mistral.rs server route handlers.
 
 use anyhow::Result;
-use axum::extract::{Json, State};
-use mistralrs_core::{parse_isq_value, MistralRs, Request};
+use axum::{
+    extract::{Json, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use mistralrs_core::{parse_isq_value, EngineStats, MistralRs, Request};
 use serde::{Deserialize, Serialize};
-use utoipa::ToSchema;
+use utoipa::{IntoParams, ToSchema};
 
 use crate::{
+    handler_core::{ErrorToResponse, JsonError},
     openai::{ModelObject, ModelObjects},
     types::ExtractedMistralRsState,
 };
@@ -72,6 +77,65 @@ pub async fn health() -> &'static str {
     "OK"
 }
 
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+pub struct StatsQuery {
+    /// Only return the statistics of this model.
+    model: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ModelStats {
+    id: String,
+    #[serde(flatten)]
+    stats: EngineStats,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ModelStatsList {
+    object: &'static str,
+    data: Vec<ModelStats>,
+}
+
+#[utoipa::path(
+  get,
+  tag = "Mistral.rs",
+  path = "/v1/stats",
+  params(StatsQuery),
+  responses((status = 200, description = "Scheduling, KV cache and throughput statistics per model", body = ModelStatsList))
+)]
+pub async fn stats(
+    State(state): ExtractedMistralRsState,
+    Query(query): Query<StatsQuery>,
+) -> impl IntoResponse {
+    let model_ids = match query.model {
+        Some(model) if model == "default" => state
+            .get_default_model_id()
+            .ok()
+            .flatten()
+            .into_iter()
+            .collect(),
+        Some(model) => vec![model],
+        None => state.list_models().unwrap_or_default(),
+    };
+
+    let mut data = Vec::new();
+    for id in model_ids {
+        match state.engine_stats(Some(&id)) {
+            Ok(stats) => data.push(ModelStats { id, stats }),
+            Err(_) => {
+                return JsonError::new(format!("Model `{id}` not found"))
+                    .to_response(StatusCode::NOT_FOUND)
+            }
+        }
+    }
+
+    Json(ModelStatsList {
+        object: "list",
+        data,
+    })
+    .into_response()
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 pub struct ReIsqRequest {
     #[schema(example = "Q4K")]
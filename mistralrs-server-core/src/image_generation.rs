@@ -5,16 +5,17 @@ use std::{error::Error, sync::Arc};
 use anyhow::Result;
 use axum::{
     extract::{Json, State},
-    http::{self},
+    http::{self, HeaderMap},
     response::IntoResponse,
 };
 use mistralrs_core::{
     Constraint, DiffusionGenerationParams, ImageGenerationResponse, MistralRs, NormalRequest,
-    Request, RequestMessage, Response, SamplingParams,
+    Request, RequestMessage, RequestPriority, Response, SamplingParams,
 };
 use tokio::sync::mpsc::{Receiver, Sender};
 
 use crate::{
+    completion_core::{apply_header_timeout, get_request_timeout},
     handler_core::{
         base_process_non_streaming_response, create_response_channel, send_request,
         ErrorToResponse, JsonError,
@@ -63,6 +64,8 @@ pub fn parse_request(
     // Validate that the requested model matches the loaded model
     validate_model_name(&oairequest.model, state.clone())?;
 
+    let timeout = get_request_timeout(oairequest.timeout, &state)?;
+
     Ok(Request::Normal(Box::new(NormalRequest {
         id: state.next_request_id(),
         messages: RequestMessage::ImageGeneration {
@@ -90,6 +93,10 @@ pub fn parse_request(
             Some(oairequest.model.clone())
         },
         truncate_sequence: false,
+        priority: RequestPriority::default(),
+        timeout,
+        prompt_logprobs: None,
+        traceparent: None,
     })))
 }
 
@@ -103,10 +110,15 @@ pub fn parse_request(
 )]
 pub async fn image_generation(
     State(state): ExtractedMistralRsState,
-    Json(oairequest): Json<ImageGenerationRequest>,
+    headers: HeaderMap,
+    Json(mut oairequest): Json<ImageGenerationRequest>,
 ) -> ImageGenerationResponder {
     let (tx, mut rx) = create_response_channel(None);
 
+    if let Err(e) = apply_header_timeout(&headers, &mut oairequest.timeout) {
+        return handle_error(state, e.into());
+    }
+
     let request = match parse_request(oairequest, state.clone(), tx) {
         Ok(x) => x,
         Err(e) => return handle_error(state, e.into()),
@@ -219,6 +219,7 @@
 //! }
 //! ```
 
+pub mod auth;
 pub mod background_tasks;
 pub mod cached_responses;
 pub mod chat_completion;
@@ -228,6 +229,9 @@ pub mod embeddings;
 pub mod handler_core;
 mod handlers;
 pub mod image_generation;
+pub mod mcp;
+pub mod messages;
+pub mod metrics;
 pub mod mistralrs_for_server_builder;
 pub mod mistralrs_server_router_builder;
 pub mod openai;
@@ -236,5 +240,6 @@ pub mod responses;
 pub mod responses_types;
 pub mod speech_generation;
 pub mod streaming;
+pub mod telemetry;
 pub mod types;
 pub mod util;
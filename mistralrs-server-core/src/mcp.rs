@@ -0,0 +1,353 @@
+//! ## MCP prompts and resources route handlers.
+//!
+//! These expose the prompts, resources and resource templates of the MCP servers a model is
+//! connected to, so clients can expand a prompt into the messages of a chat request or attach
+//! a resource as context.
+
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    extract::{Json, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use mistralrs_core::{McpClient, McpContent};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    handler_core::{ErrorToResponse, JsonError},
+    openai::{Message, MessageContent},
+    types::{ExtractedMistralRsState, SharedMistralRsState},
+};
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+pub struct McpQuery {
+    /// The model whose MCP servers are queried. Uses the default model if not set.
+    model: Option<String>,
+}
+
+/// An argument of an MCP prompt
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct McpPromptArgumentObject {
+    name: String,
+    description: Option<String>,
+    required: bool,
+}
+
+/// A prompt offered by an MCP server
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct McpPromptObject {
+    server_id: String,
+    server_name: String,
+    name: String,
+    description: Option<String>,
+    arguments: Vec<McpPromptArgumentObject>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct McpPromptList {
+    object: &'static str,
+    data: Vec<McpPromptObject>,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct McpGetPromptRequest {
+    /// The model whose MCP servers are queried. Uses the default model if not set.
+    #[serde(default)]
+    model: Option<String>,
+    /// The server offering the prompt.
+    server_id: String,
+    /// The name of the prompt.
+    name: String,
+    /// The arguments to fill the prompt in with.
+    #[serde(default)]
+    arguments: HashMap<String, String>,
+}
+
+/// An MCP prompt expanded into chat messages
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct McpPromptMessages {
+    description: Option<String>,
+    messages: Vec<Message>,
+}
+
+/// A resource offered by an MCP server
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct McpResourceObject {
+    server_id: String,
+    server_name: String,
+    uri: String,
+    name: String,
+    description: Option<String>,
+    mime_type: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct McpResourceList {
+    object: &'static str,
+    data: Vec<McpResourceObject>,
+}
+
+/// A template of resource URIs offered by an MCP server
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct McpResourceTemplateObject {
+    server_id: String,
+    server_name: String,
+    /// RFC 6570 template of the URIs, e.g. `file:///{path}`
+    uri_template: String,
+    name: String,
+    description: Option<String>,
+    mime_type: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct McpResourceTemplateList {
+    object: &'static str,
+    data: Vec<McpResourceTemplateObject>,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct McpReadResourceRequest {
+    /// The model whose MCP servers are queried. Uses the default model if not set.
+    #[serde(default)]
+    model: Option<String>,
+    /// The server offering the resource.
+    server_id: String,
+    /// The URI of the resource, which may be expanded from a resource template.
+    uri: String,
+}
+
+/// The text of an MCP resource
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct McpResourceContents {
+    uri: String,
+    text: String,
+    /// A user message with the resource as context, to add to a chat request
+    message: Message,
+}
+
+/// The MCP client of the requested model, or the error response.
+fn mcp_client(
+    state: &SharedMistralRsState,
+    model: Option<&str>,
+) -> Result<Arc<McpClient>, Response> {
+    let model = model.filter(|model| *model != "default");
+    match state.mcp_client(model) {
+        Ok(Some(client)) => Ok(client),
+        Ok(None) => Err(
+            JsonError::new("No MCP client is connected for this model".to_string())
+                .to_response(StatusCode::NOT_FOUND),
+        ),
+        Err(e) => Err(JsonError::new(e).to_response(StatusCode::NOT_FOUND)),
+    }
+}
+
+fn chat_message(role: String, content: &McpContent) -> Message {
+    let content = match content {
+        McpContent::Image { data, mime_type } => {
+            MessageContent::from_parts(vec![MessageContent::image_url_part(format!(
+                "data:{mime_type};base64,{data}"
+            ))])
+        }
+        content => MessageContent::from_text(content.to_string()),
+    };
+    Message {
+        content: Some(content),
+        role,
+        name: None,
+        tool_calls: None,
+        tool_call_id: None,
+    }
+}
+
+#[utoipa::path(
+  get,
+  tag = "Mistral.rs",
+  path = "/v1/mcp/prompts",
+  params(McpQuery),
+  responses((status = 200, description = "Prompts of the connected MCP servers", body = McpPromptList))
+)]
+pub async fn mcp_prompts(
+    State(state): ExtractedMistralRsState,
+    Query(query): Query<McpQuery>,
+) -> Response {
+    let client = match mcp_client(&state, query.model.as_deref()) {
+        Ok(client) => client,
+        Err(response) => return response,
+    };
+
+    let data = client
+        .list_prompts()
+        .await
+        .into_iter()
+        .map(|info| McpPromptObject {
+            server_id: info.server_id,
+            server_name: info.server_name,
+            name: info.prompt.name,
+            description: info.prompt.description,
+            arguments: info
+                .prompt
+                .arguments
+                .into_iter()
+                .map(|argument| McpPromptArgumentObject {
+                    name: argument.name,
+                    description: argument.description,
+                    required: argument.required,
+                })
+                .collect(),
+        })
+        .collect();
+
+    Json(McpPromptList {
+        object: "list",
+        data,
+    })
+    .into_response()
+}
+
+#[utoipa::path(
+  post,
+  tag = "Mistral.rs",
+  path = "/v1/mcp/prompts/get",
+  request_body = McpGetPromptRequest,
+  responses((status = 200, description = "The prompt expanded into chat messages", body = McpPromptMessages))
+)]
+pub async fn mcp_get_prompt(
+    State(state): ExtractedMistralRsState,
+    Json(request): Json<McpGetPromptRequest>,
+) -> Response {
+    let client = match mcp_client(&state, request.model.as_deref()) {
+        Ok(client) => client,
+        Err(response) => return response,
+    };
+
+    match client
+        .get_prompt(&request.server_id, &request.name, request.arguments)
+        .await
+    {
+        Ok(prompt) => Json(McpPromptMessages {
+            description: prompt.description,
+            messages: prompt
+                .messages
+                .into_iter()
+                .map(|message| chat_message(message.role, &message.content))
+                .collect(),
+        })
+        .into_response(),
+        Err(e) => JsonError::new(e.to_string()).to_response(StatusCode::BAD_REQUEST),
+    }
+}
+
+#[utoipa::path(
+  get,
+  tag = "Mistral.rs",
+  path = "/v1/mcp/resources",
+  params(McpQuery),
+  responses((status = 200, description = "Resources of the connected MCP servers", body = McpResourceList))
+)]
+pub async fn mcp_resources(
+    State(state): ExtractedMistralRsState,
+    Query(query): Query<McpQuery>,
+) -> Response {
+    let client = match mcp_client(&state, query.model.as_deref()) {
+        Ok(client) => client,
+        Err(response) => return response,
+    };
+
+    let data = client
+        .list_resources()
+        .await
+        .into_iter()
+        .map(|info| McpResourceObject {
+            server_id: info.server_id,
+            server_name: info.server_name,
+            uri: info.resource.uri,
+            name: info.resource.name,
+            description: info.resource.description,
+            mime_type: info.resource.mime_type,
+        })
+        .collect();
+
+    Json(McpResourceList {
+        object: "list",
+        data,
+    })
+    .into_response()
+}
+
+#[utoipa::path(
+  get,
+  tag = "Mistral.rs",
+  path = "/v1/mcp/resource_templates",
+  params(McpQuery),
+  responses((status = 200, description = "Resource templates of the connected MCP servers", body = McpResourceTemplateList))
+)]
+pub async fn mcp_resource_templates(
+    State(state): ExtractedMistralRsState,
+    Query(query): Query<McpQuery>,
+) -> Response {
+    let client = match mcp_client(&state, query.model.as_deref()) {
+        Ok(client) => client,
+        Err(response) => return response,
+    };
+
+    let data = client
+        .list_resource_templates()
+        .await
+        .into_iter()
+        .map(|info| McpResourceTemplateObject {
+            server_id: info.server_id,
+            server_name: info.server_name,
+            uri_template: info.template.uri_template,
+            name: info.template.name,
+            description: info.template.description,
+            mime_type: info.template.mime_type,
+        })
+        .collect();
+
+    Json(McpResourceTemplateList {
+        object: "list",
+        data,
+    })
+    .into_response()
+}
+
+#[utoipa::path(
+  post,
+  tag = "Mistral.rs",
+  path = "/v1/mcp/resources/read",
+  request_body = McpReadResourceRequest,
+  responses((status = 200, description = "The text of the resource", body = McpResourceContents))
+)]
+pub async fn mcp_read_resource(
+    State(state): ExtractedMistralRsState,
+    Json(request): Json<McpReadResourceRequest>,
+) -> Response {
+    let client = match mcp_client(&state, request.model.as_deref()) {
+        Ok(client) => client,
+        Err(response) => return response,
+    };
+
+    match client.read_resource(&request.server_id, &request.uri).await {
+        Ok(text) => {
+            let message = Message {
+                content: Some(MessageContent::from_text(format!(
+                    "Contents of the resource `{}`:\n\n{text}",
+                    request.uri
+                ))),
+                role: "user".to_string(),
+                name: None,
+                tool_calls: None,
+                tool_call_id: None,
+            };
+            Json(McpResourceContents {
+                uri: request.uri,
+                text,
+                message,
+            })
+            .into_response()
+        }
+        Err(e) => JsonError::new(e.to_string()).to_response(StatusCode::BAD_REQUEST),
+    }
+}
@@ -0,0 +1,1204 @@
+//! ## Anthropic Messages API functionality and route handler.
+//!
+//! Requests are converted to chat completion requests, and the responses back to messages with content blocks.
+
+use std::{collections::VecDeque, pin::Pin, task::Poll, time::Duration};
+
+use anyhow::Result;
+use axum::{
+    extract::{Json, State},
+    http::{HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, KeepAliveStream},
+        IntoResponse, Sse,
+    },
+};
+use either::Either;
+use mistralrs_core::{
+    ChatCompletionChunkResponse, ChatCompletionResponse, Function, MistralRs, Request, Response,
+    Tool, ToolChoice, ToolType,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::mpsc::Receiver;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    chat_completion::parse_normal_request as parse_chat_request,
+    completion_core::{
+        apply_header_timeout, get_traceparent, handle_completion_error, BaseCompletionResponder,
+    },
+    handler_core::{
+        create_response_channel, send_request_with_model, ErrorToResponse, ModelErrorMessage,
+    },
+    openai::{
+        ChatCompletionRequest, FunctionCalled, Message, MessageContent, StopTokens, ToolCall,
+    },
+    streaming::{get_keep_alive_interval, DoneState},
+    types::{ExtractedMistralRsState, SharedMistralRsState},
+    util::sanitize_error_message,
+};
+
+/// Content of a message, either plain text or a list of content blocks.
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum MessagesContent {
+    Text(String),
+    Blocks(Vec<ContentBlock>),
+}
+
+/// Source of an image content block.
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ImageSource {
+    Base64 { media_type: String, data: String },
+    Url { url: String },
+}
+
+/// A content block of a message.
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentBlock {
+    Text {
+        text: String,
+    },
+    Image {
+        source: ImageSource,
+    },
+    /// A tool call of the assistant.
+    ToolUse {
+        id: String,
+        name: String,
+        input: Value,
+    },
+    /// The result of a tool call, sent by the user. Only the text of block contents is used.
+    ToolResult {
+        tool_use_id: String,
+        #[serde(default)]
+        content: Option<Value>,
+        #[serde(default)]
+        is_error: Option<bool>,
+    },
+    /// Reasoning of the assistant. Previous reasoning is not passed to the model.
+    Thinking {
+        thinking: String,
+        #[serde(default)]
+        signature: Option<String>,
+    },
+    RedactedThinking {
+        data: String,
+    },
+}
+
+/// A message of the conversation.
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct MessageParam {
+    /// "user" or "assistant"
+    pub role: String,
+    pub content: MessagesContent,
+}
+
+/// A tool the model may use.
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct MessagesTool {
+    pub name: String,
+    pub description: Option<String>,
+    /// JSON schema of the tool input.
+    pub input_schema: Value,
+}
+
+/// How the model should use the tools.
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MessagesToolChoice {
+    Auto {
+        #[serde(default)]
+        disable_parallel_tool_use: Option<bool>,
+    },
+    /// Use any of the tools.
+    Any {
+        #[serde(default)]
+        disable_parallel_tool_use: Option<bool>,
+    },
+    Tool {
+        name: String,
+        #[serde(default)]
+        disable_parallel_tool_use: Option<bool>,
+    },
+    None,
+}
+
+impl MessagesToolChoice {
+    /// Whether the model may use at most one tool.
+    fn disables_parallel_tool_use(&self) -> bool {
+        match self {
+            Self::Auto {
+                disable_parallel_tool_use,
+            }
+            | Self::Any {
+                disable_parallel_tool_use,
+            }
+            | Self::Tool {
+                disable_parallel_tool_use,
+                ..
+            } => disable_parallel_tool_use.unwrap_or(false),
+            Self::None => false,
+        }
+    }
+}
+
+/// Extended thinking configuration. The budget is not enforced.
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ThinkingConfig {
+    Enabled {
+        #[serde(default)]
+        budget_tokens: Option<usize>,
+    },
+    Disabled,
+}
+
+/// Anthropic Messages API request.
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct MessagesRequest {
+    #[schema(example = "mistral")]
+    #[serde(default = "default_model")]
+    pub model: String,
+    pub messages: Vec<MessageParam>,
+    #[schema(example = 1024)]
+    pub max_tokens: usize,
+    /// System prompt, as text or text blocks.
+    #[serde(default)]
+    pub system: Option<MessagesContent>,
+    #[serde(default)]
+    pub stop_sequences: Option<Vec<String>>,
+    #[serde(default)]
+    pub stream: Option<bool>,
+    #[serde(default)]
+    pub temperature: Option<f64>,
+    #[serde(default)]
+    pub top_p: Option<f64>,
+    #[serde(default)]
+    pub top_k: Option<usize>,
+    #[serde(default)]
+    pub tools: Option<Vec<MessagesTool>>,
+    #[serde(default)]
+    pub tool_choice: Option<MessagesToolChoice>,
+    #[serde(default)]
+    pub thinking: Option<ThinkingConfig>,
+    /// Give up on the request after this many seconds. This is a mistral.rs extension.
+    #[serde(default)]
+    pub timeout: Option<f64>,
+}
+
+fn default_model() -> String {
+    "default".to_string()
+}
+
+/// Token usage of a message.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct MessagesUsage {
+    pub input_tokens: usize,
+    pub output_tokens: usize,
+}
+
+/// Anthropic Messages API response.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct MessagesResponse {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub object: &'static str,
+    pub role: &'static str,
+    pub model: String,
+    pub content: Vec<ContentBlock>,
+    /// "end_turn", "max_tokens" or "tool_use"
+    pub stop_reason: Option<&'static str>,
+    pub stop_sequence: Option<String>,
+    pub usage: MessagesUsage,
+}
+
+impl MessagesResponse {
+    fn new(id: String, model: String) -> Self {
+        Self {
+            id,
+            object: "message",
+            role: "assistant",
+            model,
+            content: Vec::new(),
+            stop_reason: None,
+            stop_sequence: None,
+            usage: MessagesUsage {
+                input_tokens: 0,
+                output_tokens: 0,
+            },
+        }
+    }
+}
+
+/// Error body in the Anthropic format.
+#[derive(Debug, Serialize)]
+struct MessagesError {
+    #[serde(rename = "type")]
+    object: &'static str,
+    error: MessagesErrorBody,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MessagesErrorBody {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    message: String,
+}
+
+impl ErrorToResponse for MessagesError {}
+
+impl MessagesError {
+    fn new(kind: &'static str, message: String) -> Self {
+        Self {
+            object: "error",
+            error: MessagesErrorBody { kind, message },
+        }
+    }
+}
+
+/// Map a chat completion finish reason to a stop reason. The finish reason of a generation
+/// ended by one of the stop sequences is `stop`, so the matched sequence is given as well.
+fn stop_reason(finish_reason: &str, stop_sequence: Option<&str>) -> &'static str {
+    match (finish_reason, stop_sequence) {
+        ("length", _) => "max_tokens",
+        ("tool_calls", _) => "tool_use",
+        (_, Some(_)) => "stop_sequence",
+        _ => "end_turn",
+    }
+}
+
+/// The text of a tool result, which is either a string or a list of content blocks.
+fn tool_result_text(content: Option<Value>) -> String {
+    match content {
+        Some(Value::String(text)) => text,
+        Some(Value::Array(blocks)) => blocks
+            .iter()
+            .filter_map(|block| block.get("text")?.as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
+
+fn text_message(role: &str, text: String) -> Message {
+    Message {
+        content: Some(MessageContent::from_text(text)),
+        role: role.to_string(),
+        name: None,
+        tool_calls: None,
+        tool_call_id: None,
+    }
+}
+
+/// Convert the messages to chat messages. Tool results become tool messages, and tool uses become tool calls.
+fn convert_messages(system: Option<MessagesContent>, messages: Vec<MessageParam>) -> Vec<Message> {
+    let mut converted = Vec::new();
+    match system {
+        Some(MessagesContent::Text(text)) => converted.push(text_message("system", text)),
+        Some(MessagesContent::Blocks(blocks)) => {
+            let text = blocks
+                .into_iter()
+                .filter_map(|block| match block {
+                    ContentBlock::Text { text } => Some(text),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+            converted.push(text_message("system", text));
+        }
+        None => (),
+    }
+
+    for message in messages {
+        let blocks = match message.content {
+            MessagesContent::Text(text) => {
+                converted.push(text_message(&message.role, text));
+                continue;
+            }
+            MessagesContent::Blocks(blocks) => blocks,
+        };
+
+        let mut texts = Vec::new();
+        let mut images = Vec::new();
+        let mut tool_calls = Vec::new();
+        for block in blocks {
+            match block {
+                ContentBlock::Text { text } => texts.push(text),
+                ContentBlock::Image { source } => images.push(match source {
+                    ImageSource::Base64 { media_type, data } => {
+                        format!("data:{media_type};base64,{data}")
+                    }
+                    ImageSource::Url { url } => url,
+                }),
+                ContentBlock::ToolUse { id, name, input } => tool_calls.push(ToolCall {
+                    id: Some(id),
+                    tp: ToolType::Function,
+                    function: FunctionCalled {
+                        name,
+                        arguments: input.to_string(),
+                    },
+                }),
+                ContentBlock::ToolResult {
+                    tool_use_id,
+                    content,
+                    is_error,
+                } => {
+                    let mut text = tool_result_text(content);
+                    if is_error == Some(true) {
+                        text = format!("Error: {text}");
+                    }
+                    converted.push(Message {
+                        tool_call_id: Some(tool_use_id),
+                        ..text_message("tool", text)
+                    });
+                }
+                ContentBlock::Thinking { .. } | ContentBlock::RedactedThinking { .. } => (),
+            }
+        }
+
+        if texts.is_empty() && images.is_empty() && tool_calls.is_empty() {
+            continue;
+        }
+        let content = if images.is_empty() {
+            (!texts.is_empty()).then(|| MessageContent::from_text(texts.join("\n")))
+        } else {
+            let parts = images
+                .into_iter()
+                .map(MessageContent::image_url_part)
+                .chain(texts.into_iter().map(MessageContent::text_part))
+                .collect();
+            Some(MessageContent::from_parts(parts))
+        };
+        converted.push(Message {
+            content,
+            role: message.role,
+            name: None,
+            tool_calls: (!tool_calls.is_empty()).then_some(tool_calls),
+            tool_call_id: None,
+        });
+    }
+
+    converted
+}
+
+/// Convert a messages request to a chat completion request.
+fn convert_request(request: MessagesRequest) -> Result<ChatCompletionRequest> {
+    let tools = request.tools.map(|tools| {
+        tools
+            .into_iter()
+            .map(|tool| Tool {
+                tp: ToolType::Function,
+                function: Function {
+                    description: tool.description,
+                    name: tool.name,
+                    parameters: match tool.input_schema {
+                        Value::Object(schema) => Some(schema.into_iter().collect()),
+                        _ => None,
+                    },
+                },
+            })
+            .collect::<Vec<_>>()
+    });
+
+    let find_tool = |name: &str| {
+        tools
+            .iter()
+            .flatten()
+            .find(|tool| tool.function.name == name)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("tool_choice names the unknown tool `{name}`."))
+    };
+    let tool_choice = match request.tool_choice {
+        Some(MessagesToolChoice::Auto { .. }) => Some(ToolChoice::Auto),
+        Some(MessagesToolChoice::None) => Some(ToolChoice::None),
+        Some(MessagesToolChoice::Tool { name, .. }) => Some(ToolChoice::Tool(find_tool(&name)?)),
+        Some(MessagesToolChoice::Any { .. }) => Some(ToolChoice::Required),
+        None => None,
+    };
+
+    let enable_thinking = request
+        .thinking
+        .map(|thinking| matches!(thinking, ThinkingConfig::Enabled { .. }));
+
+    Ok(ChatCompletionRequest {
+        messages: Either::Left(convert_messages(request.system, request.messages)),
+        model: request.model,
+        logit_bias: None,
+        logprobs: false,
+        top_logprobs: None,
+        max_tokens: Some(request.max_tokens),
+        n_choices: 1,
+        presence_penalty: None,
+        frequency_penalty: None,
+        repetition_penalty: None,
+        stop_seqs: request.stop_sequences.map(StopTokens::Multi),
+        temperature: request.temperature,
+        top_p: request.top_p,
+        seed: None,
+        stream: request.stream,
+        tools,
+        tool_choice,
+        response_format: None,
+        web_search_options: None,
+        top_k: request.top_k,
+        grammar: None,
+        min_p: None,
+        dry_multiplier: None,
+        dry_base: None,
+        dry_allowed_length: None,
+        dry_sequence_breakers: None,
+        num_beams: None,
+        length_penalty: None,
+        early_stopping: None,
+        typical_p: None,
+        top_a: None,
+        xtc_probability: None,
+        xtc_threshold: None,
+        mirostat_tau: None,
+        mirostat_eta: None,
+        sampler_order: None,
+        enable_thinking,
+        truncate_sequence: None,
+        reasoning_effort: None,
+        priority: None,
+        timeout: request.timeout,
+        prompt_logprobs: None,
+    })
+}
+
+/// Parse the arguments of a tool call. The input of a tool use is an object, so arguments which are not a
+/// JSON object are kept as a string in the `arguments` field.
+fn tool_input(arguments: &str) -> Value {
+    match serde_json::from_str(arguments) {
+        Ok(Value::Object(input)) => Value::Object(input),
+        _ => serde_json::json!({ "arguments": arguments }),
+    }
+}
+
+/// Convert a chat completion response to a message, keeping only the first tool use if
+/// `disable_parallel_tool_use` is set.
+fn convert_response(
+    response: ChatCompletionResponse,
+    disable_parallel_tool_use: bool,
+) -> MessagesResponse {
+    let mut message = MessagesResponse::new(format!("msg_{}", Uuid::new_v4()), response.model);
+    message.usage = MessagesUsage {
+        input_tokens: response.usage.prompt_tokens,
+        output_tokens: response.usage.completion_tokens,
+    };
+
+    let Some(choice) = response.choices.into_iter().next() else {
+        return message;
+    };
+    if let Some(thinking) = choice.message.reasoning_content {
+        message.content.push(ContentBlock::Thinking {
+            thinking,
+            signature: Some(String::new()),
+        });
+    }
+    if let Some(text) = choice.message.content.filter(|text| !text.is_empty()) {
+        message.content.push(ContentBlock::Text { text });
+    }
+    let max_tool_uses = if disable_parallel_tool_use {
+        1
+    } else {
+        usize::MAX
+    };
+    for tool_call in choice
+        .message
+        .tool_calls
+        .into_iter()
+        .flatten()
+        .take(max_tool_uses)
+    {
+        message.content.push(ContentBlock::ToolUse {
+            id: tool_call.id,
+            name: tool_call.function.name,
+            input: tool_input(&tool_call.function.arguments),
+        });
+    }
+    message.stop_reason = Some(stop_reason(
+        &choice.finish_reason,
+        choice.stop_sequence.as_deref(),
+    ));
+    message.stop_sequence = choice.stop_sequence;
+    message
+}
+
+/// A delta of a content block.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentBlockDelta {
+    TextDelta { text: String },
+    ThinkingDelta { thinking: String },
+    InputJsonDelta { partial_json: String },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MessageDelta {
+    stop_reason: Option<&'static str>,
+    stop_sequence: Option<String>,
+}
+
+/// Server-sent events of a streamed message.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MessagesStreamEvent {
+    MessageStart {
+        message: MessagesResponse,
+    },
+    ContentBlockStart {
+        index: usize,
+        content_block: ContentBlock,
+    },
+    ContentBlockDelta {
+        index: usize,
+        delta: ContentBlockDelta,
+    },
+    ContentBlockStop {
+        index: usize,
+    },
+    MessageDelta {
+        delta: MessageDelta,
+        usage: MessagesUsage,
+    },
+    MessageStop,
+    Error {
+        error: MessagesErrorBody,
+    },
+}
+
+impl MessagesStreamEvent {
+    fn event_type(&self) -> &'static str {
+        match self {
+            Self::MessageStart { .. } => "message_start",
+            Self::ContentBlockStart { .. } => "content_block_start",
+            Self::ContentBlockDelta { .. } => "content_block_delta",
+            Self::ContentBlockStop { .. } => "content_block_stop",
+            Self::MessageDelta { .. } => "message_delta",
+            Self::MessageStop => "message_stop",
+            Self::Error { .. } => "error",
+        }
+    }
+}
+
+/// Kind of the content block being streamed.
+#[derive(Clone, Copy, PartialEq)]
+enum BlockKind {
+    Thinking,
+    Text,
+    ToolUse,
+}
+
+/// Converts chat completion chunks to message events.
+#[derive(Default)]
+struct MessageEvents {
+    /// The content block being streamed and its index
+    block: Option<(BlockKind, usize)>,
+    next_index: usize,
+    pending: VecDeque<MessagesStreamEvent>,
+    /// Only the first tool use is streamed if set
+    disable_parallel_tool_use: bool,
+    num_tool_uses: usize,
+    /// Whether the fragments of the current tool call are dropped
+    skip_tool_call: bool,
+}
+
+impl MessageEvents {
+    fn stop_block(&mut self) {
+        if let Some((_, index)) = self.block.take() {
+            self.pending
+                .push_back(MessagesStreamEvent::ContentBlockStop { index });
+        }
+    }
+
+    /// Start a new content block unless one of this kind is being streamed, and return its index.
+    fn ensure_block(&mut self, kind: BlockKind, content_block: ContentBlock) -> usize {
+        match self.block {
+            Some((current, index)) if current == kind && kind != BlockKind::ToolUse => index,
+            _ => {
+                self.stop_block();
+                let index = self.next_index;
+                self.next_index += 1;
+                self.block = Some((kind, index));
+                self.pending
+                    .push_back(MessagesStreamEvent::ContentBlockStart {
+                        index,
+                        content_block,
+                    });
+                index
+            }
+        }
+    }
+
+    /// Queue the events of a chunk. Returns whether the message is finished.
+    fn handle_chunk(&mut self, chunk: ChatCompletionChunkResponse) -> bool {
+        let Some(choice) = chunk.choices.into_iter().next() else {
+            return false;
+        };
+
+        if let Some(thinking) = choice.delta.reasoning_content.filter(|t| !t.is_empty()) {
+            let index = self.ensure_block(
+                BlockKind::Thinking,
+                ContentBlock::Thinking {
+                    thinking: String::new(),
+                    signature: Some(String::new()),
+                },
+            );
+            self.pending
+                .push_back(MessagesStreamEvent::ContentBlockDelta {
+                    index,
+                    delta: ContentBlockDelta::ThinkingDelta { thinking },
+                });
+        }
+        if let Some(text) = choice.delta.content.filter(|t| !t.is_empty()) {
+            let index = self.ensure_block(
+                BlockKind::Text,
+                ContentBlock::Text {
+                    text: String::new(),
+                },
+            );
+            self.pending
+                .push_back(MessagesStreamEvent::ContentBlockDelta {
+                    index,
+                    delta: ContentBlockDelta::TextDelta { text },
+                });
+        }
+        for tool_call in choice.delta.tool_calls.into_iter().flatten() {
+            // The first fragment of a call starts its block, and the rest continue it.
+            let index = match (tool_call.id, tool_call.function.name) {
+                (Some(id), Some(name)) => {
+                    self.skip_tool_call = self.disable_parallel_tool_use && self.num_tool_uses > 0;
+                    if self.skip_tool_call {
+                        continue;
+                    }
+                    self.num_tool_uses += 1;
+                    self.ensure_block(
+                        BlockKind::ToolUse,
+                        ContentBlock::ToolUse {
+                            id,
+                            name,
+                            input: Value::Object(Default::default()),
+                        },
+                    )
+                }
+                _ => match self.block {
+                    Some((BlockKind::ToolUse, index)) if !self.skip_tool_call => index,
+                    _ => continue,
+                },
+            };
+            if tool_call.function.arguments.is_empty() {
+                continue;
+            }
+            self.pending
+                .push_back(MessagesStreamEvent::ContentBlockDelta {
+                    index,
+                    delta: ContentBlockDelta::InputJsonDelta {
+                        partial_json: tool_call.function.arguments,
+                    },
+                });
+        }
+
+        if let Some(finish_reason) = choice.finish_reason {
+            self.stop_block();
+            let usage = chunk.usage.map_or(
+                MessagesUsage {
+                    input_tokens: 0,
+                    output_tokens: 0,
+                },
+                |usage| MessagesUsage {
+                    input_tokens: usage.prompt_tokens,
+                    output_tokens: usage.completion_tokens,
+                },
+            );
+            self.pending.push_back(MessagesStreamEvent::MessageDelta {
+                delta: MessageDelta {
+                    stop_reason: Some(stop_reason(&finish_reason, choice.stop_sequence.as_deref())),
+                    stop_sequence: choice.stop_sequence,
+                },
+                usage,
+            });
+            self.pending.push_back(MessagesStreamEvent::MessageStop);
+            return true;
+        }
+        false
+    }
+}
+
+/// Streamer converting chat completion chunks to message events.
+pub struct MessagesStreamer {
+    rx: Receiver<Response>,
+    done_state: DoneState,
+    state: SharedMistralRsState,
+    message: Option<MessagesResponse>,
+    events: MessageEvents,
+    /// The request is canceled if the client disconnects before completion
+    request_id: usize,
+    model_id: Option<String>,
+}
+
+impl MessagesStreamer {
+    fn new(
+        rx: Receiver<Response>,
+        state: SharedMistralRsState,
+        model: String,
+        request_id: usize,
+        model_id: Option<String>,
+        disable_parallel_tool_use: bool,
+    ) -> Self {
+        Self {
+            rx,
+            done_state: DoneState::Running,
+            state,
+            message: Some(MessagesResponse::new(
+                format!("msg_{}", Uuid::new_v4()),
+                model,
+            )),
+            events: MessageEvents {
+                disable_parallel_tool_use,
+                ..Default::default()
+            },
+            request_id,
+            model_id,
+        }
+    }
+
+    fn handle_chunk(&mut self, chunk: ChatCompletionChunkResponse) {
+        if self.events.handle_chunk(chunk) {
+            self.done_state = DoneState::Done;
+        }
+    }
+
+    fn error(&mut self, kind: &'static str, message: String) {
+        self.events.pending.push_back(MessagesStreamEvent::Error {
+            error: MessagesErrorBody { kind, message },
+        });
+        self.done_state = DoneState::Done;
+    }
+}
+
+impl futures::Stream for MessagesStreamer {
+    type Item = Result<Event, axum::Error>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(event) = self.events.pending.pop_front() {
+                return Poll::Ready(Some(
+                    Event::default().event(event.event_type()).json_data(event),
+                ));
+            }
+            if !matches!(self.done_state, DoneState::Running) {
+                return Poll::Ready(None);
+            }
+            if let Some(message) = self.message.take() {
+                self.events
+                    .pending
+                    .push_back(MessagesStreamEvent::MessageStart { message });
+                continue;
+            }
+
+            match self.rx.poll_recv(cx) {
+                Poll::Ready(Some(Response::Chunk(chunk))) => {
+                    MistralRs::maybe_log_response(self.state.clone(), &chunk);
+                    self.handle_chunk(chunk);
+                }
+                Poll::Ready(Some(Response::ModelError(msg, _))) => {
+                    MistralRs::maybe_log_error(
+                        self.state.clone(),
+                        &ModelErrorMessage(msg.to_string()),
+                    );
+                    self.error("api_error", msg);
+                }
+                Poll::Ready(Some(Response::ValidationError(e))) => {
+                    self.error("invalid_request_error", sanitize_error_message(e.as_ref()));
+                }
+                Poll::Ready(Some(Response::InternalError(e))) => {
+                    MistralRs::maybe_log_error(self.state.clone(), &*e);
+                    self.error("api_error", sanitize_error_message(e.as_ref()));
+                }
+                Poll::Ready(Some(_)) => (),
+                Poll::Ready(None) => self.done_state = DoneState::Done,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl Drop for MessagesStreamer {
+    fn drop(&mut self) {
+        if !matches!(self.done_state, DoneState::Running) {
+            return;
+        }
+        if let Err(e) = self
+            .state
+            .cancel_request(self.request_id, self.model_id.as_deref())
+        {
+            tracing::warn!(
+                "Failed to cancel request {} after disconnect: {e}",
+                self.request_id
+            );
+        }
+    }
+}
+
+/// Response responder types for the Messages API
+pub type MessagesResponder =
+    BaseCompletionResponder<MessagesResponse, KeepAliveStream<MessagesStreamer>>;
+
+impl IntoResponse for MessagesResponder {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            MessagesResponder::Sse(s) => s.into_response(),
+            MessagesResponder::Json(s) => Json(s).into_response(),
+            MessagesResponder::InternalError(e) => {
+                MessagesError::new("api_error", sanitize_error_message(e.as_ref()))
+                    .to_response(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+            MessagesResponder::ValidationError(e) => {
+                MessagesError::new("invalid_request_error", sanitize_error_message(e.as_ref()))
+                    .to_response(StatusCode::BAD_REQUEST)
+            }
+            MessagesResponder::ModelError(msg, _) => {
+                MessagesError::new("api_error", msg).to_response(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+}
+
+/// Anthropic-compatible messages endpoint handler.
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/messages",
+    request_body = MessagesRequest,
+    responses((status = 200, description = "Anthropic compatible message", body = MessagesResponse))
+)]
+pub async fn messages(
+    State(state): ExtractedMistralRsState,
+    headers: HeaderMap,
+    Json(mut request): Json<MessagesRequest>,
+) -> MessagesResponder {
+    let (tx, mut rx) = create_response_channel(None);
+
+    if let Err(e) = apply_header_timeout(&headers, &mut request.timeout) {
+        return MessagesResponder::ValidationError(e.into());
+    }
+
+    let model_id = if request.model == "default" {
+        None
+    } else {
+        Some(request.model.clone())
+    };
+    let model = request.model.clone();
+    let disable_parallel_tool_use = request
+        .tool_choice
+        .as_ref()
+        .is_some_and(MessagesToolChoice::disables_parallel_tool_use);
+
+    let chat_request = match convert_request(request) {
+        Ok(x) => x,
+        Err(e) => return MessagesResponder::ValidationError(e.into()),
+    };
+    let (mut request, is_streaming) =
+        match parse_chat_request(chat_request, state.clone(), tx).await {
+            Ok(x) => x,
+            Err(e) => return MessagesResponder::ValidationError(e.into()),
+        };
+    request.traceparent = get_traceparent(&headers);
+    let request_id = request.id;
+
+    if let Err(e) =
+        send_request_with_model(&state, Request::Normal(request), model_id.as_deref()).await
+    {
+        return handle_completion_error(state, e.into());
+    }
+
+    if is_streaming {
+        let streamer = MessagesStreamer::new(
+            rx,
+            state,
+            model,
+            request_id,
+            model_id,
+            disable_parallel_tool_use,
+        );
+        let keep_alive_interval = get_keep_alive_interval();
+        return MessagesResponder::Sse(
+            Sse::new(streamer)
+                .keep_alive(KeepAlive::new().interval(Duration::from_millis(keep_alive_interval))),
+        );
+    }
+
+    match rx.recv().await {
+        Some(Response::Done(response)) => {
+            MistralRs::maybe_log_response(state, &response);
+            MessagesResponder::Json(convert_response(response, disable_parallel_tool_use))
+        }
+        Some(Response::ModelError(msg, response)) => {
+            MistralRs::maybe_log_error(state.clone(), &ModelErrorMessage(msg.to_string()));
+            MistralRs::maybe_log_response(state, &response);
+            MessagesResponder::ModelError(
+                msg,
+                convert_response(response, disable_parallel_tool_use),
+            )
+        }
+        Some(Response::ValidationError(e)) => MessagesResponder::ValidationError(e),
+        Some(Response::InternalError(e)) => {
+            MistralRs::maybe_log_error(state, &*e);
+            MessagesResponder::InternalError(e)
+        }
+        _ => MessagesResponder::InternalError(anyhow::anyhow!("Unexpected response type").into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mistralrs_core::{
+        Choice, ChunkChoice, Delta, FunctionDelta, ResponseMessage, ToolCallDelta, ToolCallType,
+        Usage,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_convert_tool_use_and_result() {
+        let request: MessagesRequest = serde_json::from_value(serde_json::json!({
+            "model": "default",
+            "max_tokens": 64,
+            "system": [{"type": "text", "text": "Be brief."}],
+            "messages": [
+                {"role": "user", "content": "What is the weather in Paris?"},
+                {"role": "assistant", "content": [
+                    {"type": "thinking", "thinking": "I should call the tool.", "signature": ""},
+                    {"type": "tool_use", "id": "toolu_1", "name": "get_weather", "input": {"city": "Paris"}}
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "toolu_1", "content": [{"type": "text", "text": "Sunny"}]}
+                ]}
+            ]
+        }))
+        .unwrap();
+
+        let messages = convert_messages(request.system, request.messages);
+        let roles = messages.iter().map(|m| m.role.as_str()).collect::<Vec<_>>();
+        assert_eq!(roles, ["system", "user", "assistant", "tool"]);
+
+        let tool_calls = messages[2].tool_calls.as_ref().unwrap();
+        assert_eq!(tool_calls[0].id.as_deref(), Some("toolu_1"));
+        assert_eq!(tool_calls[0].function.arguments, r#"{"city":"Paris"}"#);
+        assert!(messages[2].content.is_none());
+
+        assert_eq!(messages[3].tool_call_id.as_deref(), Some("toolu_1"));
+        assert_eq!(
+            messages[3].content.as_ref().unwrap().to_text().as_deref(),
+            Some("Sunny")
+        );
+    }
+
+    fn chunk(
+        content: Option<&str>,
+        tool_calls: Vec<ToolCallDelta>,
+        finish_reason: Option<&str>,
+    ) -> ChatCompletionChunkResponse {
+        ChatCompletionChunkResponse {
+            id: "chatcmpl".to_string(),
+            choices: vec![ChunkChoice {
+                finish_reason: finish_reason.map(str::to_string),
+                index: 0,
+                delta: Delta {
+                    content: content.map(str::to_string),
+                    role: "assistant".to_string(),
+                    tool_calls: (!tool_calls.is_empty()).then_some(tool_calls),
+                    reasoning_content: None,
+                },
+                logprobs: None,
+                stop_sequence: None,
+            }],
+            created: 0,
+            model: "default".to_string(),
+            system_fingerprint: "local".to_string(),
+            object: "chat.completion.chunk".to_string(),
+            usage: None,
+        }
+    }
+
+    fn tool_call(index: usize, name: Option<&str>, arguments: &str) -> ToolCallDelta {
+        ToolCallDelta {
+            index,
+            id: name.map(|name| format!("call_{name}")),
+            tp: name.map(|_| ToolCallType::Function),
+            function: FunctionDelta {
+                name: name.map(str::to_string),
+                arguments: arguments.to_string(),
+            },
+        }
+    }
+
+    /// Summarize the events as `(type, index)` pairs.
+    fn event_sequence(events: &MessageEvents) -> Vec<(&'static str, Option<usize>)> {
+        events
+            .pending
+            .iter()
+            .map(|event| {
+                let index = match event {
+                    MessagesStreamEvent::ContentBlockStart { index, .. }
+                    | MessagesStreamEvent::ContentBlockDelta { index, .. }
+                    | MessagesStreamEvent::ContentBlockStop { index } => Some(*index),
+                    _ => None,
+                };
+                (event.event_type(), index)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_stream_event_sequence() {
+        let mut events = MessageEvents::default();
+        assert!(!events.handle_chunk(chunk(Some("Let me "), vec![], None)));
+        assert!(!events.handle_chunk(chunk(Some("check."), vec![], None)));
+        assert!(!events.handle_chunk(chunk(
+            None,
+            vec![tool_call(0, Some("get_weather"), r#"{"city":"#)],
+            None
+        )));
+        assert!(!events.handle_chunk(chunk(None, vec![tool_call(0, None, r#""Paris"}"#)], None)));
+        assert!(!events.handle_chunk(chunk(
+            None,
+            vec![tool_call(1, Some("get_time"), "{}")],
+            None
+        )));
+        assert!(events.handle_chunk(chunk(None, vec![], Some("tool_calls"))));
+
+        assert_eq!(
+            event_sequence(&events),
+            [
+                ("content_block_start", Some(0)),
+                ("content_block_delta", Some(0)),
+                ("content_block_delta", Some(0)),
+                ("content_block_stop", Some(0)),
+                ("content_block_start", Some(1)),
+                ("content_block_delta", Some(1)),
+                ("content_block_delta", Some(1)),
+                ("content_block_stop", Some(1)),
+                ("content_block_start", Some(2)),
+                ("content_block_delta", Some(2)),
+                ("content_block_stop", Some(2)),
+                ("message_delta", None),
+                ("message_stop", None),
+            ]
+        );
+        let MessagesStreamEvent::MessageDelta { delta, .. } = &events.pending[11] else {
+            panic!("Expected the message delta");
+        };
+        assert_eq!(delta.stop_reason, Some("tool_use"));
+    }
+
+    #[test]
+    fn test_stream_disable_parallel_tool_use() {
+        let mut events = MessageEvents {
+            disable_parallel_tool_use: true,
+            ..Default::default()
+        };
+        events.handle_chunk(chunk(
+            None,
+            vec![
+                tool_call(0, Some("get_weather"), "{}"),
+                tool_call(1, Some("get_time"), ""),
+            ],
+            None,
+        ));
+        events.handle_chunk(chunk(
+            None,
+            vec![tool_call(1, None, "{}")],
+            Some("tool_calls"),
+        ));
+
+        assert_eq!(
+            event_sequence(&events),
+            [
+                ("content_block_start", Some(0)),
+                ("content_block_delta", Some(0)),
+                ("content_block_stop", Some(0)),
+                ("message_delta", None),
+                ("message_stop", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stop_sequence() {
+        let response = ChatCompletionResponse {
+            id: "chatcmpl".to_string(),
+            choices: vec![Choice {
+                finish_reason: "stop".to_string(),
+                index: 0,
+                message: ResponseMessage {
+                    content: Some("1, 2".to_string()),
+                    role: "assistant".to_string(),
+                    tool_calls: None,
+                    reasoning_content: None,
+                },
+                logprobs: None,
+                stop_sequence: Some(", 3".to_string()),
+            }],
+            created: 0,
+            model: "default".to_string(),
+            system_fingerprint: "local".to_string(),
+            object: "chat.completion".to_string(),
+            usage: Usage {
+                completion_tokens: 0,
+                prompt_tokens: 0,
+                total_tokens: 0,
+                avg_tok_per_sec: 0.,
+                avg_prompt_tok_per_sec: 0.,
+                avg_compl_tok_per_sec: 0.,
+                total_time_sec: 0.,
+                total_prompt_time_sec: 0.,
+                total_completion_time_sec: 0.,
+            },
+            prompt_logprobs: None,
+        };
+        let message = convert_response(response, false);
+        assert_eq!(message.stop_reason, Some("stop_sequence"));
+        assert_eq!(message.stop_sequence.as_deref(), Some(", 3"));
+
+        let mut events = MessageEvents::default();
+        let mut last = chunk(Some("1, 2"), vec![], Some("stop"));
+        last.choices[0].stop_sequence = Some(", 3".to_string());
+        assert!(events.handle_chunk(last));
+        let MessagesStreamEvent::MessageDelta { delta, .. } = &events.pending[3] else {
+            panic!("Expected the message delta");
+        };
+        assert_eq!(delta.stop_reason, Some("stop_sequence"));
+        assert_eq!(delta.stop_sequence.as_deref(), Some(", 3"));
+
+        let mut events = MessageEvents::default();
+        assert!(events.handle_chunk(chunk(Some("1, 2"), vec![], Some("stop"))));
+        let MessagesStreamEvent::MessageDelta { delta, .. } = &events.pending[3] else {
+            panic!("Expected the message delta");
+        };
+        assert_eq!(delta.stop_reason, Some("end_turn"));
+    }
+
+    #[test]
+    fn test_tool_input_is_an_object() {
+        assert_eq!(
+            tool_input(r#"{"city":"Paris"}"#),
+            serde_json::json!({"city": "Paris"})
+        );
+        assert_eq!(
+            tool_input("Paris"),
+            serde_json::json!({"arguments": "Paris"})
+        );
+        assert_eq!(tool_input("[1]"), serde_json::json!({"arguments": "[1]"}));
+    }
+}
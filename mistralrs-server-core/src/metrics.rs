@@ -0,0 +1,360 @@
+//! ## Prometheus metrics.
+
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Write},
+    sync::{LazyLock, Mutex},
+};
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{MatchedPath, Request, State},
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use mistralrs_core::LatencyHistogram;
+use serde::Deserialize;
+
+use crate::types::{ExtractedMistralRsState, SharedMistralRsState};
+
+/// Content type of the Prometheus text exposition format.
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Handled HTTP requests, by route, model and status code.
+static HTTP_REQUESTS: LazyLock<Mutex<BTreeMap<(String, String, u16), u64>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+#[derive(Deserialize)]
+struct ModelField {
+    model: Option<String>,
+}
+
+/// Resolve the model label of a request. Unknown models share a label to keep the number of series bounded.
+fn model_label(state: &SharedMistralRsState, model: Option<&str>) -> String {
+    match model {
+        None | Some("default") => state
+            .get_default_model_id()
+            .ok()
+            .flatten()
+            .unwrap_or_else(|| "default".to_string()),
+        Some(model)
+            if state
+                .list_models()
+                .is_ok_and(|models| models.iter().any(|m| m == model)) =>
+        {
+            model.to_string()
+        }
+        Some(_) => "unknown".to_string(),
+    }
+}
+
+/// Middleware which counts the handled requests by route, model and status code. Its state is the mistral.rs
+/// instance and the request body limit.
+///
+/// The model is read from the `model` field of JSON request bodies, so requests without a body have an empty model label.
+pub async fn track_requests(
+    State((state, body_limit)): State<(SharedMistralRsState, usize)>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_default();
+
+    let (request, model) = if request.method() == Method::POST {
+        let (parts, body) = request.into_parts();
+        let bytes = match to_bytes(body, body_limit).await {
+            Ok(bytes) => bytes,
+            Err(_) => {
+                record_request(route, String::new(), StatusCode::PAYLOAD_TOO_LARGE);
+                return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+            }
+        };
+        let model = serde_json::from_slice::<ModelField>(&bytes)
+            .ok()
+            .and_then(|field| field.model);
+        let model = model_label(&state, model.as_deref());
+        (Request::from_parts(parts, Body::from(bytes)), model)
+    } else {
+        (request, String::new())
+    };
+
+    let response = next.run(request).await;
+    record_request(route, model, response.status());
+    response
+}
+
+fn record_request(route: String, model: String, status: StatusCode) {
+    *HTTP_REQUESTS
+        .lock()
+        .unwrap()
+        .entry((route, model, status.as_u16()))
+        .or_default() += 1;
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn write_sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: impl Display) {
+    let labels = labels
+        .iter()
+        .map(|(key, value)| format!("{key}=\"{}\"", escape_label(value)))
+        .collect::<Vec<_>>()
+        .join(",");
+    let _ = writeln!(out, "{name}{{{labels}}} {value}");
+}
+
+fn write_histogram(out: &mut String, name: &str, model: &str, histogram: &LatencyHistogram) {
+    for (bound, count) in histogram.buckets.iter().zip(&histogram.counts) {
+        write_sample(
+            out,
+            &format!("{name}_bucket"),
+            &[("model", model), ("le", &bound.to_string())],
+            count,
+        );
+    }
+    write_sample(
+        out,
+        &format!("{name}_bucket"),
+        &[("model", model), ("le", "+Inf")],
+        histogram.count,
+    );
+    write_sample(
+        out,
+        &format!("{name}_sum"),
+        &[("model", model)],
+        histogram.sum,
+    );
+    write_sample(
+        out,
+        &format!("{name}_count"),
+        &[("model", model)],
+        histogram.count,
+    );
+}
+
+/// Render the metrics of the server and its engines in the Prometheus text format.
+fn render_metrics(state: &SharedMistralRsState) -> String {
+    let mut out = String::new();
+
+    write_header(
+        &mut out,
+        "mistralrs_http_requests_total",
+        "counter",
+        "HTTP requests handled, by route, model and status code.",
+    );
+    for ((route, model, status), count) in HTTP_REQUESTS.lock().unwrap().iter() {
+        write_sample(
+            &mut out,
+            "mistralrs_http_requests_total",
+            &[
+                ("route", route),
+                ("model", model),
+                ("status", &status.to_string()),
+            ],
+            count,
+        );
+    }
+
+    let stats = state
+        .list_models()
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|model| {
+            let stats = state.engine_stats(Some(&model)).ok()?;
+            Some((model, stats))
+        })
+        .collect::<Vec<_>>();
+
+    let gauges: [(&str, &str, &str, fn(&mistralrs_core::EngineStats) -> Option<f64>); 12] = [
+        (
+            "mistralrs_running_sequences",
+            "gauge",
+            "Sequences which are running.",
+            |s| Some(s.num_running as f64),
+        ),
+        (
+            "mistralrs_waiting_sequences",
+            "gauge",
+            "Sequences which are waiting to be scheduled.",
+            |s| Some(s.num_waiting as f64),
+        ),
+        (
+            "mistralrs_kv_cache_blocks_total",
+            "gauge",
+            "KV cache blocks, with PagedAttention.",
+            |s| s.kv_blocks_total.map(|n| n as f64),
+        ),
+        (
+            "mistralrs_kv_cache_blocks_used",
+            "gauge",
+            "KV cache blocks which are used by sequences or hold prefix cached data, with PagedAttention.",
+            |s| s.kv_blocks_used.map(|n| n as f64),
+        ),
+        (
+            "mistralrs_kv_cache_blocks_cached",
+            "gauge",
+            "KV cache blocks which hold prefix cached data, with PagedAttention.",
+            |s| s.kv_blocks_cached.map(|n| n as f64),
+        ),
+        (
+            "mistralrs_kv_cache_utilization",
+            "gauge",
+            "Fraction of the KV cache blocks which are used, with PagedAttention.",
+            |s| {
+                let total = s.kv_blocks_total.filter(|total| *total != 0)?;
+                Some(s.kv_blocks_used? as f64 / total as f64)
+            },
+        ),
+        (
+            "mistralrs_prefix_cache_hit_rate",
+            "gauge",
+            "Fraction of the sequences which started from a prefix cache hit.",
+            |s| Some(s.prefix_cache_hit_rate),
+        ),
+        (
+            "mistralrs_prefix_cache_hits_total",
+            "counter",
+            "Sequences which started from a prefix cache hit.",
+            |s| Some(s.prefix_cache_hits as f64),
+        ),
+        (
+            "mistralrs_sequences_total",
+            "counter",
+            "Sequences which have been started.",
+            |s| Some(s.num_sequences as f64),
+        ),
+        (
+            "mistralrs_prompt_tokens_total",
+            "counter",
+            "Prompt tokens processed.",
+            |s| Some(s.total_prefill_tokens as f64),
+        ),
+        (
+            "mistralrs_generated_tokens_total",
+            "counter",
+            "Tokens generated.",
+            |s| Some(s.total_decode_tokens as f64),
+        ),
+        (
+            "mistralrs_preemptions_total",
+            "counter",
+            "Sequences which were preempted to free KV cache blocks.",
+            |s| Some(s.num_preemptions as f64),
+        ),
+    ];
+    for (name, kind, help, value) in gauges {
+        write_header(&mut out, name, kind, help);
+        for (model, stats) in &stats {
+            if let Some(value) = value(stats) {
+                write_sample(&mut out, name, &[("model", model)], value);
+            }
+        }
+    }
+
+    write_header(
+        &mut out,
+        "mistralrs_time_to_first_token_seconds",
+        "histogram",
+        "Time from a sequence being added until its prompt has been processed.",
+    );
+    for (model, stats) in &stats {
+        write_histogram(
+            &mut out,
+            "mistralrs_time_to_first_token_seconds",
+            model,
+            &stats.time_to_first_token,
+        );
+    }
+    write_header(
+        &mut out,
+        "mistralrs_inter_token_latency_seconds",
+        "histogram",
+        "Time between generated tokens of a sequence.",
+    );
+    for (model, stats) in &stats {
+        write_histogram(
+            &mut out,
+            "mistralrs_inter_token_latency_seconds",
+            model,
+            &stats.inter_token_latency,
+        );
+    }
+
+    out
+}
+
+#[utoipa::path(
+  get,
+  tag = "Mistral.rs",
+  path = "/metrics",
+  responses((status = 200, description = "Prometheus metrics", content_type = "text/plain"))
+)]
+pub async fn metrics(State(state): ExtractedMistralRsState) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, PROMETHEUS_CONTENT_TYPE)],
+        render_metrics(&state),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use mistralrs_core::LatencyHistogram;
+
+    use super::{write_header, write_histogram, write_sample};
+
+    #[test]
+    fn test_render_samples() {
+        let mut out = String::new();
+        write_header(&mut out, "requests_total", "counter", "Requests.");
+        write_sample(
+            &mut out,
+            "requests_total",
+            &[
+                ("route", "/v1/chat/completions"),
+                ("model", "a \"quoted\"\nmodel"),
+            ],
+            3,
+        );
+        assert_eq!(
+            out,
+            r#"# HELP requests_total Requests.
+# TYPE requests_total counter
+requests_total{route="/v1/chat/completions",model="a \"quoted\"\nmodel"} 3
+"#
+        );
+    }
+
+    #[test]
+    fn test_render_histogram() {
+        let histogram = LatencyHistogram {
+            buckets: vec![0.1, 1.],
+            counts: vec![1, 2],
+            count: 3,
+            sum: 2.5,
+        };
+        let mut out = String::new();
+        write_histogram(&mut out, "latency_seconds", "model", &histogram);
+        assert_eq!(
+            out,
+            r#"latency_seconds_bucket{model="model",le="0.1"} 1
+latency_seconds_bucket{model="model",le="1"} 2
+latency_seconds_bucket{model="model",le="+Inf"} 3
+latency_seconds_sum{model="model"} 2.5
+latency_seconds_count{model="model"} 3
+"#
+        );
+    }
+}
@@ -1,17 +1,18 @@
 //! ## mistral.rs instance for server builder.
 
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
 use anyhow::{Context, Result};
 use candle_core::Device;
 use mistralrs_core::{
     get_auto_device_map_params, get_model_dtype, get_tgt_non_granular_index, paged_attn_supported,
     parse_isq_value, AutoDeviceMapParams, DefaultSchedulerMethod, DeviceLayerMapMetadata,
-    DeviceMapMetadata, DeviceMapSetting, Loader, LoaderBuilder, McpClientConfig, MemoryGpuConfig,
-    MistralRsBuilder, ModelSelected, PagedAttentionConfig, PagedCacheType, SchedulerConfig,
-    SearchCallback, SearchEmbeddingModel, TokenSource,
+    DeviceMapMetadata, DeviceMapSetting, KvCacheQuantization, Loader, LoaderBuilder,
+    McpClientConfig, MemoryGpuConfig, MistralRsBuilder, ModelSelected, PagedAttentionConfig,
+    PagedCacheType, PreemptionMode, PrefixCacheDiskConfig, SchedulerConfig, SearchCallback,
+    SearchEmbeddingModel, TokenSource, ToolCallParser,
 };
-use tracing::{info, warn};
+use tracing::info;
 
 use crate::types::{LoadedPipeline, SharedMistralRsState};
 use std::collections::HashMap;
@@ -31,6 +32,8 @@ pub struct ModelConfig {
     pub num_device_layers: Option<Vec<String>>,
     /// Model-specific in-situ quantization
     pub in_situ_quant: Option<String>,
+    /// Model-specific tool call parser
+    pub tool_parser: Option<String>,
 }
 
 impl ModelConfig {
@@ -42,6 +45,7 @@ impl ModelConfig {
             jinja_explicit: None,
             num_device_layers: None,
             in_situ_quant: None,
+            tool_parser: None,
         }
     }
 
@@ -64,6 +68,11 @@ impl ModelConfig {
         self.in_situ_quant = Some(in_situ_quant);
         self
     }
+
+    pub fn with_tool_parser(mut self, tool_parser: String) -> Self {
+        self.tool_parser = Some(tool_parser);
+        self
+    }
 }
 
 pub mod defaults {
@@ -73,18 +82,22 @@ pub mod defaults {
 
     use std::sync::Arc;
 
-    use mistralrs_core::PagedCacheType;
+    use mistralrs_core::{KvCacheQuantization, PagedCacheType};
 
     pub const DEVICE: Option<candle_core::Device> = None;
     pub const SEED: Option<u64> = None;
     pub const LOG: Option<String> = None;
     pub const MODEL: Option<mistralrs_core::ModelSelected> = None;
     pub const MAX_SEQS: usize = 16;
+    pub const MAX_NUM_BATCHED_TOKENS: Option<usize> = None;
     pub const NO_KV_CACHE: bool = false;
     pub const CHAT_TEMPLATE: Option<String> = None;
     pub const JINJA_EXPLICIT: Option<String> = None;
     pub const INTERACTIVE_MODE: bool = false;
     pub const PREFIX_CACHE_N: usize = 16;
+    pub const PREFIX_CACHE_DISK: Option<PathBuf> = None;
+    pub const PREFIX_CACHE_DISK_SIZE: usize = 10240;
+    pub const KV_CACHE_QUANTIZATION: Option<KvCacheQuantization> = None;
     pub const NUM_DEVICE_LAYERS: Option<Vec<String>> = None;
     pub const IN_SITU_QUANT: Option<String> = None;
     pub const PAGED_ATTN_GPU_MEM: Option<usize> = None;
@@ -101,6 +114,9 @@ pub mod defaults {
     pub const TOKEN_SOURCE: mistralrs_core::TokenSource = mistralrs_core::TokenSource::CacheToken;
     pub const SEARCH_CALLBACK: Option<Arc<mistralrs_core::SearchCallback>> = None;
     pub const PAGED_CACHE_TYPE: PagedCacheType = PagedCacheType::Auto;
+    pub const PAGED_ATTN_SWAP_SPACE: Option<usize> = None;
+    pub const REQUEST_TIMEOUT: Option<std::time::Duration> = None;
+    pub const TOOL_PARSER: Option<String> = None;
 }
 
 /// A builder for creating a mistral.rs instance with configured options for the mistral.rs server.
@@ -158,6 +174,9 @@ pub struct MistralRsForServerBuilder {
     /// Maximum running sequences at any time. If the `tgt_non_granular_index` flag is set for X-LoRA models, this will be set to 1.
     max_seqs: usize,
 
+    /// Token budget of an engine step. If set, long prompts are prefilled in chunks between decode steps.
+    max_num_batched_tokens: Option<usize>,
+
     /// Use no KV cache.
     no_kv_cache: bool,
 
@@ -179,6 +198,15 @@ pub struct MistralRsForServerBuilder {
     /// Number of prefix caches to hold on the device. Other caches are evicted to the CPU based on a LRU strategy.
     prefix_cache_n: usize,
 
+    /// Directory to persist evicted prefix caches to, so they survive restarts.
+    prefix_cache_disk: Option<PathBuf>,
+
+    /// Size budget in MB for the on-disk prefix cache. Least recently used caches are removed beyond it.
+    prefix_cache_disk_size: usize,
+
+    /// Store the KV cache quantized when PagedAttention is not used.
+    kv_cache_quantization: Option<KvCacheQuantization>,
+
     /// NOTE: This can be omitted to use automatic device mapping!
     /// Number of device layers to load and run on GPU(s). All others will be on the CPU.
     /// If one GPU is used, then this value should be an integer. Otherwise, it follows the following pattern:
@@ -189,27 +217,27 @@ pub struct MistralRsForServerBuilder {
     in_situ_quant: Option<String>,
 
     /// GPU memory to allocate for KV cache with PagedAttention in MBs.
-    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
+    /// PagedAttention is supported on CUDA, Metal and CPU. It is automatically activated on CUDA but not on Metal or CPU.
     /// The priority is as follows: `pa-ctxt-len` > `pa-gpu-mem-usage` > `pa-gpu-mem`.
     paged_attn_gpu_mem: Option<usize>,
 
     /// Percentage of GPU memory to utilize after allocation of KV cache with PagedAttention, from 0 to 1.
     /// If this is not set and the device is CUDA, it will default to `0.9`.
-    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
+    /// PagedAttention is supported on CUDA, Metal and CPU. It is automatically activated on CUDA but not on Metal or CPU.
     /// The priority is as follows: `pa-ctxt-len` > `pa-gpu-mem-usage` > `pa-gpu-mem`.
     paged_attn_gpu_mem_usage: Option<f32>,
 
     /// Total context length to allocate the KV cache for (total number of tokens which the KV cache can hold).
-    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
+    /// PagedAttention is supported on CUDA, Metal and CPU. It is automatically activated on CUDA but not on Metal or CPU.
     /// The priority is as follows: `pa-ctxt-len` > `pa-gpu-mem-usage` > `pa-gpu-mem`.
     /// This is the default setting, and it defaults to the `max-seq-len` specified in after the model type.
     paged_ctxt_len: Option<usize>,
 
     /// Block size (number of tokens per block) for PagedAttention. If this is not set and the device is CUDA, it will default to 32.
-    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
+    /// PagedAttention is supported on CUDA, Metal and CPU. It is automatically activated on CUDA but not on Metal or CPU.
     paged_attn_block_size: Option<usize>,
 
-    /// Enables or disables PagedAttention. By default, PagedAttention will be enabled for CUDA and disabled for Metal (and is not supported for CPU). Use this to override the default behavior.
+    /// Enables or disables PagedAttention. By default, PagedAttention will be enabled for CUDA and disabled for Metal and CPU. Use this to override the default behavior.
     paged_attn: Option<bool>,
 
     /// Use CPU only
@@ -229,6 +257,15 @@ pub struct MistralRsForServerBuilder {
 
     /// PagedAttention KV cache type
     paged_cache_type: PagedCacheType,
+
+    /// Host memory in MB to swap out preempted PagedAttention sequences to, instead of recomputing them
+    paged_attn_swap_space: Option<usize>,
+
+    /// Default timeout for requests which do not specify their own.
+    request_timeout: Option<Duration>,
+
+    /// Name of the tool call parser, detected from the chat template if not set.
+    tool_parser: Option<String>,
 }
 
 impl Default for MistralRsForServerBuilder {
@@ -242,12 +279,16 @@ impl Default for MistralRsForServerBuilder {
             models: Vec::new(),
             default_model_id: None,
             max_seqs: defaults::MAX_SEQS,
+            max_num_batched_tokens: defaults::MAX_NUM_BATCHED_TOKENS,
             no_kv_cache: defaults::NO_KV_CACHE,
             chat_template: defaults::CHAT_TEMPLATE,
             jinja_explicit: defaults::JINJA_EXPLICIT,
             token_source: defaults::TOKEN_SOURCE,
             interactive_mode: defaults::INTERACTIVE_MODE,
             prefix_cache_n: defaults::PREFIX_CACHE_N,
+            prefix_cache_disk: defaults::PREFIX_CACHE_DISK,
+            prefix_cache_disk_size: defaults::PREFIX_CACHE_DISK_SIZE,
+            kv_cache_quantization: defaults::KV_CACHE_QUANTIZATION,
             num_device_layers: defaults::NUM_DEVICE_LAYERS,
             in_situ_quant: defaults::IN_SITU_QUANT,
             paged_attn_gpu_mem: defaults::PAGED_ATTN_GPU_MEM,
@@ -261,6 +302,9 @@ impl Default for MistralRsForServerBuilder {
             search_callback: defaults::SEARCH_CALLBACK,
             mcp_client_config: None,
             paged_cache_type: defaults::PAGED_CACHE_TYPE,
+            paged_attn_swap_space: defaults::PAGED_ATTN_SWAP_SPACE,
+            request_timeout: defaults::REQUEST_TIMEOUT,
+            tool_parser: defaults::TOOL_PARSER,
         }
     }
 }
@@ -357,6 +401,23 @@ impl MistralRsForServerBuilder {
         self
     }
 
+    /// Sets the token budget of an engine step, enabling chunked prefill.
+    pub fn with_max_num_batched_tokens(mut self, max_num_batched_tokens: usize) -> Self {
+        self.max_num_batched_tokens = Some(max_num_batched_tokens);
+        self
+    }
+
+    /// Sets the token budget of an engine step if provided.
+    pub fn with_max_num_batched_tokens_optional(
+        mut self,
+        max_num_batched_tokens: Option<usize>,
+    ) -> Self {
+        if let Some(max_num_batched_tokens) = max_num_batched_tokens {
+            self = self.with_max_num_batched_tokens(max_num_batched_tokens);
+        }
+        self
+    }
+
     /// Sets whether to disable the key-value cache.
     pub fn with_no_kv_cache(mut self, no_kv_cache: bool) -> Self {
         self.no_kv_cache = no_kv_cache;
@@ -409,6 +470,46 @@ impl MistralRsForServerBuilder {
         self
     }
 
+    /// Sets the directory to persist evicted prefix caches to.
+    pub fn with_prefix_cache_disk(mut self, prefix_cache_disk: impl Into<PathBuf>) -> Self {
+        self.prefix_cache_disk = Some(prefix_cache_disk.into());
+        self
+    }
+
+    /// Sets the directory to persist evicted prefix caches to if provided.
+    pub fn with_prefix_cache_disk_optional(mut self, prefix_cache_disk: Option<PathBuf>) -> Self {
+        if let Some(prefix_cache_disk) = prefix_cache_disk {
+            self = self.with_prefix_cache_disk(prefix_cache_disk);
+        }
+        self
+    }
+
+    /// Sets the size budget in MB for the on-disk prefix cache.
+    pub fn with_prefix_cache_disk_size(mut self, prefix_cache_disk_size: usize) -> Self {
+        self.prefix_cache_disk_size = prefix_cache_disk_size;
+        self
+    }
+
+    /// Sets the quantized storage format of the KV cache when PagedAttention is not used.
+    pub fn with_kv_cache_quantization(
+        mut self,
+        kv_cache_quantization: KvCacheQuantization,
+    ) -> Self {
+        self.kv_cache_quantization = Some(kv_cache_quantization);
+        self
+    }
+
+    /// Sets the quantized storage format of the KV cache if provided.
+    pub fn with_kv_cache_quantization_optional(
+        mut self,
+        kv_cache_quantization: Option<KvCacheQuantization>,
+    ) -> Self {
+        if let Some(kv_cache_quantization) = kv_cache_quantization {
+            self = self.with_kv_cache_quantization(kv_cache_quantization);
+        }
+        self
+    }
+
     /// Sets the device layer mapping
     pub fn with_num_device_layers(mut self, num_device_layers: Vec<String>) -> Self {
         self.num_device_layers = Some(num_device_layers);
@@ -511,6 +612,21 @@ impl MistralRsForServerBuilder {
         self
     }
 
+    /// Sets the host memory in MB which PagedAttention swaps preempted sequences out to.
+    /// Without it, preempted sequences are recomputed.
+    pub fn with_paged_attn_swap_space(mut self, swap_space_mb: usize) -> Self {
+        self.paged_attn_swap_space = Some(swap_space_mb);
+        self
+    }
+
+    /// Sets the host memory in MB which PagedAttention swaps preempted sequences out to if provided.
+    pub fn with_paged_attn_swap_space_optional(mut self, swap_space_mb: Option<usize>) -> Self {
+        if let Some(swap_space_mb) = swap_space_mb {
+            self = self.with_paged_attn_swap_space(swap_space_mb);
+        }
+        self
+    }
+
     /// Sets the block size for PagedAttention if provided.
     pub fn with_paged_attn_block_size_optional(
         mut self,
@@ -563,6 +679,34 @@ impl MistralRsForServerBuilder {
         self
     }
 
+    /// Sets the default timeout for requests which do not specify their own.
+    pub fn with_request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = Some(request_timeout);
+        self
+    }
+
+    /// Sets the default request timeout if provided.
+    pub fn with_request_timeout_optional(mut self, request_timeout: Option<Duration>) -> Self {
+        if let Some(request_timeout) = request_timeout {
+            self = self.with_request_timeout(request_timeout);
+        }
+        self
+    }
+
+    /// Sets the tool call parser by name, see [`mistralrs_core::tool_call_parser_names`].
+    pub fn with_tool_parser(mut self, tool_parser: String) -> Self {
+        self.tool_parser = Some(tool_parser);
+        self
+    }
+
+    /// Sets the tool call parser if provided.
+    pub fn with_tool_parser_optional(mut self, tool_parser: Option<String>) -> Self {
+        if let Some(tool_parser) = tool_parser {
+            self = self.with_tool_parser(tool_parser);
+        }
+        self
+    }
+
     /// Builds the configured mistral.rs instance.
     ///
     /// ### Examples
@@ -615,6 +759,7 @@ impl MistralRsForServerBuilder {
             self.paged_attn_gpu_mem_usage,
             self.paged_ctxt_len,
             self.paged_cache_type,
+            self.paged_attn_swap_space,
             !paged_attn,
             max_seq_len,
         )?;
@@ -645,7 +790,13 @@ impl MistralRsForServerBuilder {
         )?;
         info!("Model loaded.");
 
-        let scheduler_config = init_scheduler_config(&cache_config, &pipeline, self.max_seqs).await;
+        let scheduler_config = init_scheduler_config(
+            &cache_config,
+            &pipeline,
+            self.max_seqs,
+            self.max_num_batched_tokens,
+        )
+        .await;
 
         let search_embedding_model =
             get_search_embedding_model(self.enable_search, self.search_embedding_model);
@@ -660,11 +811,29 @@ impl MistralRsForServerBuilder {
         .with_no_kv_cache(self.no_kv_cache)
         .with_prefix_cache_n(self.prefix_cache_n);
 
+        if let Some(prefix_cache_disk) =
+            init_prefix_cache_disk(&self.prefix_cache_disk, self.prefix_cache_disk_size)
+        {
+            builder = builder.with_prefix_cache_disk(prefix_cache_disk);
+        }
+
+        if let Some(kv_cache_quantization) = self.kv_cache_quantization {
+            builder = builder.with_kv_cache_quantization(kv_cache_quantization);
+        }
+
         // Add MCP client configuration if provided
         if let Some(mcp_config) = self.mcp_client_config {
             builder = builder.with_mcp_client(mcp_config);
         }
 
+        if let Some(request_timeout) = self.request_timeout {
+            builder = builder.with_default_request_timeout(request_timeout);
+        }
+
+        if let Some(tool_parser) = init_tool_parser(self.tool_parser.as_deref())? {
+            builder = builder.with_tool_parser(tool_parser);
+        }
+
         let mistralrs = builder.build().await;
 
         Ok(mistralrs)
@@ -730,6 +899,7 @@ impl MistralRsForServerBuilder {
             self.paged_attn_gpu_mem_usage,
             self.paged_ctxt_len,
             self.paged_cache_type,
+            self.paged_attn_swap_space,
             !paged_attn,
             max_seq_len,
         )?;
@@ -759,7 +929,13 @@ impl MistralRsForServerBuilder {
         );
         pipeline_names.push(first_pipeline_name);
 
-        let scheduler_config = init_scheduler_config(&cache_config, &pipeline, self.max_seqs).await;
+        let scheduler_config = init_scheduler_config(
+            &cache_config,
+            &pipeline,
+            self.max_seqs,
+            self.max_num_batched_tokens,
+        )
+        .await;
         let search_embedding_model =
             get_search_embedding_model(self.enable_search, self.search_embedding_model);
 
@@ -774,11 +950,34 @@ impl MistralRsForServerBuilder {
         .with_no_kv_cache(self.no_kv_cache)
         .with_prefix_cache_n(self.prefix_cache_n);
 
+        if let Some(prefix_cache_disk) =
+            init_prefix_cache_disk(&self.prefix_cache_disk, self.prefix_cache_disk_size)
+        {
+            builder = builder.with_prefix_cache_disk(prefix_cache_disk);
+        }
+
+        if let Some(kv_cache_quantization) = self.kv_cache_quantization {
+            builder = builder.with_kv_cache_quantization(kv_cache_quantization);
+        }
+
         // Add MCP client configuration if provided
         if let Some(mcp_config) = self.mcp_client_config.clone() {
             builder = builder.with_mcp_client(mcp_config);
         }
 
+        if let Some(request_timeout) = self.request_timeout {
+            builder = builder.with_default_request_timeout(request_timeout);
+        }
+
+        if let Some(tool_parser) = init_tool_parser(
+            first_model
+                .tool_parser
+                .as_deref()
+                .or(self.tool_parser.as_deref()),
+        )? {
+            builder = builder.with_tool_parser(tool_parser);
+        }
+
         let mistralrs = builder.build().await;
 
         // Load additional models
@@ -850,12 +1049,25 @@ impl MistralRsForServerBuilder {
                 no_kv_cache: self.no_kv_cache,
                 no_prefix_cache: false,
                 prefix_cache_n: self.prefix_cache_n,
+                prefix_cache_disk: init_prefix_cache_disk(
+                    &self.prefix_cache_disk,
+                    self.prefix_cache_disk_size,
+                ),
+                kv_cache_quantization: self.kv_cache_quantization,
                 disable_eos_stop: false,
                 throughput_logging_enabled: !self.interactive_mode,
                 search_embedding_model,
                 search_callback: self.search_callback.clone(),
                 tool_callbacks: HashMap::new(),
                 tool_callbacks_with_tools: HashMap::new(),
+                tool_parser: init_tool_parser(
+                    model_config
+                        .tool_parser
+                        .as_deref()
+                        .or(self.tool_parser.as_deref()),
+                )?,
+                // Share the MCP servers connected for the first model
+                mcp_client: mistralrs.mcp_client(None).ok().flatten(),
             };
 
             let mut add_model_config = mistralrs_core::AddModelConfig::new(engine_config);
@@ -988,11 +1200,7 @@ fn mistralrs_instance_info(loader: &dyn Loader) {
 /// Determines whether paged attention should be enabled based on device type and preferences.
 fn configure_paged_attn(device: &Device, paged_attn: Option<bool>) -> bool {
     if device.is_cpu() {
-        if paged_attn == Some(true) {
-            warn!("Paged attention is not supported on CPU.");
-        }
-
-        defaults::PAGED_ATTN_CPU
+        paged_attn.unwrap_or(defaults::PAGED_ATTN_CPU)
     } else if device.is_cuda() || mistralrs_core::distributed::use_nccl() {
         paged_attn.unwrap_or(defaults::PAGED_ATTN_CUDA)
     } else if device.is_metal() {
@@ -1009,10 +1217,15 @@ fn init_cache_config(
     paged_attn_gpu_mem_usage: Option<f32>,
     paged_ctxt_len: Option<usize>,
     cache_type: PagedCacheType,
+    swap_space: Option<usize>,
     no_paged_attn: bool,
     max_seq_len: usize,
 ) -> Result<Option<PagedAttentionConfig>> {
-    match (
+    let preemption_mode = match swap_space {
+        Some(swap_space_mb) => PreemptionMode::Swap { swap_space_mb },
+        None => PreemptionMode::Recompute,
+    };
+    let config = match (
         paged_attn_block_size,
         paged_attn_gpu_mem,
         paged_attn_gpu_mem_usage,
@@ -1065,7 +1278,8 @@ fn init_cache_config(
             )?))
         }
         (_, _, _, _, _, _) => Ok(None),
-    }
+    }?;
+    Ok(config.map(|config| config.with_preemption_mode(preemption_mode)))
 }
 
 /// Initializes the scheduler configuration based on cache settings and pipeline metadata.
@@ -1073,6 +1287,7 @@ async fn init_scheduler_config(
     cache_config: &Option<PagedAttentionConfig>,
     pipeline: &LoadedPipeline,
     args_max_seqs: usize,
+    max_num_batched_tokens: Option<usize>,
 ) -> SchedulerConfig {
     if cache_config.is_some() {
         // Handle case where we may have device mapping
@@ -1080,15 +1295,18 @@ async fn init_scheduler_config(
             SchedulerConfig::PagedAttentionMeta {
                 max_num_seqs: args_max_seqs,
                 config: cache_config.clone(),
+                max_num_batched_tokens,
             }
         } else {
             SchedulerConfig::DefaultScheduler {
                 method: DefaultSchedulerMethod::Fixed(args_max_seqs.try_into().unwrap()),
+                max_num_batched_tokens,
             }
         }
     } else {
         SchedulerConfig::DefaultScheduler {
             method: DefaultSchedulerMethod::Fixed(args_max_seqs.try_into().unwrap()),
+            max_num_batched_tokens,
         }
     }
 }
@@ -1122,3 +1340,25 @@ pub fn get_search_embedding_model(
         None
     }
 }
+
+/// Resolves the tool call parser with the given name.
+fn init_tool_parser(name: Option<&str>) -> Result<Option<Arc<dyn ToolCallParser>>> {
+    name.map(|name| {
+        mistralrs_core::tool_call_parser(name).with_context(|| {
+            format!(
+                "Unknown tool parser `{name}`, expected one of: {}.",
+                mistralrs_core::tool_call_parser_names().join(", ")
+            )
+        })
+    })
+    .transpose()
+}
+
+/// Builds the on-disk prefix cache configuration, if a directory was provided.
+fn init_prefix_cache_disk(
+    path: &Option<PathBuf>,
+    max_size_mb: usize,
+) -> Option<PrefixCacheDiskConfig> {
+    path.as_ref()
+        .map(|path| PrefixCacheDiskConfig::new(path, max_size_mb))
+}
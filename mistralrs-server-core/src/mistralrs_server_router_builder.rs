@@ -1,9 +1,12 @@
 //! ## mistral.rs server router builder.
 
+use std::sync::Arc;
+
 use anyhow::Result;
 use axum::{
     extract::DefaultBodyLimit,
     http::{self, Method},
+    middleware,
     routing::{get, post},
     Router,
 };
@@ -11,14 +14,19 @@ use tower_http::cors::{AllowOrigin, CorsLayer};
 use utoipa_swagger_ui::SwaggerUi;
 
 use crate::{
+    auth::{authenticate, usage, ApiKeyAuth},
     chat_completion::chatcompletions,
     completions::completions,
     embeddings::embeddings,
-    handlers::{health, models, re_isq},
+    handlers::{health, models, re_isq, stats},
     image_generation::image_generation,
+    mcp::{mcp_get_prompt, mcp_prompts, mcp_read_resource, mcp_resource_templates, mcp_resources},
+    messages::messages,
+    metrics::{metrics, track_requests},
     openapi_doc::get_openapi_doc,
     responses::{cancel_response, create_response, delete_response, get_response},
     speech_generation::speech_generation,
+    telemetry::trace_requests,
     types::SharedMistralRsState,
 };
 
@@ -65,6 +73,8 @@ pub struct MistralRsServerRouterBuilder {
     allowed_origins: Option<Vec<String>>,
     /// Optional axum default request body limit
     max_body_limit: Option<usize>,
+    /// Optional API keys required to access the API
+    api_keys: Option<ApiKeyAuth>,
 }
 
 impl Default for MistralRsServerRouterBuilder {
@@ -76,6 +86,7 @@ impl Default for MistralRsServerRouterBuilder {
             base_path: None,
             allowed_origins: None,
             max_body_limit: None,
+            api_keys: None,
         }
     }
 }
@@ -133,6 +144,18 @@ impl MistralRsServerRouterBuilder {
         self
     }
 
+    /// Requires an API key for all routes except the health checks, with optional per-key rate limits.
+    pub fn with_api_keys(mut self, api_keys: ApiKeyAuth) -> Self {
+        self.api_keys = Some(api_keys);
+        self
+    }
+
+    /// Requires an API key if `api_keys` is set. See `with_api_keys`.
+    pub fn with_api_keys_optional(mut self, api_keys: Option<ApiKeyAuth>) -> Self {
+        self.api_keys = api_keys;
+        self
+    }
+
     /// Builds the configured axum router.
     ///
     /// ### Examples
@@ -156,6 +179,7 @@ impl MistralRsServerRouterBuilder {
             self.base_path.as_deref(),
             self.allowed_origins,
             self.max_body_limit,
+            self.api_keys,
         );
 
         mistralrs_server_router
@@ -172,6 +196,7 @@ fn init_router(
     base_path: Option<&str>,
     allowed_origins: Option<Vec<String>>,
     max_body_limit: Option<usize>,
+    api_keys: Option<ApiKeyAuth>,
 ) -> Result<Router> {
     let allow_origin = if let Some(origins) = allowed_origins {
         let parsed_origins: Result<Vec<_>, _> = origins.into_iter().map(|o| o.parse()).collect();
@@ -188,7 +213,12 @@ fn init_router(
 
     let cors_layer = CorsLayer::new()
         .allow_methods([Method::GET, Method::POST])
-        .allow_headers([http::header::CONTENT_TYPE, http::header::AUTHORIZATION])
+        .allow_headers([
+            http::header::CONTENT_TYPE,
+            http::header::AUTHORIZATION,
+            http::HeaderName::from_static("x-api-key"),
+            http::HeaderName::from_static("anthropic-version"),
+        ])
         .allow_origin(allow_origin);
 
     // Use the provided base path or default to ""
@@ -197,10 +227,11 @@ fn init_router(
     let mut router = Router::new()
         .route("/v1/chat/completions", post(chatcompletions))
         .route("/v1/completions", post(completions))
+        .route("/v1/messages", post(messages))
         .route("/v1/embeddings", post(embeddings))
         .route("/v1/models", get(models))
-        .route("/health", get(health))
-        .route("/", get(health))
+        .route("/v1/stats", get(stats))
+        .route("/v1/usage", get(usage))
         .route("/re_isq", post(re_isq))
         .route("/v1/images/generations", post(image_generation))
         .route("/v1/audio/speech", post(speech_generation))
@@ -210,6 +241,31 @@ fn init_router(
             get(get_response).delete(delete_response),
         )
         .route("/v1/responses/{response_id}/cancel", post(cancel_response))
+        .route("/v1/mcp/prompts", get(mcp_prompts))
+        .route("/v1/mcp/prompts/get", post(mcp_get_prompt))
+        .route("/v1/mcp/resources", get(mcp_resources))
+        .route("/v1/mcp/resources/read", post(mcp_read_resource))
+        .route("/v1/mcp/resource_templates", get(mcp_resource_templates))
+        .route("/metrics", get(metrics));
+
+    // Inside the authentication layer, so that rejected requests are not read or counted.
+    router = router.route_layer(middleware::from_fn_with_state(
+        (state.clone(), router_max_body_limit),
+        track_requests,
+    ));
+
+    // Routes added after the authentication layer, such as the health checks, don't require a key.
+    if let Some(api_keys) = api_keys {
+        router = router.route_layer(middleware::from_fn_with_state(
+            Arc::new(api_keys),
+            authenticate,
+        ));
+    }
+
+    let mut router = router
+        .route("/health", get(health))
+        .route("/", get(health))
+        .route_layer(middleware::from_fn(trace_requests))
         .layer(cors_layer)
         .layer(DefaultBodyLimit::max(router_max_body_limit))
         .with_state(state);
@@ -4,7 +4,8 @@ use std::{collections::HashMap, ops::Deref};
 
 use either::Either;
 use mistralrs_core::{
-    ImageGenerationResponseFormat, LlguidanceGrammar, Tool, ToolChoice, ToolType, WebSearchOptions,
+    ImageGenerationResponseFormat, LlguidanceGrammar, RequestPriority, SamplerKind, Tool,
+    ToolChoice, ToolType, WebSearchOptions,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
@@ -569,6 +570,8 @@ pub struct ChatCompletionRequest {
     pub temperature: Option<f64>,
     #[schema(example = json!(Option::None::<f64>))]
     pub top_p: Option<f64>,
+    #[schema(example = json!(Option::None::<u64>))]
+    pub seed: Option<u64>,
     #[schema(example = true)]
     pub stream: Option<bool>,
     #[schema(example = json!(Option::None::<Vec<Tool>>))]
@@ -595,6 +598,36 @@ pub struct ChatCompletionRequest {
     pub dry_allowed_length: Option<usize>,
     #[schema(example = json!(Option::None::<String>))]
     pub dry_sequence_breakers: Option<Vec<String>>,
+    /// Decode with beam search using this many beams instead of sampling.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub num_beams: Option<usize>,
+    /// Length penalty exponent for beam search scores. Defaults to 1.0.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub length_penalty: Option<f32>,
+    /// Stop beam search once `n` hypotheses have finished. Defaults to false.
+    #[schema(example = json!(Option::None::<bool>))]
+    pub early_stopping: Option<bool>,
+    /// Locally typical sampling mass, in (0, 1).
+    #[schema(example = json!(Option::None::<f64>))]
+    pub typical_p: Option<f64>,
+    /// Top-a sampling: drop tokens with a probability below `top_a * max_prob^2`.
+    #[schema(example = json!(Option::None::<f64>))]
+    pub top_a: Option<f64>,
+    /// Chance of applying XTC (exclude top choices) at each step. Setting this enables XTC.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub xtc_probability: Option<f32>,
+    /// XTC probability threshold. Defaults to 0.1.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub xtc_threshold: Option<f32>,
+    /// Mirostat v2 target surprise. Setting this enables mirostat v2.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_tau: Option<f32>,
+    /// Mirostat v2 learning rate. Defaults to 0.1.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_eta: Option<f32>,
+    /// Explicit sampler order. Samplers which are not listed are not applied.
+    #[schema(example = json!(Option::None::<Vec<SamplerKind>>))]
+    pub sampler_order: Option<Vec<SamplerKind>>,
     #[schema(example = json!(Option::None::<bool>))]
     pub enable_thinking: Option<bool>,
     /// Reasoning effort level for Harmony-format models (GPT-OSS).
@@ -604,6 +637,15 @@ pub struct ChatCompletionRequest {
     #[schema(example = json!(Option::None::<bool>))]
     #[serde(default)]
     pub truncate_sequence: Option<bool>,
+    /// Scheduling priority class: "low", "normal" (default), or "high".
+    #[schema(example = json!(Option::None::<RequestPriority>))]
+    pub priority: Option<RequestPriority>,
+    /// Give up on the request after this many seconds, finishing with the "timeout" finish reason.
+    #[schema(example = json!(Option::None::<f64>))]
+    pub timeout: Option<f64>,
+    /// Return the logprob of every prompt token after the first, with this many top alternatives.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub prompt_logprobs: Option<usize>,
 }
 
 /// Function for ChatCompletionRequest.messages Schema generation to handle `Either`
@@ -688,6 +730,8 @@ pub struct CompletionRequest {
     pub temperature: Option<f64>,
     #[schema(example = json!(Option::None::<f64>))]
     pub top_p: Option<f64>,
+    #[schema(example = json!(Option::None::<u64>))]
+    pub seed: Option<u64>,
     #[schema(example = json!(Option::None::<String>))]
     pub suffix: Option<String>,
     #[serde(rename = "user")]
@@ -714,9 +758,48 @@ pub struct CompletionRequest {
     pub dry_allowed_length: Option<usize>,
     #[schema(example = json!(Option::None::<String>))]
     pub dry_sequence_breakers: Option<Vec<String>>,
+    /// Decode with beam search using this many beams instead of sampling.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub num_beams: Option<usize>,
+    /// Length penalty exponent for beam search scores. Defaults to 1.0.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub length_penalty: Option<f32>,
+    /// Stop beam search once `n` hypotheses have finished. Defaults to false.
+    #[schema(example = json!(Option::None::<bool>))]
+    pub early_stopping: Option<bool>,
+    /// Locally typical sampling mass, in (0, 1).
+    #[schema(example = json!(Option::None::<f64>))]
+    pub typical_p: Option<f64>,
+    /// Top-a sampling: drop tokens with a probability below `top_a * max_prob^2`.
+    #[schema(example = json!(Option::None::<f64>))]
+    pub top_a: Option<f64>,
+    /// Chance of applying XTC (exclude top choices) at each step. Setting this enables XTC.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub xtc_probability: Option<f32>,
+    /// XTC probability threshold. Defaults to 0.1.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub xtc_threshold: Option<f32>,
+    /// Mirostat v2 target surprise. Setting this enables mirostat v2.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_tau: Option<f32>,
+    /// Mirostat v2 learning rate. Defaults to 0.1.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_eta: Option<f32>,
+    /// Explicit sampler order. Samplers which are not listed are not applied.
+    #[schema(example = json!(Option::None::<Vec<SamplerKind>>))]
+    pub sampler_order: Option<Vec<SamplerKind>>,
     #[schema(example = json!(Option::None::<bool>))]
     #[serde(default)]
     pub truncate_sequence: Option<bool>,
+    /// Scheduling priority class: "low", "normal" (default), or "high".
+    #[schema(example = json!(Option::None::<RequestPriority>))]
+    pub priority: Option<RequestPriority>,
+    /// Give up on the request after this many seconds, finishing with the "timeout" finish reason.
+    #[schema(example = json!(Option::None::<f64>))]
+    pub timeout: Option<f64>,
+    /// Return the logprob of every prompt token after the first, with this many top alternatives.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub prompt_logprobs: Option<usize>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -819,6 +902,10 @@ pub struct EmbeddingRequest {
     #[schema(example = json!(Option::None::<bool>))]
     #[serde(default)]
     pub truncate_sequence: Option<bool>,
+    /// Give up on the request after this many seconds, see [`ChatCompletionRequest::timeout`].
+    #[schema(example = json!(Option::None::<f64>))]
+    #[serde(default)]
+    pub timeout: Option<f64>,
 }
 
 #[derive(Debug, Clone, Serialize, ToSchema)]
@@ -909,6 +996,12 @@ pub struct ImageGenerationRequest {
     #[serde(default = "default_1280usize")]
     #[schema(example = 1280)]
     pub width: usize,
+
+    // mistral.rs additional
+    /// Give up on the request after this many seconds, see [`ChatCompletionRequest::timeout`].
+    #[schema(example = json!(Option::None::<f64>))]
+    #[serde(default)]
+    pub timeout: Option<f64>,
 }
 
 /// Audio format options for speech generation responses.
@@ -967,6 +1060,12 @@ pub struct SpeechGenerationRequest {
     /// The desired audio format for the generated speech.
     #[schema(example = "mp3")]
     pub response_format: AudioResponseFormat,
+
+    // mistral.rs additional
+    /// Give up on the request after this many seconds, see [`ChatCompletionRequest::timeout`].
+    #[schema(example = json!(Option::None::<f64>))]
+    #[serde(default)]
+    pub timeout: Option<f64>,
 }
 
 /// Helper type for messages field in ResponsesCreateRequest
@@ -1044,6 +1143,8 @@ pub struct ResponsesCreateRequest {
     pub temperature: Option<f64>,
     #[schema(example = json!(Option::None::<f64>))]
     pub top_p: Option<f64>,
+    #[schema(example = json!(Option::None::<u64>))]
+    pub seed: Option<u64>,
     #[schema(example = false)]
     pub stream: Option<bool>,
     #[schema(example = json!(Option::None::<Vec<Tool>>))]
@@ -1099,6 +1200,12 @@ pub struct ResponsesCreateRequest {
     /// Valid values: "low", "medium", "high"
     #[schema(example = json!(Option::None::<String>))]
     pub reasoning_effort: Option<String>,
+    /// Scheduling priority class: "low", "normal" (default), or "high".
+    #[schema(example = json!(Option::None::<RequestPriority>))]
+    pub priority: Option<RequestPriority>,
+    /// Give up on the request after this many seconds, finishing with the "timeout" finish reason.
+    #[schema(example = json!(Option::None::<f64>))]
+    pub timeout: Option<f64>,
 }
 
 /// Response object
@@ -3,11 +3,28 @@
 use utoipa::OpenApi;
 
 use crate::{
+    auth::{ApiKeyUsage, __path_usage},
     chat_completion::__path_chatcompletions,
     completions::__path_completions,
     embeddings::__path_embeddings,
-    handlers::{ReIsqRequest, __path_health, __path_models, __path_re_isq},
+    handlers::{
+        ModelStats, ModelStatsList, ReIsqRequest, __path_health, __path_models, __path_re_isq,
+        __path_stats,
+    },
     image_generation::__path_image_generation,
+    mcp::{
+        McpGetPromptRequest, McpPromptArgumentObject, McpPromptList, McpPromptMessages,
+        McpPromptObject, McpReadResourceRequest, McpResourceContents, McpResourceList,
+        McpResourceObject, McpResourceTemplateList, McpResourceTemplateObject,
+        __path_mcp_get_prompt, __path_mcp_prompts, __path_mcp_read_resource,
+        __path_mcp_resource_templates, __path_mcp_resources,
+    },
+    messages::{
+        ContentBlock, ImageSource, MessageParam, MessagesContent, MessagesRequest,
+        MessagesResponse, MessagesTool, MessagesToolChoice, MessagesUsage, ThinkingConfig,
+        __path_messages,
+    },
+    metrics::__path_metrics,
     openai::{
         AudioResponseFormat, ChatCompletionRequest, CompletionRequest, EmbeddingData,
         EmbeddingEncodingFormat, EmbeddingInput, EmbeddingRequest, EmbeddingResponse,
@@ -23,8 +40,9 @@ use crate::{
     speech_generation::__path_speech_generation,
 };
 use mistralrs_core::{
-    ApproximateUserLocation, Function, ImageGenerationResponseFormat, SearchContextSize, Tool,
-    ToolChoice, ToolType, WebSearchOptions, WebSearchUserLocation,
+    ApproximateUserLocation, EngineStats, Function, ImageGenerationResponseFormat,
+    LatencyHistogram, RequestPriority, SamplerKind, SearchContextSize, Tool, ToolChoice, ToolType,
+    WebSearchOptions, WebSearchUserLocation,
 };
 
 /// This is used to generate the OpenAPI docs.
@@ -69,12 +87,14 @@ use mistralrs_core::{
 pub fn get_openapi_doc(base_path: Option<&str>) -> utoipa::openapi::OpenApi {
     #[derive(OpenApi)]
     #[openapi(
-        paths(models, health, chatcompletions, completions, embeddings, re_isq, image_generation, speech_generation, create_response, get_response, delete_response),
+        paths(models, stats, usage, metrics, health, chatcompletions, completions, messages, embeddings, re_isq, image_generation, speech_generation, create_response, get_response, delete_response, mcp_prompts, mcp_get_prompt, mcp_resources, mcp_read_resource, mcp_resource_templates),
         components(schemas(
+            ApiKeyUsage,
             ApproximateUserLocation,
             AudioResponseFormat,
             ChatCompletionRequest,
             CompletionRequest,
+            ContentBlock,
             EmbeddingData,
             EmbeddingEncodingFormat,
             EmbeddingInput,
@@ -82,18 +102,42 @@ pub fn get_openapi_doc(base_path: Option<&str>) -> utoipa::openapi::OpenApi {
             EmbeddingResponse,
             EmbeddingUsage,
             EmbeddingVector,
+            EngineStats,
             Function,
             FunctionCalled,
             Grammar,
             ImageGenerationRequest,
             ImageGenerationResponseFormat,
+            ImageSource,
             JsonSchemaResponseFormat,
+            LatencyHistogram,
+            McpGetPromptRequest,
+            McpPromptArgumentObject,
+            McpPromptList,
+            McpPromptMessages,
+            McpPromptObject,
+            McpReadResourceRequest,
+            McpResourceContents,
+            McpResourceList,
+            McpResourceObject,
+            McpResourceTemplateList,
+            McpResourceTemplateObject,
             Message,
             MessageContent,
             MessageInnerContent,
+            MessageParam,
+            MessagesContent,
+            MessagesRequest,
+            MessagesResponse,
+            MessagesTool,
+            MessagesToolChoice,
+            MessagesUsage,
             ModelObject,
             ModelObjects,
+            ModelStats,
+            ModelStatsList,
             ReIsqRequest,
+            RequestPriority,
             ResponseFormat,
             ResponsesAnnotation,
             ResponsesChunk,
@@ -110,9 +154,11 @@ pub fn get_openapi_doc(base_path: Option<&str>) -> utoipa::openapi::OpenApi {
             ResponsesOutput,
             ResponsesOutputTokensDetails,
             ResponsesUsage,
+            SamplerKind,
             SearchContextSize,
             SpeechGenerationRequest,
             StopTokens,
+            ThinkingConfig,
             Tool,
             ToolCall,
             ToolChoice,
@@ -13,14 +13,14 @@ use std::{
 use anyhow::Result;
 use axum::{
     extract::{Json, Path, State},
-    http::{self, StatusCode},
+    http::{self, HeaderMap, StatusCode},
     response::{
         sse::{Event, KeepAlive, KeepAliveStream},
         IntoResponse, Sse,
     },
 };
 use either::Either;
-use mistralrs_core::{ChatCompletionResponse, MistralRs, Request, Response};
+use mistralrs_core::{ChatCompletionResponse, MistralRs, NormalRequest, Request, Response};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use tokio::sync::mpsc::{Receiver, Sender};
@@ -33,8 +33,8 @@ use uuid::Uuid;
 use crate::{
     background_tasks::get_background_task_manager,
     cached_responses::get_response_cache,
-    chat_completion::parse_request as parse_chat_request,
-    completion_core::{handle_completion_error, BaseCompletionResponder},
+    chat_completion::parse_normal_request as parse_chat_request,
+    completion_core::{apply_header_timeout, handle_completion_error, BaseCompletionResponder},
     handler_core::{
         create_response_channel, send_request_with_model, BaseJsonModelError, ErrorToResponse,
         JsonError, ModelErrorMessage,
@@ -596,6 +596,10 @@ pub struct OpenResponsesCreateRequest {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub min_p: Option<f64>,
 
+    /// Seed for reproducible sampling (mistral.rs extension)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+
     /// DRY multiplier (mistral.rs extension)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub dry_multiplier: Option<f32>,
@@ -615,6 +619,14 @@ pub struct OpenResponsesCreateRequest {
     /// Web search options (mistral.rs extension)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub web_search_options: Option<mistralrs_core::WebSearchOptions>,
+
+    /// Scheduling priority class (mistral.rs extension)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<mistralrs_core::RequestPriority>,
+
+    /// Request timeout in seconds (mistral.rs extension)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<f64>,
 }
 
 fn default_model() -> String {
@@ -739,6 +751,8 @@ pub struct OpenResponsesStreamer {
     accumulated_text: String,
     /// Accumulated reasoning for the current output
     accumulated_reasoning: String,
+    /// Ids of the streamed tool calls by index
+    tool_call_ids: HashMap<usize, String>,
     /// Whether content part has been added
     content_part_added: bool,
     /// Whether output item has been added
@@ -753,6 +767,10 @@ pub struct OpenResponsesStreamer {
     events: Vec<OpenResponsesStreamEvent>,
     /// Request context for echoing back request parameters
     request_context: RequestContext,
+    /// ID of the request being streamed, canceled if the client disconnects before completion
+    request_id: Option<usize>,
+    /// Model the request was sent to
+    model_id: Option<String>,
 }
 
 impl OpenResponsesStreamer {
@@ -782,6 +800,7 @@ impl OpenResponsesStreamer {
             pending_events: Vec::new(),
             accumulated_text: String::new(),
             accumulated_reasoning: String::new(),
+            tool_call_ids: HashMap::new(),
             content_part_added: false,
             output_item_added: false,
             store,
@@ -789,9 +808,19 @@ impl OpenResponsesStreamer {
             on_done: None,
             events: Vec::new(),
             request_context,
+            request_id: None,
+            model_id: None,
         }
     }
 
+    /// Cancel the request in the engine if the stream is dropped before it completes,
+    /// which happens when the client disconnects.
+    pub fn cancel_on_disconnect(mut self, request_id: usize, model_id: Option<String>) -> Self {
+        self.request_id = Some(request_id);
+        self.model_id = model_id;
+        self
+    }
+
     /// Build initial response resource
     fn build_response_resource(&self, status: ResponseStatus) -> ResponseResource {
         let mut resource = ResponseResource::new(
@@ -1042,13 +1071,26 @@ impl futures::Stream for OpenResponsesStreamer {
                         // Handle tool calls
                         if let Some(tool_calls) = &choice.delta.tool_calls {
                             for tool_call in tool_calls {
+                                // Only the first fragment of a call has its id
+                                if let Some(id) = &tool_call.id {
+                                    self.tool_call_ids.insert(tool_call.index, id.clone());
+                                }
+                                let Some(call_id) =
+                                    self.tool_call_ids.get(&tool_call.index).cloned()
+                                else {
+                                    continue;
+                                };
+                                if tool_call.function.arguments.is_empty() {
+                                    continue;
+                                }
+
                                 // Emit function call arguments delta
                                 let seq = self.streaming_state.next_sequence_number();
                                 events_to_emit.push(
                                     OpenResponsesStreamEvent::FunctionCallArgumentsDelta {
                                         sequence_number: seq,
                                         output_index: 0,
-                                        call_id: tool_call.id.clone(),
+                                        call_id,
                                         delta: tool_call.function.arguments.clone(),
                                     },
                                 );
@@ -1157,6 +1199,22 @@ impl futures::Stream for OpenResponsesStreamer {
     }
 }
 
+impl Drop for OpenResponsesStreamer {
+    fn drop(&mut self) {
+        if !matches!(self.done_state, DoneState::Running) {
+            return;
+        }
+        if let Some(request_id) = self.request_id {
+            if let Err(e) = self
+                .state
+                .cancel_request(request_id, self.model_id.as_deref())
+            {
+                tracing::warn!("Failed to cancel request {request_id} after disconnect: {e}");
+            }
+        }
+    }
+}
+
 /// Get the event type string for an event
 fn get_event_type(event: &OpenResponsesStreamEvent) -> &'static str {
     match event {
@@ -1307,7 +1365,7 @@ async fn parse_openresponses_request(
     state: SharedMistralRsState,
     tx: Sender<Response>,
 ) -> Result<(
-    Request,
+    Box<NormalRequest>,
     bool,
     Option<Vec<Message>>,
     IncludeConfig,
@@ -1458,6 +1516,7 @@ async fn parse_openresponses_request(
         stop_seqs: oairequest.stop_seqs,
         temperature: oairequest.temperature,
         top_p: oairequest.top_p,
+        seed: oairequest.seed,
         stream: oairequest.stream,
         tools: oairequest.tools,
         tool_choice: oairequest.tool_choice,
@@ -1470,9 +1529,22 @@ async fn parse_openresponses_request(
         dry_base: oairequest.dry_base,
         dry_allowed_length: oairequest.dry_allowed_length,
         dry_sequence_breakers: oairequest.dry_sequence_breakers,
+        num_beams: None,
+        length_penalty: None,
+        early_stopping: None,
+        typical_p: None,
+        top_a: None,
+        xtc_probability: None,
+        xtc_threshold: None,
+        mirostat_tau: None,
+        mirostat_eta: None,
+        sampler_order: None,
         enable_thinking,
         truncate_sequence,
         reasoning_effort,
+        priority: oairequest.priority,
+        timeout: oairequest.timeout,
+        prompt_logprobs: None,
     };
 
     let (request, is_streaming) = parse_chat_request(chat_request, state, tx).await?;
@@ -1495,9 +1567,14 @@ async fn parse_openresponses_request(
 )]
 pub async fn create_response(
     State(state): ExtractedMistralRsState,
-    Json(oairequest): Json<OpenResponsesCreateRequest>,
+    headers: HeaderMap,
+    Json(mut oairequest): Json<OpenResponsesCreateRequest>,
 ) -> OpenResponsesResponder {
     let (tx, rx) = create_response_channel(None);
+
+    if let Err(e) = apply_header_timeout(&headers, &mut oairequest.timeout) {
+        return handle_error(state, e.into());
+    }
     let request_id = format!("resp_{}", Uuid::new_v4());
     let metadata = oairequest.metadata.clone();
     let store = oairequest.store.unwrap_or(true);
@@ -1550,7 +1627,8 @@ pub async fn create_response(
             task_manager.mark_in_progress(&task_id);
 
             if let Err(e) =
-                send_request_with_model(&state_clone, request, model_id.as_deref()).await
+                send_request_with_model(&state_clone, Request::Normal(request), model_id.as_deref())
+                    .await
             {
                 task_manager.mark_failed(&task_id, ResponseError::new("send_error", e.to_string()));
                 return;
@@ -1623,11 +1701,15 @@ pub async fn create_response(
             Err(e) => return handle_error(state, e.into()),
         };
 
-    if let Err(e) = send_request_with_model(&state, request, model_id.as_deref()).await {
+    let engine_request_id = request.id;
+    if let Err(e) =
+        send_request_with_model(&state, Request::Normal(request), model_id.as_deref()).await
+    {
         return handle_error(state, e.into());
     }
 
     if is_streaming {
+        // Stop generating if the client goes away mid-stream.
         let streamer = OpenResponsesStreamer::new(
             rx,
             state.clone(),
@@ -1637,7 +1719,8 @@ pub async fn create_response(
             store,
             conversation_history,
             request_context,
-        );
+        )
+        .cancel_on_disconnect(engine_request_id, model_id);
 
         let keep_alive_interval = get_keep_alive_interval();
         let sse = Sse::new(streamer)
@@ -11,11 +11,13 @@ use axum::{
 };
 use mistralrs_core::{
     speech_utils::{self, Sample},
-    Constraint, MistralRs, NormalRequest, Request, RequestMessage, Response, SamplingParams,
+    Constraint, MistralRs, NormalRequest, Request, RequestMessage, RequestPriority, Response,
+    SamplingParams,
 };
 use tokio::sync::mpsc::{Receiver, Sender};
 
 use crate::{
+    completion_core::{apply_header_timeout, get_request_timeout},
     handler_core::{create_response_channel, send_request, ErrorToResponse, JsonError},
     openai::{AudioResponseFormat, SpeechGenerationRequest},
     types::SharedMistralRsState,
@@ -61,6 +63,8 @@ pub fn parse_request(
     // Validate that the requested model matches the loaded model
     validate_model_name(&oairequest.model, state.clone())?;
 
+    let timeout = get_request_timeout(oairequest.timeout, &state)?;
+
     let request = Request::Normal(Box::new(NormalRequest {
         id: state.next_request_id(),
         messages: RequestMessage::SpeechGeneration {
@@ -83,6 +87,10 @@ pub fn parse_request(
             Some(oairequest.model.clone())
         },
         truncate_sequence: false,
+        priority: RequestPriority::default(),
+        timeout,
+        prompt_logprobs: None,
+        traceparent: None,
     }));
 
     Ok((request, oairequest.response_format))
@@ -98,10 +106,15 @@ pub fn parse_request(
 )]
 pub async fn speech_generation(
     State(state): State<Arc<MistralRs>>,
-    Json(oairequest): Json<SpeechGenerationRequest>,
+    headers: HeaderMap,
+    Json(mut oairequest): Json<SpeechGenerationRequest>,
 ) -> SpeechGenerationResponder {
     let (tx, mut rx) = create_response_channel(None);
 
+    if let Err(e) = apply_header_timeout(&headers, &mut oairequest.timeout) {
+        return handle_error(state, e.into());
+    }
+
     let (request, response_format) = match parse_request(oairequest, state.clone(), tx) {
         Ok(x) => x,
         Err(e) => return handle_error(state, e.into()),
@@ -39,6 +39,38 @@ pub struct BaseStreamer<R, C, D> {
     pub on_chunk: Option<C>,
     /// Optional callback to execute when streaming completes
     pub on_done: Option<D>,
+    /// ID of the request being streamed, canceled if the client disconnects before completion
+    pub request_id: Option<usize>,
+    /// Model the request was sent to
+    pub model_id: Option<String>,
+    /// Span covering the lifetime of the stream
+    pub span: tracing::Span,
+}
+
+impl<R, C, D> BaseStreamer<R, C, D> {
+    /// Cancel the request in the engine if the stream is dropped before it completes,
+    /// which happens when the client disconnects.
+    pub fn cancel_on_disconnect(mut self, request_id: usize, model_id: Option<String>) -> Self {
+        self.request_id = Some(request_id);
+        self.model_id = model_id;
+        self
+    }
+}
+
+impl<R, C, D> Drop for BaseStreamer<R, C, D> {
+    fn drop(&mut self) {
+        if !matches!(self.done_state, DoneState::Running) {
+            return;
+        }
+        if let Some(request_id) = self.request_id {
+            if let Err(e) = self
+                .state
+                .cancel_request(request_id, self.model_id.as_deref())
+            {
+                tracing::warn!("Failed to cancel request {request_id} after disconnect: {e}");
+            }
+        }
+    }
 }
 
 /// Generic function to create a SSE streamer with optional callbacks.
@@ -58,6 +90,9 @@ pub(crate) fn base_create_streamer<R, C, D>(
         chunks: Vec::new(),
         on_chunk,
         on_done,
+        request_id: None,
+        model_id: None,
+        span: tracing::info_span!("stream_response"),
     }
 }
 
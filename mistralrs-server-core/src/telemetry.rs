@@ -0,0 +1,35 @@
+//! ## Tracing of HTTP requests.
+
+use axum::{
+    extract::{MatchedPath, Request},
+    middleware::Next,
+    response::Response,
+};
+use mistralrs_core::{set_remote_parent, TRACEPARENT_HEADER};
+use tracing::{field, info_span, Instrument};
+
+/// Middleware which runs each request in an `http_request` span, continuing the caller's trace
+/// from the W3C `traceparent` header.
+pub async fn trace_requests(request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_default();
+    let span = info_span!(
+        parent: None,
+        "http_request",
+        "http.request.method" = %request.method(),
+        "http.route" = %route,
+        "http.response.status_code" = field::Empty,
+    );
+    let traceparent = request
+        .headers()
+        .get(TRACEPARENT_HEADER)
+        .and_then(|value| value.to_str().ok());
+    set_remote_parent(&span, traceparent);
+
+    let response = next.run(request).instrument(span.clone()).await;
+    span.record("http.response.status_code", response.status().as_u16());
+    response
+}
@@ -4,8 +4,8 @@ use indexmap::IndexMap;
 use mistralrs_core::{
     speech_utils, Constraint, DiffusionGenerationParams, DrySamplingParams,
     ImageGenerationResponseFormat, MessageContent, MistralRs, ModelCategory, NormalRequest,
-    Request, RequestMessage, Response, ResponseOk, SamplingParams, WebSearchOptions,
-    TERMINATE_ALL_NEXT_STEP,
+    Request, RequestMessage, RequestPriority, Response, ResponseOk, SamplingParams,
+    WebSearchOptions, TERMINATE_ALL_NEXT_STEP,
 };
 use regex::Regex;
 use rustyline::{error::ReadlineError, history::History, DefaultEditor, Editor, Helper};
@@ -166,6 +166,13 @@ fn interactive_sample_parameters() -> SamplingParams {
         logits_bias: None,
         n_choices: 1,
         dry_params: Some(DrySamplingParams::default()),
+        seed: None,
+        beam_search: None,
+        typical_p: None,
+        top_a: None,
+        xtc_params: None,
+        mirostat_params: None,
+        sampler_order: None,
     }
 }
 
@@ -334,6 +341,10 @@ async fn text_interactive_mode(
             web_search_options: do_search.then(WebSearchOptions::default),
             model_id: None,
             truncate_sequence: false,
+            priority: RequestPriority::default(),
+            timeout: None,
+            prompt_logprobs: None,
+            traceparent: None,
         }));
         sender.send(req).await.unwrap();
         let start_ttft = Instant::now();
@@ -648,6 +659,10 @@ async fn vision_interactive_mode(
             web_search_options: do_search.then(WebSearchOptions::default),
             model_id: None,
             truncate_sequence: false,
+            priority: RequestPriority::default(),
+            timeout: None,
+            prompt_logprobs: None,
+            traceparent: None,
         }));
         sender.send(req).await.unwrap();
         let start_ttft = Instant::now();
@@ -818,6 +833,10 @@ async fn diffusion_interactive_mode(mistralrs: Arc<MistralRs>, do_search: bool)
             web_search_options: do_search.then(WebSearchOptions::default),
             model_id: None,
             truncate_sequence: false,
+            priority: RequestPriority::default(),
+            timeout: None,
+            prompt_logprobs: None,
+            traceparent: None,
         }));
 
         let start = Instant::now();
@@ -907,6 +926,10 @@ async fn speech_interactive_mode(mistralrs: Arc<MistralRs>, do_search: bool) {
             web_search_options: do_search.then(WebSearchOptions::default),
             model_id: None,
             truncate_sequence: false,
+            priority: RequestPriority::default(),
+            timeout: None,
+            prompt_logprobs: None,
+            traceparent: None,
         }));
 
         let start = Instant::now();
@@ -1,15 +1,16 @@
 use anyhow::Result;
 use clap::Parser;
 use mistralrs_core::{
-    initialize_logging, McpClientConfig, ModelSelected, PagedCacheType, SearchEmbeddingModel,
-    TokenSource,
+    initialize_logging, KvCacheQuantization, McpClientConfig, ModelSelected, PagedCacheType,
+    SearchEmbeddingModel, TokenSource,
 };
 use rust_mcp_sdk::schema::LATEST_PROTOCOL_VERSION;
-use std::collections::HashMap;
+use std::{collections::HashMap, num::NonZeroU32, path::PathBuf, time::Duration};
 use tokio::join;
 use tracing::{error, info};
 
 use mistralrs_server_core::{
+    auth::{ApiKey, ApiKeyAuth, API_KEYS_FILE_ENV},
     mistralrs_for_server_builder::{
         configure_paged_attn_from_flags, defaults, get_search_embedding_model,
         MistralRsForServerBuilder, ModelConfig,
@@ -48,6 +49,11 @@ struct Args {
     #[arg(long, default_value_t = defaults::MAX_SEQS)]
     max_seqs: usize,
 
+    /// Token budget of an engine step. If set, long prompts are prefilled in chunks of at most
+    /// this many tokens, interleaved with the decoding of running sequences.
+    #[arg(long)]
+    max_num_batched_tokens: Option<usize>,
+
     /// Use no KV cache.
     #[arg(long, default_value_t = defaults::NO_KV_CACHE)]
     no_kv_cache: bool,
@@ -75,6 +81,30 @@ struct Args {
     #[arg(long, default_value_t = defaults::PREFIX_CACHE_N)]
     prefix_cache_n: usize,
 
+    /// Directory to persist evicted prefix caches to, so that they can be reused across restarts.
+    /// Only applies when PagedAttention is disabled.
+    #[arg(long, value_name = "DIR")]
+    prefix_cache_disk: Option<PathBuf>,
+
+    /// Size budget in MB for the on-disk prefix cache. Least recently used caches are removed beyond it.
+    #[arg(long, default_value_t = defaults::PREFIX_CACHE_DISK_SIZE)]
+    prefix_cache_disk_size: usize,
+
+    /// Store the KV cache quantized (int8 or int4) to reduce its memory usage. Values are dequantized when
+    /// attention is computed. Only applies when PagedAttention is disabled.
+    #[arg(long = "kv-cache-quant", value_parser = parse_kv_cache_quantization)]
+    kv_cache_quantization: Option<KvCacheQuantization>,
+
+    /// Format of the tool calls of the model, for example `hermes`, `mistral` or `llama`.
+    /// By default it is detected from the chat template.
+    #[arg(long)]
+    tool_parser: Option<String>,
+
+    /// Default timeout in seconds for requests which do not specify their own `timeout`.
+    /// Requests which exceed it are finished with the `timeout` finish reason.
+    #[arg(long)]
+    request_timeout: Option<f64>,
+
     /// NOTE: This can be omitted to use automatic device mapping!
     /// Number of device layers to load and run on GPU(s). All others will be on the CPU.
     /// If one GPU is used, then this value should be an integer. Otherwise, it follows the following pattern:
@@ -87,20 +117,20 @@ struct Args {
     in_situ_quant: Option<String>,
 
     /// GPU memory to allocate for KV cache with PagedAttention in MBs.
-    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
+    /// PagedAttention is supported on CUDA, Metal and CPU. It is automatically activated on CUDA but not on Metal or CPU.
     /// The priority is as follows: `pa-ctxt-len` > `pa-gpu-mem-usage` > `pa-gpu-mem`.
     #[arg(long = "pa-gpu-mem")]
     paged_attn_gpu_mem: Option<usize>,
 
     /// Percentage of GPU memory to utilize after allocation of KV cache with PagedAttention, from 0 to 1.
     /// If this is not set and the device is CUDA, it will default to `0.9`.
-    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
+    /// PagedAttention is supported on CUDA, Metal and CPU. It is automatically activated on CUDA but not on Metal or CPU.
     /// The priority is as follows: `pa-ctxt-len` > `pa-gpu-mem-usage` > `pa-gpu-mem`.
     #[arg(long = "pa-gpu-mem-usage")]
     paged_attn_gpu_mem_usage: Option<f32>,
 
     /// Total context length to allocate the KV cache for (total number of tokens which the KV cache can hold).
-    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
+    /// PagedAttention is supported on CUDA, Metal and CPU. It is automatically activated on CUDA but not on Metal or CPU.
     /// The priority is as follows: `pa-ctxt-len` > `pa-gpu-mem-usage` > `pa-gpu-mem`.
     /// This is the default setting, and it defaults to the `max-seq-len` specified in after the model type.
     #[arg(long = "pa-ctxt-len")]
@@ -111,8 +141,13 @@ struct Args {
     #[arg(long = "pa-cache-type", value_parser = parse_cache_type)]
     cache_type: Option<PagedCacheType>,
 
+    /// Host memory in MBs to swap the PagedAttention KV cache of preempted sequences out to.
+    /// By default, preempted sequences drop their KV cache and are prefilled again when rescheduled.
+    #[arg(long = "pa-swap-space")]
+    paged_attn_swap_space: Option<usize>,
+
     /// Block size (number of tokens per block) for PagedAttention. If this is not set and the device is CUDA, it will default to 32.
-    /// PagedAttention is supported on CUDA and Metal. It is automatically activated on CUDA but not on Metal.
+    /// PagedAttention is supported on CUDA, Metal and CPU. It is automatically activated on CUDA but not on Metal or CPU.
     #[arg(long = "pa-blk-size")]
     paged_attn_block_size: Option<usize>,
 
@@ -124,11 +159,11 @@ struct Args {
     )]
     no_paged_attn: bool,
 
-    /// Enable PagedAttention on Metal. Because PagedAttention is already enabled on CUDA, this is only applicable on Metal.
+    /// Enable PagedAttention on Metal or CPU. Because PagedAttention is already enabled on CUDA, this is only applicable on Metal and CPU.
     #[arg(
         long = "paged-attn",
         default_value_t = false,
-        conflicts_with_all = ["no_paged_attn", "cpu"]
+        conflicts_with = "no_paged_attn"
     )]
     paged_attn: bool,
 
@@ -155,6 +190,19 @@ struct Args {
     /// MCP client configuration file path
     #[arg(long)]
     mcp_config: Option<String>,
+
+    /// JSON file with the API keys required to access the server, with optional per-key rate limits.
+    /// Keys can also be given as a comma separated list in `MISTRALRS_API_KEYS`.
+    #[arg(long)]
+    api_keys_file: Option<String>,
+
+    /// Default maximum number of requests per minute of an API key.
+    #[arg(long)]
+    requests_per_minute: Option<NonZeroU32>,
+
+    /// Default maximum number of prompt and generated tokens per minute of an API key.
+    #[arg(long)]
+    tokens_per_minute: Option<NonZeroU32>,
 }
 
 fn parse_token_source(s: &str) -> Result<TokenSource, String> {
@@ -165,6 +213,10 @@ fn parse_cache_type(s: &str) -> Result<PagedCacheType, String> {
     s.parse()
 }
 
+fn parse_kv_cache_quantization(s: &str) -> Result<KvCacheQuantization, String> {
+    s.parse()
+}
+
 /// Load MCP configuration from file path or environment variable
 fn load_mcp_config(mcp_config_path: Option<&str>) -> Result<Option<McpClientConfig>> {
     let config_path = if let Some(path) = mcp_config_path {
@@ -208,6 +260,34 @@ fn load_mcp_config(mcp_config_path: Option<&str>) -> Result<Option<McpClientConf
 }
 
 /// Validate MCP configuration for common issues
+fn load_api_keys(args: &Args) -> Result<Option<ApiKeyAuth>> {
+    let mut keys = ApiKey::from_env();
+    let keys_file = args
+        .api_keys_file
+        .clone()
+        .or_else(|| std::env::var(API_KEYS_FILE_ENV).ok());
+    if let Some(path) = keys_file {
+        keys.extend(ApiKey::from_file(&path)?);
+    }
+
+    if keys.is_empty() {
+        if args.requests_per_minute.is_some() || args.tokens_per_minute.is_some() {
+            anyhow::bail!("Rate limits require API keys, see `--api-keys-file`.");
+        }
+        return Ok(None);
+    }
+
+    info!(
+        "API key authentication is enabled with {} keys.",
+        keys.len()
+    );
+    Ok(Some(ApiKeyAuth::new(
+        keys,
+        args.requests_per_minute,
+        args.tokens_per_minute,
+    )))
+}
+
 fn validate_mcp_config(config: &McpClientConfig) -> Result<()> {
     use std::collections::HashSet;
 
@@ -284,6 +364,8 @@ struct ModelConfigParsed {
     num_device_layers: Option<Vec<String>>,
     /// Model-specific in-situ quantization
     in_situ_quant: Option<String>,
+    /// Model-specific tool call parser
+    tool_parser: Option<String>,
 }
 
 /// Load multi-model configuration from file
@@ -312,6 +394,7 @@ fn load_multi_model_config(config_path: &str) -> Result<Vec<ModelConfig>> {
             jinja_explicit: parsed_config.jinja_explicit,
             num_device_layers: parsed_config.num_device_layers,
             in_situ_quant: parsed_config.in_situ_quant,
+            tool_parser: parsed_config.tool_parser,
         };
         configs.push(config);
     }
@@ -331,9 +414,15 @@ async fn main() -> Result<()> {
 
     // Load MCP configuration if provided
     let mcp_config = load_mcp_config(args.mcp_config.as_deref())?;
+    let api_keys = load_api_keys(&args)?;
 
     let paged_attn = configure_paged_attn_from_flags(args.paged_attn, args.no_paged_attn)?;
 
+    let request_timeout = args
+        .request_timeout
+        .map(Duration::try_from_secs_f64)
+        .transpose()?;
+
     let mistralrs = match args.model {
         ModelSelected::MultiModel {
             config,
@@ -344,17 +433,24 @@ async fn main() -> Result<()> {
 
             let mut builder = MistralRsForServerBuilder::new()
                 .with_max_seqs(args.max_seqs)
+                .with_max_num_batched_tokens_optional(args.max_num_batched_tokens)
                 .with_no_kv_cache(args.no_kv_cache)
                 .with_token_source(args.token_source)
                 .with_interactive_mode(args.interactive_mode)
                 .with_prefix_cache_n(args.prefix_cache_n)
+                .with_prefix_cache_disk_optional(args.prefix_cache_disk)
+                .with_prefix_cache_disk_size(args.prefix_cache_disk_size)
+                .with_kv_cache_quantization_optional(args.kv_cache_quantization)
                 .set_paged_attn(paged_attn)
                 .with_cpu(args.cpu)
                 .with_enable_search(args.enable_search)
                 .with_seed_optional(args.seed)
                 .with_log_optional(args.log)
                 .with_mcp_config_optional(mcp_config)
-                .with_paged_attn_cache_type(args.cache_type.unwrap_or_default());
+                .with_request_timeout_optional(request_timeout)
+                .with_tool_parser_optional(args.tool_parser)
+                .with_paged_attn_cache_type(args.cache_type.unwrap_or_default())
+                .with_paged_attn_swap_space_optional(args.paged_attn_swap_space);
 
             // Add models to builder
             for config in model_configs {
@@ -377,10 +473,14 @@ async fn main() -> Result<()> {
             let mut builder = MistralRsForServerBuilder::new()
                 .with_model(model)
                 .with_max_seqs(args.max_seqs)
+                .with_max_num_batched_tokens_optional(args.max_num_batched_tokens)
                 .with_no_kv_cache(args.no_kv_cache)
                 .with_token_source(args.token_source)
                 .with_interactive_mode(args.interactive_mode)
                 .with_prefix_cache_n(args.prefix_cache_n)
+                .with_prefix_cache_disk_optional(args.prefix_cache_disk)
+                .with_prefix_cache_disk_size(args.prefix_cache_disk_size)
+                .with_kv_cache_quantization_optional(args.kv_cache_quantization)
                 .set_paged_attn(paged_attn)
                 .with_cpu(args.cpu)
                 .with_enable_search(args.enable_search)
@@ -395,7 +495,10 @@ async fn main() -> Result<()> {
                 .with_paged_ctxt_len_optional(args.paged_ctxt_len)
                 .with_paged_attn_block_size_optional(args.paged_attn_block_size)
                 .with_mcp_config_optional(mcp_config)
-                .with_paged_attn_cache_type(args.cache_type.unwrap_or_default());
+                .with_request_timeout_optional(request_timeout)
+                .with_tool_parser_optional(args.tool_parser)
+                .with_paged_attn_cache_type(args.cache_type.unwrap_or_default())
+                .with_paged_attn_swap_space_optional(args.paged_attn_swap_space);
 
             if let Some(model) = args.search_embedding_model {
                 builder = builder.with_search_embedding_model(model);
@@ -451,7 +554,8 @@ async fn main() -> Result<()> {
         let listener = tokio::net::TcpListener::bind(format!("{ip}:{port}")).await?;
 
         let app = MistralRsServerRouterBuilder::new()
-            .with_mistralrs(mistralrs)
+            .with_mistralrs(mistralrs.clone())
+            .with_api_keys_optional(api_keys)
             .build()
             .await?;
 
@@ -466,7 +570,41 @@ async fn main() -> Result<()> {
         tokio::spawn(async {})
     };
 
-    let (_, _) = join!(oai_port, mcp_port);
+    tokio::select! {
+        _ = async { join!(oai_port, mcp_port) } => {}
+        _ = shutdown_signal() => info!("Shutting down."),
+    }
+    // Stop the engines so that their state, such as the on-disk prefix cache, is dropped cleanly.
+    tokio::task::spawn_blocking(move || mistralrs.shutdown()).await?;
 
     Ok(())
 }
+
+/// Resolves on Ctrl+C, or on SIGTERM on Unix.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for Ctrl+C: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
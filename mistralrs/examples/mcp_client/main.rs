@@ -11,7 +11,7 @@
 
 use anyhow::Result;
 use mistralrs::{
-    IsqType, McpClientConfig, McpServerConfig, McpServerSource, MemoryGpuConfig,
+    IsqType, McpClientConfig, McpSamplingConfig, McpServerConfig, McpServerSource, MemoryGpuConfig,
     PagedAttentionMetaBuilder, TextMessageRole, TextMessages, TextModelBuilder,
 };
 // use std::collections::HashMap; // Uncomment if using manual headers in examples below
@@ -110,6 +110,11 @@ async fn main() -> Result<()> {
         tool_timeout_secs: Some(30),
         // Maximum concurrent tool calls across all servers
         max_concurrent_calls: Some(5),
+        // Let the servers generate messages with the model through MCP sampling
+        sampling: Some(McpSamplingConfig {
+            max_tokens: Some(512),
+            ..Default::default()
+        }),
     };
 
     // Use the simple configuration for this example
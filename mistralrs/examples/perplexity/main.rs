@@ -4,8 +4,8 @@ use anyhow::{Context, Result};
 use clap::Parser;
 use either::Either;
 use mistralrs::{
-    cross_entropy_loss, parse_isq_value, Constraint, DType, Device, MistralRs, NormalRequest,
-    Request, ResponseOk, SamplingParams, Tensor, TextModelBuilder,
+    parse_isq_value, Constraint, MistralRs, NormalRequest, Request, RequestPriority, ResponseOk,
+    SamplingParams, TextModelBuilder,
 };
 use tokio::sync::mpsc::channel;
 
@@ -30,7 +30,8 @@ struct Args {
     calibration_file: Option<PathBuf>,
 }
 
-async fn process_chunk(runner: &MistralRs, chunk: Vec<u32>) -> anyhow::Result<(Tensor, Vec<u32>)> {
+/// Returns the base 10 logprob of every token of the chunk after the first.
+async fn process_chunk(runner: &MistralRs, chunk: Vec<u32>) -> anyhow::Result<Vec<f32>> {
     let (tx, mut rx) = channel(1);
 
     let request = Request::Normal(Box::new(NormalRequest {
@@ -48,18 +49,19 @@ async fn process_chunk(runner: &MistralRs, chunk: Vec<u32>) -> anyhow::Result<(T
         tools: None,
         tool_choice: None,
         logits_processors: None,
-        return_raw_logits: true,
+        return_raw_logits: false,
         web_search_options: None,
         model_id: None,
         truncate_sequence: false,
+        priority: RequestPriority::default(),
+        timeout: None,
+        prompt_logprobs: Some(0),
+        traceparent: None,
     }));
 
     runner.get_sender(None)?.send(request).await?;
 
-    let ResponseOk::Raw {
-        logits_chunks,
-        tokens,
-    } = rx
+    let ResponseOk::CompletionDone(response) = rx
         .recv()
         .await
         .context("Channel was erroneously closed!")?
@@ -68,7 +70,13 @@ async fn process_chunk(runner: &MistralRs, chunk: Vec<u32>) -> anyhow::Result<(T
         anyhow::bail!("Got unexpected response type.")
     };
 
-    Ok((logits_chunks[0].clone(), tokens))
+    Ok(response
+        .prompt_logprobs
+        .context("Prompt logprobs were not returned.")?
+        .into_iter()
+        .flatten()
+        .map(|logprob| logprob.logprob)
+        .collect())
 }
 
 #[tokio::main]
@@ -107,25 +115,20 @@ async fn main() -> Result<()> {
     let mut ppl_measurements = Vec::new();
     for (i, chunk) in tokens.chunks(prompt_chunksize).enumerate() {
         let start = Instant::now();
-        let (logits, tokens) = {
+        let logprobs = {
             let chunk = [vec![bos_token], chunk.to_vec()].concat();
             process_chunk(inner, chunk).await?
         };
 
-        // Upcast to float if we need to compute the loss to avoid potential precision issues
-        let logits = logits.to_device(&Device::Cpu)?.to_dtype(DType::F32)?;
-        // Shift so that tokens < n predict n
-        let shift_logits = logits.narrow(0, 0, logits.dim(0)? - 1)?.contiguous()?;
-        let shift_labels = Tensor::from_slice(&tokens[1..], (tokens.len() - 1,), &Device::Cpu)?;
-
-        let loss_fct = cross_entropy_loss(&shift_logits, &shift_labels)?;
-        let perplexity = loss_fct.exp()?.to_scalar::<f32>()?;
+        // Perplexity is the exponentiated mean negative log-likelihood of the tokens.
+        let mean_logprob = logprobs.iter().sum::<f32>() / logprobs.len() as f32;
+        let perplexity = 10f32.powf(-mean_logprob);
         let end = Instant::now();
 
         ppl_measurements.push(perplexity);
         println!(
             "Chunk {i}/{n_chunks} ({} tokens): Perplexity for `{}`, ISQ `{:?}`, {}s: {perplexity}",
-            tokens.len(),
+            logprobs.len() + 1,
             args.file,
             quant,
             end.duration_since(start).as_secs_f32(),
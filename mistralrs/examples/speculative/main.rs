@@ -1,7 +1,7 @@
 use anyhow::Result;
 use mistralrs::{
-    IsqType, RequestBuilder, SpeculativeConfig, TextMessageRole, TextMessages, TextModelBuilder,
-    TextSpeculativeBuilder,
+    IsqType, RequestBuilder, SpeculativeConfig, SpeculativeDraft, TextMessageRole, TextMessages,
+    TextModelBuilder, TextSpeculativeBuilder,
 };
 
 #[tokio::main]
@@ -10,7 +10,10 @@ async fn main() -> Result<()> {
     let draft = TextModelBuilder::new("meta-llama/Llama-3.2-1B-Instruct")
         .with_logging()
         .with_isq(IsqType::Q8_0);
-    let spec_cfg = SpeculativeConfig { gamma: 16 };
+    let spec_cfg = SpeculativeConfig {
+        gamma: 16,
+        draft: SpeculativeDraft::Model,
+    };
     let model = TextSpeculativeBuilder::new(target, draft, spec_cfg)?
         .build()
         .await?;
@@ -0,0 +1,53 @@
+use anyhow::Result;
+use mistralrs::{
+    IsqType, SpeculativeConfig, SpeculativeDraft, TextMessageRole, TextMessages, TextModelBuilder,
+    TextSpeculativeBuilder,
+};
+
+const CODE: &str = r#"fn binary_search<T: Ord>(items: &[T], target: &T) -> Option<usize> {
+    let mut lo = 0;
+    let mut hi = items.len();
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        match items[mid].cmp(target) {
+            std::cmp::Ordering::Less => lo = mid + 1,
+            std::cmp::Ordering::Greater => hi = mid,
+            std::cmp::Ordering::Equal => return Some(mid),
+        }
+    }
+    None
+}"#;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let target = TextModelBuilder::new("meta-llama/Llama-3.1-8B-Instruct")
+        .with_logging()
+        .with_isq(IsqType::Q8_0);
+    // No draft model: proposals are copied from earlier occurrences of the last 1 to 3 tokens.
+    // This works best when the output repeats the prompt, e.g. when editing code.
+    let spec_cfg = SpeculativeConfig {
+        gamma: 8,
+        draft: SpeculativeDraft::NGram {
+            min_ngram: 1,
+            max_ngram: 3,
+        },
+    };
+    let model = TextSpeculativeBuilder::new_ngram(target, spec_cfg)?
+        .build()
+        .await?;
+
+    let messages = TextMessages::new().add_message(
+        TextMessageRole::User,
+        format!("Rename `lo` to `low` and `hi` to `high` in this function:\n\n{CODE}"),
+    );
+
+    let response = model.send_chat_request(messages).await?;
+
+    println!("{}", response.choices[0].message.content.as_ref().unwrap());
+    dbg!(
+        response.usage.avg_prompt_tok_per_sec,
+        response.usage.avg_compl_tok_per_sec
+    );
+
+    Ok(())
+}
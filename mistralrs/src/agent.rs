@@ -230,9 +230,9 @@ impl<'a> AgentStream<'a> {
                                             return Some(AgentEvent::TextDelta(text.clone()));
                                         }
 
-                                        // Accumulate tool calls
-                                        if let Some(calls) = tool_calls {
-                                            accumulated_tool_calls.extend(calls.clone());
+                                        // Accumulate the fragments of the tool calls
+                                        for delta in tool_calls.iter().flatten() {
+                                            delta.clone().accumulate(accumulated_tool_calls);
                                         }
 
                                         // Check if done
@@ -288,6 +288,7 @@ impl<'a> AgentStream<'a> {
                                                         total_prompt_time_sec: 0.0,
                                                         total_completion_time_sec: 0.0,
                                                     },
+                                                    prompt_logprobs: None,
                                                 };
 
                                                 self.state = AgentStreamState::ExecutingTools {
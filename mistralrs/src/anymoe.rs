@@ -105,10 +105,12 @@ impl AnyMoeModelBuilder {
                 SchedulerConfig::PagedAttentionMeta {
                     max_num_seqs: self.base.max_num_seqs,
                     config,
+                    max_num_batched_tokens: None,
                 }
             }
             None => SchedulerConfig::DefaultScheduler {
                 method: DefaultSchedulerMethod::Fixed(self.base.max_num_seqs.try_into()?),
+                max_num_batched_tokens: None,
             },
         };
 
@@ -131,6 +133,12 @@ impl AnyMoeModelBuilder {
         if let Some(n) = self.base.prefix_cache_n {
             runner = runner.with_prefix_cache_n(n)
         }
+        if let Some(config) = self.base.prefix_cache_disk {
+            runner = runner.with_prefix_cache_disk(config);
+        }
+        if let Some(quantization) = self.base.kv_cache_quantization {
+            runner = runner.with_kv_cache_quantization(quantization);
+        }
 
         Ok(Model::new(runner.build().await))
     }
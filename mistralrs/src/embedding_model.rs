@@ -206,6 +206,7 @@ impl EmbeddingModelBuilder {
 
         let scheduler_method = SchedulerConfig::DefaultScheduler {
             method: DefaultSchedulerMethod::Fixed(self.max_num_seqs.try_into()?),
+            max_num_batched_tokens: None,
         };
 
         let runner =
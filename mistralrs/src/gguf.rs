@@ -39,9 +39,12 @@ pub struct GgufModelBuilder {
     // Other things
     pub(crate) paged_attn_cfg: Option<PagedAttentionConfig>,
     pub(crate) max_num_seqs: usize,
+    pub(crate) max_num_batched_tokens: Option<usize>,
     pub(crate) no_kv_cache: bool,
     pub(crate) with_logging: bool,
     pub(crate) prefix_cache_n: Option<usize>,
+    pub(crate) prefix_cache_disk: Option<PrefixCacheDiskConfig>,
+    pub(crate) kv_cache_quantization: Option<KvCacheQuantization>,
 }
 
 impl GgufModelBuilder {
@@ -62,8 +65,11 @@ impl GgufModelBuilder {
             hf_revision: None,
             paged_attn_cfg: None,
             max_num_seqs: 32,
+            max_num_batched_tokens: None,
             no_kv_cache: false,
             prefix_cache_n: Some(16),
+            prefix_cache_disk: None,
+            kv_cache_quantization: None,
             with_logging: false,
             topology: None,
             tok_model_id: None,
@@ -149,7 +155,7 @@ impl GgufModelBuilder {
         self
     }
 
-    /// Force usage of the CPU device. Do not use PagedAttention with this.
+    /// Force usage of the CPU device.
     pub fn with_force_cpu(mut self) -> Self {
         self.force_cpu = true;
         self
@@ -191,6 +197,13 @@ impl GgufModelBuilder {
         self
     }
 
+    /// Enable chunked prefill with a budget of `max_num_batched_tokens` tokens per step. Long
+    /// prompts are then prefilled in chunks between decode steps instead of stalling them.
+    pub fn with_max_num_batched_tokens(mut self, max_num_batched_tokens: usize) -> Self {
+        self.max_num_batched_tokens = Some(max_num_batched_tokens);
+        self
+    }
+
     /// Disable KV cache. Trade performance for memory usage.
     pub fn with_no_kv_cache(mut self) -> Self {
         self.no_kv_cache = true;
@@ -203,6 +216,19 @@ impl GgufModelBuilder {
         self
     }
 
+    /// Persist evicted prefix caches to disk so that they can be reused across restarts. This only applies when
+    /// PagedAttention is not used.
+    pub fn with_prefix_cache_disk(mut self, config: PrefixCacheDiskConfig) -> Self {
+        self.prefix_cache_disk = Some(config);
+        self
+    }
+
+    /// Store the KV cache quantized to reduce its memory usage. This only applies when PagedAttention is not used.
+    pub fn with_kv_cache_quantization(mut self, quantization: KvCacheQuantization) -> Self {
+        self.kv_cache_quantization = Some(quantization);
+        self
+    }
+
     /// Enable logging.
     pub fn with_logging(mut self) -> Self {
         self.with_logging = true;
@@ -268,10 +294,12 @@ impl GgufModelBuilder {
                 SchedulerConfig::PagedAttentionMeta {
                     max_num_seqs: self.max_num_seqs,
                     config,
+                    max_num_batched_tokens: self.max_num_batched_tokens,
                 }
             }
             None => SchedulerConfig::DefaultScheduler {
                 method: DefaultSchedulerMethod::Fixed(self.max_num_seqs.try_into()?),
+                max_num_batched_tokens: self.max_num_batched_tokens,
             },
         };
 
@@ -301,6 +329,12 @@ impl GgufModelBuilder {
         if let Some(n) = self.prefix_cache_n {
             runner = runner.with_prefix_cache_n(n)
         }
+        if let Some(config) = self.prefix_cache_disk {
+            runner = runner.with_prefix_cache_disk(config);
+        }
+        if let Some(quantization) = self.kv_cache_quantization {
+            runner = runner.with_kv_cache_quantization(quantization);
+        }
 
         Ok(Model::new(runner.build().await))
     }
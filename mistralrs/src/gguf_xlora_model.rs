@@ -83,10 +83,12 @@ impl GgufXLoraModelBuilder {
                 SchedulerConfig::PagedAttentionMeta {
                     max_num_seqs: self.gguf_model.max_num_seqs,
                     config,
+                    max_num_batched_tokens: None,
                 }
             }
             None => SchedulerConfig::DefaultScheduler {
                 method: DefaultSchedulerMethod::Fixed(self.gguf_model.max_num_seqs.try_into()?),
+                max_num_batched_tokens: None,
             },
         };
 
@@ -109,6 +111,12 @@ impl GgufXLoraModelBuilder {
         if let Some(n) = self.gguf_model.prefix_cache_n {
             runner = runner.with_prefix_cache_n(n)
         }
+        if let Some(config) = self.gguf_model.prefix_cache_disk {
+            runner = runner.with_prefix_cache_disk(config);
+        }
+        if let Some(quantization) = self.gguf_model.kv_cache_quantization {
+            runner = runner.with_kv_cache_quantization(quantization);
+        }
 
         Ok(Model::new(runner.build().await))
     }
@@ -112,6 +112,7 @@
 //!         auto_register_tools: true,
 //!         tool_timeout_secs: Some(30),
 //!         max_concurrent_calls: Some(5),
+//!         sampling: None,
 //!     };
 //!     
 //!     let model = TextModelBuilder::new("path/to/model".to_string())
@@ -158,7 +159,9 @@ pub use messages::{
     TextMessageRole, TextMessages, VisionMessages,
 };
 pub use mistralrs_core::{
-    McpClient, McpClientConfig, McpServerConfig, McpServerSource, McpToolInfo,
+    McpClient, McpClientConfig, McpCreateMessageRequest, McpPromptInfo, McpPromptResult,
+    McpResourceInfo, McpResourceTemplateInfo, McpSamplingApproval, McpSamplingConfig,
+    McpServerConfig, McpServerSource, McpToolInfo,
 };
 pub use mistralrs_core::{SearchCallback, SearchResult, ToolCallback};
 pub use model::{best_device, Model};
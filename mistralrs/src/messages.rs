@@ -1,4 +1,4 @@
-use std::{collections::HashMap, fmt::Display, sync::Arc};
+use std::{collections::HashMap, fmt::Display, sync::Arc, time::Duration};
 
 use super::*;
 use either::Either;
@@ -22,6 +22,15 @@ pub trait RequestLike {
     fn truncate_sequence(&self) -> bool {
         false
     }
+    fn priority(&self) -> RequestPriority {
+        RequestPriority::default()
+    }
+    fn timeout(&self) -> Option<Duration> {
+        None
+    }
+    fn prompt_logprobs(&self) -> Option<usize> {
+        None
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -351,6 +360,9 @@ pub struct RequestBuilder {
     web_search_options: Option<WebSearchOptions>,
     enable_thinking: Option<bool>,
     truncate_sequence: bool,
+    priority: RequestPriority,
+    timeout: Option<Duration>,
+    prompt_logprobs: Option<usize>,
 }
 
 impl Default for RequestBuilder {
@@ -375,6 +387,9 @@ impl From<TextMessages> for RequestBuilder {
             web_search_options: None,
             enable_thinking: None,
             truncate_sequence: false,
+            priority: RequestPriority::default(),
+            timeout: None,
+            prompt_logprobs: None,
         }
     }
 }
@@ -395,6 +410,9 @@ impl From<VisionMessages> for RequestBuilder {
             web_search_options: None,
             enable_thinking: None,
             truncate_sequence: false,
+            priority: RequestPriority::default(),
+            timeout: None,
+            prompt_logprobs: None,
         }
     }
 }
@@ -415,6 +433,9 @@ impl RequestBuilder {
             web_search_options: None,
             enable_thinking: None,
             truncate_sequence: false,
+            priority: RequestPriority::default(),
+            timeout: None,
+            prompt_logprobs: None,
         }
     }
 
@@ -454,6 +475,30 @@ impl RequestBuilder {
         self
     }
 
+    /// Add the messages of a prompt from an MCP server, see [`McpClient::get_prompt`].
+    ///
+    /// Images and resources without text are added as a placeholder.
+    pub fn add_mcp_prompt(mut self, prompt: &McpPromptResult) -> Self {
+        for message in &prompt.messages {
+            let role = match message.role.as_str() {
+                "user" => TextMessageRole::User,
+                "assistant" => TextMessageRole::Assistant,
+                role => TextMessageRole::Custom(role.to_string()),
+            };
+            self = self.add_message(role, message.content.to_string());
+        }
+        self
+    }
+
+    /// Attach the text of a resource from an MCP server as context in a user message,
+    /// see [`McpClient::read_resource`].
+    pub fn add_mcp_resource(self, uri: impl Display, text: impl Display) -> Self {
+        self.add_message(
+            TextMessageRole::User,
+            format!("Contents of the resource `{uri}`:\n\n{text}"),
+        )
+    }
+
     pub fn add_message_with_tool_call(
         mut self,
         role: TextMessageRole,
@@ -682,6 +727,44 @@ impl RequestBuilder {
         self
     }
 
+    /// Seed the sampler so this request is reproducible regardless of batching.
+    pub fn set_sampler_seed(mut self, seed: u64) -> Self {
+        self.sampling_params.seed = Some(seed);
+        self
+    }
+
+    pub fn set_sampler_typical_p(mut self, typical_p: f64) -> Self {
+        self.sampling_params.typical_p = Some(typical_p);
+        self
+    }
+
+    pub fn set_sampler_top_a(mut self, top_a: f64) -> Self {
+        self.sampling_params.top_a = Some(top_a);
+        self
+    }
+
+    pub fn set_sampler_xtc_params(mut self, xtc_params: XtcSamplingParams) -> Self {
+        self.sampling_params.xtc_params = Some(xtc_params);
+        self
+    }
+
+    pub fn set_sampler_mirostat_params(mut self, mirostat_params: MirostatParams) -> Self {
+        self.sampling_params.mirostat_params = Some(mirostat_params);
+        self
+    }
+
+    /// Apply the samplers in this order. Samplers which are not listed are not applied.
+    pub fn set_sampler_order(mut self, sampler_order: Vec<SamplerKind>) -> Self {
+        self.sampling_params.sampler_order = Some(sampler_order);
+        self
+    }
+
+    /// Decode with beam search instead of sampling. `n_choices` of the best hypotheses are returned.
+    pub fn set_sampler_beam_search(mut self, beam_search: BeamSearchParams) -> Self {
+        self.sampling_params.beam_search = Some(beam_search);
+        self
+    }
+
     pub fn enable_thinking(mut self, enable_thinking: bool) -> Self {
         self.enable_thinking = Some(enable_thinking);
         self
@@ -692,6 +775,25 @@ impl RequestBuilder {
         self.truncate_sequence = truncate_sequence;
         self
     }
+
+    /// Set the scheduling priority class of this request.
+    pub fn with_priority(mut self, priority: RequestPriority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Give up on this request after `timeout`, finishing with the `timeout` finish reason.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Return the logprob of every prompt token after the first, with the `top_n` most likely
+    /// alternatives for each.
+    pub fn with_prompt_logprobs(mut self, top_n: usize) -> Self {
+        self.prompt_logprobs = Some(top_n);
+        self
+    }
 }
 
 impl RequestLike for RequestBuilder {
@@ -790,6 +892,16 @@ impl RequestLike for RequestBuilder {
     fn truncate_sequence(&self) -> bool {
         self.truncate_sequence
     }
+    fn priority(&self) -> RequestPriority {
+        self.priority
+    }
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn prompt_logprobs(&self) -> Option<usize> {
+        self.prompt_logprobs
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -3,7 +3,7 @@ use candle_core::{Device, Result, Tensor};
 use either::Either;
 use futures::future::join_all;
 use mistralrs_core::*;
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc};
 use tokio::sync::mpsc::{channel, Receiver};
 
 use crate::{EmbeddingRequest, EmbeddingRequestBuilder, RequestLike, TextMessages};
@@ -51,12 +51,18 @@ pub struct Model {
 pub struct Stream<'a> {
     _server: &'a Model,
     rx: Receiver<Response>,
+    request_id: usize,
 }
 
 impl Stream<'_> {
     pub async fn next(&mut self) -> Option<Response> {
         self.rx.recv().await
     }
+
+    /// ID of the streamed request, which can be passed to [`Model::cancel_request`].
+    pub fn request_id(&self) -> usize {
+        self.request_id
+    }
 }
 
 impl Model {
@@ -72,18 +78,22 @@ impl Model {
         let (tx, rx) = channel(1);
 
         let truncate_sequence = request.truncate_sequence();
+        let priority = request.priority();
+        let timeout = request.timeout();
+        let prompt_logprobs = request.prompt_logprobs();
         let (tools, tool_choice) = if let Some((a, b)) = request.take_tools() {
             (Some(a), Some(b))
         } else {
             (None, None)
         };
+        let request_id = self.runner.next_request_id();
         let request = Request::Normal(Box::new(NormalRequest {
             messages: request.take_messages(),
             sampling_params: request.take_sampling_params(),
             response: tx,
             return_logprobs: request.return_logprobs(),
             is_streaming: true,
-            id: 0,
+            id: request_id,
             constraint: request.take_constraint(),
             suffix: None,
             tools,
@@ -93,11 +103,19 @@ impl Model {
             web_search_options: request.take_web_search_options(),
             model_id: None,
             truncate_sequence,
+            priority,
+            timeout,
+            prompt_logprobs,
+            traceparent: None,
         }));
 
         self.runner.get_sender(None)?.send(request).await?;
 
-        let stream = Stream { _server: self, rx };
+        let stream = Stream {
+            _server: self,
+            rx,
+            request_id,
+        };
 
         Ok(stream)
     }
@@ -110,6 +128,9 @@ impl Model {
         let (tx, mut rx) = channel(1);
 
         let truncate_sequence = request.truncate_sequence();
+        let priority = request.priority();
+        let timeout = request.timeout();
+        let prompt_logprobs = request.prompt_logprobs();
         let (tools, tool_choice) = if let Some((a, b)) = request.take_tools() {
             (Some(a), Some(b))
         } else {
@@ -131,6 +152,10 @@ impl Model {
             web_search_options: request.take_web_search_options(),
             model_id: None,
             truncate_sequence,
+            priority,
+            timeout,
+            prompt_logprobs,
+            traceparent: None,
         }));
 
         self.runner.get_sender(None)?.send(request).await?;
@@ -157,6 +182,9 @@ impl Model {
         let (tx, mut rx) = channel(1);
 
         let truncate_sequence = request.truncate_sequence();
+        let priority = request.priority();
+        let timeout = request.timeout();
+        let prompt_logprobs = request.prompt_logprobs();
         let (tools, tool_choice) = if let Some((a, b)) = request.take_tools() {
             (Some(a), Some(b))
         } else {
@@ -178,6 +206,10 @@ impl Model {
             web_search_options: request.take_web_search_options(),
             model_id: None,
             truncate_sequence,
+            priority,
+            timeout,
+            prompt_logprobs,
+            traceparent: None,
         }));
 
         self.runner.get_sender(None)?.send(request).await?;
@@ -225,6 +257,10 @@ impl Model {
             web_search_options: None,
             model_id: None,
             truncate_sequence: false,
+            priority: RequestPriority::default(),
+            timeout: None,
+            prompt_logprobs: None,
+            traceparent: None,
         }));
 
         self.runner.get_sender(None)?.send(request).await?;
@@ -268,6 +304,10 @@ impl Model {
             web_search_options: None,
             model_id: None,
             truncate_sequence: false,
+            priority: RequestPriority::default(),
+            timeout: None,
+            prompt_logprobs: None,
+            traceparent: None,
         }));
 
         self.runner.get_sender(None)?.send(request).await?;
@@ -324,6 +364,10 @@ impl Model {
                     web_search_options: None,
                     model_id: None,
                     truncate_sequence,
+                    priority: RequestPriority::default(),
+                    timeout: None,
+                    prompt_logprobs: None,
+                    traceparent: None,
                 }));
 
                 runner
@@ -413,6 +457,49 @@ impl Model {
         rx.recv().await.context("Channel was erroneously closed!")?
     }
 
+    /// Save the KV cache of a conversation to a session snapshot file, which [`Model::load_session`] can restore
+    /// later, possibly in another process. `tokens` is the conversation so far, for example from
+    /// [`Model::tokenize`] without the generation prompt. If they are not cached, they are prefilled first.
+    ///
+    /// Returns the number of tokens saved. With PagedAttention only full blocks are saved.
+    pub async fn save_session(
+        &self,
+        tokens: Vec<u32>,
+        path: impl Into<PathBuf>,
+    ) -> anyhow::Result<usize> {
+        let (tx, mut rx) = channel(1);
+        let request = Request::SaveSession(SaveSessionRequest {
+            id: self.runner.next_request_id(),
+            tokens,
+            path: path.into(),
+            response: tx,
+        });
+        self.runner.get_sender(None)?.send(request).await?;
+
+        rx.recv().await.context("Channel was erroneously closed!")?
+    }
+
+    /// Load a session snapshot file saved by [`Model::save_session`] into the prefix cache, so that the next
+    /// request continuing the conversation does not prefill it again.
+    ///
+    /// Returns the tokens of the restored conversation.
+    pub async fn load_session(&self, path: impl Into<PathBuf>) -> anyhow::Result<Vec<u32>> {
+        let (tx, mut rx) = channel(1);
+        let request = Request::LoadSession(LoadSessionRequest {
+            path: path.into(),
+            response: tx,
+        });
+        self.runner.get_sender(None)?.send(request).await?;
+
+        rx.recv().await.context("Channel was erroneously closed!")?
+    }
+
+    /// Cancel a streamed request, see [`Stream::request_id`]. The stream ends with a chunk with
+    /// the `canceled` finish reason.
+    pub fn cancel_request(&self, request_id: usize) -> std::result::Result<(), MistralRsError> {
+        self.runner.cancel_request(request_id, None)
+    }
+
     /// Retrieve some information about this model.
     pub fn config(&self) -> std::result::Result<MistralRsConfig, String> {
         self.runner.config(None)
@@ -423,6 +510,17 @@ impl Model {
         self.runner.max_sequence_length(None)
     }
 
+    /// Returns a snapshot of the scheduling, KV cache and throughput statistics of this model.
+    pub fn engine_stats(&self) -> std::result::Result<EngineStats, MistralRsError> {
+        self.runner.engine_stats(None)
+    }
+
+    /// Returns the MCP client of this model, to list and fetch the prompts and resources of its
+    /// MCP servers. `None` if no MCP client is configured or it failed to connect.
+    pub fn mcp_client(&self) -> std::result::Result<Option<Arc<McpClient>>, String> {
+        self.runner.mcp_client(None)
+    }
+
     pub fn inner(&self) -> &MistralRs {
         &self.runner
     }
@@ -72,6 +72,9 @@ impl MultiModel {
             (None, None)
         };
         let truncate_sequence = request.truncate_sequence();
+        let priority = request.priority();
+        let timeout = request.timeout();
+        let prompt_logprobs = request.prompt_logprobs();
 
         let request = Request::Normal(Box::new(NormalRequest {
             messages: request.take_messages(),
@@ -89,6 +92,10 @@ impl MultiModel {
             web_search_options: request.take_web_search_options(),
             model_id: model_id.map(|s| s.to_string()),
             truncate_sequence,
+            priority,
+            timeout,
+            prompt_logprobs,
+            traceparent: None,
         }));
 
         self.runner.get_sender(model_id)?.send(request).await?;
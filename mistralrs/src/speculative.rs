@@ -2,8 +2,9 @@ use std::sync::Arc;
 
 use mistralrs_core::{
     initialize_logging, AutoDeviceMapParams, DefaultSchedulerMethod, DeviceMapSetting,
-    MistralRsBuilder, NormalLoaderBuilder, NormalSpecificConfig, Pipeline, SchedulerConfig,
-    SpeculativeConfig, SpeculativePipeline,
+    MemoryGpuConfig, MistralRsBuilder, NormalLoaderBuilder, NormalSpecificConfig,
+    PagedAttentionConfig, Pipeline, SchedulerConfig, SpeculativeConfig, SpeculativeDraft,
+    SpeculativePipeline,
 };
 use tokio::sync::Mutex;
 
@@ -11,14 +12,15 @@ use crate::{best_device, Model, TextModelBuilder};
 
 pub struct TextSpeculativeBuilder {
     target: TextModelBuilder,
-    draft: TextModelBuilder,
+    draft: Option<TextModelBuilder>,
     speculative_config: SpeculativeConfig,
 }
 
 impl TextSpeculativeBuilder {
     /// Create a builder for a speculative decoding pipeline.
     ///
-    /// - PagedAttention settings are ignored as our impl of speculative decoding does not support this yet.
+    /// - PagedAttention settings are taken from the target model. The draft model's KV cache has as
+    ///   many blocks as the target's, so its own PagedAttention settings are ignored.
     /// - Prefix caching settings are ignored as our impl of speculative decoding does not support this yet.
     ///
     /// Otherwise, scheduling parameters such as `max_num_seqs` are sourced from the target model.
@@ -33,12 +35,40 @@ impl TextSpeculativeBuilder {
 
         Ok(Self {
             target,
-            draft,
+            draft: Some(draft),
             speculative_config,
         })
     }
 
-    fn build_pipeline(builder: TextModelBuilder) -> anyhow::Result<Arc<Mutex<dyn Pipeline>>> {
+    /// Create a builder for a speculative decoding pipeline without a draft model, which proposes
+    /// tokens from n-gram matches in the sequence. `speculative_config.draft` must be
+    /// [`SpeculativeDraft::NGram`].
+    ///
+    /// The same settings as [`TextSpeculativeBuilder::new`] are ignored.
+    pub fn new_ngram(
+        target: TextModelBuilder,
+        speculative_config: SpeculativeConfig,
+    ) -> anyhow::Result<Self> {
+        if target.no_kv_cache {
+            anyhow::bail!("The target must have KV cache enabled.");
+        }
+        if !matches!(speculative_config.draft, SpeculativeDraft::NGram { .. }) {
+            anyhow::bail!(
+                "`TextSpeculativeBuilder::new_ngram` requires `SpeculativeDraft::NGram`."
+            );
+        }
+
+        Ok(Self {
+            target,
+            draft: None,
+            speculative_config,
+        })
+    }
+
+    fn build_pipeline(
+        builder: TextModelBuilder,
+        paged_attn_cfg: Option<PagedAttentionConfig>,
+    ) -> anyhow::Result<Arc<Mutex<dyn Pipeline>>> {
         let config = NormalSpecificConfig {
             topology: builder.topology,
             organization: builder.organization,
@@ -76,24 +106,59 @@ impl TextSpeculativeBuilder {
                 .device_mapping
                 .unwrap_or(DeviceMapSetting::Auto(AutoDeviceMapParams::default_text())),
             builder.isq,
-            builder.paged_attn_cfg,
+            paged_attn_cfg,
         )?;
         Ok(pipeline)
     }
 
     pub async fn build(self) -> anyhow::Result<Model> {
-        let target = Self::build_pipeline(self.target.clone())?;
-        let draft = Self::build_pipeline(self.draft.clone())?;
+        let target = Self::build_pipeline(self.target.clone(), self.target.paged_attn_cfg)?;
 
-        let scheduler_method = SchedulerConfig::DefaultScheduler {
-            method: DefaultSchedulerMethod::Fixed(self.target.max_num_seqs.try_into()?),
+        let pipeline = match self.draft {
+            Some(draft) => {
+                // The draft's KV cache is addressed through the target's block tables.
+                let preemption_mode = self
+                    .target
+                    .paged_attn_cfg
+                    .map(|config| config.preemption_mode())
+                    .unwrap_or_default();
+                let draft_paged_attn_cfg = target
+                    .lock()
+                    .await
+                    .get_metadata()
+                    .cache_config
+                    .clone()
+                    .map(|config| {
+                        PagedAttentionConfig::new(
+                            Some(config.block_size),
+                            MemoryGpuConfig::ContextSize(config.num_gpu_blocks * config.block_size),
+                            config.cache_type,
+                        )
+                        .map(|config| config.with_preemption_mode(preemption_mode))
+                    })
+                    .transpose()?;
+                SpeculativePipeline::new(
+                    target,
+                    Self::build_pipeline(draft, draft_paged_attn_cfg)?,
+                    self.speculative_config,
+                )?
+            }
+            None => SpeculativePipeline::new_ngram(target, self.speculative_config)?,
         };
 
-        let pipeline = Arc::new(Mutex::new(SpeculativePipeline::new(
-            target,
-            draft,
-            self.speculative_config,
-        )?));
+        let pipeline: Arc<Mutex<dyn Pipeline>> = Arc::new(Mutex::new(pipeline));
+
+        let scheduler_method = match pipeline.lock().await.get_metadata().cache_config.clone() {
+            Some(config) => SchedulerConfig::PagedAttentionMeta {
+                max_num_seqs: self.target.max_num_seqs,
+                config,
+                max_num_batched_tokens: None,
+            },
+            None => SchedulerConfig::DefaultScheduler {
+                method: DefaultSchedulerMethod::Fixed(self.target.max_num_seqs.try_into()?),
+                max_num_batched_tokens: None,
+            },
+        };
 
         let mut runner = MistralRsBuilder::new(
             pipeline,
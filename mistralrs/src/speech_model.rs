@@ -109,6 +109,7 @@ impl SpeechModelBuilder {
 
         let scheduler_method = SchedulerConfig::DefaultScheduler {
             method: DefaultSchedulerMethod::Fixed(self.max_num_seqs.try_into()?),
+            max_num_batched_tokens: None,
         };
 
         let runner = MistralRsBuilder::new(pipeline, scheduler_method, false, None);
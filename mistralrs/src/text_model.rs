@@ -38,6 +38,7 @@ pub struct TextModelBuilder {
     pub(crate) tool_callbacks: HashMap<String, Arc<ToolCallback>>,
     pub(crate) tool_callbacks_with_tools: HashMap<String, ToolCallbackWithTool>,
     pub(crate) mcp_client_config: Option<McpClientConfig>,
+    pub(crate) mcp_sampling_approval: Option<McpSamplingApproval>,
     pub(crate) device: Option<Device>,
     pub(crate) matformer_config_path: Option<PathBuf>,
     pub(crate) matformer_slice_name: Option<String>,
@@ -54,9 +55,12 @@ pub struct TextModelBuilder {
     // Other things
     pub(crate) paged_attn_cfg: Option<PagedAttentionConfig>,
     pub(crate) max_num_seqs: usize,
+    pub(crate) max_num_batched_tokens: Option<usize>,
     pub(crate) no_kv_cache: bool,
     pub(crate) with_logging: bool,
     pub(crate) prefix_cache_n: Option<usize>,
+    pub(crate) prefix_cache_disk: Option<PrefixCacheDiskConfig>,
+    pub(crate) kv_cache_quantization: Option<KvCacheQuantization>,
 }
 
 /// Builder for PagedAttention metadata.
@@ -64,6 +68,7 @@ pub struct PagedAttentionMetaBuilder {
     block_size: Option<usize>,
     mem_gpu: MemoryGpuConfig,
     cache_type: PagedCacheType,
+    preemption_mode: PreemptionMode,
 }
 
 impl Default for PagedAttentionMetaBuilder {
@@ -72,6 +77,7 @@ impl Default for PagedAttentionMetaBuilder {
             block_size: None,
             mem_gpu: MemoryGpuConfig::ContextSize(4096),
             cache_type: PagedCacheType::Auto,
+            preemption_mode: PreemptionMode::Recompute,
         }
     }
 }
@@ -92,8 +98,18 @@ impl PagedAttentionMetaBuilder {
         self
     }
 
+    /// Swap the KV cache of preempted sequences out to `swap_space_mb` MB of host memory instead
+    /// of recomputing it.
+    pub fn with_swap_space(mut self, swap_space_mb: usize) -> Self {
+        self.preemption_mode = PreemptionMode::Swap { swap_space_mb };
+        self
+    }
+
     pub fn build(self) -> anyhow::Result<PagedAttentionConfig> {
-        PagedAttentionConfig::new(self.block_size, self.mem_gpu, self.cache_type)
+        Ok(
+            PagedAttentionConfig::new(self.block_size, self.mem_gpu, self.cache_type)?
+                .with_preemption_mode(self.preemption_mode),
+        )
     }
 }
 
@@ -122,8 +138,11 @@ impl TextModelBuilder {
             isq: None,
             paged_attn_cfg: None,
             max_num_seqs: 32,
+            max_num_batched_tokens: None,
             no_kv_cache: false,
             prefix_cache_n: Some(16),
+            prefix_cache_disk: None,
+            kv_cache_quantization: None,
             with_logging: false,
             device_mapping: None,
             imatrix: None,
@@ -136,6 +155,7 @@ impl TextModelBuilder {
             tool_callbacks: HashMap::new(),
             tool_callbacks_with_tools: HashMap::new(),
             mcp_client_config: None,
+            mcp_sampling_approval: None,
             device: None,
             matformer_config_path: None,
             matformer_slice_name: None,
@@ -185,6 +205,13 @@ impl TextModelBuilder {
         self
     }
 
+    /// Decide whether to serve each sampling request of the MCP servers, when sampling is
+    /// enabled in the MCP client configuration.
+    pub fn with_mcp_sampling_approval(mut self, approval: McpSamplingApproval) -> Self {
+        self.mcp_sampling_approval = Some(approval);
+        self
+    }
+
     /// Enable runner throughput logging.
     pub fn with_throughput_logging(mut self) -> Self {
         self.throughput_logging = true;
@@ -234,7 +261,7 @@ impl TextModelBuilder {
         self
     }
 
-    /// Force usage of the CPU device. Do not use PagedAttention with this.
+    /// Force usage of the CPU device.
     pub fn with_force_cpu(mut self) -> Self {
         self.force_cpu = true;
         self
@@ -292,6 +319,13 @@ impl TextModelBuilder {
         self
     }
 
+    /// Enable chunked prefill with a budget of `max_num_batched_tokens` tokens per step. Long
+    /// prompts are then prefilled in chunks between decode steps instead of stalling them.
+    pub fn with_max_num_batched_tokens(mut self, max_num_batched_tokens: usize) -> Self {
+        self.max_num_batched_tokens = Some(max_num_batched_tokens);
+        self
+    }
+
     /// Disable KV cache. Trade performance for memory usage.
     pub fn with_no_kv_cache(mut self) -> Self {
         self.no_kv_cache = true;
@@ -304,6 +338,19 @@ impl TextModelBuilder {
         self
     }
 
+    /// Persist evicted prefix caches to disk so that they can be reused across restarts. This only applies when
+    /// PagedAttention is not used.
+    pub fn with_prefix_cache_disk(mut self, config: PrefixCacheDiskConfig) -> Self {
+        self.prefix_cache_disk = Some(config);
+        self
+    }
+
+    /// Store the KV cache quantized to reduce its memory usage. This only applies when PagedAttention is not used.
+    pub fn with_kv_cache_quantization(mut self, quantization: KvCacheQuantization) -> Self {
+        self.kv_cache_quantization = Some(quantization);
+        self
+    }
+
     /// Enable logging.
     pub fn with_logging(mut self) -> Self {
         self.with_logging = true;
@@ -424,15 +471,18 @@ impl TextModelBuilder {
                     SchedulerConfig::PagedAttentionMeta {
                         max_num_seqs: self.max_num_seqs,
                         config,
+                        max_num_batched_tokens: self.max_num_batched_tokens,
                     }
                 } else {
                     SchedulerConfig::DefaultScheduler {
                         method: DefaultSchedulerMethod::Fixed(self.max_num_seqs.try_into()?),
+                        max_num_batched_tokens: self.max_num_batched_tokens,
                     }
                 }
             }
             None => SchedulerConfig::DefaultScheduler {
                 method: DefaultSchedulerMethod::Fixed(self.max_num_seqs.try_into()?),
+                max_num_batched_tokens: self.max_num_batched_tokens,
             },
         };
 
@@ -458,6 +508,9 @@ impl TextModelBuilder {
         if let Some(mcp_config) = self.mcp_client_config {
             runner = runner.with_mcp_client(mcp_config);
         }
+        if let Some(approval) = self.mcp_sampling_approval {
+            runner = runner.with_mcp_sampling_approval(approval);
+        }
         runner = runner
             .with_no_kv_cache(self.no_kv_cache)
             .with_no_prefix_cache(self.prefix_cache_n.is_none());
@@ -465,6 +518,12 @@ impl TextModelBuilder {
         if let Some(n) = self.prefix_cache_n {
             runner = runner.with_prefix_cache_n(n)
         }
+        if let Some(config) = self.prefix_cache_disk {
+            runner = runner.with_prefix_cache_disk(config);
+        }
+        if let Some(quantization) = self.kv_cache_quantization {
+            runner = runner.with_kv_cache_quantization(quantization);
+        }
 
         Ok(Model::new(runner.build().await))
     }
@@ -55,6 +55,8 @@ pub struct VisionModelBuilder {
     pub(crate) max_num_seqs: usize,
     pub(crate) with_logging: bool,
     pub(crate) prefix_cache_n: Option<usize>,
+    pub(crate) prefix_cache_disk: Option<PrefixCacheDiskConfig>,
+    pub(crate) kv_cache_quantization: Option<KvCacheQuantization>,
 }
 
 impl VisionModelBuilder {
@@ -95,6 +97,8 @@ impl VisionModelBuilder {
             matformer_config_path: None,
             matformer_slice_name: None,
             prefix_cache_n: None,
+            prefix_cache_disk: None,
+            kv_cache_quantization: None,
         }
     }
 
@@ -176,7 +180,7 @@ impl VisionModelBuilder {
         self
     }
 
-    /// Force usage of the CPU device. Do not use PagedAttention with this.
+    /// Force usage of the CPU device.
     pub fn with_force_cpu(mut self) -> Self {
         self.force_cpu = true;
         self
@@ -234,6 +238,19 @@ impl VisionModelBuilder {
         self
     }
 
+    /// Persist evicted prefix caches to disk so that they can be reused across restarts. This only applies when
+    /// PagedAttention is not used.
+    pub fn with_prefix_cache_disk(mut self, config: PrefixCacheDiskConfig) -> Self {
+        self.prefix_cache_disk = Some(config);
+        self
+    }
+
+    /// Store the KV cache quantized to reduce its memory usage. This only applies when PagedAttention is not used.
+    pub fn with_kv_cache_quantization(mut self, quantization: KvCacheQuantization) -> Self {
+        self.kv_cache_quantization = Some(quantization);
+        self
+    }
+
     /// Enable logging.
     pub fn with_logging(mut self) -> Self {
         self.with_logging = true;
@@ -360,15 +377,18 @@ impl VisionModelBuilder {
                     SchedulerConfig::PagedAttentionMeta {
                         max_num_seqs: self.max_num_seqs,
                         config,
+                        max_num_batched_tokens: None,
                     }
                 } else {
                     SchedulerConfig::DefaultScheduler {
                         method: DefaultSchedulerMethod::Fixed(self.max_num_seqs.try_into()?),
+                        max_num_batched_tokens: None,
                     }
                 }
             }
             None => SchedulerConfig::DefaultScheduler {
                 method: DefaultSchedulerMethod::Fixed(self.max_num_seqs.try_into()?),
+                max_num_batched_tokens: None,
             },
         };
 
@@ -398,6 +418,12 @@ impl VisionModelBuilder {
         if let Some(n) = self.prefix_cache_n {
             runner = runner.with_prefix_cache_n(n)
         }
+        if let Some(config) = self.prefix_cache_disk {
+            runner = runner.with_prefix_cache_disk(config);
+        }
+        if let Some(quantization) = self.kv_cache_quantization {
+            runner = runner.with_kv_cache_quantization(quantization);
+        }
 
         Ok(Model::new(runner.build().await))
     }
@@ -90,10 +90,12 @@ impl XLoraModelBuilder {
                 SchedulerConfig::PagedAttentionMeta {
                     max_num_seqs: self.text_model.max_num_seqs,
                     config,
+                    max_num_batched_tokens: None,
                 }
             }
             None => SchedulerConfig::DefaultScheduler {
                 method: DefaultSchedulerMethod::Fixed(self.text_model.max_num_seqs.try_into()?),
+                max_num_batched_tokens: None,
             },
         };
 
@@ -116,6 +118,12 @@ impl XLoraModelBuilder {
         if let Some(n) = self.text_model.prefix_cache_n {
             runner = runner.with_prefix_cache_n(n)
         }
+        if let Some(config) = self.text_model.prefix_cache_disk {
+            runner = runner.with_prefix_cache_disk(config);
+        }
+        if let Some(quantization) = self.text_model.kv_cache_quantization {
+            runner = runner.with_kv_cache_quantization(quantization);
+        }
 
         Ok(Model::new(runner.build().await))
     }